@@ -0,0 +1,66 @@
+//! Measures the backends on the bundled PHP grammar: lowering it, generating C code and
+//! interpreting it.
+//!
+//! Compare two revisions with `cargo bench --bench backends -- --save-baseline before`
+//! and `cargo bench --bench backends -- --baseline before`.
+
+use criterion::{
+    black_box,
+    criterion_group,
+    criterion_main,
+    Criterion,
+};
+use peacock_fuzz::{
+    backends::{
+        interpreter::GrammarInterpreter,
+        C::{
+            CGenerator,
+            LowLevelGrammar,
+        },
+    },
+    grammar::ContextFreeGrammar,
+};
+
+const PHP_GRAMMAR: &str = "test-data/static_loading/php.json";
+const PHP_ENTRYPOINT: &str = "PROGRAM";
+
+/// How many inputs are generated per iteration of the interpreter benchmark
+const SAMPLES: usize = 1000;
+
+/// The length after which the interpreter only picks the shortest expansions
+const MAX_LEN: usize = 4096;
+
+fn php_grammar() -> ContextFreeGrammar {
+    ContextFreeGrammar::builder().gramatron_grammar(PHP_GRAMMAR).unwrap().entrypoint(PHP_ENTRYPOINT).build().unwrap()
+}
+
+fn interpreter(c: &mut Criterion) {
+    let cfg = php_grammar();
+    let mut interpreter = GrammarInterpreter::new(&cfg);
+    let mut buf = Vec::with_capacity(MAX_LEN * 2);
+
+    c.bench_function("interpret php.json", |b| {
+        b.iter(|| {
+            /* Every iteration generates the same inputs */
+            interpreter.seed(1234);
+
+            for _ in 0..SAMPLES {
+                black_box(interpreter.interpret_into(&mut buf, MAX_LEN, None));
+            }
+        })
+    });
+}
+
+fn codegen(c: &mut Criterion) {
+    let cfg = php_grammar();
+    let output = std::env::temp_dir().join(format!("peacock-bench-{}.c", std::process::id()));
+
+    c.bench_function("lower php.json", |b| b.iter(|| LowLevelGrammar::from_high_level_grammar(black_box(&cfg))));
+
+    c.bench_function("generate C code for php.json", |b| b.iter(|| CGenerator::new().generate(&output, &cfg)));
+
+    let _ = std::fs::remove_file(&output);
+}
+
+criterion_group!(benches, interpreter, codegen);
+criterion_main!(benches);
@@ -0,0 +1,70 @@
+//! Measures how long it takes to load the bundled PHP grammar.
+//!
+//! The `json::Value` benchmark walks a generic JSON document like the parsers did before they
+//! deserialized grammars into typed maps and serves as the baseline for the `typed` benchmark.
+//!
+//! Compare two revisions with `cargo bench --bench parsing -- --save-baseline before`
+//! and `cargo bench --bench parsing -- --baseline before`.
+
+use criterion::{
+    criterion_group,
+    criterion_main,
+    Criterion,
+};
+use indexmap::IndexMap;
+use peacock_fuzz::grammar::ContextFreeGrammar;
+use serde_json as json;
+use std::{
+    fs::File,
+    io::BufReader,
+};
+
+const PHP_GRAMMAR: &str = "test-data/static_loading/php.json";
+const PHP_ENTRYPOINT: &str = "PROGRAM";
+
+/// The old code path: Read the grammar into a `json::Value` and walk it.
+fn parse_value() -> IndexMap<String, Vec<String>> {
+    let reader = BufReader::new(File::open(PHP_GRAMMAR).unwrap());
+    let value: json::Value = json::from_reader(reader).unwrap();
+    let mut grammar = IndexMap::new();
+
+    for (key, rhs) in value.as_object().unwrap() {
+        let variants = rhs.as_array().unwrap().iter().map(|rule| rule.as_str().unwrap().to_string()).collect();
+        grammar.insert(key.clone(), variants);
+    }
+
+    grammar
+}
+
+/// The new code path: Deserialize the grammar directly into a typed map.
+fn parse_typed() -> IndexMap<String, Vec<String>> {
+    let content = std::fs::read_to_string(PHP_GRAMMAR).unwrap();
+    json::from_str(&content).unwrap()
+}
+
+fn parse_php(c: &mut Criterion) {
+    assert_eq!(parse_value(), parse_typed());
+
+    let mut group = c.benchmark_group("deserialize gramatron php.json");
+    group.bench_function("json::Value", |b| b.iter(parse_value));
+    group.bench_function("typed", |b| b.iter(parse_typed));
+    group.finish();
+
+    c.bench_function("parse gramatron php.json", |b| {
+        b.iter(|| ContextFreeGrammar::builder().gramatron_grammar(PHP_GRAMMAR).unwrap())
+    });
+
+    c.bench_function("build gramatron php.json", |b| {
+        b.iter(|| {
+            ContextFreeGrammar::builder()
+                .gramatron_grammar(PHP_GRAMMAR)
+                .unwrap()
+                .entrypoint(PHP_ENTRYPOINT)
+                .build()
+                .unwrap()
+        })
+    });
+}
+
+criterion_group!(benches, parse_php);
+criterion_main!(benches);
@@ -0,0 +1,19 @@
+//! Measure how well a grammar models existing inputs.
+//!
+//! Use it like so:
+//! ```no_run
+//! # use peacock_fuzz::{analysis::validate_corpus, grammar::ContextFreeGrammar};
+//! // Load the grammar without optimizations such that the report refers to the rules as they were written
+//! let grammar = ContextFreeGrammar::builder()
+//!     .peacock_grammar("my-grammar.json").unwrap()
+//!     .optimize(false)
+//!     .build().unwrap();
+//!
+//! // Unparse every file in a directory and print the parse rate and the rule coverage
+//! let report = validate_corpus(&grammar, "my-corpus/").unwrap();
+//! print!("{}", report);
+//! ```
+
+mod validate;
+
+pub use validate::*;
@@ -0,0 +1,349 @@
+use serde::Serialize;
+use std::{
+    collections::HashMap,
+    path::Path,
+};
+
+use crate::{
+    backends::{
+        interpreter::Unparser,
+        C::LowLevelGrammar,
+    },
+    grammar::{
+        symbol_string,
+        ContextFreeGrammar,
+        ProductionRule,
+    },
+};
+
+/// An input that the grammar could not unparse.
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+pub struct ParseFailure {
+    /// The name of the input
+    pub file: String,
+
+    /// The length of the input in bytes
+    pub len: usize,
+
+    /// The furthest byte offset into the input that the unparser reached
+    pub offset: usize,
+
+    /// The non-terminal that the unparser tried to match at that offset
+    pub non_terminal: String,
+}
+
+/// The number of failures whose furthest offset lies in `start..end`.
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+pub struct OffsetBucket {
+    /// The first offset of the bucket
+    pub start: usize,
+
+    /// The first offset after the bucket
+    pub end: usize,
+
+    /// The number of failures in the bucket
+    pub count: usize,
+}
+
+/// A rule that no unparsed input used.
+///
+/// Symbols are written in peacock notation: non-terminals as `<A>` and terminals as `'a'`.
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+pub struct UnusedRule {
+    /// The non-terminal
+    pub non_terminal: String,
+
+    /// The alternative of the non-terminal
+    pub rule: Vec<String>,
+}
+
+/// The result of [`validate_inputs()`] and [`validate_corpus()`].
+#[derive(Debug, Default, Clone, Serialize, PartialEq)]
+pub struct ValidationReport {
+    /// The number of inputs
+    pub files: usize,
+
+    /// The number of inputs that could be unparsed completely
+    pub parsed: usize,
+
+    /// The percentage of inputs that could be unparsed completely
+    pub parse_rate: f64,
+
+    /// Every input that could not be unparsed, in the order of the inputs
+    pub failures: Vec<ParseFailure>,
+
+    /// A histogram of the furthest offsets of the failures. Bucket `i > 0` spans the offsets
+    /// `2^(i-1)..2^i`, bucket 0 only holds offset 0. Buckets after the last non-empty one are left out.
+    pub furthest_offsets: Vec<OffsetBucket>,
+
+    /// The number of rules that the unparsed inputs used
+    pub used_rules: usize,
+
+    /// The number of rules that are reachable from the entrypoint
+    pub total_rules: usize,
+
+    /// The reachable rules that no unparsed input used, in the order of their definition
+    pub unused_rules: Vec<UnusedRule>,
+}
+
+impl std::fmt::Display for ValidationReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "parsed: {}/{} files ({:.2}%)", self.parsed, self.files, self.parse_rate)?;
+
+        if !self.failures.is_empty() {
+            writeln!(f, "furthest offsets of failures:")?;
+
+            for bucket in &self.furthest_offsets {
+                writeln!(f, "    {}..{}: {}", bucket.start, bucket.end, bucket.count)?;
+            }
+
+            writeln!(f, "failures:")?;
+
+            for failure in &self.failures {
+                writeln!(
+                    f,
+                    "    {}: offset {}/{} in <{}>",
+                    failure.file, failure.offset, failure.len, failure.non_terminal
+                )?;
+            }
+        }
+
+        writeln!(f, "rule coverage: {}/{} rules used", self.used_rules, self.total_rules)?;
+
+        if !self.unused_rules.is_empty() {
+            writeln!(f, "unused rules:")?;
+
+            for rule in &self.unused_rules {
+                writeln!(f, "    <{}> -> {}", rule.non_terminal, rule.rule.join(" "))?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// The histogram bucket of `offset`
+fn bucket(offset: usize) -> usize {
+    (usize::BITS - offset.leading_zeros()) as usize
+}
+
+/// Unparse every input with a pure-Rust version of the unparser of the [`C`](crate::backends::C) backend,
+/// thorough unparsing included, and report how many of them are part of the language of `grammar`,
+/// where the others diverge from it and which rules the inputs don't use.
+///
+/// `inputs` are pairs of a name and the content of an input. Empty inputs count as failures
+/// because the generated code cannot unparse them either.
+/// The report refers to the rules of `grammar`, so build it with `optimize(false)` to get
+/// a report about the rules as they were written.
+pub fn validate_inputs<I, S, B>(grammar: &ContextFreeGrammar, inputs: I) -> ValidationReport
+where
+    I: IntoIterator<Item = (S, B)>,
+    S: Into<String>,
+    B: AsRef<[u8]>,
+{
+    let ll = LowLevelGrammar::from_high_level_grammar(grammar);
+    let unparser = Unparser::new(&ll);
+    let mut usage: Vec<Vec<bool>> =
+        (0..ll.nonterminals().len()).map(|nonterm| vec![false; ll.rules()[&nonterm].len()]).collect();
+    let mut report = ValidationReport::default();
+
+    for (name, content) in inputs {
+        let content = content.as_ref();
+        report.files += 1;
+
+        match unparser.unparse(content) {
+            Ok(walk) => {
+                report.parsed += 1;
+
+                /* The steps of builtins hold values instead of rule indices */
+                for (nonterm, rule) in walk {
+                    if let Some(used) = usage[nonterm].get_mut(rule) {
+                        *used = true;
+                    }
+                }
+            },
+            Err(divergence) => report.failures.push(ParseFailure {
+                file: name.into(),
+                len: content.len(),
+                offset: divergence.offset,
+                non_terminal: ll.nonterminals()[divergence.nonterm].clone(),
+            }),
+        }
+    }
+
+    if report.files > 0 {
+        report.parse_rate = report.parsed as f64 * 100.0 / report.files as f64;
+    }
+
+    let buckets = report.failures.iter().map(|failure| bucket(failure.offset) + 1).max().unwrap_or(0);
+    report.furthest_offsets = (0..buckets)
+        .map(|i| OffsetBucket {
+            start: if i == 0 { 0 } else { 1 << (i - 1) },
+            end: 1 << i,
+            count: 0,
+        })
+        .collect();
+
+    for failure in &report.failures {
+        report.furthest_offsets[bucket(failure.offset)].count += 1;
+    }
+
+    /* The dynamic non-terminal has no rules in the grammar */
+    let rules: HashMap<&str, Vec<&ProductionRule>> =
+        grammar.rules_by_nonterminal().into_iter().map(|(nonterm, rules)| (nonterm.id(), rules)).collect();
+    let reachable = ll.reachable_nonterminals();
+
+    for (nonterm, name) in ll.nonterminals().iter().enumerate() {
+        let Some(alternatives) = rules.get(name.as_str()) else {
+            continue;
+        };
+
+        if !reachable[nonterm] {
+            continue;
+        }
+
+        for (rule, used) in usage[nonterm].iter().enumerate() {
+            report.total_rules += 1;
+
+            if *used {
+                report.used_rules += 1;
+            } else {
+                report.unused_rules.push(UnusedRule {
+                    non_terminal: name.clone(),
+                    rule: alternatives[rule].rhs().iter().map(symbol_string).collect(),
+                });
+            }
+        }
+    }
+
+    report
+}
+
+/// Like [`validate_inputs()`] for every file in the directory `dir`, sorted by name.
+/// Subdirectories are not searched.
+pub fn validate_corpus<P: AsRef<Path>>(grammar: &ContextFreeGrammar, dir: P) -> std::io::Result<ValidationReport> {
+    let mut paths = Vec::new();
+
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+
+        if path.is_file() {
+            paths.push(path);
+        }
+    }
+
+    paths.sort();
+
+    let mut inputs = Vec::with_capacity(paths.len());
+
+    for path in paths {
+        let name = path.file_name().unwrap().to_string_lossy().into_owned();
+        inputs.push((name, std::fs::read(&path)?));
+    }
+
+    Ok(validate_inputs(grammar, inputs))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn grammar() -> ContextFreeGrammar {
+        ContextFreeGrammar::builder()
+            .peacock_grammar_str(
+                r#"{"<ENTRYPOINT>": [["<E>"]], "<E>": [["<E>", "'+'", "<E>"], ["<N>"]], "<N>": [["'1'"], ["'2'"], ["'3'"]]}"#,
+            )
+            .unwrap()
+            .optimize(false)
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_validate_inputs() {
+        let inputs = [("a", "1+2"), ("b", "2"), ("c", "1+"), ("d", "x"), ("e", "1+2+1+1x")];
+        let report = validate_inputs(&grammar(), inputs);
+
+        assert_eq!(report.files, 5);
+        assert_eq!(report.parsed, 2);
+        assert_eq!(report.parse_rate, 40.0);
+        assert_eq!(
+            report.failures.iter().map(|failure| (failure.file.as_str(), failure.offset)).collect::<Vec<_>>(),
+            vec![("c", 2), ("d", 0), ("e", 7)]
+        );
+        assert_eq!(report.failures[0].non_terminal, "N");
+        assert_eq!(
+            report.furthest_offsets.iter().map(|bucket| (bucket.start, bucket.end, bucket.count)).collect::<Vec<_>>(),
+            vec![(0, 1, 1), (1, 2, 0), (2, 4, 1), (4, 8, 1)]
+        );
+        assert_eq!((report.used_rules, report.total_rules), (5, 6));
+        assert_eq!(
+            report.unused_rules,
+            vec![UnusedRule {
+                non_terminal: "N".to_string(),
+                rule: vec!["'3'".to_string()],
+            }]
+        );
+
+        /* The format of the report is stable */
+        assert_eq!(
+            report.to_string(),
+            "parsed: 2/5 files (40.00%)
+furthest offsets of failures:
+    0..1: 1
+    1..2: 0
+    2..4: 1
+    4..8: 1
+failures:
+    c: offset 2/2 in <N>
+    d: offset 0/1 in <ENTRYPOINT>
+    e: offset 7/8 in <E>
+rule coverage: 5/6 rules used
+unused rules:
+    <N> -> '3'
+"
+        );
+        assert_eq!(
+            serde_json::to_value(&report).unwrap(),
+            serde_json::json!({
+                "files": 5,
+                "parsed": 2,
+                "parse_rate": 40.0,
+                "failures": [
+                    {"file": "c", "len": 2, "offset": 2, "non_terminal": "N"},
+                    {"file": "d", "len": 1, "offset": 0, "non_terminal": "ENTRYPOINT"},
+                    {"file": "e", "len": 8, "offset": 7, "non_terminal": "E"},
+                ],
+                "furthest_offsets": [
+                    {"start": 0, "end": 1, "count": 1},
+                    {"start": 1, "end": 2, "count": 0},
+                    {"start": 2, "end": 4, "count": 1},
+                    {"start": 4, "end": 8, "count": 1},
+                ],
+                "used_rules": 5,
+                "total_rules": 6,
+                "unused_rules": [{"non_terminal": "N", "rule": ["'3'"]}],
+            })
+        );
+
+        let report = validate_inputs(&grammar(), Vec::<(String, Vec<u8>)>::new());
+        assert_eq!(report.parse_rate, 0.0);
+        assert_eq!(report.unused_rules.len(), 6);
+        assert!(report.to_string().starts_with("parsed: 0/0 files (0.00%)\nrule coverage: 0/6 rules used\n"));
+    }
+
+    #[test]
+    fn test_validate_corpus() {
+        let dir = std::env::temp_dir().join(format!("peacock-validate-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("subdir")).unwrap();
+        std::fs::write(dir.join("2"), "3+3").unwrap();
+        std::fs::write(dir.join("1"), "").unwrap();
+
+        let report = validate_corpus(&grammar(), &dir).unwrap();
+        assert_eq!((report.files, report.parsed), (2, 1));
+        assert_eq!(report.failures[0].file, "1");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
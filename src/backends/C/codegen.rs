@@ -1,19 +1,38 @@
 use itertools::Itertools;
 use std::{
+    collections::HashMap,
     fs::File,
     io::Write,
-    path::Path,
+    path::{
+        Path,
+        PathBuf,
+    },
 };
 
 use crate::{
-    backends::C::{
-        formatter::CFormatter,
-        grammar::{
-            LLSymbol,
-            LowLevelGrammar,
+    backends::{
+        interpreter::Unparser,
+        Backend,
+        GenerationProfile,
+        C::{
+            formatter::CFormatter,
+            grammar::{
+                LLSymbol,
+                LowLevelGrammar,
+                TerminalTable,
+            },
+            python::{
+                emit_python_module,
+                PythonModule,
+            },
         },
     },
-    grammar::ContextFreeGrammar,
+    error::BackendError,
+    grammar::{
+        BindingOp,
+        Builtin,
+        ContextFreeGrammar,
+    },
 };
 
 fn rule_has_nonterminals(rule: &[LLSymbol]) -> bool {
@@ -36,10 +55,19 @@ fn rules_have_nonterminals(rules: &[Vec<LLSymbol>]) -> bool {
     false
 }
 
+/// Whether `rule` writes bytes. Uses of bindings count as terminals, the markers that delimit bindings don't.
 fn rule_has_terminals(rule: &[LLSymbol]) -> bool {
     for symbol in rule {
-        if matches!(symbol, LLSymbol::Terminal(_)) {
-            return true;
+        match symbol {
+            LLSymbol::Terminal(_)
+            | LLSymbol::Repeat {
+                ..
+            } => return true,
+            LLSymbol::Binding {
+                op,
+                ..
+            } if op.is_reference() => return true,
+            _ => {},
         }
     }
 
@@ -56,20 +84,118 @@ fn rules_have_terminals(rules: &[Vec<LLSymbol>]) -> bool {
     false
 }
 
+/// The number of bytes of the terminals at the start of `rule`.
+fn terminal_prefix_len(rule: &[LLSymbol], terminals: &TerminalTable) -> usize {
+    rule.iter()
+        .map_while(|symbol| match symbol {
+            LLSymbol::Terminal(term) => Some(terminals.entry(term).1),
+            LLSymbol::Repeat {
+                count,
+                ..
+            } => Some(*count),
+            LLSymbol::NonTerminal(_)
+            | LLSymbol::Binding {
+                ..
+            } => None,
+        })
+        .sum()
+}
+
+/// The name of the macro for `op` in the generated code
+fn binding_op_macro(op: BindingOp) -> String {
+    format!("BINDING_{}", op.keyword().to_uppercase())
+}
+
+/// The constant expansions of single-rule non-terminals that span more than one step of a walk.
+/// Their serialization functions write the whole expansion with a single memcpy instead of descending into every child.
+fn combined_expansions(grammar: &LowLevelGrammar) -> HashMap<usize, (String, Vec<usize>)> {
+    grammar.constant_expansions().into_iter().filter(|(_, (_, nonterminals))| nonterminals.len() > 1).collect()
+}
+
+/// The files that the generated code is written to.
+/// Without splitting, everything is written to the primary file.
+struct Output {
+    primary: CFormatter<File>,
+    header: Option<CFormatter<File>>,
+    parts: Vec<CFormatter<File>>,
+}
+
+impl Output {
+    /// Where types, macros and forward declarations go
+    fn declarations(&mut self) -> &mut CFormatter<File> {
+        match &mut self.header {
+            Some(header) => header,
+            None => &mut self.primary,
+        }
+    }
+
+    /// Flush all files and return the first error that occurred while writing them
+    fn finish(self) -> std::io::Result<()> {
+        self.primary.finish()?;
+
+        if let Some(header) = self.header {
+            header.finish()?;
+        }
+
+        for part in self.parts {
+            part.finish()?;
+        }
+
+        Ok(())
+    }
+
+    /// Where the functions of the non-terminal `nonterm` go
+    fn part(&mut self, nonterm: usize) -> &mut CFormatter<File> {
+        if self.parts.is_empty() {
+            &mut self.primary
+        } else {
+            let len = self.parts.len();
+            &mut self.parts[nonterm % len]
+        }
+    }
+
+    /// Make a variable that is defined in the primary file accessible from all files
+    fn share<S: AsRef<str>>(&mut self, declaration: S) {
+        if let Some(header) = &mut self.header {
+            header.write(format!("extern INTERNAL {};", declaration.as_ref()));
+        }
+    }
+}
+
 fn emit_includes(fmt: &mut CFormatter<File>) {
     #[cfg(feature = "debug-codegen")]
     fmt.write("#include <stdio.h>");
 
     fmt.write("#include <stddef.h>");
+    fmt.write("#include <stdint.h>");
     fmt.blankline();
 }
 
-fn emit_macros(fmt: &mut CFormatter<File>) {
+fn emit_macros(split: bool, freestanding: bool, fmt: &mut CFormatter<File>) {
     fmt.write("/* Helper Macros */");
 
+    fmt.write("#undef INTERNAL");
+
+    if split {
+        fmt.write("#define INTERNAL __attribute__((visibility (\"hidden\")))");
+    } else {
+        /* Rules that are unreachable from the entrypoint have functions nobody calls */
+        fmt.write("#define INTERNAL static __attribute__((unused))");
+    }
+
+    fmt.blankline();
+
     fmt.write("#undef THREAD_LOCAL");
-    fmt.write("#ifdef MAKE_THREAD_SAFE");
-    fmt.write("#define THREAD_LOCAL __thread");
+
+    if freestanding {
+        /* __thread needs TLS support from the runtime */
+        fmt.write("#ifdef PEACOCK_THREAD_LOCAL");
+        fmt.write("#define THREAD_LOCAL PEACOCK_THREAD_LOCAL");
+    } else {
+        fmt.write("#ifdef MAKE_THREAD_SAFE");
+        fmt.write("#define THREAD_LOCAL __thread");
+    }
+
     fmt.write("#else");
     fmt.write("#define THREAD_LOCAL");
     fmt.write("#endif");
@@ -81,11 +207,22 @@ fn emit_macros(fmt: &mut CFormatter<File>) {
     fmt.write("#define LIKELY(x) __builtin_expect(!!(x), 1)");
     fmt.blankline();
 
-    fmt.write("#ifndef __clang__");
-    fmt.write("#undef __builtin_memcpy_inline");
-    fmt.write("#define __builtin_memcpy_inline __builtin_memcpy");
-    fmt.write("#endif");
-    fmt.blankline();
+    if freestanding {
+        emit_memory_functions(fmt);
+    } else {
+        for name in ["memcpy", "memmove", "memset", "memcmp"] {
+            fmt.write(format!("#undef peacock_{}", name));
+            fmt.write(format!("#define peacock_{0} __builtin_{0}", name));
+        }
+
+        fmt.write("#undef peacock_memcpy_inline");
+        fmt.write("#ifdef __clang__");
+        fmt.write("#define peacock_memcpy_inline __builtin_memcpy_inline");
+        fmt.write("#else");
+        fmt.write("#define peacock_memcpy_inline __builtin_memcpy");
+        fmt.write("#endif");
+        fmt.blankline();
+    }
 
     fmt.write("#undef EXPORT_FUNCTION");
     fmt.write("#ifdef MAKE_VISIBLE");
@@ -96,7 +233,149 @@ fn emit_macros(fmt: &mut CFormatter<File>) {
     fmt.blankline();
 }
 
-fn emit_rand(fmt: &mut CFormatter<File>) {
+/// Define the macros `peacock_memcpy`, `peacock_memmove`, `peacock_memset` and `peacock_memcmp`, which the generated
+/// code calls instead of the memory builtins, as plain loops unless they are overridden with `-D`.
+fn emit_memory_functions(fmt: &mut CFormatter<File>) {
+    fmt.write("/* Memory functions for freestanding targets */");
+
+    fmt.write("#ifndef PEACOCK_SIZE_T_WIDTH");
+    fmt.write("#define PEACOCK_SIZE_T_WIDTH 8");
+    fmt.write("#endif");
+    fmt.write("_Static_assert(sizeof(size_t) == PEACOCK_SIZE_T_WIDTH, \"Automaton walks depend on the width of size_t, define PEACOCK_SIZE_T_WIDTH to the width on this target\");");
+    fmt.blankline();
+
+    fmt.write("#ifndef peacock_memcpy");
+    fmt.write("#define peacock_memcpy peacock_copy_bytes");
+    fmt.write(
+        "static inline __attribute__((unused)) void* peacock_copy_bytes (void* dst, const void* src, size_t len) {",
+    );
+    fmt.indent();
+    fmt.write("unsigned char* d = (unsigned char*) dst;");
+    fmt.write("const unsigned char* s = (const unsigned char*) src;");
+    fmt.write("for (size_t i = 0; i < len; ++i) {");
+    fmt.indent();
+    fmt.write("d[i] = s[i];");
+    fmt.unindent();
+    fmt.write("}");
+    fmt.write("return dst;");
+    fmt.unindent();
+    fmt.write("}");
+    fmt.write("#endif");
+    fmt.blankline();
+
+    fmt.write("#ifndef peacock_memmove");
+    fmt.write("#define peacock_memmove peacock_move_bytes");
+    fmt.write(
+        "static inline __attribute__((unused)) void* peacock_move_bytes (void* dst, const void* src, size_t len) {",
+    );
+    fmt.indent();
+    fmt.write("unsigned char* d = (unsigned char*) dst;");
+    fmt.write("const unsigned char* s = (const unsigned char*) src;");
+    fmt.write("if (d < s) {");
+    fmt.indent();
+    fmt.write("for (size_t i = 0; i < len; ++i) {");
+    fmt.indent();
+    fmt.write("d[i] = s[i];");
+    fmt.unindent();
+    fmt.write("}");
+    fmt.unindent();
+    fmt.write("} else {");
+    fmt.indent();
+    fmt.write("for (size_t i = len; i > 0; --i) {");
+    fmt.indent();
+    fmt.write("d[i - 1] = s[i - 1];");
+    fmt.unindent();
+    fmt.write("}");
+    fmt.unindent();
+    fmt.write("}");
+    fmt.write("return dst;");
+    fmt.unindent();
+    fmt.write("}");
+    fmt.write("#endif");
+    fmt.blankline();
+
+    fmt.write("#ifndef peacock_memset");
+    fmt.write("#define peacock_memset peacock_set_bytes");
+    fmt.write("static inline __attribute__((unused)) void* peacock_set_bytes (void* dst, int c, size_t len) {");
+    fmt.indent();
+    fmt.write("unsigned char* d = (unsigned char*) dst;");
+    fmt.write("for (size_t i = 0; i < len; ++i) {");
+    fmt.indent();
+    fmt.write("d[i] = (unsigned char) c;");
+    fmt.unindent();
+    fmt.write("}");
+    fmt.write("return dst;");
+    fmt.unindent();
+    fmt.write("}");
+    fmt.write("#endif");
+    fmt.blankline();
+
+    fmt.write("#ifndef peacock_memcmp");
+    fmt.write("#define peacock_memcmp peacock_compare_bytes");
+    fmt.write(
+        "static inline __attribute__((unused)) int peacock_compare_bytes (const void* a, const void* b, size_t len) {",
+    );
+    fmt.indent();
+    fmt.write("const unsigned char* x = (const unsigned char*) a;");
+    fmt.write("const unsigned char* y = (const unsigned char*) b;");
+    fmt.write("for (size_t i = 0; i < len; ++i) {");
+    fmt.indent();
+    fmt.write("if (x[i] != y[i]) {");
+    fmt.indent();
+    fmt.write("return (int) x[i] - (int) y[i];");
+    fmt.unindent();
+    fmt.write("}");
+    fmt.unindent();
+    fmt.write("}");
+    fmt.write("return 0;");
+    fmt.unindent();
+    fmt.write("}");
+    fmt.write("#endif");
+    fmt.blankline();
+
+    fmt.write("#undef peacock_memcpy_inline");
+    fmt.write("#define peacock_memcpy_inline peacock_memcpy");
+    fmt.blankline();
+}
+
+/// All functions that the generated code exports
+const EXPORTED_SYMBOLS: [&str; 22] = [
+    "seed_generator",
+    "grammar_fingerprint",
+    "peacock_grammar_digest",
+    "get_rule_counters",
+    "get_rule_names",
+    "mutate_sequence",
+    "mutate_sequence_inplace",
+    "mutate_sequence_from",
+    "mutate_sequence_seeded",
+    "mutate_sequence_inplace_seeded",
+    "set_max_depth",
+    "subtree_length",
+    "serialize_sequence",
+    "serialize_sequence_checked",
+    "unparse_sequence",
+    "unparse_sequence_bounded",
+    "unparse_sequence_mem",
+    "unparse_failure",
+    "PEACOCK_MIN_OUTPUT_LEN",
+    "PEACOCK_MAX_OUTPUT_LEN",
+    "add_dynamic_terminal",
+    "validate_sequence",
+];
+
+fn emit_symbol_prefix(prefix: &str, fmt: &mut CFormatter<File>) {
+    fmt.write("/* Exported symbols carry a prefix */");
+
+    for name in EXPORTED_SYMBOLS {
+        fmt.write(format!("#define {0} {1}{0}", name, prefix));
+    }
+
+    fmt.blankline();
+}
+
+fn emit_rand(out: &mut Output) {
+    let fmt = out.declarations();
     fmt.write("/* RNG */");
 
     fmt.write("#ifndef STATIC_SEED");
@@ -104,13 +383,15 @@ fn emit_rand(fmt: &mut CFormatter<File>) {
     fmt.write("#endif");
     fmt.blankline();
 
-    fmt.write("static THREAD_LOCAL size_t rand_state = STATIC_SEED;");
-    fmt.blankline();
+    out.share("THREAD_LOCAL uint64_t rand_state");
+    out.primary.write("INTERNAL THREAD_LOCAL uint64_t rand_state = STATIC_SEED;");
+    out.primary.blankline();
 
+    let fmt = out.declarations();
     fmt.write("#ifndef DISABLE_rand");
-    fmt.write("static inline size_t rand (void) {");
+    fmt.write("static inline uint64_t rand (void) {");
     fmt.indent();
-    fmt.write("size_t x = rand_state;");
+    fmt.write("uint64_t x = rand_state;");
     fmt.write("x ^= x << 13;");
     fmt.write("x ^= x >> 7;");
     fmt.write("x ^= x << 17;");
@@ -118,13 +399,14 @@ fn emit_rand(fmt: &mut CFormatter<File>) {
     fmt.unindent();
     fmt.write("}");
     fmt.write("#else");
-    fmt.write("size_t rand (void);");
+    fmt.write("uint64_t rand (void);");
     fmt.write("#endif");
     fmt.blankline();
 
+    let fmt = &mut out.primary;
     fmt.write("#ifndef DISABLE_seed_generator");
     fmt.write("EXPORT_FUNCTION");
-    fmt.write("void seed_generator (size_t new_seed) {");
+    fmt.write("void seed_generator (uint64_t new_seed) {");
     fmt.indent();
     fmt.write("if (!new_seed) {");
     fmt.indent();
@@ -136,662 +418,3794 @@ fn emit_rand(fmt: &mut CFormatter<File>) {
     fmt.unindent();
     fmt.write("}");
     fmt.write("#else");
-    fmt.write("void seed_generator (size_t);");
+    fmt.write("void seed_generator (uint64_t);");
     fmt.write("#endif");
     fmt.blankline();
 }
 
-fn emit_types(fmt: &mut CFormatter<File>) {
-    fmt.write("// Used to represent a sequence of rules");
-    fmt.write("typedef struct {");
+fn emit_fingerprint(fingerprint: u64, fmt: &mut CFormatter<File>) {
+    fmt.write("// Identifies the grammar this code was generated from");
+    fmt.write("EXPORT_FUNCTION");
+    fmt.write("unsigned long long grammar_fingerprint (void) {");
     fmt.indent();
-    fmt.write("size_t* buf;");
-    fmt.write("size_t len;");
-    fmt.write("size_t capacity;");
+    fmt.write(format!("return {:#018x}ULL;", fingerprint));
     fmt.unindent();
-    fmt.write("} Sequence;");
+    fmt.write("}");
+    fmt.blankline();
+
+    fmt.write("// The fingerprint in hex, like ContextFreeGrammar::digest()");
+    fmt.write("EXPORT_FUNCTION");
+    fmt.write("const char* peacock_grammar_digest (void) {");
+    fmt.indent();
+    fmt.write(format!("return \"{:016x}\";", fingerprint));
+    fmt.unindent();
+    fmt.write("}");
     fmt.blankline();
 }
 
-fn emit_mutation_declarations(grammar: &LowLevelGrammar, fmt: &mut CFormatter<File>) {
-    fmt.write("/* Forward declarations for sequence mutation functions */");
+fn emit_output_bounds(grammar: &LowLevelGrammar, fmt: &mut CFormatter<File>) {
+    fmt.write("// Bounds on the number of bytes a serialized input can have");
+    fmt.write("EXPORT_FUNCTION");
+    fmt.write(format!("const size_t PEACOCK_MIN_OUTPUT_LEN = {}UL;", grammar.min_output_len()));
+    fmt.write("EXPORT_FUNCTION");
 
-    for nonterm in grammar.rules().keys() {
-        fmt.write(format!(
-            "static int mutate_seq_nonterm{} (size_t* const, size_t* const, const size_t, size_t* const);",
-            *nonterm
-        ));
+    if let Some(max) = grammar.max_output_len() {
+        fmt.write(format!("const size_t PEACOCK_MAX_OUTPUT_LEN = {}UL;", max));
+    } else {
+        fmt.write("const size_t PEACOCK_MAX_OUTPUT_LEN = SIZE_MAX;");
     }
 
     fmt.blankline();
 }
 
-fn emit_mutation_function_rule(rule: &[LLSymbol], fmt: &mut CFormatter<File>) {
-    for symbol in rule {
-        if let LLSymbol::NonTerminal(dst) = symbol {
-            fmt.write(format!("if (UNLIKELY(!mutate_seq_nonterm{}(buf, len, capacity, step))) {{", dst.id()));
-            fmt.indent();
-            fmt.write("return 0;");
-            fmt.unindent();
-            fmt.write("}");
-            fmt.blankline();
-        }
-    }
+fn is_dynamic(grammar: &LowLevelGrammar, nonterm: usize) -> bool {
+    grammar.dynamic().is_some_and(|dynamic| dynamic.id() == nonterm)
 }
 
-fn emit_mutation_function_single(rule: &[LLSymbol], fmt: &mut CFormatter<File>) {
-    fmt.write("size_t idx = *len;");
+/// A C condition that checks whether the variable `nonterm` is one of `nonterms`
+fn nonterm_condition(nonterms: &[usize]) -> String {
+    nonterms.iter().map(|nonterm| format!("nonterm == {}", nonterm)).join(" || ")
+}
+
+/// The ids of all builtins of `grammar`
+fn builtin_nonterminals(grammar: &LowLevelGrammar) -> Vec<usize> {
+    (0..grammar.nonterminals().len()).filter(|nonterm| grammar.builtin(*nonterm).is_some()).collect()
+}
+
+/// A C literal for the two's complement of `value`
+fn c_int64(value: i64) -> String {
+    format!("{:#x}ULL", value as u64)
+}
+
+/// The helpers that the functions of builtins share. Values of `@int` builtins are handled as two's complement
+/// in a `uint64_t` and the bytes of `@bytes` builtins are a splitmix64 stream, exactly like in [`Builtin`].
+fn emit_builtins(fmt: &mut CFormatter<File>) {
+    fmt.write("/* Builtins */");
+    fmt.write(
+        "// Write the decimal representation of the two's complement value x. Returns 0 if out_len is too small.",
+    );
+    fmt.write("static inline __attribute__((unused)) size_t builtin_int (uint64_t x, unsigned char* out, const size_t out_len) {");
+    fmt.indent();
+    fmt.write("unsigned char digits[20];");
+    fmt.write("size_t n = 0;");
+    fmt.write("size_t negative = (size_t) (x >> 63);");
+    fmt.write("uint64_t magnitude = negative ? 0 - x : x;");
     fmt.blankline();
-    fmt.write("if (*step >= idx) {");
+    fmt.write("do {");
     fmt.indent();
-    fmt.write("if (UNLIKELY(idx >= capacity)) {");
+    fmt.write("digits[n++] = (unsigned char) ('0' + magnitude % 10);");
+    fmt.write("magnitude /= 10;");
+    fmt.unindent();
+    fmt.write("} while (magnitude);");
+    fmt.blankline();
+    fmt.write("if (UNLIKELY(out_len < n + negative)) {");
     fmt.indent();
     fmt.write("return 0;");
     fmt.unindent();
     fmt.write("}");
     fmt.blankline();
-    fmt.write("buf[idx] = 0;");
-    fmt.write("*len = idx + 1;");
+    fmt.write("if (negative) {");
+    fmt.indent();
+    fmt.write("*out++ = '-';");
     fmt.unindent();
     fmt.write("}");
     fmt.blankline();
-
-    fmt.write("*step += 1;");
+    fmt.write("for (size_t i = n; i > 0; --i) {");
+    fmt.indent();
+    fmt.write("*out++ = digits[i - 1];");
+    fmt.unindent();
+    fmt.write("}");
+    fmt.blankline();
+    fmt.write("return n + negative;");
+    fmt.unindent();
+    fmt.write("}");
     fmt.blankline();
 
-    emit_mutation_function_rule(rule, fmt);
+    fmt.write("// Write len bytes of the splitmix64 stream that starts at seed");
+    fmt.write(
+        "static inline __attribute__((unused)) void builtin_bytes (uint64_t seed, unsigned char* out, size_t len) {",
+    );
+    fmt.indent();
+    fmt.write("while (len) {");
+    fmt.indent();
+    fmt.write("seed += 0x9E3779B97F4A7C15ULL;");
+    fmt.write("uint64_t z = seed;");
+    fmt.write("z = (z ^ (z >> 30)) * 0xBF58476D1CE4E5B9ULL;");
+    fmt.write("z = (z ^ (z >> 27)) * 0x94D049BB133111EBULL;");
+    fmt.write("z ^= z >> 31;");
+    fmt.blankline();
+    fmt.write("for (size_t i = 0; i < 8 && len; ++i, --len) {");
+    fmt.indent();
+    fmt.write("*out++ = (unsigned char) z;");
+    fmt.write("z >>= 8;");
+    fmt.unindent();
+    fmt.write("}");
+    fmt.unindent();
+    fmt.write("}");
+    fmt.unindent();
+    fmt.write("}");
+    fmt.blankline();
 
-    fmt.write("return 1;");
+    fmt.write("// Match the longest decimal number without leading zeros between min and max, which are two's complement values.");
+    fmt.write("// Returns its length and stores its value in *value or returns 0 if there is no such number.");
+    fmt.write("static inline __attribute__((unused)) size_t match_builtin_int (const unsigned char* input, const size_t input_len, const uint64_t min, const uint64_t max, uint64_t* const value) {");
+    fmt.indent();
+    fmt.write("const uint64_t sign = 1ULL << 63;");
+    fmt.write("size_t start = input_len > 0 && input[0] == '-';");
+    fmt.write("uint64_t limit = start ? sign : sign - 1;");
+    fmt.write("uint64_t magnitude = 0;");
+    fmt.write("size_t best = 0;");
+    fmt.blankline();
+    fmt.write("for (size_t i = start; i < input_len; ++i) {");
+    fmt.indent();
+    fmt.write("if (input[i] < '0' || input[i] > '9' || (i > start && input[start] == '0')) {");
+    fmt.indent();
+    fmt.write("break;");
+    fmt.unindent();
+    fmt.write("}");
+    fmt.blankline();
+    fmt.write("uint64_t digit = (uint64_t) (input[i] - '0');");
+    fmt.blankline();
+    fmt.write("if (magnitude > (limit - digit) / 10) {");
+    fmt.indent();
+    fmt.write("break;");
+    fmt.unindent();
+    fmt.write("}");
+    fmt.blankline();
+    fmt.write("magnitude = magnitude * 10 + digit;");
+    fmt.blankline();
+    fmt.write("// -0 is not a number");
+    fmt.write("if (start && magnitude == 0) {");
+    fmt.indent();
+    fmt.write("break;");
+    fmt.unindent();
+    fmt.write("}");
+    fmt.blankline();
+    fmt.write("uint64_t x = start ? 0 - magnitude : magnitude;");
+    fmt.blankline();
+    fmt.write("// Flipping the sign bit makes unsigned comparisons work for signed values");
+    fmt.write("if ((x ^ sign) >= (min ^ sign) && (x ^ sign) <= (max ^ sign)) {");
+    fmt.indent();
+    fmt.write("best = i + 1;");
+    fmt.write("*value = x;");
+    fmt.unindent();
+    fmt.write("}");
+    fmt.unindent();
+    fmt.write("}");
+    fmt.blankline();
+    fmt.write("return best;");
+    fmt.unindent();
+    fmt.write("}");
+    fmt.blankline();
 }
 
-fn emit_mutation_function_multiple(rules: &[Vec<LLSymbol>], fmt: &mut CFormatter<File>) {
-    let have_nonterminals = rules_have_nonterminals(rules);
-
-    fmt.write("size_t idx = *len;");
-    fmt.write("size_t target;");
+fn emit_dynamic_terminals(out: &mut Output) {
+    let fmt = out.declarations();
+    fmt.write("/* Terminals that are discovered at runtime */");
+    fmt.write("#ifndef DYNAMIC_TERMINALS_CAPACITY");
+    fmt.write(" #define DYNAMIC_TERMINALS_CAPACITY 256");
+    fmt.write("#endif");
+    fmt.write("#ifndef DYNAMIC_TERMINAL_MAX_LEN");
+    fmt.write(" #define DYNAMIC_TERMINAL_MAX_LEN 64");
+    fmt.write("#endif");
     fmt.blankline();
 
-    if have_nonterminals {
-        fmt.write("if (*step < idx) {");
-        fmt.indent();
-        fmt.write("target = buf[*step];");
-        fmt.unindent();
-        fmt.write("} else {");
-    } else {
-        fmt.write("if (*step >= idx) {");
-    }
+    out.share("unsigned char DYNAMIC_TERMINALS[DYNAMIC_TERMINALS_CAPACITY][DYNAMIC_TERMINAL_MAX_LEN]");
+    out.share("size_t DYNAMIC_TERMINAL_LENGTHS[DYNAMIC_TERMINALS_CAPACITY]");
+    out.share("size_t dynamic_terminals_count");
+
+    let fmt = &mut out.primary;
+    fmt.write("INTERNAL unsigned char DYNAMIC_TERMINALS[DYNAMIC_TERMINALS_CAPACITY][DYNAMIC_TERMINAL_MAX_LEN];");
+    fmt.write("INTERNAL size_t DYNAMIC_TERMINAL_LENGTHS[DYNAMIC_TERMINALS_CAPACITY];");
+    fmt.write("INTERNAL size_t dynamic_terminals_count = 0;");
+    fmt.blankline();
 
+    fmt.write("EXPORT_FUNCTION");
+    fmt.write("int add_dynamic_terminal (const unsigned char* data, size_t len) {");
     fmt.indent();
-    fmt.write("if (UNLIKELY(idx >= capacity)) {");
+    fmt.write("if (UNLIKELY(!data || !len || len > DYNAMIC_TERMINAL_MAX_LEN || dynamic_terminals_count >= DYNAMIC_TERMINALS_CAPACITY)) {");
     fmt.indent();
     fmt.write("return 0;");
     fmt.unindent();
     fmt.write("}");
     fmt.blankline();
-    fmt.write(format!("target = rand() % {};", rules.len()));
-    fmt.write("buf[idx] = target;");
-    fmt.write("*len = idx + 1;");
+    fmt.write("for (size_t i = 0; i < dynamic_terminals_count; ++i) {");
+    fmt.indent();
+    fmt.write("if (DYNAMIC_TERMINAL_LENGTHS[i] == len && peacock_memcmp(DYNAMIC_TERMINALS[i], data, len) == 0) {");
+    fmt.indent();
+    fmt.write("return 0;");
+    fmt.unindent();
+    fmt.write("}");
     fmt.unindent();
     fmt.write("}");
     fmt.blankline();
-
-    fmt.write("*step += 1;");
+    fmt.write("peacock_memcpy(DYNAMIC_TERMINALS[dynamic_terminals_count], data, len);");
+    fmt.write("DYNAMIC_TERMINAL_LENGTHS[dynamic_terminals_count] = len;");
+    fmt.write("dynamic_terminals_count += 1;");
+    fmt.write("return 1;");
+    fmt.unindent();
+    fmt.write("}");
     fmt.blankline();
+}
 
-    if have_nonterminals {
-        fmt.write("switch (target) {");
-        fmt.indent();
+/// Assign every rule of the grammar a global id. Returns the id of the first rule of each non-terminal.
+fn rule_offsets(grammar: &LowLevelGrammar) -> Vec<usize> {
+    let mut offsets = vec![0; grammar.nonterminals().len()];
+    let mut cursor = 0;
 
-        for (i, rule) in rules.iter().enumerate() {
-            fmt.write(format!("case {}: {{", i));
-            fmt.indent();
+    for nonterm in grammar.rules().keys().sorted() {
+        offsets[*nonterm] = cursor;
+        cursor += grammar.rules()[nonterm].len();
+    }
 
-            emit_mutation_function_rule(rule, fmt);
+    offsets
+}
 
-            fmt.write("break;");
-            fmt.unindent();
-            fmt.write("}");
+fn c_string(s: &str) -> String {
+    let mut ret = String::with_capacity(s.len() + 2);
+    ret.push('"');
+
+    for byte in s.bytes() {
+        if (byte.is_ascii_graphic() && byte != b'"' && byte != b'\\' && byte != b'?') || byte == b' ' {
+            ret.push(byte as char);
+        } else {
+            ret.push_str(&format!("\\{:03o}", byte));
         }
+    }
 
-        fmt.write("default: {");
-        fmt.indent();
-        fmt.write("__builtin_unreachable();");
-        fmt.unindent();
-        fmt.write("}");
+    ret.push('"');
+    ret
+}
 
-        fmt.unindent();
-        fmt.write("}");
-        fmt.blankline();
+fn emit_statistics(grammar: &LowLevelGrammar, offsets: &[usize], out: &mut Output) {
+    let num_rules: usize = grammar.rules().values().map(Vec::len).sum();
+    let mut names = vec![""; num_rules];
+
+    for (nonterm, rules) in grammar.rules() {
+        for i in 0..rules.len() {
+            names[offsets[*nonterm] + i] = &grammar.nonterminals()[*nonterm];
+        }
     }
 
-    fmt.write("return 1;");
-}
+    out.share(format!("size_t RULE_COUNTERS[{}]", num_rules));
 
-fn emit_mutation_function(
-    nonterm: usize,
-    rules: &[Vec<LLSymbol>],
-    grammar: &LowLevelGrammar,
-    fmt: &mut CFormatter<File>,
-) {
-    fmt.write(format!(
-        "// This is the sequence mutation function for non-terminal {:?}",
-        grammar.nonterminals()[nonterm]
-    ));
-    fmt.write(format!("static int mutate_seq_nonterm{} (size_t* const buf, size_t* const len, const size_t capacity, size_t* const step) {{", nonterm));
+    let fmt = &mut out.primary;
+    fmt.write("/* Statistics */");
+    fmt.write(format!("INTERNAL size_t RULE_COUNTERS[{}];", num_rules));
+    fmt.write(format!("static const char* RULE_NAMES[{}] = {{", num_rules));
     fmt.indent();
 
-    if rules.is_empty() {
-        unreachable!()
-    } else if rules.len() == 1 {
-        emit_mutation_function_single(&rules[0], fmt);
-    } else {
-        emit_mutation_function_multiple(rules, fmt);
+    for name in names {
+        fmt.write(format!("{},", c_string(name)));
     }
 
     fmt.unindent();
-    fmt.write("}");
+    fmt.write("};");
     fmt.blankline();
-}
 
-fn emit_mutation_entrypoint(grammar: &LowLevelGrammar, fmt: &mut CFormatter<File>) {
     fmt.write("EXPORT_FUNCTION");
-    fmt.write("size_t mutate_sequence (size_t* buf, size_t len, const size_t capacity) {");
-    fmt.indent();
-
-    #[cfg(feature = "debug-codegen")]
-    fmt.write("printf(\"Calling mutate_sequence(%p, %lu, %lu)\\n\", buf, len, capacity);");
-
-    fmt.write("if (UNLIKELY(!buf | !capacity)) {");
+    fmt.write("void get_rule_counters (size_t** out, size_t* len) {");
     fmt.indent();
-    fmt.write("return 0;");
+    fmt.write("*out = RULE_COUNTERS;");
+    fmt.write(format!("*len = {};", num_rules));
     fmt.unindent();
     fmt.write("}");
+    fmt.blankline();
 
-    fmt.write("size_t step = 0;");
-    fmt.write(format!("mutate_seq_nonterm{}(buf, &len, capacity, &step);", grammar.entrypoint().id()));
-    fmt.write("return len;");
-
+    fmt.write("EXPORT_FUNCTION");
+    fmt.write("void get_rule_names (const char*** out, size_t* len) {");
+    fmt.indent();
+    fmt.write("*out = RULE_NAMES;");
+    fmt.write(format!("*len = {};", num_rules));
     fmt.unindent();
     fmt.write("}");
     fmt.blankline();
 }
 
-fn emit_mutation_code(grammar: &LowLevelGrammar, fmt: &mut CFormatter<File>) {
-    emit_mutation_declarations(grammar, fmt);
-
-    for (nonterm, rules) in grammar.rules() {
-        emit_mutation_function(*nonterm, rules, grammar, fmt);
-    }
+fn emit_types(fmt: &mut CFormatter<File>) {
+    fmt.write("// Used to represent a sequence of rules");
+    fmt.write("typedef struct {");
+    fmt.indent();
+    fmt.write("size_t* buf;");
+    fmt.write("size_t len;");
+    fmt.write("size_t capacity;");
+    fmt.unindent();
+    fmt.write("} Sequence;");
+    fmt.blankline();
 
-    emit_mutation_entrypoint(grammar, fmt);
+    fmt.write("// Result of serialize_sequence_checked(), guarded like in the header");
+    fmt.write("#ifndef __PEACOCK_SERIALIZATION_RESULT");
+    fmt.write("#define __PEACOCK_SERIALIZATION_RESULT");
+    fmt.write("typedef struct {");
+    fmt.indent();
+    fmt.write("size_t len;");
+    fmt.write("int truncated;");
+    fmt.unindent();
+    fmt.write("} SerializationResult;");
+    fmt.write("#endif");
+    fmt.blankline();
 }
 
-fn emit_terminals(grammar: &LowLevelGrammar, fmt: &mut CFormatter<File>) {
-    fmt.write("/* Terminals */");
-
-    for (i, term) in grammar.terminals().iter().enumerate() {
-        let term = term.as_bytes();
-
-        fmt.write(format!("static const unsigned char TERM{}[{}] = {{", i, term.len()));
-        fmt.indent();
-
-        for chunk in term.chunks(8) {
-            let x: Vec<String> = chunk.iter().map(|x| format!("{:#02X},", *x)).collect();
-            fmt.write(x.join(" "));
-        }
+fn emit_mutation_declarations(grammar: &LowLevelGrammar, fmt: &mut CFormatter<File>) {
+    fmt.write("/* Forward declarations for sequence mutation functions */");
 
-        fmt.unindent();
-        fmt.write("};");
+    for nonterm in grammar.rules().keys() {
+        fmt.write(format!(
+            "INTERNAL int mutate_seq_nonterm{} (size_t* const, size_t* const, const size_t, size_t* const);",
+            *nonterm
+        ));
     }
 
     fmt.blankline();
 }
 
-fn emit_serialization_declarations(grammar: &LowLevelGrammar, fmt: &mut CFormatter<File>) {
-    fmt.write("/* Forward declarations for serialization functions */");
-
-    for nonterm in grammar.rules().keys() {
-        fmt.write(format!("static size_t serialize_seq_nonterm{} (const size_t* const, const size_t, unsigned char*, size_t, size_t* const);", *nonterm));
+fn emit_mutation_function_rule(rule: &[LLSymbol], fmt: &mut CFormatter<File>) {
+    if !rule_has_nonterminals(rule) {
+        return;
     }
 
+    /* A failed mutation starts over at depth 0, so only the successful path has to restore the depth */
+    fmt.write("mutation_depth += 1;");
     fmt.blankline();
-}
 
-fn emit_serialization_function_rule(rule: &[LLSymbol], fmt: &mut CFormatter<File>) {
     for symbol in rule {
-        match symbol {
-            LLSymbol::NonTerminal(nonterm) => {
-                fmt.write(format!("len = serialize_seq_nonterm{}(seq, seq_len, out, out_len, step);", nonterm.id()));
-                fmt.write("out += len; out_len -= len;");
-                fmt.blankline();
-            },
-            LLSymbol::Terminal(term) => {
-                fmt.write(format!("if (UNLIKELY(out_len < sizeof(TERM{}))) {{", term.id()));
-                fmt.indent();
-                fmt.write("goto end;");
-                fmt.unindent();
-                fmt.write("}");
-                fmt.write(format!("__builtin_memcpy_inline(out, TERM{0}, sizeof(TERM{0}));", term.id()));
-                fmt.write(format!("out += sizeof(TERM{0}); out_len -= sizeof(TERM{0});", term.id()));
-                fmt.blankline();
-            },
+        if let LLSymbol::NonTerminal(dst) = symbol {
+            fmt.write(format!("if (UNLIKELY(!mutate_seq_nonterm{}(buf, len, capacity, step))) {{", dst.id()));
+            fmt.indent();
+            fmt.write("return 0;");
+            fmt.unindent();
+            fmt.write("}");
+            fmt.blankline();
         }
     }
-}
 
-fn emit_serialization_function_single(rule: &[LLSymbol], fmt: &mut CFormatter<File>) {
-    let has_nonterminals = rule_has_nonterminals(rule);
-
-    if !has_nonterminals {
-        fmt.write("(void) seq;");
-        fmt.blankline();
-    }
+    fmt.write("mutation_depth -= 1;");
+    fmt.blankline();
+}
 
-    fmt.write("if (UNLIKELY(*step >= seq_len)) {");
+fn emit_mutation_function_single(rule: &[LLSymbol], fmt: &mut CFormatter<File>) {
+    fmt.write("size_t idx = *len;");
+    fmt.blankline();
+    fmt.write("if (*step < idx) {");
+    fmt.indent();
+    fmt.write("// After a point mutation, the step may have belonged to a different non-terminal");
+    fmt.write("buf[*step] = 0;");
+    fmt.unindent();
+    fmt.write("} else {");
+    fmt.indent();
+    fmt.write("if (UNLIKELY(idx >= capacity)) {");
     fmt.indent();
     fmt.write("return 0;");
     fmt.unindent();
     fmt.write("}");
     fmt.blankline();
+    fmt.write("buf[idx] = 0;");
+    fmt.write("*len = idx + 1;");
+    fmt.unindent();
+    fmt.write("}");
+    fmt.blankline();
 
-    if has_nonterminals {
-        fmt.write("size_t len;");
-    }
-
-    fmt.write("unsigned char* original_out = out;");
     fmt.write("*step += 1;");
     fmt.blankline();
 
-    emit_serialization_function_rule(rule, fmt);
+    emit_mutation_function_rule(rule, fmt);
+
+    fmt.write("return 1;");
+}
 
-    if rule_has_terminals(rule) {
-        fmt.write("end:");
+/// The body of a function for a non-terminal without rules, which always fails.
+/// [`LowLevelGrammar`] rejects such non-terminals but the generated code must not depend on that.
+fn emit_function_without_rules(params: &[&str], fmt: &mut CFormatter<File>) {
+    for param in params {
+        fmt.write(format!("(void) {};", param));
     }
-    fmt.write("return (size_t) (out - original_out);");
+
+    fmt.write("return 0;");
 }
 
-fn emit_serialization_function_multiple(rules: &[Vec<LLSymbol>], fmt: &mut CFormatter<File>) {
-    fmt.write("if (UNLIKELY(*step >= seq_len)) {");
+/// The default arm of a switch over the rules of a non-terminal
+fn emit_invalid_rule(fast: bool, bail_out: &str, fmt: &mut CFormatter<File>) {
+    fmt.write("default: {");
     fmt.indent();
-    fmt.write("return 0;");
-    fmt.unindent();
-    fmt.write("}");
-    fmt.blankline();
 
-    if rules_have_nonterminals(rules) {
-        fmt.write("size_t len;");
+    if fast {
+        fmt.write("__builtin_unreachable();");
+    } else {
+        /* The sequence might not have been created by this grammar */
+        fmt.write(bail_out);
     }
 
-    fmt.write("unsigned char* original_out = out;");
-    fmt.write("size_t target = seq[*step];");
-    fmt.write("*step += 1;");
-    fmt.blankline();
+    fmt.unindent();
+    fmt.write("}");
+}
 
-    fmt.write("switch (target) {");
-    fmt.indent();
+/// Choose a new rule such that recursive rules get less likely the deeper the derivation is
+/// and are never taken beyond the maximum depth.
+/// Without decay, rand() is consumed exactly like in the interpreter.
+fn emit_rule_choice(rules: &[Vec<LLSymbol>], recursive: &[bool], decay: bool, fmt: &mut CFormatter<File>) {
+    let (rec, nonrec): (Vec<usize>, Vec<usize>) = (0..rules.len()).partition(|i| recursive[*i]);
 
-    for (i, rule) in rules.iter().enumerate() {
-        fmt.write(format!("case {}: {{", i));
-        fmt.indent();
+    if rec.is_empty() || nonrec.is_empty() {
+        fmt.write(format!("target = rand() % {};", rules.len()));
+        return;
+    }
 
-        emit_serialization_function_rule(rule, fmt);
+    let list = |indices: &[usize]| indices.iter().map(|x| x.to_string()).join(", ");
 
-        fmt.write("break;");
+    if !decay {
+        fmt.write("if (UNLIKELY(mutation_depth >= max_depth)) {");
+        fmt.indent();
+        fmt.write(format!("static const size_t NONRECURSIVE[{}] = {{{}}};", nonrec.len(), list(&nonrec)));
+        fmt.write(format!("target = NONRECURSIVE[rand() % {}];", nonrec.len()));
+        fmt.unindent();
+        fmt.write("} else {");
+        fmt.indent();
+        fmt.write(format!("target = rand() % {};", rules.len()));
         fmt.unindent();
         fmt.write("}");
+        return;
     }
 
-    fmt.write("default: {");
+    fmt.write(format!("static const size_t NONRECURSIVE[{}] = {{{}}};", nonrec.len(), list(&nonrec)));
+    fmt.write(format!("static const size_t RECURSIVE[{}] = {{{}}};", rec.len(), list(&rec)));
+    fmt.write("uint64_t weight = recursion_weight();");
+    fmt.write(format!("uint64_t total = {}ULL * RECURSION_WEIGHT_ONE;", nonrec.len()));
+    fmt.write(format!("uint64_t x = rand() % (total + {}ULL * weight);", rec.len()));
+    fmt.blankline();
+    fmt.write("if (x < total) {");
     fmt.indent();
-    fmt.write("__builtin_unreachable();");
+    fmt.write("target = NONRECURSIVE[x / RECURSION_WEIGHT_ONE];");
     fmt.unindent();
-    fmt.write("}");
-
+    fmt.write("} else {");
+    fmt.indent();
+    fmt.write("target = RECURSIVE[(x - total) / weight];");
     fmt.unindent();
     fmt.write("}");
-    fmt.blankline();
-
-    if rules_have_terminals(rules) {
-        fmt.write("end:");
-    }
-    fmt.write("return (size_t) (out - original_out);");
 }
 
-fn emit_serialization_function(
-    nonterm: usize,
+fn emit_mutation_function_multiple(
     rules: &[Vec<LLSymbol>],
-    grammar: &LowLevelGrammar,
+    recursive: &[bool],
+    decay: bool,
+    fast: bool,
     fmt: &mut CFormatter<File>,
 ) {
-    fmt.write(format!("// This is the serialization function for non-terminal {:?}", grammar.nonterminals()[nonterm]));
-    fmt.write(format!("static size_t serialize_seq_nonterm{} (const size_t* const seq, const size_t seq_len, unsigned char* out, size_t out_len, size_t* const step) {{", nonterm));
-    fmt.indent();
-
-    #[cfg(feature = "debug-codegen")]
-    fmt.write(format!(
-        "printf(\"Serializing %s (%lu/%lu)\\n\", {:?}, *step + 1, seq_len);",
-        grammar.nonterminals()[nonterm]
-    ));
+    let have_nonterminals = rules_have_nonterminals(rules);
 
-    if rules.is_empty() {
-        unreachable!()
-    } else if rules.len() == 1 {
-        emit_serialization_function_single(&rules[0], fmt);
-    } else {
-        emit_serialization_function_multiple(rules, fmt);
-    }
+    fmt.write("size_t idx = *len;");
+    fmt.write("size_t target;");
+    fmt.blankline();
 
+    fmt.write("if (*step < idx) {");
+    fmt.indent();
+    fmt.write("target = buf[*step];");
+    fmt.blankline();
+    fmt.write("// Choose again at the point of a point mutation and wherever the old choice does not fit anymore");
+    fmt.write(format!("if (UNLIKELY(*step == mutation_point || target >= {})) {{", rules.len()));
+    fmt.indent();
+    fmt.write("if (*step == mutation_point) {");
+    fmt.indent();
+    fmt.write(format!("target = (target + 1 + rand() % {0}) % {1};", rules.len() - 1, rules.len()));
+    fmt.unindent();
+    fmt.write("} else {");
+    fmt.indent();
+    emit_rule_choice(rules, recursive, decay, fmt);
     fmt.unindent();
     fmt.write("}");
     fmt.blankline();
-}
-
-fn emit_serialization_entrypoint(grammar: &LowLevelGrammar, fmt: &mut CFormatter<File>) {
-    fmt.write("EXPORT_FUNCTION");
-    fmt.write("size_t serialize_sequence (const size_t* seq, const size_t seq_len, unsigned char* out, const size_t out_len) {");
+    fmt.write("buf[*step] = target;");
+    fmt.unindent();
+    fmt.write("}");
+    fmt.unindent();
+    fmt.write("} else {");
     fmt.indent();
-
-    fmt.write("if (UNLIKELY(!seq || !seq_len || !out || !out_len)) {");
+    fmt.write("if (UNLIKELY(idx >= capacity)) {");
     fmt.indent();
     fmt.write("return 0;");
     fmt.unindent();
     fmt.write("}");
-
-    fmt.write("size_t step = 0;");
-    fmt.write(format!("return serialize_seq_nonterm{}(seq, seq_len, out, out_len, &step);", grammar.entrypoint().id()));
+    fmt.blankline();
+    emit_rule_choice(rules, recursive, decay, fmt);
+    fmt.write("buf[idx] = target;");
+    fmt.write("*len = idx + 1;");
     fmt.unindent();
     fmt.write("}");
     fmt.blankline();
-}
 
-fn emit_serialization_code(grammar: &LowLevelGrammar, fmt: &mut CFormatter<File>) {
-    emit_terminals(grammar, fmt);
-    emit_serialization_declarations(grammar, fmt);
-
-    for (nonterm, rules) in grammar.rules() {
-        emit_serialization_function(*nonterm, rules, grammar, fmt);
-    }
+    fmt.write("*step += 1;");
+    fmt.blankline();
 
-    emit_serialization_entrypoint(grammar, fmt);
-}
+    if have_nonterminals {
+        fmt.write("switch (target) {");
+        fmt.indent();
 
-fn emit_header(
-    mut outfile: File,
-    mutations: bool,
-    serializations: bool,
-    unparsing: bool,
-) -> Result<(), std::io::Error> {
-    write!(
-        &mut outfile,
-        "
-#ifndef __PEACOCK_GENERATOR_H
-#define __PEACOCK_GENERATOR_H
+        for (i, rule) in rules.iter().enumerate() {
+            fmt.write(format!("case {}: {{", i));
+            fmt.indent();
 
-#include <stddef.h>
-"
-    )?;
+            emit_mutation_function_rule(rule, fmt);
 
-    if mutations {
-        writeln!(&mut outfile, "size_t mutate_sequence (size_t* buf, size_t len, const size_t capacity);")?;
-    }
+            fmt.write("break;");
+            fmt.unindent();
+            fmt.write("}");
+        }
 
-    if serializations {
-        writeln!(&mut outfile, "size_t serialize_sequence (const size_t* seq, const size_t seq_len, unsigned char* out, const size_t out_len);")?;
-    }
+        emit_invalid_rule(fast, "return 0;", fmt);
 
-    if unparsing {
-        writeln!(&mut outfile, "size_t unparse_sequence (size_t* seq_buf, const size_t seq_capacity, const unsigned char* input, const size_t input_len);")?;
+        fmt.unindent();
+        fmt.write("}");
+        fmt.blankline();
     }
 
-    write!(
-        &mut outfile,
-        "
-void seed_generator (size_t new_seed);
+    fmt.write("return 1;");
+}
 
-
-#endif /* __PEACOCK_GENERATOR_H */
-"
-    )?;
-
-    Ok(())
+/// Rule i > 0 of the dynamic non-terminal stands for the dynamic terminal i - 1
+fn emit_mutation_function_dynamic(fmt: &mut CFormatter<File>) {
+    fmt.write("size_t idx = *len;");
+    fmt.blankline();
+    fmt.write("if (*step >= idx) {");
+    fmt.indent();
+    fmt.write("if (UNLIKELY(idx >= capacity)) {");
+    fmt.indent();
+    fmt.write("return 0;");
+    fmt.unindent();
+    fmt.write("}");
+    fmt.blankline();
+    fmt.write("buf[idx] = rand() % (dynamic_terminals_count + 1);");
+    fmt.write("*len = idx + 1;");
+    fmt.unindent();
+    fmt.write("}");
+    fmt.blankline();
+    fmt.write("*step += 1;");
+    fmt.write("return 1;");
 }
 
-fn emit_unparsing_declarations(grammar: &LowLevelGrammar, fmt: &mut CFormatter<File>) {
-    fmt.write("/* Forward declarations for unparsing functions */");
-
-    for nonterm in grammar.rules().keys() {
-        fmt.write(format!("static int unparse_seq_nonterm{} (Sequence* const, const unsigned char* const, const size_t, size_t* const);", *nonterm));
+/// The step of a new value of `builtin` that is at most `span` above its minimum, chosen with the random number `x`.
+/// This has to match [`Builtin::choose()`].
+fn builtin_choice(builtin: &Builtin, span: u64) -> String {
+    let offset = |x: &str| if span == u64::MAX { x.to_string() } else { format!("{} % {}ULL", x, span + 1) };
+
+    match builtin {
+        Builtin::Int {
+            ..
+        } => offset("x"),
+        Builtin::Bytes {
+            ..
+        } => format!("(x & ~0xFFFFULL) | ({})", offset("(x & 0xFFFFULL)")),
     }
+}
+
+/// The step of a builtin holds its value. Every value is valid, so only a point mutation chooses a new one.
+fn emit_mutation_function_builtin(
+    nonterm: usize,
+    builtin: &Builtin,
+    profile: &GenerationProfile,
+    grammar: &LowLevelGrammar,
+    fmt: &mut CFormatter<File>,
+) {
+    let choice = builtin_choice(builtin, profile.scaled_span(builtin));
 
+    fmt.write(format!(
+        "// This is the sequence mutation function for non-terminal {:?}",
+        grammar.nonterminals()[nonterm]
+    ));
+    fmt.write(format!("INTERNAL int mutate_seq_nonterm{} (size_t* const buf, size_t* const len, const size_t capacity, size_t* const step) {{", nonterm));
+    fmt.indent();
+    fmt.write("size_t idx = *len;");
+    fmt.blankline();
+    fmt.write("if (*step < idx) {");
+    fmt.indent();
+    fmt.write("if (UNLIKELY(*step == mutation_point)) {");
+    fmt.indent();
+    fmt.write("uint64_t x = rand();");
+    fmt.write(format!("buf[*step] = (size_t) ({});", choice));
+    fmt.unindent();
+    fmt.write("}");
+    fmt.unindent();
+    fmt.write("} else {");
+    fmt.indent();
+    fmt.write("if (UNLIKELY(idx >= capacity)) {");
+    fmt.indent();
+    fmt.write("return 0;");
+    fmt.unindent();
+    fmt.write("}");
+    fmt.blankline();
+    fmt.write("uint64_t x = rand();");
+    fmt.write(format!("buf[idx] = (size_t) ({});", choice));
+    fmt.write("*len = idx + 1;");
+    fmt.unindent();
+    fmt.write("}");
+    fmt.blankline();
+    fmt.write("*step += 1;");
+    fmt.write("return 1;");
+    fmt.unindent();
+    fmt.write("}");
     fmt.blankline();
 }
 
-fn emit_unparsing_function(
+fn emit_mutation_function(
     nonterm: usize,
     rules: &[Vec<LLSymbol>],
     grammar: &LowLevelGrammar,
+    recursive: &[bool],
+    decay: bool,
+    fast: bool,
     fmt: &mut CFormatter<File>,
 ) {
-    fmt.write(format!("// This is the unparsing function for non-terminal {:?}", grammar.nonterminals()[nonterm]));
-    fmt.write(format!("static int unparse_seq_nonterm{} (Sequence* const seq, const unsigned char* const input, const size_t input_len, size_t* const cursor) {{", nonterm));
+    fmt.write(format!(
+        "// This is the sequence mutation function for non-terminal {:?}",
+        grammar.nonterminals()[nonterm]
+    ));
+    fmt.write(format!("INTERNAL int mutate_seq_nonterm{} (size_t* const buf, size_t* const len, const size_t capacity, size_t* const step) {{", nonterm));
     fmt.indent();
 
-    fmt.write("size_t seq_idx = seq->len;");
+    if is_dynamic(grammar, nonterm) {
+        emit_mutation_function_dynamic(fmt);
+    } else if rules.is_empty() {
+        emit_function_without_rules(&["buf", "len", "capacity", "step"], fmt);
+    } else if rules.len() == 1 {
+        emit_mutation_function_single(&rules[0], fmt);
+    } else {
+        emit_mutation_function_multiple(rules, recursive, decay, fast, fmt);
+    }
+
+    fmt.unindent();
+    fmt.write("}");
     fmt.blankline();
-    fmt.write("if (UNLIKELY(seq_idx >= seq->capacity)) {");
+}
+
+fn emit_mutation_entrypoint(grammar: &LowLevelGrammar, fmt: &mut CFormatter<File>) {
+    fmt.write("EXPORT_FUNCTION");
+    fmt.write("size_t mutate_sequence (size_t* buf, size_t len, const size_t capacity) {");
+    fmt.indent();
+
+    #[cfg(feature = "debug-codegen")]
+    fmt.write("printf(\"Calling mutate_sequence(%p, %lu, %lu)\\n\", buf, len, capacity);");
+
+    fmt.write("if (UNLIKELY(!buf | !capacity)) {");
     fmt.indent();
     fmt.write("return 0;");
     fmt.unindent();
     fmt.write("}");
-    fmt.blankline();
 
-    fmt.write("size_t target_cursor = 0;");
-    fmt.write("size_t target_id = (size_t) -1LL;");
-    fmt.write("size_t target_seq_len = seq_idx;");
+    fmt.write("size_t step = 0;");
+    fmt.write("mutation_depth = 0;");
+    fmt.write(format!("mutate_seq_nonterm{}(buf, &len, capacity, &step);", grammar.entrypoint().id()));
+    fmt.write("return len;");
+
+    fmt.unindent();
+    fmt.write("}");
     fmt.blankline();
 
-    for (i, rule) in rules.iter().enumerate().sorted_by(|(_, a), (_, b)| b.len().cmp(&a.len())) {
-        fmt.write(format!("// Rule #{}", i));
-        fmt.write("do {");
-        fmt.indent();
-        fmt.write("seq->len = seq_idx + 1;");
-        fmt.write("size_t tmp_cursor = *cursor;");
-        fmt.blankline();
+    fmt.write("EXPORT_FUNCTION");
+    fmt.write(
+        "size_t mutate_sequence_from (size_t* buf, size_t len, const size_t capacity, const size_t start_step) {",
+    );
+    fmt.indent();
+    fmt.write("if (UNLIKELY(!buf || start_step >= len || len > capacity)) {");
+    fmt.indent();
+    fmt.write("return len;");
+    fmt.unindent();
+    fmt.write("}");
+    fmt.blankline();
+    fmt.write("size_t step = 0;");
+    fmt.write("mutation_depth = 0;");
+    fmt.write("mutation_point = start_step;");
+    fmt.write(format!("int complete = mutate_seq_nonterm{}(buf, &len, capacity, &step);", grammar.entrypoint().id()));
+    fmt.write("mutation_point = (size_t) -1;");
+    fmt.blankline();
+    fmt.write("// The new derivation may be finished before the old walk ends");
+    fmt.write("if (complete && step < len) {");
+    fmt.indent();
+    fmt.write("len = step;");
+    fmt.unindent();
+    fmt.write("}");
+    fmt.blankline();
+    fmt.write("return len;");
+    fmt.unindent();
+    fmt.write("}");
+    fmt.blankline();
+}
 
-        for symbol in rule {
-            match symbol {
-                LLSymbol::Terminal(term) => {
-                    fmt.write(format!(
-                        "if (UNLIKELY(input_len - tmp_cursor < sizeof(TERM{0})) || __builtin_memcmp(&input[tmp_cursor], TERM{0}, sizeof(TERM{0})) != 0) {{",
-                        term.id()
-                    ));
-                    fmt.indent();
-                    fmt.write("break;");
-                    fmt.unindent();
-                    fmt.write("}");
-                    fmt.write(format!("tmp_cursor += sizeof(TERM{0});", term.id()));
-                    fmt.blankline();
-                },
-                LLSymbol::NonTerminal(nonterm) => {
-                    fmt.write(format!(
-                        "if (!unparse_seq_nonterm{}(seq, input, input_len, &tmp_cursor)) {{",
-                        nonterm.id()
-                    ));
-                    fmt.indent();
-                    fmt.write("break;");
-                    fmt.unindent();
-                    fmt.write("}");
-                    fmt.blankline();
-                },
+fn emit_rule_tables(grammar: &LowLevelGrammar, fmt: &mut CFormatter<File>) {
+    let offsets = rule_offsets(grammar);
+    let num_nonterms = grammar.nonterminals().len();
+    let mut counts = vec![0; num_nonterms];
+    let mut starts = vec![0];
+    let mut children = Vec::new();
+
+    for nonterm in grammar.rules().keys().sorted() {
+        counts[*nonterm] = grammar.rules()[nonterm].len();
+
+        for rule in &grammar.rules()[nonterm] {
+            for symbol in rule {
+                if let LLSymbol::NonTerminal(child) = symbol {
+                    children.push(child.id());
+                }
             }
+
+            starts.push(children.len());
         }
+    }
 
-        fmt.write("if (tmp_cursor > target_cursor) {");
+    fmt.write("/* The shape of all rules for walking sequences */");
+
+    let mut table = |name: &str, values: &[usize]| {
+        fmt.write(format!("static const size_t {}[{}] = {{", name, values.len().max(1)));
         fmt.indent();
-        fmt.write(format!("target_id = {};", i));
-        fmt.write("target_cursor = tmp_cursor;");
-        fmt.write("target_seq_len = seq->len;");
+        fmt.write(values.iter().map(|x| format!("{},", x)).join(" "));
         fmt.unindent();
-        fmt.write("}");
+        fmt.write("};");
+    };
+
+    table("RULE_OFFSETS", &offsets);
+    table("RULE_COUNTS", &counts);
+    table("RULE_CHILDREN_START", &starts);
+    table("RULE_CHILDREN", &children);
+    fmt.blankline();
+}
+
+fn emit_validation_code(grammar: &LowLevelGrammar, fmt: &mut CFormatter<File>) {
+    fmt.write("// Check that every step of the expansion at *step chooses an existing rule");
+    fmt.write("static int validate_subtree (const size_t* const seq, const size_t len, size_t* const step, const size_t nonterm) {");
+    fmt.indent();
+    fmt.write("size_t target = seq[*step];");
+    fmt.write("*step += 1;");
+    fmt.blankline();
 
+    if let Some(dynamic) = grammar.dynamic() {
+        fmt.write("// Sequences from earlier runs may refer to terminals that have not been discovered yet");
+        fmt.write(format!("if (nonterm == {}) {{", dynamic.id()));
+        fmt.indent();
+        fmt.write("return 1;");
         fmt.unindent();
-        fmt.write("} while (0);");
+        fmt.write("}");
         fmt.blankline();
     }
 
-    fmt.write("seq->len = target_seq_len;");
-    fmt.blankline();
+    let builtins = builtin_nonterminals(grammar);
 
-    fmt.write(format!("if (target_id < {}) {{", rules.len()));
+    if !builtins.is_empty() {
+        fmt.write("// Every value of a builtin is valid");
+        fmt.write(format!("if ({}) {{", nonterm_condition(&builtins)));
+        fmt.indent();
+        fmt.write("return 1;");
+        fmt.unindent();
+        fmt.write("}");
+        fmt.blankline();
+    }
+
+    fmt.write("if (target >= RULE_COUNTS[nonterm]) {");
     fmt.indent();
-    fmt.write("*cursor = target_cursor;");
-    fmt.write("seq->buf[seq_idx] = target_id;");
-    fmt.write("return 1;");
+    fmt.write("return 0;");
     fmt.unindent();
-    fmt.write("} else {");
+    fmt.write("}");
+    fmt.blankline();
+    fmt.write("size_t rule = RULE_OFFSETS[nonterm] + target;");
+    fmt.blankline();
+    fmt.write("for (size_t i = RULE_CHILDREN_START[rule]; i < RULE_CHILDREN_START[rule + 1] && *step < len; ++i) {");
+    fmt.indent();
+    fmt.write("if (!validate_subtree(seq, len, step, RULE_CHILDREN[i])) {");
     fmt.indent();
     fmt.write("return 0;");
     fmt.unindent();
     fmt.write("}");
-
     fmt.unindent();
     fmt.write("}");
     fmt.blankline();
-}
+    fmt.write("return 1;");
+    fmt.unindent();
+    fmt.write("}");
+    fmt.blankline();
 
-fn emit_unparsing_entrypoint(grammar: &LowLevelGrammar, fmt: &mut CFormatter<File>) {
     fmt.write("EXPORT_FUNCTION");
-    fmt.write("size_t unparse_sequence (size_t* seq_buf, const size_t seq_capacity, const unsigned char* input, const size_t input_len) {");
+    fmt.write("int validate_sequence (const size_t* seq, const size_t len) {");
     fmt.indent();
-
-    fmt.write("if (UNLIKELY(!seq_buf || !seq_capacity || !input || !input_len)) {");
+    fmt.write("if (!len) {");
     fmt.indent();
-    fmt.write("return 0;");
+    fmt.write("return 1;");
     fmt.unindent();
     fmt.write("}");
-
-    fmt.write("Sequence seq = {");
-    fmt.indent();
-    fmt.write(".buf = seq_buf,");
-    fmt.write(".len = 0,");
-    fmt.write(".capacity = seq_capacity,");
-    fmt.unindent();
-    fmt.write("};");
-    fmt.write("size_t cursor = 0;");
-    fmt.write(format!("if (!unparse_seq_nonterm{}(&seq, input, input_len, &cursor)) {{", grammar.entrypoint().id()));
+    fmt.blankline();
+    fmt.write("if (UNLIKELY(!seq)) {");
     fmt.indent();
     fmt.write("return 0;");
     fmt.unindent();
-    fmt.write("} else { ");
-    fmt.indent();
-    fmt.write("return seq.len;");
-    fmt.unindent();
     fmt.write("}");
+    fmt.blankline();
+    fmt.write("size_t step = 0;");
+    fmt.write(format!("return validate_subtree(seq, len, &step, {});", grammar.entrypoint().id()));
     fmt.unindent();
     fmt.write("}");
     fmt.blankline();
 }
 
-fn emit_unparsing_code(grammar: &LowLevelGrammar, fmt: &mut CFormatter<File>) {
-    emit_unparsing_declarations(grammar, fmt);
+fn emit_subtree_code(grammar: &LowLevelGrammar, fast: bool, fmt: &mut CFormatter<File>) {
+    let num_nonterms = grammar.nonterminals().len();
 
-    for (nonterm, rules) in grammar.rules() {
-        emit_unparsing_function(*nonterm, rules, grammar, fmt);
+    fmt.write(format!(
+        "static int (* const MUTATE_NONTERM[{}]) (size_t* const, size_t* const, const size_t, size_t* const) = {{",
+        num_nonterms.max(1)
+    ));
+    fmt.indent();
+
+    for nonterm in 0..num_nonterms {
+        if grammar.rules().contains_key(&nonterm) {
+            fmt.write(format!("mutate_seq_nonterm{},", nonterm));
+        } else {
+            fmt.write("NULL,");
+        }
     }
 
-    emit_unparsing_entrypoint(grammar, fmt);
-}
+    fmt.unindent();
+    fmt.write("};");
+    fmt.blankline();
 
-/// This is the main struct of the [`C`](crate::backends::C) backend that does all the heavy lifting and generates the code.
-///
-/// For documentation of the generated C code see the [README](https://github.com/z2-2z/peacock#c-api-documentation) of this project.
-pub struct CGenerator {
-    header: bool,
-    mutations: bool,
-    serializations: bool,
-    unparsing: bool,
-}
+    fmt.write("typedef struct {");
+    fmt.indent();
+    fmt.write("size_t index;");
+    fmt.write("size_t nonterm;");
+    fmt.write("size_t end;");
+    fmt.unindent();
+    fmt.write("} SubtreeQuery;");
+    fmt.blankline();
 
-impl CGenerator {
-    /// Create a new CGenerator.
-    #[allow(clippy::new_without_default)]
-    pub fn new() -> Self {
-        Self {
-            header: true,
-            mutations: true,
-            serializations: true,
-            unparsing: true,
-        }
-    }
+    fmt.write("// Walk the expansion at *step and record the extent of the expansion at query->index");
+    fmt.write("static void walk_subtree (const size_t* const seq, const size_t len, size_t* const step, const size_t nonterm, SubtreeQuery* const query) {");
+    fmt.indent();
+    fmt.write("size_t here = *step;");
+    fmt.write("*step += 1;");
 
-    /// Also generate a .h file with all the definitions of the public C API of the generated code.
-    ///
-    /// Default: `true`
-    pub fn generate_header(mut self, flag: bool) -> Self {
-        self.header = flag;
-        self
-    }
+    /* The steps of the dynamic non-terminal and of builtins hold values instead of rule indices */
+    let values: Vec<usize> =
+        grammar.dynamic().map(|dynamic| dynamic.id()).into_iter().chain(builtin_nonterminals(grammar)).collect();
 
-    /// Emit code that realizes the mutation of an automaton walk.
-    ///
-    /// Default: `true`
-    pub fn emit_mutation_procedure(mut self, flag: bool) -> Self {
-        self.mutations = flag;
-        self
+    if values.is_empty() {
+        fmt.write("size_t target = seq[here];");
+    } else {
+        fmt.write(format!("size_t target = ({}) ? 0 : seq[here];", nonterm_condition(&values)));
     }
 
-    /// Emit code that realizes the serialization of automaton walks into human-readable output.
+    if !fast {
+        fmt.blankline();
+        fmt.write("if (UNLIKELY(target >= RULE_COUNTS[nonterm])) {");
+        fmt.indent();
+        fmt.write("return;");
+        fmt.unindent();
+        fmt.write("}");
+    }
+
+    fmt.blankline();
+    fmt.write("size_t rule = RULE_OFFSETS[nonterm] + target;");
+    fmt.write("for (size_t i = RULE_CHILDREN_START[rule]; i < RULE_CHILDREN_START[rule + 1] && *step < len && !query->end; ++i) {");
+    fmt.indent();
+    fmt.write("walk_subtree(seq, len, step, RULE_CHILDREN[i], query);");
+    fmt.unindent();
+    fmt.write("}");
+    fmt.blankline();
+    fmt.write("if (here == query->index) {");
+    fmt.indent();
+    fmt.write("query->nonterm = nonterm;");
+    fmt.write("query->end = *step;");
+    fmt.unindent();
+    fmt.write("}");
+    fmt.unindent();
+    fmt.write("}");
+    fmt.blankline();
+
+    fmt.write("static SubtreeQuery find_subtree (const size_t* seq, const size_t len, const size_t index) {");
+    fmt.indent();
+    fmt.write("SubtreeQuery query = {index, 0, 0};");
+    fmt.write("size_t step = 0;");
+    fmt.blankline();
+    fmt.write("if (index < len) {");
+    fmt.indent();
+    fmt.write(format!("walk_subtree(seq, len, &step, {}, &query);", grammar.entrypoint().id()));
+    fmt.unindent();
+    fmt.write("}");
+    fmt.blankline();
+    fmt.write("return query;");
+    fmt.unindent();
+    fmt.write("}");
+    fmt.blankline();
+
+    fmt.write("EXPORT_FUNCTION");
+    fmt.write("size_t subtree_length (const size_t* seq, const size_t len, const size_t index) {");
+    fmt.indent();
+    fmt.write("SubtreeQuery query = find_subtree(seq, len, index);");
+    fmt.write("return query.end ? query.end - index : 0;");
+    fmt.unindent();
+    fmt.write("}");
+    fmt.blankline();
+
+    fmt.write("EXPORT_FUNCTION");
+    fmt.write("size_t mutate_sequence_inplace (size_t* buf, size_t len, const size_t capacity, const size_t index) {");
+    fmt.indent();
+    fmt.write("SubtreeQuery query = find_subtree(buf, len, index);");
+    fmt.blankline();
+    fmt.write("if (UNLIKELY(!query.end || len > capacity)) {");
+    fmt.indent();
+    fmt.write("return len;");
+    fmt.unindent();
+    fmt.write("}");
+    fmt.blankline();
+    fmt.write("// Park the tail at the end of the buffer while the new subtree is generated");
+    fmt.write("size_t tail_len = len - query.end;");
+    fmt.write("size_t* tail = buf + capacity - tail_len;");
+    fmt.write("peacock_memmove(tail, buf + query.end, tail_len * sizeof(size_t));");
+    fmt.blankline();
+    fmt.write("size_t subtree_len = 0;");
+    fmt.write("size_t step = 0;");
+    fmt.write("mutation_depth = 0;");
+    fmt.write(
+        "int complete = MUTATE_NONTERM[query.nonterm](buf + index, &subtree_len, capacity - tail_len - index, &step);",
+    );
+    fmt.blankline();
+    fmt.write("// If the new subtree does not fit, the tail cannot be reattached");
+    fmt.write("if (UNLIKELY(!complete)) {");
+    fmt.indent();
+    fmt.write("return index + subtree_len;");
+    fmt.unindent();
+    fmt.write("}");
+    fmt.blankline();
+    fmt.write("peacock_memmove(buf + index + subtree_len, tail, tail_len * sizeof(size_t));");
+    fmt.write("return index + subtree_len + tail_len;");
+    fmt.unindent();
+    fmt.write("}");
+    fmt.blankline();
+}
+
+/// The weight of the recursive rules at depth d is `RECURSION_WEIGHTS[d] / RECURSION_WEIGHT_ONE`
+/// relative to the non-recursive rules. The last entry holds for all deeper levels.
+fn recursion_weights(decay: f32) -> Vec<u64> {
+    const ONE: f64 = 65536.0;
+    const MAX_ENTRIES: usize = 256;
+    let mut weights = Vec::new();
+    let mut weight = ONE;
+
+    while weights.len() < MAX_ENTRIES {
+        let rounded = weight.round() as u64;
+        weights.push(rounded);
+
+        if rounded == 0 {
+            break;
+        }
+
+        weight *= decay as f64;
+    }
+
+    weights
+}
+
+fn emit_recursion_control(decay: f32, out: &mut Output) {
+    out.share("THREAD_LOCAL size_t mutation_depth");
+    out.share("size_t max_depth");
+    out.share("THREAD_LOCAL size_t mutation_point");
+    out.primary.write("/* Depth of the derivation that is currently being mutated */");
+    out.primary.write("INTERNAL THREAD_LOCAL size_t mutation_depth = 0;");
+    out.primary.write("INTERNAL size_t max_depth = (size_t) -1;");
+    out.primary.write("// The step whose rule mutate_sequence_from() chooses again");
+    out.primary.write("INTERNAL THREAD_LOCAL size_t mutation_point = (size_t) -1;");
+    out.primary.blankline();
+
+    if decay < 1.0 {
+        let weights = recursion_weights(decay);
+        let declaration = format!("const uint64_t RECURSION_WEIGHTS[{}]", weights.len());
+        out.share(&declaration);
+
+        let fmt = &mut out.primary;
+        fmt.write(format!("INTERNAL {} = {{", declaration));
+        fmt.indent();
+
+        for chunk in weights.chunks(8) {
+            fmt.write(chunk.iter().map(|x| format!("{},", x)).join(" "));
+        }
+
+        fmt.unindent();
+        fmt.write("};");
+        fmt.blankline();
+
+        let fmt = out.declarations();
+        fmt.write("#define RECURSION_WEIGHT_ONE 65536ULL");
+        fmt.blankline();
+        fmt.write("static inline uint64_t recursion_weight (void) {");
+        fmt.indent();
+        fmt.write("if (UNLIKELY(mutation_depth >= max_depth)) {");
+        fmt.indent();
+        fmt.write("return 0;");
+        fmt.unindent();
+        fmt.write("}");
+        fmt.blankline();
+        fmt.write(format!("return RECURSION_WEIGHTS[mutation_depth < {0} ? mutation_depth : {0}];", weights.len() - 1));
+        fmt.unindent();
+        fmt.write("}");
+        fmt.blankline();
+    }
+
+    let fmt = &mut out.primary;
+    fmt.write("EXPORT_FUNCTION");
+    fmt.write("void set_max_depth (size_t depth) {");
+    fmt.indent();
+    fmt.write("max_depth = depth;");
+    fmt.unindent();
+    fmt.write("}");
+    fmt.blankline();
+}
+
+fn emit_seeded_variants(fmt: &mut CFormatter<File>) {
+    fmt.write("// Turn a seed into an RNG state such that consecutive seeds give unrelated walks");
+    fmt.write("static uint64_t seeded_rand_state (uint64_t seed) {");
+    fmt.indent();
+    fmt.write("uint64_t x = seed + 0x9E3779B97F4A7C15ULL;");
+    fmt.write("x = (x ^ (x >> 30)) * 0xBF58476D1CE4E5B9ULL;");
+    fmt.write("x = (x ^ (x >> 27)) * 0x94D049BB133111EBULL;");
+    fmt.write("x ^= x >> 31;");
+    fmt.write("return x ? x : 0xDEADBEEF;");
+    fmt.unindent();
+    fmt.write("}");
+    fmt.blankline();
+
+    fmt.write("EXPORT_FUNCTION");
+    fmt.write("size_t mutate_sequence_seeded (uint64_t seed, size_t* buf, size_t len, const size_t capacity) {");
+    fmt.indent();
+    fmt.write("uint64_t saved_state = rand_state;");
+    fmt.write("rand_state = seeded_rand_state(seed);");
+    fmt.write("len = mutate_sequence(buf, len, capacity);");
+    fmt.write("rand_state = saved_state;");
+    fmt.write("return len;");
+    fmt.unindent();
+    fmt.write("}");
+    fmt.blankline();
+
+    fmt.write("EXPORT_FUNCTION");
+    fmt.write("size_t mutate_sequence_inplace_seeded (uint64_t seed, size_t* buf, size_t len, const size_t capacity, const size_t index) {");
+    fmt.indent();
+    fmt.write("uint64_t saved_state = rand_state;");
+    fmt.write("rand_state = seeded_rand_state(seed);");
+    fmt.write("len = mutate_sequence_inplace(buf, len, capacity, index);");
+    fmt.write("rand_state = saved_state;");
+    fmt.write("return len;");
+    fmt.unindent();
+    fmt.write("}");
+    fmt.blankline();
+}
+
+fn emit_mutation_code(
+    grammar: &LowLevelGrammar,
+    decay: f32,
+    fast: bool,
+    seeded: bool,
+    profile: &GenerationProfile,
+    out: &mut Output,
+) {
+    let recursive = grammar.recursive_rules();
+
+    emit_recursion_control(decay, out);
+    emit_mutation_declarations(grammar, out.declarations());
+
+    for (nonterm, rules) in grammar.rules() {
+        if let Some(builtin) = grammar.builtin(*nonterm) {
+            emit_mutation_function_builtin(*nonterm, builtin, profile, grammar, out.part(*nonterm));
+        } else {
+            emit_mutation_function(
+                *nonterm,
+                rules,
+                grammar,
+                &recursive[nonterm],
+                decay < 1.0,
+                fast,
+                out.part(*nonterm),
+            );
+        }
+    }
+
+    emit_mutation_entrypoint(grammar, &mut out.primary);
+    emit_subtree_code(grammar, fast, &mut out.primary);
+
+    if seeded {
+        emit_seeded_variants(&mut out.primary);
+    }
+}
+
+fn emit_terminal_array(name: &str, content: &[u8], out: &mut Output) {
+    let declaration = format!("const unsigned char {}[{}]", name, content.len().max(1));
+    out.share(&declaration);
+
+    let fmt = &mut out.primary;
+    fmt.write(format!("INTERNAL {} = {{", declaration));
+    fmt.indent();
+
+    for chunk in content.chunks(8) {
+        let x: Vec<String> = chunk.iter().map(|x| format!("{:#02X},", *x)).collect();
+        fmt.write(x.join(" "));
+    }
+
+    if content.is_empty() {
+        fmt.write("0,");
+    }
+
+    fmt.unindent();
+    fmt.write("};");
+}
+
+fn emit_terminals(terminals: &TerminalTable, out: &mut Output) {
+    out.primary.write("/* Terminals */");
+
+    if let Some(blob) = terminals.blob() {
+        emit_terminal_array("TERMS", blob, out);
+
+        /* The offset and the length of every terminal in TERMS */
+        let entries = terminals.entries();
+        let declaration = format!("const size_t TERMS_INDEX[{}][2]", entries.len().max(1));
+        out.share(&declaration);
+
+        let fmt = &mut out.primary;
+        fmt.write(format!("INTERNAL {} = {{", declaration));
+        fmt.indent();
+
+        for (offset, len) in entries {
+            fmt.write(format!("{{{}, {}}},", offset, len));
+        }
+
+        if entries.is_empty() {
+            fmt.write("{0, 0},");
+        }
+
+        fmt.unindent();
+        fmt.write("};");
+    } else {
+        for (i, term) in terminals.terminals().iter().enumerate() {
+            emit_terminal_array(&format!("TERM{}", i), term.as_bytes(), out);
+        }
+    }
+
+    out.primary.blankline();
+}
+
+fn emit_serialization_declarations(grammar: &LowLevelGrammar, out: &mut Output) {
+    out.share("THREAD_LOCAL int serialization_truncated");
+    out.primary.write("// Set when the output buffer was too small for a terminal");
+    out.primary.write("INTERNAL THREAD_LOCAL int serialization_truncated = 0;");
+    out.primary.blankline();
+
+    let fmt = out.declarations();
+    fmt.write("// Serialization stops when *step is set to this");
+    fmt.write("#define SEQUENCE_INVALID ((size_t) -1)");
+    fmt.blankline();
+    fmt.write("/* Forward declarations for serialization functions */");
+
+    for nonterm in grammar.rules().keys() {
+        fmt.write(format!("INTERNAL size_t serialize_seq_nonterm{} (const size_t* const, const size_t, unsigned char*, size_t, size_t* const);", *nonterm));
+    }
+
+    fmt.blankline();
+
+    if grammar.has_bindings() {
+        emit_serialization_bindings(grammar, out);
+    }
+}
+
+/// The binding stacks of the serializer. Bindings point into the output buffer, so uses copy the bytes that were
+/// written between the begin and the end marker. Bindings that are nested deeper than `BINDING_DEPTH` are ignored.
+fn emit_serialization_bindings(grammar: &LowLevelGrammar, out: &mut Output) {
+    let slots = grammar.bindings().len();
+
+    let fmt = out.declarations();
+    fmt.write("/* Forward declarations for bindings */");
+    fmt.write("INTERNAL void binding_begin (const size_t, unsigned char*);");
+    fmt.write("INTERNAL void binding_end (const size_t, unsigned char*);");
+    fmt.write("INTERNAL int binding_use (const size_t, const int, unsigned char**, size_t*);");
+    fmt.blankline();
+
+    let fmt = &mut out.primary;
+    fmt.write("/* Bindings */");
+    fmt.write("#ifndef BINDING_DEPTH");
+    fmt.write(" #define BINDING_DEPTH 64");
+    fmt.write("#endif");
+    fmt.write("#define BINDING_OPEN ((size_t) -1LL)");
+    fmt.blankline();
+    fmt.write("typedef struct {");
+    fmt.indent();
+    fmt.write("unsigned char* start;");
+    fmt.write("size_t len;");
+    fmt.unindent();
+    fmt.write("} Binding;");
+    fmt.blankline();
+
+    for (slot, name) in grammar.bindings().iter().enumerate() {
+        fmt.write(format!("// Slot {}: {}", slot, c_string(name)));
+    }
+    fmt.write(format!("INTERNAL THREAD_LOCAL Binding bindings[{}][BINDING_DEPTH];", slots));
+    fmt.write(format!("INTERNAL THREAD_LOCAL size_t binding_depths[{}];", slots));
+    fmt.blankline();
+
+    fmt.write("INTERNAL void binding_begin (const size_t slot, unsigned char* out) {");
+    fmt.indent();
+    fmt.write("size_t depth = binding_depths[slot]++;");
+    fmt.blankline();
+    fmt.write("if (LIKELY(depth < BINDING_DEPTH)) {");
+    fmt.indent();
+    fmt.write("bindings[slot][depth].start = out;");
+    fmt.write("bindings[slot][depth].len = BINDING_OPEN;");
+    fmt.unindent();
+    fmt.write("}");
+    fmt.unindent();
+    fmt.write("}");
+    fmt.blankline();
+
+    fmt.write("INTERNAL void binding_end (const size_t slot, unsigned char* out) {");
+    fmt.indent();
+    fmt.write("size_t depth = binding_depths[slot];");
+    fmt.blankline();
+    fmt.write("if (LIKELY(depth > 0 && depth <= BINDING_DEPTH) && bindings[slot][depth - 1].len == BINDING_OPEN) {");
+    fmt.indent();
+    fmt.write("bindings[slot][depth - 1].len = (size_t) (out - bindings[slot][depth - 1].start);");
+    fmt.unindent();
+    fmt.write("}");
+    fmt.unindent();
+    fmt.write("}");
+    fmt.blankline();
+
+    fmt.write("// Copy the innermost binding of a slot to the output and drop it if pop is set.");
+    fmt.write("// Returns 0 if the output buffer is too small.");
+    fmt.write("INTERNAL int binding_use (const size_t slot, const int pop, unsigned char** out, size_t* out_len) {");
+    fmt.indent();
+    fmt.write("size_t depth = binding_depths[slot];");
+    fmt.blankline();
+    fmt.write("if (UNLIKELY(depth > BINDING_DEPTH)) {");
+    fmt.indent();
+    fmt.write("binding_depths[slot] -= pop;");
+    fmt.write("return 1;");
+    fmt.unindent();
+    fmt.write("}");
+    fmt.blankline();
+    fmt.write("if (depth == 0 || bindings[slot][depth - 1].len == BINDING_OPEN) {");
+    fmt.indent();
+    fmt.write("return 1;");
+    fmt.unindent();
+    fmt.write("}");
+    fmt.blankline();
+    fmt.write("Binding* binding = &bindings[slot][depth - 1];");
+    fmt.blankline();
+    fmt.write("if (UNLIKELY(*out_len < binding->len)) {");
+    fmt.indent();
+    fmt.write("return 0;");
+    fmt.unindent();
+    fmt.write("}");
+    fmt.blankline();
+    fmt.write("peacock_memcpy(*out, binding->start, binding->len);");
+    fmt.write("*out += binding->len; *out_len -= binding->len;");
+    fmt.write("binding_depths[slot] -= pop;");
+    fmt.write("return 1;");
+    fmt.unindent();
+    fmt.write("}");
+    fmt.blankline();
+}
+
+fn emit_serialization_function_rule(
+    rule: &[LLSymbol],
+    terminals: &TerminalTable,
+    fast: bool,
+    fmt: &mut CFormatter<File>,
+) {
+    for symbol in rule {
+        match symbol {
+            LLSymbol::NonTerminal(nonterm) => {
+                fmt.write(format!("len = serialize_seq_nonterm{}(seq, seq_len, out, out_len, step);", nonterm.id()));
+                fmt.write("out += len; out_len -= len;");
+
+                if !fast {
+                    fmt.write("if (UNLIKELY(*step == SEQUENCE_INVALID)) {");
+                    fmt.indent();
+                    fmt.write("return (size_t) (out - original_out);");
+                    fmt.unindent();
+                    fmt.write("}");
+                }
+
+                fmt.blankline();
+            },
+            LLSymbol::Terminal(term) => {
+                let (_, len) = terminals.entry(term);
+                fmt.write(format!("if (UNLIKELY(out_len < {})) {{", len));
+                fmt.indent();
+                fmt.write("serialization_truncated = 1;");
+                fmt.write("goto end;");
+                fmt.unindent();
+                fmt.write("}");
+                fmt.write(format!("peacock_memcpy_inline(out, {}, {});", terminals.pointer(term), len));
+                fmt.write(format!("out += {0}; out_len -= {0};", len));
+                fmt.blankline();
+            },
+            LLSymbol::Repeat {
+                byte,
+                count,
+            } => {
+                fmt.write(format!("if (UNLIKELY(out_len < {})) {{", count));
+                fmt.indent();
+                fmt.write("serialization_truncated = 1;");
+                fmt.write("goto end;");
+                fmt.unindent();
+                fmt.write("}");
+                fmt.write(format!("peacock_memset(out, {}, {});", byte, count));
+                fmt.write(format!("out += {0}; out_len -= {0};", count));
+                fmt.blankline();
+            },
+            LLSymbol::Binding {
+                op,
+                slot,
+            } => {
+                match op {
+                    BindingOp::Begin => fmt.write(format!("binding_begin({}, out);", slot)),
+                    BindingOp::End => fmt.write(format!("binding_end({}, out);", slot)),
+                    BindingOp::Use | BindingOp::Pop => {
+                        let pop = (*op == BindingOp::Pop) as usize;
+                        fmt.write(format!("if (UNLIKELY(!binding_use({}, {}, &out, &out_len))) {{", slot, pop));
+                        fmt.indent();
+                        fmt.write("serialization_truncated = 1;");
+                        fmt.write("goto end;");
+                        fmt.unindent();
+                        fmt.write("}");
+                    },
+                }
+                fmt.blankline();
+            },
+        }
+    }
+}
+
+/// A derivation that spans multiple steps but always produces the same bytes
+struct CombinedExpansion<'a> {
+    content: &'a str,
+    steps: usize,
+    /// The rule counters of all steps
+    counters: Vec<usize>,
+}
+
+/// Serialize a combined expansion in one go if the walk and the output buffer are long enough
+fn emit_serialization_combined(expansion: &CombinedExpansion, terminals: &TerminalTable, fmt: &mut CFormatter<File>) {
+    let len = expansion.content.len();
+
+    fmt.write(format!("// The derivation always takes {} steps and produces {} bytes", expansion.steps, len));
+
+    if len == 0 {
+        fmt.write(format!("if (LIKELY(seq_len - *step >= {})) {{", expansion.steps));
+    } else {
+        fmt.write(format!("if (LIKELY(seq_len - *step >= {} && out_len >= {})) {{", expansion.steps, len));
+    }
+
+    fmt.indent();
+
+    if len > 0 {
+        let term = terminals.find(expansion.content).expect("Combined expansion is missing in the terminal table");
+        fmt.write(format!("peacock_memcpy_inline(out, {}, {});", terminals.pointer(&term), len));
+    }
+
+    fmt.write(format!("*step += {};", expansion.steps));
+
+    for counter in &expansion.counters {
+        fmt.write(format!("RULE_COUNTERS[{}]++;", counter));
+    }
+
+    fmt.write(format!("return {};", len));
+    fmt.unindent();
+    fmt.write("}");
+    fmt.blankline();
+}
+
+fn emit_serialization_function_single(
+    rule: &[LLSymbol],
+    terminals: &TerminalTable,
+    counter: Option<usize>,
+    combined: Option<CombinedExpansion>,
+    fast: bool,
+    fmt: &mut CFormatter<File>,
+) {
+    let has_nonterminals = rule_has_nonterminals(rule);
+
+    if !has_nonterminals {
+        fmt.write("(void) seq;");
+
+        if !rule_has_terminals(rule) {
+            fmt.write("(void) out_len;");
+        }
+
+        fmt.blankline();
+    }
+
+    fmt.write("if (UNLIKELY(*step >= seq_len)) {");
+    fmt.indent();
+    fmt.write("return 0;");
+    fmt.unindent();
+    fmt.write("}");
+    fmt.blankline();
+
+    if let Some(combined) = combined {
+        emit_serialization_combined(&combined, terminals, fmt);
+    }
+
+    if has_nonterminals {
+        fmt.write("size_t len;");
+    }
+
+    fmt.write("unsigned char* original_out = out;");
+    fmt.write("*step += 1;");
+
+    if let Some(counter) = counter {
+        fmt.write(format!("RULE_COUNTERS[{}]++;", counter));
+    }
+    fmt.blankline();
+
+    emit_serialization_function_rule(rule, terminals, fast, fmt);
+
+    if rule_has_terminals(rule) {
+        fmt.write("end:");
+    }
+    fmt.write("return (size_t) (out - original_out);");
+}
+
+fn emit_serialization_function_multiple(
+    rules: &[Vec<LLSymbol>],
+    terminals: &TerminalTable,
+    counters: Option<usize>,
+    fast: bool,
+    fmt: &mut CFormatter<File>,
+) {
+    if !rules_have_nonterminals(rules) && !rules_have_terminals(rules) {
+        fmt.write("(void) out_len;");
+        fmt.blankline();
+    }
+
+    fmt.write("if (UNLIKELY(*step >= seq_len)) {");
+    fmt.indent();
+    fmt.write("return 0;");
+    fmt.unindent();
+    fmt.write("}");
+    fmt.blankline();
+
+    if rules_have_nonterminals(rules) {
+        fmt.write("size_t len;");
+    }
+
+    fmt.write("unsigned char* original_out = out;");
+    fmt.write("size_t target = seq[*step];");
+    fmt.write("*step += 1;");
+    fmt.blankline();
+
+    fmt.write("switch (target) {");
+    fmt.indent();
+
+    for (i, rule) in rules.iter().enumerate() {
+        fmt.write(format!("case {}: {{", i));
+        fmt.indent();
+
+        if let Some(counters) = counters {
+            fmt.write(format!("RULE_COUNTERS[{}]++;", counters + i));
+        }
+
+        emit_serialization_function_rule(rule, terminals, fast, fmt);
+
+        fmt.write("break;");
+        fmt.unindent();
+        fmt.write("}");
+    }
+
+    emit_invalid_rule(fast, "*step = SEQUENCE_INVALID; return 0;", fmt);
+
+    fmt.unindent();
+    fmt.write("}");
+    fmt.blankline();
+
+    if rules_have_terminals(rules) {
+        fmt.write("end:");
+    }
+    fmt.write("return (size_t) (out - original_out);");
+}
+
+fn emit_serialization_function_dynamic(counter: Option<usize>, fmt: &mut CFormatter<File>) {
+    fmt.write("if (UNLIKELY(*step >= seq_len)) {");
+    fmt.indent();
+    fmt.write("return 0;");
+    fmt.unindent();
+    fmt.write("}");
+    fmt.blankline();
+
+    fmt.write("size_t target = seq[*step];");
+    fmt.write("*step += 1;");
+
+    if let Some(counter) = counter {
+        fmt.write(format!("RULE_COUNTERS[{}]++;", counter));
+    }
+    fmt.blankline();
+
+    fmt.write("// Sequences from earlier runs may refer to terminals that have not been discovered yet");
+    fmt.write("if (target == 0 || target > dynamic_terminals_count) {");
+    fmt.indent();
+    fmt.write("return 0;");
+    fmt.unindent();
+    fmt.write("}");
+    fmt.blankline();
+
+    fmt.write("size_t len = DYNAMIC_TERMINAL_LENGTHS[target - 1];");
+    fmt.blankline();
+    fmt.write("if (UNLIKELY(out_len < len)) {");
+    fmt.indent();
+    fmt.write("serialization_truncated = 1;");
+    fmt.write("return 0;");
+    fmt.unindent();
+    fmt.write("}");
+    fmt.blankline();
+    fmt.write("peacock_memcpy(out, DYNAMIC_TERMINALS[target - 1], len);");
+    fmt.write("return len;");
+}
+
+/// The step of a builtin holds its value, see [`Builtin::render()`]
+fn emit_serialization_function_builtin(builtin: &Builtin, counter: Option<usize>, fmt: &mut CFormatter<File>) {
+    fmt.write("if (UNLIKELY(*step >= seq_len)) {");
+    fmt.indent();
+    fmt.write("return 0;");
+    fmt.unindent();
+    fmt.write("}");
+    fmt.blankline();
+
+    fmt.write("uint64_t target = seq[*step];");
+    fmt.write("*step += 1;");
+
+    if let Some(counter) = counter {
+        fmt.write(format!("RULE_COUNTERS[{}]++;", counter));
+    }
+    fmt.blankline();
+
+    let span = builtin.span();
+
+    match builtin {
+        Builtin::Int {
+            min,
+            ..
+        } => {
+            if span == u64::MAX {
+                fmt.write(format!("size_t len = builtin_int({} + target, out, out_len);", c_int64(*min)));
+            } else {
+                fmt.write(format!(
+                    "size_t len = builtin_int({} + target % {}ULL, out, out_len);",
+                    c_int64(*min),
+                    span + 1
+                ));
+            }
+
+            fmt.blankline();
+            fmt.write("if (UNLIKELY(!len)) {");
+            fmt.indent();
+            fmt.write("serialization_truncated = 1;");
+            fmt.unindent();
+            fmt.write("}");
+            fmt.blankline();
+            fmt.write("return len;");
+        },
+        Builtin::Bytes {
+            min,
+            ..
+        } => {
+            fmt.write(format!("size_t len = {} + (size_t) ((target & 0xFFFFULL) % {}ULL);", min, span + 1));
+            fmt.blankline();
+            fmt.write("if (UNLIKELY(out_len < len)) {");
+            fmt.indent();
+            fmt.write("serialization_truncated = 1;");
+            fmt.write("return 0;");
+            fmt.unindent();
+            fmt.write("}");
+            fmt.blankline();
+            fmt.write("builtin_bytes(target >> 16, out, len);");
+            fmt.write("return len;");
+        },
+    }
+}
+
+fn emit_serialization_function(
+    nonterm: usize,
+    grammar: &LowLevelGrammar,
+    terminals: &TerminalTable,
+    offsets: Option<&[usize]>,
+    combined: Option<&(String, Vec<usize>)>,
+    fast: bool,
+    fmt: &mut CFormatter<File>,
+) {
+    let rules = &grammar.rules()[&nonterm];
+    let counters = offsets.map(|offsets| offsets[nonterm]);
+
+    fmt.write(format!("// This is the serialization function for non-terminal {:?}", grammar.nonterminals()[nonterm]));
+    fmt.write(format!("INTERNAL size_t serialize_seq_nonterm{} (const size_t* const seq, const size_t seq_len, unsigned char* out, size_t out_len, size_t* const step) {{", nonterm));
+    fmt.indent();
+
+    #[cfg(feature = "debug-codegen")]
+    fmt.write(format!(
+        "printf(\"Serializing %s (%lu/%lu)\\n\", {:?}, *step + 1, seq_len);",
+        grammar.nonterminals()[nonterm]
+    ));
+
+    if let Some(builtin) = grammar.builtin(nonterm) {
+        emit_serialization_function_builtin(builtin, counters, fmt);
+    } else if is_dynamic(grammar, nonterm) {
+        emit_serialization_function_dynamic(counters, fmt);
+    } else if rules.is_empty() {
+        emit_function_without_rules(&["seq", "seq_len", "out", "out_len", "step"], fmt);
+    } else if rules.len() == 1 {
+        let combined = combined.map(|(content, nonterminals)| CombinedExpansion {
+            content,
+            steps: nonterminals.len(),
+            counters: offsets
+                .map(|offsets| nonterminals.iter().map(|nonterm| offsets[*nonterm]).collect())
+                .unwrap_or_default(),
+        });
+        emit_serialization_function_single(&rules[0], terminals, counters, combined, fast, fmt);
+    } else {
+        emit_serialization_function_multiple(rules, terminals, counters, fast, fmt);
+    }
+
+    fmt.unindent();
+    fmt.write("}");
+    fmt.blankline();
+}
+
+fn emit_serialization_entrypoint(grammar: &LowLevelGrammar, fmt: &mut CFormatter<File>) {
+    fmt.write("EXPORT_FUNCTION");
+    fmt.write("size_t serialize_sequence (const size_t* seq, const size_t seq_len, unsigned char* out, const size_t out_len) {");
+    fmt.indent();
+
+    fmt.write("if (UNLIKELY(!seq || !seq_len || !out || !out_len)) {");
+    fmt.indent();
+    fmt.write("return 0;");
+    fmt.unindent();
+    fmt.write("}");
+
+    if grammar.has_bindings() {
+        fmt.write("peacock_memset(binding_depths, 0, sizeof(binding_depths));");
+    }
+
+    fmt.write("size_t step = 0;");
+    fmt.write(format!("return serialize_seq_nonterm{}(seq, seq_len, out, out_len, &step);", grammar.entrypoint().id()));
+    fmt.unindent();
+    fmt.write("}");
+    fmt.blankline();
+}
+
+fn emit_serialization_checked_entrypoint(fmt: &mut CFormatter<File>) {
+    fmt.write("EXPORT_FUNCTION");
+    fmt.write("void serialize_sequence_checked (const size_t* seq, const size_t seq_len, unsigned char* out, const size_t out_len, SerializationResult* result) {");
+    fmt.indent();
+    fmt.write("serialization_truncated = 0;");
+    fmt.write("result->len = serialize_sequence(seq, seq_len, out, out_len);");
+    fmt.write("result->truncated = serialization_truncated;");
+    fmt.unindent();
+    fmt.write("}");
+    fmt.blankline();
+}
+
+fn emit_serialization_code(
+    grammar: &LowLevelGrammar,
+    terminals: &TerminalTable,
+    offsets: Option<&[usize]>,
+    combined: &HashMap<usize, (String, Vec<usize>)>,
+    fast: bool,
+    out: &mut Output,
+) {
+    emit_serialization_declarations(grammar, out);
+
+    for nonterm in grammar.rules().keys() {
+        let expansion = combined.get(nonterm);
+        emit_serialization_function(*nonterm, grammar, terminals, offsets, expansion, fast, out.part(*nonterm));
+    }
+
+    emit_serialization_entrypoint(grammar, &mut out.primary);
+    emit_serialization_checked_entrypoint(&mut out.primary);
+}
+
+fn emit_header(mut outfile: File, generator: &CGenerator, dynamic: bool) -> Result<(), std::io::Error> {
+    let prefix = &generator.prefix;
+    let guard = format!("__PEACOCK_GENERATOR_{}H", prefix.to_uppercase());
+
+    write!(
+        &mut outfile,
+        "
+#ifndef {guard}
+#define {guard}
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern \"C\" {{
+#endif
+"
+    )?;
+
+    if generator.mutations {
+        writeln!(&mut outfile, "size_t {prefix}mutate_sequence (size_t* buf, size_t len, const size_t capacity);")?;
+        writeln!(
+            &mut outfile,
+            "size_t {prefix}mutate_sequence_inplace (size_t* buf, size_t len, const size_t capacity, const size_t index);"
+        )?;
+        writeln!(
+            &mut outfile,
+            "size_t {prefix}mutate_sequence_from (size_t* buf, size_t len, const size_t capacity, const size_t start_step);"
+        )?;
+        writeln!(
+            &mut outfile,
+            "size_t {prefix}subtree_length (const size_t* seq, const size_t len, const size_t index);"
+        )?;
+        writeln!(&mut outfile, "void {prefix}set_max_depth (size_t depth);")?;
+    }
+
+    if generator.mutations && generator.seeded {
+        writeln!(
+            &mut outfile,
+            "size_t {prefix}mutate_sequence_seeded (uint64_t seed, size_t* buf, size_t len, const size_t capacity);"
+        )?;
+        writeln!(&mut outfile, "size_t {prefix}mutate_sequence_inplace_seeded (uint64_t seed, size_t* buf, size_t len, const size_t capacity, const size_t index);")?;
+    }
+
+    if generator.serializations {
+        writeln!(&mut outfile, "size_t {prefix}serialize_sequence (const size_t* seq, const size_t seq_len, unsigned char* out, const size_t out_len);")?;
+        writeln!(&mut outfile, "#ifndef __PEACOCK_SERIALIZATION_RESULT")?;
+        writeln!(&mut outfile, "#define __PEACOCK_SERIALIZATION_RESULT")?;
+        writeln!(&mut outfile, "typedef struct {{ size_t len; int truncated; }} SerializationResult;")?;
+        writeln!(&mut outfile, "#endif")?;
+        writeln!(&mut outfile, "void {prefix}serialize_sequence_checked (const size_t* seq, const size_t seq_len, unsigned char* out, const size_t out_len, SerializationResult* result);")?;
+    }
+
+    if generator.unparsing {
+        writeln!(&mut outfile, "size_t {prefix}unparse_sequence (size_t* seq_buf, const size_t seq_capacity, const unsigned char* input, const size_t input_len);")?;
+        writeln!(&mut outfile, "size_t {prefix}unparse_sequence_bounded (size_t* seq_buf, size_t seq_capacity, const unsigned char* input, const size_t input_len, const size_t max_bytes, const size_t max_steps, int* truncated);")?;
+        writeln!(&mut outfile, "size_t {prefix}unparse_failure (const char** nonterm);")?;
+    }
+
+    if generator.unparsing && generator.memoized_unparsing {
+        writeln!(&mut outfile, "size_t {prefix}unparse_sequence_mem (size_t* seq_buf, const size_t seq_capacity, const unsigned char* input, const size_t input_len, void* scratch, const size_t scratch_len);")?;
+    }
+
+    if dynamic {
+        writeln!(&mut outfile, "int {prefix}add_dynamic_terminal (const unsigned char* data, size_t len);")?;
+    }
+
+    writeln!(&mut outfile, "int {prefix}validate_sequence (const size_t* seq, const size_t len);")?;
+
+    if generator.statistics {
+        writeln!(&mut outfile, "void {prefix}get_rule_counters (size_t** out, size_t* len);")?;
+        writeln!(&mut outfile, "void {prefix}get_rule_names (const char*** out, size_t* len);")?;
+    }
+
+    write!(
+        &mut outfile,
+        "
+void {prefix}seed_generator (uint64_t new_seed);
+unsigned long long {prefix}grammar_fingerprint (void);
+const char* {prefix}peacock_grammar_digest (void);
+extern const size_t {prefix}PEACOCK_MIN_OUTPUT_LEN;
+extern const size_t {prefix}PEACOCK_MAX_OUTPUT_LEN;
+
+#ifdef __cplusplus
+}}
+#endif
+
+#endif /* {guard} */
+"
+    )?;
+
+    Ok(())
+}
+
+fn emit_unparsing_diagnostics(grammar: &LowLevelGrammar, out: &mut Output) {
+    let num_nonterms = grammar.nonterminals().len();
+
+    out.share("THREAD_LOCAL size_t unparse_furthest");
+    out.share("THREAD_LOCAL size_t unparse_furthest_nonterm");
+
+    let fmt = out.declarations();
+    fmt.write("// Remember the furthest position in the input where a terminal did not match");
+    fmt.write("#undef UNPARSE_FAILED");
+    fmt.write("#define UNPARSE_FAILED(cursor, nonterm) do { if ((cursor) > unparse_furthest || unparse_furthest_nonterm == (size_t) -1LL) { unparse_furthest = (cursor); unparse_furthest_nonterm = (nonterm); } } while (0)");
+    fmt.blankline();
+
+    let fmt = &mut out.primary;
+    fmt.write("/* Unparsing diagnostics */");
+    fmt.write("INTERNAL THREAD_LOCAL size_t unparse_furthest = 0;");
+    fmt.write("INTERNAL THREAD_LOCAL size_t unparse_furthest_nonterm = (size_t) -1LL;");
+    fmt.write(format!("static const char* NONTERMINAL_NAMES[{}] = {{", num_nonterms));
+    fmt.indent();
+
+    for name in grammar.nonterminals() {
+        fmt.write(format!("{},", c_string(name)));
+    }
+
+    fmt.unindent();
+    fmt.write("};");
+    fmt.blankline();
+
+    fmt.write("EXPORT_FUNCTION");
+    fmt.write("size_t unparse_failure (const char** nonterm) {");
+    fmt.indent();
+    fmt.write("if (nonterm) {");
+    fmt.indent();
+    fmt.write(format!(
+        "*nonterm = (unparse_furthest_nonterm < {}) ? NONTERMINAL_NAMES[unparse_furthest_nonterm] : NULL;",
+        num_nonterms
+    ));
+    fmt.unindent();
+    fmt.write("}");
+    fmt.write("return unparse_furthest;");
+    fmt.unindent();
+    fmt.write("}");
+    fmt.blankline();
+}
+
+fn emit_unparsing_bounds(grammar: &LowLevelGrammar, out: &mut Output) {
+    out.share("THREAD_LOCAL int unparse_prefix");
+    out.share("THREAD_LOCAL int unparse_overflow");
+
+    let fmt = &mut out.primary;
+    fmt.write("/* State of the bounded unparser */");
+    fmt.write("// Set while unparsing a cut off input, the walk then only covers a prefix of a derivation");
+    fmt.write("INTERNAL THREAD_LOCAL int unparse_prefix = 0;");
+    fmt.write("// Set when the sequence ran out of capacity");
+    fmt.write("INTERNAL THREAD_LOCAL int unparse_overflow = 0;");
+    fmt.blankline();
+
+    let fmt = out.declarations();
+    fmt.write("// Whether the rest of a cut off input is a prefix of the terminal");
+    fmt.write("static inline __attribute__((unused)) int match_cut_terminal (const unsigned char* input, const size_t available, const unsigned char* terminal, const size_t len) {");
+    fmt.indent();
+    fmt.write("return unparse_prefix && available < len && peacock_memcmp(input, terminal, available) == 0;");
+    fmt.unindent();
+    fmt.write("}");
+    fmt.blankline();
+
+    if grammar.has_repeats() {
+        fmt.write("// Whether the rest of a cut off input is a prefix of the run");
+        fmt.write("static inline __attribute__((unused)) int match_cut_repeat (const unsigned char* input, const size_t available, const unsigned char byte, const size_t count) {");
+        fmt.indent();
+        fmt.write("return unparse_prefix && available < count && match_repeat(input, byte, available);");
+        fmt.unindent();
+        fmt.write("}");
+        fmt.blankline();
+    }
+}
+
+/// The bindings of the unparsers. Like in the interpreter, bindings are ranges of the input in an arena whose
+/// nodes never change, such that the state of all bindings can be saved and restored by copying the heads of the stacks.
+fn emit_unparsing_bindings(grammar: &LowLevelGrammar, out: &mut Output) {
+    let slots = grammar.bindings().len();
+
+    let fmt = out.declarations();
+    fmt.write("/* Bindings of the unparser */");
+
+    for op in [BindingOp::Begin, BindingOp::End, BindingOp::Use, BindingOp::Pop] {
+        fmt.write(format!("#define {} {}", binding_op_macro(op), op as usize));
+    }
+
+    fmt.blankline();
+    fmt.write("typedef struct {");
+    fmt.indent();
+    fmt.write(format!("size_t tops[{}];", slots));
+    fmt.write("size_t len;");
+    fmt.unindent();
+    fmt.write("} UnparseBindings;");
+    fmt.blankline();
+    fmt.write("INTERNAL int unparse_binding (const size_t, const int, const unsigned char* const, const size_t, size_t* const);");
+    fmt.blankline();
+
+    out.share("THREAD_LOCAL UnparseBindings unparse_bindings");
+
+    let fmt = &mut out.primary;
+    fmt.write("#ifndef UNPARSE_BINDINGS_CAPACITY");
+    fmt.write(" #define UNPARSE_BINDINGS_CAPACITY 4096");
+    fmt.write("#endif");
+    fmt.write("#define UNPARSE_BINDING_NONE ((size_t) -1LL)");
+    fmt.blankline();
+    fmt.write("typedef struct {");
+    fmt.indent();
+    fmt.write("size_t start;");
+    fmt.write("size_t end;");
+    fmt.write("size_t prev;");
+    fmt.unindent();
+    fmt.write("} UnparseBinding;");
+    fmt.blankline();
+    fmt.write("INTERNAL THREAD_LOCAL UnparseBinding UNPARSE_BINDING_NODES[UNPARSE_BINDINGS_CAPACITY];");
+    fmt.write("INTERNAL THREAD_LOCAL UnparseBindings unparse_bindings;");
+    fmt.blankline();
+
+    fmt.write("static void reset_unparse_bindings (void) {");
+    fmt.indent();
+    fmt.write(format!("for (size_t i = 0; i < {}; ++i) {{", slots));
+    fmt.indent();
+    fmt.write("unparse_bindings.tops[i] = UNPARSE_BINDING_NONE;");
+    fmt.unindent();
+    fmt.write("}");
+    fmt.write("unparse_bindings.len = 0;");
+    fmt.unindent();
+    fmt.write("}");
+    fmt.blankline();
+
+    fmt.write("// Apply a marker to the bindings of a slot while matching the input at *cursor.");
+    fmt.write("// Returns 0 if a use does not repeat the bytes of its binding or if the arena is full.");
+    fmt.write("INTERNAL int unparse_binding (const size_t slot, const int op, const unsigned char* const input, const size_t input_len, size_t* const cursor) {");
+    fmt.indent();
+    fmt.write("size_t top = unparse_bindings.tops[slot];");
+    fmt.blankline();
+    fmt.write("if (op == BINDING_BEGIN || op == BINDING_END) {");
+    fmt.indent();
+    fmt.write("if (op == BINDING_END && (top == UNPARSE_BINDING_NONE || UNPARSE_BINDING_NODES[top].end != UNPARSE_BINDING_NONE)) {");
+    fmt.indent();
+    fmt.write("return 1;");
+    fmt.unindent();
+    fmt.write("}");
+    fmt.blankline();
+    fmt.write("if (UNLIKELY(unparse_bindings.len >= UNPARSE_BINDINGS_CAPACITY)) {");
+    fmt.indent();
+    fmt.write("unparse_overflow = 1;");
+    fmt.write("return 0;");
+    fmt.unindent();
+    fmt.write("}");
+    fmt.blankline();
+    fmt.write("UnparseBinding* node = &UNPARSE_BINDING_NODES[unparse_bindings.len];");
+    fmt.blankline();
+    fmt.write("if (op == BINDING_BEGIN) {");
+    fmt.indent();
+    fmt.write("node->start = *cursor;");
+    fmt.write("node->end = UNPARSE_BINDING_NONE;");
+    fmt.write("node->prev = top;");
+    fmt.unindent();
+    fmt.write("} else {");
+    fmt.indent();
+    fmt.write("*node = UNPARSE_BINDING_NODES[top];");
+    fmt.write("node->end = *cursor;");
+    fmt.unindent();
+    fmt.write("}");
+    fmt.blankline();
+    fmt.write("unparse_bindings.tops[slot] = unparse_bindings.len++;");
+    fmt.write("return 1;");
+    fmt.unindent();
+    fmt.write("}");
+    fmt.blankline();
+    fmt.write("if (top == UNPARSE_BINDING_NONE || UNPARSE_BINDING_NODES[top].end == UNPARSE_BINDING_NONE) {");
+    fmt.indent();
+    fmt.write("return 1;");
+    fmt.unindent();
+    fmt.write("}");
+    fmt.blankline();
+    fmt.write("const unsigned char* bound = &input[UNPARSE_BINDING_NODES[top].start];");
+    fmt.write("size_t len = UNPARSE_BINDING_NODES[top].end - UNPARSE_BINDING_NODES[top].start;");
+    fmt.blankline();
+    fmt.write("if (UNLIKELY(input_len - *cursor < len) || peacock_memcmp(&input[*cursor], bound, len) != 0) {");
+    fmt.indent();
+    fmt.write("if (!match_cut_terminal(&input[*cursor], input_len - *cursor, bound, len)) {");
+    fmt.indent();
+    fmt.write("return 0;");
+    fmt.unindent();
+    fmt.write("}");
+    fmt.write("*cursor = input_len;");
+    fmt.unindent();
+    fmt.write("} else {");
+    fmt.indent();
+    fmt.write("*cursor += len;");
+    fmt.unindent();
+    fmt.write("}");
+    fmt.blankline();
+    fmt.write("if (op == BINDING_POP) {");
+    fmt.indent();
+    fmt.write("unparse_bindings.tops[slot] = UNPARSE_BINDING_NODES[top].prev;");
+    fmt.unindent();
+    fmt.write("}");
+    fmt.write("return 1;");
+    fmt.unindent();
+    fmt.write("}");
+    fmt.blankline();
+}
+
+/// The memo table of the greedy unparser. It maps a non-terminal and an offset into the input to the result of its
+/// unparsing function and lives in the scratch buffer of `unparse_sequence_mem()`. The table is a cache:
+/// results that collide with others may get evicted, successful results are only remembered while the arena
+/// for their steps has room left.
+fn emit_unparsing_memo(out: &mut Output) {
+    let fmt = out.declarations();
+    fmt.write("/* Memo table of the unparser */");
+    fmt.write("#define UNPARSE_MEMO_EMPTY ((size_t) -1LL)");
+    fmt.write("#define UNPARSE_MEMO_FAILED ((size_t) -1LL)");
+    fmt.write("#define UNPARSE_MEMO_PROBES 4");
+    fmt.write("#define UNPARSE_MEMO_MIN_SLOTS 64");
+    fmt.blankline();
+    fmt.write("typedef struct {");
+    fmt.indent();
+    fmt.write("size_t nonterm;");
+    fmt.write("size_t cursor;");
+    fmt.write("size_t end;");
+    fmt.write("size_t offset;");
+    fmt.write("size_t len;");
+    fmt.unindent();
+    fmt.write("} UnparseMemo;");
+    fmt.blankline();
+    fmt.write("typedef struct {");
+    fmt.indent();
+    fmt.write("UnparseMemo* slots;");
+    fmt.write("size_t mask;");
+    fmt.write("size_t* arena;");
+    fmt.write("size_t arena_len;");
+    fmt.write("size_t arena_capacity;");
+    fmt.unindent();
+    fmt.write("} UnparseMemoTable;");
+    fmt.blankline();
+    fmt.write("INTERNAL int unparse_memo_lookup (const size_t, Sequence* const, size_t* const);");
+    fmt.write("INTERNAL void unparse_memo_store (const size_t, const size_t, const Sequence* const, const size_t, const size_t);");
+    fmt.blankline();
+
+    out.share("THREAD_LOCAL UnparseMemoTable unparse_memo");
+
+    let fmt = &mut out.primary;
+    fmt.write("// Only set while unparse_sequence_mem() is running");
+    fmt.write("INTERNAL THREAD_LOCAL UnparseMemoTable unparse_memo;");
+    fmt.blankline();
+
+    fmt.write("static inline size_t unparse_memo_hash (const size_t nonterm, const size_t cursor) {");
+    fmt.indent();
+    fmt.write(
+        "uint64_t h = ((uint64_t) cursor * 0x9E3779B97F4A7C15ULL) ^ ((uint64_t) nonterm * 0xBF58476D1CE4E5B9ULL);",
+    );
+    fmt.write("return (size_t) (h ^ (h >> 32));");
+    fmt.unindent();
+    fmt.write("}");
+    fmt.blankline();
+
+    fmt.write(
+        "// Returns the remembered result of the unparsing function of nonterm at *cursor and appends its steps to seq",
+    );
+    fmt.write("// or returns -1 if the result is not known");
+    fmt.write("INTERNAL int unparse_memo_lookup (const size_t nonterm, Sequence* const seq, size_t* const cursor) {");
+    fmt.indent();
+    fmt.write(
+        "// Results that ran out of room are not remembered, trying all other alternatives would take exponential time",
+    );
+    fmt.write("if (UNLIKELY(unparse_overflow)) {");
+    fmt.indent();
+    fmt.write("return 0;");
+    fmt.unindent();
+    fmt.write("}");
+    fmt.blankline();
+    fmt.write("size_t h = unparse_memo_hash(nonterm, *cursor);");
+    fmt.blankline();
+    fmt.write("for (size_t i = 0; i < UNPARSE_MEMO_PROBES; ++i) {");
+    fmt.indent();
+    fmt.write("const UnparseMemo* memo = &unparse_memo.slots[(h + i) & unparse_memo.mask];");
+    fmt.blankline();
+    fmt.write("if (memo->nonterm == UNPARSE_MEMO_EMPTY) {");
+    fmt.indent();
+    fmt.write("break;");
+    fmt.unindent();
+    fmt.write("}");
+    fmt.blankline();
+    fmt.write("if (memo->nonterm != nonterm || memo->cursor != *cursor) {");
+    fmt.indent();
+    fmt.write("continue;");
+    fmt.unindent();
+    fmt.write("}");
+    fmt.blankline();
+    fmt.write("if (memo->end == UNPARSE_MEMO_FAILED) {");
+    fmt.indent();
+    fmt.write("return 0;");
+    fmt.unindent();
+    fmt.write("}");
+    fmt.blankline();
+    fmt.write("if (UNLIKELY(seq->capacity - seq->len < memo->len)) {");
+    fmt.indent();
+    fmt.write("unparse_overflow = 1;");
+    fmt.write("return 0;");
+    fmt.unindent();
+    fmt.write("}");
+    fmt.blankline();
+    fmt.write("peacock_memcpy(&seq->buf[seq->len], &unparse_memo.arena[memo->offset], memo->len * sizeof(size_t));");
+    fmt.write("seq->len += memo->len;");
+    fmt.write("*cursor = memo->end;");
+    fmt.write("return 1;");
+    fmt.unindent();
+    fmt.write("}");
+    fmt.blankline();
+    fmt.write("return -1;");
+    fmt.unindent();
+    fmt.write("}");
+    fmt.blankline();
+
+    fmt.write("// Remember the result of the unparsing function of nonterm at cursor, end is UNPARSE_MEMO_FAILED if it did not match.");
+    fmt.write("// The steps of a match are the steps of seq after seq_idx.");
+    fmt.write("INTERNAL void unparse_memo_store (const size_t nonterm, const size_t cursor, const Sequence* const seq, const size_t seq_idx, const size_t end) {");
+    fmt.indent();
+    fmt.write("size_t len = (end == UNPARSE_MEMO_FAILED) ? 0 : seq->len - seq_idx;");
+    fmt.blankline();
+    fmt.write("// Results that ran out of room depend on where in the sequence they started");
+    fmt.write("if (unparse_overflow || unparse_memo.arena_capacity - unparse_memo.arena_len < len) {");
+    fmt.indent();
+    fmt.write("return;");
+    fmt.unindent();
+    fmt.write("}");
+    fmt.blankline();
+    fmt.write("size_t h = unparse_memo_hash(nonterm, cursor);");
+    fmt.write("UnparseMemo* memo = &unparse_memo.slots[h & unparse_memo.mask];");
+    fmt.blankline();
+    fmt.write("// Take the first free slot or evict the first one");
+    fmt.write("for (size_t i = 0; i < UNPARSE_MEMO_PROBES; ++i) {");
+    fmt.indent();
+    fmt.write("UnparseMemo* candidate = &unparse_memo.slots[(h + i) & unparse_memo.mask];");
+    fmt.blankline();
+    fmt.write("if (candidate->nonterm == UNPARSE_MEMO_EMPTY) {");
+    fmt.indent();
+    fmt.write("memo = candidate;");
+    fmt.write("break;");
+    fmt.unindent();
+    fmt.write("}");
+    fmt.unindent();
+    fmt.write("}");
+    fmt.blankline();
+    fmt.write("memo->nonterm = nonterm;");
+    fmt.write("memo->cursor = cursor;");
+    fmt.write("memo->end = end;");
+    fmt.write("memo->offset = unparse_memo.arena_len;");
+    fmt.write("memo->len = len;");
+    fmt.write("peacock_memcpy(&unparse_memo.arena[unparse_memo.arena_len], &seq->buf[seq_idx], len * sizeof(size_t));");
+    fmt.write("unparse_memo.arena_len += len;");
+    fmt.unindent();
+    fmt.write("}");
+    fmt.blankline();
+}
+
+/// A non-terminal at the end of a cut off input is not expanded
+fn emit_unparsing_cut(fmt: &mut CFormatter<File>) {
+    fmt.write("if (UNLIKELY(unparse_prefix && *cursor == input_len)) {");
+    fmt.indent();
+    fmt.write("return 1;");
+    fmt.unindent();
+    fmt.write("}");
+    fmt.blankline();
+}
+
+fn emit_unparsing_declarations(grammar: &LowLevelGrammar, fmt: &mut CFormatter<File>) {
+    fmt.write("/* Forward declarations for unparsing functions */");
+
+    for nonterm in grammar.rules().keys() {
+        fmt.write(format!("INTERNAL int unparse_seq_nonterm{} (Sequence* const, const unsigned char* const, const size_t, size_t* const);", *nonterm));
+    }
+
+    fmt.blankline();
+}
+
+fn emit_match_repeat(fmt: &mut CFormatter<File>) {
+    fmt.write("// Check whether the next count bytes of the input are all equal to byte");
+    fmt.write("static inline __attribute__((unused)) int match_repeat (const unsigned char* input, const unsigned char byte, size_t count) {");
+    fmt.indent();
+    fmt.write("for (; count > 0; --count, ++input) {");
+    fmt.indent();
+    fmt.write("if (*input != byte) {");
+    fmt.indent();
+    fmt.write("return 0;");
+    fmt.unindent();
+    fmt.write("}");
+    fmt.unindent();
+    fmt.write("}");
+    fmt.blankline();
+    fmt.write("return 1;");
+    fmt.unindent();
+    fmt.write("}");
+    fmt.blankline();
+}
+
+fn emit_unparsing_function_dynamic(nonterm: usize, grammar: &LowLevelGrammar, fmt: &mut CFormatter<File>) {
+    fmt.write(format!("// This is the unparsing function for non-terminal {:?}", grammar.nonterminals()[nonterm]));
+    fmt.write(format!("INTERNAL int unparse_seq_nonterm{} (Sequence* const seq, const unsigned char* const input, const size_t input_len, size_t* const cursor) {{", nonterm));
+    fmt.indent();
+
+    emit_unparsing_cut(fmt);
+
+    fmt.write("size_t seq_idx = seq->len;");
+    fmt.blankline();
+    fmt.write("if (UNLIKELY(seq_idx >= seq->capacity)) {");
+    fmt.indent();
+    fmt.write("unparse_overflow = 1;");
+    fmt.write("return 0;");
+    fmt.unindent();
+    fmt.write("}");
+    fmt.blankline();
+
+    fmt.write("size_t target_id = 0;");
+    fmt.write("size_t target_len = 0;");
+    fmt.blankline();
+    fmt.write("// Match the longest dynamic terminal or nothing");
+    fmt.write("for (size_t i = 0; i < dynamic_terminals_count; ++i) {");
+    fmt.indent();
+    fmt.write("size_t len = DYNAMIC_TERMINAL_LENGTHS[i];");
+    fmt.blankline();
+    fmt.write("if (len > target_len && input_len - *cursor >= len && peacock_memcmp(&input[*cursor], DYNAMIC_TERMINALS[i], len) == 0) {");
+    fmt.indent();
+    fmt.write("target_id = i + 1;");
+    fmt.write("target_len = len;");
+    fmt.unindent();
+    fmt.write("}");
+    fmt.unindent();
+    fmt.write("}");
+    fmt.blankline();
+
+    fmt.write("seq->buf[seq_idx] = target_id;");
+    fmt.write("seq->len = seq_idx + 1;");
+    fmt.write("*cursor += target_len;");
+    fmt.write("return 1;");
+
+    fmt.unindent();
+    fmt.write("}");
+    fmt.blankline();
+}
+
+/// Builtins match like [`Builtin::unparse()`]: `@int` matches its longest value and `@bytes` only the empty string
+fn emit_unparsing_function_builtin(
+    nonterm: usize,
+    builtin: &Builtin,
+    grammar: &LowLevelGrammar,
+    fmt: &mut CFormatter<File>,
+) {
+    fmt.write(format!("// This is the unparsing function for non-terminal {:?}", grammar.nonterminals()[nonterm]));
+    fmt.write(format!("INTERNAL int unparse_seq_nonterm{} (Sequence* const seq, const unsigned char* const input, const size_t input_len, size_t* const cursor) {{", nonterm));
+    fmt.indent();
+
+    emit_unparsing_cut(fmt);
+
+    /* Random bytes cannot be recovered from the input */
+    if let Builtin::Bytes {
+        min: 1..,
+        ..
+    } = builtin
+    {
+        fmt.write("(void) seq;");
+        fmt.write("(void) input;");
+        fmt.write(format!("UNPARSE_FAILED(*cursor, {});", nonterm));
+        fmt.write("return 0;");
+        fmt.unindent();
+        fmt.write("}");
+        fmt.blankline();
+        return;
+    }
+
+    fmt.write("size_t seq_idx = seq->len;");
+    fmt.blankline();
+    fmt.write("if (UNLIKELY(seq_idx >= seq->capacity)) {");
+    fmt.indent();
+    fmt.write("unparse_overflow = 1;");
+    fmt.write("return 0;");
+    fmt.unindent();
+    fmt.write("}");
+    fmt.blankline();
+
+    match builtin {
+        Builtin::Int {
+            min,
+            max,
+        } => {
+            fmt.write("uint64_t value = 0;");
+            fmt.write(format!(
+                "size_t len = match_builtin_int(&input[*cursor], input_len - *cursor, {}, {}, &value);",
+                c_int64(*min),
+                c_int64(*max)
+            ));
+            fmt.blankline();
+            fmt.write("if (!len) {");
+            fmt.indent();
+            fmt.write(format!("UNPARSE_FAILED(*cursor, {});", nonterm));
+            fmt.write("return 0;");
+            fmt.unindent();
+            fmt.write("}");
+            fmt.blankline();
+            fmt.write(format!("seq->buf[seq_idx] = (size_t) (value - {});", c_int64(*min)));
+            fmt.write("seq->len = seq_idx + 1;");
+            fmt.write("*cursor += len;");
+            fmt.write("return 1;");
+        },
+        Builtin::Bytes {
+            ..
+        } => {
+            fmt.write("(void) input;");
+            fmt.write("seq->buf[seq_idx] = 0;");
+            fmt.write("seq->len = seq_idx + 1;");
+            fmt.write("return 1;");
+        },
+    }
+
+    fmt.unindent();
+    fmt.write("}");
+    fmt.blankline();
+}
+
+fn emit_unparsing_function(
+    nonterm: usize,
+    rules: &[Vec<LLSymbol>],
+    grammar: &LowLevelGrammar,
+    terminals: &TerminalTable,
+    frequencies: Option<&[usize]>,
+    memoized: bool,
+    fmt: &mut CFormatter<File>,
+) {
+    fmt.write(format!("// This is the unparsing function for non-terminal {:?}", grammar.nonterminals()[nonterm]));
+    fmt.write(format!("INTERNAL int unparse_seq_nonterm{} (Sequence* const seq, const unsigned char* const input, const size_t input_len, size_t* const cursor) {{", nonterm));
+    fmt.indent();
+
+    if !rules_have_nonterminals(rules) && !rules_have_terminals(rules) {
+        fmt.write("(void) input;");
+        fmt.blankline();
+    }
+
+    emit_unparsing_cut(fmt);
+
+    fmt.write("size_t seq_idx = seq->len;");
+    fmt.blankline();
+    fmt.write("if (UNLIKELY(seq_idx >= seq->capacity)) {");
+    fmt.indent();
+    fmt.write("unparse_overflow = 1;");
+    fmt.write("return 0;");
+    fmt.unindent();
+    fmt.write("}");
+    fmt.blankline();
+
+    if memoized {
+        fmt.write("if (unparse_memo.slots) {");
+        fmt.indent();
+        fmt.write(format!("int known = unparse_memo_lookup({}, seq, cursor);", nonterm));
+        fmt.blankline();
+        fmt.write("if (known >= 0) {");
+        fmt.indent();
+        fmt.write("return known;");
+        fmt.unindent();
+        fmt.write("}");
+        fmt.unindent();
+        fmt.write("}");
+        fmt.blankline();
+    }
+
+    fmt.write("size_t target_cursor = 0;");
+    fmt.write("size_t target_id = (size_t) -1LL;");
+    fmt.write("size_t target_seq_len = seq_idx;");
+    fmt.blankline();
+
+    /* Every alternative starts with the bindings before the non-terminal, the winner's bindings are kept */
+    let bindings = grammar.has_bindings() && rules.len() > 1;
+
+    if bindings {
+        fmt.write("UnparseBindings initial_bindings = unparse_bindings;");
+        fmt.write("UnparseBindings target_bindings = unparse_bindings;");
+        fmt.blankline();
+    }
+
+    /* On ties the first alternative wins, so prefer longer terminals to resolve '=' vs. '==' correctly */
+    let frequency = |i: usize| frequencies.and_then(|frequencies| frequencies.get(i)).copied().unwrap_or(0);
+    let order = rules.iter().enumerate().sorted_by(|(i, a), (j, b)| {
+        terminal_prefix_len(b, terminals)
+            .cmp(&terminal_prefix_len(a, terminals))
+            .then(b.len().cmp(&a.len()))
+            .then(frequency(*j).cmp(&frequency(*i)))
+    });
+
+    for (n, (i, rule)) in order.enumerate() {
+        fmt.write(format!("// Rule #{}", i));
+
+        /* No alternative can consume more than the whole input */
+        if n > 0 {
+            fmt.write("if (target_cursor < input_len) do {");
+        } else {
+            fmt.write("do {");
+        }
+
+        fmt.indent();
+        fmt.write("seq->len = seq_idx + 1;");
+        fmt.write("size_t tmp_cursor = *cursor;");
+
+        if bindings {
+            fmt.write("unparse_bindings = initial_bindings;");
+            fmt.write("unparse_bindings.len = target_bindings.len;");
+        }
+
+        fmt.blankline();
+
+        for symbol in rule {
+            match symbol {
+                LLSymbol::Terminal(term) => {
+                    let (_, len) = terminals.entry(term);
+                    let pointer = terminals.pointer(term);
+                    emit_unparsing_match(
+                        format!(
+                            "UNLIKELY(input_len - tmp_cursor < {1}) || peacock_memcmp(&input[tmp_cursor], {0}, {1}) != 0",
+                            pointer, len
+                        ),
+                        format!(
+                            "match_cut_terminal(&input[tmp_cursor], input_len - tmp_cursor, {}, {})",
+                            pointer, len
+                        ),
+                        len,
+                        nonterm,
+                        fmt,
+                    );
+                },
+                LLSymbol::Repeat {
+                    byte,
+                    count,
+                } => {
+                    emit_unparsing_match(
+                        format!(
+                            "UNLIKELY(input_len - tmp_cursor < {1}) || !match_repeat(&input[tmp_cursor], {0}, {1})",
+                            byte, count
+                        ),
+                        format!("match_cut_repeat(&input[tmp_cursor], input_len - tmp_cursor, {}, {})", byte, count),
+                        *count,
+                        nonterm,
+                        fmt,
+                    );
+                },
+                LLSymbol::NonTerminal(nonterm) => {
+                    fmt.write(format!(
+                        "if (!unparse_seq_nonterm{}(seq, input, input_len, &tmp_cursor)) {{",
+                        nonterm.id()
+                    ));
+                    fmt.indent();
+                    fmt.write("break;");
+                    fmt.unindent();
+                    fmt.write("}");
+                    fmt.blankline();
+                },
+                LLSymbol::Binding {
+                    op,
+                    slot,
+                } => {
+                    fmt.write(format!(
+                        "if (!unparse_binding({}, {}, input, input_len, &tmp_cursor)) {{",
+                        slot,
+                        binding_op_macro(*op)
+                    ));
+                    fmt.indent();
+                    fmt.write(format!("UNPARSE_FAILED(tmp_cursor, {});", nonterm));
+                    fmt.write("break;");
+                    fmt.unindent();
+                    fmt.write("}");
+                    fmt.blankline();
+                },
+            }
+        }
+
+        fmt.write("if (tmp_cursor > target_cursor) {");
+        fmt.indent();
+        fmt.write(format!("target_id = {};", i));
+        fmt.write("target_cursor = tmp_cursor;");
+        fmt.write("target_seq_len = seq->len;");
+
+        if bindings {
+            fmt.write("target_bindings = unparse_bindings;");
+        }
+
+        fmt.unindent();
+        fmt.write("}");
+
+        fmt.unindent();
+        fmt.write("} while (0);");
+        fmt.blankline();
+    }
+
+    fmt.write("seq->len = target_seq_len;");
+
+    if bindings {
+        fmt.write("unparse_bindings = target_bindings;");
+    }
+
+    fmt.blankline();
+
+    fmt.write(format!("if (target_id < {}) {{", rules.len()));
+    fmt.indent();
+    fmt.write("seq->buf[seq_idx] = target_id;");
+
+    if memoized {
+        fmt.write("if (unparse_memo.slots) {");
+        fmt.indent();
+        fmt.write(format!("unparse_memo_store({}, *cursor, seq, seq_idx, target_cursor);", nonterm));
+        fmt.unindent();
+        fmt.write("}");
+    }
+
+    fmt.write("*cursor = target_cursor;");
+    fmt.write("return 1;");
+    fmt.unindent();
+    fmt.write("} else {");
+    fmt.indent();
+
+    if memoized {
+        fmt.write("if (unparse_memo.slots) {");
+        fmt.indent();
+        fmt.write(format!("unparse_memo_store({}, *cursor, seq, seq_idx, UNPARSE_MEMO_FAILED);", nonterm));
+        fmt.unindent();
+        fmt.write("}");
+    }
+
+    fmt.write("return 0;");
+    fmt.unindent();
+    fmt.write("}");
+
+    fmt.unindent();
+    fmt.write("}");
+    fmt.blankline();
+}
+
+/// Consume `len` bytes of input if they don't satisfy `mismatch`. Otherwise give up on the current rule of `nonterm`
+/// unless the input was cut off in the middle of the terminal, which `cut` checks.
+fn emit_unparsing_match(mismatch: String, cut: String, len: usize, nonterm: usize, fmt: &mut CFormatter<File>) {
+    fmt.write(format!("if ({}) {{", mismatch));
+    fmt.indent();
+    fmt.write(format!("if (!{}) {{", cut));
+    fmt.indent();
+    fmt.write(format!("UNPARSE_FAILED(tmp_cursor, {});", nonterm));
+    fmt.write("break;");
+    fmt.unindent();
+    fmt.write("}");
+    fmt.write("tmp_cursor = input_len;");
+    fmt.unindent();
+    fmt.write("} else {");
+    fmt.indent();
+    fmt.write(format!("tmp_cursor += {};", len));
+    fmt.unindent();
+    fmt.write("}");
+    fmt.blankline();
+}
+
+fn emit_thorough_unparsing(grammar: &LowLevelGrammar, terminals: &TerminalTable, fmt: &mut CFormatter<File>) {
+    fmt.write("/* Backtracking unparser that is used when the greedy unparser fails */");
+    fmt.write("#ifndef UNPARSE_BUDGET");
+    fmt.write(" #define UNPARSE_BUDGET 1000000");
+    fmt.write("#endif");
+    fmt.blankline();
+
+    fmt.write("typedef struct {");
+    fmt.indent();
+    fmt.write("size_t is_terminal;");
+    fmt.write("size_t id;");
+    fmt.write("size_t len;");
+    fmt.unindent();
+    fmt.write("} UnparseSymbol;");
+    fmt.blankline();
+
+    /* Terminals are referenced by their offset in the blob or by their index */
+    if terminals.blob().is_some() {
+        fmt.write("#define TERMINAL_AT(i) (&TERMS[i])");
+    } else {
+        let len = terminals.terminals().len();
+        fmt.write(format!("static const unsigned char* const TERMINAL_POINTERS[{}] = {{", len.max(1)));
+        fmt.indent();
+        for i in 0..len {
+            fmt.write(format!("TERM{},", i));
+        }
+        if len == 0 {
+            fmt.write("NULL,");
+        }
+        fmt.unindent();
+        fmt.write("};");
+        fmt.write("#define TERMINAL_AT(i) (TERMINAL_POINTERS[i])");
+    }
+    fmt.blankline();
+
+    fmt.write("typedef struct {");
+    fmt.indent();
+    fmt.write("const UnparseSymbol* symbols;");
+    fmt.write("size_t len;");
+    fmt.unindent();
+    fmt.write("} UnparseList;");
+    fmt.blankline();
+
+    fmt.write("typedef struct {");
+    fmt.indent();
+    fmt.write("const UnparseList* rules;");
+    fmt.write("size_t len;");
+    fmt.unindent();
+    fmt.write("} UnparseNonTerminal;");
+    fmt.blankline();
+
+    /* Flatten the grammar into tables, rule ids are the same as in the greedy unparser */
+    let mut symbols = Vec::new();
+    let mut rules = Vec::new();
+    let mut nonterms = Vec::new();
+
+    for nonterm in 0..grammar.nonterminals().len() {
+        let nonterm_rules = grammar.rules().get(&nonterm).map_or(&[][..], Vec::as_slice);
+        nonterms.push((rules.len(), nonterm_rules.len()));
+
+        for rule in nonterm_rules {
+            rules.push((symbols.len(), rule.len()));
+
+            for symbol in rule {
+                symbols.push(match symbol {
+                    LLSymbol::Terminal(term) => {
+                        let (offset, len) = terminals.entry(term);
+                        format!("{{1, {}, {}}},", offset, len)
+                    },
+                    /* Builtins are matched by their greedy unparsing function */
+                    LLSymbol::NonTerminal(nonterm) if grammar.builtin(nonterm.id()).is_some() => {
+                        format!("{{4, {}, 0}},", nonterm.id())
+                    },
+                    LLSymbol::NonTerminal(nonterm) => format!("{{0, {}, 0}},", nonterm.id()),
+                    /* Runs store the byte in the place of the offset */
+                    LLSymbol::Repeat {
+                        byte,
+                        count,
+                    } => format!("{{2, {}, {}}},", byte, count),
+                    /* Markers store the slot in the place of the offset and the operation in the place of the length */
+                    LLSymbol::Binding {
+                        op,
+                        slot,
+                    } => format!("{{3, {}, {}}},", slot, binding_op_macro(*op)),
+                });
+            }
+        }
+    }
+
+    fmt.write(format!("static const UnparseSymbol UNPARSE_SYMBOLS[{}] = {{", symbols.len().max(1)));
+    fmt.indent();
+    for symbol in symbols {
+        fmt.write(symbol);
+    }
+    fmt.unindent();
+    fmt.write("};");
+    fmt.blankline();
+
+    fmt.write(format!("static const UnparseList UNPARSE_RULES[{}] = {{", rules.len().max(1)));
+    fmt.indent();
+    for (offset, len) in rules {
+        fmt.write(format!("{{&UNPARSE_SYMBOLS[{}], {}}},", offset, len));
+    }
+    fmt.unindent();
+    fmt.write("};");
+    fmt.blankline();
+
+    fmt.write(format!("static const UnparseNonTerminal UNPARSE_NONTERMINALS[{}] = {{", nonterms.len()));
+    fmt.indent();
+    for (offset, len) in nonterms {
+        fmt.write(format!("{{&UNPARSE_RULES[{}], {}}},", offset, len));
+    }
+    fmt.unindent();
+    fmt.write("};");
+    fmt.blankline();
+
+    fmt.write(format!("static const UnparseSymbol UNPARSE_ENTRYPOINT = {{0, {}, 0}};", grammar.entrypoint().id()));
+    fmt.blankline();
+
+    fmt.write("typedef struct {");
+    fmt.indent();
+    fmt.write("Sequence* seq;");
+    fmt.write("const unsigned char* input;");
+    fmt.write("size_t input_len;");
+    fmt.write("size_t budget;");
+    fmt.unindent();
+    fmt.write("} Unparser;");
+    fmt.blankline();
+
+    fmt.write("// The symbols that still have to be matched after the current non-terminal");
+    fmt.write("typedef struct Continuation {");
+    fmt.indent();
+    fmt.write("const UnparseSymbol* symbols;");
+    fmt.write("size_t len;");
+    fmt.write("size_t nonterm;");
+    fmt.write("const struct Continuation* next;");
+    fmt.unindent();
+    fmt.write("} Continuation;");
+    fmt.blankline();
+
+    fmt.write("static int unparse_thorough (Unparser* const u, Continuation cont, size_t cursor) {");
+    fmt.indent();
+    fmt.write("const UnparseSymbol* symbol;");
+    fmt.blankline();
+    fmt.write("while (1) {");
+    fmt.indent();
+    fmt.write("if (cont.len == 0) {");
+    fmt.indent();
+    fmt.write("if (!cont.next) {");
+    fmt.indent();
+    fmt.write("if (cursor != u->input_len) {");
+    fmt.indent();
+    fmt.write("UNPARSE_FAILED(cursor, cont.nonterm);");
+    fmt.unindent();
+    fmt.write("}");
+    fmt.write("return cursor == u->input_len;");
+    fmt.unindent();
+    fmt.write("}");
+    fmt.blankline();
+    fmt.write("cont = *cont.next;");
+    fmt.write("continue;");
+    fmt.unindent();
+    fmt.write("}");
+    fmt.blankline();
+    fmt.write("symbol = cont.symbols;");
+    fmt.write("cont.symbols++;");
+    fmt.write("cont.len--;");
+    fmt.blankline();
+    fmt.write("if (!symbol->is_terminal) {");
+    fmt.indent();
+    fmt.write("break;");
+    fmt.unindent();
+    fmt.write("}");
+    fmt.blankline();
+    if grammar.has_bindings() {
+        fmt.write("if (symbol->is_terminal == 3) {");
+        fmt.indent();
+        fmt.write("if (!unparse_binding(symbol->id, (int) symbol->len, u->input, u->input_len, &cursor)) {");
+        fmt.indent();
+        fmt.write("UNPARSE_FAILED(cursor, cont.nonterm);");
+        fmt.write("return 0;");
+        fmt.unindent();
+        fmt.write("}");
+        fmt.write("continue;");
+        fmt.unindent();
+        fmt.write("}");
+        fmt.blankline();
+    }
+    let builtins = builtin_nonterminals(grammar);
+
+    if !builtins.is_empty() {
+        fmt.write("if (symbol->is_terminal == 4) {");
+        fmt.indent();
+        fmt.write("int matched = 0;");
+        fmt.blankline();
+        fmt.write("switch (symbol->id) {");
+        fmt.indent();
+
+        for nonterm in builtins {
+            fmt.write(format!("case {}: {{", nonterm));
+            fmt.indent();
+            fmt.write(format!("matched = unparse_seq_nonterm{}(u->seq, u->input, u->input_len, &cursor);", nonterm));
+            fmt.write("break;");
+            fmt.unindent();
+            fmt.write("}");
+        }
+
+        fmt.write("default: {");
+        fmt.indent();
+        fmt.write("break;");
+        fmt.unindent();
+        fmt.write("}");
+        fmt.unindent();
+        fmt.write("}");
+        fmt.blankline();
+        fmt.write("if (!matched) {");
+        fmt.indent();
+        fmt.write("return 0;");
+        fmt.unindent();
+        fmt.write("}");
+        fmt.write("continue;");
+        fmt.unindent();
+        fmt.write("}");
+        fmt.blankline();
+    }
+    if grammar.has_repeats() {
+        fmt.write("if (UNLIKELY(u->input_len - cursor < symbol->len) || (symbol->is_terminal == 2 ? !match_repeat(&u->input[cursor], (unsigned char) symbol->id, symbol->len) : peacock_memcmp(&u->input[cursor], TERMINAL_AT(symbol->id), symbol->len) != 0)) {");
+    } else {
+        fmt.write("if (UNLIKELY(u->input_len - cursor < symbol->len) || peacock_memcmp(&u->input[cursor], TERMINAL_AT(symbol->id), symbol->len) != 0) {");
+    }
+    fmt.indent();
+    fmt.write("UNPARSE_FAILED(cursor, cont.nonterm);");
+    fmt.write("return 0;");
+    fmt.unindent();
+    fmt.write("}");
+    fmt.write("cursor += symbol->len;");
+    fmt.unindent();
+    fmt.write("}");
+    fmt.blankline();
+    fmt.write("size_t seq_idx = u->seq->len;");
+    fmt.blankline();
+    fmt.write("if (UNLIKELY(seq_idx >= u->seq->capacity)) {");
+    fmt.indent();
+    fmt.write("unparse_overflow = 1;");
+    fmt.write("return 0;");
+    fmt.unindent();
+    fmt.write("}");
+    fmt.blankline();
+    fmt.write("const UnparseNonTerminal* nonterm = &UNPARSE_NONTERMINALS[symbol->id];");
+    if grammar.has_bindings() {
+        fmt.write("UnparseBindings initial_bindings = unparse_bindings;");
+    }
+    fmt.blankline();
+    fmt.write("for (size_t i = 0; i < nonterm->len; ++i) {");
+    fmt.indent();
+    fmt.write("if (UNLIKELY(u->budget == 0)) {");
+    fmt.indent();
+    fmt.write("break;");
+    fmt.unindent();
+    fmt.write("}");
+    fmt.write("u->budget--;");
+    if grammar.has_bindings() {
+        fmt.write("unparse_bindings = initial_bindings;");
+    }
+    fmt.blankline();
+    fmt.write("u->seq->buf[seq_idx] = i;");
+    fmt.write("u->seq->len = seq_idx + 1;");
+    fmt.write("Continuation expansion = {");
+    fmt.indent();
+    fmt.write(".symbols = nonterm->rules[i].symbols,");
+    fmt.write(".len = nonterm->rules[i].len,");
+    fmt.write(".nonterm = symbol->id,");
+    fmt.write(".next = &cont,");
+    fmt.unindent();
+    fmt.write("};");
+    fmt.blankline();
+    fmt.write("if (unparse_thorough(u, expansion, cursor)) {");
+    fmt.indent();
+    fmt.write("return 1;");
+    fmt.unindent();
+    fmt.write("}");
+    fmt.unindent();
+    fmt.write("}");
+    fmt.blankline();
+    fmt.write("u->seq->len = seq_idx;");
+    fmt.write("return 0;");
+    fmt.unindent();
+    fmt.write("}");
+    fmt.blankline();
+}
+
+fn emit_unparsing_entrypoint(grammar: &LowLevelGrammar, thorough: bool, fmt: &mut CFormatter<File>) {
+    let entrypoint = grammar.entrypoint().id();
+
+    fmt.write("EXPORT_FUNCTION");
+    fmt.write("size_t unparse_sequence (size_t* seq_buf, const size_t seq_capacity, const unsigned char* input, const size_t input_len) {");
+    fmt.indent();
+
+    fmt.write("unparse_furthest = 0;");
+    fmt.write("unparse_furthest_nonterm = (size_t) -1LL;");
+    fmt.blankline();
+
+    fmt.write("if (UNLIKELY(!seq_buf || !seq_capacity || !input || !input_len)) {");
+    fmt.indent();
+    fmt.write(format!("unparse_furthest_nonterm = {};", entrypoint));
+    fmt.write("return 0;");
+    fmt.unindent();
+    fmt.write("}");
+
+    fmt.write("Sequence seq = {");
+    fmt.indent();
+    fmt.write(".buf = seq_buf,");
+    fmt.write(".len = 0,");
+    fmt.write(".capacity = seq_capacity,");
+    fmt.unindent();
+    fmt.write("};");
+    fmt.write("size_t cursor = 0;");
+    fmt.blankline();
+
+    if grammar.has_bindings() {
+        fmt.write("reset_unparse_bindings();");
+    }
+
+    /* A prefix of the input is not enough, e.g. an input that gets migrated to a new grammar must match as a whole */
+    fmt.write(format!(
+        "if (unparse_seq_nonterm{}(&seq, input, input_len, &cursor) && cursor == input_len) {{",
+        entrypoint
+    ));
+    fmt.indent();
+    fmt.write("unparse_furthest_nonterm = (size_t) -1LL;");
+    fmt.write("return seq.len;");
+    fmt.unindent();
+    fmt.write("}");
+    fmt.blankline();
+
+    fmt.write("// Trailing input that the grammar does not cover");
+    fmt.write(format!("UNPARSE_FAILED(cursor, {});", entrypoint));
+    fmt.blankline();
+
+    if thorough {
+        fmt.write("seq.len = 0;");
+
+        if grammar.has_bindings() {
+            fmt.write("reset_unparse_bindings();");
+        }
+
+        fmt.write("Unparser unparser = {");
+        fmt.indent();
+        fmt.write(".seq = &seq,");
+        fmt.write(".input = input,");
+        fmt.write(".input_len = input_len,");
+        fmt.write(".budget = UNPARSE_BUDGET,");
+        fmt.unindent();
+        fmt.write("};");
+        fmt.write("Continuation cont = {");
+        fmt.indent();
+        fmt.write(".symbols = &UNPARSE_ENTRYPOINT,");
+        fmt.write(".len = 1,");
+        fmt.write(format!(".nonterm = {},", entrypoint));
+        fmt.write(".next = NULL,");
+        fmt.unindent();
+        fmt.write("};");
+        fmt.write("if (unparse_thorough(&unparser, cont, 0)) {");
+        fmt.indent();
+        fmt.write("unparse_furthest_nonterm = (size_t) -1LL;");
+        fmt.write("return seq.len;");
+        fmt.unindent();
+        fmt.write("}");
+        fmt.blankline();
+    }
+
+    fmt.write("return 0;");
+    fmt.unindent();
+    fmt.write("}");
+    fmt.blankline();
+}
+
+fn emit_bounded_unparsing_entrypoint(grammar: &LowLevelGrammar, fmt: &mut CFormatter<File>) {
+    let entrypoint = grammar.entrypoint().id();
+
+    fmt.write("// Like unparse_sequence but for inputs that need more than max_bytes bytes or max_steps steps.");
+    fmt.write("// Those get cut off and the walk only covers a prefix of the input, which *truncated signals.");
+    fmt.write("EXPORT_FUNCTION");
+    fmt.write("size_t unparse_sequence_bounded (size_t* seq_buf, size_t seq_capacity, const unsigned char* input, const size_t input_len, const size_t max_bytes, const size_t max_steps, int* truncated) {");
+    fmt.indent();
+
+    fmt.write("size_t limit = max_bytes;");
+    fmt.blankline();
+    fmt.write("if (truncated) {");
+    fmt.indent();
+    fmt.write("*truncated = 0;");
+    fmt.unindent();
+    fmt.write("}");
+    fmt.blankline();
+    fmt.write("if (seq_capacity > max_steps) {");
+    fmt.indent();
+    fmt.write("seq_capacity = max_steps;");
+    fmt.unindent();
+    fmt.write("}");
+    fmt.blankline();
+
+    fmt.write("if (input_len <= max_bytes) {");
+    fmt.indent();
+    fmt.write("unparse_overflow = 0;");
+    fmt.write("size_t len = unparse_sequence(seq_buf, seq_capacity, input, input_len);");
+    fmt.blankline();
+    fmt.write("if (len || !unparse_overflow) {");
+    fmt.indent();
+    fmt.write("return len;");
+    fmt.unindent();
+    fmt.write("}");
+    fmt.blankline();
+    fmt.write("limit = input_len / 2;");
+    fmt.unindent();
+    fmt.write("}");
+    fmt.blankline();
+
+    fmt.write("if (UNLIKELY(!seq_buf || !seq_capacity || !input)) {");
+    fmt.indent();
+    fmt.write("return 0;");
+    fmt.unindent();
+    fmt.write("}");
+    fmt.blankline();
+
+    fmt.write("Sequence seq = {");
+    fmt.indent();
+    fmt.write(".buf = seq_buf,");
+    fmt.write(".len = 0,");
+    fmt.write(".capacity = seq_capacity,");
+    fmt.unindent();
+    fmt.write("};");
+    fmt.blankline();
+
+    fmt.write("// Halve the prefix until its walk fits into the sequence");
+    fmt.write("unparse_prefix = 1;");
+    fmt.blankline();
+    fmt.write("for (; limit > 0; limit /= 2) {");
+    fmt.indent();
+    fmt.write("size_t cursor = 0;");
+    fmt.write("seq.len = 0;");
+
+    if grammar.has_bindings() {
+        fmt.write("reset_unparse_bindings();");
+    }
+
+    fmt.blankline();
+    fmt.write(format!("if (unparse_seq_nonterm{}(&seq, input, limit, &cursor) && cursor == limit) {{", entrypoint));
+    fmt.indent();
+    fmt.write("break;");
+    fmt.unindent();
+    fmt.write("}");
+    fmt.unindent();
+    fmt.write("}");
+    fmt.blankline();
+    fmt.write("unparse_prefix = 0;");
+    fmt.blankline();
+
+    fmt.write("if (limit == 0) {");
+    fmt.indent();
+    fmt.write("return 0;");
+    fmt.unindent();
+    fmt.write("}");
+    fmt.blankline();
+
+    fmt.write("if (truncated) {");
+    fmt.indent();
+    fmt.write("*truncated = 1;");
+    fmt.unindent();
+    fmt.write("}");
+    fmt.blankline();
+    fmt.write("unparse_furthest_nonterm = (size_t) -1LL;");
+    fmt.write("return seq.len;");
+
+    fmt.unindent();
+    fmt.write("}");
+    fmt.blankline();
+}
+
+fn emit_memoized_unparsing_entrypoint(grammar: &LowLevelGrammar, memo_table: bool, fmt: &mut CFormatter<File>) {
+    fmt.write("// Like unparse_sequence but remembers the results of non-terminals in the memory of scratch,");
+    fmt.write("// such that ambiguous grammars don't take exponential time. Without enough scratch space, this is unparse_sequence.");
+    fmt.write("EXPORT_FUNCTION");
+    fmt.write("size_t unparse_sequence_mem (size_t* seq_buf, const size_t seq_capacity, const unsigned char* input, const size_t input_len, void* scratch, const size_t scratch_len) {");
+    fmt.indent();
+
+    if !memo_table {
+        fmt.write("// The results of non-terminals depend on the bindings");
+        fmt.write("(void) scratch;");
+        fmt.write("(void) scratch_len;");
+        fmt.write("return unparse_sequence(seq_buf, seq_capacity, input, input_len);");
+        fmt.unindent();
+        fmt.write("}");
+        fmt.blankline();
+        return;
+    }
+
+    fmt.write("uintptr_t start = ((uintptr_t) scratch + sizeof(size_t) - 1) & ~((uintptr_t) sizeof(size_t) - 1);");
+    fmt.write("uintptr_t end = (uintptr_t) scratch + scratch_len;");
+    fmt.write("size_t space = (scratch && end >= start) ? (size_t) (end - start) : 0;");
+    fmt.write("size_t slots = UNPARSE_MEMO_MIN_SLOTS;");
+    fmt.blankline();
+    fmt.write("if (slots * sizeof(UnparseMemo) > space / 2) {");
+    fmt.indent();
+    fmt.write("return unparse_sequence(seq_buf, seq_capacity, input, input_len);");
+    fmt.unindent();
+    fmt.write("}");
+    fmt.blankline();
+    fmt.write("// The table gets at most half of the scratch space and not many more slots than there are pairs of non-terminals and offsets");
+    fmt.write(format!(
+        "while (slots * 2 * sizeof(UnparseMemo) <= space / 2 && slots / {} <= input_len) {{",
+        grammar.nonterminals().len()
+    ));
+    fmt.indent();
+    fmt.write("slots *= 2;");
+    fmt.unindent();
+    fmt.write("}");
+    fmt.blankline();
+    fmt.write("unparse_memo.slots = (UnparseMemo*) start;");
+    fmt.write("unparse_memo.mask = slots - 1;");
+    fmt.write("unparse_memo.arena = (size_t*) (start + slots * sizeof(UnparseMemo));");
+    fmt.write("unparse_memo.arena_len = 0;");
+    fmt.write("unparse_memo.arena_capacity = (space - slots * sizeof(UnparseMemo)) / sizeof(size_t);");
+    fmt.write("peacock_memset(unparse_memo.slots, 0xFF, slots * sizeof(UnparseMemo));");
+    fmt.write("unparse_overflow = 0;");
+    fmt.blankline();
+    fmt.write("size_t len = unparse_sequence(seq_buf, seq_capacity, input, input_len);");
+    fmt.write("unparse_memo.slots = NULL;");
+    fmt.write("return len;");
+
+    fmt.unindent();
+    fmt.write("}");
+    fmt.blankline();
+}
+
+fn emit_standalone_main(fmt: &mut CFormatter<File>) {
+    fmt.write("/* Generate a single input to stdout when compiled with -DPEACOCK_STANDALONE */");
+    fmt.write("#ifdef PEACOCK_STANDALONE");
+    fmt.write("#include <stdio.h>");
+    fmt.blankline();
+    fmt.write("// <stdlib.h> would clash with rand()");
+    fmt.write("unsigned long long strtoull (const char*, char**, int);");
+    fmt.blankline();
+    fmt.write("#ifndef PEACOCK_STANDALONE_SEQ_LEN");
+    fmt.write(" #define PEACOCK_STANDALONE_SEQ_LEN 4096");
+    fmt.write("#endif");
+    fmt.write("#ifndef PEACOCK_STANDALONE_OUT_LEN");
+    fmt.write(" #define PEACOCK_STANDALONE_OUT_LEN (1 << 20)");
+    fmt.write("#endif");
+    fmt.blankline();
+    fmt.write("static size_t standalone_seq[PEACOCK_STANDALONE_SEQ_LEN];");
+    fmt.write("static unsigned char standalone_out[PEACOCK_STANDALONE_OUT_LEN];");
+    fmt.blankline();
+    fmt.write("int main (int argc, char** argv) {");
+    fmt.indent();
+    fmt.write("if (argc > 2) {");
+    fmt.indent();
+    fmt.write("fprintf(stderr, \"Usage: %s [<seed>]\\n\", argv[0]);");
+    fmt.write("return 1;");
+    fmt.unindent();
+    fmt.write("}");
+    fmt.blankline();
+    fmt.write("if (argc == 2) {");
+    fmt.indent();
+    fmt.write("seed_generator(strtoull(argv[1], NULL, 0));");
+    fmt.unindent();
+    fmt.write("}");
+    fmt.blankline();
+    fmt.write("size_t seq_len = mutate_sequence(standalone_seq, 0, PEACOCK_STANDALONE_SEQ_LEN);");
+    fmt.write(
+        "size_t out_len = serialize_sequence(standalone_seq, seq_len, standalone_out, PEACOCK_STANDALONE_OUT_LEN);",
+    );
+    fmt.blankline();
+    fmt.write("if (fwrite(standalone_out, 1, out_len, stdout) != out_len) {");
+    fmt.indent();
+    fmt.write("return 1;");
+    fmt.unindent();
+    fmt.write("}");
+    fmt.blankline();
+    fmt.write("return 0;");
+    fmt.unindent();
+    fmt.write("}");
+    fmt.write("#endif");
+    fmt.blankline();
+}
+
+/// Count how often every rule is used in the walks of `samples`. Samples that cannot be unparsed are skipped.
+fn measure_frequencies(grammar: &LowLevelGrammar, samples: &[Vec<u8>]) -> Vec<Vec<usize>> {
+    let unparser = Unparser::new(grammar);
+    let mut frequencies: Vec<Vec<usize>> =
+        (0..grammar.nonterminals().len()).map(|nonterm| vec![0; grammar.rules()[&nonterm].len()]).collect();
+
+    for sample in samples {
+        if let Ok(walk) = unparser.unparse(sample) {
+            /* The steps of builtins hold values instead of rule indices */
+            for (nonterm, rule) in walk {
+                if let Some(frequency) = frequencies[nonterm].get_mut(rule) {
+                    *frequency += 1;
+                }
+            }
+        }
+    }
+
+    frequencies
+}
+
+fn emit_unparsing_code(
+    grammar: &LowLevelGrammar,
+    terminals: &TerminalTable,
+    thorough: bool,
+    memoized: bool,
+    frequencies: Option<&[Vec<usize>]>,
+    out: &mut Output,
+) {
+    emit_unparsing_diagnostics(grammar, out);
+    emit_unparsing_declarations(grammar, out.declarations());
+
+    if grammar.has_repeats() {
+        emit_match_repeat(out.declarations());
+    }
+
+    emit_unparsing_bounds(grammar, out);
+
+    if grammar.has_bindings() {
+        emit_unparsing_bindings(grammar, out);
+    }
+
+    /* The result of a non-terminal depends on the bindings, so it cannot be remembered for an offset alone */
+    let memo_table = memoized && !grammar.has_bindings();
+
+    if memo_table {
+        emit_unparsing_memo(out);
+    }
+
+    for (nonterm, rules) in grammar.rules() {
+        if let Some(builtin) = grammar.builtin(*nonterm) {
+            emit_unparsing_function_builtin(*nonterm, builtin, grammar, out.part(*nonterm));
+        } else if is_dynamic(grammar, *nonterm) {
+            emit_unparsing_function_dynamic(*nonterm, grammar, out.part(*nonterm));
+        } else {
+            let frequencies = frequencies.map(|frequencies| frequencies[*nonterm].as_slice());
+            emit_unparsing_function(*nonterm, rules, grammar, terminals, frequencies, memo_table, out.part(*nonterm));
+        }
+    }
+
+    if thorough {
+        emit_thorough_unparsing(grammar, terminals, &mut out.primary);
+    }
+
+    emit_unparsing_entrypoint(grammar, thorough, &mut out.primary);
+    emit_bounded_unparsing_entrypoint(grammar, &mut out.primary);
+
+    if memoized {
+        emit_memoized_unparsing_entrypoint(grammar, memo_table, &mut out.primary);
+    }
+}
+
+/// The order in which the greedy unparser tries the alternatives of a non-terminal, see [`CGenerator::unparse_order`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnparseOrder {
+    /// Alternatives that start with longer terminals come first, then longer alternatives
+    LongestFirst,
+
+    /// Like [`LongestFirst`](UnparseOrder::LongestFirst) but alternatives that are equal in both respects
+    /// are tried in the order of their frequency, most frequent first
+    Frequency,
+}
+
+/// This is the main struct of the [`C`](crate::backends::C) backend that does all the heavy lifting and generates the code.
+///
+/// For documentation of the generated C code see the [README](https://github.com/z2-2z/peacock#c-api-documentation) of this project.
+pub struct CGenerator {
+    header: bool,
+    mutations: bool,
+    serializations: bool,
+    unparsing: bool,
+    thorough_unparsing: bool,
+    memoized_unparsing: bool,
+    statistics: bool,
+    prefix: String,
+    files: usize,
+    terminal_blob: bool,
+    unsafe_fast_paths: bool,
+    recursion_decay: f32,
+    python: bool,
+    combine: bool,
+    seeded: bool,
+    standalone: bool,
+    freestanding: bool,
+    unparse_order: UnparseOrder,
+    frequencies: HashMap<String, Vec<usize>>,
+    samples: Vec<Vec<u8>>,
+    profile: GenerationProfile,
+}
+
+impl CGenerator {
+    /// Create a new CGenerator.
+    #[allow(clippy::new_without_default)]
+    pub fn new() -> Self {
+        Self {
+            header: true,
+            mutations: true,
+            serializations: true,
+            unparsing: true,
+            thorough_unparsing: false,
+            memoized_unparsing: false,
+            statistics: false,
+            prefix: String::new(),
+            files: 1,
+            terminal_blob: false,
+            unsafe_fast_paths: false,
+            recursion_decay: 1.0,
+            python: false,
+            combine: true,
+            seeded: false,
+            standalone: false,
+            freestanding: false,
+            unparse_order: UnparseOrder::LongestFirst,
+            frequencies: HashMap::new(),
+            samples: Vec::new(),
+            profile: GenerationProfile::new(),
+        }
+    }
+
+    /// Also generate a .h file with all the definitions of the public C API of the generated code.
+    ///
+    /// Default: `true`
+    pub fn generate_header(mut self, flag: bool) -> Self {
+        self.header = flag;
+        self
+    }
+
+    /// Emit code that realizes the mutation of an automaton walk.
+    ///
+    /// Default: `true`
+    pub fn emit_mutation_procedure(mut self, flag: bool) -> Self {
+        self.mutations = flag;
+        self
+    }
+
+    /// Emit code that realizes the serialization of automaton walks into human-readable output.
+    ///
+    /// Default: `true`
+    pub fn emit_serialization_procedure(mut self, flag: bool) -> Self {
+        self.serializations = flag;
+        self
+    }
+
+    /// Emit code that realizes the unparsing of user inputs into automaton walks.
+    ///
+    /// Default: `true`
+    pub fn emit_unparsing_procedure(mut self, flag: bool) -> Self {
+        self.unparsing = flag;
+        self
+    }
+
+    /// Retry with a backtracking unparser if the fast, greedy unparser cannot consume the whole input.
+    /// The greedy unparser commits to the rule that consumes the most input at every non-terminal,
+    /// which fails on inputs that require a shorter match. The backtracking unparser tries all
+    /// alternatives until it has expanded `UNPARSE_BUDGET` non-terminals.
+    ///
+    /// This makes the generated code bigger.
+    ///
+    /// Default: `false`
+    pub fn thorough_unparsing(mut self, flag: bool) -> Self {
+        self.thorough_unparsing = flag;
+        self
+    }
+
+    /// Also export `unparse_sequence_mem()`, which caches the results of non-terminals at offsets of the input
+    /// in a scratch buffer of the caller. The greedy unparser retries the alternatives of a non-terminal
+    /// at the same offset over and over again, which takes exponential time on ambiguous grammars.
+    /// The cache avoids most of these retries but it is not a complete memo table: A result is dropped when
+    /// its slot is not found within 4 probes or the scratch buffer has no room left for its steps,
+    /// and the Rust components give it 256 bytes of scratch per byte of input.
+    /// Once the walk does not fit into the sequence anymore, `unparse_sequence_mem()` gives up right away.
+    /// Grammars with bindings are unparsed without the cache.
+    ///
+    /// Default: `false`
+    pub fn memoized_unparsing(mut self, flag: bool) -> Self {
+        self.memoized_unparsing = flag;
+        self
+    }
+
+    /// Choose the order in which the greedy unparser tries the alternatives of a non-terminal.
+    /// The alternative that consumes the most input always wins, the order decides between alternatives
+    /// that consume the same amount. Once an alternative consumed the whole input, the remaining ones are skipped,
+    /// so trying the common alternatives first makes unparsing faster.
+    ///
+    /// [`UnparseOrder::Frequency`] takes the frequencies from [`rule_frequencies`](CGenerator::rule_frequencies)
+    /// and [`measure_rule_frequencies`](CGenerator::measure_rule_frequencies).
+    ///
+    /// Default: [`UnparseOrder::LongestFirst`]
+    pub fn unparse_order(mut self, order: UnparseOrder) -> Self {
+        self.unparse_order = order;
+        self
+    }
+
+    /// Set how often the alternatives of non-terminals occur for [`UnparseOrder::Frequency`].
+    /// `frequencies` maps the id of a non-terminal to one number per alternative, in the order of
+    /// [`ContextFreeGrammar::rules_by_nonterminal`]. Missing entries count as `0`.
+    /// This is added to the frequencies measured with [`measure_rule_frequencies`](CGenerator::measure_rule_frequencies).
+    ///
+    /// Default: no frequencies
+    pub fn rule_frequencies(mut self, frequencies: HashMap<String, Vec<usize>>) -> Self {
+        self.frequencies = frequencies;
+        self
+    }
+
+    /// Measure the frequencies for [`UnparseOrder::Frequency`] by unparsing `samples`, e.g. the inputs of a seed corpus,
+    /// and counting the rules of their walks. Samples that are not part of the language of the grammar are ignored.
+    ///
+    /// Default: no samples
+    pub fn measure_rule_frequencies<I, B>(mut self, samples: I) -> Self
+    where
+        I: IntoIterator<Item = B>,
+        B: AsRef<[u8]>,
+    {
+        self.samples = samples.into_iter().map(|sample| sample.as_ref().to_vec()).collect();
+        self
+    }
+
+    /// Count how often every rule gets serialized and export the counters via `get_rule_counters()`.
+    /// The non-terminal each counter belongs to can be looked up with `get_rule_names()`.
+    ///
+    /// Default: `false`
+    pub fn emit_statistics(mut self, flag: bool) -> Self {
+        self.statistics = flag;
+        self
+    }
+
+    /// Prepend `prefix` to the names of all exported functions, e.g. `mutate_sequence` becomes
+    /// `<prefix>mutate_sequence`. This allows multiple generators to be linked into the same binary.
+    /// The prefix must only consist of alphanumeric characters and underscores.
+    ///
+    /// Default: `""`
+    pub fn symbol_prefix<S: Into<String>>(mut self, prefix: S) -> Self {
+        let prefix = prefix.into();
+        assert!(prefix.chars().all(|c| c.is_ascii_alphanumeric() || c == '_'), "Invalid symbol prefix '{}'", prefix);
+        self.prefix = prefix;
+        self
+    }
+
+    /// Distribute the functions of the non-terminals over `files` source files in total, such that
+    /// big grammars can be compiled in parallel and with less memory.
+    /// The file given to [`generate`](CGenerator::generate) contains the exported functions and
+    /// includes the internal header `<name>_internal.h`. The other files are called `<name>_part<N>.c`.
+    /// All of them must be compiled and linked together.
+    ///
+    /// Default: `1`
+    pub fn split_output(mut self, files: usize) -> Self {
+        assert!(files > 0, "Output must consist of at least one file");
+        self.files = files;
+        self
+    }
+
+    /// Store all terminals in one string table `TERMS` and copy them from `TERMS + offset` instead of emitting
+    /// a `TERMn` array for every terminal. Terminals that are contained in other terminals are stored only once.
+    /// `TERMS_INDEX` holds the offset and the length of every terminal in `TERMS`.
+    /// This reduces the number of symbols and relocations of grammars with many terminals and improves locality.
+    ///
+    /// Default: `false`
+    pub fn terminal_blob(mut self, flag: bool) -> Self {
+        self.terminal_blob = flag;
+        self
+    }
+
+    /// Assume that every automaton walk was created by this grammar and drop the checks for rule indices
+    /// that are out of range. Malformed walks, e.g. from corrupted corpus files, then cause undefined behavior
+    /// in the mutation and serialization functions instead of being cut off.
+    /// `validate_sequence()` is still available to check walks up front.
+    ///
+    /// Default: `false`
+    pub fn unsafe_fast_paths(mut self, flag: bool) -> Self {
+        self.unsafe_fast_paths = flag;
+        self
+    }
+
+    /// Make recursive rules less likely the deeper the mutator is in the derivation tree.
+    /// At depth `d` a recursive rule is `decay^d` times as likely as a non-recursive rule of
+    /// the same non-terminal, which keeps new walks from running into the capacity of the buffer.
+    /// A rule is recursive if it can derive its own non-terminal again.
+    ///
+    /// With a decay of `1.0` all rules are equally likely and the mutator draws the same walks as the
+    /// [`GrammarInterpreter`](crate::backends::interpreter::GrammarInterpreter).
+    ///
+    /// Default: `1.0`
+    pub fn recursion_decay(mut self, decay: f32) -> Self {
+        assert!(decay > 0.0 && decay <= 1.0, "Recursion decay must be in (0, 1]");
+        self.recursion_decay = decay;
+        self
+    }
+
+    /// If a non-terminal has a single rule and all non-terminals in its derivation too, it always produces the same bytes.
+    /// Serialize such derivations with one copy of the precomputed output instead of calling the serialization
+    /// function of every child, as long as the walk and the output buffer are long enough for all of it.
+    /// Derivations of more than 4 KiB are always serialized child by child. The output is the same either way.
+    ///
+    /// Default: `true`
+    pub fn combine_constant_expansions(mut self, flag: bool) -> Self {
+        self.combine = flag;
+        self
+    }
+
+    /// Also emit `mutate_sequence_seeded()` and `mutate_sequence_inplace_seeded()`, which take the seed of the RNG
+    /// as an argument and leave the RNG state of `seed_generator()` untouched.
+    /// This makes every generated walk reproducible from its seed alone.
+    ///
+    /// Default: `false`
+    pub fn emit_seeded_variants(mut self, flag: bool) -> Self {
+        self.seeded = flag;
+        self
+    }
+
+    /// Append a `main()` to the generated code that is only compiled with `-DPEACOCK_STANDALONE`.
+    /// It seeds the RNG with the number in its first argument, if there is one, generates a walk with `mutate_sequence()`
+    /// and writes its serialization to stdout. The buffers hold `PEACOCK_STANDALONE_SEQ_LEN` rules and
+    /// `PEACOCK_STANDALONE_OUT_LEN` bytes, both can be overridden with `-D`.
+    ///
+    /// This needs the mutation and the serialization procedure.
+    ///
+    /// Default: `false`
+    pub fn emit_standalone_main(mut self, flag: bool) -> Self {
+        self.standalone = flag;
+        self
+    }
+
+    /// Generate code for freestanding targets that have no libc and no thread-local storage.
+    /// The generated code calls `peacock_memcpy`, `peacock_memmove`, `peacock_memset` and `peacock_memcmp`
+    /// instead of the memory builtins, which default to plain loops and can be overridden with `-D`, thread-local variables use the qualifier in `PEACOCK_THREAD_LOCAL`
+    /// instead of `__thread`, if it is defined, and a static assertion checks that `size_t` has `PEACOCK_SIZE_T_WIDTH` bytes (default: 8),
+    /// because automaton walks are not portable between targets with different widths.
     ///
-    /// Default: `true`
-    pub fn emit_serialization_procedure(mut self, flag: bool) -> Self {
-        self.serializations = flag;
+    /// This cannot be combined with [`emit_standalone_main()`](CGenerator::emit_standalone_main).
+    ///
+    /// Default: `false`
+    pub fn freestanding(mut self, flag: bool) -> Self {
+        self.freestanding = flag;
         self
     }
 
-    /// Emit code that realizes the unparsing of user inputs into automaton walks.
+    /// Scale the ranges of builtins like `@int(0, 1000)` with `profile` when the mutation procedure
+    /// chooses new values. The scaled bounds are baked into the generated code. Serialization and unparsing
+    /// still accept every value of a builtin, so walks from differently configured generators stay valid.
     ///
-    /// Default: `true`
-    pub fn emit_unparsing_procedure(mut self, flag: bool) -> Self {
-        self.unparsing = flag;
+    /// Default: [`GenerationProfile::new()`], which uses the whole ranges
+    pub fn profile(mut self, profile: GenerationProfile) -> Self {
+        self.profile = profile;
+        self
+    }
+
+    /// Also generate a CPython extension module `<name>_python.c` that exposes `mutate_sequence`, `serialize_sequence`,
+    /// `unparse_sequence` and `seed_generator` to Python, operating on lists of rule ids and `bytes`.
+    /// The module is called like the stem of the output file and must be compiled together with the
+    /// other sources into `<name>.so`, with the include path of `python3-config --includes`.
+    /// The symbol prefix is honored.
+    ///
+    /// Default: `false`
+    pub fn emit_python_module(mut self, flag: bool) -> Self {
+        self.python = flag;
         self
     }
 
     /// Generate the C code for the given grammar `grammar` and write it to `output`.
-    pub fn generate<P: AsRef<Path>>(self, output: P, grammar: &ContextFreeGrammar) {
-        let grammar = LowLevelGrammar::from_high_level_grammar(grammar);
-        let outfile = File::create(output.as_ref()).expect("Could not create source file");
-        let mut formatter = CFormatter::new(outfile);
+    ///
+    /// Returns the paths of all C source files that were generated, starting with `output`.
+    ///
+    /// # Panics
+    /// If the grammar is not valid or the code cannot be written. Use the [`Backend`] implementation to get an error instead.
+    pub fn generate<P: AsRef<Path>>(self, output: P, grammar: &ContextFreeGrammar) -> Vec<PathBuf> {
+        self.write_sources(output.as_ref(), grammar).unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    fn write_sources(self, output: &Path, grammar: &ContextFreeGrammar) -> Result<Vec<PathBuf>, BackendError> {
+        let fingerprint = grammar.semantic_hash();
+        let grammar = LowLevelGrammar::try_from_high_level_grammar(grammar)?;
+        let combined = if self.serializations && self.combine { combined_expansions(&grammar) } else { HashMap::new() };
+        let terminals = TerminalTable::new(
+            &grammar
+                .terminals()
+                .iter()
+                .chain(combined.keys().sorted().map(|nonterm| &combined[nonterm].0))
+                .cloned()
+                .collect::<Vec<_>>(),
+            self.terminal_blob,
+        );
+        let split = self.files > 1;
+        let mut sources = vec![output.to_path_buf()];
+        let mut out = Output {
+            primary: CFormatter::new(File::create(output)?),
+            header: None,
+            parts: Vec::new(),
+        };
+
+        if split {
+            let stem = output.file_stem().and_then(|x| x.to_str()).expect("Invalid output filename");
+            let header_name = format!("{}_internal.h", stem);
+            let guard = format!(
+                "__PEACOCK_{}_INTERNAL_H",
+                stem.to_uppercase().replace(|c: char| !c.is_ascii_alphanumeric(), "_")
+            );
+            let mut header = CFormatter::new(File::create(output.with_file_name(&header_name))?);
+            header.write(format!("#ifndef {}", guard));
+            header.write(format!("#define {}", guard));
+            header.blankline();
+            out.header = Some(header);
+
+            for i in 1..self.files {
+                let path = output.with_file_name(format!("{}_part{}.c", stem, i));
+                out.parts.push(CFormatter::new(File::create(&path)?));
+                sources.push(path);
+            }
+
+            for fmt in std::iter::once(&mut out.primary).chain(out.parts.iter_mut()) {
+                fmt.write(format!("#include \"{}\"", header_name));
+                fmt.blankline();
+            }
+        }
+
+        let fmt = out.declarations();
+        emit_includes(fmt);
+        emit_macros(split, self.freestanding, fmt);
+
+        if !self.prefix.is_empty() {
+            emit_symbol_prefix(&self.prefix, fmt);
+        }
+
+        emit_types(fmt);
+        emit_rand(&mut out);
+        emit_fingerprint(fingerprint, &mut out.primary);
+        emit_output_bounds(&grammar, &mut out.primary);
+
+        if grammar.dynamic().is_some() {
+            emit_dynamic_terminals(&mut out);
+        }
+
+        if grammar.has_builtins() {
+            emit_builtins(out.declarations());
+        }
 
-        emit_includes(&mut formatter);
-        emit_macros(&mut formatter);
-        emit_types(&mut formatter);
-        emit_rand(&mut formatter);
+        emit_rule_tables(&grammar, &mut out.primary);
+        emit_validation_code(&grammar, &mut out.primary);
 
         if self.mutations {
-            emit_mutation_code(&grammar, &mut formatter);
+            emit_mutation_code(
+                &grammar,
+                self.recursion_decay,
+                self.unsafe_fast_paths,
+                self.seeded,
+                &self.profile,
+                &mut out,
+            );
+        }
+
+        if self.serializations || self.unparsing {
+            emit_terminals(&terminals, &mut out);
+        }
+
+        let offsets = self.statistics.then(|| rule_offsets(&grammar));
+
+        if let Some(offsets) = &offsets {
+            emit_statistics(&grammar, offsets, &mut out);
         }
 
         if self.serializations {
-            emit_serialization_code(&grammar, &mut formatter);
+            emit_serialization_code(
+                &grammar,
+                &terminals,
+                offsets.as_deref(),
+                &combined,
+                self.unsafe_fast_paths,
+                &mut out,
+            );
         }
 
         if self.unparsing {
-            emit_unparsing_code(&grammar, &mut formatter);
+            let frequencies = (self.unparse_order == UnparseOrder::Frequency).then(|| self.frequencies(&grammar));
+            emit_unparsing_code(
+                &grammar,
+                &terminals,
+                self.thorough_unparsing,
+                self.memoized_unparsing,
+                frequencies.as_deref(),
+                &mut out,
+            );
+        }
+
+        if self.standalone {
+            assert!(
+                self.mutations && self.serializations,
+                "A standalone main() needs the mutation and the serialization procedure"
+            );
+            assert!(!self.freestanding, "A standalone main() needs a hosted environment");
+            emit_standalone_main(&mut out.primary);
+        }
+
+        if let Some(header) = &mut out.header {
+            header.write("#endif");
         }
 
+        out.finish()?;
+
         if self.header {
-            let mut outfile = output.as_ref().to_path_buf();
-            outfile.set_extension("h");
-            let outfile = File::create(outfile).expect("Could not create header file");
-            emit_header(outfile, self.mutations, self.serializations, self.unparsing)
-                .expect("Could not write to header file");
+            let outfile = File::create(output.with_extension("h"))?;
+            emit_header(outfile, &self, grammar.dynamic().is_some())?;
+        }
+
+        if self.python {
+            let stem = output.file_stem().and_then(|x| x.to_str()).expect("Invalid output filename");
+            let name = stem.replace(|c: char| !c.is_ascii_alphanumeric() && c != '_', "_");
+            let path = output.with_file_name(format!("{}_python.c", stem));
+            emit_python_module(
+                &path,
+                &PythonModule {
+                    name: &name,
+                    prefix: &self.prefix,
+                    mutations: self.mutations,
+                    serializations: self.serializations,
+                    unparsing: self.unparsing,
+                },
+            )?;
+            sources.push(path);
+        }
+
+        Ok(sources)
+    }
+
+    /// The given and the measured frequencies of all rules of `grammar`, indexed by non-terminal and rule
+    fn frequencies(&self, grammar: &LowLevelGrammar) -> Vec<Vec<usize>> {
+        let mut frequencies = measure_frequencies(grammar, &self.samples);
+
+        for (nonterm, name) in grammar.nonterminals().iter().enumerate() {
+            if let Some(given) = self.frequencies.get(name) {
+                for (frequency, given) in frequencies[nonterm].iter_mut().zip(given) {
+                    *frequency += given;
+                }
+            }
         }
+
+        frequencies
+    }
+}
+
+impl Backend for CGenerator {
+    fn generate(self, output: &Path, grammar: &ContextFreeGrammar) -> Result<(), BackendError> {
+        self.write_sources(output, grammar).map(|_| ())
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    #[cfg(not(feature = "static-loading"))]
+    use crate::backends::C::{
+        compile,
+        CompileOptions,
+    };
+
+    #[cfg(not(feature = "static-loading"))]
+    const SHARED: &[&str] = &["-O0", "-fPIC", "-shared"];
+    #[cfg(not(feature = "static-loading"))]
+    const STRICT: &[&str] = &["-O0", "-fPIC", "-shared", "-Wall", "-Wextra", "-Werror"];
+
+    /// The files that a test creates in the temp directory. They all start with the same stem
+    /// and are removed when this is dropped.
+    #[cfg(not(feature = "static-loading"))]
+    struct TestFiles {
+        stem: String,
+    }
+
+    #[cfg(not(feature = "static-loading"))]
+    impl TestFiles {
+        fn new(name: &str) -> Self {
+            Self {
+                stem: format!("peacock-{}-{}", name, std::process::id()),
+            }
+        }
+
+        fn path(&self, suffix: &str) -> PathBuf {
+            std::env::temp_dir().join(format!("{}{}", self.stem, suffix))
+        }
+    }
+
+    #[cfg(not(feature = "static-loading"))]
+    impl Drop for TestFiles {
+        fn drop(&mut self) {
+            let Ok(entries) = std::fs::read_dir(std::env::temp_dir()) else {
+                return;
+            };
+
+            for entry in entries.flatten() {
+                let name = entry.file_name();
+
+                /* Also remove the headers and the parts of split outputs */
+                if let Some(rest) = name.to_string_lossy().strip_prefix(&self.stem) {
+                    if rest.is_empty() || rest.starts_with(['.', '_']) {
+                        let _ = std::fs::remove_file(entry.path());
+                    }
+                }
+            }
+        }
+    }
+
+    /// A generator that was compiled into a shared object and loaded for a test
+    #[cfg(not(feature = "static-loading"))]
+    struct TestGenerator {
+        lib: libloading::Library,
+        files: TestFiles,
+    }
+
+    #[cfg(not(feature = "static-loading"))]
+    impl TestGenerator {
+        /// Generate the code of `generator` for `cfg`, compile it with `flags` and load it
+        fn compile(generator: CGenerator, cfg: &ContextFreeGrammar, name: &str, flags: &[&str]) -> Self {
+            let files = TestFiles::new(name);
+            let so_file = files.path(".so");
+            let sources = generator.generate(files.path(".c"), cfg);
+            compile(&so_file, &sources, &CompileOptions::new().flags(flags.iter().copied())).unwrap();
+            let lib = unsafe { libloading::Library::new(&so_file) }.unwrap();
+
+            Self {
+                lib,
+                files,
+            }
+        }
+
+        fn get<T>(&self, symbol: &[u8]) -> libloading::Symbol<'_, T> {
+            unsafe { self.lib.get::<T>(symbol) }.unwrap()
+        }
+
+        /// The code of the primary source file
+        fn code(&self) -> String {
+            std::fs::read_to_string(self.files.path(".c")).unwrap()
+        }
+    }
 
     #[test]
     fn test_generator() {
@@ -802,4 +4216,889 @@ mod tests {
             .unwrap();
         CGenerator::new().generate("/tmp/out.c", &cfg);
     }
+
+    #[cfg(not(feature = "static-loading"))]
+    #[test]
+    fn test_thorough_unparsing() {
+        type UnparseFunc = unsafe extern "C" fn(*mut usize, usize, *const u8, usize) -> usize;
+        type SerializeFunc = unsafe extern "C" fn(*const usize, usize, *mut u8, usize) -> usize;
+
+        let cfg = ContextFreeGrammar::builder()
+            .peacock_grammar("test-data/grammars/greedy_unparsing.json")
+            .unwrap()
+            .build()
+            .unwrap();
+        let input = b"xab";
+
+        for thorough in [false, true] {
+            let generator = CGenerator::new().thorough_unparsing(thorough);
+            let lib = TestGenerator::compile(generator, &cfg, &format!("unparse-{}", thorough), SHARED);
+            let unparse = lib.get::<UnparseFunc>(b"unparse_sequence");
+            let serialize = lib.get::<SerializeFunc>(b"serialize_sequence");
+
+            let mut seq = [0usize; 16];
+            let mut out = [0u8; 16];
+            let seq_len = unsafe { unparse(seq.as_mut_ptr(), seq.len(), input.as_ptr(), input.len()) };
+            let out_len = unsafe { serialize(seq.as_ptr(), seq_len, out.as_mut_ptr(), out.len()) };
+
+            if thorough {
+                assert!(seq_len > 0);
+                assert_eq!(&out[..out_len], input);
+            } else {
+                assert_ne!(&out[..out_len], input);
+            }
+        }
+    }
+
+    #[cfg(not(feature = "static-loading"))]
+    #[test]
+    fn test_terminal_blob() {
+        type SeedFunc = unsafe extern "C" fn(u64);
+        type MutateFunc = unsafe extern "C" fn(*mut usize, usize, usize) -> usize;
+        type SerializeFunc = unsafe extern "C" fn(*const usize, usize, *mut u8, usize) -> usize;
+        type UnparseFunc = unsafe extern "C" fn(*mut usize, usize, *const u8, usize) -> usize;
+
+        let cfg = ContextFreeGrammar::builder()
+            .peacock_grammar_str(
+                r#"{
+                    "<ENTRYPOINT>": [["<TYPE>", "' '", "<NAME>", "';'", "<ENTRYPOINT>"], ["<TYPE>", "' '", "<NAME>", "';'"]],
+                    "<TYPE>": [["'integer'"], ["'int'"], ["'eraser'"]],
+                    "<NAME>": [["'ger'"], ["'x'", "<NAME>"]]
+                }"#,
+            )
+            .unwrap()
+            .build()
+            .unwrap();
+
+        for (thorough, files) in [(false, 1), (true, 1), (true, 3)] {
+            let mut outputs = Vec::new();
+
+            for blob in [false, true] {
+                let generator = CGenerator::new().thorough_unparsing(thorough).split_output(files).terminal_blob(blob);
+                let lib =
+                    TestGenerator::compile(generator, &cfg, &format!("blob-{}-{}-{}", thorough, files, blob), STRICT);
+                let code = lib.code();
+                assert_eq!(code.contains("TERMS["), blob);
+                assert_eq!(code.contains("TERMS_INDEX["), blob);
+                assert_eq!(code.contains("TERM0["), !blob);
+
+                let seed = lib.get::<SeedFunc>(b"seed_generator");
+                let mutate = lib.get::<MutateFunc>(b"mutate_sequence");
+                let serialize = lib.get::<SerializeFunc>(b"serialize_sequence");
+                let unparse = lib.get::<UnparseFunc>(b"unparse_sequence");
+
+                let mut seq = vec![0usize; 4096];
+                let mut out = vec![0u8; 1 << 16];
+                let mut output = Vec::new();
+
+                for i in 1..=100 {
+                    unsafe { seed(i) };
+                    let seq_len = unsafe { mutate(seq.as_mut_ptr(), 0, seq.len()) };
+                    let len = unsafe { serialize(seq.as_ptr(), seq_len, out.as_mut_ptr(), out.len()) };
+                    let input = out[..len].to_vec();
+
+                    let mut unparsed = vec![0usize; 4096];
+                    let unparsed_len =
+                        unsafe { unparse(unparsed.as_mut_ptr(), unparsed.len(), input.as_ptr(), input.len()) };
+                    assert!(unparsed_len > 0);
+                    let len = unsafe { serialize(unparsed.as_ptr(), unparsed_len, out.as_mut_ptr(), out.len()) };
+                    assert_eq!(&out[..len], &input[..]);
+
+                    output.push(input);
+                }
+
+                outputs.push(output);
+            }
+
+            assert_eq!(outputs[0], outputs[1]);
+        }
+
+        /* The blob serializes the same walks of a big grammar to the same bytes as the TERMn arrays */
+        let cfg = ContextFreeGrammar::builder()
+            .gramatron_grammar("test-data/static_loading/php.json")
+            .unwrap()
+            .entrypoint("PROGRAM")
+            .build()
+            .unwrap();
+        let mut outputs = Vec::new();
+
+        for blob in [false, true] {
+            let generator = CGenerator::new().terminal_blob(blob);
+            let lib = TestGenerator::compile(generator, &cfg, &format!("blob-php-{}", blob), SHARED);
+            let seed = lib.get::<SeedFunc>(b"seed_generator");
+            let mutate = lib.get::<MutateFunc>(b"mutate_sequence");
+            let serialize = lib.get::<SerializeFunc>(b"serialize_sequence");
+
+            let mut seq = vec![0usize; 4096];
+            let mut out = vec![0u8; 1 << 20];
+            let mut output = Vec::new();
+
+            for i in 1..=100 {
+                unsafe { seed(i) };
+                let seq_len = unsafe { mutate(seq.as_mut_ptr(), 0, seq.len()) };
+                let len = unsafe { serialize(seq.as_ptr(), seq_len, out.as_mut_ptr(), out.len()) };
+                output.push((seq[..seq_len].to_vec(), out[..len].to_vec()));
+            }
+
+            outputs.push(output);
+        }
+
+        assert_eq!(outputs[0], outputs[1]);
+    }
+
+    #[cfg(not(feature = "static-loading"))]
+    #[test]
+    fn test_unparse_terminal_prefix() {
+        type UnparseFunc = unsafe extern "C" fn(*mut usize, usize, *const u8, usize) -> usize;
+
+        /* Both alternatives of <OP> match "==", the one that starts with the longer terminal must win */
+        let cfg = ContextFreeGrammar::builder()
+            .peacock_grammar_str(
+                r#"{"<ENTRYPOINT>": [["'a'", "<OP>", "'b'"]], "<OP>": [["'='", "<EQ>"], ["'=='"]], "<EQ>": [["'='"]]}"#,
+            )
+            .unwrap()
+            .optimize(false)
+            .build()
+            .unwrap();
+        let lib = TestGenerator::compile(CGenerator::new(), &cfg, "prefix-order", SHARED);
+        let unparse = lib.get::<UnparseFunc>(b"unparse_sequence");
+
+        let input = b"a==b";
+        let mut seq = [0usize; 16];
+        let seq_len = unsafe { unparse(seq.as_mut_ptr(), seq.len(), input.as_ptr(), input.len()) };
+        assert_eq!(&seq[..seq_len], &[0, 1]);
+    }
+
+    #[cfg(not(feature = "static-loading"))]
+    #[test]
+    fn test_unparse_order() {
+        type UnparseFunc = unsafe extern "C" fn(*mut usize, usize, *const u8, usize) -> usize;
+
+        /* Both alternatives of <X> consume the whole input */
+        let cfg = ContextFreeGrammar::builder()
+            .peacock_grammar_str(
+                r#"{"<ENTRYPOINT>": [["<X>"]], "<X>": [["<A>"], ["<B>"]], "<A>": [["'x'"]], "<B>": [["'x'"]]}"#,
+            )
+            .unwrap()
+            .optimize(false)
+            .build()
+            .unwrap();
+        let generators = [
+            (CGenerator::new(), [0, 0, 0]),
+            (CGenerator::new().rule_frequencies(HashMap::from([("X".to_string(), vec![1, 5])])), [0, 0, 0]),
+            (
+                CGenerator::new()
+                    .unparse_order(UnparseOrder::Frequency)
+                    .rule_frequencies(HashMap::from([("X".to_string(), vec![1, 5])])),
+                [0, 1, 0],
+            ),
+        ];
+
+        for (i, (generator, expected)) in generators.into_iter().enumerate() {
+            let lib = TestGenerator::compile(generator, &cfg, &format!("unparse-order-{}", i), SHARED);
+            let unparse = lib.get::<UnparseFunc>(b"unparse_sequence");
+
+            let mut seq = [0usize; 16];
+            let seq_len = unsafe { unparse(seq.as_mut_ptr(), seq.len(), b"x".as_ptr(), 1) };
+            assert_eq!(&seq[..seq_len], &expected);
+        }
+
+        /* Measured frequencies only reorder alternatives that are equally long */
+        let cfg = ContextFreeGrammar::builder()
+            .peacock_grammar_str(r#"{"<ENTRYPOINT>": [["<K>"]], "<K>": [["'if'"], ["'do'"], ["'else'"]]}"#)
+            .unwrap()
+            .optimize(false)
+            .build()
+            .unwrap();
+        let files = TestFiles::new("unparse-measured");
+        CGenerator::new()
+            .unparse_order(UnparseOrder::Frequency)
+            .measure_rule_frequencies(["do", "do", "if", "invalid"])
+            .generate(files.path(".c"), &cfg);
+        let code = std::fs::read_to_string(files.path(".c")).unwrap();
+        let function = &code[code.find("unparsing function for non-terminal \"K\"").unwrap()..];
+        let position = |rule: &str| function.find(&format!("// Rule #{}\n", rule)).unwrap();
+        assert!(position("2") < position("1") && position("1") < position("0"));
+    }
+
+    #[cfg(not(feature = "static-loading"))]
+    #[test]
+    fn test_repeat_terminals() {
+        type UnparseFunc = unsafe extern "C" fn(*mut usize, usize, *const u8, usize) -> usize;
+        type SerializeFunc = unsafe extern "C" fn(*const usize, usize, *mut u8, usize) -> usize;
+
+        let cfg = ContextFreeGrammar::builder()
+            .peacock_grammar_str(r#"{"<ENTRYPOINT>": [["'<'", "@repeat('A', 4096)", "'>'"], ["'x'"]]}"#)
+            .unwrap()
+            .optimize(false)
+            .build()
+            .unwrap();
+        let mut input = vec![b'A'; 4096];
+        input.insert(0, b'<');
+        input.push(b'>');
+
+        for thorough in [false, true] {
+            let generator = CGenerator::new().thorough_unparsing(thorough);
+            let lib = TestGenerator::compile(generator, &cfg, &format!("repeat-{}", thorough), SHARED);
+            assert!(!lib.code().contains(&"A".repeat(64)));
+
+            let unparse = lib.get::<UnparseFunc>(b"unparse_sequence");
+            let serialize = lib.get::<SerializeFunc>(b"serialize_sequence");
+
+            let mut seq = [0usize; 16];
+            let mut out = vec![0u8; 8192];
+            let out_len = unsafe { serialize(seq.as_ptr(), 2, out.as_mut_ptr(), out.len()) };
+            assert!(out[..out_len] == input);
+            assert!(unsafe { serialize(seq.as_ptr(), 2, out.as_mut_ptr(), 100) } <= 100);
+
+            seq[1] = 1;
+            let seq_len = unsafe { unparse(seq.as_mut_ptr(), seq.len(), input.as_ptr(), input.len()) };
+            assert_eq!(&seq[..seq_len], &[0, 0]);
+
+            input[4000] = b'B';
+            assert_eq!(unsafe { unparse(seq.as_mut_ptr(), seq.len(), input.as_ptr(), input.len()) }, 0);
+            input[4000] = b'A';
+        }
+    }
+
+    #[cfg(not(feature = "static-loading"))]
+    #[test]
+    fn test_bindings() {
+        use crate::backends::interpreter::tests::tags_match;
+
+        type MutateFunc = unsafe extern "C" fn(*mut usize, usize, usize) -> usize;
+        type SerializeFunc = unsafe extern "C" fn(*const usize, usize, *mut u8, usize) -> usize;
+        type UnparseFunc = unsafe extern "C" fn(*mut usize, usize, *const u8, usize) -> usize;
+
+        let cfg = ContextFreeGrammar::builder()
+            .peacock_grammar("test-data/grammars/xml.json")
+            .unwrap()
+            .context_sensitive(true)
+            .build()
+            .unwrap();
+
+        for (thorough, files) in [(false, 1), (true, 1), (true, 3)] {
+            let generator = CGenerator::new().thorough_unparsing(thorough).split_output(files);
+            let lib = TestGenerator::compile(generator, &cfg, &format!("bindings-{}-{}", thorough, files), STRICT);
+            let mutate = lib.get::<MutateFunc>(b"mutate_sequence");
+            let serialize = lib.get::<SerializeFunc>(b"serialize_sequence");
+            let unparse = lib.get::<UnparseFunc>(b"unparse_sequence");
+            let mut seq = vec![0usize; 4096];
+            let mut copy = vec![0usize; 4096];
+            let mut out = vec![0u8; 1 << 16];
+            let mut reserialized = vec![0u8; 1 << 16];
+            let mut seq_len = 0;
+
+            for _ in 0..1000 {
+                seq_len = unsafe { mutate(seq.as_mut_ptr(), seq_len, seq.len()) };
+                let out_len = unsafe { serialize(seq.as_ptr(), seq_len, out.as_mut_ptr(), out.len()) };
+                assert!(tags_match(&out[..out_len]), "{}", String::from_utf8_lossy(&out[..out_len]));
+
+                let copy_len = unsafe { unparse(copy.as_mut_ptr(), copy.len(), out.as_ptr(), out_len) };
+                assert!(copy_len > 0);
+                let len = unsafe { serialize(copy.as_ptr(), copy_len, reserialized.as_mut_ptr(), reserialized.len()) };
+                assert_eq!(&reserialized[..len], &out[..out_len]);
+            }
+
+            for input in [&b"<a></b>"[..], b"<div><a></div></a>", b"<a></>"] {
+                assert_eq!(unsafe { unparse(seq.as_mut_ptr(), seq.len(), input.as_ptr(), input.len()) }, 0);
+            }
+
+            /* Truncated uses */
+            let input = b"<span></span>";
+            let seq_len = unsafe { unparse(seq.as_mut_ptr(), seq.len(), input.as_ptr(), input.len()) };
+            assert!(seq_len > 0);
+            assert!(unsafe { serialize(seq.as_ptr(), seq_len, out.as_mut_ptr(), 10) } <= 10);
+        }
+    }
+
+    #[cfg(not(feature = "static-loading"))]
+    #[test]
+    fn test_builtins() {
+        use crate::backends::interpreter::{
+            GrammarInterpreter,
+            Unparser,
+        };
+
+        type SeedFunc = unsafe extern "C" fn(u64);
+        type MutateFunc = unsafe extern "C" fn(*mut usize, usize, usize) -> usize;
+        type SerializeFunc = unsafe extern "C" fn(*const usize, usize, *mut u8, usize) -> usize;
+        type UnparseFunc = unsafe extern "C" fn(*mut usize, usize, *const u8, usize) -> usize;
+        type ValidateFunc = unsafe extern "C" fn(*const usize, usize) -> i32;
+
+        let cfg =
+            ContextFreeGrammar::builder().peacock_grammar("test-data/grammars/builtins.json").unwrap().build().unwrap();
+        let ll = LowLevelGrammar::from_high_level_grammar(&cfg);
+        let unparser = Unparser::new(&ll);
+        let profile = GenerationProfile::new().length_scale(0.5).value_scale(0.01);
+
+        for (thorough, files) in [(false, 1), (true, 1), (true, 3)] {
+            let generator = CGenerator::new().thorough_unparsing(thorough).split_output(files).profile(profile);
+            let lib = TestGenerator::compile(generator, &cfg, &format!("builtins-{}-{}", thorough, files), STRICT);
+            let seed = lib.get::<SeedFunc>(b"seed_generator");
+            let mutate = lib.get::<MutateFunc>(b"mutate_sequence");
+            let serialize = lib.get::<SerializeFunc>(b"serialize_sequence");
+            let unparse = lib.get::<UnparseFunc>(b"unparse_sequence");
+            let validate = lib.get::<ValidateFunc>(b"validate_sequence");
+
+            let mut interpreter = GrammarInterpreter::new(&cfg);
+            interpreter.profile(profile);
+            let mut expected = Vec::new();
+            let mut walk = Vec::new();
+            let mut seq = vec![0usize; 4096];
+            let mut out = vec![0u8; 1 << 16];
+
+            /* The generated code draws the same values as the interpreter */
+            for i in 1..=200 {
+                unsafe { seed(i) };
+                interpreter.seed(i);
+
+                let seq_len = unsafe { mutate(seq.as_mut_ptr(), 0, seq.len()) };
+                let len = unsafe { serialize(seq.as_ptr(), seq_len, out.as_mut_ptr(), out.len()) };
+                interpreter.interpret_into(&mut expected, usize::MAX, Some(&mut walk));
+                assert_eq!(&out[..len], &expected[..]);
+                assert!(seq[..seq_len].iter().eq(walk.iter().map(|(_, step)| step)));
+                assert_eq!(unsafe { validate(seq.as_ptr(), seq_len) }, 1);
+            }
+
+            /* Every value of a builtin is a valid step */
+            for (step, (nonterm, _)) in walk.iter().enumerate() {
+                if ll.builtin(*nonterm).is_some() {
+                    seq[step] = usize::MAX - step;
+                }
+            }
+
+            let walk: Vec<(usize, usize)> =
+                walk.iter().enumerate().map(|(step, (nonterm, _))| (*nonterm, seq[step])).collect();
+            expected.clear();
+            interpreter.replay(&walk, &mut expected).unwrap();
+            let len = unsafe { serialize(seq.as_ptr(), walk.len(), out.as_mut_ptr(), out.len()) };
+            assert_eq!(&out[..len], &expected[..]);
+            assert_eq!(unsafe { validate(seq.as_ptr(), walk.len()) }, 1);
+
+            /* Integers are unparsed like in the interpreter, random bytes only if they are empty */
+            for input in [&b"12 x-9223372036854775808 b| -1000"[..], b"x9223372036854775807", b"1000 0 -7 b|"] {
+                let seq_len = unsafe { unparse(seq.as_mut_ptr(), seq.len(), input.as_ptr(), input.len()) };
+                let walk = unparser.unparse(input).unwrap();
+                assert!(seq[..seq_len].iter().eq(walk.iter().map(|(_, step)| step)));
+
+                let len = unsafe { serialize(seq.as_ptr(), seq_len, out.as_mut_ptr(), out.len()) };
+                assert_eq!(&out[..len], input);
+            }
+
+            for input in [&b"1001"[..], b"007", b"-0", b"c", b"x9223372036854775808", b"b12|"] {
+                assert_eq!(unsafe { unparse(seq.as_mut_ptr(), seq.len(), input.as_ptr(), input.len()) }, 0);
+                assert!(unparser.unparse(input).is_err());
+            }
+        }
+    }
+
+    #[cfg(not(feature = "static-loading"))]
+    #[test]
+    fn test_memoized_unparsing() {
+        type MutateFunc = unsafe extern "C" fn(*mut usize, usize, usize) -> usize;
+        type SerializeFunc = unsafe extern "C" fn(*const usize, usize, *mut u8, usize) -> usize;
+        type UnparseFunc = unsafe extern "C" fn(*mut usize, usize, *const u8, usize) -> usize;
+        type UnparseMemFunc = unsafe extern "C" fn(*mut usize, usize, *const u8, usize, *mut u8, usize) -> usize;
+
+        let nested = |depth: usize, inner: &str| format!("{}{}{}", "(".repeat(depth), inner, ")".repeat(depth));
+        let ambiguous = ContextFreeGrammar::builder()
+            .peacock_grammar("test-data/grammars/ambiguous.json")
+            .unwrap()
+            .build()
+            .unwrap();
+        let xml = ContextFreeGrammar::builder()
+            .peacock_grammar("test-data/grammars/xml.json")
+            .unwrap()
+            .context_sensitive(true)
+            .build()
+            .unwrap();
+
+        for (name, cfg) in [("ambiguous", &ambiguous), ("xml", &xml)] {
+            for (thorough, files) in [(false, 1), (true, 3)] {
+                let generator =
+                    CGenerator::new().thorough_unparsing(thorough).memoized_unparsing(true).split_output(files);
+                let lib =
+                    TestGenerator::compile(generator, cfg, &format!("memo-{}-{}-{}", name, thorough, files), STRICT);
+                let mutate = lib.get::<MutateFunc>(b"mutate_sequence");
+                let serialize = lib.get::<SerializeFunc>(b"serialize_sequence");
+                let unparse = lib.get::<UnparseFunc>(b"unparse_sequence");
+                let unparse_mem = lib.get::<UnparseMemFunc>(b"unparse_sequence_mem");
+
+                let mut scratch = vec![0u8; 1 << 20];
+                let mut seq = vec![0usize; 1 << 16];
+                let mut expected = vec![0usize; 1 << 16];
+                let mut out = vec![0u8; 1 << 16];
+
+                /* Small inputs have the same walk with and without the memo table, also if it does not fit into scratch */
+                let mut inputs: Vec<Vec<u8>> = (0..200)
+                    .map(|_| {
+                        let len = unsafe { mutate(seq.as_mut_ptr(), 0, 4096) };
+                        let len = unsafe { serialize(seq.as_ptr(), len, out.as_mut_ptr(), out.len()) };
+                        out[..len].to_vec()
+                    })
+                    .filter(|input| input.len() < 256)
+                    .collect();
+                inputs.push(nested(6, "x+x*x").into_bytes());
+                inputs.push(b"(x-(x*(x+x))".to_vec());
+
+                for input in &inputs {
+                    let len = unsafe { unparse(expected.as_mut_ptr(), expected.len(), input.as_ptr(), input.len()) };
+
+                    for scratch_len in [scratch.len(), 64, 0] {
+                        let mem_len = unsafe {
+                            unparse_mem(
+                                seq.as_mut_ptr(),
+                                seq.len(),
+                                input.as_ptr(),
+                                input.len(),
+                                scratch.as_mut_ptr().wrapping_add(1),
+                                scratch_len.saturating_sub(1),
+                            )
+                        };
+                        assert_eq!(&seq[..mem_len], &expected[..len]);
+                    }
+                }
+
+                if name != "ambiguous" {
+                    continue;
+                }
+
+                /* The unmemoized unparser would expand 4^64 non-terminals */
+                let input = nested(64, "x-x").into_bytes();
+                let len = unsafe {
+                    unparse_mem(
+                        seq.as_mut_ptr(),
+                        seq.len(),
+                        input.as_ptr(),
+                        input.len(),
+                        scratch.as_mut_ptr(),
+                        scratch.len(),
+                    )
+                };
+                assert!(len > 0);
+                let out_len = unsafe { serialize(seq.as_ptr(), len, out.as_mut_ptr(), out.len()) };
+                assert_eq!(&out[..out_len], &input[..]);
+
+                /* Once the sequence is full, the memoized unparser gives up */
+                let len = unsafe {
+                    unparse_mem(seq.as_mut_ptr(), 100, input.as_ptr(), input.len(), scratch.as_mut_ptr(), scratch.len())
+                };
+                assert_eq!(len, 0);
+            }
+        }
+    }
+
+    #[cfg(not(feature = "static-loading"))]
+    #[test]
+    fn test_bounded_unparsing() {
+        type MutateFunc = unsafe extern "C" fn(*mut usize, usize, usize) -> usize;
+        type SerializeFunc = unsafe extern "C" fn(*const usize, usize, *mut u8, usize) -> usize;
+        type UnparseFunc = unsafe extern "C" fn(*mut usize, usize, *const u8, usize) -> usize;
+        type UnparseBoundedFunc =
+            unsafe extern "C" fn(*mut usize, usize, *const u8, usize, usize, usize, *mut i32) -> usize;
+
+        let cfg = ContextFreeGrammar::builder()
+            .peacock_grammar_str(
+                r#"{
+                    "<ENTRYPOINT>": [["<WORD>", "' '", "<ENTRYPOINT>"], ["<WORD>"]],
+                    "<WORD>": [["'hello'"], ["'world'"], ["@repeat('z', 100)"]]
+                }"#,
+            )
+            .unwrap()
+            .optimize(false)
+            .build()
+            .unwrap();
+        let words = ["hello", "world", &"z".repeat(100)];
+        let input = (0..10000).map(|i| words[i % 3]).collect::<Vec<_>>().join(" ").into_bytes();
+
+        for thorough in [false, true] {
+            let generator = CGenerator::new().thorough_unparsing(thorough);
+            let lib = TestGenerator::compile(generator, &cfg, &format!("bounded-{}", thorough), STRICT);
+            let mutate = lib.get::<MutateFunc>(b"mutate_sequence");
+            let serialize = lib.get::<SerializeFunc>(b"serialize_sequence");
+            let unparse = lib.get::<UnparseFunc>(b"unparse_sequence");
+            let unparse_bounded = lib.get::<UnparseBoundedFunc>(b"unparse_sequence_bounded");
+            let mut seq = vec![0usize; 1 << 16];
+            let mut out = vec![0u8; 1 << 22];
+            let mut truncated = 0;
+
+            /* The walk does not fit into the sequence */
+            assert_eq!(unsafe { unparse(seq.as_mut_ptr(), 1000, input.as_ptr(), input.len()) }, 0);
+
+            for (max_bytes, max_steps) in [(1000, usize::MAX), (usize::MAX, 100), (1001, 1000)] {
+                let seq_len = unsafe {
+                    unparse_bounded(
+                        seq.as_mut_ptr(),
+                        seq.len(),
+                        input.as_ptr(),
+                        input.len(),
+                        max_bytes,
+                        max_steps,
+                        &mut truncated,
+                    )
+                };
+                assert!(seq_len > 0 && seq_len <= max_steps);
+                assert_eq!(truncated, 1);
+
+                let out_len = unsafe { serialize(seq.as_ptr(), seq_len, out.as_mut_ptr(), out.len()) };
+                assert!(out_len > 0 && out_len < input.len());
+                assert!(input.starts_with(&out[..out_len]));
+
+                /* Mutations grow the walk back into a complete one */
+                let seq_len = unsafe { mutate(seq.as_mut_ptr(), seq_len, seq.len()) };
+                let out_len = unsafe { serialize(seq.as_ptr(), seq_len, out.as_mut_ptr(), out.len()) };
+                let mut copy = vec![0usize; seq.len()];
+                assert!(unsafe { unparse(copy.as_mut_ptr(), copy.len(), out.as_ptr(), out_len) } > 0);
+            }
+
+            /* Inputs within the bounds are unparsed completely */
+            let seq_len = unsafe {
+                unparse_bounded(
+                    seq.as_mut_ptr(),
+                    seq.len(),
+                    input.as_ptr(),
+                    input.len(),
+                    input.len(),
+                    1 << 16,
+                    &mut truncated,
+                )
+            };
+            assert_eq!(truncated, 0);
+            let out_len = unsafe { serialize(seq.as_ptr(), seq_len, out.as_mut_ptr(), out.len()) };
+            assert!(out[..out_len] == input);
+
+            /* Only inputs that are cut off get a partial walk */
+            for (invalid, max_bytes) in [(&b"hello  world"[..], 100), (b"xhello world", 8)] {
+                let seq_len = unsafe {
+                    unparse_bounded(
+                        seq.as_mut_ptr(),
+                        seq.len(),
+                        invalid.as_ptr(),
+                        invalid.len(),
+                        max_bytes,
+                        100,
+                        &mut truncated,
+                    )
+                };
+                assert_eq!((seq_len, truncated), (0, 0));
+            }
+        }
+    }
+
+    #[cfg(not(feature = "static-loading"))]
+    #[test]
+    fn test_recursion_decay() {
+        type SeedFunc = unsafe extern "C" fn(u64);
+        type MutateFunc = unsafe extern "C" fn(*mut usize, usize, usize) -> usize;
+        type MaxDepthFunc = unsafe extern "C" fn(usize);
+
+        const CAPACITY: usize = 24;
+        const ROUNDS: u64 = 1000;
+
+        let cfg = ContextFreeGrammar::builder()
+            .gramatron_grammar("test-data/static_loading/php.json")
+            .unwrap()
+            .entrypoint("PROGRAM")
+            .build()
+            .unwrap();
+        let mut truncations = Vec::new();
+
+        for decay in [1.0, 0.5] {
+            let generator = CGenerator::new().recursion_decay(decay);
+            let lib = TestGenerator::compile(generator, &cfg, &format!("decay-{}", decay), SHARED);
+            let seed = lib.get::<SeedFunc>(b"seed_generator");
+            let mutate = lib.get::<MutateFunc>(b"mutate_sequence");
+            let set_max_depth = lib.get::<MaxDepthFunc>(b"set_max_depth");
+
+            /* Without decay, also measure how a depth limit keeps walks short */
+            let limits: &[usize] = if decay < 1.0 { &[usize::MAX] } else { &[usize::MAX, 4] };
+
+            for limit in limits {
+                let mut buf = [0usize; CAPACITY];
+                let mut truncated = 0;
+
+                unsafe { set_max_depth(*limit) };
+
+                for i in 1..=ROUNDS {
+                    unsafe { seed(i) };
+
+                    if unsafe { mutate(buf.as_mut_ptr(), 0, CAPACITY) } == CAPACITY {
+                        truncated += 1;
+                    }
+                }
+
+                truncations.push(truncated);
+            }
+        }
+
+        assert!(truncations[1] < truncations[0]);
+        assert!(truncations[2] < truncations[0]);
+    }
+
+    #[cfg(not(feature = "static-loading"))]
+    #[test]
+    fn test_standalone_main() {
+        use crate::backends::interpreter::GrammarInterpreter;
+
+        let cfg = ContextFreeGrammar::builder()
+            .peacock_grammar_str(
+                r#"{"<ENTRYPOINT>": [["<DIGIT>", "<ENTRYPOINT>"], ["<DIGIT>"]], "<DIGIT>": [["'0'"], ["'1'"], ["'2'"]]}"#,
+            )
+            .unwrap()
+            .build()
+            .unwrap();
+        let files = TestFiles::new("standalone");
+        let exe_file = files.path("");
+        let sources =
+            CGenerator::new().emit_standalone_main(true).symbol_prefix("standalone_").generate(files.path(".c"), &cfg);
+        compile(
+            &exe_file,
+            &sources,
+            &CompileOptions::new().flags(["-O0", "-Wall", "-Wextra", "-Werror"]).define("PEACOCK_STANDALONE"),
+        )
+        .unwrap();
+
+        for seed in [1, 1234] {
+            let output = std::process::Command::new(&exe_file).arg(seed.to_string()).output().unwrap();
+            assert!(output.status.success());
+
+            let mut interpreter = GrammarInterpreter::new(&cfg);
+            interpreter.seed(seed);
+            let mut expected = Vec::new();
+            interpreter.interpret(&mut expected).unwrap();
+            assert_eq!(output.stdout, expected);
+        }
+
+        let output = std::process::Command::new(&exe_file).args(["1", "2"]).output().unwrap();
+        assert!(!output.status.success());
+
+        /* Without the define the code stays a library */
+        let so_file = files.path(".so");
+        compile(&so_file, &sources, &CompileOptions::new().flags(SHARED.iter().copied())).unwrap();
+        let lib = unsafe { libloading::Library::new(&so_file) }.unwrap();
+        assert!(unsafe { lib.get::<unsafe extern "C" fn()>(b"main") }.is_err());
+    }
+
+    #[cfg(not(feature = "static-loading"))]
+    #[test]
+    fn test_header_with_source() {
+        let cfg = ContextFreeGrammar::builder()
+            .peacock_grammar_str(r#"{"<ENTRYPOINT>": [["'a'", "<ENTRYPOINT>"], ["'b'"]]}"#)
+            .unwrap()
+            .build()
+            .unwrap();
+        let files = TestFiles::new("header-with-source");
+        CGenerator::new().generate(files.path(".c"), &cfg);
+
+        /* The header and the generated code define the shared types only once */
+        let unity = files.path("_unity.c");
+        std::fs::write(
+            &unity,
+            format!("#include \"{}\"\n#include \"{}\"\n", files.path(".h").display(), files.path(".c").display()),
+        )
+        .unwrap();
+        compile(files.path(".so"), &[unity], &CompileOptions::new().flags(STRICT.iter().copied())).unwrap();
+    }
+
+    #[cfg(not(feature = "static-loading"))]
+    #[test]
+    fn test_freestanding() {
+        use crate::{
+            backends::interpreter::GrammarInterpreter,
+            error::CompileError,
+        };
+
+        type SeedFunc = unsafe extern "C" fn(u64);
+        type MutateFunc = unsafe extern "C" fn(*mut usize, usize, usize) -> usize;
+        type SerializeFunc = unsafe extern "C" fn(*const usize, usize, *mut u8, usize) -> usize;
+        type UnparseFunc = unsafe extern "C" fn(*mut usize, usize, *const u8, usize) -> usize;
+
+        let cfg = ContextFreeGrammar::builder()
+            .peacock_grammar_str(
+                r#"{"<ENTRYPOINT>": [["<WORD>", "' '", "<ENTRYPOINT>"], ["<WORD>"]], "<WORD>": [["'foo'"], ["'bar'"], ["'foobar'"]]}"#,
+            )
+            .unwrap()
+            .build()
+            .unwrap();
+        let files = TestFiles::new("freestanding");
+        let c_file = files.path(".c");
+        let obj_file = files.path(".o");
+        let sources = CGenerator::new().freestanding(true).thorough_unparsing(true).generate(&c_file, &cfg);
+
+        let code = std::fs::read_to_string(&c_file).unwrap();
+        assert!(!code.contains("__thread"));
+        assert!(!code.contains("__builtin_mem"));
+        let header = std::fs::read_to_string(c_file.with_extension("h")).unwrap();
+        assert!(header.contains("extern \"C\" {"));
+
+        let options = CompileOptions::new().flags([
+            "-O2",
+            "-fPIC",
+            "-ffreestanding",
+            "-nostdlib",
+            "-Wall",
+            "-Wextra",
+            "-Werror",
+            "-c",
+        ]);
+
+        match compile(&obj_file, &sources, &options) {
+            Err(CompileError::Launch {
+                ..
+            }) => return,
+            result => result.unwrap(),
+        }
+
+        /* The object must not reference anything from libc, e.g. memcpy() */
+        let so_file = files.path(".so");
+        let link = CompileOptions::new().flags(["-nostdlib", "-shared", "-Wl,-z,defs"]);
+        compile(&so_file, std::slice::from_ref(&obj_file), &link).unwrap();
+
+        /* A different width of size_t must be acknowledged */
+        assert!(compile(&obj_file, &sources, &options.define("PEACOCK_SIZE_T_WIDTH=3")).is_err());
+
+        /* The replacements of the builtins still work */
+        let generator = CGenerator::new().freestanding(true).thorough_unparsing(true);
+        let lib = TestGenerator::compile(generator, &cfg, "freestanding-hosted", SHARED);
+        let seed = lib.get::<SeedFunc>(b"seed_generator");
+        let mutate = lib.get::<MutateFunc>(b"mutate_sequence");
+        let serialize = lib.get::<SerializeFunc>(b"serialize_sequence");
+        let unparse = lib.get::<UnparseFunc>(b"unparse_sequence");
+        let mut seq = vec![0usize; 4096];
+        let mut out = vec![0u8; 1 << 16];
+
+        for i in 1..100 {
+            unsafe { seed(i) };
+            let seq_len = unsafe { mutate(seq.as_mut_ptr(), 0, seq.len()) };
+            let out_len = unsafe { serialize(seq.as_ptr(), seq_len, out.as_mut_ptr(), out.len()) };
+
+            let mut interpreter = GrammarInterpreter::new(&cfg);
+            interpreter.seed(i);
+            let mut expected = Vec::new();
+            interpreter.interpret(&mut expected).unwrap();
+            assert_eq!(&out[..out_len], &expected[..]);
+
+            let mut unparsed = vec![0usize; 4096];
+            let unparsed_len = unsafe { unparse(unparsed.as_mut_ptr(), unparsed.len(), out.as_ptr(), out_len) };
+            let reserialized_len = unsafe { serialize(unparsed.as_ptr(), unparsed_len, out.as_mut_ptr(), out.len()) };
+            assert_eq!(&out[..reserialized_len], &expected[..]);
+        }
+    }
+
+    #[cfg(not(feature = "static-loading"))]
+    #[test]
+    fn test_single_rule_grammar() {
+        type MutateFunc = unsafe extern "C" fn(*mut usize, usize, usize) -> usize;
+        type SerializeFunc = unsafe extern "C" fn(*const usize, usize, *mut u8, usize) -> usize;
+        type UnparseFunc = unsafe extern "C" fn(*mut usize, usize, *const u8, usize) -> usize;
+
+        for optimize in [false, true] {
+            let cfg = ContextFreeGrammar::builder()
+                .peacock_grammar_str(r#"{"<ENTRYPOINT>": [["'x'"]]}"#)
+                .unwrap()
+                .optimize(optimize)
+                .build()
+                .unwrap();
+            let lib = TestGenerator::compile(CGenerator::new(), &cfg, &format!("single-{}", optimize), STRICT);
+            let mutate = lib.get::<MutateFunc>(b"mutate_sequence");
+            let serialize = lib.get::<SerializeFunc>(b"serialize_sequence");
+            let unparse = lib.get::<UnparseFunc>(b"unparse_sequence");
+            let mut seq = [0usize; 16];
+            let mut out = [0u8; 16];
+
+            let seq_len = unsafe { mutate(seq.as_mut_ptr(), 0, seq.len()) };
+            let out_len = unsafe { serialize(seq.as_ptr(), seq_len, out.as_mut_ptr(), out.len()) };
+            assert_eq!(&out[..out_len], b"x");
+
+            let seq_len = unsafe { unparse(seq.as_mut_ptr(), seq.len(), b"x".as_ptr(), 1) };
+            assert!(seq_len > 0);
+            assert_eq!(unsafe { unparse(seq.as_mut_ptr(), seq.len(), b"y".as_ptr(), 1) }, 0);
+        }
+    }
+
+    #[cfg(not(feature = "static-loading"))]
+    #[test]
+    fn test_combined_expansions() {
+        use crate::backends::interpreter::GrammarInterpreter;
+
+        type SeedFunc = unsafe extern "C" fn(u64);
+        type MutateFunc = unsafe extern "C" fn(*mut usize, usize, usize) -> usize;
+        type SerializeFunc = unsafe extern "C" fn(*const usize, usize, *mut u8, usize) -> usize;
+        type CountersFunc = unsafe extern "C" fn(*mut *mut usize, *mut usize);
+
+        /* <PAIR> always derives "key=v" in 4 steps and <NIL> derives nothing in 2 steps */
+        let cfg = ContextFreeGrammar::builder()
+            .peacock_grammar_str(
+                r#"{
+                    "<ENTRYPOINT>": [["<PAIR>", "<NIL>", "';'", "<ENTRYPOINT>"], ["<PAIR>"], ["'x'", "<ENTRYPOINT>"]],
+                    "<PAIR>": [["<KEY>", "'='", "<VALUE>"]],
+                    "<KEY>": [["'key'"]],
+                    "<VALUE>": [["<EMPTY>", "'v'"]],
+                    "<EMPTY>": [["''"]],
+                    "<NIL>": [["<EMPTY>"]]
+                }"#,
+            )
+            .unwrap()
+            .optimize(false)
+            .build()
+            .unwrap();
+        let ll = LowLevelGrammar::from_high_level_grammar(&cfg);
+        let mut combined: Vec<(String, usize)> = combined_expansions(&ll)
+            .into_values()
+            .map(|(content, nonterminals)| (content, nonterminals.len()))
+            .collect();
+        combined.sort();
+        assert_eq!(combined, vec![("".to_string(), 2), ("key=v".to_string(), 4), ("v".to_string(), 2)]);
+
+        let mut outputs = Vec::new();
+
+        for combine in [false, true] {
+            let generator = CGenerator::new().emit_statistics(true).combine_constant_expansions(combine);
+            let lib = TestGenerator::compile(generator, &cfg, &format!("combined-{}", combine), SHARED);
+            assert_eq!(lib.code().contains("always takes 4 steps and produces 5 bytes"), combine);
+
+            let seed = lib.get::<SeedFunc>(b"seed_generator");
+            let mutate = lib.get::<MutateFunc>(b"mutate_sequence");
+            let serialize = lib.get::<SerializeFunc>(b"serialize_sequence");
+            let get_rule_counters = lib.get::<CountersFunc>(b"get_rule_counters");
+
+            let mut interpreter = GrammarInterpreter::new(&cfg);
+            let mut expected = Vec::new();
+            let mut seq = vec![0usize; 4096];
+            let mut out = vec![0u8; 1 << 16];
+            let mut output = Vec::new();
+
+            for i in 1..=200 {
+                unsafe { seed(i) };
+                interpreter.seed(i);
+
+                let seq_len = unsafe { mutate(seq.as_mut_ptr(), 0, seq.len()) };
+                let len = unsafe { serialize(seq.as_ptr(), seq_len, out.as_mut_ptr(), out.len()) };
+                interpreter.interpret_into(&mut expected, usize::MAX, None);
+                assert_eq!(&out[..len], &expected[..]);
+
+                /* Short buffers and walks must be handled like before */
+                for out_len in 1..len {
+                    let clipped = unsafe { serialize(seq.as_ptr(), seq_len, out.as_mut_ptr(), out_len) };
+                    output.push(out[..clipped].to_vec());
+                }
+
+                for short in 1..seq_len {
+                    let clipped = unsafe { serialize(seq.as_ptr(), short, out.as_mut_ptr(), out.len()) };
+                    output.push(out[..clipped].to_vec());
+                }
+            }
+
+            let mut counters = std::ptr::null_mut();
+            let mut num_counters = 0;
+            unsafe { get_rule_counters(&mut counters, &mut num_counters) };
+            let counters = unsafe { std::slice::from_raw_parts(counters, num_counters) }.to_vec();
+
+            outputs.push((output, counters));
+        }
+
+        /* Also every step of a walk must be counted like before */
+        assert_eq!(outputs[0], outputs[1]);
+    }
 }
@@ -0,0 +1,304 @@
+use std::{
+    path::{
+        Path,
+        PathBuf,
+    },
+    process::Command,
+};
+
+use crate::{
+    backends::C::CGenerator,
+    error::CompileError,
+    grammar::ContextFreeGrammar,
+};
+
+/// The flags that `peacock-fuzz` compiles generators with.
+const DEFAULT_FLAGS: [&str; 10] = [
+    "-flto",
+    "-s",
+    "-fvisibility=hidden",
+    "-DMAKE_VISIBLE",
+    "-march=native",
+    "-fomit-frame-pointer",
+    "-fno-stack-protector",
+    "-fPIC",
+    "-shared",
+    "-nostdlib",
+];
+
+/// Flags that only make sense when linking and are dropped for static archives.
+const LINK_FLAGS: [&str; 4] = ["-flto", "-s", "-shared", "-nostdlib"];
+
+/// Options for [`compile()`].
+pub struct CompileOptions {
+    compiler: String,
+    archiver: String,
+    opt_level: String,
+    flags: Vec<String>,
+    defines: Vec<String>,
+    static_archive: bool,
+}
+
+impl CompileOptions {
+    /// Create new options with the compiler from the environment variable `CC` or `cc` and the
+    /// flags that produce an optimized shared object.
+    #[allow(clippy::new_without_default)]
+    pub fn new() -> Self {
+        Self {
+            compiler: std::env::var("CC").unwrap_or_else(|_| "cc".to_string()),
+            archiver: std::env::var("AR").unwrap_or_else(|_| "ar".to_string()),
+            opt_level: "fast".to_string(),
+            flags: DEFAULT_FLAGS.iter().map(|x| x.to_string()).collect(),
+            defines: Vec::new(),
+            static_archive: false,
+        }
+    }
+
+    /// Use the C compiler `compiler`.
+    pub fn compiler<S: Into<String>>(mut self, compiler: S) -> Self {
+        self.compiler = compiler.into();
+        self
+    }
+
+    /// Use the optimization level `-O<level>`. An optimization level in the flags takes precedence.
+    ///
+    /// Default: `fast`
+    pub fn opt_level<S: Into<String>>(mut self, level: S) -> Self {
+        self.opt_level = level.into();
+        self
+    }
+
+    /// Replace all flags with `flags`.
+    pub fn flags<I, S>(mut self, flags: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.flags = flags.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Pass the additional flag `flag` to the compiler.
+    pub fn flag<S: Into<String>>(mut self, flag: S) -> Self {
+        self.flags.push(flag.into());
+        self
+    }
+
+    /// Define the preprocessor macro `define`, either as `NAME` or as `NAME=VALUE`.
+    pub fn define<S: Into<String>>(mut self, define: S) -> Self {
+        self.defines.push(define.into());
+        self
+    }
+
+    /// Compile the sources into object files and bundle them into a static archive with the archiver
+    /// from the environment variable `AR` or `ar` instead of linking a shared object.
+    /// Use this for the `static-loading` feature.
+    ///
+    /// Default: `false`
+    pub fn static_archive(mut self, flag: bool) -> Self {
+        self.static_archive = flag;
+        self
+    }
+
+    fn command(&self) -> Command {
+        let mut command = Command::new(&self.compiler);
+        command.arg(format!("-O{}", self.opt_level));
+
+        for flag in &self.flags {
+            if !self.static_archive || !LINK_FLAGS.contains(&flag.as_str()) {
+                command.arg(flag);
+            }
+        }
+
+        command.args(self.defines.iter().map(|define| format!("-D{}", define)));
+        command
+    }
+}
+
+/// Run `command` and turn a failure into a [`CompileError`] that contains its output.
+fn run(mut command: Command, output: &Path) -> Result<(), CompileError> {
+    let result = command.output().map_err(|err| CompileError::Launch {
+        program: command.get_program().to_string_lossy().into_owned(),
+        source: err,
+    })?;
+
+    if !result.status.success() {
+        return Err(CompileError::Failed {
+            output: output.to_path_buf(),
+            stderr: String::from_utf8_lossy(&result.stderr).into_owned(),
+        });
+    }
+
+    Ok(())
+}
+
+/// Compile the C files `sources` that were created by [`CGenerator::generate`]
+/// into `output`.
+///
+/// Returns an error that contains the output of the compiler if compilation fails.
+pub fn compile<P: AsRef<Path>, S: AsRef<Path>>(
+    output: P,
+    sources: &[S],
+    options: &CompileOptions,
+) -> Result<(), CompileError> {
+    let output = output.as_ref();
+
+    if !options.static_archive {
+        let mut command = options.command();
+        command.arg("-o").arg(output).args(sources.iter().map(AsRef::as_ref));
+        return run(command, output);
+    }
+
+    let mut objects = Vec::with_capacity(sources.len());
+
+    for (i, source) in sources.iter().enumerate() {
+        let object = PathBuf::from(format!("{}.{}.o", output.display(), i));
+        let mut command = options.command();
+        command.arg("-c").arg("-o").arg(&object).arg(source.as_ref());
+        run(command, &object)?;
+        objects.push(object);
+    }
+
+    /* ar appends to existing archives */
+    let _ = std::fs::remove_file(output);
+
+    let mut command = Command::new(&options.archiver);
+    command.arg("rcs").arg(output).args(&objects);
+    let result = run(command, output);
+
+    for object in objects {
+        let _ = std::fs::remove_file(object);
+    }
+
+    result
+}
+
+/// Generate the code for `grammar` with `generator` next to `output` and compile it into `output`.
+/// The C files are named like `output` with the extension `.c`.
+///
+/// Returns the path of the compiled file.
+pub fn compile_grammar<P: AsRef<Path>>(
+    output: P,
+    grammar: &ContextFreeGrammar,
+    generator: CGenerator,
+    options: &CompileOptions,
+) -> Result<PathBuf, CompileError> {
+    let output = output.as_ref();
+    let sources = generator.generate(output.with_extension("c"), grammar);
+    compile(output, &sources, options)?;
+    Ok(output.to_path_buf())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_output() {
+        type MutateFunc = unsafe extern "C" fn(*mut usize, usize, usize) -> usize;
+        type SerializeFunc = unsafe extern "C" fn(*const usize, usize, *mut u8, usize) -> usize;
+        type UnparseFunc = unsafe extern "C" fn(*mut usize, usize, *const u8, usize) -> usize;
+
+        let cfg =
+            ContextFreeGrammar::builder().peacock_grammar("test-data/grammars/regex.json").unwrap().build().unwrap();
+        let dir = std::env::temp_dir();
+        let mut outputs = Vec::new();
+
+        for files in [1, 4] {
+            let c_file = dir.join(format!("peacock-split{}-{}.c", files, std::process::id()));
+            let so_file = dir.join(format!("peacock-split{}-{}.so", files, std::process::id()));
+            let sources = CGenerator::new().emit_statistics(true).split_output(files).generate(&c_file, &cfg);
+            assert_eq!(sources.len(), files);
+
+            compile(&so_file, &sources, &CompileOptions::new().flags(["-O0", "-fPIC", "-shared"])).unwrap();
+
+            let lib = unsafe { libloading::Library::new(&so_file) }.unwrap();
+            let mutate = unsafe { lib.get::<MutateFunc>(b"mutate_sequence") }.unwrap();
+            let serialize = unsafe { lib.get::<SerializeFunc>(b"serialize_sequence") }.unwrap();
+            let unparse = unsafe { lib.get::<UnparseFunc>(b"unparse_sequence") }.unwrap();
+            let mut sequence = vec![0usize; 4096];
+            let mut out = vec![0u8; 1 << 20];
+            let mut generated = Vec::new();
+
+            for _ in 0..100 {
+                let len = unsafe { mutate(sequence.as_mut_ptr(), 0, sequence.len()) };
+                let size = unsafe { serialize(sequence.as_ptr(), len, out.as_mut_ptr(), out.len()) };
+                let unparsed = unsafe { unparse(sequence.as_mut_ptr(), sequence.len(), out.as_ptr(), size) };
+                assert_eq!(unparsed, len);
+                generated.push(out[..size].to_vec());
+            }
+
+            outputs.push(generated);
+        }
+
+        assert_eq!(outputs[0], outputs[1]);
+    }
+
+    #[test]
+    fn test_strict_warnings() {
+        let dir = std::env::temp_dir();
+        let grammars = [
+            ("test-data/grammars/regex.json", true),
+            ("test-data/grammars/unparse.json", true),
+            ("test-data/grammars/exclusion.json", true),
+            ("test-data/grammars/epsilon_rules.json", false),
+            ("test-data/grammars/unused_rules.json", false),
+            ("test-data/grammars/dynamic.json", true),
+        ];
+        let options = CompileOptions::new().flags(["-Wall", "-Wextra", "-Werror", "-O2", "-fPIC", "-shared"]);
+
+        for (i, (grammar, optimize)) in grammars.into_iter().enumerate() {
+            let cfg =
+                ContextFreeGrammar::builder().peacock_grammar(grammar).unwrap().optimize(optimize).build().unwrap();
+
+            for (stats, thorough, files, fast, decay) in [
+                (false, false, 1, false, 1.0),
+                (true, true, 1, false, 0.5),
+                (true, false, 3, false, 0.5),
+                (false, false, 1, true, 1.0),
+            ] {
+                let name = format!("peacock-strict{}-{}-{}-{}-{}", i, thorough, files, fast, std::process::id());
+                let sources = CGenerator::new()
+                    .emit_statistics(stats)
+                    .thorough_unparsing(thorough)
+                    .split_output(files)
+                    .unsafe_fast_paths(fast)
+                    .recursion_decay(decay)
+                    .generate(dir.join(format!("{}.c", name)), &cfg);
+                compile(dir.join(format!("{}.so", name)), &sources, &options).unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn test_compile_errors() {
+        let dir = std::env::temp_dir();
+        let cfg =
+            ContextFreeGrammar::builder().peacock_grammar("test-data/grammars/regex.json").unwrap().build().unwrap();
+        let so_file = dir.join(format!("peacock-compile-errors-{}.so", std::process::id()));
+
+        let options = CompileOptions::new().compiler("/does/not/exist/cc");
+        let err = compile_grammar(&so_file, &cfg, CGenerator::new(), &options).unwrap_err();
+        assert!(matches!(&err, CompileError::Launch { program, .. } if program == "/does/not/exist/cc"));
+
+        let options = CompileOptions::new().define("PEACOCK_TEST=1").flag("-DPEACOCK_TEST=2").flag("-Werror");
+        let err = compile_grammar(&so_file, &cfg, CGenerator::new(), &options).unwrap_err();
+        assert!(
+            matches!(&err, CompileError::Failed { output, stderr } if output == &so_file && stderr.contains("PEACOCK_TEST"))
+        );
+        assert!(err.to_string().contains("PEACOCK_TEST"));
+    }
+
+    #[test]
+    fn test_static_archive() {
+        let dir = std::env::temp_dir();
+        let cfg =
+            ContextFreeGrammar::builder().peacock_grammar("test-data/grammars/regex.json").unwrap().build().unwrap();
+        let archive = dir.join(format!("peacock-static-{}.a", std::process::id()));
+        let options = CompileOptions::new().opt_level("0").static_archive(true);
+
+        compile_grammar(&archive, &cfg, CGenerator::new().split_output(2), &options).unwrap();
+        assert!(std::fs::read(&archive).unwrap().starts_with(b"!<arch>"));
+        assert!(!Path::new(&format!("{}.0.o", archive.display())).exists());
+    }
+}
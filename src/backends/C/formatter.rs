@@ -3,9 +3,12 @@ use std::io::{
     Write,
 };
 
+/// Writes indented lines of C code. The first error that occurs while writing is reported by
+/// [`finish()`](CFormatter::finish), all writes after it are dropped.
 pub struct CFormatter<T: Write> {
     stream: BufWriter<T>,
     indentation: usize,
+    error: Option<std::io::Error>,
 }
 
 impl<T> CFormatter<T>
@@ -16,6 +19,7 @@ where
         Self {
             stream: BufWriter::new(writer),
             indentation: 0,
+            error: None,
         }
     }
 
@@ -30,12 +34,25 @@ where
     }
 
     pub fn write<S: AsRef<str>>(&mut self, line: S) {
-        writeln!(&mut self.stream, "{:width$}{}", "", line.as_ref(), width = self.indentation)
-            .expect("Could not write to outfile");
+        if self.error.is_none() {
+            let result = writeln!(&mut self.stream, "{:width$}{}", "", line.as_ref(), width = self.indentation);
+            self.error = result.err();
+        }
     }
 
     pub fn blankline(&mut self) {
-        writeln!(&mut self.stream).expect("Could not write to outfile");
+        if self.error.is_none() {
+            self.error = writeln!(&mut self.stream).err();
+        }
+    }
+
+    /// Flush everything that was written and return the first error that occurred.
+    pub fn finish(mut self) -> std::io::Result<()> {
+        if let Some(error) = self.error.take() {
+            return Err(error);
+        }
+
+        self.stream.flush()
     }
 }
 
@@ -54,5 +71,15 @@ mod tests {
         fmt.blankline();
         fmt.unindent();
         fmt.write("}");
+        fmt.finish().unwrap();
+    }
+
+    #[test]
+    fn test_write_error() {
+        let mut buf = [0u8; 8];
+        let mut fmt = CFormatter::new(&mut buf[..]);
+        fmt.write("int main (void) {");
+        fmt.write("}");
+        assert_eq!(fmt.finish().unwrap_err().kind(), std::io::ErrorKind::WriteZero);
     }
 }
@@ -1,48 +1,113 @@
+use petgraph::{
+    algo::{
+        tarjan_scc,
+        toposort,
+    },
+    graphmap::DiGraphMap,
+};
 use std::collections::HashMap;
 
-use crate::grammar::{
-    ContextFreeGrammar,
-    Symbol,
+use crate::{
+    error::GrammarError,
+    grammar::{
+        BindingOp,
+        Builtin,
+        ContextFreeGrammar,
+        Symbol,
+        DYNAMIC_NONTERMINAL,
+    },
 };
 
+/// The number of bytes of the longest constant expansion. Longer ones would bloat the terminal table,
+/// and grammars that double their output with every level could not even be expanded.
+const MAX_CONSTANT_EXPANSION: usize = 4096;
+
+/// A terminal of a [`LowLevelGrammar`].
 #[derive(Copy, Clone, Debug)]
 pub struct LLTerminal(usize);
 
 impl LLTerminal {
+    /// The index of the terminal in [`LowLevelGrammar::terminals()`]
     pub fn id(&self) -> usize {
         self.0
     }
 }
 
+/// A non-terminal of a [`LowLevelGrammar`].
 #[derive(Copy, Clone, Debug)]
 pub struct LLNonTerminal(usize);
 
 impl LLNonTerminal {
+    /// The index of the non-terminal in [`LowLevelGrammar::nonterminals()`]
     pub fn id(&self) -> usize {
         self.0
     }
 }
 
+/// A symbol in a rule of a [`LowLevelGrammar`].
 #[derive(Clone, Debug)]
+#[non_exhaustive]
 pub enum LLSymbol {
+    /// A terminal
     Terminal(LLTerminal),
+    /// A non-terminal
     NonTerminal(LLNonTerminal),
+    /// `count` times `byte`, which is not stored in the terminal table
+    Repeat {
+        /// The repeated byte
+        byte: u8,
+        /// How often it gets repeated
+        count: usize,
+    },
+    /// A binding marker of a context-sensitive grammar
+    Binding {
+        /// What the marker does
+        op: BindingOp,
+        /// The index of the binding name in [`LowLevelGrammar::bindings()`]
+        slot: usize,
+    },
 }
 
+/// The representation of a grammar that the backends work with.
+/// Terminals and non-terminals are numbered and rules are referenced by their index in the rules of their non-terminal.
 pub struct LowLevelGrammar {
     rules: HashMap<usize, Vec<Vec<LLSymbol>>>,
     terminals: Vec<String>,
     nonterminals: Vec<String>,
+    bindings: Vec<String>,
     entrypoint: LLNonTerminal,
+    dynamic: Option<LLNonTerminal>,
+    builtins: HashMap<usize, Builtin>,
 }
 
 impl LowLevelGrammar {
+    /// Number the symbols of `grammar` and merge consecutive terminals.
+    ///
+    /// # Panics
+    /// If `grammar` is not complete, see [`try_from_high_level_grammar()`](LowLevelGrammar::try_from_high_level_grammar).
+    /// This cannot happen for grammars that come out of a [`GrammarBuilder`](crate::grammar::GrammarBuilder).
     pub fn from_high_level_grammar(grammar: &ContextFreeGrammar) -> Self {
+        match Self::try_from_high_level_grammar(grammar) {
+            Ok(grammar) => grammar,
+            Err(err) => panic!("Invalid grammar: {}", err),
+        }
+    }
+
+    /// Like [`from_high_level_grammar()`](LowLevelGrammar::from_high_level_grammar) but returns an error
+    /// if `grammar` has no rules, its entrypoint has no rules or it references a non-terminal without rules.
+    pub fn try_from_high_level_grammar(grammar: &ContextFreeGrammar) -> Result<Self, GrammarError> {
+        if grammar.rules().is_empty() {
+            return Err(GrammarError::EmptyGrammar);
+        }
+
         let mut rules = HashMap::new();
         let mut nonterm_map = HashMap::new();
         let mut nonterminals = Vec::new();
         let mut term_map = HashMap::new();
         let mut terminals = Vec::new();
+        let mut bindings = Vec::new();
+        let mut dynamic = None;
+        let mut builtins = HashMap::new();
 
         for rule in grammar.rules() {
             let lhs_id = *nonterm_map.entry(rule.lhs().id()).or_insert_with(|| {
@@ -51,16 +116,83 @@ impl LowLevelGrammar {
                 ret
             });
             let mut ll_symbols = Vec::new();
+            let mut i = 0;
+
+            while i < rule.rhs().len() {
+                match &rule.rhs()[i] {
+                    Symbol::Terminal(term) if term.is_dynamic() => {
+                        /* The placeholder becomes a reference to a non-terminal that the backends treat specially */
+                        let id = *nonterm_map.entry(DYNAMIC_NONTERMINAL).or_insert_with(|| {
+                            let ret = nonterminals.len();
+                            nonterminals.push(DYNAMIC_NONTERMINAL.to_string());
+                            ret
+                        });
+                        dynamic = Some(LLNonTerminal(id));
+                        ll_symbols.push(LLSymbol::NonTerminal(LLNonTerminal(id)));
+                    },
+                    Symbol::Terminal(term) if term.builtin().is_some() => {
+                        /* Builtins also become non-terminals whose step holds the chosen value */
+                        let builtin = *term.builtin().unwrap();
+                        let id = match builtins.iter().find(|(_, known)| **known == builtin) {
+                            Some((id, _)) => *id,
+                            None => {
+                                nonterminals.push(builtin.to_string());
+                                builtins.insert(nonterminals.len() - 1, builtin);
+                                nonterminals.len() - 1
+                            },
+                        };
+                        ll_symbols.push(LLSymbol::NonTerminal(LLNonTerminal(id)));
+                    },
+                    Symbol::Terminal(term) if term.run().is_some() => {
+                        let (byte, count) = term.run().unwrap();
+                        ll_symbols.push(LLSymbol::Repeat {
+                            byte,
+                            count,
+                        });
+                    },
+                    Symbol::Terminal(term) if term.binding().is_some() => {
+                        let (op, name) = term.binding().unwrap();
+                        let slot = match bindings.iter().position(|binding| binding == name) {
+                            Some(slot) => slot,
+                            None => {
+                                bindings.push(name.to_string());
+                                bindings.len() - 1
+                            },
+                        };
+                        ll_symbols.push(LLSymbol::Binding {
+                            op,
+                            slot,
+                        });
+                    },
+                    Symbol::Terminal(_) => {
+                        /* Consecutive terminals get merged into a single one */
+                        let mut content = String::new();
+
+                        while let Some(Symbol::Terminal(term)) = rule.rhs().get(i) {
+                            if term.is_dynamic()
+                                || term.run().is_some()
+                                || term.binding().is_some()
+                                || term.builtin().is_some()
+                            {
+                                break;
+                            }
+
+                            content.push_str(&term.expanded());
+                            i += 1;
+                        }
+
+                        /* Empty terminals don't produce any output */
+                        if content.is_empty() {
+                            continue;
+                        }
 
-            for symbol in rule.rhs() {
-                match symbol {
-                    Symbol::Terminal(term) => {
-                        let id = *term_map.entry(term.content()).or_insert_with(|| {
+                        let id = *term_map.entry(content).or_insert_with_key(|content| {
                             let ret = terminals.len();
-                            terminals.push(term.content().to_string());
+                            terminals.push(content.clone());
                             ret
                         });
                         ll_symbols.push(LLSymbol::Terminal(LLTerminal(id)));
+                        continue;
                     },
                     Symbol::NonTerminal(nonterm) => {
                         let id = *nonterm_map.entry(nonterm.id()).or_insert_with(|| {
@@ -71,39 +203,649 @@ impl LowLevelGrammar {
                         ll_symbols.push(LLSymbol::NonTerminal(LLNonTerminal(id)));
                     },
                 }
+
+                i += 1;
             }
 
             rules.entry(lhs_id).or_insert_with(Vec::new).push(ll_symbols);
         }
 
-        Self {
+        /* The dynamic non-terminal has one rule that stands for all of its runtime terminals */
+        if let Some(dynamic) = dynamic {
+            rules.insert(dynamic.id(), vec![Vec::new()]);
+        }
+
+        /* Same for the builtins, whose values are chosen at runtime */
+        for id in builtins.keys() {
+            rules.insert(*id, vec![Vec::new()]);
+        }
+
+        let Some(entrypoint) = nonterm_map.get(grammar.entrypoint().id()).filter(|id| rules.contains_key(*id)) else {
+            return Err(GrammarError::MissingEntrypoint(grammar.entrypoint().id().to_string()));
+        };
+
+        /* The backends expect at least one rule for every non-terminal */
+        if let Some(undefined) = (0..nonterminals.len()).find(|id| !rules.contains_key(id)) {
+            return Err(GrammarError::MissingNonTerminal(nonterminals[undefined].clone()));
+        }
+
+        Ok(Self {
             rules,
             terminals,
             nonterminals,
-            entrypoint: LLNonTerminal(*nonterm_map.get(grammar.entrypoint().id()).unwrap()),
-        }
+            bindings,
+            entrypoint: LLNonTerminal(*entrypoint),
+            dynamic,
+            builtins,
+        })
+    }
+
+    /// The non-terminal that derives the terminals that were discovered during fuzzing,
+    /// if the grammar uses it. Its rule is empty, the backends must pick a terminal for it at runtime.
+    pub fn dynamic(&self) -> Option<&LLNonTerminal> {
+        self.dynamic.as_ref()
     }
 
+    /// The builtin that the non-terminal with the id `nonterm` stands for, if any.
+    /// Like the dynamic non-terminal, it has a single empty rule and its step in an automaton walk
+    /// holds the chosen value instead of a rule index.
+    pub fn builtin(&self, nonterm: usize) -> Option<&Builtin> {
+        self.builtins.get(&nonterm)
+    }
+
+    /// Whether the grammar contains a builtin like `@int(0, 1000)`.
+    pub(crate) fn has_builtins(&self) -> bool {
+        !self.builtins.is_empty()
+    }
+
+    /// The rules of every non-terminal by its id. The dynamic non-terminal and the builtins have a single empty rule.
     pub fn rules(&self) -> &HashMap<usize, Vec<Vec<LLSymbol>>> {
         &self.rules
     }
 
+    /// The rules of the non-terminal with the id `nonterm`, in the order that the rule indices refer to.
+    pub fn rules_of(&self, nonterm: usize) -> &[Vec<LLSymbol>] {
+        self.rules.get(&nonterm).map_or(&[], Vec::as_slice)
+    }
+
+    /// Whether a rule contains a run of a repeated byte.
+    pub(crate) fn has_repeats(&self) -> bool {
+        self.rules.values().flatten().flatten().any(|symbol| matches!(symbol, LLSymbol::Repeat { .. }))
+    }
+
+    /// Whether a rule contains a binding marker, which makes the grammar context-sensitive.
+    pub(crate) fn has_bindings(&self) -> bool {
+        !self.bindings.is_empty()
+    }
+
+    /// The name of every binding by its slot, see [`LLSymbol::Binding`].
+    pub fn bindings(&self) -> &[String] {
+        &self.bindings
+    }
+
+    /// The content of every terminal by its id.
     pub fn terminals(&self) -> &[String] {
         &self.terminals
     }
 
+    /// The name of every non-terminal by its id.
     pub fn nonterminals(&self) -> &[String] {
         &self.nonterminals
     }
 
+    /// The non-terminal that every derivation starts with.
     pub fn entrypoint(&self) -> &LLNonTerminal {
         &self.entrypoint
     }
+
+    /// For every non-terminal: the length of its shortest expansion and the rule that leads to it.
+    pub fn minimal_expansions(&self) -> Vec<(usize, usize)> {
+        let mut minimal = vec![(usize::MAX, 0); self.nonterminals.len()];
+        let mut changed = true;
+
+        while changed {
+            changed = false;
+
+            for (nonterm, rules) in &self.rules {
+                for (i, rule) in rules.iter().enumerate() {
+                    let mut len = self.builtins.get(nonterm).map_or(0, Builtin::min_len);
+
+                    for symbol in rule {
+                        len = len.saturating_add(match symbol {
+                            LLSymbol::Terminal(term) => self.terminals[term.id()].len(),
+                            LLSymbol::NonTerminal(nonterm) => minimal[nonterm.id()].0,
+                            LLSymbol::Repeat {
+                                count,
+                                ..
+                            } => *count,
+                            /* The bytes of a binding are counted where it is defined */
+                            LLSymbol::Binding {
+                                ..
+                            } => 0,
+                        });
+                    }
+
+                    if len < minimal[*nonterm].0 {
+                        minimal[*nonterm] = (len, i);
+                        changed = true;
+                    }
+                }
+            }
+        }
+
+        minimal
+    }
+
+    /// The number of bytes of the shortest input that the grammar can produce.
+    pub fn min_output_len(&self) -> usize {
+        self.minimal_expansions()[self.entrypoint.id()].0
+    }
+
+    /// For every non-terminal: whether it can appear in a derivation of the entrypoint.
+    pub(crate) fn reachable_nonterminals(&self) -> Vec<bool> {
+        let mut reachable = vec![false; self.nonterminals.len()];
+        let mut stack = vec![self.entrypoint.id()];
+        reachable[self.entrypoint.id()] = true;
+
+        while let Some(nonterm) = stack.pop() {
+            for symbol in self.rules[&nonterm].iter().flatten() {
+                if let LLSymbol::NonTerminal(child) = symbol {
+                    if !reachable[child.id()] {
+                        reachable[child.id()] = true;
+                        stack.push(child.id());
+                    }
+                }
+            }
+        }
+
+        reachable
+    }
+
+    /// For every rule of every non-terminal: whether the rule can derive its own non-terminal again,
+    /// i.e. whether one of its children lies in the same strongly connected component.
+    pub(crate) fn recursive_rules(&self) -> HashMap<usize, Vec<bool>> {
+        let mut graph = DiGraphMap::<usize, ()>::new();
+
+        for (nonterm, rules) in &self.rules {
+            graph.add_node(*nonterm);
+
+            for symbol in rules.iter().flatten() {
+                if let LLSymbol::NonTerminal(child) = symbol {
+                    graph.add_edge(*nonterm, child.id(), ());
+                }
+            }
+        }
+
+        let mut component = vec![usize::MAX; self.nonterminals.len()];
+
+        for (i, members) in tarjan_scc(&graph).into_iter().enumerate() {
+            for nonterm in members {
+                component[nonterm] = i;
+            }
+        }
+
+        self.rules
+            .iter()
+            .map(|(nonterm, rules)| {
+                let recursive = rules
+                    .iter()
+                    .map(|rule| {
+                        rule.iter().any(|symbol| match symbol {
+                            LLSymbol::NonTerminal(child) => component[child.id()] == component[*nonterm],
+                            LLSymbol::Terminal(_)
+                            | LLSymbol::Repeat {
+                                ..
+                            }
+                            | LLSymbol::Binding {
+                                ..
+                            } => false,
+                        })
+                    })
+                    .collect();
+                (*nonterm, recursive)
+            })
+            .collect()
+    }
+
+    /// For every non-terminal that has a single rule and whose children also have a single rule, recursively,
+    /// and that therefore always derives the same string: that string and the non-terminals of the derivation
+    /// in the order in which they occupy the steps of an automaton walk, starting with the non-terminal itself.
+    /// Expansions of more than [`MAX_CONSTANT_EXPANSION`] bytes are left out and serialized normally.
+    pub(crate) fn constant_expansions(&self) -> HashMap<usize, (String, Vec<usize>)> {
+        fn expand(
+            grammar: &LowLevelGrammar,
+            nonterm: usize,
+            memo: &mut HashMap<usize, Option<(String, Vec<usize>)>>,
+        ) -> Option<(String, Vec<usize>)> {
+            if let Some(result) = memo.get(&nonterm) {
+                return result.clone();
+            }
+
+            /* Guards against cycles, which cannot finish their derivation anyway */
+            memo.insert(nonterm, None);
+
+            let result = match grammar.rules.get(&nonterm).map(Vec::as_slice) {
+                Some([rule])
+                    if grammar.dynamic.is_none_or(|dynamic| dynamic.id() != nonterm)
+                        && !grammar.builtins.contains_key(&nonterm) =>
+                {
+                    let mut content = String::new();
+                    let mut nonterminals = vec![nonterm];
+
+                    for symbol in rule {
+                        match symbol {
+                            LLSymbol::Terminal(term) => content.push_str(&grammar.terminals[term.id()]),
+                            LLSymbol::NonTerminal(child) => {
+                                let (child_content, child_nonterminals) = expand(grammar, child.id(), memo)?;
+                                content.push_str(&child_content);
+                                nonterminals.extend(child_nonterminals);
+                            },
+                            /* Runs are written with memset, copying them from the terminal table would bloat it */
+                            LLSymbol::Repeat {
+                                ..
+                            } => return None,
+                            /* Uses produce different bytes every time and bindings must see the output */
+                            LLSymbol::Binding {
+                                ..
+                            } => return None,
+                        }
+
+                        if content.len() > MAX_CONSTANT_EXPANSION {
+                            return None;
+                        }
+                    }
+
+                    Some((content, nonterminals))
+                },
+                _ => None,
+            };
+
+            memo.insert(nonterm, result.clone());
+            result
+        }
+
+        let mut memo = HashMap::new();
+
+        self.rules.keys().filter_map(|nonterm| Some((*nonterm, expand(self, *nonterm, &mut memo)?))).collect()
+    }
+
+    /// The number of bytes of the longest input that the grammar can produce.
+    /// Returns `None` if a cycle is reachable from the entrypoint, the length does not fit into a `usize`
+    /// or the grammar repeats the bytes of bindings.
+    pub fn max_output_len(&self) -> Option<usize> {
+        let mut graph = DiGraphMap::<usize, ()>::new();
+        let mut stack = vec![self.entrypoint.id()];
+        graph.add_node(self.entrypoint.id());
+
+        while let Some(nonterm) = stack.pop() {
+            for symbol in self.rules.get(&nonterm).into_iter().flatten().flatten() {
+                if let LLSymbol::NonTerminal(child) = symbol {
+                    if !graph.contains_node(child.id()) {
+                        stack.push(child.id());
+                    }
+
+                    graph.add_edge(nonterm, child.id(), ());
+                }
+            }
+        }
+
+        /* Terminals that are discovered at runtime can have any length */
+        if self.dynamic.is_some_and(|dynamic| graph.contains_node(dynamic.id())) {
+            return None;
+        }
+
+        let repeats_bindings = self
+            .rules
+            .values()
+            .flatten()
+            .flatten()
+            .any(|symbol| matches!(symbol, LLSymbol::Binding { op, .. } if op.is_reference()));
+
+        if repeats_bindings {
+            return None;
+        }
+
+        let order = toposort(&graph, None).ok()?;
+        let mut maximal = vec![0usize; self.nonterminals.len()];
+
+        for nonterm in order.into_iter().rev() {
+            let mut max = 0;
+
+            for rule in self.rules.get(&nonterm).into_iter().flatten() {
+                let mut len = self.builtins.get(&nonterm).map_or(0, Builtin::max_len);
+
+                for symbol in rule {
+                    len = len.checked_add(match symbol {
+                        LLSymbol::Terminal(term) => self.terminals[term.id()].len(),
+                        LLSymbol::NonTerminal(nonterm) => maximal[nonterm.id()],
+                        LLSymbol::Repeat {
+                            count,
+                            ..
+                        } => *count,
+                        LLSymbol::Binding {
+                            ..
+                        } => 0,
+                    })?;
+                }
+
+                max = max.max(len);
+            }
+
+            maximal[nonterm] = max;
+        }
+
+        Some(maximal[self.entrypoint.id()])
+    }
+
+    /// Walk the automaton walk `sequence` like the serializer does and describe the rule that was
+    /// taken at every step, e.g. `<EXPR> -> <TERM> '+' <EXPR>`.
+    /// Every entry is indented by two spaces per level of the derivation tree.
+    ///
+    /// The trace ends early at the first entry that is not a valid rule index.
+    pub fn describe_sequence(&self, sequence: &[usize]) -> Vec<String> {
+        let mut trace = Vec::with_capacity(sequence.len());
+        let mut stack = vec![(self.entrypoint.id(), 0)];
+        let mut steps = sequence.iter();
+
+        while let Some((nonterm, depth)) = stack.pop() {
+            let Some(&idx) = steps.next() else {
+                break;
+            };
+            let indent = "  ".repeat(depth);
+            let name = &self.nonterminals[nonterm];
+
+            /* Rule i > 0 of the dynamic non-terminal stands for the dynamic terminal i - 1 */
+            if self.dynamic.is_some_and(|dynamic| dynamic.id() == nonterm) {
+                if idx == 0 {
+                    trace.push(format!("{}<{}> -> ''", indent, name));
+                } else {
+                    trace.push(format!("{}<{}> -> dynamic terminal #{}", indent, name, idx - 1));
+                }
+
+                continue;
+            }
+
+            if let Some(builtin) = self.builtins.get(&nonterm) {
+                trace.push(format!("{}<{}> -> '{}'", indent, name, builtin.render(idx as u64).escape_ascii()));
+                continue;
+            }
+
+            let Some(rule) = self.rules.get(&nonterm).and_then(|rules| rules.get(idx)) else {
+                trace.push(format!("{}<{}> -> invalid rule #{}", indent, name, idx));
+                break;
+            };
+
+            let rhs: Vec<String> = rule
+                .iter()
+                .map(|symbol| match symbol {
+                    LLSymbol::Terminal(term) => format!("'{}'", self.terminals[term.id()].escape_debug()),
+                    LLSymbol::NonTerminal(nonterm) => format!("<{}>", self.nonterminals[nonterm.id()]),
+                    LLSymbol::Repeat {
+                        byte,
+                        count,
+                    } => format!("@repeat('{}', {})", (*byte as char).escape_debug(), count),
+                    LLSymbol::Binding {
+                        op,
+                        slot,
+                    } => format!("@{}({})", op.keyword(), self.bindings[*slot]),
+                })
+                .collect();
+
+            if rhs.is_empty() {
+                trace.push(format!("{}<{}> -> ''", indent, name));
+            } else {
+                trace.push(format!("{}<{}> -> {}", indent, name, rhs.join(" ")));
+            }
+
+            for symbol in rule.iter().rev() {
+                if let LLSymbol::NonTerminal(child) = symbol {
+                    stack.push((child.id(), depth + 1));
+                }
+            }
+        }
+
+        trace
+    }
+}
+
+/// A suffix automaton of a growing string. It finds the first occurrence of a pattern
+/// in time linear in the length of the pattern and grows in amortized constant time per byte.
+struct SubstringIndex {
+    states: Vec<SubstringState>,
+    last: usize,
+}
+
+struct SubstringState {
+    len: usize,
+    link: Option<usize>,
+    /// The end of the first occurrence of the substrings of this state
+    first_end: usize,
+    next: HashMap<u8, usize>,
+}
+
+impl SubstringIndex {
+    fn new() -> Self {
+        Self {
+            states: vec![SubstringState {
+                len: 0,
+                link: None,
+                first_end: 0,
+                next: HashMap::new(),
+            }],
+            last: 0,
+        }
+    }
+
+    fn push(&mut self, byte: u8) {
+        let cur = self.states.len();
+        let len = self.states[self.last].len + 1;
+        self.states.push(SubstringState {
+            len,
+            link: None,
+            first_end: len,
+            next: HashMap::new(),
+        });
+
+        let mut p = Some(self.last);
+
+        while let Some(state) = p {
+            if self.states[state].next.contains_key(&byte) {
+                break;
+            }
+
+            self.states[state].next.insert(byte, cur);
+            p = self.states[state].link;
+        }
+
+        self.states[cur].link = match p {
+            None => Some(0),
+            Some(p) => {
+                let q = self.states[p].next[&byte];
+
+                if self.states[p].len + 1 == self.states[q].len {
+                    Some(q)
+                } else {
+                    let clone = self.states.len();
+                    self.states.push(SubstringState {
+                        len: self.states[p].len + 1,
+                        link: self.states[q].link,
+                        first_end: self.states[q].first_end,
+                        next: self.states[q].next.clone(),
+                    });
+
+                    let mut p = Some(p);
+
+                    while let Some(state) = p {
+                        if self.states[state].next.get(&byte) != Some(&q) {
+                            break;
+                        }
+
+                        self.states[state].next.insert(byte, clone);
+                        p = self.states[state].link;
+                    }
+
+                    self.states[q].link = Some(clone);
+                    Some(clone)
+                }
+            },
+        };
+
+        self.last = cur;
+    }
+
+    /// The offset of the first occurrence of `pattern`
+    fn find(&self, pattern: &[u8]) -> Option<usize> {
+        let mut state = 0;
+
+        for byte in pattern {
+            state = *self.states[state].next.get(byte)?;
+        }
+
+        Some(self.states[state].first_end - pattern.len())
+    }
+}
+
+/// The length of the longest prefix of `term` that `data` ends with.
+/// `term` must not be contained in `data`.
+fn overlap(data: &[u8], term: &[u8]) -> usize {
+    let tail = &data[data.len() - term.len().min(data.len())..];
+
+    /* Knuth-Morris-Pratt: failure[i] is the longest proper border of term[..=i] */
+    let mut failure = vec![0; term.len()];
+    let mut k = 0;
+
+    for i in 1..term.len() {
+        while k > 0 && term[i] != term[k] {
+            k = failure[k - 1];
+        }
+
+        if term[i] == term[k] {
+            k += 1;
+        }
+
+        failure[i] = k;
+    }
+
+    let mut matched = 0;
+
+    for byte in tail {
+        while matched > 0 && *byte != term[matched] {
+            matched = failure[matched - 1];
+        }
+
+        if *byte == term[matched] {
+            matched += 1;
+        }
+    }
+
+    matched
+}
+
+/// How the C backend stores the terminals of a grammar.
+///
+/// By default, every terminal gets its own `TERMn` array. As a blob, all terminals are stored
+/// in the single string table `TERMS`. Terminals that are contained in other terminals or overlap
+/// with the end of the table don't occupy additional space.
+pub struct TerminalTable {
+    terminals: Vec<String>,
+    positions: HashMap<String, usize>,
+    blob: Option<Vec<u8>>,
+    entries: Vec<(usize, usize)>,
+}
+
+impl TerminalTable {
+    pub fn new(terminals: &[String], blob: bool) -> Self {
+        let mut positions = HashMap::with_capacity(terminals.len());
+
+        for (id, term) in terminals.iter().enumerate().rev() {
+            positions.insert(term.clone(), id);
+        }
+
+        if !blob {
+            return Self {
+                terminals: terminals.to_vec(),
+                positions,
+                blob: None,
+                entries: terminals.iter().enumerate().map(|(id, term)| (id, term.len())).collect(),
+            };
+        }
+
+        let mut data: Vec<u8> = Vec::new();
+        let mut index = SubstringIndex::new();
+        let mut entries = vec![(0, 0); terminals.len()];
+
+        /* Place long terminals first such that shorter ones can be found inside of them */
+        let mut order: Vec<usize> = (0..terminals.len()).collect();
+        order.sort_by(|a, b| terminals[*b].len().cmp(&terminals[*a].len()).then(a.cmp(b)));
+
+        for id in order {
+            let term = terminals[id].as_bytes();
+
+            if let Some(offset) = index.find(term) {
+                entries[id] = (offset, term.len());
+                continue;
+            }
+
+            let overlap = overlap(&data, term);
+            entries[id] = (data.len() - overlap, term.len());
+
+            for byte in &term[overlap..] {
+                index.push(*byte);
+            }
+
+            data.extend_from_slice(&term[overlap..]);
+        }
+
+        Self {
+            terminals: terminals.to_vec(),
+            positions,
+            blob: Some(data),
+            entries,
+        }
+    }
+
+    /// The contents of `TERMS` if the terminals are stored as a blob.
+    pub fn blob(&self) -> Option<&[u8]> {
+        self.blob.as_deref()
+    }
+
+    /// The contents of all terminals.
+    pub fn terminals(&self) -> &[String] {
+        &self.terminals
+    }
+
+    /// The index of a terminal for `TERMINAL_AT()` and its length.
+    /// In a blob, the index is the offset of the terminal in `TERMS`.
+    pub fn entry(&self, term: &LLTerminal) -> (usize, usize) {
+        self.entries[term.id()]
+    }
+
+    /// The indices and lengths of all terminals, see [`entry()`](TerminalTable::entry).
+    pub fn entries(&self) -> &[(usize, usize)] {
+        &self.entries
+    }
+
+    /// A C expression that points to the content of a terminal.
+    pub fn pointer(&self, term: &LLTerminal) -> String {
+        match &self.blob {
+            Some(_) => format!("&TERMS[{}]", self.entries[term.id()].0),
+            None => format!("TERM{}", term.id()),
+        }
+    }
+
+    /// The terminal with the given content, if the table contains it.
+    pub fn find(&self, content: &str) -> Option<LLTerminal> {
+        self.positions.get(content).copied().map(LLTerminal)
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::grammar::{
+        tests::rule,
+        NonTerminal,
+    };
 
     #[test]
     fn test_ll() {
@@ -117,4 +859,191 @@ mod tests {
         println!("terminals = {:?}", ll.terminals());
         println!("nonterminals = {:?}", ll.nonterminals());
     }
+
+    #[test]
+    fn test_terminal_table() {
+        let terminals: Vec<String> =
+            ["integer", "int", "ger", "eraser", "", "int"].iter().map(|x| x.to_string()).collect();
+        let table = TerminalTable::new(&terminals, true);
+        let blob = table.blob().unwrap();
+
+        for (i, term) in terminals.iter().enumerate() {
+            let (offset, len) = table.entry(&LLTerminal(i));
+            assert_eq!(&blob[offset..offset + len], term.as_bytes());
+        }
+
+        assert_eq!(blob, b"integeraser");
+        assert_eq!(table.pointer(&LLTerminal(2)), "&TERMS[4]");
+
+        let table = TerminalTable::new(&terminals, false);
+        assert!(table.blob().is_none());
+        assert_eq!(table.entry(&LLTerminal(2)), (2, 3));
+        assert_eq!(table.pointer(&LLTerminal(2)), "TERM2");
+    }
+
+    #[test]
+    fn test_terminal_table_layout() {
+        /* The layout of the quadratic search that the table used before */
+        fn reference(terminals: &[String]) -> (Vec<u8>, Vec<(usize, usize)>) {
+            let mut data: Vec<u8> = Vec::new();
+            let mut entries = vec![(0, 0); terminals.len()];
+            let mut order: Vec<usize> = (0..terminals.len()).collect();
+            order.sort_by(|a, b| terminals[*b].len().cmp(&terminals[*a].len()).then(a.cmp(b)));
+
+            for id in order {
+                let term = terminals[id].as_bytes();
+
+                if let Some(offset) = (0..=data.len().saturating_sub(term.len()))
+                    .find(|offset| data.len() >= term.len() && &data[*offset..*offset + term.len()] == term)
+                {
+                    entries[id] = (offset, term.len());
+                    continue;
+                }
+
+                let mut overlap = term.len().min(data.len());
+
+                while overlap > 0 && !data.ends_with(&term[..overlap]) {
+                    overlap -= 1;
+                }
+
+                entries[id] = (data.len() - overlap, term.len());
+                data.extend_from_slice(&term[overlap..]);
+            }
+
+            (data, entries)
+        }
+
+        for (path, entrypoint) in
+            [("test-data/static_loading/php.json", "PROGRAM"), ("test-data/grammars/gramatron.json", "ENTRYPOINT")]
+        {
+            let cfg =
+                ContextFreeGrammar::builder().gramatron_grammar(path).unwrap().entrypoint(entrypoint).build().unwrap();
+            let grammar = LowLevelGrammar::from_high_level_grammar(&cfg);
+            let table = TerminalTable::new(grammar.terminals(), true);
+            let (data, entries) = reference(grammar.terminals());
+            assert_eq!(table.blob().unwrap(), &data[..]);
+            assert_eq!(table.entries(), &entries[..]);
+        }
+
+        let terminals: Vec<String> =
+            ["abab", "babb", "aaa", "bbba", "ba", "abba", "b", "aab"].iter().map(|x| x.to_string()).collect();
+        let table = TerminalTable::new(&terminals, true);
+        let (data, entries) = reference(&terminals);
+        assert_eq!(table.blob().unwrap(), &data[..]);
+        assert_eq!(table.entries(), &entries[..]);
+    }
+
+    #[test]
+    fn test_output_len() {
+        let cfg = ContextFreeGrammar::new(
+            vec![
+                rule("S", &["ab", "<A>", "<A>"]),
+                rule("S", &["c"]),
+                rule("A", &["d"]),
+                rule("A", &["efg"]),
+                rule("A", &[""]),
+            ],
+            NonTerminal::new("S"),
+        );
+        let ll = LowLevelGrammar::from_high_level_grammar(&cfg);
+        assert_eq!(ll.min_output_len(), 1);
+        assert_eq!(ll.max_output_len(), Some(8));
+
+        /* Recursion that is not reachable from the entrypoint does not matter */
+        let cfg = ContextFreeGrammar::new(
+            vec![rule("S", &["<A>"]), rule("A", &["a"]), rule("B", &["b", "<B>"]), rule("B", &["b"])],
+            NonTerminal::new("S"),
+        );
+        let ll = LowLevelGrammar::from_high_level_grammar(&cfg);
+        assert_eq!(ll.min_output_len(), 1);
+        assert_eq!(ll.max_output_len(), Some(1));
+
+        let cfg =
+            ContextFreeGrammar::new(vec![rule("S", &["(", "<S>", ")"]), rule("S", &["x"])], NonTerminal::new("S"));
+        let ll = LowLevelGrammar::from_high_level_grammar(&cfg);
+        assert_eq!(ll.min_output_len(), 1);
+        assert_eq!(ll.max_output_len(), None);
+    }
+
+    #[test]
+    fn test_recursive_rules() {
+        let cfg = ContextFreeGrammar::new(
+            vec![
+                rule("S", &["(", "<E>", ")"]),
+                rule("S", &["x", "<A>"]),
+                rule("E", &["<S>", "+", "<A>"]),
+                rule("E", &["n"]),
+                rule("A", &["a"]),
+                rule("A", &["b"]),
+            ],
+            NonTerminal::new("S"),
+        );
+        let ll = LowLevelGrammar::from_high_level_grammar(&cfg);
+        let recursive = ll.recursive_rules();
+        let id = |name: &str| ll.nonterminals().iter().position(|x| x == name).unwrap();
+
+        assert_eq!(recursive[&id("S")], [true, false]);
+        assert_eq!(recursive[&id("E")], [true, false]);
+        assert_eq!(recursive[&id("A")], [false, false]);
+    }
+
+    #[test]
+    fn test_constant_expansions() {
+        /* Every level doubles the output of the previous one */
+        let mut rules = vec![rule("S", &["<A16>"]), rule("A0", &["ab"])];
+
+        for i in 1..=16 {
+            let child = format!("<A{}>", i - 1);
+            rules.push(rule(&format!("A{}", i), &[&child, &child]));
+        }
+
+        let cfg = ContextFreeGrammar::new(rules, NonTerminal::new("S"));
+        let ll = LowLevelGrammar::from_high_level_grammar(&cfg);
+        let expansions = ll.constant_expansions();
+        let id = |name: &str| ll.nonterminals().iter().position(|x| x == name).unwrap();
+
+        assert_eq!(expansions[&id("A0")].0, "ab");
+        assert_eq!(expansions[&id("A11")].0.len(), MAX_CONSTANT_EXPANSION);
+        assert_eq!(expansions[&id("A11")].1.len(), (1 << 12) - 1);
+        assert!(!expansions.contains_key(&id("A12")));
+        assert!(!expansions.contains_key(&id("S")));
+    }
+
+    #[test]
+    fn test_describe_sequence() {
+        let cfg = ContextFreeGrammar::new(
+            vec![rule("S", &["(", "<S>", "<A>", ")"]), rule("S", &["x"]), rule("A", &["\n"]), rule("A", &[""])],
+            NonTerminal::new("S"),
+        );
+        let ll = LowLevelGrammar::from_high_level_grammar(&cfg);
+
+        assert_eq!(
+            ll.describe_sequence(&[0, 0, 1, 1, 0]),
+            ["<S> -> '(' <S> <A> ')'", "  <S> -> '(' <S> <A> ')'", "    <S> -> 'x'", "    <A> -> ''", "  <A> -> '\\n'",]
+        );
+        assert_eq!(
+            ll.describe_sequence(&[0, 1, 5, 0]),
+            ["<S> -> '(' <S> <A> ')'", "  <S> -> 'x'", "  <A> -> invalid rule #5"]
+        );
+        assert_eq!(ll.describe_sequence(&[0]), ["<S> -> '(' <S> <A> ')'"]);
+    }
+
+    #[test]
+    fn test_incomplete_grammar() {
+        let cfg = ContextFreeGrammar::new(Vec::new(), NonTerminal::new("S"));
+        assert!(matches!(LowLevelGrammar::try_from_high_level_grammar(&cfg), Err(GrammarError::EmptyGrammar)));
+
+        let cfg = ContextFreeGrammar::new(vec![rule("A", &["a"])], NonTerminal::new("S"));
+        let err = LowLevelGrammar::try_from_high_level_grammar(&cfg).err().unwrap();
+        assert!(matches!(&err, GrammarError::MissingEntrypoint(nonterm) if nonterm == "S"));
+
+        let cfg = ContextFreeGrammar::new(vec![rule("S", &["<A>"])], NonTerminal::new("S"));
+        let err = LowLevelGrammar::try_from_high_level_grammar(&cfg).err().unwrap();
+        assert!(matches!(&err, GrammarError::MissingNonTerminal(nonterm) if nonterm == "A"));
+
+        let cfg = ContextFreeGrammar::new(vec![rule("S", &["x"])], NonTerminal::new("S"));
+        let ll = LowLevelGrammar::try_from_high_level_grammar(&cfg).unwrap();
+        assert_eq!(ll.terminals(), ["x"]);
+        assert_eq!(ll.rules()[&ll.entrypoint().id()].len(), 1);
+    }
 }
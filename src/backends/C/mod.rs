@@ -11,11 +11,34 @@
 //! CGenerator::new().generate("mutator.c", &grammar);
 //! ```
 //!
+//! Big grammars can be split into multiple files that are compiled together:
+//! ```no_run
+//! # use peacock_fuzz::{backends::C::{compile, CGenerator, CompileOptions}, grammar::ContextFreeGrammar};
+//! # let grammar = ContextFreeGrammar::builder().peacock_grammar("my-grammar.json").unwrap().build().unwrap();
+//! let sources = CGenerator::new().split_output(8).generate("mutator.c", &grammar);
+//! compile("mutator.so", &sources, &CompileOptions::new()).unwrap();
+//! ```
+//!
 //! The API is documented in the [README](https://github.com/z2-2z/peacock#c-api-documentation) of this project.
 
 mod codegen;
+mod compile;
 mod formatter;
 mod grammar;
+mod python;
 
-pub use codegen::CGenerator;
-pub(crate) use grammar::*;
+pub use codegen::{
+    CGenerator,
+    UnparseOrder,
+};
+pub use compile::{
+    compile,
+    compile_grammar,
+    CompileOptions,
+};
+pub use grammar::{
+    LLNonTerminal,
+    LLSymbol,
+    LLTerminal,
+    LowLevelGrammar,
+};
@@ -0,0 +1,424 @@
+use std::{
+    fs::File,
+    path::Path,
+};
+
+use crate::backends::C::formatter::CFormatter;
+
+/// The number of steps an automaton walk can have if the Python caller does not give a capacity
+const DEFAULT_CAPACITY: usize = 4096;
+
+/// The maximum size of a serialized input if the Python caller does not give one
+const DEFAULT_MAX_LEN: usize = 1 << 20;
+
+/// What the Python module has to wrap
+pub(crate) struct PythonModule<'a> {
+    pub(crate) name: &'a str,
+    pub(crate) prefix: &'a str,
+    pub(crate) mutations: bool,
+    pub(crate) serializations: bool,
+    pub(crate) unparsing: bool,
+}
+
+fn emit_prototypes(module: &PythonModule, fmt: &mut CFormatter<File>) {
+    let prefix = module.prefix;
+
+    fmt.write("/* The public API of the generator */");
+
+    if module.mutations {
+        fmt.write(format!("size_t {prefix}mutate_sequence (size_t* buf, size_t len, const size_t capacity);"));
+    }
+
+    if module.serializations {
+        fmt.write(format!(
+            "size_t {prefix}serialize_sequence (const size_t* seq, const size_t seq_len, unsigned char* out, const size_t out_len);"
+        ));
+    }
+
+    if module.unparsing {
+        fmt.write(format!("size_t {prefix}unparse_sequence (size_t* seq_buf, const size_t seq_capacity, const unsigned char* input, const size_t input_len);"));
+    }
+
+    fmt.write(format!("void {prefix}seed_generator (uint64_t new_seed);"));
+    fmt.blankline();
+}
+
+fn emit_conversions(fmt: &mut CFormatter<File>) {
+    fmt.write("/* Copy a Python sequence of rule ids into a new buffer that can hold capacity steps or exactly the sequence if capacity is negative. */");
+    fmt.write("/* obj may be NULL for an empty sequence. */");
+    fmt.write("static __attribute__((unused)) size_t* sequence_from_python (PyObject* obj, Py_ssize_t capacity, size_t* len) {");
+    fmt.indent();
+    fmt.write("PyObject* fast = obj ? PySequence_Fast(obj, \"expected a sequence of rule ids\") : PyList_New(0);");
+    fmt.blankline();
+    fmt.write("if (!fast) {");
+    fmt.indent();
+    fmt.write("return NULL;");
+    fmt.unindent();
+    fmt.write("}");
+    fmt.blankline();
+    fmt.write("Py_ssize_t n = PySequence_Fast_GET_SIZE(fast);");
+    fmt.blankline();
+    fmt.write("if (capacity < 0) {");
+    fmt.indent();
+    fmt.write("capacity = n;");
+    fmt.unindent();
+    fmt.write("} else if (n > capacity) {");
+    fmt.indent();
+    fmt.write("Py_DECREF(fast);");
+    fmt.write("PyErr_SetString(PyExc_ValueError, \"sequence is longer than capacity\");");
+    fmt.write("return NULL;");
+    fmt.unindent();
+    fmt.write("}");
+    fmt.blankline();
+    fmt.write("size_t* buf = PyMem_Malloc(((size_t) capacity + 1) * sizeof(size_t));");
+    fmt.blankline();
+    fmt.write("if (!buf) {");
+    fmt.indent();
+    fmt.write("Py_DECREF(fast);");
+    fmt.write("PyErr_NoMemory();");
+    fmt.write("return NULL;");
+    fmt.unindent();
+    fmt.write("}");
+    fmt.blankline();
+    fmt.write("for (Py_ssize_t i = 0; i < n; ++i) {");
+    fmt.indent();
+    fmt.write("buf[i] = PyLong_AsSize_t(PySequence_Fast_GET_ITEM(fast, i));");
+    fmt.blankline();
+    fmt.write("if (buf[i] == (size_t) -1 && PyErr_Occurred()) {");
+    fmt.indent();
+    fmt.write("PyMem_Free(buf);");
+    fmt.write("Py_DECREF(fast);");
+    fmt.write("return NULL;");
+    fmt.unindent();
+    fmt.write("}");
+    fmt.unindent();
+    fmt.write("}");
+    fmt.blankline();
+    fmt.write("Py_DECREF(fast);");
+    fmt.write("*len = (size_t) n;");
+    fmt.write("return buf;");
+    fmt.unindent();
+    fmt.write("}");
+    fmt.blankline();
+
+    fmt.write("/* Create a list of rule ids and release the buffer */");
+    fmt.write("static __attribute__((unused)) PyObject* sequence_to_python (size_t* buf, size_t len) {");
+    fmt.indent();
+    fmt.write("PyObject* list = PyList_New((Py_ssize_t) len);");
+    fmt.blankline();
+    fmt.write("for (size_t i = 0; list && i < len; ++i) {");
+    fmt.indent();
+    fmt.write("PyObject* item = PyLong_FromSize_t(buf[i]);");
+    fmt.blankline();
+    fmt.write("if (!item) {");
+    fmt.indent();
+    fmt.write("Py_CLEAR(list);");
+    fmt.write("break;");
+    fmt.unindent();
+    fmt.write("}");
+    fmt.blankline();
+    fmt.write("PyList_SET_ITEM(list, (Py_ssize_t) i, item);");
+    fmt.unindent();
+    fmt.write("}");
+    fmt.blankline();
+    fmt.write("PyMem_Free(buf);");
+    fmt.write("return list;");
+    fmt.unindent();
+    fmt.write("}");
+    fmt.blankline();
+}
+
+fn emit_capacity_check(fmt: &mut CFormatter<File>) {
+    fmt.write("if (capacity < 0) {");
+    fmt.indent();
+    fmt.write("PyErr_SetString(PyExc_ValueError, \"capacity must not be negative\");");
+    fmt.write("return NULL;");
+    fmt.unindent();
+    fmt.write("}");
+    fmt.blankline();
+}
+
+fn emit_mutate(prefix: &str, fmt: &mut CFormatter<File>) {
+    fmt.write(format!("static PyObject* {prefix}python_mutate_sequence (PyObject* self, PyObject* args) {{"));
+    fmt.indent();
+    fmt.write("(void) self;");
+    fmt.write("PyObject* seq = NULL;");
+    fmt.write(format!("Py_ssize_t capacity = {};", DEFAULT_CAPACITY));
+    fmt.write("size_t len = 0;");
+    fmt.blankline();
+    fmt.write("if (!PyArg_ParseTuple(args, \"|On\", &seq, &capacity)) {");
+    fmt.indent();
+    fmt.write("return NULL;");
+    fmt.unindent();
+    fmt.write("}");
+    fmt.blankline();
+    emit_capacity_check(fmt);
+    fmt.write("size_t* buf = sequence_from_python(seq, capacity, &len);");
+    fmt.blankline();
+    fmt.write("if (!buf) {");
+    fmt.indent();
+    fmt.write("return NULL;");
+    fmt.unindent();
+    fmt.write("}");
+    fmt.blankline();
+    fmt.write(format!("len = {prefix}mutate_sequence(buf, len, (size_t) capacity);"));
+    fmt.write("return sequence_to_python(buf, len);");
+    fmt.unindent();
+    fmt.write("}");
+    fmt.blankline();
+}
+
+fn emit_serialize(prefix: &str, fmt: &mut CFormatter<File>) {
+    fmt.write(format!("static PyObject* {prefix}python_serialize_sequence (PyObject* self, PyObject* args) {{"));
+    fmt.indent();
+    fmt.write("(void) self;");
+    fmt.write("PyObject* seq;");
+    fmt.write(format!("Py_ssize_t max_len = {};", DEFAULT_MAX_LEN));
+    fmt.write("size_t len = 0;");
+    fmt.blankline();
+    fmt.write("if (!PyArg_ParseTuple(args, \"O|n\", &seq, &max_len)) {");
+    fmt.indent();
+    fmt.write("return NULL;");
+    fmt.unindent();
+    fmt.write("}");
+    fmt.blankline();
+    fmt.write("if (max_len < 0) {");
+    fmt.indent();
+    fmt.write("PyErr_SetString(PyExc_ValueError, \"max_len must not be negative\");");
+    fmt.write("return NULL;");
+    fmt.unindent();
+    fmt.write("}");
+    fmt.blankline();
+    fmt.write("size_t* buf = sequence_from_python(seq, -1, &len);");
+    fmt.blankline();
+    fmt.write("if (!buf) {");
+    fmt.indent();
+    fmt.write("return NULL;");
+    fmt.unindent();
+    fmt.write("}");
+    fmt.blankline();
+    fmt.write("PyObject* out = PyBytes_FromStringAndSize(NULL, max_len);");
+    fmt.blankline();
+    fmt.write("if (out) {");
+    fmt.indent();
+    fmt.write(format!(
+        "size_t written = {prefix}serialize_sequence(buf, len, (unsigned char*) PyBytes_AS_STRING(out), (size_t) max_len);"
+    ));
+    fmt.write("_PyBytes_Resize(&out, (Py_ssize_t) written);");
+    fmt.unindent();
+    fmt.write("}");
+    fmt.blankline();
+    fmt.write("PyMem_Free(buf);");
+    fmt.write("return out;");
+    fmt.unindent();
+    fmt.write("}");
+    fmt.blankline();
+}
+
+fn emit_unparse(prefix: &str, fmt: &mut CFormatter<File>) {
+    fmt.write(format!("static PyObject* {prefix}python_unparse_sequence (PyObject* self, PyObject* args) {{"));
+    fmt.indent();
+    fmt.write("(void) self;");
+    fmt.write("Py_buffer input;");
+    fmt.write(format!("Py_ssize_t capacity = {};", DEFAULT_CAPACITY));
+    fmt.write("size_t len = 0;");
+    fmt.blankline();
+    fmt.write("if (!PyArg_ParseTuple(args, \"y*|n\", &input, &capacity)) {");
+    fmt.indent();
+    fmt.write("return NULL;");
+    fmt.unindent();
+    fmt.write("}");
+    fmt.blankline();
+    fmt.write("size_t* buf = NULL;");
+    fmt.blankline();
+    fmt.write("if (capacity < 0) {");
+    fmt.indent();
+    fmt.write("PyErr_SetString(PyExc_ValueError, \"capacity must not be negative\");");
+    fmt.unindent();
+    fmt.write("} else {");
+    fmt.indent();
+    fmt.write("buf = sequence_from_python(NULL, capacity, &len);");
+    fmt.unindent();
+    fmt.write("}");
+    fmt.blankline();
+    fmt.write("if (!buf) {");
+    fmt.indent();
+    fmt.write("PyBuffer_Release(&input);");
+    fmt.write("return NULL;");
+    fmt.unindent();
+    fmt.write("}");
+    fmt.blankline();
+    fmt.write(format!(
+        "len = {prefix}unparse_sequence(buf, (size_t) capacity, (const unsigned char*) input.buf, (size_t) input.len);"
+    ));
+    fmt.write("PyBuffer_Release(&input);");
+    fmt.write("return sequence_to_python(buf, len);");
+    fmt.unindent();
+    fmt.write("}");
+    fmt.blankline();
+}
+
+fn emit_seed(prefix: &str, fmt: &mut CFormatter<File>) {
+    fmt.write(format!("static PyObject* {prefix}python_seed_generator (PyObject* self, PyObject* args) {{"));
+    fmt.indent();
+    fmt.write("(void) self;");
+    fmt.write("unsigned long long seed;");
+    fmt.blankline();
+    fmt.write("if (!PyArg_ParseTuple(args, \"K\", &seed)) {");
+    fmt.indent();
+    fmt.write("return NULL;");
+    fmt.unindent();
+    fmt.write("}");
+    fmt.blankline();
+    fmt.write(format!("{prefix}seed_generator((uint64_t) seed);"));
+    fmt.write("Py_RETURN_NONE;");
+    fmt.unindent();
+    fmt.write("}");
+    fmt.blankline();
+}
+
+fn emit_module_definition(module: &PythonModule, fmt: &mut CFormatter<File>) {
+    let name = module.name;
+    let prefix = module.prefix;
+
+    fmt.write("static PyMethodDef METHODS[] = {");
+    fmt.indent();
+
+    if module.mutations {
+        fmt.write(format!("{{\"mutate_sequence\", {prefix}python_mutate_sequence, METH_VARARGS, \"mutate_sequence(seq=[], capacity={DEFAULT_CAPACITY}) -> list: Create a random mutant of an automaton walk\"}},"));
+    }
+
+    if module.serializations {
+        fmt.write(format!("{{\"serialize_sequence\", {prefix}python_serialize_sequence, METH_VARARGS, \"serialize_sequence(seq, max_len={DEFAULT_MAX_LEN}) -> bytes: Create the input of an automaton walk\"}},"));
+    }
+
+    if module.unparsing {
+        fmt.write(format!("{{\"unparse_sequence\", {prefix}python_unparse_sequence, METH_VARARGS, \"unparse_sequence(data, capacity={DEFAULT_CAPACITY}) -> list: Find the automaton walk of an input or [] if it does not adhere to the grammar\"}},"));
+    }
+
+    fmt.write(format!("{{\"seed_generator\", {prefix}python_seed_generator, METH_VARARGS, \"seed_generator(seed): Seed the RNG of the generator\"}},"));
+    fmt.write("{NULL, NULL, 0, NULL},");
+    fmt.unindent();
+    fmt.write("};");
+    fmt.blankline();
+
+    fmt.write("static struct PyModuleDef MODULE = {");
+    fmt.indent();
+    fmt.write("PyModuleDef_HEAD_INIT,");
+    fmt.write(format!("\"{name}\","));
+    fmt.write("\"A grammar-based generator created by peacock\",");
+    fmt.write("-1,");
+    fmt.write("METHODS,");
+    fmt.write("NULL,");
+    fmt.write("NULL,");
+    fmt.write("NULL,");
+    fmt.write("NULL,");
+    fmt.unindent();
+    fmt.write("};");
+    fmt.blankline();
+
+    fmt.write(format!("PyMODINIT_FUNC PyInit_{name} (void) {{"));
+    fmt.indent();
+    fmt.write("return PyModule_Create(&MODULE);");
+    fmt.unindent();
+    fmt.write("}");
+}
+
+/// Write a CPython extension module into `output` that wraps the generator.
+/// It is a separate translation unit because the internal `rand()` of the generator clashes with the one of `<stdlib.h>`.
+pub(crate) fn emit_python_module(output: &Path, module: &PythonModule) -> std::io::Result<()> {
+    let mut fmt = CFormatter::new(File::create(output)?);
+    let prefix = module.prefix;
+
+    fmt.write(format!(
+        "/* Python bindings for the generator. Compile this together with the generator into {}.so */",
+        module.name
+    ));
+    fmt.write("#define PY_SSIZE_T_CLEAN");
+    fmt.write("#include <Python.h>");
+    fmt.write("#include <stddef.h>");
+    fmt.write("#include <stdint.h>");
+    fmt.blankline();
+
+    emit_prototypes(module, &mut fmt);
+    emit_conversions(&mut fmt);
+
+    if module.mutations {
+        emit_mutate(prefix, &mut fmt);
+    }
+
+    if module.serializations {
+        emit_serialize(prefix, &mut fmt);
+    }
+
+    if module.unparsing {
+        emit_unparse(prefix, &mut fmt);
+    }
+
+    emit_seed(prefix, &mut fmt);
+    emit_module_definition(module, &mut fmt);
+    fmt.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        backends::C::{
+            compile,
+            CGenerator,
+            CompileOptions,
+        },
+        grammar::ContextFreeGrammar,
+    };
+    use std::process::Command;
+
+    const SCRIPT: &str = r#"
+import sys
+import peacock_gen as gen
+
+gen.seed_generator(1234)
+
+for _ in range(100):
+    seq = gen.mutate_sequence()
+    data = gen.serialize_sequence(seq)
+    assert isinstance(data, bytes)
+    assert gen.serialize_sequence(gen.unparse_sequence(data)) == data
+    assert len(gen.serialize_sequence(seq, 1)) <= 1
+    assert gen.mutate_sequence(seq, len(seq) + 16) != None
+
+for call in [lambda: gen.mutate_sequence([0], -1), lambda: gen.mutate_sequence(["x"]), lambda: gen.mutate_sequence([0, 0], 1)]:
+    try:
+        call()
+        sys.exit(1)
+    except (ValueError, TypeError):
+        pass
+"#;
+
+    #[test]
+    fn test_python_module() {
+        let Ok(includes) = Command::new("python3-config").arg("--includes").output() else {
+            println!("python3-config not found, skipping");
+            return;
+        };
+        let includes = String::from_utf8(includes.stdout).unwrap();
+
+        let cfg =
+            ContextFreeGrammar::builder().peacock_grammar("test-data/grammars/regex.json").unwrap().build().unwrap();
+        let dir = std::env::temp_dir().join(format!("peacock-python-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let sources =
+            CGenerator::new().symbol_prefix("py_").emit_python_module(true).generate(dir.join("peacock_gen.c"), &cfg);
+        assert!(sources.last().unwrap().ends_with("peacock_gen_python.c"));
+
+        let options = includes.split_whitespace().fold(
+            CompileOptions::new().flags(["-Wall", "-Wextra", "-Werror", "-O1", "-fPIC", "-shared"]),
+            |options, flag| options.flag(flag),
+        );
+        compile(dir.join("peacock_gen.so"), &sources, &options).unwrap();
+
+        let status = Command::new("python3").arg("-c").arg(SCRIPT).env("PYTHONPATH", &dir).status().unwrap();
+        assert!(status.success());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
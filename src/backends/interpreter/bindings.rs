@@ -0,0 +1,207 @@
+use crate::grammar::BindingOp;
+
+/// A binding of an input that is being generated
+struct Binding {
+    bytes: Vec<u8>,
+    complete: bool,
+}
+
+/// The bindings of an input that is being generated, like the binding stacks of the C backend.
+/// Every binding name has a stack of bindings with the innermost one last. All bytes that are
+/// generated while a binding is open are appended to it, see [`BindingOp`].
+#[derive(Default)]
+pub(crate) struct Bindings {
+    stacks: Vec<Vec<Binding>>,
+    open: usize,
+}
+
+impl Bindings {
+    /// Bindings for a grammar with `slots` binding names
+    pub(crate) fn new(slots: usize) -> Self {
+        Self {
+            stacks: (0..slots).map(|_| Vec::new()).collect(),
+            open: 0,
+        }
+    }
+
+    /// Forget all bindings before the next input is generated
+    pub(crate) fn clear(&mut self) {
+        for stack in &mut self.stacks {
+            stack.clear();
+        }
+
+        self.open = 0;
+    }
+
+    /// Apply a marker to the bindings of `slot` and return the bytes that it produces.
+    /// The caller has to [`record`](Bindings::record) them like every other output.
+    ///
+    /// Markers only look at the innermost binding: uses of a binding that is still open produce nothing.
+    pub(crate) fn apply(&mut self, op: BindingOp, slot: usize) -> Vec<u8> {
+        let stack = &mut self.stacks[slot];
+
+        match op {
+            BindingOp::Begin => {
+                stack.push(Binding {
+                    bytes: Vec::new(),
+                    complete: false,
+                });
+                self.open += 1;
+            },
+            BindingOp::End => {
+                if let Some(binding) = stack.last_mut().filter(|binding| !binding.complete) {
+                    binding.complete = true;
+                    self.open -= 1;
+                }
+            },
+            BindingOp::Use => {
+                if let Some(binding) = stack.last().filter(|binding| binding.complete) {
+                    return binding.bytes.clone();
+                }
+            },
+            BindingOp::Pop => {
+                if stack.last().is_some_and(|binding| binding.complete) {
+                    return stack.pop().unwrap().bytes;
+                }
+            },
+        }
+
+        Vec::new()
+    }
+
+    /// Append generated bytes to all open bindings
+    pub(crate) fn record(&mut self, bytes: &[u8]) {
+        if self.open == 0 {
+            return;
+        }
+
+        for binding in self.stacks.iter_mut().flatten() {
+            if !binding.complete {
+                binding.bytes.extend_from_slice(bytes);
+            }
+        }
+    }
+
+    /// Append a run of `count` times `byte` to all open bindings
+    pub(crate) fn record_repeat(&mut self, byte: u8, count: usize) {
+        if self.open == 0 {
+            return;
+        }
+
+        for binding in self.stacks.iter_mut().flatten() {
+            if !binding.complete {
+                binding.bytes.resize(binding.bytes.len() + count, byte);
+            }
+        }
+    }
+}
+
+/// A binding while unparsing: a range of the input and the binding of the same name that encloses it
+#[derive(Clone, Copy)]
+struct Node {
+    start: usize,
+    end: Option<usize>,
+    prev: Option<usize>,
+}
+
+/// The bindings while unparsing, as ranges of the input, with the same semantics as [`Bindings`].
+///
+/// Nodes are never changed after they were created, a stack of bindings is a linked list of nodes.
+/// This way the unparsers can save the state of all bindings before they try an alternative and
+/// restore it afterwards by copying the heads of the lists, see [`UnparseBindings::save`].
+#[derive(Default)]
+pub(crate) struct UnparseBindings {
+    nodes: Vec<Node>,
+    tops: Vec<Option<usize>>,
+}
+
+/// The state of all bindings at some point of unparsing
+#[derive(Clone, Default)]
+pub(crate) struct BindingState {
+    tops: Vec<Option<usize>>,
+    nodes: usize,
+}
+
+impl UnparseBindings {
+    /// Bindings for a grammar with `slots` binding names
+    pub(crate) fn new(slots: usize) -> Self {
+        Self {
+            nodes: Vec::new(),
+            tops: vec![None; slots],
+        }
+    }
+
+    /// Forget all bindings before the next input is unparsed
+    pub(crate) fn clear(&mut self) {
+        self.nodes.clear();
+        self.tops.fill(None);
+    }
+
+    /// The current state of all bindings
+    pub(crate) fn save(&self) -> BindingState {
+        BindingState {
+            tops: self.tops.clone(),
+            nodes: self.nodes.len(),
+        }
+    }
+
+    /// Go back to the state `state`. Nodes that were created after it are discarded, except for
+    /// the nodes of the state `keep`, which is still needed.
+    pub(crate) fn restore(&mut self, state: &BindingState, keep: &BindingState) {
+        self.tops.clone_from(&state.tops);
+        self.nodes.truncate(state.nodes.max(keep.nodes));
+    }
+
+    /// Apply a marker to the bindings of `slot` while matching `input` at `cursor`.
+    /// Returns the new cursor or `None` if a use does not repeat the bytes of its binding.
+    pub(crate) fn apply(&mut self, op: BindingOp, slot: usize, input: &[u8], cursor: usize) -> Option<usize> {
+        let top = self.tops[slot].map(|top| self.nodes[top]);
+
+        match op {
+            BindingOp::Begin => {
+                self.push(
+                    slot,
+                    Node {
+                        start: cursor,
+                        end: None,
+                        prev: self.tops[slot],
+                    },
+                );
+            },
+            BindingOp::End => {
+                if let Some(node) = top.filter(|node| node.end.is_none()) {
+                    self.push(
+                        slot,
+                        Node {
+                            end: Some(cursor),
+                            ..node
+                        },
+                    );
+                }
+            },
+            BindingOp::Use | BindingOp::Pop => {
+                let Some(node) = top.filter(|node| node.end.is_some()) else {
+                    return Some(cursor);
+                };
+                let bound = &input[node.start..node.end.unwrap()];
+
+                if !input[cursor..].starts_with(bound) {
+                    return None;
+                }
+
+                if op == BindingOp::Pop {
+                    self.tops[slot] = node.prev;
+                }
+
+                return Some(cursor + bound.len());
+            },
+        }
+
+        Some(cursor)
+    }
+
+    fn push(&mut self, slot: usize, node: Node) {
+        self.tops[slot] = Some(self.nodes.len());
+        self.nodes.push(node);
+    }
+}
@@ -0,0 +1,342 @@
+use ahash::RandomState;
+use std::{
+    collections::HashSet,
+    sync::Arc,
+};
+
+use crate::backends::{
+    interpreter::Bindings,
+    C::{
+        LLSymbol,
+        LowLevelGrammar,
+    },
+};
+
+/// The default number of outputs that an [`Enumeration`] remembers to filter duplicates
+pub const DEFAULT_DEDUP_CAP: usize = 1_000_000;
+
+/// A rule choice in the walk of an [`Enumeration`]
+#[derive(Clone, Copy, Debug)]
+struct Choice {
+    nonterm: usize,
+    level: usize,
+    rule: usize,
+}
+
+/// An exhaustive enumeration of the derivations of a grammar, created by
+/// [`GrammarInterpreter::enumerate`](super::GrammarInterpreter::enumerate).
+///
+/// The derivations are visited with iterative deepening: first all derivation trees of depth 1, then all of
+/// depth 2 and so on up to the maximum depth. Only the walk of the current derivation is kept in memory,
+/// so apart from the duplicate filter the memory usage is proportional to the size of the largest derivation.
+///
+/// Different walks can serialize to the same output. The hashes of the yielded outputs are remembered
+/// to filter such duplicates, but only up to a cap (see [`dedup_cap`](Enumeration::dedup_cap)).
+/// After that, outputs may be yielded more than once.
+///
+/// Builtins like `@int(0, 1000)` are not enumerated, they always produce the value of step 0, e.g. their minimum.
+pub struct Enumeration {
+    grammar: Arc<LowLevelGrammar>,
+    dynamic_terminals: Vec<Vec<u8>>,
+    heights: Vec<usize>,
+    max_depth: usize,
+    depth: usize,
+    started: bool,
+    choices: Vec<Choice>,
+    hasher: RandomState,
+    seen: HashSet<u64>,
+    dedup_cap: usize,
+}
+
+impl Enumeration {
+    pub(crate) fn new(grammar: Arc<LowLevelGrammar>, dynamic_terminals: Vec<Vec<u8>>, max_depth: usize) -> Self {
+        let mut enumeration = Self {
+            grammar,
+            dynamic_terminals,
+            heights: Vec::new(),
+            max_depth,
+            depth: 1,
+            started: false,
+            choices: Vec::new(),
+            hasher: RandomState::with_seeds(0, 0, 0, 0),
+            seen: HashSet::new(),
+            dedup_cap: DEFAULT_DEDUP_CAP,
+        };
+        enumeration.heights = enumeration.minimal_heights();
+        enumeration
+    }
+
+    /// Remember at most `cap` outputs to filter duplicates. Default: [`DEFAULT_DEDUP_CAP`].
+    pub fn dedup_cap(mut self, cap: usize) -> Self {
+        self.dedup_cap = cap;
+        self
+    }
+
+    fn is_dynamic(&self, nonterm: usize) -> bool {
+        self.grammar.dynamic().is_some_and(|dynamic| dynamic.id() == nonterm)
+    }
+
+    /// Rule i > 0 of the dynamic non-terminal stands for the dynamic terminal i - 1
+    fn num_rules(&self, nonterm: usize) -> usize {
+        if self.is_dynamic(nonterm) {
+            self.dynamic_terminals.len() + 1
+        } else {
+            self.grammar.rules()[&nonterm].len()
+        }
+    }
+
+    fn children(&self, nonterm: usize, rule: usize) -> impl DoubleEndedIterator<Item = usize> + '_ {
+        let rule: &[LLSymbol] = if self.is_dynamic(nonterm) { &[] } else { &self.grammar.rules()[&nonterm][rule] };
+
+        rule.iter().filter_map(|symbol| match symbol {
+            LLSymbol::NonTerminal(child) => Some(child.id()),
+            LLSymbol::Terminal(_)
+            | LLSymbol::Repeat {
+                ..
+            }
+            | LLSymbol::Binding {
+                ..
+            } => None,
+        })
+    }
+
+    /// The depth of the shallowest derivation tree that starts with `rule`, `usize::MAX` if there is none
+    fn rule_height(&self, heights: &[usize], nonterm: usize, rule: usize) -> usize {
+        self.children(nonterm, rule).map(|child| heights[child]).max().unwrap_or(0).saturating_add(1)
+    }
+
+    /// For every non-terminal: the depth of its shallowest derivation tree
+    fn minimal_heights(&self) -> Vec<usize> {
+        let mut heights = vec![usize::MAX; self.grammar.nonterminals().len()];
+        let mut changed = true;
+
+        while changed {
+            changed = false;
+
+            for &nonterm in self.grammar.rules().keys() {
+                for rule in 0..self.num_rules(nonterm) {
+                    let height = self.rule_height(&heights, nonterm, rule);
+
+                    if height < heights[nonterm] {
+                        heights[nonterm] = height;
+                        changed = true;
+                    }
+                }
+            }
+        }
+
+        heights
+    }
+
+    fn is_feasible(&self, choice: &Choice) -> bool {
+        let height = self.rule_height(&self.heights, choice.nonterm, choice.rule);
+        height != usize::MAX && choice.level + height - 1 <= self.depth
+    }
+
+    /// Replay the current walk and extend it with the first feasible rule of every pending non-terminal
+    fn complete(&mut self) {
+        let mut stack = vec![(self.grammar.entrypoint().id(), 1)];
+
+        for choice in &self.choices {
+            stack.pop();
+
+            for child in self.children(choice.nonterm, choice.rule).rev() {
+                stack.push((child, choice.level + 1));
+            }
+        }
+
+        while let Some((nonterm, level)) = stack.pop() {
+            let choice = (0..self.num_rules(nonterm))
+                .map(|rule| Choice {
+                    nonterm,
+                    level,
+                    rule,
+                })
+                .find(|choice| self.is_feasible(choice))
+                .expect("Every pending non-terminal fits into the depth");
+
+            for child in self.children(nonterm, choice.rule).rev() {
+                stack.push((child, level + 1));
+            }
+
+            self.choices.push(choice);
+        }
+    }
+
+    /// Move on to the next walk in lexicographic order. Returns `false` if there is none.
+    fn advance(&mut self) -> bool {
+        while let Some(mut choice) = self.choices.pop() {
+            for rule in choice.rule + 1..self.num_rules(choice.nonterm) {
+                choice.rule = rule;
+
+                if self.is_feasible(&choice) {
+                    self.choices.push(choice);
+                    self.complete();
+                    return true;
+                }
+            }
+        }
+
+        false
+    }
+
+    fn serialize(&self) -> Vec<u8> {
+        let mut output = Vec::new();
+        let mut stack = vec![LLSymbol::NonTerminal(*self.grammar.entrypoint())];
+        let mut choices = self.choices.iter();
+        let mut bindings = Bindings::new(self.grammar.bindings().len());
+
+        while let Some(symbol) = stack.pop() {
+            match symbol {
+                LLSymbol::Terminal(term) => {
+                    let term = self.grammar.terminals()[term.id()].as_bytes();
+                    output.extend_from_slice(term);
+                    bindings.record(term);
+                },
+                LLSymbol::Repeat {
+                    byte,
+                    count,
+                } => {
+                    output.resize(output.len() + count, byte);
+                    bindings.record_repeat(byte, count);
+                },
+                LLSymbol::Binding {
+                    op,
+                    slot,
+                } => {
+                    let bytes = bindings.apply(op, slot);
+                    output.extend_from_slice(&bytes);
+                    bindings.record(&bytes);
+                },
+                LLSymbol::NonTerminal(nonterm) => {
+                    let choice = choices.next().unwrap();
+
+                    if let Some(builtin) = self.grammar.builtin(nonterm.id()) {
+                        let value = builtin.render(choice.rule as u64);
+                        output.extend_from_slice(&value);
+                        bindings.record(&value);
+                    } else if self.is_dynamic(nonterm.id()) {
+                        if choice.rule > 0 {
+                            let term = &self.dynamic_terminals[choice.rule - 1];
+                            output.extend_from_slice(term);
+                            bindings.record(term);
+                        }
+                    } else {
+                        stack.extend(self.grammar.rules()[&nonterm.id()][choice.rule].iter().rev().cloned());
+                    }
+                },
+            }
+        }
+
+        output
+    }
+}
+
+impl Iterator for Enumeration {
+    type Item = Vec<u8>;
+
+    fn next(&mut self) -> Option<Vec<u8>> {
+        loop {
+            if !self.started {
+                if self.depth > self.max_depth {
+                    return None;
+                }
+
+                self.started = true;
+                self.choices.clear();
+
+                if self.heights[self.grammar.entrypoint().id()] > self.depth {
+                    self.started = false;
+                    self.depth += 1;
+                    continue;
+                }
+
+                self.complete();
+            } else if !self.advance() {
+                self.started = false;
+                self.depth += 1;
+                continue;
+            }
+
+            /* Shallower derivations were yielded in an earlier round */
+            if self.choices.iter().map(|choice| choice.level).max() != Some(self.depth) {
+                continue;
+            }
+
+            let output = self.serialize();
+            let hash = self.hasher.hash_one(&output);
+
+            if self.seen.contains(&hash) {
+                continue;
+            }
+
+            if self.seen.len() < self.dedup_cap {
+                self.seen.insert(hash);
+            }
+
+            return Some(output);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        backends::interpreter::GrammarInterpreter,
+        grammar::{
+            ContextFreeGrammar,
+            NonTerminal,
+            ProductionRule,
+            Symbol,
+            Terminal,
+        },
+    };
+
+    fn grammar(rules: &[(&str, &[&str])]) -> ContextFreeGrammar {
+        let rules = rules
+            .iter()
+            .map(|(lhs, rhs)| {
+                let rhs = rhs
+                    .iter()
+                    .map(|symbol| match symbol.strip_prefix('<').and_then(|x| x.strip_suffix('>')) {
+                        Some(nonterm) => Symbol::NonTerminal(NonTerminal::new(nonterm)),
+                        None => Symbol::Terminal(Terminal::new(*symbol)),
+                    })
+                    .collect();
+                ProductionRule::new(NonTerminal::new(*lhs), rhs)
+            })
+            .collect();
+        ContextFreeGrammar::new(rules, NonTerminal::new("S"))
+    }
+
+    fn enumerate(cfg: &ContextFreeGrammar, max_depth: usize) -> Vec<String> {
+        GrammarInterpreter::new(cfg).enumerate(max_depth).map(|output| String::from_utf8(output).unwrap()).collect()
+    }
+
+    #[test]
+    fn test_enumerate() {
+        /* Infinite languages are cut by the depth */
+        let cfg = grammar(&[("S", &["(", "<S>", ")"]), ("S", &["x"])]);
+        assert_eq!(enumerate(&cfg, 0), Vec::<String>::new());
+        assert_eq!(enumerate(&cfg, 3), ["x", "(x)", "((x))"]);
+
+        /* Finite languages are enumerated completely */
+        let cfg = grammar(&[("S", &["<A>", "<A>"]), ("A", &["a"]), ("A", &["b"]), ("A", &[""])]);
+        let mut outputs = enumerate(&cfg, 10);
+        outputs.sort();
+        assert_eq!(outputs, ["", "a", "aa", "ab", "b", "ba", "bb"]);
+
+        /* Different walks that produce the same output */
+        let cfg = grammar(&[("S", &["<A>"]), ("S", &["<B>"]), ("A", &["x"]), ("B", &["x"])]);
+        assert_eq!(enumerate(&cfg, 2), ["x"]);
+        assert_eq!(GrammarInterpreter::new(&cfg).enumerate(2).dedup_cap(0).count(), 2);
+
+        let cfg =
+            ContextFreeGrammar::builder().peacock_grammar("test-data/grammars/dynamic.json").unwrap().build().unwrap();
+        let mut interpreter = GrammarInterpreter::new(&cfg);
+        interpreter.add_dynamic_terminal(b"magic");
+        let mut outputs: Vec<Vec<u8>> = interpreter.enumerate(10).collect();
+        outputs.sort();
+        assert_eq!(outputs, [&b"if x == 0;"[..], b"if x == ;", b"if x == magic;"]);
+    }
+}
@@ -12,36 +12,152 @@
 //! GrammarInterpreter::new(&grammar).interpret(&mut stream).unwrap();
 //! ```
 
-use std::io::Write;
+use rand_core::RngCore;
+use std::{
+    io::Write,
+    sync::Arc,
+};
+
+mod bindings;
+pub(crate) use bindings::{
+    BindingState,
+    Bindings,
+    UnparseBindings,
+};
+
+mod enumerate;
+pub use enumerate::{
+    Enumeration,
+    DEFAULT_DEDUP_CAP,
+};
+
+mod unparse;
+pub(crate) use unparse::Unparser;
 
 use crate::{
-    backends::C::{
-        LLSymbol,
-        LowLevelGrammar,
+    backends::{
+        GenerationProfile,
+        C::{
+            LLSymbol,
+            LowLevelGrammar,
+        },
     },
+    error::ReplayError,
     grammar::ContextFreeGrammar,
 };
 
+/// The sum of the weights of the rules of a non-terminal in coverage-guided mode is about this times the number of rules
+const COVERAGE_WEIGHT: usize = 1 << 16;
+
+/// An RNG that can be cloned together with the interpreter
+trait InterpreterRng: RngCore + Send {
+    fn clone_box(&self) -> Box<dyn InterpreterRng>;
+}
+
+impl<R> InterpreterRng for R
+where
+    R: RngCore + Send + Clone + 'static,
+{
+    fn clone_box(&self) -> Box<dyn InterpreterRng> {
+        Box::new(self.clone())
+    }
+}
+
+/// How often every rule was used in coverage-guided mode
+#[derive(Clone)]
+struct RuleUsage {
+    counts: Vec<Vec<u64>>,
+    /// The number of rules whose count is not zero
+    covered: usize,
+    /// The number of rules that are reachable from the entrypoint
+    total: usize,
+}
+
+impl RuleUsage {
+    fn record(&mut self, nonterm: usize, rule: usize) {
+        let count = &mut self.counts[nonterm][rule];
+
+        if *count == 0 {
+            self.covered += 1;
+        }
+
+        *count += 1;
+    }
+}
+
 /// The GrammarInterpreter interprets the rules of a grammar to generate inputs.
+///
+/// Cloning an interpreter is cheap because clones share the grammar. This makes it easy to
+/// generate inputs in multiple threads, just make sure to [`seed`](GrammarInterpreter::seed) every clone differently.
 pub struct GrammarInterpreter {
-    grammar: LowLevelGrammar,
-    seed: usize,
+    grammar: Arc<LowLevelGrammar>,
+    seed: u64,
+    rng: Option<Box<dyn InterpreterRng>>,
     stack: Vec<LLSymbol>,
+    minimal: Arc<[(usize, usize)]>,
+    dynamic_terminals: Vec<Vec<u8>>,
+    usage: Option<RuleUsage>,
+    bindings: Bindings,
+    profile: GenerationProfile,
 }
 
 impl GrammarInterpreter {
     /// Create a new GrammarInterpreter.
     #[allow(clippy::new_without_default)]
     pub fn new(grammar: &ContextFreeGrammar) -> Self {
+        Self::with_shared(Arc::new(LowLevelGrammar::from_high_level_grammar(grammar)))
+    }
+
+    /// Create a new GrammarInterpreter for a grammar that may be shared with other users.
+    pub(crate) fn with_shared(grammar: Arc<LowLevelGrammar>) -> Self {
+        let minimal = grammar.minimal_expansions().into();
+        let bindings = Bindings::new(grammar.bindings().len());
+
         Self {
-            grammar: LowLevelGrammar::from_high_level_grammar(grammar),
+            grammar,
             seed: 0xDEADBEEF,
+            rng: None,
             stack: Vec::with_capacity(4096),
+            minimal,
+            dynamic_terminals: Vec::new(),
+            usage: None,
+            bindings,
+            profile: GenerationProfile::new(),
+        }
+    }
+
+    /// Add a terminal that the non-terminal [`DYNAMIC`](crate::grammar::DYNAMIC_NONTERMINAL) can derive,
+    /// e.g. a magic value that was discovered during fuzzing.
+    ///
+    /// Returns `false` if the terminal is empty or was already added.
+    pub fn add_dynamic_terminal(&mut self, terminal: &[u8]) -> bool {
+        if terminal.is_empty() || self.dynamic_terminals.iter().any(|known| known == terminal) {
+            return false;
         }
+
+        self.dynamic_terminals.push(terminal.to_vec());
+        true
+    }
+
+    /// Make random choices with `rng` instead of the built-in xorshift, e.g. to draw them from
+    /// the same source as the rest of a differential test. Like the built-in RNG it is only advanced
+    /// for non-terminals with a choice. Clones of the interpreter get a clone of `rng`.
+    pub fn with_rng<R>(mut self, rng: R) -> Self
+    where
+        R: RngCore + Send + Clone + 'static,
+    {
+        self.rng = Some(Box::new(rng));
+        self
     }
 
     /// Seed the RNG of the GrammarInterpreter.
-    pub fn seed(&mut self, seed: usize) {
+    ///
+    /// The RNG is the same 64-bit xorshift as the one of the C backend and it is only advanced for
+    /// non-terminals with a choice and for builtins, so a seed generates the same inputs on every platform and in both backends.
+    /// This replaces an RNG that was set with [`with_rng`](GrammarInterpreter::with_rng).
+    pub fn seed(&mut self, seed: u64) {
+        self.rng = None;
+
         if seed == 0 {
             self.seed = 0xDEADBEEF;
         } else {
@@ -49,26 +165,344 @@ impl GrammarInterpreter {
         }
     }
 
+    /// Scale the ranges of builtins like `@int(0, 1000)` in new inputs with `profile`.
+    /// [`replay`](GrammarInterpreter::replay) is not affected, it accepts every value of a builtin.
+    ///
+    /// Every builtin advances the RNG once, independent of the profile.
+    pub fn profile(&mut self, profile: GenerationProfile) {
+        self.profile = profile;
+    }
+
+    /// Enable or disable coverage-guided generation.
+    ///
+    /// In coverage-guided mode the interpreter counts how often every rule was used over all generated inputs
+    /// and picks a rule with a probability that is inversely proportional to its usage. This covers all rules of
+    /// the grammar much faster than uniform choices, e.g. to build a seed corpus.
+    /// Disabling the mode resets the counters.
+    pub fn coverage_guided(&mut self, flag: bool) {
+        if !flag {
+            self.usage = None;
+        } else if self.usage.is_none() {
+            let nonterms = self.grammar.nonterminals().len();
+            self.usage = Some(RuleUsage {
+                counts: (0..nonterms).map(|nonterm| vec![0; self.grammar.rules()[&nonterm].len()]).collect(),
+                covered: 0,
+                total: self.reachable_rules(),
+            });
+        }
+    }
+
+    /// The rules that no input used since coverage-guided mode was enabled, as pairs of
+    /// (non-terminal, rule id). Rules are numbered in the order of their definition in the grammar,
+    /// like in the walks of [`interpret_into`](GrammarInterpreter::interpret_into).
+    ///
+    /// Rules of non-terminals that are not reachable from the entrypoint can never be covered and are left out.
+    /// Returns all other rules if coverage-guided mode is disabled.
+    pub fn uncovered_rules(&self) -> Vec<(&str, usize)> {
+        let reachable = self.grammar.reachable_nonterminals();
+        let mut uncovered = Vec::new();
+
+        for (nonterm, name) in self.grammar.nonterminals().iter().enumerate() {
+            if !reachable[nonterm] {
+                continue;
+            }
+
+            for rule in 0..self.grammar.rules()[&nonterm].len() {
+                if self.usage.as_ref().is_none_or(|usage| usage.counts[nonterm][rule] == 0) {
+                    uncovered.push((name.as_str(), rule));
+                }
+            }
+        }
+
+        uncovered
+    }
+
+    /// The number of rules that were used since coverage-guided mode was enabled and the number of rules
+    /// that are reachable from the entrypoint.
+    /// This is cheap in coverage-guided mode, the counts are kept up to date while generating.
+    pub fn rule_coverage(&self) -> (usize, usize) {
+        match &self.usage {
+            Some(usage) => (usage.covered, usage.total),
+            None => (0, self.reachable_rules()),
+        }
+    }
+
+    /// The number of rules of all non-terminals that are reachable from the entrypoint
+    fn reachable_rules(&self) -> usize {
+        let reachable = self.grammar.reachable_nonterminals();
+        (0..reachable.len())
+            .filter(|nonterm| reachable[*nonterm])
+            .map(|nonterm| self.grammar.rules()[&nonterm].len())
+            .sum()
+    }
+
+    /// Enumerate every distinct output of the grammar whose derivation tree is at most `max_depth` levels deep,
+    /// shallow derivations first. The RNG is not used.
+    ///
+    /// Infinite languages are cut by the depth, but the number of outputs can still grow exponentially with it.
+    /// See [`Enumeration`] for the memory usage.
+    pub fn enumerate(&self, max_depth: usize) -> Enumeration {
+        Enumeration::new(self.grammar.clone(), self.dynamic_terminals.clone(), max_depth)
+    }
+
     /// Generate one input and write it to the given output stream `stream`.
     /// Returns the number of bytes written to `stream`.
     pub fn interpret<S: Write>(&mut self, stream: &mut S) -> std::io::Result<usize> {
+        Ok(self.expand(stream, usize::MAX, usize::MAX, None)?.0)
+    }
+
+    /// Generate one input and write at most `max_bytes` bytes of it to `stream`.
+    ///
+    /// In contrast to the `max_len` of [`interpret_into`](GrammarInterpreter::interpret_into),
+    /// this is a hard limit that ignores the structure of the input. Generation stops as soon as
+    /// a terminal does not fit anymore, the part of it that fits is still written.
+    ///
+    /// Returns the number of bytes written to `stream` and whether the input was cut off.
+    pub fn interpret_bounded<S: Write>(&mut self, stream: &mut S, max_bytes: usize) -> std::io::Result<(usize, bool)> {
+        self.expand(stream, usize::MAX, max_bytes, None)
+    }
+
+    /// Generate one input into `buf`, reusing its allocation.
+    ///
+    /// Once the input would grow beyond `max_len` bytes only the shortest expansions are chosen,
+    /// so the input is never cut off in the middle of a terminal. It only exceeds `max_len`
+    /// if the shortest input of the grammar is longer than that.
+    ///
+    /// If `walk` is given, it receives the chosen rules as pairs of (non-terminal id, rule id)
+    /// with the same numbering that the [`C`](crate::backends::C) backend uses, such that
+    /// the rule ids form a valid sequence for the generated code.
+    ///
+    /// Returns the number of bytes in `buf`.
+    pub fn interpret_into(
+        &mut self,
+        buf: &mut Vec<u8>,
+        max_len: usize,
+        walk: Option<&mut Vec<(usize, usize)>>,
+    ) -> usize {
+        buf.clear();
+        self.expand(buf, max_len, usize::MAX, walk).expect("Writing to a Vec cannot fail").0
+    }
+
+    /// Generate one input, write it to `stream` and record the chosen rules in `walk_out`
+    /// as pairs of (non-terminal id, rule id), like [`interpret_into`](GrammarInterpreter::interpret_into) does.
+    /// The walk can be turned into the same bytes later with [`replay`](GrammarInterpreter::replay).
+    ///
+    /// Returns the number of bytes written to `stream`.
+    pub fn interpret_recorded<S: Write>(
+        &mut self,
+        stream: &mut S,
+        walk_out: &mut Vec<(usize, usize)>,
+    ) -> std::io::Result<usize> {
+        Ok(self.expand(stream, usize::MAX, usize::MAX, Some(walk_out))?.0)
+    }
+
+    /// Write the input that `walk` describes to `stream`, without using the RNG.
+    /// `walk` is a sequence of (non-terminal id, rule id) pairs as recorded by
+    /// [`interpret_recorded`](GrammarInterpreter::interpret_recorded) or [`interpret_into`](GrammarInterpreter::interpret_into).
+    ///
+    /// Fails if the walk does not fit the grammar, e.g. because it was recorded with a different version of it.
+    /// Returns the number of bytes written to `stream`.
+    pub fn replay<S: Write>(&mut self, walk: &[(usize, usize)], stream: &mut S) -> Result<usize, ReplayError> {
+        let result = self.replay_walk(walk, stream);
+        self.stack.clear();
+        result
+    }
+
+    fn replay_walk<S: Write>(&mut self, walk: &[(usize, usize)], stream: &mut S) -> Result<usize, ReplayError> {
+        let mut generated = 0;
+        let mut step = 0;
+
+        assert!(self.stack.is_empty());
+        self.stack.push(LLSymbol::NonTerminal(*self.grammar.entrypoint()));
+        self.bindings.clear();
+
+        while let Some(symbol) = self.stack.pop() {
+            match symbol {
+                LLSymbol::Terminal(term) => {
+                    let term = self.grammar.terminals()[term.id()].as_bytes();
+                    generated += term.len();
+                    stream.write_all(term)?;
+                    self.bindings.record(term);
+                },
+                LLSymbol::Repeat {
+                    byte,
+                    count,
+                } => {
+                    generated += count;
+                    write_repeat(stream, byte, count)?;
+                    self.bindings.record_repeat(byte, count);
+                },
+                LLSymbol::Binding {
+                    op,
+                    slot,
+                } => {
+                    let bytes = self.bindings.apply(op, slot);
+                    generated += bytes.len();
+                    stream.write_all(&bytes)?;
+                    self.bindings.record(&bytes);
+                },
+                LLSymbol::NonTerminal(nonterm) => {
+                    let Some(&(found, rule)) = walk.get(step) else {
+                        return Err(ReplayError::Incomplete(walk.len()));
+                    };
+                    let name = || self.grammar.nonterminals()[nonterm.id()].clone();
+
+                    if found != nonterm.id() {
+                        return Err(ReplayError::WrongNonTerminal {
+                            step,
+                            expected: name(),
+                            expected_id: nonterm.id(),
+                            found,
+                        });
+                    }
+
+                    /* Every step of a builtin stands for one of its values */
+                    if let Some(builtin) = self.grammar.builtin(nonterm.id()) {
+                        let value = builtin.render(rule as u64);
+                        generated += value.len();
+                        stream.write_all(&value)?;
+                        self.bindings.record(&value);
+                        step += 1;
+                        continue;
+                    }
+
+                    let rules = self.grammar.rules().get(&nonterm.id()).unwrap();
+                    let is_dynamic = self.grammar.dynamic().is_some_and(|dynamic| dynamic.id() == nonterm.id());
+                    let choices = if is_dynamic { self.dynamic_terminals.len() + 1 } else { rules.len() };
+
+                    if rule >= choices {
+                        return Err(ReplayError::RuleOutOfRange {
+                            step,
+                            non_terminal: name(),
+                            rule,
+                            rules: choices,
+                        });
+                    }
+
+                    step += 1;
+
+                    /* Rule i > 0 of the dynamic non-terminal stands for the dynamic terminal i - 1 */
+                    if is_dynamic {
+                        if rule > 0 {
+                            let term = &self.dynamic_terminals[rule - 1];
+                            generated += term.len();
+                            stream.write_all(term)?;
+                            self.bindings.record(term);
+                        }
+
+                        continue;
+                    }
+
+                    for symbol in rules[rule].iter().rev() {
+                        self.stack.push(symbol.clone());
+                    }
+                },
+            }
+        }
+
+        if step < walk.len() {
+            return Err(ReplayError::TrailingSteps {
+                steps: step,
+                len: walk.len(),
+            });
+        }
+
+        Ok(generated)
+    }
+
+    /// Lazily generate inputs, one after another. The RNG advances with every input, so
+    /// the iterator never ends and yields the same inputs as repeated calls to [`interpret`](GrammarInterpreter::interpret).
+    ///
+    /// ```no_run
+    /// # use peacock_fuzz::{backends::interpreter::GrammarInterpreter, grammar::ContextFreeGrammar};
+    /// # let grammar = ContextFreeGrammar::builder().peacock_grammar("my-grammar.json").unwrap().build().unwrap();
+    /// let mut interpreter = GrammarInterpreter::new(&grammar);
+    /// let short: Vec<Vec<u8>> = interpreter.samples().filter(|input| input.len() < 32).take(10).collect();
+    /// ```
+    pub fn samples(&mut self) -> impl Iterator<Item = Vec<u8>> + '_ {
+        let mut buf = Vec::new();
+
+        std::iter::from_fn(move || {
+            self.interpret_into(&mut buf, usize::MAX, None);
+            Some(buf.to_vec())
+        })
+    }
+
+    fn expand<S: Write>(
+        &mut self,
+        stream: &mut S,
+        max_len: usize,
+        max_bytes: usize,
+        mut walk: Option<&mut Vec<(usize, usize)>>,
+    ) -> std::io::Result<(usize, bool)> {
         let mut generated = 0;
+        // The minimum number of bytes the input will have given the current stack
+        let mut committed = self.minimal[self.grammar.entrypoint().id()].0;
+
+        if let Some(walk) = walk.as_mut() {
+            walk.clear();
+        }
 
         assert!(self.stack.is_empty());
         self.stack.push(LLSymbol::NonTerminal(*self.grammar.entrypoint()));
+        self.bindings.clear();
 
         while let Some(symbol) = self.stack.pop() {
             match symbol {
                 LLSymbol::Terminal(term) => {
                     let term = &self.grammar.terminals()[term.id()].as_bytes();
+
+                    if term.len() > max_bytes - generated {
+                        stream.write_all(&term[..max_bytes - generated])?;
+                        self.stack.clear();
+                        return Ok((max_bytes, true));
+                    }
+
                     generated += term.len();
                     stream.write_all(term)?;
+                    self.bindings.record(term);
+                },
+                LLSymbol::Repeat {
+                    byte,
+                    count,
+                } => {
+                    if count > max_bytes - generated {
+                        write_repeat(stream, byte, max_bytes - generated)?;
+                        self.stack.clear();
+                        return Ok((max_bytes, true));
+                    }
+
+                    generated += count;
+                    write_repeat(stream, byte, count)?;
+                    self.bindings.record_repeat(byte, count);
+                },
+                /* Uses do not count towards max_len because they repeat bytes that were already committed */
+                LLSymbol::Binding {
+                    op,
+                    slot,
+                } => {
+                    let bytes = self.bindings.apply(op, slot);
+
+                    if bytes.len() > max_bytes - generated {
+                        stream.write_all(&bytes[..max_bytes - generated])?;
+                        self.stack.clear();
+                        return Ok((max_bytes, true));
+                    }
+
+                    generated += bytes.len();
+                    stream.write_all(&bytes)?;
+                    self.bindings.record(&bytes);
                 },
                 LLSymbol::NonTerminal(nonterm) => {
                     let rules = self.grammar.rules().get(&nonterm.id()).unwrap();
+                    let is_dynamic = self.grammar.dynamic().is_some_and(|dynamic| dynamic.id() == nonterm.id());
 
                     // Inline RNG because of borrow problems
-                    let rand = {
+                    let mut draw = || {
+                        if let Some(rng) = self.rng.as_mut() {
+                            return rng.next_u64();
+                        }
+
                         let mut x = self.seed;
                         x ^= x << 13;
                         x ^= x >> 7;
@@ -77,22 +511,187 @@ impl GrammarInterpreter {
                         x
                     };
 
-                    let rule = &rules[rand % rules.len()];
+                    /* The step of a builtin holds its value, which is shortened like a rule if it does not fit */
+                    if let Some(builtin) = self.grammar.builtin(nonterm.id()) {
+                        let mut step = builtin.choose(draw(), self.profile.scaled_span(builtin));
+                        let mut value = builtin.render(step);
+                        let new_committed = (committed - self.minimal[nonterm.id()].0).saturating_add(value.len());
+
+                        if new_committed > max_len {
+                            step = builtin.shortest();
+                            value = builtin.render(step);
+                        } else {
+                            committed = new_committed;
+                        }
+
+                        if value.len() > max_bytes - generated {
+                            stream.write_all(&value[..max_bytes - generated])?;
+                            self.stack.clear();
+                            return Ok((max_bytes, true));
+                        }
 
-                    for symbol in rule.iter().rev() {
+                        generated += value.len();
+                        stream.write_all(&value)?;
+                        self.bindings.record(&value);
+
+                        if let Some(walk) = walk.as_mut() {
+                            walk.push((nonterm.id(), step as usize));
+                        }
+
+                        if let Some(usage) = self.usage.as_mut() {
+                            usage.record(nonterm.id(), 0);
+                        }
+
+                        continue;
+                    }
+
+                    /* Rule i > 0 of the dynamic non-terminal stands for the dynamic terminal i - 1 */
+                    if is_dynamic {
+                        let mut idx = (draw() % (self.dynamic_terminals.len() + 1) as u64) as usize;
+
+                        if idx > 0 {
+                            let term = &self.dynamic_terminals[idx - 1];
+
+                            if committed.saturating_add(term.len()) > max_len {
+                                idx = 0;
+                            } else if term.len() > max_bytes - generated {
+                                stream.write_all(&term[..max_bytes - generated])?;
+                                self.stack.clear();
+                                return Ok((max_bytes, true));
+                            } else {
+                                committed += term.len();
+                                generated += term.len();
+                                stream.write_all(term)?;
+                                self.bindings.record(term);
+                            }
+                        }
+
+                        if let Some(walk) = walk.as_mut() {
+                            walk.push((nonterm.id(), idx));
+                        }
+
+                        if let Some(usage) = self.usage.as_mut() {
+                            usage.record(nonterm.id(), 0);
+                        }
+
+                        continue;
+                    }
+
+                    let (minimal_len, minimal_rule) = self.minimal[nonterm.id()];
+                    let mut idx = if rules.len() == 1 {
+                        0
+                    } else if let Some(usage) = &self.usage {
+                        /* Rarely used rules are more likely */
+                        let weight = |count: &u64| (COVERAGE_WEIGHT / (*count as usize).saturating_add(1)).max(1);
+                        let counts = &usage.counts[nonterm.id()];
+                        let mut x = (draw() % counts.iter().map(weight).sum::<usize>() as u64) as usize;
+                        let mut idx = 0;
+
+                        while x >= weight(&counts[idx]) {
+                            x -= weight(&counts[idx]);
+                            idx += 1;
+                        }
+
+                        idx
+                    } else {
+                        (draw() % rules.len() as u64) as usize
+                    };
+                    let mut rule_len = 0usize;
+
+                    for symbol in &rules[idx] {
+                        rule_len = rule_len.saturating_add(match symbol {
+                            LLSymbol::Terminal(term) => self.grammar.terminals()[term.id()].len(),
+                            LLSymbol::Repeat {
+                                count,
+                                ..
+                            } => *count,
+                            LLSymbol::NonTerminal(nonterm) => self.minimal[nonterm.id()].0,
+                            LLSymbol::Binding {
+                                ..
+                            } => 0,
+                        });
+                    }
+
+                    let new_committed = (committed - minimal_len).saturating_add(rule_len);
+
+                    if new_committed > max_len {
+                        idx = minimal_rule;
+                    } else {
+                        committed = new_committed;
+                    }
+
+                    if let Some(walk) = walk.as_mut() {
+                        walk.push((nonterm.id(), idx));
+                    }
+
+                    if let Some(usage) = self.usage.as_mut() {
+                        usage.record(nonterm.id(), idx);
+                    }
+
+                    for symbol in rules[idx].iter().rev() {
                         self.stack.push(symbol.clone());
                     }
                 },
             }
         }
 
-        Ok(generated)
+        Ok((generated, false))
     }
 }
 
+impl Clone for GrammarInterpreter {
+    fn clone(&self) -> Self {
+        Self {
+            grammar: self.grammar.clone(),
+            seed: self.seed,
+            rng: self.rng.as_ref().map(|rng| rng.clone_box()),
+            stack: Vec::with_capacity(4096),
+            minimal: self.minimal.clone(),
+            dynamic_terminals: self.dynamic_terminals.clone(),
+            usage: self.usage.clone(),
+            bindings: Bindings::new(self.grammar.bindings().len()),
+            profile: self.profile,
+        }
+    }
+}
+
+/// Write `count` times `byte` to `stream` without allocating a buffer of that size
+fn write_repeat<S: Write>(stream: &mut S, byte: u8, mut count: usize) -> std::io::Result<()> {
+    let chunk = [byte; 256];
+
+    while count > 0 {
+        let len = count.min(chunk.len());
+        stream.write_all(&chunk[..len])?;
+        count -= len;
+    }
+
+    Ok(())
+}
+
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
     use super::*;
+    use crate::grammar::Builtin;
+
+    /// Whether every element of an input of `xml.json` is closed with the name it was opened with
+    pub(crate) fn tags_match(input: &[u8]) -> bool {
+        let input = std::str::from_utf8(input).unwrap();
+        let mut stack = Vec::new();
+
+        for tag in input.split('<').skip(1) {
+            let (tag, _) = tag.split_once('>').unwrap();
+
+            if let Some(name) = tag.strip_prefix('/') {
+                if stack.pop() != Some(name) {
+                    return false;
+                }
+            } else if !tag.ends_with('/') {
+                stack.push(tag);
+            }
+        }
+
+        stack.is_empty()
+    }
 
     #[test]
     fn test_interpreter() {
@@ -108,4 +707,442 @@ mod tests {
         println!();
         println!("Generated {} bytes", len);
     }
+
+    #[test]
+    fn test_interpret_into() {
+        let cfg = ContextFreeGrammar::builder()
+            .gramatron_grammar("test-data/grammars/gramatron.json")
+            .unwrap()
+            .build()
+            .unwrap();
+        let mut interpreter = GrammarInterpreter::new(&cfg);
+        let mut buf = Vec::new();
+
+        for max_len in [16, 64, 1024] {
+            for _ in 0..200 {
+                let len = interpreter.interpret_into(&mut buf, max_len, None);
+                assert_eq!(len, buf.len());
+                assert!(len <= max_len);
+            }
+        }
+    }
+
+    #[test]
+    fn test_samples() {
+        let cfg = ContextFreeGrammar::builder()
+            .gramatron_grammar("test-data/grammars/gramatron.json")
+            .unwrap()
+            .build()
+            .unwrap();
+        let mut interpreter = GrammarInterpreter::new(&cfg);
+        interpreter.seed(1238);
+        let samples: Vec<Vec<u8>> = interpreter.samples().take(50).collect();
+
+        interpreter.seed(1238);
+
+        for sample in &samples {
+            let mut buf = Vec::new();
+            interpreter.interpret(&mut buf).unwrap();
+            assert_eq!(&buf, sample);
+        }
+
+        assert!(samples.windows(2).any(|pair| pair[0] != pair[1]));
+    }
+
+    #[test]
+    fn test_interpret_bounded() {
+        let cfg =
+            ContextFreeGrammar::builder().peacock_grammar("test-data/grammars/regex.json").unwrap().build().unwrap();
+        let mut interpreter = GrammarInterpreter::new(&cfg);
+        let mut bounded = GrammarInterpreter::new(&cfg);
+        let mut full = Vec::new();
+        let mut truncated_once = false;
+
+        for max_bytes in [0, 1, 7, 64] {
+            for seed in 1..200 {
+                // A cut-off input consumes fewer random numbers
+                interpreter.seed(seed);
+                bounded.seed(seed);
+
+                let mut buf = Vec::new();
+                interpreter.interpret_into(&mut full, usize::MAX, None);
+                let (len, truncated) = bounded.interpret_bounded(&mut buf, max_bytes).unwrap();
+                assert_eq!(len, buf.len());
+                assert_eq!(truncated, full.len() > max_bytes);
+                assert_eq!(buf, full[..len]);
+                truncated_once |= truncated;
+            }
+        }
+
+        assert!(truncated_once);
+    }
+
+    #[test]
+    fn test_repeat_terminals() {
+        let cfg = ContextFreeGrammar::builder()
+            .peacock_grammar_str(r#"{"<ENTRYPOINT>": [["'<'", "@repeat('A', 1000)", "'>'"]]}"#)
+            .unwrap()
+            .build()
+            .unwrap();
+        let mut interpreter = GrammarInterpreter::new(&cfg);
+        let mut expected = vec![b'A'; 1000];
+        expected.insert(0, b'<');
+        expected.push(b'>');
+
+        let mut buf = Vec::new();
+        assert_eq!(interpreter.interpret(&mut buf).unwrap(), expected.len());
+        assert_eq!(buf, expected);
+
+        buf.clear();
+        assert_eq!(interpreter.interpret_bounded(&mut buf, 300).unwrap(), (300, true));
+        assert_eq!(buf, expected[..300]);
+    }
+
+    #[test]
+    fn test_builtins() {
+        let cfg =
+            ContextFreeGrammar::builder().peacock_grammar("test-data/grammars/builtins.json").unwrap().build().unwrap();
+        let ll = LowLevelGrammar::from_high_level_grammar(&cfg);
+        let mut interpreter = GrammarInterpreter::new(&cfg);
+        let mut buf = Vec::new();
+        let mut walk = Vec::new();
+        let mut replayed = Vec::new();
+
+        /* The values of the builtins in a walk, with their distance to the minimum */
+        let values = |walk: &[(usize, usize)]| -> Vec<(Builtin, Vec<u8>, u64)> {
+            walk.iter()
+                .filter_map(|(nonterm, step)| {
+                    let builtin = *ll.builtin(*nonterm)?;
+                    let value = builtin.render(*step as u64);
+                    let offset = match builtin {
+                        Builtin::Int {
+                            min,
+                            ..
+                        } => std::str::from_utf8(&value).unwrap().parse::<i64>().unwrap().wrapping_sub(min) as u64,
+                        Builtin::Bytes {
+                            min,
+                            ..
+                        } => (value.len() - min) as u64,
+                    };
+                    assert!(offset <= builtin.span());
+                    Some((builtin, value, offset))
+                })
+                .collect()
+        };
+        let mut lengths = std::collections::HashSet::new();
+
+        for _ in 0..500 {
+            interpreter.interpret_into(&mut buf, usize::MAX, Some(&mut walk));
+
+            replayed.clear();
+            interpreter.replay(&walk, &mut replayed).unwrap();
+            assert_eq!(replayed, buf);
+
+            for (builtin, value, _) in values(&walk) {
+                if builtin.keyword() == "bytes" {
+                    lengths.insert((builtin, value.len()));
+                }
+            }
+        }
+
+        assert_eq!(lengths.len(), 17 + 3);
+
+        /* A profile narrows the values of new inputs but not of replayed ones */
+        let profile = GenerationProfile::new().length_scale(0.25).value_scale(0.0);
+        let mut scaled = interpreter.clone();
+        scaled.profile(profile);
+        let mut scaled_walk = Vec::new();
+
+        for _ in 0..500 {
+            scaled.interpret_into(&mut buf, usize::MAX, Some(&mut scaled_walk));
+
+            for (builtin, _, offset) in values(&scaled_walk) {
+                assert!(offset <= profile.scaled_span(&builtin), "{}", builtin);
+            }
+        }
+
+        let int = Builtin::Int {
+            min: -1000,
+            max: 1000,
+        };
+        let bytes = Builtin::Bytes {
+            min: 0,
+            max: 16,
+        };
+        assert_eq!(profile.scaled_span(&int), 0);
+        assert_eq!(profile.scaled_span(&bytes), 4);
+
+        /* The upper bits of the step of bytes seed their content and survive the choice */
+        let step = bytes.choose(u64::MAX, bytes.span());
+        assert_eq!(step >> 16, u64::MAX >> 16);
+        assert_eq!(bytes.render(step).len(), (0xFFFF % 17) as usize);
+
+        replayed.clear();
+        buf.clear();
+        scaled.replay(&walk, &mut replayed).unwrap();
+        interpreter.replay(&walk, &mut buf).unwrap();
+        assert_eq!(replayed, buf);
+
+        /* Without room, builtins take their shortest value */
+        for _ in 0..100 {
+            interpreter.interpret_into(&mut buf, 1, None);
+            assert_eq!(buf, b"0");
+        }
+
+        /* Every value of a builtin is a valid step */
+        for (nonterm, step) in &mut walk {
+            if ll.builtin(*nonterm).is_some() {
+                *step = usize::MAX;
+            }
+        }
+
+        interpreter.replay(&walk, &mut buf).unwrap();
+    }
+
+    #[test]
+    fn test_dynamic_terminals() {
+        let cfg =
+            ContextFreeGrammar::builder().peacock_grammar("test-data/grammars/dynamic.json").unwrap().build().unwrap();
+        let mut interpreter = GrammarInterpreter::new(&cfg);
+        let mut buf = Vec::new();
+
+        for _ in 0..100 {
+            interpreter.interpret_into(&mut buf, usize::MAX, None);
+            assert!(buf == b"if x == ;" || buf == b"if x == 0;", "{:?}", String::from_utf8_lossy(&buf));
+        }
+
+        assert!(interpreter.add_dynamic_terminal(b"0xDEADBEEF"));
+        assert!(!interpreter.add_dynamic_terminal(b"0xDEADBEEF"));
+        assert!(!interpreter.add_dynamic_terminal(b""));
+
+        let mut found = false;
+
+        for _ in 0..100 {
+            interpreter.interpret_into(&mut buf, usize::MAX, None);
+            found |= buf == b"if x == 0xDEADBEEF;";
+        }
+
+        assert!(found);
+    }
+
+    #[test]
+    fn test_clone() {
+        let cfg =
+            ContextFreeGrammar::builder().peacock_grammar("test-data/grammars/regex.json").unwrap().build().unwrap();
+        let mut interpreter = GrammarInterpreter::new(&cfg);
+        interpreter.seed(1);
+        let mut clone = interpreter.clone();
+        assert!(Arc::ptr_eq(&interpreter.grammar, &clone.grammar));
+
+        let (mut a, mut b) = (Vec::new(), Vec::new());
+
+        for _ in 0..100 {
+            interpreter.interpret_into(&mut a, 4096, None);
+            clone.interpret_into(&mut b, 4096, None);
+            assert_eq!(a, b);
+        }
+    }
+
+    #[test]
+    fn test_coverage_guided() {
+        let build = || {
+            ContextFreeGrammar::builder()
+                .gramatron_grammar("test-data/grammars/gramatron.json")
+                .unwrap()
+                .optimize(false)
+                .build()
+                .unwrap()
+        };
+        let cfg = build();
+        let mut interpreter = GrammarInterpreter::new(&cfg);
+        let (covered, total) = interpreter.rule_coverage();
+        assert_eq!(covered, 0);
+        assert_eq!(interpreter.uncovered_rules().len(), total);
+
+        /* The numbering of the rules does not change between builds */
+        assert_eq!(interpreter.uncovered_rules(), GrammarInterpreter::new(&build()).uncovered_rules());
+
+        /* <IDENTIFIERLIST> is not reachable from the entrypoint */
+        assert!(!interpreter.uncovered_rules().iter().any(|(nonterm, _)| *nonterm == "IDENTIFIERLIST"));
+
+        interpreter.coverage_guided(true);
+        let mut buf = Vec::new();
+        let mut generations = 0;
+
+        while !interpreter.uncovered_rules().is_empty() {
+            assert!(generations < 1000, "{:?} are still uncovered", interpreter.uncovered_rules());
+            interpreter.interpret_into(&mut buf, 4096, None);
+            generations += 1;
+            assert_eq!(interpreter.rule_coverage().0, total - interpreter.uncovered_rules().len());
+        }
+
+        assert_eq!(interpreter.rule_coverage(), (total, total));
+
+        interpreter.coverage_guided(false);
+        assert_eq!(interpreter.rule_coverage(), (0, total));
+    }
+
+    /// A splitmix64 generator, to check that choices come from an injected RNG
+    #[derive(Clone)]
+    struct SplitMix(u64);
+
+    impl RngCore for SplitMix {
+        fn next_u32(&mut self) -> u32 {
+            self.next_u64() as u32
+        }
+
+        fn next_u64(&mut self) -> u64 {
+            self.0 = self.0.wrapping_add(0x9E3779B97F4A7C15);
+            let mut z = self.0;
+            z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+            z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+            z ^ (z >> 31)
+        }
+
+        fn fill_bytes(&mut self, dest: &mut [u8]) {
+            rand_core::impls::fill_bytes_via_next(self, dest)
+        }
+
+        fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand_core::Error> {
+            self.fill_bytes(dest);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_with_rng() {
+        let cfg =
+            ContextFreeGrammar::builder().peacock_grammar("test-data/grammars/regex.json").unwrap().build().unwrap();
+        let mut interpreter = GrammarInterpreter::new(&cfg).with_rng(SplitMix(1));
+        let mut clone = interpreter.clone();
+        let mut default = GrammarInterpreter::new(&cfg);
+        let samples: Vec<Vec<u8>> = interpreter.samples().take(50).collect();
+
+        assert_eq!(clone.samples().take(50).collect::<Vec<_>>(), samples);
+        assert_eq!(GrammarInterpreter::new(&cfg).with_rng(SplitMix(1)).samples().take(50).collect::<Vec<_>>(), samples);
+        assert_ne!(default.samples().take(50).collect::<Vec<_>>(), samples);
+
+        /* Seeding switches back to the built-in RNG */
+        interpreter.seed(7);
+        default.seed(7);
+        assert_eq!(interpreter.samples().take(50).collect::<Vec<_>>(), default.samples().take(50).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_bindings() {
+        let cfg = ContextFreeGrammar::builder()
+            .peacock_grammar("test-data/grammars/xml.json")
+            .unwrap()
+            .context_sensitive(true)
+            .build()
+            .unwrap();
+        let mut interpreter = GrammarInterpreter::new(&cfg).with_rng(SplitMix(7));
+        let mut replayer = GrammarInterpreter::new(&cfg);
+        let mut walk = Vec::new();
+        let mut nested = 0;
+
+        for _ in 0..1000 {
+            let mut recorded = Vec::new();
+            interpreter.interpret_recorded(&mut recorded, &mut walk).unwrap();
+            assert!(tags_match(&recorded), "{}", String::from_utf8_lossy(&recorded));
+
+            let mut replayed = Vec::new();
+            replayer.replay(&walk, &mut replayed).unwrap();
+            assert_eq!(replayed, recorded);
+
+            if recorded.windows(2).filter(|pair| pair == b"</").count() > 1 {
+                nested += 1;
+            }
+        }
+
+        assert!(nested > 0);
+    }
+
+    #[test]
+    fn test_replay() {
+        let grammar = |json: &str| {
+            ContextFreeGrammar::builder().peacock_grammar_str(json).unwrap().optimize(false).build().unwrap()
+        };
+        let cfg =
+            grammar(r#"{"<ENTRYPOINT>": [["<X>", "<ENTRYPOINT>"], ["<X>"]], "<X>": [["'a'"], ["'b'"], ["'c'"]]}"#);
+        let mut interpreter = GrammarInterpreter::new(&cfg).with_rng(SplitMix(3));
+        let mut replayer = GrammarInterpreter::new(&cfg);
+        let mut walk = Vec::new();
+        let mut walks = Vec::new();
+
+        for _ in 0..100 {
+            let mut recorded = Vec::new();
+            let len = interpreter.interpret_recorded(&mut recorded, &mut walk).unwrap();
+            assert_eq!(len, recorded.len());
+
+            let mut replayed = Vec::new();
+            assert_eq!(replayer.replay(&walk, &mut replayed).unwrap(), len);
+            assert_eq!(replayed, recorded);
+            walks.push((walk.clone(), recorded));
+        }
+
+        /* Walks from interpret_into work as well */
+        let mut buf = Vec::new();
+        let mut replayed = Vec::new();
+        interpreter.interpret_into(&mut buf, usize::MAX, Some(&mut walk));
+        replayer.replay(&walk, &mut replayed).unwrap();
+        assert_eq!(replayed, buf);
+
+        /* <X> lost a rule */
+        let modified = grammar(r#"{"<ENTRYPOINT>": [["<X>", "<ENTRYPOINT>"], ["<X>"]], "<X>": [["'a'"], ["'b'"]]}"#);
+        let mut replayer = GrammarInterpreter::new(&modified);
+        let (walk, _) = walks.iter().find(|(_, bytes)| bytes.contains(&b'c')).unwrap();
+        let err = replayer.replay(walk, &mut Vec::new()).unwrap_err();
+        assert!(err.to_string().contains("rule 2 of <X> but it only has 2 rules"), "{}", err);
+
+        /* The replayer is still usable after an error */
+        let (walk, bytes) = walks.iter().find(|(_, bytes)| !bytes.contains(&b'c')).unwrap();
+        let mut replayed = Vec::new();
+        replayer.replay(walk, &mut replayed).unwrap();
+        assert_eq!(&replayed, bytes);
+
+        /* A new non-terminal changes the ids */
+        let modified = grammar(
+            r#"{"<ENTRYPOINT>": [["<Y>", "<X>", "<ENTRYPOINT>"], ["<X>"]], "<Y>": [["'y'"]], "<X>": [["'a'"], ["'b'"], ["'c'"]]}"#,
+        );
+        let (walk, _) = walks.iter().find(|(walk, _)| walk.len() > 2).unwrap();
+        let err = GrammarInterpreter::new(&modified).replay(walk, &mut Vec::new()).unwrap_err();
+        assert!(matches!(err, ReplayError::WrongNonTerminal { .. }), "{}", err);
+
+        /* Walks that are too short or too long */
+        let mut replayer = GrammarInterpreter::new(&cfg);
+        assert!(matches!(replayer.replay(&walk[..walk.len() - 1], &mut Vec::new()), Err(ReplayError::Incomplete(_))));
+        let mut longer = walk.clone();
+        longer.push(walk[0]);
+        assert!(matches!(replayer.replay(&longer, &mut Vec::new()), Err(ReplayError::TrailingSteps { .. })));
+    }
+
+    #[cfg(all(feature = "components", not(feature = "static-loading")))]
+    #[test]
+    fn test_walk_numbering() {
+        use crate::components::{
+            ffi::generator_serialize,
+            tests::load_test_generator,
+        };
+
+        load_test_generator();
+
+        let cfg = ContextFreeGrammar::builder()
+            .gramatron_grammar("test-data/grammars/gramatron.json")
+            .unwrap()
+            .build()
+            .unwrap();
+        let mut interpreter = GrammarInterpreter::new(&cfg);
+        let mut buf = Vec::new();
+        let mut walk = Vec::new();
+        let mut out = vec![0u8; 1 << 20];
+
+        for _ in 0..100 {
+            interpreter.interpret_into(&mut buf, 4096, Some(&mut walk));
+            let sequence: Vec<usize> = walk.iter().map(|(_, rule)| *rule).collect();
+            let (len, truncated) = generator_serialize(&sequence, out.as_mut_ptr(), out.len());
+            assert!(!truncated);
+            assert_eq!(&out[..len], &buf);
+        }
+    }
 }
@@ -0,0 +1,464 @@
+use itertools::Itertools;
+use std::{
+    cell::{
+        Cell,
+        RefCell,
+    },
+    collections::HashMap,
+    rc::Rc,
+};
+
+use crate::backends::{
+    interpreter::{
+        BindingState,
+        UnparseBindings,
+    },
+    C::{
+        LLSymbol,
+        LowLevelGrammar,
+    },
+};
+
+/// The number of non-terminals that unparsing a single input may expand, like `UNPARSE_BUDGET` in the C backend
+const UNPARSE_BUDGET: usize = 1_000_000;
+
+/// How deep the greedy unparser may descend before it gives up, which also stops left recursion
+const GREEDY_MAX_DEPTH: usize = 1024;
+
+/// Where an input diverged from the grammar: the furthest offset that the unparser reached and
+/// the non-terminal that it tried to match there.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Divergence {
+    pub(crate) offset: usize,
+    pub(crate) nonterm: usize,
+}
+
+/// The symbols that still have to be matched after a non-terminal.
+/// All alternatives of the non-terminal share it.
+struct Continuation<'a> {
+    symbols: &'a [LLSymbol],
+    nonterm: usize,
+    /// Where the expansion of `nonterm` started
+    start: usize,
+    next: Option<Rc<Continuation<'a>>>,
+}
+
+/// A non-terminal whose remaining alternatives have not been tried yet
+struct ChoicePoint<'a> {
+    nonterm: usize,
+    next_rule: usize,
+    cursor: usize,
+    walk_len: usize,
+    bindings: BindingState,
+    cont: Rc<Continuation<'a>>,
+}
+
+/// A pure-Rust version of the unparser of the C backend. It records the chosen rules as pairs of
+/// (non-terminal id, rule id), like the walks of the [`GrammarInterpreter`](super::GrammarInterpreter).
+///
+/// Without dynamic terminals, the dynamic non-terminal matches nothing.
+/// Builtins only match their longest value, the unparsers never backtrack into shorter values.
+/// Uses of bindings match the bytes of the binding on the path that the unparser is currently trying.
+pub(crate) struct Unparser<'a> {
+    grammar: &'a LowLevelGrammar,
+    /// The order in which the greedy unparser tries the alternatives of every non-terminal
+    order: HashMap<usize, Vec<usize>>,
+    budget: Cell<usize>,
+    furthest: Cell<Option<Divergence>>,
+    bindings: RefCell<UnparseBindings>,
+}
+
+impl<'a> Unparser<'a> {
+    pub(crate) fn new(grammar: &'a LowLevelGrammar) -> Self {
+        let terminal_prefix_len = |rule: &[LLSymbol]| -> usize {
+            rule.iter()
+                .map_while(|symbol| match symbol {
+                    LLSymbol::Terminal(term) => Some(grammar.terminals()[term.id()].len()),
+                    LLSymbol::Repeat {
+                        count,
+                        ..
+                    } => Some(*count),
+                    LLSymbol::NonTerminal(_)
+                    | LLSymbol::Binding {
+                        ..
+                    } => None,
+                })
+                .sum()
+        };
+        let order = grammar
+            .rules()
+            .iter()
+            .map(|(nonterm, rules)| {
+                let order = rules
+                    .iter()
+                    .enumerate()
+                    .sorted_by(|(_, a), (_, b)| {
+                        terminal_prefix_len(b).cmp(&terminal_prefix_len(a)).then(b.len().cmp(&a.len()))
+                    })
+                    .map(|(i, _)| i)
+                    .collect();
+                (*nonterm, order)
+            })
+            .collect();
+
+        Self {
+            grammar,
+            order,
+            budget: Cell::new(0),
+            furthest: Cell::new(None),
+            bindings: RefCell::new(UnparseBindings::new(grammar.bindings().len())),
+        }
+    }
+
+    /// Unparse `input` like `unparse_sequence()` of the C backend with thorough unparsing enabled:
+    /// first greedily and then with backtracking if that fails.
+    pub(crate) fn unparse(&self, input: &[u8]) -> Result<Vec<(usize, usize)>, Divergence> {
+        let entrypoint = self.grammar.entrypoint().id();
+        self.furthest.set(None);
+
+        if input.is_empty() {
+            return Err(Divergence {
+                offset: 0,
+                nonterm: entrypoint,
+            });
+        }
+
+        let mut walk = Vec::new();
+        self.budget.set(UNPARSE_BUDGET);
+        self.bindings.borrow_mut().clear();
+
+        match self.greedy_nonterm(entrypoint, input, 0, 0, &mut walk) {
+            Some(cursor) if cursor == input.len() && self.budget.get() > 0 => return Ok(walk),
+            Some(cursor) => self.failed(cursor, entrypoint),
+            None => self.failed(0, entrypoint),
+        }
+
+        self.budget.set(UNPARSE_BUDGET);
+        self.bindings.borrow_mut().clear();
+        self.thorough(input).ok_or_else(|| self.furthest.get().unwrap())
+    }
+
+    /// The walk that the greedy unparser finds for `input` or `None` if it does not consume all of it
+    pub(crate) fn greedy(&self, input: &[u8]) -> Option<Vec<(usize, usize)>> {
+        let mut walk = Vec::new();
+        self.budget.set(UNPARSE_BUDGET);
+        self.bindings.borrow_mut().clear();
+
+        if input.is_empty() {
+            return None;
+        }
+
+        match self.greedy_nonterm(self.grammar.entrypoint().id(), input, 0, 0, &mut walk) {
+            Some(cursor) if cursor == input.len() && self.budget.get() > 0 => Some(walk),
+            _ => None,
+        }
+    }
+
+    fn is_dynamic(&self, nonterm: usize) -> bool {
+        self.grammar.dynamic().is_some_and(|dynamic| dynamic.id() == nonterm)
+    }
+
+    /// Like `UNPARSE_FAILED()` in the C backend
+    fn failed(&self, offset: usize, nonterm: usize) {
+        if self.furthest.get().is_none_or(|furthest| offset > furthest.offset) {
+            self.furthest.set(Some(Divergence {
+                offset,
+                nonterm,
+            }));
+        }
+    }
+
+    /// Whether `symbol` matches `input` at `cursor`. Returns the new cursor.
+    /// Binding markers are applied to the bindings of the current path.
+    fn match_terminal(&self, symbol: &LLSymbol, input: &[u8], cursor: usize) -> Option<usize> {
+        match symbol {
+            LLSymbol::Terminal(term) => {
+                let term = self.grammar.terminals()[term.id()].as_bytes();
+                input[cursor..].starts_with(term).then_some(cursor + term.len())
+            },
+            LLSymbol::Repeat {
+                byte,
+                count,
+            } => (input.len() - cursor >= *count && input[cursor..cursor + count].iter().all(|c| c == byte))
+                .then_some(cursor + count),
+            LLSymbol::Binding {
+                op,
+                slot,
+            } => self.bindings.borrow_mut().apply(*op, *slot, input, cursor),
+            LLSymbol::NonTerminal(_) => unreachable!(),
+        }
+    }
+
+    fn greedy_nonterm(
+        &self,
+        nonterm: usize,
+        input: &[u8],
+        cursor: usize,
+        depth: usize,
+        walk: &mut Vec<(usize, usize)>,
+    ) -> Option<usize> {
+        if self.budget.get() == 0 || depth >= GREEDY_MAX_DEPTH {
+            self.budget.set(0);
+            return None;
+        }
+
+        self.budget.set(self.budget.get() - 1);
+
+        if self.is_dynamic(nonterm) {
+            walk.push((nonterm, 0));
+            return Some(cursor);
+        }
+
+        if let Some(builtin) = self.grammar.builtin(nonterm) {
+            let Some((len, step)) = builtin.unparse(&input[cursor..]) else {
+                self.failed(cursor, nonterm);
+                return None;
+            };
+            walk.push((nonterm, step));
+            return Some(cursor + len);
+        }
+
+        let seq_idx = walk.len();
+        let mut target_cursor = 0;
+        let mut target_walk = Vec::new();
+        let initial_bindings = self.bindings.borrow().save();
+        let mut target_bindings = initial_bindings.clone();
+
+        for &i in &self.order[&nonterm] {
+            walk.truncate(seq_idx);
+            walk.push((nonterm, i));
+            self.bindings.borrow_mut().restore(&initial_bindings, &target_bindings);
+            let mut tmp_cursor = Some(cursor);
+
+            for symbol in &self.grammar.rules()[&nonterm][i] {
+                let Some(cursor) = tmp_cursor else {
+                    break;
+                };
+
+                tmp_cursor = match symbol {
+                    LLSymbol::NonTerminal(child) => self.greedy_nonterm(child.id(), input, cursor, depth + 1, walk),
+                    _ => {
+                        let next = self.match_terminal(symbol, input, cursor);
+
+                        if next.is_none() {
+                            self.failed(cursor, nonterm);
+                        }
+
+                        next
+                    },
+                };
+            }
+
+            /* Like in the C backend, a rule only wins if it gets further than the ones before */
+            if let Some(tmp_cursor) = tmp_cursor {
+                if tmp_cursor > target_cursor {
+                    target_cursor = tmp_cursor;
+                    target_walk = walk[seq_idx..].to_vec();
+                    target_bindings = self.bindings.borrow().save();
+                }
+            }
+        }
+
+        walk.truncate(seq_idx);
+
+        if target_walk.is_empty() {
+            return None;
+        }
+
+        walk.extend(target_walk);
+        self.bindings.borrow_mut().restore(&target_bindings, &target_bindings);
+        Some(target_cursor)
+    }
+
+    /// Whether the expansions in `cont` already contain `nonterm` so often at `cursor` that expanding it
+    /// again cannot lead to a parse. Every round of a left recursion has to consume at least one byte
+    /// to be useful, so at most as many rounds as there are bytes left are tried.
+    fn is_curtailed(cont: &Continuation, nonterm: usize, cursor: usize, remaining: usize) -> bool {
+        let mut count = 0;
+        let mut cont = Some(cont);
+
+        while let Some(current) = cont {
+            if current.nonterm == nonterm && current.start == cursor {
+                count += 1;
+
+                if count > remaining {
+                    return true;
+                }
+            }
+
+            cont = current.next.as_deref();
+        }
+
+        false
+    }
+
+    /// The backtracking unparser. In contrast to the one of the C backend it does not
+    /// recurse, so it works for inputs of any size.
+    fn thorough(&self, input: &[u8]) -> Option<Vec<(usize, usize)>> {
+        let entrypoint = [LLSymbol::NonTerminal(*self.grammar.entrypoint())];
+        let mut walk = Vec::new();
+        let mut choices: Vec<ChoicePoint> = Vec::new();
+        let mut cont = Continuation {
+            symbols: &entrypoint,
+            nonterm: self.grammar.entrypoint().id(),
+            start: 0,
+            next: None,
+        };
+        let mut cursor = 0;
+
+        loop {
+            /* Match terminals until the next non-terminal */
+            loop {
+                let Some((symbol, rest)) = cont.symbols.split_first() else {
+                    match cont.next.take() {
+                        Some(next) => {
+                            cont = Continuation {
+                                symbols: next.symbols,
+                                nonterm: next.nonterm,
+                                start: next.start,
+                                next: next.next.clone(),
+                            };
+                            continue;
+                        },
+                        None if cursor == input.len() => return Some(walk),
+                        None => {
+                            self.failed(cursor, cont.nonterm);
+                            break;
+                        },
+                    }
+                };
+                cont.symbols = rest;
+
+                if let LLSymbol::NonTerminal(child) = symbol {
+                    /* Builtins have a single way to match, so they don't need a choice point */
+                    if let Some(builtin) = self.grammar.builtin(child.id()) {
+                        match builtin.unparse(&input[cursor..]) {
+                            Some((len, step)) => {
+                                walk.push((child.id(), step));
+                                cursor += len;
+                                continue;
+                            },
+                            None => {
+                                self.failed(cursor, child.id());
+                                break;
+                            },
+                        }
+                    }
+
+                    if !Self::is_curtailed(&cont, child.id(), cursor, input.len() - cursor) {
+                        choices.push(ChoicePoint {
+                            nonterm: child.id(),
+                            next_rule: 0,
+                            cursor,
+                            walk_len: walk.len(),
+                            bindings: self.bindings.borrow().save(),
+                            cont: Rc::new(Continuation {
+                                symbols: cont.symbols,
+                                nonterm: cont.nonterm,
+                                start: cont.start,
+                                next: cont.next.take(),
+                            }),
+                        });
+                    }
+                    break;
+                }
+
+                match self.match_terminal(symbol, input, cursor) {
+                    Some(next) => cursor = next,
+                    None => {
+                        self.failed(cursor, cont.nonterm);
+                        break;
+                    },
+                }
+            }
+
+            /* Continue with the next untried alternative */
+            loop {
+                let choice = choices.last_mut()?;
+                let rules = &self.grammar.rules()[&choice.nonterm];
+
+                if choice.next_rule >= rules.len() || self.budget.get() == 0 {
+                    choices.pop();
+                    continue;
+                }
+
+                self.budget.set(self.budget.get() - 1);
+
+                let i = choice.next_rule;
+                choice.next_rule += 1;
+                walk.truncate(choice.walk_len);
+                walk.push((choice.nonterm, i));
+                self.bindings.borrow_mut().restore(&choice.bindings, &choice.bindings);
+                cursor = choice.cursor;
+                cont = Continuation {
+                    symbols: &rules[i],
+                    nonterm: choice.nonterm,
+                    start: choice.cursor,
+                    next: Some(choice.cont.clone()),
+                };
+                break;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        backends::interpreter::GrammarInterpreter,
+        grammar::{
+            tests::grammar,
+            ContextFreeGrammar,
+        },
+    };
+
+    #[test]
+    fn test_unparse() {
+        /* The greedy unparser takes '=' for <OP> and fails, backtracking takes '==' */
+        let ll = LowLevelGrammar::from_high_level_grammar(&grammar(r#"{"<ENTRYPOINT>": [["<OP>", "'=x'"]], "<OP>": [["'='"], ["'=='"]]}"#));
+        let unparser = Unparser::new(&ll);
+        assert!(unparser.greedy(b"==x").is_none());
+        let walk = unparser.unparse(b"==x").unwrap();
+        assert_eq!(walk.iter().map(|(_, rule)| *rule).collect::<Vec<_>>(), vec![0, 0]);
+        let walk = unparser.unparse(b"===x").unwrap();
+        assert_eq!(walk.iter().map(|(_, rule)| *rule).collect::<Vec<_>>(), vec![0, 1]);
+
+        let failure = unparser.unparse(b"==y").unwrap_err();
+        assert_eq!(failure.offset, 2);
+        assert_eq!(ll.nonterminals()[failure.nonterm], "ENTRYPOINT");
+        assert_eq!(unparser.unparse(b"").unwrap_err().offset, 0);
+
+        /* Left recursion */
+        let ll = LowLevelGrammar::from_high_level_grammar(&grammar(r#"{"<ENTRYPOINT>": [["<E>"]], "<E>": [["<E>", "'+'", "<E>"], ["'1'"]]}"#));
+        let unparser = Unparser::new(&ll);
+        assert_eq!(unparser.unparse(b"1+1+1").unwrap().len(), 6);
+        assert_eq!(unparser.unparse(b"1+1+").unwrap_err().offset, 4);
+    }
+
+    #[test]
+    fn test_unparse_bindings() {
+        let cfg = ContextFreeGrammar::builder()
+            .peacock_grammar("test-data/grammars/xml.json")
+            .unwrap()
+            .context_sensitive(true)
+            .build()
+            .unwrap();
+        let ll = LowLevelGrammar::from_high_level_grammar(&cfg);
+        let unparser = Unparser::new(&ll);
+
+        let mut interpreter = GrammarInterpreter::new(&cfg);
+
+        for input in [&b"<a></a>"[..], b"<div><a>text</a><xb/><span></span></div>", b"<a><a><a></a></a></a>"] {
+            assert!(unparser.greedy(input).is_some(), "{}", String::from_utf8_lossy(input));
+
+            let mut replayed = Vec::new();
+            interpreter.replay(&unparser.unparse(input).unwrap(), &mut replayed).unwrap();
+            assert_eq!(replayed, input);
+        }
+
+        for input in [&b"<a></b>"[..], b"<div><a></div></a>", b"<a><a></a></xa>", b"<a></>"] {
+            assert!(unparser.unparse(input).is_err(), "{}", String::from_utf8_lossy(input));
+        }
+    }
+}
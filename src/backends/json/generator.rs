@@ -1,6 +1,6 @@
+use indexmap::IndexMap;
 use serde::ser::Serialize;
 use serde_json::{
-    json,
     ser::PrettyFormatter,
     Serializer,
     Value,
@@ -11,9 +11,21 @@ use std::{
     path::Path,
 };
 
-use crate::grammar::{
-    ContextFreeGrammar,
-    Symbol,
+use crate::{
+    backends::Backend,
+    error::{
+        BackendError,
+        ParsingError,
+    },
+    grammar::{
+        ContextFreeGrammar,
+        Symbol,
+        DYNAMIC_NONTERMINAL,
+    },
+    parser::peacock::{
+        parse_metadata,
+        META_KEY,
+    },
 };
 
 fn enclosed_in(s: &str, start: char, end: char) -> bool {
@@ -21,7 +33,7 @@ fn enclosed_in(s: &str, start: char, end: char) -> bool {
 }
 
 fn terminal_string(content: &str) -> String {
-    if enclosed_in(content, '<', '>') || enclosed_in(content, '\'', '\'') {
+    if enclosed_in(content, '<', '>') || enclosed_in(content, '\'', '\'') || content.starts_with('@') {
         return format!("'{}'", content);
     }
 
@@ -29,52 +41,147 @@ fn terminal_string(content: &str) -> String {
 }
 
 /// This is the main struct of the [`json`](crate::backends::json) backend that does all the heavy lifting and generates the grammar.
-pub struct JsonGenerator {}
+pub struct JsonGenerator {
+    metadata: Option<Value>,
+    header: Vec<String>,
+}
 
 impl JsonGenerator {
     /// Create a new JsonGenerator.
     #[allow(clippy::new_without_default)]
     pub fn new() -> Self {
-        Self {}
+        Self {
+            metadata: None,
+            header: Vec::new(),
+        }
+    }
+
+    /// Write `metadata` as the top-level `"$meta"` object of the grammar, e.g.
+    /// `{"name": "http", "version": "1.0.0", "description": "..."}`.
+    /// The peacock parser ignores it, so it survives loading and emitting a grammar
+    /// if it is passed on with [`read_metadata()`](JsonGenerator::read_metadata).
+    ///
+    /// # Panics
+    /// If `metadata` is not a JSON object.
+    pub fn with_metadata(mut self, metadata: Value) -> Self {
+        assert!(metadata.is_object(), "Metadata must be a JSON object");
+        self.metadata = Some(metadata);
+        self
+    }
+
+    /// Write `comment` as a `//` comment before the grammar, e.g. where the grammar came from.
+    /// Multi-line comments get one `//` per line. Can be called multiple times.
+    pub fn with_header<S: AsRef<str>>(mut self, comment: S) -> Self {
+        self.header.extend(comment.as_ref().lines().map(|line| line.to_string()));
+        self
+    }
+
+    /// Read the `"$meta"` object of the peacock grammar in `path`, if it has one.
+    pub fn read_metadata<P: AsRef<Path>>(path: P) -> Result<Option<Value>, ParsingError> {
+        parse_metadata(path.as_ref())
     }
 
     /// Write the production rules of the supplied `grammar` into the output file `path` in peacock format.
+    ///
+    /// The non-terminals appear in the order in which they were first defined and their alternatives
+    /// in the order of the rules, such that the same grammar always results in the same file.
+    /// The header comes first, followed by the metadata.
+    ///
+    /// # Panics
+    /// If the file cannot be written. Use the [`Backend`] implementation to get an error instead.
     pub fn generate<P: AsRef<Path>>(self, path: P, grammar: &ContextFreeGrammar) {
-        let mut json = json!({});
-        let object = json.as_object_mut().unwrap();
-
-        for rule in grammar.rules() {
-            let array = object.entry(format!("<{}>", rule.lhs().id())).or_insert_with(|| Value::Array(Vec::new()));
-            let array = array.as_array_mut().unwrap();
-
-            let mut insert = Vec::new();
-            for symbol in rule.rhs() {
-                match symbol {
-                    Symbol::Terminal(term) => {
-                        insert.push(Value::String(terminal_string(term.content())));
-                    },
-                    Symbol::NonTerminal(nonterm) => {
-                        insert.push(Value::String(format!("<{}>", nonterm.id())));
-                    },
+        self.write_grammar(path.as_ref(), grammar).unwrap_or_else(|err| panic!("{}", err));
+    }
+
+    fn write_grammar(&self, path: &Path, grammar: &ContextFreeGrammar) -> std::io::Result<()> {
+        let mut json: IndexMap<String, Value> = IndexMap::new();
+
+        if let Some(metadata) = &self.metadata {
+            json.insert(META_KEY.to_string(), metadata.clone());
+        }
+
+        for (nonterm, rules) in grammar.rules_by_nonterminal() {
+            let mut array = Vec::with_capacity(rules.len());
+
+            /* The placeholder of <DYNAMIC> is added again when the grammar is loaded */
+            let rules: Vec<_> = rules
+                .into_iter()
+                .filter(|rule| {
+                    !(nonterm.id() == DYNAMIC_NONTERMINAL && matches!(rule.rhs(), [symbol] if symbol.is_dynamic()))
+                })
+                .collect();
+
+            if rules.is_empty() {
+                continue;
+            }
+
+            for rule in rules {
+                let mut insert = Vec::new();
+
+                /* Ɛ is written as an empty variant */
+                if rule.rhs().len() == 1 && rule.rhs()[0].is_epsilon() {
+                    array.push(Value::Array(insert));
+                    continue;
+                }
+
+                for symbol in rule.rhs() {
+                    match symbol {
+                        Symbol::Terminal(term) if term.is_dynamic() => {
+                            insert.push(Value::String(format!("<{}>", DYNAMIC_NONTERMINAL)));
+                        },
+                        Symbol::Terminal(term) => match (term.run(), term.binding(), term.builtin()) {
+                            (Some((byte, count)), _, _) => {
+                                insert.push(Value::String(format!("@repeat('{}', {})", byte as char, count)));
+                            },
+                            (_, Some((op, name)), _) => {
+                                insert.push(Value::String(format!("@{}({})", op.keyword(), name)));
+                            },
+                            (_, _, Some(builtin)) => insert.push(Value::String(builtin.to_string())),
+                            _ => insert.push(Value::String(terminal_string(&term.expanded()))),
+                        },
+                        Symbol::NonTerminal(nonterm) => {
+                            insert.push(Value::String(format!("<{}>", nonterm.id())));
+                        },
+                    }
                 }
+
+                array.push(Value::Array(insert));
             }
 
-            array.push(Value::Array(insert));
+            json.insert(format!("<{}>", nonterm.id()), Value::Array(array));
         }
 
         let mut buf = Vec::new();
+
+        for line in &self.header {
+            writeln!(buf, "// {}", line)?;
+        }
+
         let formatter = PrettyFormatter::with_indent(b"    ");
         let mut ser = Serializer::with_formatter(&mut buf, formatter);
         json.serialize(&mut ser).unwrap();
 
-        let mut file = File::create(path).expect("Could not open output file");
-        file.write_all(&buf).expect("Could not write to output file");
+        let mut file = File::create(path)?;
+        file.write_all(&buf)
+    }
+}
+
+impl Backend for JsonGenerator {
+    fn generate(self, output: &Path, grammar: &ContextFreeGrammar) -> Result<(), BackendError> {
+        self.write_grammar(output, grammar)?;
+        Ok(())
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::grammar::{
+        NonTerminal,
+        ProductionRule,
+        Terminal,
+    };
+    use serde_json::json;
 
     #[test]
     fn test_generator() {
@@ -88,4 +195,133 @@ mod tests {
 
         ContextFreeGrammar::builder().peacock_grammar("/tmp/new.json").unwrap().build().unwrap();
     }
+
+    #[test]
+    fn test_epsilon() {
+        let cfg = ContextFreeGrammar::builder()
+            .peacock_grammar_str(r#"{"<ENTRYPOINT>": [["'a'", "<OPT>"]], "<OPT>": [["'b'"], [""]]}"#)
+            .unwrap()
+            .optimize(false)
+            .build()
+            .unwrap();
+        let path = std::env::temp_dir().join(format!("peacock-epsilon-{}.json", std::process::id()));
+        JsonGenerator::new().generate(&path, &cfg);
+
+        let json: Value = serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(json["<OPT>"], json!([["b"], []]));
+
+        let reloaded = ContextFreeGrammar::builder().peacock_grammar(&path).unwrap().optimize(false).build().unwrap();
+        assert_eq!(reloaded.rules(), cfg.rules());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_dynamic() {
+        for optimize in [false, true] {
+            let cfg = ContextFreeGrammar::builder()
+                .peacock_grammar("test-data/grammars/dynamic.json")
+                .unwrap()
+                .optimize(optimize)
+                .build()
+                .unwrap();
+            let path = std::env::temp_dir().join(format!("peacock-dynamic-{}-{}.json", optimize, std::process::id()));
+            JsonGenerator::new().generate(&path, &cfg);
+
+            let json: Value = serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+            assert!(json.get("<DYNAMIC>").is_none());
+
+            let reloaded =
+                ContextFreeGrammar::builder().peacock_grammar(&path).unwrap().optimize(optimize).build().unwrap();
+            assert_eq!(reloaded.semantic_hash(), cfg.semantic_hash());
+            std::fs::remove_file(&path).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_metadata() {
+        let metadata = json!({"name": "numbers", "version": "1.2.0", "description": "Lists of numbers"});
+        let cfg = ContextFreeGrammar::builder()
+            .peacock_grammar_str(r#"{"<ENTRYPOINT>": [["<LIST>"]], "<LIST>": [["'1'"], ["'1'", "','", "<LIST>"]]}"#)
+            .unwrap()
+            .optimize(false)
+            .build()
+            .unwrap();
+        let path = std::env::temp_dir().join(format!("peacock-metadata-{}.json", std::process::id()));
+        JsonGenerator::new()
+            .with_header("Generated from numbers.json\noptimize: true")
+            .with_metadata(metadata.clone())
+            .generate(&path, &cfg);
+
+        let output = std::fs::read_to_string(&path).unwrap();
+        let header = "// Generated from numbers.json\n// optimize: true\n{\n    \"$meta\": {";
+        assert!(output.starts_with(header), "{}", output);
+
+        /* The metadata survives loading and emitting the grammar again */
+        let reloaded = ContextFreeGrammar::builder().peacock_grammar(&path).unwrap().optimize(false).build().unwrap();
+        assert_eq!(reloaded.rules(), cfg.rules());
+        assert_eq!(JsonGenerator::read_metadata(&path).unwrap(), Some(metadata.clone()));
+
+        let reread = JsonGenerator::read_metadata(&path).unwrap().unwrap();
+        JsonGenerator::new().with_metadata(reread).generate(&path, &reloaded);
+        let json: Value = serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(json["$meta"], metadata);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_invalid_metadata() {
+        JsonGenerator::new().with_metadata(json!("not an object"));
+    }
+
+    #[test]
+    fn test_deterministic_output() {
+        let grammar = r#"{"<ENTRYPOINT>": [["<B>", "<A>"]], "<B>": [["'b'"], ["<A>"]], "<A>": [["'a'"]]}"#;
+        let build = || {
+            ContextFreeGrammar::builder()
+                .peacock_grammar_str(grammar)
+                .unwrap()
+                /* A late alternative of an earlier non-terminal stays with the other alternatives */
+                .transform(|rules| {
+                    rules.push(ProductionRule::new(NonTerminal::new("B"), vec![Symbol::Terminal(Terminal::new("c"))]))
+                })
+                .optimize(false)
+                .build()
+                .unwrap()
+        };
+        let dir = std::env::temp_dir();
+        let first = dir.join(format!("peacock-order-1-{}.json", std::process::id()));
+        let second = dir.join(format!("peacock-order-2-{}.json", std::process::id()));
+        JsonGenerator::new().generate(&first, &build());
+        JsonGenerator::new().generate(&second, &build());
+
+        let output = std::fs::read_to_string(&first).unwrap();
+        assert_eq!(output, std::fs::read_to_string(&second).unwrap());
+
+        let positions: Vec<usize> =
+            ["<ENTRYPOINT>", "<B>", "<A>"].iter().map(|key| output.find(&format!("\"{}\":", key)).unwrap()).collect();
+        assert!(positions.windows(2).all(|pair| pair[0] < pair[1]), "{}", output);
+
+        let json: Value = serde_json::from_str(&output).unwrap();
+        assert_eq!(json["<B>"], json!([["b"], ["<A>"], ["c"]]));
+
+        std::fs::remove_file(&first).unwrap();
+        std::fs::remove_file(&second).unwrap();
+
+        /* The optimized grammars are also written identically */
+        let cfg = || {
+            ContextFreeGrammar::builder()
+                .gramatron_grammar("test-data/grammars/gramatron.json")
+                .unwrap()
+                .build()
+                .unwrap()
+        };
+        JsonGenerator::new().generate(&first, &cfg());
+        JsonGenerator::new().generate(&second, &cfg());
+        assert_eq!(std::fs::read(&first).unwrap(), std::fs::read(&second).unwrap());
+
+        std::fs::remove_file(&first).unwrap();
+        std::fs::remove_file(&second).unwrap();
+    }
 }
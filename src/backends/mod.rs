@@ -1,4 +1,31 @@
 //! This module has all the available backends.
+//!
+//! Backends outside of this crate implement [`Backend`] and usually start by turning the grammar into a
+//! [`LowLevelGrammar`](C::LowLevelGrammar), where all symbols are numbered, or by iterating over
+//! [`ContextFreeGrammar::rules_by_nonterminal()`].
+//!
+//! ```
+//! # use peacock_fuzz::{backends::{Backend, C::{LLSymbol, LowLevelGrammar}}, error::BackendError, grammar::ContextFreeGrammar};
+//! # use std::path::Path;
+//! /// Writes the number of rules of every non-terminal
+//! struct RuleCounter;
+//!
+//! impl Backend for RuleCounter {
+//!     fn generate(self, output: &Path, grammar: &ContextFreeGrammar) -> Result<(), BackendError> {
+//!         let grammar = LowLevelGrammar::try_from_high_level_grammar(grammar)?;
+//!         let mut lines = String::new();
+//!
+//!         for (nonterm, name) in grammar.nonterminals().iter().enumerate() {
+//!             let rules = grammar.rules_of(nonterm);
+//!             let terminals = rules.iter().flatten().filter(|symbol| matches!(symbol, LLSymbol::Terminal(_))).count();
+//!             lines.push_str(&format!("{}: {} rules, {} terminals\n", name, rules.len(), terminals));
+//!         }
+//!
+//!         std::fs::write(output, lines)?;
+//!         Ok(())
+//!     }
+//! }
+//! ```
 
 #[allow(non_snake_case)]
 pub mod C;
@@ -6,3 +33,74 @@ pub mod C;
 pub mod json;
 
 pub mod interpreter;
+
+mod profile;
+pub use profile::GenerationProfile;
+
+use std::path::Path;
+
+use crate::{
+    error::BackendError,
+    grammar::ContextFreeGrammar,
+};
+
+/// A backend turns a grammar into a file.
+///
+/// The backends of this crate also have an inherent `generate()` method with more options that panics on errors.
+pub trait Backend {
+    /// Generate the output for `grammar` and write it to `output`.
+    fn generate(self, output: &Path, grammar: &ContextFreeGrammar) -> Result<(), BackendError>;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        backends::{
+            json::JsonGenerator,
+            C::CGenerator,
+        },
+        error::GrammarError,
+        grammar::{
+            NonTerminal,
+            ProductionRule,
+            Symbol,
+        },
+    };
+
+    fn generate<B: Backend>(backend: B, output: &Path, grammar: &ContextFreeGrammar) -> Result<(), BackendError> {
+        backend.generate(output, grammar)
+    }
+
+    #[test]
+    fn test_backends() {
+        let grammar = ContextFreeGrammar::builder()
+            .peacock_grammar_str(r#"{"<ENTRYPOINT>": [["'a'", "<ENTRYPOINT>"], ["'b'"]]}"#)
+            .unwrap()
+            .build()
+            .unwrap();
+        let dir = std::env::temp_dir().join(format!("peacock-backends-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+
+        generate(CGenerator::new(), &dir.join("out.c"), &grammar).unwrap();
+        assert!(dir.join("out.h").exists());
+        generate(JsonGenerator::new(), &dir.join("out.json"), &grammar).unwrap();
+        let parsed = ContextFreeGrammar::builder().peacock_grammar(dir.join("out.json")).unwrap().build().unwrap();
+        assert_eq!(parsed.semantic_hash(), grammar.semantic_hash());
+
+        /* Errors are returned instead of panicking */
+        let missing = dir.join("missing").join("out");
+        assert!(matches!(generate(CGenerator::new(), &missing, &grammar), Err(BackendError::Io(_))));
+        assert!(matches!(generate(JsonGenerator::new(), &missing, &grammar), Err(BackendError::Io(_))));
+
+        let incomplete = ContextFreeGrammar::new(
+            vec![ProductionRule::new(NonTerminal::new("S"), vec![Symbol::NonTerminal(NonTerminal::new("A"))])],
+            NonTerminal::new("S"),
+        );
+        let err = generate(CGenerator::new(), &dir.join("incomplete.c"), &incomplete).unwrap_err();
+        assert!(matches!(err, BackendError::Grammar(GrammarError::MissingNonTerminal(_))), "{}", err);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
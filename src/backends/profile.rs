@@ -0,0 +1,72 @@
+use crate::grammar::Builtin;
+
+/// How much of the ranges of builtin terminals like `@int(0, 1000)` and `@bytes(0, 4096)` the backends use
+/// when they generate new inputs.
+///
+/// A profile scales the part of every range above its minimum, e.g. a length scale of `0.25` turns
+/// `@bytes(0, 4096)` into `@bytes(0, 1024)` for new inputs. This keeps inputs small for slow targets
+/// without editing the grammar. Serialization and unparsing still use the whole range, so walks
+/// stay valid under every profile.
+///
+/// The [`GrammarInterpreter`](crate::backends::interpreter::GrammarInterpreter) applies a profile at runtime,
+/// the [`CGenerator`](crate::backends::C::CGenerator) bakes the scaled bounds into the generated code.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct GenerationProfile {
+    lengths: f64,
+    values: f64,
+}
+
+impl Default for GenerationProfile {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl GenerationProfile {
+    /// A profile that uses the whole range of every builtin.
+    pub fn new() -> Self {
+        Self {
+            lengths: 1.0,
+            values: 1.0,
+        }
+    }
+
+    /// Scale the range of lengths of `@bytes` builtins by a factor between 0 and 1.
+    /// With a factor of 0, they always have their minimum length.
+    ///
+    /// Default: `1.0`
+    pub fn length_scale(mut self, scale: f64) -> Self {
+        assert!((0.0..=1.0).contains(&scale), "Invalid length scale");
+        self.lengths = scale;
+        self
+    }
+
+    /// Scale the range of values of `@int` builtins by a factor between 0 and 1.
+    /// With a factor of 0, they are always their minimum.
+    ///
+    /// Default: `1.0`
+    pub fn value_scale(mut self, scale: f64) -> Self {
+        assert!((0.0..=1.0).contains(&scale), "Invalid value scale");
+        self.values = scale;
+        self
+    }
+
+    /// How far above its minimum `builtin` may be chosen under this profile.
+    pub fn scaled_span(&self, builtin: &Builtin) -> u64 {
+        let scale = match builtin {
+            Builtin::Int {
+                ..
+            } => self.values,
+            Builtin::Bytes {
+                ..
+            } => self.lengths,
+        };
+        let span = builtin.span();
+
+        if scale == 1.0 {
+            span
+        } else {
+            ((span as f64 * scale).round() as u64).min(span)
+        }
+    }
+}
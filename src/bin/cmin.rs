@@ -0,0 +1,329 @@
+use clap::Parser;
+use libafl::prelude::{
+    ConstFeedback,
+    Error,
+    ExitKind,
+    HasObservers,
+    HitcountsMapObserver,
+    InMemoryCorpus,
+    Input,
+    MapObserver,
+    NopEventManager,
+    QueueScheduler,
+    StdFuzzer,
+    StdMapObserver,
+    StdState,
+};
+use libafl_bolts::prelude::{
+    tuple_list,
+    AsSliceMut,
+    StdRand,
+};
+use peacock_fuzz::components::{
+    load_dynamic_terminals,
+    load_generator,
+    PeacockInput,
+};
+use std::{
+    collections::HashSet,
+    path::{
+        Path,
+        PathBuf,
+    },
+};
+
+pub mod fuzz;
+use fuzz::{
+    coverage_map,
+    InputDelivery,
+    TargetOptions,
+};
+
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+struct Args {
+    /// The generator.so that the inputs of the corpus belong to
+    #[arg(short, long)]
+    generator: String,
+
+    /// Directory with the inputs to minimize
+    #[arg(short, long)]
+    corpus: String,
+
+    /// Directory that receives the minimized corpus
+    #[arg(short, long)]
+    output: String,
+
+    /// The dynamic-terminals-N.dict file from the output directory of peacock-fuzz.
+    /// Without it, inputs that contain terminals of <DYNAMIC> are not serialized like during fuzzing
+    #[arg(long, value_name = "FILE")]
+    dynamic_terminals: Option<String>,
+
+    #[command(flatten)]
+    target: TargetOptions,
+
+    #[arg(trailing_var_arg = true, allow_hyphen_values = true, required = true)]
+    cmdline: Vec<String>,
+}
+
+/// An input of the corpus and the coverage that it achieved in the target
+#[derive(Debug)]
+struct Entry {
+    path: PathBuf,
+    sequence_len: usize,
+    /// Pairs of (map index, hitcount bucket)
+    features: HashSet<(usize, u8)>,
+}
+
+/// Execute every input in the corpus directory and record its coverage.
+/// Inputs that cannot be loaded are skipped with a warning.
+fn collect_coverage(args: &Args) -> Result<Vec<Entry>, Error> {
+    let map_size = args.target.map_size();
+    let mut shmem = coverage_map(map_size)?;
+    let edges_observer = unsafe { HitcountsMapObserver::new(StdMapObserver::new("shared_mem", shmem.as_slice_mut())) };
+
+    let mut feedback = ConstFeedback::new(false);
+    let mut objective = ConstFeedback::new(false);
+    let mut state = StdState::new(
+        StdRand::with_seed(0),
+        InMemoryCorpus::<PeacockInput>::new(),
+        InMemoryCorpus::new(),
+        &mut feedback,
+        &mut objective,
+    )?;
+    let mut fuzzer = StdFuzzer::new(QueueScheduler::new(), feedback, objective);
+    let mut mgr = NopEventManager::new();
+
+    let delivery = InputDelivery::new(&args.target, &args.cmdline, 0).map_err(Error::illegal_argument)?;
+    let mut executor = delivery.forkserver(map_size, edges_observer, tuple_list!())?;
+
+    let mut paths = Vec::new();
+
+    for entry in std::fs::read_dir(&args.corpus)? {
+        let path = entry?.path();
+
+        if path.is_file() {
+            paths.push(path);
+        }
+    }
+
+    /* Make the selection independent of the order of the directory entries */
+    paths.sort();
+
+    let mut entries = Vec::with_capacity(paths.len());
+
+    for path in paths {
+        let input = match PeacockInput::from_file(&path) {
+            Ok(input) => input,
+            Err(err) => {
+                eprintln!("Warning: Skipping {}: {}", path.display(), err);
+                continue;
+            },
+        };
+
+        let exit_kind = fuzzer.execute_input(&mut state, &mut executor, &mut mgr, &input)?;
+
+        if exit_kind != ExitKind::Ok {
+            eprintln!("Warning: {} resulted in {:?}", path.display(), exit_kind);
+        }
+
+        let observer = &executor.observers().0;
+        let features = (0..observer.usable_count())
+            .filter_map(|idx| {
+                let hits = observer.get(idx);
+                (hits != 0).then_some((idx, hits))
+            })
+            .collect();
+
+        entries.push(Entry {
+            path,
+            sequence_len: input.sequence().len(),
+            features,
+        });
+    }
+
+    Ok(entries)
+}
+
+/// Greedy set cover: Repeatedly pick the entry that covers the most features that the previously picked
+/// entries did not cover until the union of all features is covered. Ties are broken in favor of shorter
+/// sequences. Returns the indices of the picked entries.
+fn minimize(entries: &[Entry]) -> Vec<usize> {
+    let mut covered = HashSet::new();
+    let mut picked = Vec::new();
+
+    loop {
+        let best = entries
+            .iter()
+            .enumerate()
+            .map(|(idx, entry)| (entry.features.difference(&covered).count(), idx))
+            .filter(|(new, _)| *new > 0)
+            .max_by(|(new_a, a), (new_b, b)| {
+                new_a.cmp(new_b).then(entries[*b].sequence_len.cmp(&entries[*a].sequence_len)).then(b.cmp(a))
+            });
+
+        let Some((_, idx)) = best else {
+            break;
+        };
+
+        covered.extend(entries[idx].features.iter().copied());
+        picked.push(idx);
+    }
+
+    picked
+}
+
+/// Copy the picked inputs into the output directory
+fn copy_entries(entries: &[Entry], picked: &[usize], output: &Path) {
+    std::fs::create_dir_all(output).expect("Could not create output directory");
+
+    for idx in picked {
+        let path = &entries[*idx].path;
+        std::fs::copy(path, output.join(path.file_name().unwrap())).expect("Could not copy input");
+    }
+}
+
+fn run(args: &Args) -> usize {
+    load_generator(&args.generator);
+    if let Some(path) = &args.dynamic_terminals {
+        load_dynamic_terminals(path).expect("Could not load dynamic terminals");
+    }
+    args.target.install_postprocessor();
+
+    let entries = collect_coverage(args).expect("Could not execute corpus");
+    let picked = minimize(&entries);
+    copy_entries(&entries, &picked, Path::new(&args.output));
+
+    picked.len()
+}
+
+fn main() {
+    let args = Args::parse();
+    let kept = run(&args);
+    eprintln!("Kept {} inputs", kept);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use peacock_fuzz::{
+        backends::C::{
+            compile,
+            CGenerator,
+            CompileOptions,
+        },
+        grammar::ContextFreeGrammar,
+    };
+
+    /// A target that speaks the forkserver protocol and covers one map entry for every distinct byte of its input
+    const TARGET: &str = r#"
+#include <stdint.h>
+#include <stdlib.h>
+#include <unistd.h>
+#include <sys/shm.h>
+#include <sys/wait.h>
+
+int main (void) {
+    uint8_t* map = shmat(atoi(getenv("__AFL_SHM_ID")), NULL, 0);
+    uint32_t msg = 0x41464c01;
+
+    if (write(199, &msg, 4) != 4 || read(198, &msg, 4) != 4) {
+        return 1;
+    }
+
+    msg = 0;
+    write(199, &msg, 4);
+    msg = 0x41464c01;
+    write(199, &msg, 4);
+
+    while (read(198, &msg, 4) == 4) {
+        pid_t pid = fork();
+
+        if (!pid) {
+            unsigned char buf[256];
+            ssize_t len = read(0, buf, sizeof(buf));
+
+            for (ssize_t i = 0; i < len; ++i) {
+                map[buf[i]] = 1;
+            }
+
+            return 0;
+        }
+
+        int status = 0;
+        write(199, &pid, 4);
+        waitpid(pid, &status, 0);
+        write(199, &status, 4);
+    }
+
+    return 0;
+}
+"#;
+
+    fn entry(name: &str, sequence_len: usize, features: &[usize]) -> Entry {
+        Entry {
+            path: PathBuf::from(name),
+            sequence_len,
+            features: features.iter().map(|idx| (*idx, 1)).collect(),
+        }
+    }
+
+    #[test]
+    fn test_minimize() {
+        let entries = [
+            entry("a", 5, &[1, 2]),
+            entry("b", 3, &[1, 2]),
+            entry("c", 1, &[3]),
+            entry("d", 9, &[1, 2, 3]),
+            entry("e", 1, &[]),
+        ];
+        assert_eq!(minimize(&entries), vec![3]);
+        assert_eq!(minimize(&entries[..3]), vec![1, 2]);
+        assert_eq!(minimize(&entries[4..]), Vec::<usize>::new());
+    }
+
+    #[test]
+    fn test_cmin() {
+        let dir = std::env::temp_dir().join(format!("peacock-cmin-{}", std::process::id()));
+        let corpus = dir.join("corpus");
+        let output = dir.join("output");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&corpus).unwrap();
+
+        let grammar = dir.join("grammar.json");
+        std::fs::write(
+            &grammar,
+            r#"{"<ENTRYPOINT>": [["<CHAR>", "<ENTRYPOINT>"], ["<CHAR>"]], "<CHAR>": [["'a'"], ["'b'"], ["'c'"]]}"#,
+        )
+        .unwrap();
+        let cfg = ContextFreeGrammar::builder().peacock_grammar(&grammar).unwrap().build().unwrap();
+        let generator = dir.join("generator.so");
+        let sources = CGenerator::new().generate(dir.join("generator.c"), &cfg);
+        compile(&generator, &sources, &CompileOptions::new()).unwrap();
+
+        let target = dir.join("target");
+        std::fs::write(dir.join("target.c"), TARGET).unwrap();
+        compile(&target, &[dir.join("target.c")], &CompileOptions::new().flags(["-O0"])).unwrap();
+
+        for (name, content) in [("1", "ab"), ("2", "ba"), ("3", "c"), ("4", "bca"), ("5", "aabbcc"), ("6", "xyz")] {
+            std::fs::write(corpus.join(name), content).unwrap();
+        }
+
+        let args = Args::parse_from([
+            "peacock-cmin",
+            "--generator",
+            &generator.to_string_lossy(),
+            "--corpus",
+            &corpus.to_string_lossy(),
+            "--output",
+            &output.to_string_lossy(),
+            "--",
+            &target.to_string_lossy(),
+        ]);
+
+        /* "xyz" is not part of the language and "bca" is the shortest input that covers a, b and c */
+        assert_eq!(run(&args), 1);
+        assert_eq!(std::fs::read_to_string(output.join("4")).unwrap(), "bca");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
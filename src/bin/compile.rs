@@ -5,39 +5,105 @@ use peacock_fuzz::{
 };
 
 pub mod fuzz;
-use fuzz::GrammarFormat;
+use fuzz::{
+    add_grammar,
+    init_logging,
+    warn_unparse_ambiguities,
+    GrammarFormat,
+    OrExit,
+};
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Args {
+    /// Path to the grammar or - to read it from stdin
     #[arg(long, value_name = "GRAMMAR")]
     grammar: String,
 
-    #[arg(long)]
-    output: String,
+    #[arg(long, required_unless_present = "check")]
+    output: Option<String>,
 
     #[arg(long, default_value_t = GrammarFormat::Peacock)]
     format: GrammarFormat,
 
     #[arg(short, long)]
     entrypoint: Option<String>,
+
+    #[arg(long, default_value_t = false)]
+    thorough_unparsing: bool,
+
+    /// List the alternatives where one can derive a prefix of the other, which makes unparsing ambiguous
+    #[arg(long, default_value_t = false)]
+    warn_ambiguities: bool,
+
+    /// Also export unparse_sequence_mem(), which remembers partial results in a scratch buffer
+    #[arg(long, default_value_t = false)]
+    memoized_unparsing: bool,
+
+    /// Store all terminals in a single string table instead of one array per terminal
+    #[arg(long, default_value_t = false)]
+    terminal_blob: bool,
+
+    /// Distribute the generated code over FILES source files
+    #[arg(long, value_name = "FILES", default_value_t = 1)]
+    split_output: usize,
+
+    /// Only check that the grammar is valid and exit
+    #[arg(long, default_value_t = false)]
+    check: bool,
+
+    /// Print the number of rules before and after every transformation as a table
+    #[arg(long, default_value_t = false)]
+    trace: bool,
+
+    /// Print the number of rules before and after every transformation as JSON
+    #[arg(long, default_value_t = false)]
+    trace_json: bool,
+    /// Allow bindings in the grammar, i.e. parts of an input that must be repeated later on
+    #[arg(long, default_value_t = false)]
+    context_sensitive: bool,
 }
 
 fn main() {
     let args = Args::parse();
+    init_logging();
 
-    let mut cfg = ContextFreeGrammar::builder();
-
-    match args.format {
-        GrammarFormat::Peacock => cfg = cfg.peacock_grammar(&args.grammar).unwrap(),
-        GrammarFormat::Gramatron => cfg = cfg.gramatron_grammar(&args.grammar).unwrap(),
-    }
+    let mut cfg = add_grammar(
+        ContextFreeGrammar::builder().context_sensitive(args.context_sensitive),
+        args.format,
+        &args.grammar,
+    );
 
     if let Some(entrypoint) = args.entrypoint {
         cfg = cfg.entrypoint(entrypoint);
     }
 
-    let cfg = cfg.build().unwrap();
+    let cfg = if args.trace || args.trace_json {
+        let (cfg, trace) = cfg.build_with_trace().or_exit();
+
+        if args.trace_json {
+            println!("{}", serde_json::to_string_pretty(&trace).unwrap());
+        } else {
+            print!("{}", trace);
+        }
+
+        cfg
+    } else {
+        cfg.build().or_exit()
+    };
+
+    if args.warn_ambiguities {
+        warn_unparse_ambiguities(&cfg);
+    }
+
+    if args.check {
+        return;
+    }
 
-    CGenerator::new().generate(&args.output, &cfg);
+    CGenerator::new()
+        .thorough_unparsing(args.thorough_unparsing)
+        .memoized_unparsing(args.memoized_unparsing)
+        .terminal_blob(args.terminal_blob)
+        .split_output(args.split_output)
+        .generate(args.output.expect("--output is required unless --check is given"), &cfg);
 }
@@ -0,0 +1,72 @@
+use clap::Parser;
+use peacock_fuzz::{
+    error::ParsingError,
+    grammar::{
+        diff,
+        ContextFreeGrammar,
+        GrammarBuilder,
+    },
+};
+
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+struct Args {
+    #[arg(short, long)]
+    entrypoint: Option<String>,
+
+    /// Print the differences as JSON
+    #[arg(long, default_value_t = false)]
+    json: bool,
+
+    old: String,
+
+    new: String,
+}
+
+/// Load a grammar in any of the supported formats.
+/// If it is in neither format, the error of the peacock parser is returned.
+fn load(path: &str) -> Result<GrammarBuilder, ParsingError> {
+    ContextFreeGrammar::builder()
+        .peacock_grammar(path)
+        .or_else(|e| ContextFreeGrammar::builder().gramatron_grammar(path).map_err(|_| e))
+}
+
+fn build(path: &str, entrypoint: &Option<String>) -> ContextFreeGrammar {
+    let mut builder = match load(path) {
+        Ok(builder) => builder,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(e.exit_code());
+        },
+    };
+
+    if let Some(entrypoint) = entrypoint {
+        builder = builder.entrypoint(entrypoint);
+    }
+
+    match builder.optimize(false).build() {
+        Ok(cfg) => cfg,
+        Err(e) => {
+            eprintln!("{}: {}", path, e);
+            std::process::exit(e.exit_code());
+        },
+    }
+}
+
+fn main() {
+    let args = Args::parse();
+
+    let old = build(&args.old, &args.entrypoint);
+    let new = build(&args.new, &args.entrypoint);
+    let diff = diff(&old, &new);
+
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(&diff).unwrap());
+    } else {
+        print!("{}", diff);
+    }
+
+    if !diff.is_empty() {
+        std::process::exit(1);
+    }
+}
@@ -4,25 +4,195 @@ use libafl::prelude::{
     Input,
 };
 use libafl_bolts::prelude::AsSlice;
-use peacock_fuzz::components::{
-    load_generator,
-    PeacockInput,
+use peacock_fuzz::{
+    backends::C::LowLevelGrammar,
+    components::{
+        load_dynamic_terminals,
+        load_generator,
+        set_postprocessor,
+        Generator,
+        PeacockInput,
+    },
+    grammar::ContextFreeGrammar,
+    tree::DerivationTree,
 };
 use std::io::Write;
 
+pub mod fuzz;
+use fuzz::{
+    add_grammar,
+    check_postprocessor,
+    init_logging,
+    parse_postprocessor,
+    GrammarFormat,
+    OrExit,
+};
+
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Args {
     #[arg(short, long)]
     generator: String,
 
+    /// If the file cannot be unparsed, show where it diverges from the grammar
+    #[arg(long, default_value_t = false)]
+    why: bool,
+
+    /// Print the rules that produced the input to stderr.
+    /// GRAMMAR must be the grammar that the generator was compiled from.
+    #[arg(long, value_name = "GRAMMAR")]
+    trace: Option<String>,
+
+    /// Print the derivation tree of the input with the byte ranges of the non-terminals instead of a list of rules.
+    /// Needs --trace.
+    #[arg(long, default_value_t = false, requires = "trace")]
+    tree: bool,
+
+    #[arg(long, default_value_t = GrammarFormat::Peacock)]
+    format: GrammarFormat,
+
+    #[arg(short, long)]
+    entrypoint: Option<String>,
+
+    /// Wrap the input like peacock-fuzz --postprocess LIST did for the target
+    #[arg(long, value_name = "LIST", value_parser = check_postprocessor)]
+    postprocess: Option<String>,
+
+    /// The dynamic-terminals-N.dict file from the output directory of peacock-fuzz.
+    /// Without it, inputs that contain terminals of <DYNAMIC> are not serialized like during fuzzing
+    #[arg(long, value_name = "FILE")]
+    dynamic_terminals: Option<String>,
+
     file: String,
 }
 
+/// Print the line of `file` at which unparsing failed and mark the offending column
+fn explain(generator: &Generator, file: &str) {
+    let Some(failure) = generator.last_unparse_failure() else {
+        return;
+    };
+    let content = std::fs::read(file).expect("Could not read specified input file");
+    let (line, column) = failure.line_column(&content);
+    let text = content.split(|byte| *byte == b'\n').nth(line - 1).unwrap_or_default();
+
+    eprintln!("{}:{}:{}: expected <{}>", file, line, column, failure.non_terminal);
+    eprintln!("{}", String::from_utf8_lossy(text));
+    eprintln!("{}^", " ".repeat(column - 1));
+}
+
+/// Print the derivation that `input` describes in `grammar`
+fn trace(args: &Args, generator: &Generator, grammar: &str, input: &PeacockInput) {
+    let mut builder = add_grammar(ContextFreeGrammar::builder(), args.format, grammar);
+
+    if let Some(entrypoint) = &args.entrypoint {
+        builder = builder.entrypoint(entrypoint);
+    }
+
+    let cfg = builder.build().or_exit();
+
+    if generator.fingerprint().is_some_and(|fingerprint| fingerprint != cfg.semantic_hash()) {
+        eprintln!("Warning: The generator was not compiled from {}, the trace is probably wrong", grammar);
+    }
+
+    /* The same names that peacock-fuzz gives the input and the generator in its output directory */
+    eprintln!("Input: {}", input.generate_name(None));
+    eprintln!("Grammar: generator-{:016x}.so", cfg.content_hash());
+
+    let ll = LowLevelGrammar::from_high_level_grammar(&cfg);
+
+    if args.tree {
+        match DerivationTree::from_sequence(&ll, input.sequence()) {
+            Ok(tree) => eprint!("{}", tree.pretty_print()),
+            Err(err) => eprintln!("{}", err),
+        }
+
+        return;
+    }
+
+    for (step, line) in ll.describe_sequence(input.sequence()).iter().enumerate() {
+        eprintln!("{:>6}: {}", step, line);
+    }
+}
+
+/// Load the input file and return the bytes that the target received
+fn run(args: &Args) -> Vec<u8> {
+    let generator = load_generator(&args.generator).generator;
+
+    if let Some(path) = &args.dynamic_terminals {
+        load_dynamic_terminals(path).expect("Could not load dynamic terminals");
+    }
+    let input = PeacockInput::from_file(&args.file);
+
+    if let Err(err) = &input {
+        if args.why {
+            eprintln!("{}", err);
+            explain(&generator, &args.file);
+            std::process::exit(1);
+        }
+    }
+
+    let input = input.expect("Could not load specified input file");
+
+    if let Some(grammar) = &args.trace {
+        trace(args, &generator, grammar, &input);
+    }
+
+    if input.pinned_len() > 0 {
+        eprintln!("Pinned sequence length: {}", input.pinned_len());
+    }
+
+    if let Some(spec) = &args.postprocess {
+        set_postprocessor(Some(Box::new(parse_postprocessor(spec).unwrap())));
+    }
+
+    input.target_bytes().as_slice().to_vec()
+}
+
 fn main() {
     let args = Args::parse();
-    load_generator(&args.generator);
-    let input = PeacockInput::from_file(&args.file).expect("Could not load specified input file");
-    let input = input.target_bytes();
-    std::io::stdout().write_all(input.as_slice()).expect("Could not write to stdout");
+    init_logging();
+    let output = run(&args);
+    std::io::stdout().write_all(&output).expect("Could not write to stdout");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use peacock_fuzz::backends::C::{
+        compile_grammar,
+        CGenerator,
+        CompileOptions,
+    };
+
+    #[test]
+    fn test_postprocess() {
+        let dir = std::env::temp_dir().join(format!("peacock-dump-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let cfg = ContextFreeGrammar::builder()
+            .peacock_grammar_str(r#"{"<ENTRYPOINT>": [["'a'", "<ENTRYPOINT>"], ["'b'"]]}"#)
+            .unwrap()
+            .build()
+            .unwrap();
+        let generator = dir.join("generator.so");
+        compile_grammar(&generator, &cfg, CGenerator::new(), &CompileOptions::new()).unwrap();
+        let input = dir.join("input");
+        std::fs::write(&input, "aab").unwrap();
+
+        let args = |postprocess: &[&str]| {
+            let mut cmdline = vec!["peacock-dump", "--generator", generator.to_str().unwrap()];
+            cmdline.extend_from_slice(postprocess);
+            cmdline.push(input.to_str().unwrap());
+            Args::parse_from(cmdline)
+        };
+
+        assert_eq!(run(&args(&[])), b"aab");
+        assert_eq!(run(&args(&["--postprocess", "base64,len16be"])), b"\x00\x04YWFi");
+        assert_eq!(run(&args(&["--postprocess", "crc32"])), b"aab\x97\x22\x0e\x69");
+        assert!(Args::try_parse_from(["peacock-dump", "-g", "x", "--postprocess", "rot13", "input"]).is_err());
+        assert!(Args::try_parse_from(["peacock-dump", "-g", "x", "--tree", "input"]).is_err());
+        assert!(Args::try_parse_from(["peacock-dump", "-g", "x", "--trace", "grammar.json", "--tree", "input"]).is_ok());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 }
@@ -9,15 +9,22 @@ use libafl::prelude::{
     CrashFeedback,
     Error,
     EventConfig,
+    EventRestarter,
     ForkserverExecutor,
     Fuzzer,
     HasCorpus,
+    HasTargetBytes,
     HitcountsMapObserver,
     IndexesLenTimeMinimizerScheduler,
+    Input,
     Launcher,
     LlmpRestartingEventManager,
+    MapObserver,
     MaxMapFeedback,
+    Observer,
+    ObserversTuple,
     OnDiskCorpus,
+    ProgressReporter,
     StdFuzzer,
     StdMapObserver,
     StdMutationalStage,
@@ -26,6 +33,7 @@ use libafl::prelude::{
     TimeFeedback,
     TimeObserver,
     TimeoutFeedback,
+    UsesInput,
 };
 #[cfg(not(debug_assertions))]
 use libafl::prelude::{
@@ -34,42 +42,128 @@ use libafl::prelude::{
 };
 use libafl_bolts::prelude::{
     current_nanos,
+    get_unique_std_input_file,
     tuple_list,
     AsSliceMut,
     CoreId,
     Cores,
+    Prepend,
     ShMem,
     ShMemProvider,
+    SimpleStderrLogger,
     StdRand,
+    Truncate,
+    UnixShMem,
     UnixShMemProvider,
 };
-use nix::sys::signal::Signal;
+use log::LevelFilter;
+#[cfg(not(feature = "static-loading"))]
+use nix::fcntl::{
+    Flock,
+    FlockArg,
+};
+use nix::{
+    errno::Errno,
+    sys::{
+        mman::{
+            mmap_anonymous,
+            MapFlags,
+            ProtFlags,
+        },
+        signal::{
+            sigaction,
+            SaFlags,
+            SigAction,
+            SigHandler,
+            SigSet,
+            Signal,
+        },
+        wait::waitpid,
+    },
+};
 use peacock_fuzz::{
-    backends::C::CGenerator,
     components::{
         load_generator,
         seed_generator,
+        set_postprocessor,
+        Base64,
+        Chain,
+        Crc32,
+        Generator,
+        LengthPrefix,
         PeacockGenerator,
+        PeacockHavocMutator,
         PeacockInput,
         PeacockMutator,
+        PeacockStatsStage,
+        TokenCollectorStage,
+    },
+    error::{
+        GrammarError,
+        ParsingError,
+    },
+    grammar::{
+        ContextFreeGrammar,
+        GrammarBuilder,
+    },
+};
+#[cfg(not(feature = "static-loading"))]
+use peacock_fuzz::{
+    backends::C::{
+        compile,
+        CGenerator,
+        CompileOptions,
+    },
+    components::{
+        reload_generator,
+        GeneratorReloadStage,
     },
-    grammar::ContextFreeGrammar,
 };
+#[cfg(not(feature = "static-loading"))]
+use std::fs::File;
 use std::{
+    ffi::c_int,
+    io::Read,
+    num::NonZeroUsize,
     path::{
         Path,
         PathBuf,
     },
-    process::Command,
-    time::Duration,
+    sync::{
+        atomic::{
+            AtomicBool,
+            Ordering,
+        },
+        OnceLock,
+    },
+    time::{
+        Duration,
+        Instant,
+    },
 };
 
 const PRELOAD_ENV: &str = "PEACOCK_PRELOAD";
-const CC_ENV: &str = "CC";
 const MAP_SIZE_ENV: &str = "PEACOCK_MAP_SIZE";
+#[cfg(not(feature = "static-loading"))]
+const CFLAGS_ENV: &str = "PEACOCK_CFLAGS";
+const LOG_ENV: &str = "RUST_LOG";
 
 const DEFAULT_MAP_SIZE: usize = 2_621_440;
-const DEFAULT_CC: &str = "cc";
+
+/// How many ambiguities of the grammar are printed at most
+const MAX_LISTED_AMBIGUITIES: usize = 10;
+
+/// How long the target may run before it is killed, in milliseconds
+const DEFAULT_TIMEOUT: u64 = 10_000;
+
+/// How many inputs of the queue are cached in memory
+const DEFAULT_CACHE_SIZE: usize = 128;
+
+/// How often new tokens are added to the dynamic terminals of the generator
+const TOKEN_COLLECTION_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How often the clients report their progress to the broker
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(15);
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, clap::ValueEnum)]
 pub enum GrammarFormat {
@@ -86,17 +180,359 @@ impl std::fmt::Display for GrammarFormat {
     }
 }
 
-#[derive(Parser, Debug)]
+/// The power schedules of LibAFL that --power-schedule can choose
+#[derive(Debug, Copy, Clone, PartialEq, Eq, clap::ValueEnum)]
+pub enum Schedule {
+    Explore,
+    Fast,
+    Coe,
+    Lin,
+    Quad,
+    Exploit,
+}
+
+impl std::fmt::Display for Schedule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Schedule::Explore => write!(f, "explore"),
+            Schedule::Fast => write!(f, "fast"),
+            Schedule::Coe => write!(f, "coe"),
+            Schedule::Lin => write!(f, "lin"),
+            Schedule::Quad => write!(f, "quad"),
+            Schedule::Exploit => write!(f, "exploit"),
+        }
+    }
+}
+
+/// The power schedule of LibAFL that `schedule` stands for
+pub fn power_schedule(schedule: Schedule) -> PowerSchedule {
+    match schedule {
+        Schedule::Explore => PowerSchedule::EXPLORE,
+        Schedule::Fast => PowerSchedule::FAST,
+        Schedule::Coe => PowerSchedule::COE,
+        Schedule::Lin => PowerSchedule::LIN,
+        Schedule::Quad => PowerSchedule::QUAD,
+        Schedule::Exploit => PowerSchedule::EXPLOIT,
+    }
+}
+
+/// Errors that terminate a command line tool with a stable exit code instead of a panic
+pub trait ExitCode {
+    /// The exit code of the process
+    fn exit_code(&self) -> i32;
+}
+
+impl ExitCode for ParsingError {
+    fn exit_code(&self) -> i32 {
+        ParsingError::exit_code(self)
+    }
+}
+
+impl ExitCode for GrammarError {
+    fn exit_code(&self) -> i32 {
+        GrammarError::exit_code(self)
+    }
+}
+
+/// Print a one-line diagnostic and exit on errors
+pub trait OrExit<T> {
+    /// Unwrap the value or exit the process with the exit code of the error
+    fn or_exit(self) -> T;
+}
+
+impl<T, E> OrExit<T> for Result<T, E>
+where
+    E: ExitCode + std::fmt::Display,
+{
+    fn or_exit(self) -> T {
+        match self {
+            Ok(value) => value,
+            Err(err) => {
+                eprintln!("Error: {}", err);
+                std::process::exit(err.exit_code());
+            },
+        }
+    }
+}
+
+/// Load the grammar `grammar` in format `format` into `builder`. If `grammar` is `-`, it is read from stdin.
+pub fn add_grammar(builder: GrammarBuilder, format: GrammarFormat, grammar: &str) -> GrammarBuilder {
+    if grammar == "-" {
+        let mut content = String::new();
+        std::io::stdin().read_to_string(&mut content).expect("Could not read grammar from stdin");
+
+        match format {
+            GrammarFormat::Peacock => builder.peacock_grammar_str(&content).or_exit(),
+            GrammarFormat::Gramatron => builder.gramatron_grammar_str(&content).or_exit(),
+        }
+    } else {
+        match format {
+            GrammarFormat::Peacock => builder.peacock_grammar(grammar).or_exit(),
+            GrammarFormat::Gramatron => builder.gramatron_grammar(grammar).or_exit(),
+        }
+    }
+}
+
+/// Options that control how the target is executed and how inputs are delivered to it
+#[derive(clap::Args, Debug, Clone)]
+pub struct TargetOptions {
+    /// How long the target may run before it is killed, in milliseconds
+    #[arg(long, value_name = "MS", default_value_t = DEFAULT_TIMEOUT, value_parser = clap::value_parser!(u64).range(1..))]
+    timeout: u64,
+
+    /// The signal that kills the target on a timeout, e.g. SIGKILL, TERM or 9
+    #[arg(long, value_name = "SIG", default_value = "SIGKILL", value_parser = parse_signal)]
+    kill_signal: Signal,
+
+    /// The size of the coverage map in bytes, rounded up to a multiple of 64
+    #[arg(long, value_name = "N", env = MAP_SIZE_ENV, default_value_t = DEFAULT_MAP_SIZE, value_parser = parse_map_size)]
+    map_size: usize,
+
+    /// Deliver inputs to the target via stdin
+    #[arg(long, conflicts_with = "file")]
+    stdin: bool,
+
+    /// Deliver inputs to the target as a file at the position of @@ or as the last argument
+    #[arg(long)]
+    file: bool,
+
+    /// Path of the input file. {core} and {pid} get replaced with the core and process id of a fuzzer instance
+    #[arg(long, value_name = "TEMPLATE")]
+    input_file: Option<String>,
+
+    /// Extension of the input file
+    #[arg(long, value_name = "EXT")]
+    input_extension: Option<String>,
+
+    /// Wrap every input before it is delivered. LIST is a comma-separated list of
+    /// len8, len16, len32, len64 (little-endian length prefix, append "be" for big-endian),
+    /// crc32, crc32be (append a checksum) and base64, that are applied in order
+    #[arg(long, value_name = "LIST", value_parser = check_postprocessor)]
+    postprocess: Option<String>,
+}
+
+impl TargetOptions {
+    /// The size of the coverage map from --map-size or PEACOCK_MAP_SIZE
+    pub fn map_size(&self) -> usize {
+        self.map_size
+    }
+
+    /// Register the postprocessor given with --postprocess for all inputs of this process
+    pub fn install_postprocessor(&self) {
+        if let Some(spec) = &self.postprocess {
+            set_postprocessor(Some(Box::new(parse_postprocessor(spec).unwrap())));
+        }
+    }
+}
+
+/// Parse the signal of --kill-signal, given by its name with or without the SIG prefix or by its number
+pub fn parse_signal(spec: &str) -> Result<Signal, String> {
+    if let Ok(number) = spec.parse::<i32>() {
+        return Signal::try_from(number).map_err(|_| format!("Invalid signal number: {}", number));
+    }
+
+    let name = spec.to_ascii_uppercase();
+    let name = if name.starts_with("SIG") { name } else { format!("SIG{}", name) };
+    name.parse::<Signal>().map_err(|_| format!("Invalid signal: {}", spec))
+}
+
+/// Parse the size of the coverage map and round it up to a multiple of 64
+pub fn parse_map_size(spec: &str) -> Result<usize, String> {
+    match spec.parse::<usize>() {
+        Ok(0) => Err("The map size must not be 0".to_string()),
+        Ok(map_size) => map_size.checked_next_multiple_of(64).ok_or_else(|| format!("Map size too large: {}", spec)),
+        Err(_) => Err(format!("Invalid map size: {}", spec)),
+    }
+}
+
+/// Parse the number of cached inputs of --cache-size, which must not be 0
+fn parse_cache_size(spec: &str) -> Result<usize, String> {
+    match spec.parse::<usize>() {
+        Ok(0) => Err("The cache size must not be 0".to_string()),
+        Ok(cache_size) => Ok(cache_size),
+        Err(_) => Err(format!("Invalid cache size: {}", spec)),
+    }
+}
+
+/// Parse the probability of --corruption-probability, which must be between 0 and 1
+fn parse_probability(spec: &str) -> Result<f64, String> {
+    match spec.parse::<f64>() {
+        Ok(probability) if (0.0..=1.0).contains(&probability) => Ok(probability),
+        _ => Err(format!("Invalid probability: {}", spec)),
+    }
+}
+
+/// Parse the list of postprocessors of --postprocess
+pub fn parse_postprocessor(spec: &str) -> Result<Chain, String> {
+    let mut chain = Chain::new();
+
+    for name in spec.split(',') {
+        chain = match name.trim() {
+            "len8" | "len8be" => chain.then(LengthPrefix::new(1, false)),
+            "len16" => chain.then(LengthPrefix::new(2, false)),
+            "len16be" => chain.then(LengthPrefix::new(2, true)),
+            "len32" => chain.then(LengthPrefix::new(4, false)),
+            "len32be" => chain.then(LengthPrefix::new(4, true)),
+            "len64" => chain.then(LengthPrefix::new(8, false)),
+            "len64be" => chain.then(LengthPrefix::new(8, true)),
+            "crc32" => chain.then(Crc32::new(false)),
+            "crc32be" => chain.then(Crc32::new(true)),
+            "base64" => chain.then(Base64),
+            name => return Err(format!("Unknown postprocessor '{}'", name)),
+        };
+    }
+
+    Ok(chain)
+}
+
+/// Validate --postprocess without keeping the parsed chain
+pub fn check_postprocessor(spec: &str) -> Result<String, String> {
+    parse_postprocessor(spec).map(|_| spec.to_string())
+}
+
+/// Parse a duration like `7200`, `90s`, `30m`, `2h`, `1d` or a combination like `1h30m`.
+/// A number without unit is in seconds.
+pub fn parse_duration(spec: &str) -> Result<Duration, String> {
+    if let Ok(secs) = spec.parse::<u64>() {
+        return Ok(Duration::from_secs(secs));
+    }
+
+    let mut secs: u64 = 0;
+    let mut number = String::new();
+
+    for c in spec.chars() {
+        if c.is_ascii_digit() {
+            number.push(c);
+            continue;
+        }
+
+        let unit = match c {
+            's' => 1,
+            'm' => 60,
+            'h' => 60 * 60,
+            'd' => 24 * 60 * 60,
+            _ => return Err(format!("Invalid unit '{}' in duration '{}'", c, spec)),
+        };
+        let Ok(value) = number.parse::<u64>() else {
+            return Err(format!("Invalid duration '{}'", spec));
+        };
+
+        secs = value.checked_mul(unit).and_then(|value| secs.checked_add(value)).ok_or("Duration is too long")?;
+        number.clear();
+    }
+
+    if !number.is_empty() || spec.is_empty() {
+        return Err(format!("Invalid duration '{}'", spec));
+    }
+
+    Ok(Duration::from_secs(secs))
+}
+
+/// Set when the campaign should end. It lives in memory that is shared by all processes of the launcher
+/// such that a signal to any of them stops every client.
+static SHUTDOWN: OnceLock<&'static AtomicBool> = OnceLock::new();
+
+extern "C" fn request_shutdown(_signal: c_int) {
+    if let Some(shutdown) = SHUTDOWN.get() {
+        shutdown.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Make SIGINT, SIGTERM and SIGQUIT request a shutdown instead of terminating the process.
+/// Processes that are forked afterwards inherit the handler and the shutdown flag.
+fn install_shutdown_handler() -> Result<(), Error> {
+    if SHUTDOWN.get().is_none() {
+        let len = NonZeroUsize::new(std::mem::size_of::<AtomicBool>()).unwrap();
+        let flag =
+            unsafe { mmap_anonymous(None, len, ProtFlags::PROT_READ | ProtFlags::PROT_WRITE, MapFlags::MAP_SHARED) }
+                .map_err(|err| Error::unknown(format!("Could not map the shutdown flag: {}", err)))?;
+        let _ = SHUTDOWN.set(unsafe { flag.cast::<AtomicBool>().as_ref() });
+    }
+
+    let action = SigAction::new(SigHandler::Handler(request_shutdown), SaFlags::SA_RESTART, SigSet::empty());
+
+    for signal in [Signal::SIGINT, Signal::SIGTERM, Signal::SIGQUIT] {
+        unsafe { sigaction(signal, &action) }
+            .map_err(|err| Error::unknown(format!("Could not install handler for {}: {}", signal, err)))?;
+    }
+
+    Ok(())
+}
+
+/// Whether a signal was received or the deadline of --timeout-total has passed
+fn shutdown_requested(deadline: Option<Instant>) -> bool {
+    SHUTDOWN.get().is_some_and(|shutdown| shutdown.load(Ordering::Relaxed))
+        || deadline.is_some_and(|deadline| Instant::now() >= deadline)
+}
+
+/// Write the inputs of the corpus that are only cached in memory to disk
+fn flush_corpus<S>(state: &S) -> Result<(), Error>
+where
+    S: HasCorpus + UsesInput<Input = PeacockInput>,
+{
+    for id in state.corpus().ids() {
+        let testcase = state.corpus().get(id)?.borrow();
+
+        if let (Some(path), Some(input)) = (testcase.file_path(), testcase.input()) {
+            if !path.exists() {
+                input.to_file(path)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// The state of the terminal before the TUI took it over
+#[cfg(not(debug_assertions))]
+struct TerminalState(Option<nix::sys::termios::Termios>);
+
+#[cfg(not(debug_assertions))]
+impl TerminalState {
+    fn save() -> Self {
+        use std::io::IsTerminal;
+
+        let stdin = std::io::stdin();
+        let termios = if stdin.is_terminal() { nix::sys::termios::tcgetattr(&stdin).ok() } else { None };
+        Self(termios)
+    }
+
+    /// Leave the alternate screen of the TUI and disable mouse capture and raw mode
+    fn restore(self) {
+        use std::io::{
+            IsTerminal,
+            Write,
+        };
+
+        let stdout = std::io::stdout();
+
+        if !stdout.is_terminal() {
+            return;
+        }
+
+        /* The TUI thread keeps drawing until the process exits, so it must not get hold of stdout again */
+        let mut lock = stdout.lock();
+        let _ = lock.write_all(b"\x1b[?1000l\x1b[?1002l\x1b[?1003l\x1b[?1015l\x1b[?1006l\x1b[?1049l\x1b[?25h");
+        let _ = lock.flush();
+        std::mem::forget(lock);
+
+        if let Some(termios) = self.0 {
+            let _ = nix::sys::termios::tcsetattr(std::io::stdin(), nix::sys::termios::SetArg::TCSANOW, &termios);
+        }
+    }
+}
+
+#[derive(Parser, Debug, Clone)]
 #[command(author, version, about, long_about = None)]
 struct Args {
-    #[arg(long, value_name = "CORES")]
-    cores: String,
+    #[arg(long, value_name = "CORES", required_unless_present = "check")]
+    cores: Option<String>,
 
     #[arg(long, value_name = "GRAMMAR")]
     grammar: String,
 
-    #[arg(short)]
-    output: String,
+    #[arg(short, required_unless_present = "check")]
+    output: Option<String>,
 
     #[arg(long, default_value_t = GrammarFormat::Peacock)]
     format: GrammarFormat,
@@ -104,13 +540,243 @@ struct Args {
     #[arg(short, long)]
     entrypoint: Option<String>,
 
+    /// List the alternatives where one can derive a prefix of the other, which makes unparsing ambiguous
+    #[arg(long, default_value_t = false)]
+    warn_ambiguities: bool,
+
     #[arg(short, long)]
     corpus: Option<String>,
 
-    #[arg(trailing_var_arg = true, allow_hyphen_values = true, required = true)]
+    /// Write a report about how often every grammar rule was used to the output directory every SECS seconds
+    #[arg(long, value_name = "SECS")]
+    stats_interval: Option<u64>,
+
+    /// Check the grammar file for modifications every SECS seconds and hot-swap the generator
+    /// without restarting the fuzzer. The corpus gets translated to the new grammar.
+    #[arg(long, value_name = "SECS")]
+    watch_grammar: Option<u64>,
+
+    /// Stop fuzzing cleanly after DURATION, e.g. 7200, 90m or 2h. SIGINT and SIGTERM also stop the fuzzer cleanly
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration)]
+    timeout_total: Option<Duration>,
+
+    /// Add the tokens of this dictionary in AFL format to the non-terminal <DYNAMIC> of the grammar,
+    /// e.g. the file that AFL++ writes with AFL_LLVM_DICT2FILE. Can be given multiple times.
+    #[arg(long, value_name = "FILE")]
+    dict: Vec<String>,
+
+    #[command(flatten)]
+    target: TargetOptions,
+
+    /// The power schedule that decides how often an input of the queue gets mutated
+    #[arg(long, value_name = "SCHEDULE", default_value_t = Schedule::Explore)]
+    power_schedule: Schedule,
+
+    /// How many inputs of the queue are cached in memory
+    #[arg(long, value_name = "N", default_value_t = DEFAULT_CACHE_SIZE, value_parser = parse_cache_size)]
+    cache_size: usize,
+
+    /// Deliver a few randomly flipped, inserted or deleted bytes instead of a grammar-based mutation with
+    /// probability P, e.g. 0.05, such that the target also sees inputs outside of the grammar
+    #[arg(long, value_name = "P", default_value_t = 0.0, value_parser = parse_probability)]
+    corruption_probability: f64,
+
+    /// Hardlink the input file into the output directory such that the last input survives a crash of the fuzzer
+    #[arg(long)]
+    keep_last_input: bool,
+
+    /// Fuzz with the generator.so in the output directory even if it was compiled from a different grammar
+    #[arg(long)]
+    force_stale_generator: bool,
+
+    /// Print how inputs are delivered to the target and exit
+    #[arg(long)]
+    dry_run: bool,
+
+    /// Only check that the grammar is valid and exit
+    #[arg(long)]
+    check: bool,
+
+    /// Distribute the code of the generator over FILES source files to speed up compilation of big grammars
+    #[arg(long, value_name = "FILES", default_value_t = 1)]
+    split_output: usize,
+
+    #[arg(trailing_var_arg = true, allow_hyphen_values = true, required_unless_present = "check")]
     cmdline: Vec<String>,
 }
 
+impl Args {
+    fn output(&self) -> &str {
+        self.output.as_deref().expect("Output directory is required unless --check is given")
+    }
+
+    fn cores(&self) -> &str {
+        self.cores.as_deref().expect("Cores are required unless --check is given")
+    }
+
+    fn delivery(&self, core: usize) -> Result<InputDelivery, String> {
+        InputDelivery::new(&self.target, &self.cmdline, core)
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum TargetArg {
+    Plain(String),
+    Input,
+}
+
+/// How the inputs get delivered to the target
+#[derive(Debug)]
+pub struct InputDelivery {
+    program: String,
+    args: Vec<TargetArg>,
+    input_file: PathBuf,
+    stdin: bool,
+    timeout: Duration,
+    kill_signal: Signal,
+}
+
+impl InputDelivery {
+    /// Determine how inputs are delivered to the target `cmdline` by the fuzzer instance on `core`
+    pub fn new(options: &TargetOptions, cmdline: &[String], core: usize) -> Result<Self, String> {
+        let has_marker = cmdline[1..].iter().any(|arg| arg == "@@");
+        let custom_file = options.input_file.is_some() || options.input_extension.is_some();
+
+        if options.stdin && has_marker {
+            return Err("--stdin cannot be combined with @@ in the command line".to_string());
+        }
+
+        if options.stdin && custom_file {
+            return Err("--input-file and --input-extension require inputs to be delivered as files".to_string());
+        }
+
+        let stdin = !options.file && !has_marker && !custom_file;
+
+        let mut input_file = match &options.input_file {
+            Some(template) => {
+                template.replace("{core}", &core.to_string()).replace("{pid}", &std::process::id().to_string())
+            },
+            None => get_unique_std_input_file(),
+        };
+
+        if let Some(extension) = &options.input_extension {
+            input_file.push('.');
+            input_file.push_str(extension.trim_start_matches('.'));
+        }
+
+        let mut target_args: Vec<TargetArg> = cmdline[1..]
+            .iter()
+            .map(|arg| if arg == "@@" { TargetArg::Input } else { TargetArg::Plain(arg.clone()) })
+            .collect();
+
+        if !stdin && !has_marker {
+            target_args.push(TargetArg::Input);
+        }
+
+        Ok(Self {
+            program: cmdline[0].clone(),
+            args: target_args,
+            input_file: PathBuf::from(input_file),
+            stdin,
+            timeout: Duration::from_millis(options.timeout),
+            kill_signal: options.kill_signal,
+        })
+    }
+
+    /// The file through which inputs are passed to the target
+    pub fn input_file(&self) -> &Path {
+        &self.input_file
+    }
+
+    /// Start the target in a forkserver that reports its coverage to `edges_observer`
+    pub fn forkserver<A, MO, OT, S>(
+        &self,
+        map_size: usize,
+        edges_observer: A,
+        observers: OT,
+    ) -> Result<ForkserverExecutor<(A, OT), S, UnixShMemProvider>, Error>
+    where
+        MO: MapObserver + Truncate,
+        A: Observer<S> + AsRef<MO> + AsMut<MO>,
+        OT: ObserversTuple<S> + Prepend<MO, PreprendResult = OT>,
+        S: UsesInput,
+        S::Input: Input + HasTargetBytes,
+    {
+        let mut builder = ForkserverExecutor::builder().program(&self.program);
+
+        for arg in &self.args {
+            builder = match arg {
+                TargetArg::Plain(arg) => builder.arg(arg),
+                TargetArg::Input => builder.arg_input_file(&self.input_file),
+            };
+        }
+
+        builder
+            .debug_child(cfg!(debug_assertions))
+            .coverage_map_size(map_size)
+            .is_persistent(false)
+            .timeout(self.timeout)
+            .kill_signal(self.kill_signal)
+            .build_dynamic_map(edges_observer, observers)
+    }
+}
+
+impl std::fmt::Display for InputDelivery {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.program)?;
+
+        for arg in &self.args {
+            match arg {
+                TargetArg::Plain(arg) => write!(f, " {}", arg)?,
+                TargetArg::Input => write!(f, " {}", self.input_file.display())?,
+            }
+        }
+
+        if self.stdin {
+            write!(f, " < {}", self.input_file.display())?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Print log messages up to the level in RUST_LOG, e.g. `RUST_LOG=debug` shows what the
+/// transformations of the grammar builder do. Logging stays off if the variable is not set.
+pub fn init_logging() {
+    let Ok(value) = std::env::var(LOG_ENV) else {
+        return;
+    };
+
+    match value.parse::<LevelFilter>() {
+        Ok(level) => {
+            if SimpleStderrLogger::set_logger().is_ok() {
+                log::set_max_level(level);
+            }
+        },
+        Err(_) => eprintln!("Warning: ignoring invalid log level in {}: {}", LOG_ENV, value),
+    }
+}
+
+/// Allocate a coverage map of `map_size` bytes and announce it to targets that are started afterwards.
+/// Libraries in PEACOCK_PRELOAD get preloaded into the targets. PEACOCK_MAP_SIZE was already parsed
+/// into `map_size` and does not reach the targets.
+pub fn coverage_map(map_size: usize) -> Result<UnixShMem, Error> {
+    if let Ok(value) = std::env::var(PRELOAD_ENV) {
+        std::env::set_var("LD_PRELOAD", value);
+        std::env::remove_var(PRELOAD_ENV);
+    }
+
+    std::env::remove_var(MAP_SIZE_ENV);
+
+    let mut shmem_provider = UnixShMemProvider::new()?;
+    let shmem = shmem_provider.new_shmem(map_size)?;
+    shmem.write_to_env("__AFL_SHM_ID")?;
+    std::env::set_var("AFL_MAP_SIZE", format!("{}", map_size));
+
+    Ok(shmem)
+}
+
+#[cfg(not(feature = "static-loading"))]
 fn mkdir(dir: &str) {
     match std::fs::create_dir(dir) {
         Ok(()) => {},
@@ -122,105 +788,267 @@ fn mkdir(dir: &str) {
     }
 }
 
-/// Return true if a is newer than b
-fn is_newer<P1: AsRef<Path>, P2: AsRef<Path>>(a: P1, b: P2) -> bool {
-    let a = std::fs::metadata(a).unwrap().modified().unwrap();
-    let b = std::fs::metadata(b).unwrap().modified().unwrap();
-    a > b
+/// Additional flags for the C compiler from PEACOCK_CFLAGS
+#[cfg(not(feature = "static-loading"))]
+fn extra_cflags() -> Vec<String> {
+    std::env::var(CFLAGS_ENV).unwrap_or_default().split_whitespace().map(String::from).collect()
 }
 
-fn compile_source(output: &Path, input: &Path) {
-    let cc = if let Ok(var) = std::env::var(CC_ENV) { var } else { DEFAULT_CC.to_string() };
-
-    let output = Command::new(cc)
-        .args([
-            "-o",
-            &output.to_string_lossy(),
-            "-flto",
-            "-s",
-            "-fvisibility=hidden",
-            "-DMAKE_VISIBLE",
-            "-Ofast",
-            "-march=native",
-            "-fomit-frame-pointer",
-            "-fno-stack-protector",
-            "-fPIC",
-            "-shared",
-            &input.to_string_lossy(),
-            "-nostdlib",
-        ])
-        .output()
-        .expect("Could not launch C compiler");
+/// Compile the generator with the compiler in CC and the additional flags in PEACOCK_CFLAGS.
+/// Exits with the output of the compiler if that fails.
+#[cfg(not(feature = "static-loading"))]
+fn compile_source(output: &Path, sources: &[PathBuf]) {
+    let options = extra_cflags().into_iter().fold(CompileOptions::new(), CompileOptions::flag);
 
-    if !output.status.success() {
-        panic!("Compiling grammar failed");
+    if let Err(err) = compile(output, sources, &options) {
+        eprintln!("{}", err);
+        std::process::exit(1);
     }
 }
 
-fn generate_source(args: &Args, c_file: &Path) {
-    let mut cfg = ContextFreeGrammar::builder();
+/// Warn about alternatives that the unparser cannot tell apart
+pub fn warn_unparse_ambiguities(cfg: &ContextFreeGrammar) {
+    let reports = cfg.unparse_ambiguities();
 
-    match &args.format {
-        GrammarFormat::Peacock => cfg = cfg.peacock_grammar(&args.grammar).unwrap(),
-        GrammarFormat::Gramatron => cfg = cfg.gramatron_grammar(&args.grammar).unwrap(),
+    if reports.is_empty() {
+        return;
     }
 
+    eprintln!(
+        "Warning: {} pair(s) of alternatives where one can derive a prefix of the other, which makes unparsing ambiguous:",
+        reports.len()
+    );
+
+    for report in reports.iter().take(MAX_LISTED_AMBIGUITIES) {
+        eprintln!("  {}", report);
+    }
+
+    if reports.len() > MAX_LISTED_AMBIGUITIES {
+        eprintln!("  ... and {} more", reports.len() - MAX_LISTED_AMBIGUITIES);
+    }
+}
+
+fn build_grammar(args: &Args) -> ContextFreeGrammar {
+    let mut cfg = add_grammar(ContextFreeGrammar::builder(), args.format, &args.grammar);
+
     if let Some(entrypoint) = &args.entrypoint {
         cfg = cfg.entrypoint(entrypoint);
     }
 
-    let cfg = cfg.build().unwrap();
+    let (cfg, report) = cfg.build_with_report().or_exit();
+
+    if report.is_degenerate() {
+        eprintln!(
+            "Warning: The grammar has only {} derivation(s) and will always produce the same input",
+            report.derivations
+        );
+
+        if let Some(pass) = report.collapsed_by {
+            eprintln!("Warning: The grammar lost its variability in the optimization pass {}", pass);
+        }
+    }
+
+    if args.warn_ambiguities {
+        warn_unparse_ambiguities(&cfg);
+    }
+
+    cfg
+}
+
+/// Like build_grammar() but reports errors instead of exiting
+#[cfg(not(feature = "static-loading"))]
+fn check_grammar(args: &Args) -> Result<(), String> {
+    let builder = ContextFreeGrammar::builder();
+    let mut builder = match args.format {
+        GrammarFormat::Peacock => builder.peacock_grammar(&args.grammar),
+        GrammarFormat::Gramatron => builder.gramatron_grammar(&args.grammar),
+    }
+    .map_err(|err| err.to_string())?;
+
+    if let Some(entrypoint) = &args.entrypoint {
+        builder = builder.entrypoint(entrypoint);
+    }
+
+    builder.build().map(|_| ()).map_err(|err| err.to_string())
+}
+
+#[cfg(not(feature = "static-loading"))]
+fn generate_source(args: &Args, cfg: &ContextFreeGrammar, c_file: &Path) -> Vec<PathBuf> {
+    CGenerator::new()
+        .emit_statistics(args.stats_interval.is_some())
+        .split_output(args.split_output)
+        .generate(c_file, cfg)
+}
+
+/// Identifies the grammar and the options that generator.so was compiled with
+#[cfg(not(feature = "static-loading"))]
+fn generator_key(args: &Args, cfg: &ContextFreeGrammar) -> String {
+    format!(
+        "{:016x} stats={} split={} cc={} cflags={:?} version={}\n",
+        cfg.content_hash(),
+        args.stats_interval.is_some(),
+        args.split_output,
+        std::env::var("CC").unwrap_or_default(),
+        extra_cflags(),
+        env!("CARGO_PKG_VERSION")
+    )
+}
 
-    CGenerator::new().generate(c_file, &cfg);
+/// Generate and compile generator.so unless an up-to-date version already exists in the output directory.
+/// A version is up-to-date if the hash stored in generator.so.hash matches the hash of the loaded grammar.
+/// Multiple processes that share an output directory serialize on a lockfile and never see a half-written file.
+/// Returns the versioned copy of generator.so, see versioned_generator().
+#[cfg(not(feature = "static-loading"))]
+fn compile_grammar(args: &Args) -> PathBuf {
+    versioned_generator(args, &build_grammar(args))
 }
 
-fn load_grammar(args: &Args) {
-    let generator_so = PathBuf::from(format!("{}/generator.so", &args.output));
-    let c_file = PathBuf::from(format!("{}/generator.c", &args.output));
+/// Like compile_grammar() for an already built grammar
+#[cfg(not(feature = "static-loading"))]
+fn compile_generator(args: &Args, cfg: &ContextFreeGrammar) -> PathBuf {
+    let output_dir = Path::new(args.output());
+    let generator_so = output_dir.join("generator.so");
+    let generator_hash = output_dir.join("generator.so.hash");
+    let key = generator_key(args, cfg);
+
+    mkdir(args.output());
+
+    let lockfile = File::create(output_dir.join(".generator.lock")).expect("Could not create lockfile");
+    let _lock = Flock::lock(lockfile, FlockArg::LockExclusive)
+        .map_err(|(_, err)| err)
+        .expect("Could not lock output directory");
 
-    mkdir(&args.output);
-    if !generator_so.exists() || is_newer(&args.grammar, &generator_so) {
+    let up_to_date = generator_so.exists() && std::fs::read_to_string(&generator_hash).is_ok_and(|x| x == key);
+
+    if !up_to_date {
         println!("Compiling generator.so ...");
-        generate_source(args, &c_file);
-        compile_source(&generator_so, &c_file);
+        // The generated files include each other by name, so they are created in a separate directory
+        let tmp_dir = output_dir.join(format!(".generator.{}", std::process::id()));
+        mkdir(&tmp_dir.to_string_lossy());
+        let tmp_generator_so = tmp_dir.join("generator.so");
+        let sources = generate_source(args, cfg, &tmp_dir.join("generator.c"));
+        compile_source(&tmp_generator_so, &sources);
+
+        for entry in std::fs::read_dir(&tmp_dir).expect("Could not read generator directory") {
+            let path = entry.expect("Could not read generator directory").path();
+
+            if path != tmp_generator_so {
+                std::fs::rename(&path, output_dir.join(path.file_name().unwrap()))
+                    .expect("Could not move generated code into place");
+            }
+        }
+
+        std::fs::rename(&tmp_generator_so, &generator_so).expect("Could not move generator.so into place");
+        std::fs::remove_dir(&tmp_dir).expect("Could not remove generator directory");
+        std::fs::write(&generator_hash, key).expect("Could not write generator.so.hash");
     }
 
-    load_generator(generator_so);
+    generator_so
+}
+
+/// Like compile_generator() but returns a copy of generator.so at generator-HASH.so, where HASH identifies
+/// the grammar. Every rebuild of --watch-grammar gets its own file, so the old ones stay available to
+/// replay the inputs that were created with them.
+#[cfg(not(feature = "static-loading"))]
+fn versioned_generator(args: &Args, cfg: &ContextFreeGrammar) -> PathBuf {
+    let generator_so = compile_generator(args, cfg);
+    let versioned = Path::new(args.output()).join(format!("generator-{:016x}.so", cfg.content_hash()));
+
+    if !versioned.exists() {
+        let tmp = versioned.with_extension(format!("so.{}", std::process::id()));
+        std::fs::copy(&generator_so, &tmp).expect("Could not copy generator.so");
+        std::fs::rename(&tmp, &versioned).expect("Could not move the generator into place");
+    }
+
+    versioned
+}
+
+/// Compile and load generator.so. If it was compiled from a different grammar than the one in
+/// --grammar, e.g. because it was replaced after generator.so.hash had been written, it gets
+/// recompiled unless --force-stale-generator is given.
+#[cfg(not(feature = "static-loading"))]
+fn load_grammar(args: &Args) -> Generator {
+    let cfg = build_grammar(args);
+    let loaded = load_generator(compile_generator(args, &cfg));
+
+    if loaded.matches(&cfg) {
+        return loaded.generator;
+    }
+
+    let found = loaded.digest.as_deref().unwrap_or("none");
+
+    if args.force_stale_generator {
+        eprintln!(
+            "Warning: generator.so was compiled from a different grammar (digest {}, expected {})",
+            found,
+            cfg.digest()
+        );
+        return loaded.generator;
+    }
+
+    println!("generator.so was compiled from a different grammar (digest {}), recompiling ...", found);
+    let _ = std::fs::remove_file(Path::new(args.output()).join("generator.so.hash"));
+    let generator = reload_generator(compile_generator(args, &cfg));
+
+    if generator.digest() != Some(cfg.digest()) {
+        eprintln!("Could not compile a generator.so for the grammar {}", args.grammar);
+        std::process::exit(1);
+    }
+
+    generator
+}
+
+/// Load the generator that was linked into the fuzzer. It cannot be recompiled, so a generator
+/// that was created from a different grammar than the one in --grammar is an error unless
+/// --force-stale-generator is given.
+#[cfg(feature = "static-loading")]
+fn load_grammar(args: &Args) -> Generator {
+    let cfg = build_grammar(args);
+    let loaded = load_generator();
+
+    if loaded.matches(&cfg) {
+        return loaded.generator;
+    }
+
+    let found = loaded.digest.as_deref().unwrap_or("none");
+
+    if args.force_stale_generator {
+        eprintln!(
+            "Warning: The linked generator was created from a different grammar (digest {}, expected {})",
+            found,
+            cfg.digest()
+        );
+        return loaded.generator;
+    }
+
+    eprintln!(
+        "The linked generator was created from a different grammar (digest {}, expected {})",
+        found,
+        cfg.digest()
+    );
+    std::process::exit(1);
 }
 
 /* Harness */
 fn fuzz(args: Args) -> Result<(), Error> {
-    let mut map_size = if let Ok(value) = std::env::var(MAP_SIZE_ENV) {
-        std::env::remove_var(MAP_SIZE_ENV);
-        value.parse().expect("Invalid map size speficiation")
-    } else {
-        DEFAULT_MAP_SIZE
-    };
+    let map_size = args.target.map_size();
+    let deadline = args.timeout_total.map(|timeout| Instant::now() + timeout);
+    let launcher_pid = std::process::id();
 
-    if map_size % 64 != 0 {
-        map_size = ((map_size + 63) >> 6) << 6;
-    }
+    install_shutdown_handler()?;
 
     let mut run_client = |state: Option<_>, mut mgr: LlmpRestartingEventManager<_, _, _>, core_id: CoreId| {
-        let output_dir = Path::new(&args.output);
+        /* LibAFL installs its own handlers that exit immediately */
+        install_shutdown_handler()?;
+
+        let output_dir = Path::new(args.output());
         let queue_dir = output_dir.join("queue");
         let crashes_dir = output_dir.join("crashes");
         let seed = current_nanos().rotate_left(core_id.0 as u32);
-        let powerschedule = PowerSchedule::EXPLORE;
-        let timeout = Duration::from_secs(10);
-        let signal = str::parse::<Signal>("SIGKILL").unwrap();
-        let debug_child = cfg!(debug_assertions);
-
-        if let Ok(value) = std::env::var(PRELOAD_ENV) {
-            std::env::set_var("LD_PRELOAD", value);
-            std::env::remove_var(PRELOAD_ENV);
-        }
+        let powerschedule = power_schedule(args.power_schedule);
 
-        let mut shmem_provider = UnixShMemProvider::new()?;
-        let mut shmem = shmem_provider.new_shmem(map_size)?;
-        shmem.write_to_env("__AFL_SHM_ID")?;
+        let mut shmem = coverage_map(map_size)?;
         let shmem_buf = shmem.as_slice_mut();
-        std::env::set_var("AFL_MAP_SIZE", format!("{}", map_size));
 
         let edges_observer =
             unsafe { HitcountsMapObserver::new(StdMapObserver::new("shared_mem", shmem_buf)).track_indices() };
@@ -235,21 +1063,21 @@ fn fuzz(args: Args) -> Result<(), Error> {
 
         let mut objective = feedback_or!(CrashFeedback::new(), TimeoutFeedback::new());
 
-        seed_generator(seed as usize);
+        seed_generator(seed);
 
         let mut state = if let Some(state) = state {
             state
         } else {
             StdState::new(
                 StdRand::with_seed(seed),
-                CachedOnDiskCorpus::<PeacockInput>::new(&queue_dir, 128)?,
+                CachedOnDiskCorpus::<PeacockInput>::new(&queue_dir, args.cache_size)?,
                 OnDiskCorpus::new(crashes_dir)?,
                 &mut feedback,
                 &mut objective,
             )?
         };
 
-        let mutator = PeacockMutator::new();
+        let mutator = PeacockHavocMutator::new(PeacockMutator::new()).with_probability(args.corruption_probability);
 
         let mutational = StdMutationalStage::with_max_iterations(mutator, 1);
 
@@ -260,15 +1088,19 @@ fn fuzz(args: Args) -> Result<(), Error> {
 
         let mut fuzzer = StdFuzzer::new(scheduler, feedback, objective);
 
-        let mut executor = ForkserverExecutor::builder()
-            .program(&args.cmdline[0])
-            .debug_child(debug_child)
-            .parse_afl_cmdline(args.cmdline.get(1..).unwrap_or(&[]))
-            .coverage_map_size(map_size)
-            .is_persistent(false)
-            .timeout(timeout)
-            .kill_signal(signal)
-            .build_dynamic_map(edges_observer, tuple_list!(time_observer))?;
+        let delivery = args.delivery(core_id.0).map_err(Error::illegal_argument)?;
+        let mut executor = delivery.forkserver(map_size, edges_observer, tuple_list!(time_observer))?;
+
+        if args.keep_last_input {
+            let mut link = output_dir.join(format!("last-input-{}", core_id.0));
+
+            if let Some(extension) = &args.target.input_extension {
+                link.set_extension(extension.trim_start_matches('.'));
+            }
+
+            let _ = std::fs::remove_file(&link);
+            std::fs::hard_link(delivery.input_file(), &link)?;
+        }
 
         if state.corpus().count() == 0 {
             if let Some(corpus) = &args.corpus {
@@ -283,14 +1115,48 @@ fn fuzz(args: Args) -> Result<(), Error> {
             }
         }
 
-        let mut stages = tuple_list!(calibration, mutational);
+        let stats_interval = Duration::from_secs(args.stats_interval.unwrap_or(u64::MAX));
+        let stats = PeacockStatsStage::new(output_dir.join(format!("rule-stats-{}.json", core_id.0)), stats_interval);
+
+        let mut tokens = TokenCollectorStage::new(TOKEN_COLLECTION_INTERVAL)
+            .pool_file(output_dir.join(format!("dynamic-terminals-{}.dict", core_id.0)));
+
+        for dict in &args.dict {
+            tokens = tokens.token_file(dict);
+        }
+
+        #[cfg(not(feature = "static-loading"))]
+        let mut stages = {
+            let watch_interval = Duration::from_secs(args.watch_grammar.unwrap_or(u64::MAX));
+            let reload = GeneratorReloadStage::new(&args.grammar, watch_interval, || {
+                /* A broken grammar must not end the campaign, so it gets checked before compile_grammar() could exit */
+                check_grammar(&args)?;
+                Ok(compile_grammar(&args))
+            });
+
+            tuple_list!(calibration, mutational, stats, tokens, reload)
+        };
+
+        /* A linked generator cannot be swapped */
+        #[cfg(feature = "static-loading")]
+        let mut stages = tuple_list!(calibration, mutational, stats, tokens);
+
+        while !shutdown_requested(deadline) {
+            mgr.maybe_report_progress(&mut state, PROGRESS_INTERVAL)?;
+            fuzzer.fuzz_one(&mut stages, &mut executor, &mut state, &mut mgr)?;
+        }
 
-        fuzzer.fuzz_loop(&mut stages, &mut executor, &mut state, &mut mgr)?;
+        flush_corpus(&state)?;
+        mgr.report_progress(&mut state)?;
+        mgr.send_exiting()?;
         Ok(())
     };
 
     let shmem_provider = UnixShMemProvider::new()?;
 
+    #[cfg(not(debug_assertions))]
+    let terminal = TerminalState::save();
+
     #[cfg(not(debug_assertions))]
     let monitor = {
         let tui = TuiUI::new("peacock".to_string(), true);
@@ -300,9 +1166,9 @@ fn fuzz(args: Args) -> Result<(), Error> {
     #[cfg(debug_assertions)]
     let monitor = libafl::prelude::MultiMonitor::new(|s| println!("{}", s));
 
-    let cores = Cores::from_cmdline(&args.cores).expect("Invalid core specification");
+    let cores = Cores::from_cmdline(args.cores()).expect("Invalid core specification");
 
-    match Launcher::builder()
+    let result = match Launcher::builder()
         .shmem_provider(shmem_provider)
         .configuration(EventConfig::AlwaysUnique)
         .monitor(monitor)
@@ -313,11 +1179,317 @@ fn fuzz(args: Args) -> Result<(), Error> {
     {
         Err(Error::ShuttingDown) | Ok(()) => Ok(()),
         e => e,
+    };
+
+    /* Clients and respawners return here too */
+    if std::process::id() == launcher_pid {
+        /* The broker stops once all clients have exited or when it gets a signal. Then the clients still have to finish */
+        request_shutdown(0);
+
+        while waitpid(None, None) != Err(Errno::ECHILD) {}
+
+        #[cfg(not(debug_assertions))]
+        terminal.restore();
     }
+
+    result
 }
 
 pub fn main() {
     let args = Args::parse();
+    init_logging();
+
+    if args.check {
+        build_grammar(&args);
+        return;
+    }
+
+    if args.watch_grammar.is_some() && args.grammar == "-" {
+        eprintln!("--watch-grammar cannot be used with a grammar from stdin");
+        std::process::exit(1);
+    }
+
+    if cfg!(feature = "static-loading") && args.watch_grammar.is_some() {
+        eprintln!("--watch-grammar cannot be used with a generator that is linked into the fuzzer");
+        std::process::exit(1);
+    }
+
+    match args.delivery(0) {
+        Ok(delivery) if args.dry_run => {
+            println!("{}", delivery);
+            return;
+        },
+        Ok(_) => {},
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        },
+    }
+
     load_grammar(&args);
+    args.target.install_postprocessor();
     fuzz(args).expect("Could not launch fuzzer");
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Serializes the tests that replace the default generator
+    #[cfg(not(feature = "static-loading"))]
+    static DEFAULT_GENERATOR: std::sync::Mutex<()> = std::sync::Mutex::new(());
+
+    #[test]
+    #[cfg(not(feature = "static-loading"))]
+    fn test_concurrent_compilation() {
+        let output = std::env::temp_dir().join(format!("peacock-fuzz-test-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&output);
+        let args = Args::parse_from([
+            "peacock-fuzz",
+            "--cores",
+            "0",
+            "--grammar",
+            "test-data/grammars/unit_rules.json",
+            "-o",
+            &output.to_string_lossy(),
+            "true",
+        ]);
+
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                let args = args.clone();
+                std::thread::spawn(move || compile_grammar(&args))
+            })
+            .collect();
+
+        for thread in threads {
+            let generator_so = thread.join().unwrap();
+            let lib = unsafe { libloading::Library::new(&generator_so) }.unwrap();
+            unsafe { lib.get::<unsafe extern "C" fn()>(b"mutate_sequence") }.unwrap();
+        }
+
+        std::fs::remove_dir_all(&output).unwrap();
+    }
+
+    #[test]
+    #[cfg(not(feature = "static-loading"))]
+    fn test_reload_generator() {
+        let output = std::env::temp_dir().join(format!("peacock-reload-test-{}", std::process::id()));
+        let grammar = std::env::temp_dir().join(format!("peacock-reload-test-{}.json", std::process::id()));
+        let _ = std::fs::remove_dir_all(&output);
+        std::fs::write(&grammar, r#"{"<ENTRYPOINT>": [["'a'", "<ENTRYPOINT>"], ["'a'"]]}"#).unwrap();
+        let args = Args::parse_from([
+            "peacock-fuzz",
+            "--cores",
+            "0",
+            "--grammar",
+            &grammar.to_string_lossy(),
+            "-o",
+            &output.to_string_lossy(),
+            "--watch-grammar",
+            "1",
+            "true",
+        ]);
+
+        let _lock = DEFAULT_GENERATOR.lock().unwrap();
+        let old_so = compile_grammar(&args);
+        let old = load_generator(&old_so).generator;
+        let fingerprint = old.fingerprint();
+
+        std::fs::write(&grammar, "{").unwrap();
+        assert!(check_grammar(&args).is_err());
+
+        std::fs::write(&grammar, r#"{"<ENTRYPOINT>": [["'b'", "<ENTRYPOINT>"], ["'b'"]]}"#).unwrap();
+        check_grammar(&args).unwrap();
+        let new_so = compile_grammar(&args);
+        let new = reload_generator(&new_so);
+
+        assert_ne!(new.fingerprint(), fingerprint);
+        assert_ne!(new_so, old_so);
+        assert!(old_so.exists());
+        assert!(old.is_unloaded());
+        assert!(!new.is_unloaded());
+
+        std::fs::remove_dir_all(&output).unwrap();
+        std::fs::remove_file(&grammar).unwrap();
+    }
+
+    #[test]
+    #[cfg(not(feature = "static-loading"))]
+    fn test_stale_generator() {
+        let dir = std::env::temp_dir().join(format!("peacock-stale-test-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let args = |letter: char, force: bool| {
+            let grammar = dir.join(format!("{}.json", letter));
+            std::fs::write(&grammar, format!(r#"{{"<ENTRYPOINT>": [["'{0}'", "<ENTRYPOINT>"], ["'{0}'"]]}}"#, letter))
+                .unwrap();
+            let mut argv = vec![
+                "peacock-fuzz".to_string(),
+                "--cores".to_string(),
+                "0".to_string(),
+                "--grammar".to_string(),
+                grammar.to_string_lossy().into_owned(),
+                "-o".to_string(),
+                dir.join(format!("out-{}", letter)).to_string_lossy().into_owned(),
+            ];
+
+            if force {
+                argv.push("--force-stale-generator".to_string());
+            }
+
+            argv.push("true".to_string());
+            Args::parse_from(argv)
+        };
+        let a = args('a', false);
+        let b = args('b', false);
+        let digest_a = build_grammar(&a).digest();
+
+        /* Put the generator.so of b where the one of a is expected and keep the hash of a */
+        compile_grammar(&a);
+        let stale = std::fs::read(compile_grammar(&b)).unwrap();
+        let generator_so = Path::new(a.output()).join("generator.so");
+        std::fs::write(&generator_so, &stale).unwrap();
+
+        let _lock = DEFAULT_GENERATOR.lock().unwrap();
+        assert_ne!(load_grammar(&args('a', true)).digest().unwrap(), digest_a);
+        assert_eq!(std::fs::read(&generator_so).unwrap(), stale);
+
+        assert_eq!(load_grammar(&a).digest().unwrap(), digest_a);
+        assert_ne!(std::fs::read(&generator_so).unwrap(), stale);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("7200"), Ok(Duration::from_secs(7200)));
+        assert_eq!(parse_duration("90s"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_duration("2h"), Ok(Duration::from_secs(7200)));
+        assert_eq!(parse_duration("1h30m"), Ok(Duration::from_secs(5400)));
+        assert_eq!(parse_duration("1d"), Ok(Duration::from_secs(86400)));
+        assert!(parse_duration("").is_err());
+        assert!(parse_duration("h").is_err());
+        assert!(parse_duration("2h30").is_err());
+        assert!(parse_duration("5w").is_err());
+
+        let args = Args::parse_from(["peacock-fuzz", "--grammar", "grammar.json", "--timeout-total", "2h", "--check"]);
+        assert_eq!(args.timeout_total, Some(Duration::from_secs(7200)));
+    }
+
+    #[test]
+    fn test_parse_probability() {
+        assert_eq!(parse_probability("0"), Ok(0.0));
+        assert_eq!(parse_probability("0.05"), Ok(0.05));
+        assert_eq!(parse_probability("1"), Ok(1.0));
+        assert!(parse_probability("1.5").is_err());
+        assert!(parse_probability("-0.1").is_err());
+        assert!(parse_probability("NaN").is_err());
+
+        let args = Args::parse_from(["peacock-fuzz", "--grammar", "grammar.json", "--check"]);
+        assert_eq!(args.corruption_probability, 0.0);
+    }
+
+    #[test]
+    fn test_execution_options() {
+        let parse = |extra: &[&str]| {
+            let mut argv = vec!["peacock-fuzz", "--grammar", "grammar.json", "--check"];
+            argv.extend_from_slice(extra);
+            Args::try_parse_from(argv)
+        };
+
+        let args = parse(&[]).unwrap();
+        assert_eq!(args.target.timeout, DEFAULT_TIMEOUT);
+        assert_eq!(args.target.kill_signal, Signal::SIGKILL);
+        assert_eq!(args.target.map_size(), DEFAULT_MAP_SIZE);
+        assert_eq!(args.power_schedule, Schedule::Explore);
+        assert_eq!(args.cache_size, DEFAULT_CACHE_SIZE);
+
+        let args = parse(&[
+            "--timeout",
+            "500",
+            "--kill-signal",
+            "term",
+            "--map-size",
+            "100",
+            "--power-schedule",
+            "quad",
+            "--cache-size",
+            "16",
+        ])
+        .unwrap();
+        assert_eq!(args.target.timeout, 500);
+        assert_eq!(args.target.kill_signal, Signal::SIGTERM);
+        assert_eq!(args.target.map_size(), 128);
+        assert_eq!(power_schedule(args.power_schedule), PowerSchedule::QUAD);
+        assert_eq!(args.cache_size, 16);
+
+        let delivery = InputDelivery::new(&args.target, &["./target".to_string()], 0).unwrap();
+        assert_eq!(delivery.timeout, Duration::from_millis(500));
+        assert_eq!(delivery.kill_signal, Signal::SIGTERM);
+
+        assert_eq!(parse_signal("9"), Ok(Signal::SIGKILL));
+        assert_eq!(parse_signal("SIGINT"), Ok(Signal::SIGINT));
+        assert_eq!(parse_map_size("64"), Ok(64));
+        assert_eq!(parse_map_size("65"), Ok(128));
+
+        for invalid in [
+            &["--timeout", "0"][..],
+            &["--kill-signal", "SIGFOO"],
+            &["--kill-signal", "1000"],
+            &["--map-size", "0"],
+            &["--map-size", "-1"],
+            &["--power-schedule", "rare"],
+            &["--cache-size", "0"],
+        ] {
+            assert!(parse(invalid).is_err(), "{:?}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_power_schedule() {
+        for (schedule, expected) in [
+            (Schedule::Explore, PowerSchedule::EXPLORE),
+            (Schedule::Fast, PowerSchedule::FAST),
+            (Schedule::Coe, PowerSchedule::COE),
+            (Schedule::Lin, PowerSchedule::LIN),
+            (Schedule::Quad, PowerSchedule::QUAD),
+            (Schedule::Exploit, PowerSchedule::EXPLOIT),
+        ] {
+            assert_eq!(power_schedule(schedule), expected);
+            /* The default value of clap is the name of the schedule */
+            assert_eq!(<Schedule as clap::ValueEnum>::from_str(&schedule.to_string(), false), Ok(schedule));
+        }
+    }
+
+    #[test]
+    fn test_input_delivery() {
+        let parse = |extra: &[&str], cmdline: &[&str]| {
+            let mut argv = vec!["peacock-fuzz", "--cores", "0", "--grammar", "grammar.json", "-o", "out"];
+            argv.extend_from_slice(extra);
+            argv.extend_from_slice(cmdline);
+            Args::parse_from(argv).delivery(3)
+        };
+
+        let delivery = parse(&[], &["./target", "-v"]).unwrap();
+        assert!(delivery.stdin);
+        assert_eq!(delivery.args, vec![TargetArg::Plain("-v".to_string())]);
+
+        let delivery = parse(&[], &["./target", "@@", "-v"]).unwrap();
+        assert!(!delivery.stdin);
+        assert_eq!(delivery.args, vec![TargetArg::Input, TargetArg::Plain("-v".to_string())]);
+
+        let delivery =
+            parse(&["--file", "--input-file", "/tmp/in-{core}", "--input-extension", ".json"], &["./target"]).unwrap();
+        assert!(!delivery.stdin);
+        assert_eq!(delivery.args, vec![TargetArg::Input]);
+        assert_eq!(delivery.input_file, PathBuf::from("/tmp/in-3.json"));
+        assert_eq!(delivery.to_string(), "./target /tmp/in-3.json");
+
+        assert!(parse(&["--stdin"], &["./target", "@@"]).is_err());
+        assert!(parse(&["--stdin", "--input-extension", "json"], &["./target"]).is_err());
+
+        assert!(Args::try_parse_from(["peacock-fuzz", "--grammar", "grammar.json", "--check"]).is_ok());
+        assert!(Args::try_parse_from(["peacock-fuzz", "--grammar", "grammar.json"]).is_err());
+    }
+}
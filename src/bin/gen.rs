@@ -1,23 +1,50 @@
+use ahash::RandomState;
 use clap::Parser;
 use peacock_fuzz::{
     backends::interpreter::GrammarInterpreter,
     grammar::ContextFreeGrammar,
 };
 use std::{
+    collections::HashSet,
     io::{
         stdout,
-        BufWriter,
         Write,
     },
-    time::SystemTime,
+    path::Path,
+    sync::{
+        atomic::{
+            AtomicUsize,
+            Ordering,
+        },
+        Mutex,
+    },
+    time::{
+        Instant,
+        SystemTime,
+    },
 };
 
 pub mod fuzz;
-use fuzz::GrammarFormat;
+use fuzz::{
+    add_grammar,
+    init_logging,
+    GrammarFormat,
+    OrExit,
+};
+
+/// How many bytes a thread collects before it writes them to stdout
+const STDOUT_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Give up on finding new unique inputs after this many duplicates in a row
+const MAX_DUPLICATES: usize = 10_000;
+
+/// How many inputs --cover generates at most if no limit is given
+const DEFAULT_COVER_LIMIT: &str = "100000";
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Args {
+    /// Path to the grammar or - to read it from stdin
     #[arg(short, long)]
     grammar: String,
 
@@ -35,44 +62,331 @@ struct Args {
 
     #[arg(long, short)]
     seed: Option<String>,
+
+    #[arg(long)]
+    max_len: Option<usize>,
+
+    /// Generate inputs in JOBS threads
+    #[arg(long, short, value_name = "JOBS", default_value_t = 1)]
+    jobs: usize,
+
+    /// Write every input into its own file in the directory DIR instead of printing it.
+    /// Every thread gets its own subdirectory.
+    #[arg(long, short, value_name = "DIR")]
+    output: Option<String>,
+
+    /// Don't generate the same input twice
+    #[arg(long, default_value_t = false)]
+    unique: bool,
+
+    /// Generate every distinct input whose derivation is at most --max-depth levels deep instead of
+    /// random ones. --count, --jobs and --seed are ignored.
+    #[arg(long, default_value_t = false, requires = "max_depth")]
+    enumerate: bool,
+
+    #[arg(long, value_name = "N")]
+    max_depth: Option<usize>,
+
+    /// Generate inputs until every rule of the grammar was used or MAX inputs were generated.
+    /// Only the inputs that use new rules are kept. --count, --jobs and --unique are ignored.
+    #[arg(long, value_name = "MAX", num_args = 0..=1, default_missing_value = DEFAULT_COVER_LIMIT, conflicts_with = "enumerate")]
+    cover: Option<usize>,
+
+    /// Only check that the grammar is valid and exit
+    #[arg(long, default_value_t = false)]
+    check: bool,
 }
 
-fn main() {
-    let args = Args::parse();
+/// Generate inputs until `counter` reaches `count`. Returns the number of inputs this worker generated.
+fn worker(
+    args: &Args,
+    mut interpreter: GrammarInterpreter,
+    job: usize,
+    count: usize,
+    counter: &AtomicUsize,
+    seen: &Mutex<HashSet<u64>>,
+) -> usize {
+    let max_len = args.max_len.unwrap_or(usize::MAX);
+    let hasher = RandomState::with_seeds(0, 0, 0, 0);
+    let mut buf = Vec::new();
+    // Threads write whole inputs to stdout such that they don't interleave
+    let mut pending = Vec::new();
+    let mut generated = 0;
+    let dir = args.output.as_ref().map(|output| Path::new(output).join(format!("job{}", job)));
 
-    let count = args.count.parse::<usize>().unwrap();
+    if let Some(dir) = &dir {
+        std::fs::create_dir_all(dir).expect("Could not create output directory");
+    }
+
+    'outer: while counter.fetch_add(1, Ordering::Relaxed) < count {
+        let mut duplicates = 0;
+
+        loop {
+            interpreter.interpret_into(&mut buf, max_len, None);
+
+            if !args.unique || seen.lock().unwrap().insert(hasher.hash_one(&buf)) {
+                break;
+            }
+
+            duplicates += 1;
+
+            if duplicates >= MAX_DUPLICATES {
+                eprintln!("Job {} could not find any more unique inputs", job);
+                break 'outer;
+            }
+        }
+
+        if let Some(dir) = &dir {
+            std::fs::write(dir.join(format!("{}", generated)), &buf).expect("Could not write input");
+        } else {
+            pending.extend_from_slice(&buf);
+            pending.push(b'\n');
+
+            if pending.len() >= STDOUT_CHUNK_SIZE {
+                stdout().lock().write_all(&pending).unwrap();
+                pending.clear();
+            }
+        }
+
+        generated += 1;
+    }
+
+    let mut stream = stdout().lock();
+    stream.write_all(&pending).unwrap();
+    stream.flush().unwrap();
+
+    generated
+}
+
+/// Write all inputs up to the maximum depth. Returns the number of inputs written.
+fn enumerate(args: &Args, interpreter: &GrammarInterpreter) -> usize {
+    let max_len = args.max_len.unwrap_or(usize::MAX);
+    let mut stream = stdout().lock();
+    let mut generated = 0;
+
+    if let Some(dir) = &args.output {
+        std::fs::create_dir_all(dir).expect("Could not create output directory");
+    }
+
+    for input in interpreter.enumerate(args.max_depth.unwrap()).filter(|input| input.len() <= max_len) {
+        if let Some(dir) = &args.output {
+            std::fs::write(Path::new(dir).join(format!("{}", generated)), &input).expect("Could not write input");
+        } else {
+            stream.write_all(&input).unwrap();
+            stream.write_all(b"\n").unwrap();
+        }
+
+        generated += 1;
+    }
+
+    stream.flush().unwrap();
+    generated
+}
+
+/// Generate inputs until all rules are covered. Returns the number of inputs written.
+fn cover(args: &Args, mut interpreter: GrammarInterpreter, seed: u64, limit: usize) -> usize {
+    let max_len = args.max_len.unwrap_or(usize::MAX);
+    let mut stream = stdout().lock();
+    let mut buf = Vec::new();
+    let mut generated = 0;
+    let (mut covered, total) = interpreter.rule_coverage();
+
+    if let Some(dir) = &args.output {
+        std::fs::create_dir_all(dir).expect("Could not create output directory");
+    }
+
+    interpreter.seed(seed);
+    interpreter.coverage_guided(true);
+
+    for _ in 0..limit {
+        if covered == total {
+            break;
+        }
+
+        interpreter.interpret_into(&mut buf, max_len, None);
+        let now_covered = interpreter.rule_coverage().0;
+
+        if now_covered == covered {
+            continue;
+        }
+
+        covered = now_covered;
 
-    let mut builder = ContextFreeGrammar::builder();
+        if let Some(dir) = &args.output {
+            std::fs::write(Path::new(dir).join(format!("{}", generated)), &buf).expect("Could not write input");
+        } else {
+            stream.write_all(&buf).unwrap();
+            stream.write_all(b"\n").unwrap();
+        }
 
-    match args.format {
-        GrammarFormat::Peacock => builder = builder.peacock_grammar(args.grammar).unwrap(),
-        GrammarFormat::Gramatron => builder = builder.gramatron_grammar(args.grammar).unwrap(),
+        generated += 1;
     }
 
-    if let Some(entrypoint) = args.entrypoint {
+    stream.flush().unwrap();
+    eprintln!("Covered {}/{} rules ({:.1}%)", covered, total, 100.0 * covered as f64 / total as f64);
+
+    for (nonterm, rule) in interpreter.uncovered_rules() {
+        eprintln!("  Uncovered: rule #{} of <{}>", rule, nonterm);
+    }
+
+    generated
+}
+
+/// Generate the inputs requested by `args`. Returns the number of inputs generated.
+fn run(args: &Args) -> usize {
+    let mut builder = add_grammar(ContextFreeGrammar::builder(), args.format, &args.grammar);
+
+    if let Some(entrypoint) = &args.entrypoint {
         builder = builder.entrypoint(entrypoint);
     }
 
     builder = builder.optimize(!args.dont_optimize);
 
-    let cfg = builder.build().unwrap();
+    let cfg = builder.build().or_exit();
+
+    if args.check {
+        return 0;
+    }
+
+    let interpreter = GrammarInterpreter::new(&cfg);
 
-    let mut stream = BufWriter::new(stdout());
-    let mut interpreter = GrammarInterpreter::new(&cfg);
+    if args.enumerate {
+        return enumerate(args, &interpreter);
+    }
 
-    if let Some(seed) = args.seed {
-        let seed = seed.parse::<usize>().unwrap();
-        interpreter.seed(seed);
+    let seed = if let Some(seed) = &args.seed {
+        seed.parse::<u64>().unwrap()
     } else {
-        let seed =
-            SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs() ^ std::process::id() as u64;
-        interpreter.seed(seed as usize);
+        SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs() ^ std::process::id() as u64
+    };
+
+    if let Some(limit) = args.cover {
+        return cover(args, interpreter, seed, limit);
+    }
+
+    let count = args.count.parse::<usize>().unwrap();
+
+    let counter = AtomicUsize::new(0);
+    let seen = Mutex::new(HashSet::new());
+
+    std::thread::scope(|scope| {
+        let workers: Vec<_> = (0..args.jobs.max(1))
+            .map(|job| {
+                let mut interpreter = interpreter.clone();
+                interpreter.seed(seed.wrapping_add((job as u64).wrapping_mul(0x9e3779b97f4a7c15)));
+                let (counter, seen) = (&counter, &seen);
+                scope.spawn(move || worker(args, interpreter, job, count, counter, seen))
+            })
+            .collect();
+
+        workers.into_iter().map(|worker| worker.join().unwrap()).sum()
+    })
+}
+
+fn main() {
+    let args = Args::parse();
+    init_logging();
+    let start = Instant::now();
+    let generated = run(&args);
+    let elapsed = start.elapsed().as_secs_f64();
+
+    if !args.check && (args.jobs > 1 || args.output.is_some() || args.enumerate || args.cover.is_some()) {
+        eprintln!("Generated {} inputs in {:.2}s ({:.0} inputs/s)", generated, elapsed, generated as f64 / elapsed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_jobs() {
+        let output = std::env::temp_dir().join(format!("peacock-gen-test-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&output);
+        let args = Args::parse_from([
+            "peacock-gen",
+            "--grammar",
+            "test-data/grammars/regex.json",
+            "--count",
+            "500",
+            "--jobs",
+            "4",
+            "--unique",
+            "--seed",
+            "1",
+            "--output",
+            &output.to_string_lossy(),
+        ]);
+
+        assert_eq!(run(&args), 500);
+
+        let mut inputs = HashSet::new();
+
+        for job in std::fs::read_dir(&output).unwrap() {
+            for file in std::fs::read_dir(job.unwrap().path()).unwrap() {
+                assert!(inputs.insert(std::fs::read(file.unwrap().path()).unwrap()));
+            }
+        }
+
+        assert_eq!(inputs.len(), 500);
+        std::fs::remove_dir_all(&output).unwrap();
     }
 
-    for _ in 0..count {
-        interpreter.interpret(&mut stream).unwrap();
-        writeln!(&mut stream).unwrap();
+    #[test]
+    fn test_enumerate() {
+        let output = std::env::temp_dir().join(format!("peacock-gen-enumerate-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&output);
+        let args = Args::parse_from([
+            "peacock-gen",
+            "--grammar",
+            "test-data/grammars/dynamic.json",
+            "--enumerate",
+            "--max-depth",
+            "10",
+            "--output",
+            &output.to_string_lossy(),
+        ]);
+
+        assert_eq!(run(&args), 2);
+        assert!(Args::try_parse_from(["peacock-gen", "--grammar", "x", "--enumerate"]).is_err());
+
+        let mut inputs: Vec<Vec<u8>> =
+            std::fs::read_dir(&output).unwrap().map(|file| std::fs::read(file.unwrap().path()).unwrap()).collect();
+        inputs.sort();
+        assert_eq!(inputs, [&b"if x == 0;"[..], b"if x == ;"]);
+        std::fs::remove_dir_all(&output).unwrap();
     }
 
-    stream.flush().unwrap();
+    #[test]
+    fn test_cover() {
+        let output = std::env::temp_dir().join(format!("peacock-gen-cover-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&output);
+        let args = Args::parse_from([
+            "peacock-gen",
+            "--grammar",
+            "test-data/grammars/regex.json",
+            "--cover",
+            "--seed",
+            "1",
+            "--output",
+            &output.to_string_lossy(),
+        ]);
+        assert_eq!(args.cover, Some(100000));
+
+        /* Every kept input uses at least one new rule */
+        let cfg =
+            ContextFreeGrammar::builder().peacock_grammar("test-data/grammars/regex.json").unwrap().build().unwrap();
+        let (_, total) = GrammarInterpreter::new(&cfg).rule_coverage();
+        let kept = run(&args);
+        assert!((1..=total).contains(&kept));
+        assert_eq!(std::fs::read_dir(&output).unwrap().count(), kept);
+        std::fs::remove_dir_all(&output).unwrap();
+    }
+
+    #[test]
+    fn test_check() {
+        let args =
+            Args::parse_from(["peacock-gen", "--grammar", "test-data/grammars/regex.json", "--check", "--count", "10"]);
+        assert_eq!(run(&args), 0);
+    }
 }
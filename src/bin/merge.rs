@@ -2,7 +2,11 @@ use clap::Parser;
 
 use peacock_fuzz::{
     backends::json::JsonGenerator,
-    grammar::ContextFreeGrammar,
+    grammar::{
+        lint,
+        ContextFreeGrammar,
+        LintSeverity,
+    },
 };
 
 #[derive(Parser, Debug)]
@@ -22,6 +26,51 @@ struct Args {
 
     #[arg(long, default_value_t = false)]
     optimize: bool,
+
+    #[arg(long, value_name = "NONTERM")]
+    subset: Option<String>,
+
+    /// Print duplicate alternatives and other problems of the resulting grammar.
+    /// Exits with 1 if one of them is an error
+    #[arg(long, default_value_t = false)]
+    lint: bool,
+
+    /// Print the number of rules before and after every transformation as a table
+    #[arg(long, default_value_t = false)]
+    trace: bool,
+
+    /// Print the number of rules before and after every transformation as JSON
+    #[arg(long, default_value_t = false)]
+    trace_json: bool,
+
+    /// Start the output with a comment that lists the merged files and the options of the merge
+    #[arg(long, default_value_t = false)]
+    provenance: bool,
+}
+
+/// The comment that --provenance writes before the merged grammar
+fn provenance(args: &Args) -> String {
+    let mut comment = String::from("Generated by peacock-merge from:\n");
+
+    for path in &args.peacock_grammar {
+        comment.push_str(&format!("  {} (peacock)\n", path));
+    }
+
+    for path in &args.gramatron_grammar {
+        comment.push_str(&format!("  {} (gramatron)\n", path));
+    }
+
+    comment.push_str(&format!("optimize: {}", args.optimize));
+
+    if let Some(entrypoint) = &args.entrypoint {
+        comment.push_str(&format!(", entrypoint: {}", entrypoint));
+    }
+
+    if let Some(root) = &args.subset {
+        comment.push_str(&format!(", subset: {}", root));
+    }
+
+    comment
 }
 
 fn main() {
@@ -43,10 +92,51 @@ fn main() {
 
     builder = builder.optimize(args.optimize);
 
-    if let Some(entrypoint) = args.entrypoint {
+    if let Some(entrypoint) = &args.entrypoint {
         builder = builder.entrypoint(entrypoint);
     }
 
-    let cfg = builder.build().unwrap();
-    JsonGenerator::new().generate(args.output, &cfg);
+    let mut cfg = if args.trace || args.trace_json {
+        let (cfg, trace) = builder.build_with_trace().unwrap();
+
+        if args.trace_json {
+            println!("{}", serde_json::to_string_pretty(&trace).unwrap());
+        } else {
+            print!("{}", trace);
+        }
+
+        cfg
+    } else {
+        builder.build().unwrap()
+    };
+
+    if let Some(root) = &args.subset {
+        cfg = cfg.subgrammar(root).unwrap();
+    }
+
+    let findings = if args.lint { lint(&cfg) } else { Vec::new() };
+
+    for finding in &findings {
+        println!("{}", finding);
+    }
+
+    let mut generator = JsonGenerator::new();
+
+    if args.provenance {
+        generator = generator.with_header(provenance(&args));
+    }
+
+    /* The metadata of the first grammar that has some survives the merge */
+    for path in &args.peacock_grammar {
+        if let Some(metadata) = JsonGenerator::read_metadata(path).unwrap() {
+            generator = generator.with_metadata(metadata);
+            break;
+        }
+    }
+
+    generator.generate(&args.output, &cfg);
+
+    if findings.iter().any(|finding| finding.severity == LintSeverity::Error) {
+        std::process::exit(1);
+    }
 }
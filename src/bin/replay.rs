@@ -0,0 +1,342 @@
+use clap::Parser;
+use libafl::prelude::{
+    ConstFeedback,
+    Error,
+    ExitKind,
+    HasTargetBytes,
+    HitcountsMapObserver,
+    InMemoryCorpus,
+    Input,
+    NopEventManager,
+    QueueScheduler,
+    StdFuzzer,
+    StdMapObserver,
+    StdState,
+};
+use libafl_bolts::prelude::{
+    tuple_list,
+    AsSlice,
+    AsSliceMut,
+    StdRand,
+};
+use nix::{
+    sys::wait::WaitStatus,
+    unistd::Pid,
+};
+use peacock_fuzz::components::{
+    load_dynamic_terminals,
+    load_generator,
+    PeacockInput,
+};
+use std::path::{
+    Path,
+    PathBuf,
+};
+
+pub mod fuzz;
+use fuzz::{
+    coverage_map,
+    InputDelivery,
+    TargetOptions,
+};
+
+/// Exit code if at least one input crashed the target
+const EXIT_CRASH: i32 = 1;
+
+/// Exit code if at least one input timed out and none crashed
+const EXIT_TIMEOUT: i32 = 124;
+
+/// Exit code if at least one input could not be loaded or ended with an unknown status
+/// and all others exited cleanly
+const EXIT_INVALID: i32 = 125;
+
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+struct Args {
+    /// The generator.so that the inputs belong to
+    #[arg(short, long)]
+    generator: String,
+
+    /// A single input or a directory of inputs to execute, e.g. the crashes directory of peacock-fuzz
+    #[arg(short, long)]
+    input: String,
+
+    /// Also write the bytes that the target received for every input into this directory
+    #[arg(long, value_name = "DIR")]
+    output_bytes: Option<String>,
+
+    /// The dynamic-terminals-N.dict file from the output directory of peacock-fuzz.
+    /// Without it, inputs that contain terminals of <DYNAMIC> are not serialized like during fuzzing
+    #[arg(long, value_name = "FILE")]
+    dynamic_terminals: Option<String>,
+
+    #[command(flatten)]
+    target: TargetOptions,
+
+    #[arg(trailing_var_arg = true, allow_hyphen_values = true, required = true)]
+    cmdline: Vec<String>,
+}
+
+/// How a single execution of the target ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Outcome {
+    /// The input could not be loaded
+    Invalid,
+    Exited(i32),
+    /// The target neither exited nor crashed nor timed out, or its status could not be collected
+    Unknown,
+    Crashed(Option<nix::sys::signal::Signal>),
+    TimedOut,
+}
+
+impl Outcome {
+    /// The exit code of the process if this is the most severe outcome. Crashes outweigh timeouts,
+    /// which outweigh inputs that could not be loaded.
+    fn exit_code(&self) -> i32 {
+        match self {
+            Outcome::Exited(_) => 0,
+            Outcome::Invalid | Outcome::Unknown => EXIT_INVALID,
+            Outcome::TimedOut => EXIT_TIMEOUT,
+            Outcome::Crashed(_) => EXIT_CRASH,
+        }
+    }
+
+    fn severity(&self) -> usize {
+        match self {
+            Outcome::Exited(_) => 0,
+            Outcome::Invalid | Outcome::Unknown => 1,
+            Outcome::TimedOut => 2,
+            Outcome::Crashed(_) => 3,
+        }
+    }
+}
+
+impl std::fmt::Display for Outcome {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Outcome::Invalid => write!(f, "could not be loaded"),
+            Outcome::Exited(status) => write!(f, "exited with status {}", status),
+            Outcome::Unknown => write!(f, "ended with an unknown status"),
+            Outcome::Crashed(Some(signal)) => write!(f, "crashed with {}", signal),
+            Outcome::Crashed(None) => write!(f, "crashed"),
+            Outcome::TimedOut => write!(f, "timed out"),
+        }
+    }
+}
+
+/// The inputs in `input` if it is a directory, sorted by name, or `input` itself
+fn collect_inputs(input: &Path) -> Result<Vec<PathBuf>, Error> {
+    if !input.is_dir() {
+        return Ok(vec![input.to_path_buf()]);
+    }
+
+    let mut paths = Vec::new();
+
+    for entry in std::fs::read_dir(input)? {
+        let path = entry?.path();
+
+        if path.is_file() {
+            paths.push(path);
+        }
+    }
+
+    paths.sort();
+    Ok(paths)
+}
+
+/// Execute every input once with the same forkserver configuration as peacock-fuzz and print how it ended
+fn replay(args: &Args) -> Result<Vec<(PathBuf, Outcome)>, Error> {
+    let map_size = args.target.map_size();
+    let mut shmem = coverage_map(map_size)?;
+    let edges_observer = unsafe { HitcountsMapObserver::new(StdMapObserver::new("shared_mem", shmem.as_slice_mut())) };
+
+    let mut feedback = ConstFeedback::new(false);
+    let mut objective = ConstFeedback::new(false);
+    let mut state = StdState::new(
+        StdRand::with_seed(0),
+        InMemoryCorpus::<PeacockInput>::new(),
+        InMemoryCorpus::new(),
+        &mut feedback,
+        &mut objective,
+    )?;
+    let mut fuzzer = StdFuzzer::new(QueueScheduler::new(), feedback, objective);
+    let mut mgr = NopEventManager::new();
+
+    let delivery = InputDelivery::new(&args.target, &args.cmdline, 0).map_err(Error::illegal_argument)?;
+    let mut executor = delivery.forkserver(map_size, edges_observer, tuple_list!())?;
+
+    if let Some(dir) = &args.output_bytes {
+        std::fs::create_dir_all(dir)?;
+    }
+
+    let mut results = Vec::new();
+
+    for path in collect_inputs(Path::new(&args.input))? {
+        let input = match PeacockInput::from_file(&path) {
+            Ok(input) => input,
+            Err(err) => {
+                println!("{}: {} ({})", path.display(), Outcome::Invalid, err);
+                results.push((path, Outcome::Invalid));
+                continue;
+            },
+        };
+
+        if let Some(dir) = &args.output_bytes {
+            std::fs::write(Path::new(dir).join(path.file_name().unwrap()), input.target_bytes().as_slice())?;
+        }
+
+        let exit_kind = fuzzer.execute_input(&mut state, &mut executor, &mut mgr, &input)?;
+        let status = WaitStatus::from_raw(Pid::from_raw(0), executor.forkserver().status());
+        let outcome = match (exit_kind, status) {
+            (ExitKind::Timeout, _) => Outcome::TimedOut,
+            (ExitKind::Crash, Ok(WaitStatus::Signaled(_, signal, _))) => Outcome::Crashed(Some(signal)),
+            (ExitKind::Crash, _) => Outcome::Crashed(None),
+            (_, Ok(WaitStatus::Exited(_, status))) => Outcome::Exited(status),
+            (_, _) => Outcome::Unknown,
+        };
+
+        println!("{}: {}", path.display(), outcome);
+        results.push((path, outcome));
+    }
+
+    Ok(results)
+}
+
+fn run(args: &Args) -> i32 {
+    load_generator(&args.generator);
+    if let Some(path) = &args.dynamic_terminals {
+        load_dynamic_terminals(path).expect("Could not load dynamic terminals");
+    }
+    args.target.install_postprocessor();
+
+    let results = replay(args).expect("Could not replay inputs");
+
+    results.iter().map(|(_, outcome)| outcome).max_by_key(|outcome| outcome.severity()).map_or(0, Outcome::exit_code)
+}
+
+fn main() {
+    let args = Args::parse();
+    std::process::exit(run(&args));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use peacock_fuzz::{
+        backends::C::{
+            compile,
+            CGenerator,
+            CompileOptions,
+        },
+        grammar::ContextFreeGrammar,
+    };
+
+    /// A target that speaks the forkserver protocol, aborts if its input contains a 'c'
+    /// and exits with 3 if its input contains a 'b'
+    const TARGET: &str = r#"
+#include <stdint.h>
+#include <stdlib.h>
+#include <string.h>
+#include <unistd.h>
+#include <sys/wait.h>
+
+int main (void) {
+    uint32_t msg = 0x41464c01;
+
+    if (write(199, &msg, 4) != 4 || read(198, &msg, 4) != 4) {
+        return 1;
+    }
+
+    msg = 0;
+    write(199, &msg, 4);
+    msg = 0x41464c01;
+    write(199, &msg, 4);
+
+    while (read(198, &msg, 4) == 4) {
+        pid_t pid = fork();
+
+        if (!pid) {
+            char buf[256] = {0};
+            read(0, buf, sizeof(buf) - 1);
+
+            if (strchr(buf, 'c')) {
+                abort();
+            }
+
+            return strchr(buf, 'b') ? 3 : 0;
+        }
+
+        int status = 0;
+        write(199, &pid, 4);
+        waitpid(pid, &status, 0);
+        write(199, &status, 4);
+    }
+
+    return 0;
+}
+"#;
+
+    #[test]
+    fn test_replay() {
+        let dir = std::env::temp_dir().join(format!("peacock-replay-{}", std::process::id()));
+        let inputs = dir.join("inputs");
+        let bytes = dir.join("bytes");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&inputs).unwrap();
+
+        let grammar = dir.join("grammar.json");
+        std::fs::write(
+            &grammar,
+            r#"{"<ENTRYPOINT>": [["<CHAR>", "<ENTRYPOINT>"], ["<CHAR>"]], "<CHAR>": [["'a'"], ["'b'"], ["'c'"]]}"#,
+        )
+        .unwrap();
+        let cfg = ContextFreeGrammar::builder().peacock_grammar(&grammar).unwrap().build().unwrap();
+        let generator = dir.join("generator.so");
+        let sources = CGenerator::new().generate(dir.join("generator.c"), &cfg);
+        compile(&generator, &sources, &CompileOptions::new()).unwrap();
+
+        let target = dir.join("target");
+        std::fs::write(dir.join("target.c"), TARGET).unwrap();
+        compile(&target, &[dir.join("target.c")], &CompileOptions::new().flags(["-O0"])).unwrap();
+
+        for (name, content) in [("1", "aa"), ("2", "ab"), ("3", "xyz")] {
+            std::fs::write(inputs.join(name), content).unwrap();
+        }
+
+        let parse = |input: &Path| {
+            Args::parse_from([
+                "peacock-replay",
+                "--generator",
+                &generator.to_string_lossy(),
+                "--input",
+                &input.to_string_lossy(),
+                "--output-bytes",
+                &bytes.to_string_lossy(),
+                "--",
+                &target.to_string_lossy(),
+            ])
+        };
+
+        load_generator(&generator);
+        let outcomes: Vec<Outcome> =
+            replay(&parse(&inputs)).unwrap().into_iter().map(|(_, outcome)| outcome).collect();
+        assert_eq!(outcomes, vec![Outcome::Exited(0), Outcome::Exited(3), Outcome::Invalid]);
+        assert_eq!(std::fs::read_to_string(bytes.join("2")).unwrap(), "ab");
+        assert_eq!(run(&parse(&inputs)), EXIT_INVALID);
+
+        std::fs::write(inputs.join("4"), "bca").unwrap();
+        assert_eq!(
+            replay(&parse(&inputs.join("4"))).unwrap(),
+            vec![(inputs.join("4"), Outcome::Crashed(Some(nix::sys::signal::Signal::SIGABRT)))]
+        );
+        assert_eq!(run(&parse(&inputs)), EXIT_CRASH);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_unknown_outcome() {
+        assert_eq!(Outcome::Unknown.exit_code(), EXIT_INVALID);
+        assert!(Outcome::Unknown.severity() > Outcome::Exited(0).severity());
+    }
+}
@@ -0,0 +1,107 @@
+use clap::Parser;
+use peacock_fuzz::{
+    analysis::{
+        validate_corpus,
+        ValidationReport,
+    },
+    grammar::ContextFreeGrammar,
+};
+
+pub mod fuzz;
+use fuzz::{
+    add_grammar,
+    init_logging,
+    GrammarFormat,
+    OrExit,
+};
+
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+struct Args {
+    /// Path to the grammar or - to read it from stdin
+    #[arg(long, value_name = "GRAMMAR")]
+    grammar: String,
+
+    #[arg(long, default_value_t = GrammarFormat::Peacock)]
+    format: GrammarFormat,
+
+    #[arg(short, long)]
+    entrypoint: Option<String>,
+
+    /// Print the report as JSON
+    #[arg(long, default_value_t = false)]
+    json: bool,
+
+    /// A directory of sample inputs
+    corpus: String,
+}
+
+fn validate(args: &Args) -> ValidationReport {
+    let mut builder = add_grammar(ContextFreeGrammar::builder(), args.format, &args.grammar);
+
+    if let Some(entrypoint) = &args.entrypoint {
+        builder = builder.entrypoint(entrypoint);
+    }
+
+    /* Report the rules as they were written */
+    let cfg = builder.optimize(false).build().or_exit();
+
+    validate_corpus(&cfg, &args.corpus).expect("Could not read corpus")
+}
+
+fn main() {
+    let args = Args::parse();
+    init_logging();
+
+    let report = validate(&args);
+
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(&report).unwrap());
+    } else {
+        print!("{}", report);
+    }
+
+    if !report.failures.is_empty() {
+        std::process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate() {
+        let dir = std::env::temp_dir().join(format!("peacock-validate-bin-{}", std::process::id()));
+        let corpus = dir.join("corpus");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&corpus).unwrap();
+
+        let grammar = dir.join("grammar.json");
+        std::fs::write(
+            &grammar,
+            r#"{"<ENTRYPOINT>": [["<CHAR>", "<ENTRYPOINT>"], ["<CHAR>"]], "<CHAR>": [["'a'"], ["'b'"], ["'c'"]]}"#,
+        )
+        .unwrap();
+
+        for (name, content) in [("1", "abba"), ("2", "ab!"), ("3", "b")] {
+            std::fs::write(corpus.join(name), content).unwrap();
+        }
+
+        let args = Args::parse_from([
+            "peacock-validate",
+            "--grammar",
+            &grammar.to_string_lossy(),
+            "--json",
+            &corpus.to_string_lossy(),
+        ]);
+        assert!(args.json);
+
+        let report = validate(&args);
+        assert_eq!((report.files, report.parsed), (3, 2));
+        assert_eq!(report.failures[0].offset, 2);
+        assert_eq!((report.used_rules, report.total_rules), (5, 6));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,355 @@
+use libafl::{
+    prelude::{
+        BytesInput,
+        Corpus,
+        Error,
+        HasCorpus,
+        HasMutatorBytes,
+        HasTargetBytes,
+        MutationResult,
+        Mutator,
+        Testcase,
+    },
+    stages::mutational::MutatedTransform,
+};
+use libafl_bolts::prelude::{
+    AsSlice,
+    HasLen,
+    Named,
+};
+use std::{
+    borrow::Cow,
+    ops::RangeBounds,
+    vec::{
+        Drain,
+        Splice,
+    },
+};
+
+use crate::components::PeacockInput;
+
+impl From<&PeacockInput> for BytesInput {
+    fn from(input: &PeacockInput) -> Self {
+        BytesInput::new(input.target_bytes().as_slice().to_vec())
+    }
+}
+
+/// A byte-level view of a [`PeacockInput`], such that stages that are generic over [`MutatedTransform`]
+/// can run byte-level mutators on a peacock corpus, e.g.
+/// `StdMutationalStage::<_, _, PeacockBytesView, _, _>::transforming(StdScheduledMutator::new(havoc_mutations_no_crossover()))`
+/// or a stage with [`tokens_mutations()`](libafl::prelude::tokens_mutations) and [`I2SRandReplace`](libafl::prelude::I2SRandReplace) after a cmplog tracing stage.
+///
+/// The view holds the serialization of the input before the postprocessor is applied.
+/// The mutated bytes are unparsed with the generator of the original input. If they are not part of its language
+/// or would change the pinned prefix, the original input is handed back unchanged.
+/// Since most byte-level mutations leave the grammar, [`PeacockBytesMutator`] is the better choice in most cases.
+#[derive(Debug, Clone)]
+pub struct PeacockBytesView {
+    bytes: Vec<u8>,
+    base: PeacockInput,
+}
+
+impl HasLen for PeacockBytesView {
+    fn len(&self) -> usize {
+        self.bytes.len()
+    }
+}
+
+impl HasMutatorBytes for PeacockBytesView {
+    fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    fn bytes_mut(&mut self) -> &mut [u8] {
+        &mut self.bytes
+    }
+
+    fn resize(&mut self, new_len: usize, value: u8) {
+        self.bytes.resize(new_len, value);
+    }
+
+    fn extend<'a, I: IntoIterator<Item = &'a u8>>(&mut self, iter: I) {
+        Extend::extend(&mut self.bytes, iter);
+    }
+
+    fn splice<R, I>(&mut self, range: R, replace_with: I) -> Splice<'_, I::IntoIter>
+    where
+        R: RangeBounds<usize>,
+        I: IntoIterator<Item = u8>,
+    {
+        self.bytes.splice(range, replace_with)
+    }
+
+    fn drain<R>(&mut self, range: R) -> Drain<'_, u8>
+    where
+        R: RangeBounds<usize>,
+    {
+        self.bytes.drain(range)
+    }
+}
+
+impl<S> MutatedTransform<PeacockInput, S> for PeacockBytesView
+where
+    S: HasCorpus<Input = PeacockInput>,
+{
+    type Post = ();
+
+    fn try_transform_from(base: &mut Testcase<PeacockInput>, state: &S) -> Result<Self, Error> {
+        state.corpus().load_input_into(base)?;
+        let base = base.input().as_ref().unwrap().clone();
+
+        Ok(Self {
+            bytes: base.serialize().to_vec(),
+            base,
+        })
+    }
+
+    fn try_transform_into(self, _state: &S) -> Result<(PeacockInput, Self::Post), Error> {
+        let mut input = self.base;
+        input.reparse(&self.bytes);
+        Ok((input, ()))
+    }
+}
+
+/// This component applies a byte-level mutator to the serialization of an input and unparses the result.
+///
+/// If the mutated bytes are not part of the language of the grammar or if they would change the pinned prefix
+/// of the input (see [`PeacockInput::set_pinned_len`]), the mutation is skipped and the input stays as it is.
+/// This makes it possible to use any `Mutator<BytesInput, _>`, e.g. a `StdScheduledMutator` with
+/// `havoc_mutations_no_crossover()`, in a regular mutational stage next to the [`PeacockMutator`](crate::components::PeacockMutator).
+///
+/// The bytes are taken before the postprocessor is applied.
+pub struct PeacockBytesMutator<M> {
+    inner: M,
+    name: Cow<'static, str>,
+}
+
+impl<M> PeacockBytesMutator<M>
+where
+    M: Named,
+{
+    /// Create a new mutator that mutates the serialization of inputs with `inner`.
+    pub fn new(inner: M) -> Self {
+        let name = Cow::Owned(format!("PeacockBytesMutator<{}>", inner.name()));
+
+        Self {
+            inner,
+            name,
+        }
+    }
+}
+
+impl<M> Named for PeacockBytesMutator<M> {
+    fn name(&self) -> &Cow<'static, str> {
+        &self.name
+    }
+}
+
+impl<M, S> Mutator<PeacockInput, S> for PeacockBytesMutator<M>
+where
+    M: Mutator<BytesInput, S>,
+{
+    fn mutate(&mut self, state: &mut S, input: &mut PeacockInput) -> Result<MutationResult, Error> {
+        let mut bytes = BytesInput::new(input.serialize().to_vec());
+
+        if self.inner.mutate(state, &mut bytes)? == MutationResult::Skipped {
+            return Ok(MutationResult::Skipped);
+        }
+
+        if input.reparse(bytes.bytes()) {
+            Ok(MutationResult::Mutated)
+        } else {
+            Ok(MutationResult::Skipped)
+        }
+    }
+}
+
+#[cfg(all(test, not(feature = "static-loading")))]
+mod tests {
+    use super::*;
+    use crate::{
+        backends::C::CGenerator,
+        components::{
+            ffi::{
+                generator_mutate,
+                Generator,
+            },
+            tests::{
+                compile_generator,
+                load_test_generator,
+            },
+        },
+        grammar::ContextFreeGrammar,
+    };
+    use libafl::prelude::{
+        havoc_mutations_no_crossover,
+        ConstFeedback,
+        ExitKind,
+        HasCurrentCorpusId,
+        InMemoryCorpus,
+        InProcessExecutor,
+        NopEventManager,
+        QueueScheduler,
+        Stage,
+        StdFuzzer,
+        StdMutationalStage,
+        StdScheduledMutator,
+        StdState,
+    };
+    use libafl_bolts::prelude::{
+        tuple_list,
+        StdRand,
+    };
+
+    fn random_input(min_len: usize) -> PeacockInput {
+        let mut input = PeacockInput::default();
+
+        while input.sequence().len() < min_len {
+            input.sequence_mut().clear();
+            generator_mutate(input.sequence_mut());
+        }
+
+        input
+    }
+
+    #[test]
+    fn test_bytes_roundtrip() {
+        load_test_generator();
+
+        for _ in 0..100 {
+            let input = random_input(1);
+            let bytes = BytesInput::from(&input);
+            let unparsed = PeacockInput::try_from_bytes(bytes.bytes()).unwrap();
+            assert_eq!(BytesInput::from(&unparsed).bytes(), bytes.bytes());
+        }
+
+        let err = PeacockInput::try_from_bytes(b"\x00\x01").unwrap_err().to_string();
+        assert!(err.contains("diverged from the grammar at offset 0"), "{}", err);
+    }
+
+    /// A generator where byte-level mutations have a good chance to stay in the language
+    fn abc_generator(name: &str, prefix: &str) -> Generator {
+        let path = std::env::temp_dir().join(format!("peacock-{}-{}.json", name, std::process::id()));
+        std::fs::write(
+            &path,
+            r#"{"<ENTRYPOINT>": [["<CHAR>", "<ENTRYPOINT>"], ["<CHAR>"]], "<CHAR>": [["'a'"], ["'b'"], ["'c'"]]}"#,
+        )
+        .unwrap();
+        let cfg = ContextFreeGrammar::builder().peacock_grammar(&path).unwrap().build().unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let so_file = compile_generator(&cfg, CGenerator::new().symbol_prefix(prefix), name);
+        Generator::load(&so_file, prefix)
+    }
+
+    #[test]
+    fn test_hybrid_mutation() {
+        load_test_generator();
+
+        let mut feedback = ConstFeedback::new(false);
+        let mut objective = ConstFeedback::new(false);
+        let mut state = StdState::new(
+            StdRand::with_seed(0),
+            InMemoryCorpus::<PeacockInput>::new(),
+            InMemoryCorpus::new(),
+            &mut feedback,
+            &mut objective,
+        )
+        .unwrap();
+
+        let mut testcase = Testcase::new(random_input(8));
+        let view = PeacockBytesView::try_transform_from(&mut testcase, &state).unwrap();
+        let (input, ()) = view.clone().try_transform_into(&state).unwrap();
+        assert_eq!(input.serialize(), view.bytes());
+        assert_eq!(input.sequence(), testcase.input().as_ref().unwrap().sequence());
+
+        let generator = abc_generator("bytes", "bytes_");
+        let mut mutator = PeacockBytesMutator::new(StdScheduledMutator::new(havoc_mutations_no_crossover()));
+        let (mut mutated, mut skipped) = (0, 0);
+        let mut sequence = Vec::with_capacity(4096);
+
+        for _ in 0..500 {
+            let mut input = PeacockInput::new(Some(generator), 4096);
+
+            while input.sequence().len() < 8 {
+                input.sequence_mut().clear();
+                generator.mutate(input.sequence_mut());
+            }
+
+            input.set_pinned_len(2);
+            let before = input.clone();
+
+            match mutator.mutate(&mut state, &mut input).unwrap() {
+                MutationResult::Mutated => {
+                    mutated += 1;
+                    assert_eq!(&input.sequence()[..2], &before.sequence()[..2]);
+                    assert!(generator.unparse(&mut sequence, input.serialize()));
+                },
+                MutationResult::Skipped => {
+                    skipped += 1;
+                    assert_eq!(input.sequence(), before.sequence());
+                },
+            }
+        }
+
+        assert!(mutated > 0 && skipped > 0, "mutated={} skipped={}", mutated, skipped);
+    }
+
+    #[test]
+    fn test_transforming_stage() {
+        load_test_generator();
+        let generator = abc_generator("bytes-stage", "bytes_stage_");
+
+        let mut feedback = ConstFeedback::new(false);
+        let mut objective = ConstFeedback::new(false);
+        let mut state = StdState::new(
+            StdRand::with_seed(0),
+            InMemoryCorpus::<PeacockInput>::new(),
+            InMemoryCorpus::new(),
+            &mut feedback,
+            &mut objective,
+        )
+        .unwrap();
+        let mut fuzzer = StdFuzzer::new(QueueScheduler::new(), feedback, objective);
+        let mut mgr = NopEventManager::new();
+
+        let mut base = PeacockInput::new(Some(generator), 4096);
+
+        while base.sequence().len() < 8 {
+            base.sequence_mut().clear();
+            generator.mutate(base.sequence_mut());
+        }
+
+        let base_bytes = base.serialize().to_vec();
+        let id = state.corpus_mut().add(Testcase::new(base)).unwrap();
+        state.set_corpus_id(id).unwrap();
+
+        let (mut mutated, mut unchanged) = (0, 0);
+        let mut sequence = Vec::with_capacity(4096);
+        let mut harness = |input: &PeacockInput| {
+            let bytes = input.serialize().to_vec();
+
+            /* Unparsing with the default generator would have failed on this grammar */
+            assert!(generator.unparse(&mut sequence, &bytes));
+
+            if bytes == base_bytes {
+                unchanged += 1;
+            } else {
+                mutated += 1;
+            }
+
+            ExitKind::Ok
+        };
+        let mut executor =
+            InProcessExecutor::new(&mut harness, tuple_list!(), &mut fuzzer, &mut state, &mut mgr).unwrap();
+
+        let mut stage = StdMutationalStage::<_, _, PeacockBytesView, _, _>::transforming(StdScheduledMutator::new(
+            havoc_mutations_no_crossover(),
+        ));
+
+        for _ in 0..20 {
+            stage.perform(&mut fuzzer, &mut executor, &mut state, &mut mgr).unwrap();
+        }
+
+        assert!(mutated > 0 && unchanged > 0, "mutated={} unchanged={}", mutated, unchanged);
+    }
+}
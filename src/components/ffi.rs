@@ -1,32 +1,203 @@
+use std::{
+    cell::RefCell,
+    ffi::{
+        c_char,
+        CStr,
+    },
+    ops::Deref,
+    ptr::{
+        null,
+        null_mut,
+    },
+    sync::{
+        atomic::{
+            AtomicBool,
+            AtomicUsize,
+            Ordering,
+        },
+        RwLock,
+    },
+};
 #[cfg(not(feature = "static-loading"))]
-use {
-    std::ops::Deref,
-    std::path::Path,
+use std::{
+    path::Path,
+    sync::Mutex,
 };
 
+use crate::grammar::ContextFreeGrammar;
+
 type GrammarMutationFunc = unsafe extern "C" fn(buf: *mut usize, len: usize, capacity: usize) -> usize;
+type GrammarMutationInplaceFunc =
+    unsafe extern "C" fn(buf: *mut usize, len: usize, capacity: usize, index: usize) -> usize;
+type GrammarMutationSeededFunc = unsafe extern "C" fn(seed: u64, buf: *mut usize, len: usize, capacity: usize) -> usize;
+type GrammarSubtreeLengthFunc = unsafe extern "C" fn(seq: *const usize, len: usize, index: usize) -> usize;
 type GrammarSerializationFunc =
     unsafe extern "C" fn(seq: *const usize, seq_len: usize, out: *mut u8, out_len: usize) -> usize;
-type GrammarSeedFunc = unsafe extern "C" fn(seed: usize);
+type GrammarSerializationCheckedFunc = unsafe extern "C" fn(
+    seq: *const usize,
+    seq_len: usize,
+    out: *mut u8,
+    out_len: usize,
+    result: *mut SerializationResult,
+);
+type GrammarSeedFunc = unsafe extern "C" fn(seed: u64);
 type GrammarUnparseFunc =
     unsafe extern "C" fn(seq: *mut usize, seq_capacity: usize, input: *const u8, input_len: usize) -> usize;
+type GrammarUnparseBoundedFunc = unsafe extern "C" fn(
+    seq: *mut usize,
+    seq_capacity: usize,
+    input: *const u8,
+    input_len: usize,
+    max_bytes: usize,
+    max_steps: usize,
+    truncated: *mut i32,
+) -> usize;
+type GrammarUnparseMemFunc = unsafe extern "C" fn(
+    seq: *mut usize,
+    seq_capacity: usize,
+    input: *const u8,
+    input_len: usize,
+    scratch: *mut u8,
+    scratch_len: usize,
+) -> usize;
+type GrammarUnparseFailureFunc = unsafe extern "C" fn(nonterm: *mut *const c_char) -> usize;
+type GrammarFingerprintFunc = unsafe extern "C" fn() -> u64;
+type GrammarDigestFunc = unsafe extern "C" fn() -> *const c_char;
+type GrammarRuleCountersFunc = unsafe extern "C" fn(out: *mut *mut usize, len: *mut usize);
+type GrammarRuleNamesFunc = unsafe extern "C" fn(out: *mut *const *const c_char, len: *mut usize);
+type GrammarAddDynamicTerminalFunc = unsafe extern "C" fn(data: *const u8, len: usize) -> i32;
+type GrammarValidateFunc = unsafe extern "C" fn(seq: *const usize, len: usize) -> i32;
+
+/// The generator that is used by all components that were not given a [`Generator`] explicitly.
+static DEFAULT_GENERATOR: RwLock<Option<Generator>> = RwLock::new(None);
+
+/// Incremented every time the default generator gets replaced
+static DEFAULT_GENERATION: AtomicUsize = AtomicUsize::new(0);
+
+/// The reference count of a library that has been unloaded
+const UNLOADED: usize = usize::MAX / 2;
 
-#[allow(non_upper_case_globals)]
-static mut grammar_mutate: Option<GrammarMutationFunc> = None;
-#[allow(non_upper_case_globals)]
-static mut grammar_serialize: Option<GrammarSerializationFunc> = None;
-#[allow(non_upper_case_globals)]
-static mut grammar_seed: Option<GrammarSeedFunc> = None;
-#[allow(non_upper_case_globals)]
-static mut grammar_unparse: Option<GrammarUnparseFunc> = None;
+/// The size of the first buffer of [`Generator::serialize_to_vec`]
+const INITIAL_SERIALIZATION_CAPACITY: usize = 4096;
+
+/// The number of bytes of the scratch buffer of `unparse_sequence_mem` per byte of input
+const UNPARSE_SCRATCH_PER_BYTE: usize = 256;
+
+/// The maximum size of the scratch buffer of `unparse_sequence_mem`
+const UNPARSE_SCRATCH_LIMIT: usize = 64 * 1024 * 1024;
+
+thread_local! {
+    /// The memory for the memo tables of the unparsers of all generators on this thread. It only grows.
+    static UNPARSE_SCRATCH: RefCell<Vec<u8>> = const { RefCell::new(Vec::new()) };
+}
 
 #[cfg(feature = "static-loading")]
 #[link(name = "generator")]
 extern "C" {
     fn mutate_sequence(buf: *mut usize, len: usize, capacity: usize) -> usize;
+    fn mutate_sequence_inplace(buf: *mut usize, len: usize, capacity: usize, index: usize) -> usize;
+    fn mutate_sequence_from(buf: *mut usize, len: usize, capacity: usize, start_step: usize) -> usize;
+    fn subtree_length(seq: *const usize, len: usize, index: usize) -> usize;
     fn serialize_sequence(seq: *const usize, seq_len: usize, out: *mut u8, out_len: usize) -> usize;
-    fn seed_generator(seed: usize);
+    fn serialize_sequence_checked(
+        seq: *const usize,
+        seq_len: usize,
+        out: *mut u8,
+        out_len: usize,
+        result: *mut SerializationResult,
+    );
+    fn seed_generator(seed: u64);
     fn unparse_sequence(seq: *mut usize, seq_capacity: usize, input: *const u8, input_len: usize) -> usize;
+    fn unparse_sequence_bounded(
+        seq: *mut usize,
+        seq_capacity: usize,
+        input: *const u8,
+        input_len: usize,
+        max_bytes: usize,
+        max_steps: usize,
+        truncated: *mut i32,
+    ) -> usize;
+    fn unparse_failure(nonterm: *mut *const c_char) -> usize;
+    #[link_name = "grammar_fingerprint"]
+    fn get_grammar_fingerprint() -> u64;
+    fn peacock_grammar_digest() -> *const c_char;
+    fn validate_sequence(seq: *const usize, len: usize) -> i32;
+}
+
+/// Keeps track of the calls into a dynamically loaded generator such that the library can be
+/// unloaded once [`reload_generator`] replaced it and all calls returned.
+#[derive(Debug)]
+#[cfg_attr(feature = "static-loading", allow(dead_code))]
+struct LoadedLibrary {
+    #[cfg(not(feature = "static-loading"))]
+    lib: Mutex<Option<libloading::Library>>,
+    /// The number of references that are in use or [`UNLOADED`]
+    references: AtomicUsize,
+    retired: AtomicBool,
+}
+
+impl LoadedLibrary {
+    fn acquire(&self) {
+        if self.references.fetch_add(1, Ordering::Acquire) >= UNLOADED {
+            self.references.fetch_sub(1, Ordering::Relaxed);
+            panic!("The generator was unloaded after reload_generator() replaced it");
+        }
+    }
+
+    fn release(&self) {
+        if self.references.fetch_sub(1, Ordering::AcqRel) == 1 && self.retired.load(Ordering::Acquire) {
+            self.unload();
+        }
+    }
+
+    /// Unload the library as soon as no references are in use anymore
+    #[cfg(not(feature = "static-loading"))]
+    fn retire(&self) {
+        self.retired.store(true, Ordering::Release);
+        // Publishes the store to every release() that comes later in the order of `references`, so
+        // either one of them or the unload() below sees that the library is retired and unused
+        self.references.fetch_add(0, Ordering::AcqRel);
+        self.unload();
+    }
+
+    fn unload(&self) {
+        if self.references.compare_exchange(0, UNLOADED, Ordering::AcqRel, Ordering::Relaxed).is_ok() {
+            #[cfg(not(feature = "static-loading"))]
+            drop(self.lib.lock().unwrap().take());
+        }
+    }
+
+    fn is_unloaded(&self) -> bool {
+        self.references.load(Ordering::Acquire) >= UNLOADED
+    }
+}
+
+/// A handle to the functions of a loaded generator.
+///
+/// Multiple generators can be used in the same process by creating them with different
+/// [`CGenerator::symbol_prefix`](crate::backends::C::CGenerator::symbol_prefix)es and handing
+/// their handles to the components, e.g. via [`PeacockMutator::with_generator`](crate::components::PeacockMutator::with_generator).
+#[derive(Debug, Clone, Copy)]
+pub struct Generator {
+    library: Option<&'static LoadedLibrary>,
+    mutate: Option<GrammarMutationFunc>,
+    mutate_inplace: Option<GrammarMutationInplaceFunc>,
+    mutate_from: Option<GrammarMutationInplaceFunc>,
+    mutate_seeded: Option<GrammarMutationSeededFunc>,
+    subtree_length: Option<GrammarSubtreeLengthFunc>,
+    serialize: Option<GrammarSerializationFunc>,
+    serialize_checked: Option<GrammarSerializationCheckedFunc>,
+    seed: GrammarSeedFunc,
+    unparse: Option<GrammarUnparseFunc>,
+    unparse_bounded: Option<GrammarUnparseBoundedFunc>,
+    unparse_mem: Option<GrammarUnparseMemFunc>,
+    unparse_failure: Option<GrammarUnparseFailureFunc>,
+    fingerprint: Option<GrammarFingerprintFunc>,
+    digest: Option<GrammarDigestFunc>,
+    rule_counters: Option<GrammarRuleCountersFunc>,
+    rule_names: Option<GrammarRuleNamesFunc>,
+    add_dynamic_terminal: Option<GrammarAddDynamicTerminalFunc>,
+    validate: Option<GrammarValidateFunc>,
 }
 
 /// This function initializes the generator. Must be called before anything else.
@@ -34,89 +205,623 @@ extern "C" {
 /// This is the __static__ version of this function, meaning that it expects you to link the generator
 /// functions statically into the binary. The generator must be an archive file called `libgenerator.a`
 /// otherwise symbol resolution will fail.
+///
+/// The generator becomes the default generator of all components and its handle is returned
+/// together with what the generator supports.
 #[cfg(feature = "static-loading")]
-pub fn load_generator() {
-    unsafe {
-        grammar_mutate = Some(mutate_sequence);
-        grammar_serialize = Some(serialize_sequence);
-        grammar_seed = Some(seed_generator);
-        grammar_unparse = Some(unparse_sequence);
+pub fn load_generator() -> LoadedGenerator {
+    let generator = Generator {
+        library: None,
+        mutate: Some(mutate_sequence),
+        mutate_inplace: Some(mutate_sequence_inplace),
+        mutate_from: Some(mutate_sequence_from),
+        mutate_seeded: None,
+        subtree_length: Some(subtree_length),
+        serialize: Some(serialize_sequence),
+        serialize_checked: Some(serialize_sequence_checked),
+        seed: seed_generator,
+        unparse: Some(unparse_sequence),
+        unparse_bounded: Some(unparse_sequence_bounded),
+        unparse_mem: None,
+        unparse_failure: Some(unparse_failure),
+        fingerprint: Some(get_grammar_fingerprint),
+        digest: Some(peacock_grammar_digest),
+        rule_counters: None,
+        rule_names: None,
+        add_dynamic_terminal: None,
+        validate: Some(validate_sequence),
+    };
+
+    *DEFAULT_GENERATOR.write().unwrap() = Some(generator);
+    LoadedGenerator::new(generator)
+}
+
+#[cfg(not(feature = "static-loading"))]
+fn get_function<T: Copy>(lib: &libloading::Library, prefix: &str, name: &str) -> T {
+    get_optional_function(lib, prefix, name)
+        .unwrap_or_else(|| panic!("Could not find function {}{} in generator.so", prefix, name))
+}
+
+/// Replace the default generator with the generator in the shared object at `path` while fuzzing.
+///
+/// Calls that are executing code of the old generator finish with the old generator and the
+/// old library gets unloaded once the last of them returned. Calling the handle that was returned
+/// for the old generator afterwards panics.
+/// The shared object may be at the same path as the old one, e.g. after it was recompiled in place.
+///
+/// Inputs that were created with the old generator are not translated, see
+/// [`GeneratorReloadStage`](crate::components::GeneratorReloadStage) for that.
+#[cfg(not(feature = "static-loading"))]
+pub fn reload_generator<P: AsRef<Path>>(path: P) -> Generator {
+    static RELOADS: AtomicUsize = AtomicUsize::new(0);
+
+    /* dlopen() hands out the already loaded library if a file gets opened under the same name twice */
+    let copy = std::env::temp_dir().join(format!(
+        "peacock-generator-{}-{}.so",
+        std::process::id(),
+        RELOADS.fetch_add(1, Ordering::Relaxed)
+    ));
+    std::fs::copy(path, &copy).expect("Could not copy generator.so");
+    let generator = Generator::load(&copy, "");
+    let _ = std::fs::remove_file(&copy);
+
+    let old = DEFAULT_GENERATOR.write().unwrap().replace(generator);
+    DEFAULT_GENERATION.fetch_add(1, Ordering::SeqCst);
+
+    if let Some(old) = old {
+        old.retire();
     }
+
+    generator
 }
 
 #[cfg(not(feature = "static-loading"))]
-fn get_function<T: Copy>(lib: &libloading::Library, name: &[u8]) -> T {
-    let f: libloading::Symbol<T> = unsafe { lib.get(name) }.expect("Could not find function in generator.so");
-    let f = f.deref();
-    *f
+fn get_optional_function<T: Copy>(lib: &libloading::Library, prefix: &str, name: &str) -> Option<T> {
+    let name = format!("{}{}", prefix, name);
+    let f: libloading::Symbol<T> = unsafe { lib.get(name.as_bytes()) }.ok()?;
+    Some(*f.deref())
 }
 
 /// This function initializes the generator. Must be called before anything else.
 ///
 /// This is the __dynamic__ version of this function, which gets a path to a
 /// shared object as an argument and loads that via dlopen().
+///
+/// The generator becomes the default generator of all components and its handle is returned
+/// together with what the generator supports.
 #[cfg(not(feature = "static-loading"))]
-pub fn load_generator<P: AsRef<Path>>(path: P) {
-    let path = path.as_ref();
+pub fn load_generator<P: AsRef<Path>>(path: P) -> LoadedGenerator {
+    let generator = Generator::load(path, "");
 
-    unsafe {
-        let lib = libloading::Library::new(path).expect("Could not load generator.so");
-        grammar_mutate = Some(get_function::<GrammarMutationFunc>(&lib, b"mutate_sequence"));
-        grammar_serialize = Some(get_function::<GrammarSerializationFunc>(&lib, b"serialize_sequence"));
-        grammar_seed = Some(get_function::<GrammarSeedFunc>(&lib, b"seed_generator"));
-        grammar_unparse = Some(get_function::<GrammarUnparseFunc>(&lib, b"unparse_sequence"));
-        std::mem::forget(lib);
-    }
+    *DEFAULT_GENERATOR.write().unwrap() = Some(generator);
+    LoadedGenerator::new(generator)
 }
 
-pub(crate) fn generator_mutate(sequence: &mut Vec<usize>) {
-    let len = sequence.len();
-    let capacity = sequence.capacity();
-    let buf = sequence.as_mut_ptr();
+/// What [`load_generator`] found in the generator.
+#[derive(Debug, Clone)]
+pub struct LoadedGenerator {
+    /// The handle of the generator, which is also the default generator now
+    pub generator: Generator,
+
+    /// The digest of the grammar the generator was created from, see [`Generator::digest`]
+    pub digest: Option<String>,
+
+    /// The procedures that the generator exports
+    pub capabilities: Capabilities,
+}
 
-    let f = unsafe { grammar_mutate }.expect("load_generator() has not been called before fuzzing");
+impl LoadedGenerator {
+    fn new(generator: Generator) -> Self {
+        Self {
+            generator,
+            digest: generator.digest(),
+            capabilities: generator.capabilities(),
+        }
+    }
 
-    unsafe {
-        let new_len = f(buf, len, capacity);
-        sequence.set_len(new_len);
+    /// Whether the generator was created from `grammar`.
+    /// Generators created by older versions of peacock never match.
+    pub fn matches(&self, grammar: &ContextFreeGrammar) -> bool {
+        self.digest.as_ref().is_some_and(|digest| *digest == grammar.digest())
     }
 }
 
-pub(crate) fn generator_serialize(sequence: &[usize], out: *mut u8, out_len: usize) -> usize {
-    let seq = sequence.as_ptr();
-    let seq_len = sequence.len();
+/// The procedures that a generator exports. They can be turned off with
+/// [`CGenerator::emit_mutation_procedure`](crate::backends::C::CGenerator::emit_mutation_procedure) and friends.
+/// Using a procedure that a generator does not export panics.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Capabilities {
+    /// Whether the generator exports `mutate_sequence`
+    pub mutate: bool,
+
+    /// Whether the generator exports `serialize_sequence`
+    pub serialize: bool,
+
+    /// Whether the generator exports `unparse_sequence`
+    pub unparse: bool,
+}
+
+impl Generator {
+    /// Load the generator in the shared object at `path` whose functions were exported with the
+    /// given [`symbol_prefix`](crate::backends::C::CGenerator::symbol_prefix).
+    ///
+    /// In contrast to [`load_generator`] this does not change the default generator.
+    #[cfg(not(feature = "static-loading"))]
+    pub fn load<P: AsRef<Path>>(path: P, prefix: &str) -> Self {
+        let lib = unsafe { libloading::Library::new(path.as_ref()) }.expect("Could not load generator.so");
+        let mut generator = Self {
+            library: None,
+            mutate: get_optional_function(&lib, prefix, "mutate_sequence"),
+            mutate_inplace: get_optional_function(&lib, prefix, "mutate_sequence_inplace"),
+            mutate_from: get_optional_function(&lib, prefix, "mutate_sequence_from"),
+            mutate_seeded: get_optional_function(&lib, prefix, "mutate_sequence_seeded"),
+            subtree_length: get_optional_function(&lib, prefix, "subtree_length"),
+            serialize: get_optional_function(&lib, prefix, "serialize_sequence"),
+            serialize_checked: get_optional_function(&lib, prefix, "serialize_sequence_checked"),
+            seed: get_function(&lib, prefix, "seed_generator"),
+            unparse: get_optional_function(&lib, prefix, "unparse_sequence"),
+            unparse_bounded: get_optional_function(&lib, prefix, "unparse_sequence_bounded"),
+            unparse_mem: get_optional_function(&lib, prefix, "unparse_sequence_mem"),
+            unparse_failure: get_optional_function(&lib, prefix, "unparse_failure"),
+            fingerprint: get_optional_function(&lib, prefix, "grammar_fingerprint"),
+            digest: get_optional_function(&lib, prefix, "peacock_grammar_digest"),
+            rule_counters: get_optional_function(&lib, prefix, "get_rule_counters"),
+            rule_names: get_optional_function(&lib, prefix, "get_rule_names"),
+            add_dynamic_terminal: get_optional_function(&lib, prefix, "add_dynamic_terminal"),
+            validate: get_optional_function(&lib, prefix, "validate_sequence"),
+        };
+        /* The bookkeeping must outlive all copies of the handle, only the library itself gets unloaded */
+        generator.library = Some(Box::leak(Box::new(LoadedLibrary {
+            lib: Mutex::new(Some(lib)),
+            references: AtomicUsize::new(0),
+            retired: AtomicBool::new(false),
+        })));
+        generator
+    }
+
+    /// The handle that was set by [`load_generator`] or [`reload_generator`].
+    /// The generator stays loaded while the returned reference exists.
+    pub(crate) fn default_handle() -> Option<GeneratorRef> {
+        /* Acquire the reference under the lock such that a reload cannot unload the library in between */
+        let default = DEFAULT_GENERATOR.read().unwrap();
+        default.as_ref().map(Generator::acquire)
+    }
+
+    /// Like [`default_handle`](Generator::default_handle) but panics if no generator was loaded.
+    pub(crate) fn expect_default() -> GeneratorRef {
+        Self::default_handle().expect("load_generator() has not been called before fuzzing")
+    }
+
+    /// `generator` or the default generator if it is `None`.
+    pub(crate) fn or_default(generator: Option<Generator>) -> GeneratorRef {
+        match generator {
+            Some(generator) => generator.acquire(),
+            None => Self::expect_default(),
+        }
+    }
+
+    /// Keep the library of this generator loaded while the returned reference exists.
+    /// Panics if the library was already unloaded.
+    pub(crate) fn acquire(&self) -> GeneratorRef {
+        if let Some(library) = self.library {
+            library.acquire();
+        }
+
+        GeneratorRef(*self)
+    }
+
+    /// Unload the library of this generator as soon as no references to it are in use anymore.
+    #[cfg(not(feature = "static-loading"))]
+    pub(crate) fn retire(&self) {
+        if let Some(library) = self.library {
+            library.retire();
+        }
+    }
+
+    /// Whether [`reload_generator`] replaced this generator and its library got unloaded.
+    pub fn is_unloaded(&self) -> bool {
+        self.library.is_some_and(LoadedLibrary::is_unloaded)
+    }
+
+    /// Seed the RNG of this generator.
+    pub fn seed(&self, seed: u64) {
+        let _ref = self.acquire();
+
+        unsafe {
+            (self.seed)(seed);
+        }
+    }
+
+    /// Returns the fingerprint of the grammar this generator was created from.
+    /// Generators created by older versions of peacock do not have a fingerprint.
+    pub fn fingerprint(&self) -> Option<u64> {
+        let f = self.fingerprint?;
+        let _ref = self.acquire();
+        Some(unsafe { f() })
+    }
+
+    /// Returns the fingerprint as 16 hex digits, see [`ContextFreeGrammar::digest`].
+    /// Generators created by older versions of peacock do not have a digest.
+    pub fn digest(&self) -> Option<String> {
+        let f = self.digest?;
+        let _ref = self.acquire();
+        Some(unsafe { CStr::from_ptr(f()) }.to_string_lossy().into_owned())
+    }
+
+    /// Returns which of the procedures for mutation, serialization and unparsing this generator exports.
+    pub fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            mutate: self.mutate.is_some(),
+            serialize: self.serialize.is_some(),
+            unparse: self.unparse.is_some(),
+        }
+    }
+
+    pub(crate) fn mutate(&self, sequence: &mut Vec<usize>) {
+        let f = self.mutate.expect("The generator does not export mutate_sequence");
+        let _ref = self.acquire();
+        let len = sequence.len();
+        let capacity = sequence.capacity();
+        let buf = sequence.as_mut_ptr();
+
+        unsafe {
+            let new_len = f(buf, len, capacity);
+            sequence.set_len(new_len);
+        }
+    }
+
+    /// Replace the expansion at `index` of `sequence` with a new random expansion of the same
+    /// non-terminal, keeping everything after it. Returns `false` if the generator was
+    /// created by an older version of peacock that does not support in-place mutations.
+    pub(crate) fn mutate_inplace(&self, sequence: &mut Vec<usize>, index: usize) -> bool {
+        let Some(f) = self.mutate_inplace else {
+            return false;
+        };
+        let _ref = self.acquire();
+        let len = sequence.len();
+        let capacity = sequence.capacity();
+        let buf = sequence.as_mut_ptr();
+
+        unsafe {
+            let new_len = f(buf, len, capacity, index);
+            sequence.set_len(new_len);
+        }
 
-    let f = unsafe { grammar_serialize }.expect("load_generator() has not been called before fuzzing");
+        true
+    }
+
+    /// Choose a different rule at step `index` of `sequence` and keep the steps after it where they still fit.
+    /// Returns `false` if the generator was created by an older version of peacock that does not support point mutations.
+    pub(crate) fn mutate_from(&self, sequence: &mut Vec<usize>, index: usize) -> bool {
+        let Some(f) = self.mutate_from else {
+            return false;
+        };
+        let _ref = self.acquire();
+        let len = sequence.len();
+        let capacity = sequence.capacity();
+        let buf = sequence.as_mut_ptr();
+
+        unsafe {
+            let new_len = f(buf, len, capacity, index);
+            sequence.set_len(new_len);
+        }
+
+        true
+    }
+
+    /// Like [`mutate`](Generator::mutate) but with an RNG that is initialized from `seed`, such that the same seed
+    /// and the same `sequence` always give the same mutant. The RNG state of [`seed`](Generator::seed) is not touched.
+    /// Returns `false` if the generator was not created with
+    /// [`CGenerator::emit_seeded_variants`](crate::backends::C::CGenerator::emit_seeded_variants).
+    pub(crate) fn mutate_seeded(&self, seed: u64, sequence: &mut Vec<usize>) -> bool {
+        let Some(f) = self.mutate_seeded else {
+            return false;
+        };
+        let _ref = self.acquire();
+        let len = sequence.len();
+        let capacity = sequence.capacity();
+        let buf = sequence.as_mut_ptr();
+
+        unsafe {
+            let new_len = f(seed, buf, len, capacity);
+            sequence.set_len(new_len);
+        }
+
+        true
+    }
+
+    /// Whether this generator was created with
+    /// [`CGenerator::emit_seeded_variants`](crate::backends::C::CGenerator::emit_seeded_variants).
+    /// Generators that are linked statically never are.
+    pub fn supports_seeds(&self) -> bool {
+        self.mutate_seeded.is_some()
+    }
+
+    /// Returns the number of steps that belong to the expansion at `index` of `sequence`.
+    /// Generators created by older versions of peacock cannot compute this.
+    pub fn subtree_length(&self, sequence: &[usize], index: usize) -> Option<usize> {
+        let f = self.subtree_length?;
+        let _ref = self.acquire();
+        Some(unsafe { f(sequence.as_ptr(), sequence.len(), index) })
+    }
+
+    /// Serialize `sequence` into `out`. Returns the number of bytes written and whether the
+    /// output had to be truncated because `out` was too small.
+    /// Generators created by older versions of peacock never report truncation.
+    pub(crate) fn serialize(&self, sequence: &[usize], out: *mut u8, out_len: usize) -> (usize, bool) {
+        let serialize = self.serialize.expect("The generator does not export serialize_sequence");
+        let _ref = self.acquire();
+        let seq = sequence.as_ptr();
+        let seq_len = sequence.len();
+
+        if let Some(f) = self.serialize_checked {
+            let mut result = SerializationResult::default();
+            unsafe { f(seq, seq_len, out, out_len, &mut result) };
+            return (result.len, result.truncated != 0);
+        }
+
+        (unsafe { serialize(seq, seq_len, out, out_len) }, false)
+    }
+
+    /// Serialize `sequence` into a new vector that holds exactly the output, without the postprocessor.
+    /// The vector starts small and grows until the output fits.
+    pub fn serialize_to_vec(&self, sequence: &[usize]) -> Vec<u8> {
+        let mut out: Vec<u8> = Vec::with_capacity(INITIAL_SERIALIZATION_CAPACITY);
+
+        loop {
+            let (len, truncated) = self.serialize(sequence, out.as_mut_ptr(), out.capacity());
+
+            /* Older generators don't report truncation, so a full buffer might not hold everything */
+            let complete = if self.serialize_checked.is_some() { !truncated } else { len < out.capacity() };
+
+            if complete {
+                unsafe { out.set_len(len) };
+                return out;
+            }
+
+            out.reserve_exact(out.capacity() * 2);
+        }
+    }
+
+    /// Find the automaton walk that produces `input` and store it in `sequence`.
+    /// The whole input must match the grammar, an input of which only a prefix matches is rejected.
+    /// Generators that were created with [`CGenerator::memoized_unparsing`](crate::backends::C::CGenerator::memoized_unparsing)
+    /// keep their cache in a scratch buffer of the current thread. Statically linked generators never use the cache.
+    pub(crate) fn unparse(&self, sequence: &mut Vec<usize>, input: &[u8]) -> bool {
+        let f = self.unparse.expect("The generator does not export unparse_sequence");
+        let _ref = self.acquire();
+        let seq = sequence.as_mut_ptr();
+        let seq_capacity = sequence.capacity();
+        let input_len = input.len();
+        let input = input.as_ptr();
+
+        let new_len = match self.unparse_mem {
+            Some(f) => UNPARSE_SCRATCH.with_borrow_mut(|scratch| {
+                let wanted =
+                    input_len.saturating_add(1).saturating_mul(UNPARSE_SCRATCH_PER_BYTE).min(UNPARSE_SCRATCH_LIMIT);
+
+                if scratch.len() < wanted {
+                    scratch.resize(wanted, 0);
+                }
+
+                unsafe { f(seq, seq_capacity, input, input_len, scratch.as_mut_ptr(), scratch.len()) }
+            }),
+            None => unsafe { f(seq, seq_capacity, input, input_len) },
+        };
+
+        if new_len == 0 {
+            return false;
+        }
+
+        unsafe {
+            sequence.set_len(new_len);
+        }
+
+        true
+    }
+
+    /// Like [`unparse`](Generator::unparse) but walks that need more than `max_bytes` bytes of `input` or more
+    /// than `max_steps` steps only cover a prefix of `input`.
+    /// Returns `None` if no prefix could be unparsed, otherwise whether the walk had to be cut off.
+    /// Generators created by older versions of peacock unparse the whole input regardless of the bounds.
+    pub(crate) fn unparse_bounded(
+        &self,
+        sequence: &mut Vec<usize>,
+        input: &[u8],
+        max_bytes: usize,
+        max_steps: usize,
+    ) -> Option<bool> {
+        let Some(f) = self.unparse_bounded else {
+            return self.unparse(sequence, input).then_some(false);
+        };
+        let _ref = self.acquire();
+        let seq = sequence.as_mut_ptr();
+        let seq_capacity = sequence.capacity();
+        let input_len = input.len();
+        let input = input.as_ptr();
+        let mut truncated = 0;
+
+        let new_len = unsafe { f(seq, seq_capacity, input, input_len, max_bytes, max_steps, &mut truncated) };
+
+        if new_len == 0 {
+            return None;
+        }
+
+        unsafe {
+            sequence.set_len(new_len);
+        }
+
+        Some(truncated != 0)
+    }
+
+    /// Returns where the last call to `unparse` on this thread diverged from the grammar.
+    /// Returns `None` if it succeeded or if the generator was created by an older version of peacock
+    /// that does not keep track of this.
+    pub fn last_unparse_failure(&self) -> Option<UnparseFailure> {
+        let f = self.unparse_failure?;
+        let _ref = self.acquire();
+        let mut nonterm = null();
+
+        unsafe {
+            let offset = f(&mut nonterm);
 
-    unsafe { f(seq, seq_len, out, out_len) }
+            if nonterm.is_null() {
+                return None;
+            }
+
+            Some(UnparseFailure {
+                offset,
+                non_terminal: CStr::from_ptr(nonterm).to_string_lossy().into_owned(),
+            })
+        }
+    }
+
+    /// Add a terminal to the runtime terminals of the non-terminal [`DYNAMIC`](crate::grammar::DYNAMIC_NONTERMINAL).
+    ///
+    /// Returns `false` if the terminal was not added because it is known already, it is empty or too long, the
+    /// pool is full or the grammar does not use dynamic terminals.
+    /// Dynamic terminals are only supported for dynamically loaded generators.
+    pub fn add_dynamic_terminal(&self, terminal: &[u8]) -> bool {
+        let Some(f) = self.add_dynamic_terminal else {
+            return false;
+        };
+        let _ref = self.acquire();
+
+        unsafe { f(terminal.as_ptr(), terminal.len()) != 0 }
+    }
+
+    /// Check that every entry of `sequence` is a valid rule index for the non-terminal it belongs to.
+    /// Generators created by older versions of peacock cannot check this and accept every sequence.
+    pub fn validate_sequence(&self, sequence: &[usize]) -> bool {
+        let Some(f) = self.validate else {
+            return true;
+        };
+        let _ref = self.acquire();
+
+        unsafe { f(sequence.as_ptr(), sequence.len()) != 0 }
+    }
+
+    /// Returns a snapshot of the rule counters of the generator if it was created with
+    /// [`CGenerator::emit_statistics`](crate::backends::C::CGenerator::emit_statistics).
+    /// Statistics are only supported for dynamically loaded generators.
+    pub(crate) fn rule_counters(&self) -> Option<Vec<usize>> {
+        let f = self.rule_counters?;
+        let _ref = self.acquire();
+        let mut buf = null_mut();
+        let mut len = 0;
+
+        unsafe {
+            f(&mut buf, &mut len);
+            Some(std::slice::from_raw_parts(buf, len).to_vec())
+        }
+    }
+
+    /// Returns the name of the non-terminal of every rule counter.
+    pub(crate) fn rule_names(&self) -> Option<Vec<String>> {
+        let f = self.rule_names?;
+        let _ref = self.acquire();
+        let mut buf = null();
+        let mut len = 0;
+
+        unsafe {
+            f(&mut buf, &mut len);
+            let names = std::slice::from_raw_parts(buf, len);
+            Some(names.iter().map(|name| CStr::from_ptr(*name).to_string_lossy().into_owned()).collect())
+        }
+    }
 }
 
-/// Seed the RNG of the generator.
-pub fn generator_seed(seed: usize) {
-    let f = unsafe { grammar_seed }.expect("load_generator() has not been called before generator_seed()");
+/// A [`Generator`] whose library does not get unloaded while this reference exists.
+pub(crate) struct GeneratorRef(Generator);
+
+impl Deref for GeneratorRef {
+    type Target = Generator;
 
-    unsafe {
-        f(seed);
+    fn deref(&self) -> &Self::Target {
+        &self.0
     }
 }
 
-pub(crate) fn generator_unparse(sequence: &mut Vec<usize>, input: &[u8]) -> bool {
-    let seq = sequence.as_mut_ptr();
-    let seq_capacity = sequence.capacity();
-    let input_len = input.len();
-    let input = input.as_ptr();
+impl Drop for GeneratorRef {
+    fn drop(&mut self) {
+        if let Some(library) = self.0.library {
+            library.release();
+        }
+    }
+}
 
-    let f = unsafe { grammar_unparse }.expect("load_generator() has not been called before fuzzing");
+/// Where an input diverged from the grammar during unparsing.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnparseFailure {
+    /// The furthest byte offset into the input that the unparser reached
+    pub offset: usize,
 
-    let new_len = unsafe { f(seq, seq_capacity, input, input_len) };
+    /// The non-terminal that the unparser tried to match at that offset
+    pub non_terminal: String,
+}
 
-    if new_len == 0 {
-        return false;
+impl UnparseFailure {
+    /// Translate the offset into a line and a column of `input`, both starting at 1.
+    pub fn line_column(&self, input: &[u8]) -> (usize, usize) {
+        let before = &input[..self.offset.min(input.len())];
+        let line = before.iter().filter(|byte| **byte == b'\n').count() + 1;
+        let column = before.iter().rev().take_while(|byte| **byte != b'\n').count() + 1;
+        (line, column)
     }
+}
 
-    unsafe {
-        sequence.set_len(new_len);
+impl std::fmt::Display for UnparseFailure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "offset {} while matching <{}>", self.offset, self.non_terminal)
     }
+}
+
+/// Mirrors `SerializationResult` of the generated code.
+#[repr(C)]
+#[derive(Default)]
+pub(crate) struct SerializationResult {
+    len: usize,
+    truncated: i32,
+}
+
+#[cfg(test)]
+pub(crate) fn generator_mutate(sequence: &mut Vec<usize>) {
+    Generator::expect_default().mutate(sequence)
+}
+
+#[cfg(test)]
+pub(crate) fn generator_serialize(sequence: &[usize], out: *mut u8, out_len: usize) -> (usize, bool) {
+    Generator::expect_default().serialize(sequence, out, out_len)
+}
+
+/// Serialize `sequence` with the default generator into a new vector, see [`Generator::serialize_to_vec`].
+/// This needs neither LibAFL nor a [`PeacockInput`](crate::components::PeacockInput).
+pub fn serialize_to_vec(sequence: &[usize]) -> Vec<u8> {
+    Generator::expect_default().serialize_to_vec(sequence)
+}
+
+/// Seed the RNG of the default generator.
+pub fn generator_seed(seed: u64) {
+    Generator::default_handle().expect("load_generator() has not been called before generator_seed()").seed(seed)
+}
+
+/// Changes every time [`reload_generator`] replaces the default generator.
+pub(crate) fn default_generation() -> usize {
+    DEFAULT_GENERATION.load(Ordering::SeqCst)
+}
+
+pub(crate) fn generator_fingerprint() -> Option<u64> {
+    Generator::default_handle()?.fingerprint()
+}
+
+pub(crate) fn generator_validate(sequence: &[usize]) -> bool {
+    Generator::default_handle().is_none_or(|generator| generator.validate_sequence(sequence))
+}
+
+pub(crate) fn generator_rule_counters() -> Option<Vec<usize>> {
+    Generator::default_handle()?.rule_counters()
+}
 
-    true
+pub(crate) fn generator_rule_names() -> Option<Vec<String>> {
+    Generator::default_handle()?.rule_names()
 }
@@ -1,27 +1,435 @@
 use crate::components::{
-    ffi::generator_mutate,
+    default_sequence_capacity,
+    ffi,
     PeacockInput,
 };
+use ahash::RandomState;
 use libafl::prelude::{
     Error,
     Generator,
+    HasMetadata,
 };
+use libafl_bolts::impl_serdeany;
+use serde::{
+    Deserialize,
+    Serialize,
+};
+
+const COVERAGE_MAP_SIZE: usize = 1 << 16;
 
 /// This component generates new inputs from scratch.
-pub struct PeacockGenerator;
+pub struct PeacockGenerator {
+    generator: Option<ffi::Generator>,
+    capacity: usize,
+    next_seed: Option<u64>,
+}
 
 impl PeacockGenerator {
     /// Create a new generator.
     #[allow(clippy::new_without_default)]
     pub fn new() -> Self {
-        Self {}
+        Self {
+            generator: None,
+            capacity: default_sequence_capacity(),
+            next_seed: None,
+        }
+    }
+
+    /// Generate inputs with `generator` instead of the default generator.
+    pub fn with_generator(mut self, generator: &ffi::Generator) -> Self {
+        self.generator = Some(*generator);
+        self
+    }
+
+    /// Give every input its own seed, starting at `start` and counting up, and record it in
+    /// [`PeacockInput::seed`]. The same seed always generates the same input, independent of
+    /// all inputs that were generated before.
+    ///
+    /// This requires a generator that was created with
+    /// [`CGenerator::emit_seeded_variants`](crate::backends::C::CGenerator::emit_seeded_variants),
+    /// otherwise generating an input fails.
+    pub fn with_explicit_seeds(mut self, start: u64) -> Self {
+        self.next_seed = Some(start);
+        self
     }
+
+    /// Generate inputs whose sequences have room for `capacity` entries.
+    /// See [`PeacockInput`] for how this limits the generated inputs.
+    ///
+    /// Default: [`default_sequence_capacity()`] at the time of construction
+    pub fn with_capacity(mut self, capacity: usize) -> Self {
+        assert!(capacity > 0, "Sequence capacity must not be zero");
+        self.capacity = capacity;
+        self
+    }
+
+    /// The number of sequence entries that generated inputs have room for.
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+}
+
+/// Create a new input from scratch with `generator` or the default generator.
+fn generate_input(generator: Option<ffi::Generator>, capacity: usize) -> PeacockInput {
+    let mut input = PeacockInput::new(generator, capacity);
+    input.generator().mutate(input.sequence_mut());
+    input
 }
 
 impl<S> Generator<PeacockInput, S> for PeacockGenerator {
     fn generate(&mut self, _state: &mut S) -> Result<PeacockInput, Error> {
-        let mut input = PeacockInput::default();
-        generator_mutate(input.sequence_mut());
+        let Some(seed) = self.next_seed else {
+            return Ok(generate_input(self.generator, self.capacity));
+        };
+        let mut input = PeacockInput::new(self.generator, self.capacity);
+
+        if !input.generator().mutate_seeded(seed, input.sequence_mut()) {
+            return Err(Error::illegal_state(
+                "Explicit seeds need a generator that was created with CGenerator::emit_seeded_variants()",
+            ));
+        }
+
+        input.set_seed(seed);
+        self.next_seed = Some(seed.wrapping_add(1));
         Ok(input)
     }
 }
+
+/// The estimated grammar coverage of all inputs that were generated by a [`PeacockCoverageGenerator`].
+///
+/// A sequence only stores the index of a rule and not the non-terminal it belongs to,
+/// so every pair of consecutive rule choices is used as an approximation of a rule in context.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PeacockCoverageMetadata {
+    map: Vec<u8>,
+}
+
+impl_serdeany!(PeacockCoverageMetadata);
+
+impl PeacockCoverageMetadata {
+    fn new() -> Self {
+        Self {
+            map: vec![0; COVERAGE_MAP_SIZE / 8],
+        }
+    }
+
+    fn edges(sequence: &[usize]) -> impl Iterator<Item = usize> + '_ {
+        let hasher = RandomState::with_seeds(0, 0, 0, 0);
+        let mut prev = usize::MAX;
+
+        sequence.iter().map(move |rule| {
+            let edge = hasher.hash_one((prev, *rule)) as usize % COVERAGE_MAP_SIZE;
+            prev = *rule;
+            edge
+        })
+    }
+
+    /// The number of pairs of rule choices in `sequence` that have not been observed so far.
+    pub fn novelty(&self, sequence: &[usize]) -> usize {
+        Self::edges(sequence).filter(|edge| self.map[edge / 8] & (1 << (edge % 8)) == 0).count()
+    }
+
+    /// Mark all pairs of rule choices in `sequence` as observed.
+    pub fn observe(&mut self, sequence: &[usize]) {
+        for edge in Self::edges(sequence) {
+            self.map[edge / 8] |= 1 << (edge % 8);
+        }
+    }
+}
+
+/// This component generates new inputs from scratch, like the [`PeacockGenerator`],
+/// but tries multiple times and keeps the input that covers the most parts of the grammar that
+/// previously generated inputs did not cover.
+/// The coverage is tracked in the [`PeacockCoverageMetadata`] of the state.
+pub struct PeacockCoverageGenerator {
+    attempts: usize,
+    generator: Option<ffi::Generator>,
+    capacity: usize,
+}
+
+impl PeacockCoverageGenerator {
+    /// Create a new generator that picks the best out of `attempts` inputs.
+    pub fn new(attempts: usize) -> Self {
+        assert!(attempts > 0, "PeacockCoverageGenerator needs at least one attempt");
+
+        Self {
+            attempts,
+            generator: None,
+            capacity: default_sequence_capacity(),
+        }
+    }
+
+    /// Generate inputs with `generator` instead of the default generator.
+    pub fn with_generator(mut self, generator: &ffi::Generator) -> Self {
+        self.generator = Some(*generator);
+        self
+    }
+
+    /// Generate inputs whose sequences have room for `capacity` entries.
+    ///
+    /// Default: [`default_sequence_capacity()`] at the time of construction
+    pub fn with_capacity(mut self, capacity: usize) -> Self {
+        assert!(capacity > 0, "Sequence capacity must not be zero");
+        self.capacity = capacity;
+        self
+    }
+
+    /// The number of sequence entries that generated inputs have room for.
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+}
+
+impl<S> Generator<PeacockInput, S> for PeacockCoverageGenerator
+where
+    S: HasMetadata,
+{
+    fn generate(&mut self, state: &mut S) -> Result<PeacockInput, Error> {
+        let coverage = state.metadata_or_insert_with(PeacockCoverageMetadata::new);
+        let mut best: Option<(usize, PeacockInput)> = None;
+
+        for _ in 0..self.attempts {
+            let input = generate_input(self.generator, self.capacity);
+            let novelty = coverage.novelty(input.sequence());
+
+            if best.as_ref().is_none_or(|(score, _)| novelty > *score) {
+                best = Some((novelty, input));
+            }
+        }
+
+        let (_, input) = best.unwrap();
+        coverage.observe(input.sequence());
+        Ok(input)
+    }
+}
+
+/// This component regenerates a recorded input from its sequence, e.g. one that was attached to a bug report.
+///
+/// Every call to [`generate`](Generator::generate) returns an input with exactly the given sequence
+/// or fails if the sequence is not valid for the grammar of the generator.
+pub struct PeacockReplayGenerator {
+    sequence: Vec<usize>,
+    fingerprint: Option<u64>,
+    generator: Option<ffi::Generator>,
+}
+
+impl PeacockReplayGenerator {
+    /// Create a new generator that replays `sequence`.
+    pub fn from_sequence(sequence: Vec<usize>) -> Self {
+        Self {
+            sequence,
+            fingerprint: None,
+            generator: None,
+        }
+    }
+
+    /// Generate inputs with `generator` instead of the default generator.
+    pub fn with_generator(mut self, generator: &ffi::Generator) -> Self {
+        self.generator = Some(*generator);
+        self
+    }
+
+    /// Only replay the sequence if the grammar of the generator has the given fingerprint,
+    /// e.g. the one from [`PeacockInput::fingerprint`] of the input that the sequence was taken from.
+    /// Generators created by older versions of peacock do not have a fingerprint and are not checked.
+    pub fn with_fingerprint(mut self, fingerprint: u64) -> Self {
+        self.fingerprint = Some(fingerprint);
+        self
+    }
+
+    /// The sequence that gets replayed.
+    pub fn sequence(&self) -> &[usize] {
+        &self.sequence
+    }
+}
+
+impl<S> Generator<PeacockInput, S> for PeacockReplayGenerator {
+    fn generate(&mut self, _state: &mut S) -> Result<PeacockInput, Error> {
+        let mut input = PeacockInput::new(self.generator, self.sequence.len().max(default_sequence_capacity()));
+
+        if let (Some(expected), Some(actual)) = (self.fingerprint, input.fingerprint()) {
+            if expected != actual {
+                return Err(Error::illegal_argument(format!(
+                    "The sequence was recorded with a different grammar (fingerprint {:016x}, expected {:016x})",
+                    expected, actual
+                )));
+            }
+        }
+
+        if !input.generator().validate_sequence(&self.sequence) {
+            return Err(Error::illegal_argument("The sequence contains rules that don't exist in the grammar"));
+        }
+
+        input.sequence_mut().extend_from_slice(&self.sequence);
+        Ok(input)
+    }
+}
+
+#[cfg(all(test, not(feature = "static-loading")))]
+mod tests {
+    use super::*;
+    use crate::components::tests::load_test_generator;
+    use libafl::prelude::NopState;
+
+    #[test]
+    fn test_coverage_generator() {
+        load_test_generator();
+
+        let mut state = NopState::<PeacockInput>::new();
+        let mut generator = PeacockCoverageGenerator::new(8);
+
+        for _ in 0..32 {
+            let input = generator.generate(&mut state).unwrap();
+            let coverage = state.metadata::<PeacockCoverageMetadata>().unwrap();
+            assert_eq!(coverage.novelty(input.sequence()), 0);
+        }
+    }
+
+    #[test]
+    fn test_capacity() {
+        load_test_generator();
+
+        let mut state = NopState::<PeacockInput>::new();
+        let mut generator = PeacockGenerator::new().with_capacity(16);
+
+        for _ in 0..100 {
+            let input = generator.generate(&mut state).unwrap();
+            assert!(input.capacity() >= 16);
+            assert!(input.sequence().len() <= 16);
+        }
+
+        let mut generator = PeacockGenerator::new();
+        let input = generator.generate(&mut state).unwrap();
+        assert!(input.capacity() >= crate::components::DEFAULT_SEQUENCE_CAPACITY);
+    }
+
+    #[test]
+    fn test_default_capacity() {
+        use crate::{
+            backends::C::CGenerator,
+            components::{
+                default_sequence_capacity,
+                tests::compile_generator,
+                PeacockMutator,
+                DEFAULT_SEQUENCE_CAPACITY,
+            },
+            grammar::ContextFreeGrammar,
+        };
+        use libafl::prelude::Mutator;
+
+        /* Most derivations of this grammar never terminate */
+        let cfg = ContextFreeGrammar::builder()
+            .peacock_grammar_str(
+                r#"{"<ENTRYPOINT>": [["<E>"]], "<E>": [["<E>", "<E>", "<E>"], ["<E>", "<E>"], ["'x'"]]}"#,
+            )
+            .unwrap()
+            .optimize(false)
+            .build()
+            .unwrap();
+        let so_file = compile_generator(&cfg, CGenerator::new().symbol_prefix("capacity_"), "capacity");
+        let generator = ffi::Generator::load(&so_file, "capacity_");
+
+        /* Other tests rely on the global default, so the capacity is passed explicitly */
+        let mut small = PeacockGenerator::new().with_generator(&generator);
+        let mut large = PeacockGenerator::new().with_generator(&generator).with_capacity(1 << 17);
+        assert_eq!(small.capacity(), default_sequence_capacity());
+        assert_eq!(PeacockMutator::new().capacity(), default_sequence_capacity());
+
+        /* The generated code recurses once per level of the derivation tree */
+        std::thread::Builder::new()
+            .stack_size(1 << 29)
+            .spawn(move || {
+                let mut state = NopState::<PeacockInput>::new();
+                assert!(
+                    (0..32).all(|_| small.generate(&mut state).unwrap().sequence().len() <= DEFAULT_SEQUENCE_CAPACITY)
+                );
+                assert!(
+                    (0..32).any(|_| large.generate(&mut state).unwrap().sequence().len() > DEFAULT_SEQUENCE_CAPACITY)
+                );
+
+                /* Inputs that exhausted their capacity get more room on the next mutation */
+                let mut mutator = PeacockMutator::new().with_generator(&generator).with_capacity(64);
+                let mut input = PeacockInput::new(Some(generator), 64);
+
+                while input.sequence().len() < input.capacity() {
+                    input.sequence_mut().clear();
+                    generator.mutate(input.sequence_mut());
+                }
+
+                mutator.mutate(&mut state, &mut input).unwrap();
+                assert!(input.capacity() >= 128);
+            })
+            .unwrap()
+            .join()
+            .unwrap();
+    }
+
+    #[test]
+    fn test_explicit_seeds() {
+        use crate::{
+            backends::C::CGenerator,
+            components::tests::compile_generator,
+            grammar::ContextFreeGrammar,
+        };
+
+        load_test_generator();
+
+        let mut state = NopState::<PeacockInput>::new();
+        assert!(PeacockGenerator::new().with_explicit_seeds(0).generate(&mut state).is_err());
+
+        let cfg = ContextFreeGrammar::builder()
+            .gramatron_grammar("test-data/grammars/gramatron.json")
+            .unwrap()
+            .build()
+            .unwrap();
+        let so_file =
+            compile_generator(&cfg, CGenerator::new().symbol_prefix("seeded_").emit_seeded_variants(true), "seeded");
+        let generator = ffi::Generator::load(&so_file, "seeded_");
+        assert!(generator.supports_seeds());
+
+        let mut first = PeacockGenerator::new().with_generator(&generator).with_explicit_seeds(100);
+        let inputs: Vec<PeacockInput> = (0..20).map(|_| first.generate(&mut state).unwrap()).collect();
+        assert!(inputs.windows(2).any(|pair| pair[0].sequence() != pair[1].sequence()));
+
+        /* The shared RNG state does not influence seeded inputs and seeded inputs do not advance it */
+        generator.seed(1234);
+        let mut expected = Vec::with_capacity(4096);
+        generator.mutate(&mut expected);
+        generator.seed(1234);
+
+        let mut second = PeacockGenerator::new().with_generator(&generator).with_explicit_seeds(100);
+
+        for (i, input) in inputs.iter().enumerate() {
+            assert_eq!(input.seed(), Some(100 + i as u64));
+            assert_eq!(second.generate(&mut state).unwrap().sequence(), input.sequence());
+        }
+
+        let mut unseeded = Vec::with_capacity(4096);
+        generator.mutate(&mut unseeded);
+        assert_eq!(unseeded, expected);
+    }
+
+    #[test]
+    fn test_replay_generator() {
+        load_test_generator();
+
+        let mut state = NopState::<PeacockInput>::new();
+        let recorded = PeacockGenerator::new().generate(&mut state).unwrap();
+        let mut generator = PeacockReplayGenerator::from_sequence(recorded.sequence().to_vec())
+            .with_fingerprint(recorded.fingerprint().unwrap());
+
+        for _ in 0..2 {
+            let input = generator.generate(&mut state).unwrap();
+            assert_eq!(input.sequence(), recorded.sequence());
+            let bytes = input.serialize().to_vec();
+            assert_eq!(bytes, recorded.serialize());
+        }
+
+        let mut generator = PeacockReplayGenerator::from_sequence(recorded.sequence().to_vec())
+            .with_fingerprint(!recorded.fingerprint().unwrap());
+        assert!(generator.generate(&mut state).unwrap_err().to_string().contains("different grammar"));
+
+        let mut generator = PeacockReplayGenerator::from_sequence(vec![usize::MAX]);
+        assert!(generator.generate(&mut state).unwrap_err().to_string().contains("don't exist"));
+    }
+}
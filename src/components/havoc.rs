@@ -0,0 +1,352 @@
+use libafl::prelude::{
+    Corpus,
+    CorpusId,
+    Error,
+    HasCorpus,
+    HasMetadata,
+    HasRand,
+    HasSolutions,
+    Input,
+    MutationResult,
+    Mutator,
+    UsesInput,
+};
+use libafl_bolts::{
+    impl_serdeany,
+    prelude::{
+        Named,
+        Rand,
+    },
+};
+use serde::{
+    Deserialize,
+    Serialize,
+};
+use std::borrow::Cow;
+
+use crate::components::{
+    input::CORRUPTION_EXTENSION,
+    PeacockInput,
+};
+
+/// The default probability of [`PeacockHavocMutator`] to corrupt an input instead of mutating its structure
+pub const DEFAULT_CORRUPTION_PROBABILITY: f64 = 0.05;
+
+/// The maximum number of bytes that a single corruption flips, inserts or deletes
+const MAX_CORRUPTED_BYTES: usize = 4;
+
+/// Marks a testcase whose bytes were corrupted by a [`PeacockHavocMutator`] and could not be unparsed.
+/// The input of the testcase holds the sequence before the corruption and still delivers `bytes` to the target.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PeacockCorruptionMetadata {
+    /// The corrupted bytes, before the postprocessor was applied
+    pub bytes: Vec<u8>,
+}
+
+impl_serdeany!(PeacockCorruptionMetadata);
+
+/// This component combines a structural mutator with rare byte-level corruptions, such that the
+/// target also sees inputs that are not part of the language of the grammar and exercises its error paths.
+///
+/// With the configured probability, the serialization of an input gets a few random bit flips, insertions
+/// or deletions instead of a structural mutation by `inner`. The corrupted bytes are only delivered to the
+/// target, see [`PeacockInput::is_corrupted`], they never end up in the sequence of an input.
+/// If a corrupted input gets added to the corpus or to the solutions, its corruption is resolved after the execution:
+/// - If the corrupted bytes are part of the language, the sequence of the testcase is replaced with their unparsed sequence.
+/// - Otherwise the testcase keeps the corrupted bytes, such that re-executions see what the target saw, and gets a
+///   [`PeacockCorruptionMetadata`]. The bytes are also written next to the file of the testcase with the extension
+///   `.corrupted`, where [`PeacockInput::from_file`](libafl::prelude::Input::from_file) picks them up again.
+///   Mutations start from the sequence before the corruption.
+///
+/// The bytes are corrupted before the postprocessor is applied.
+pub struct PeacockHavocMutator<M> {
+    inner: M,
+    probability: f64,
+    corrupted: Option<Vec<u8>>,
+    solutions: usize,
+    name: Cow<'static, str>,
+}
+
+impl<M> PeacockHavocMutator<M>
+where
+    M: Named,
+{
+    /// Create a new mutator that applies `inner` unless it corrupts an input with the
+    /// [`DEFAULT_CORRUPTION_PROBABILITY`].
+    pub fn new(inner: M) -> Self {
+        let name = Cow::Owned(format!("PeacockHavocMutator<{}>", inner.name()));
+
+        Self {
+            inner,
+            probability: DEFAULT_CORRUPTION_PROBABILITY,
+            corrupted: None,
+            solutions: 0,
+            name,
+        }
+    }
+
+    /// Corrupt inputs with the given probability between 0 and 1. A probability of 0 never corrupts an input.
+    ///
+    /// Default: [`DEFAULT_CORRUPTION_PROBABILITY`]
+    pub fn with_probability(mut self, probability: f64) -> Self {
+        assert!((0.0..=1.0).contains(&probability), "Invalid corruption probability");
+        self.probability = probability;
+        self
+    }
+}
+
+impl<M> Named for PeacockHavocMutator<M> {
+    fn name(&self) -> &Cow<'static, str> {
+        &self.name
+    }
+}
+
+/// Flip, insert or delete up to [`MAX_CORRUPTED_BYTES`] random bytes of `bytes`
+fn corrupt<R: Rand>(rand: &mut R, bytes: &mut Vec<u8>) {
+    for _ in 0..rand.between(1, MAX_CORRUPTED_BYTES) {
+        let op = if bytes.is_empty() { 1 } else { rand.below(3) };
+
+        match op {
+            0 => {
+                let index = rand.below(bytes.len());
+                bytes[index] ^= 1 << rand.below(8);
+            },
+            1 => {
+                let index = rand.below(bytes.len() + 1);
+                bytes.insert(index, rand.below(256) as u8);
+            },
+            _ => {
+                let index = rand.below(bytes.len());
+                bytes.remove(index);
+            },
+        }
+    }
+}
+
+/// Resolve the corruption of the testcase `id` in `corpus` that was created from the corrupted `bytes`
+fn settle<C>(corpus: &C, id: CorpusId, bytes: &[u8]) -> Result<(), Error>
+where
+    C: Corpus<Input = PeacockInput>,
+{
+    let mut testcase = corpus.get(id)?.borrow_mut();
+    corpus.load_input_into(&mut testcase)?;
+
+    let path = testcase.file_path().clone();
+    let input = testcase.input_mut().as_mut().unwrap();
+    input.take_corruption();
+
+    if input.reparse(bytes) {
+        if let Some(path) = &path {
+            input.to_file(path)?;
+        }
+
+        return Ok(());
+    }
+
+    input.set_corruption(bytes.to_vec());
+
+    if let Some(path) = &path {
+        std::fs::write(path.with_extension(CORRUPTION_EXTENSION), bytes)?;
+    }
+
+    testcase.add_metadata(PeacockCorruptionMetadata {
+        bytes: bytes.to_vec(),
+    });
+    Ok(())
+}
+
+impl<M, S> Mutator<PeacockInput, S> for PeacockHavocMutator<M>
+where
+    M: Mutator<PeacockInput, S>,
+    S: HasRand + HasCorpus + HasSolutions + UsesInput<Input = PeacockInput>,
+{
+    fn mutate(&mut self, state: &mut S, input: &mut PeacockInput) -> Result<MutationResult, Error> {
+        self.corrupted = None;
+
+        if self.probability == 0.0 || !state.rand_mut().coinflip(self.probability) {
+            return self.inner.mutate(state, input);
+        }
+
+        let mut bytes = PeacockInput::serialize(input).to_vec();
+        corrupt(state.rand_mut(), &mut bytes);
+
+        self.corrupted = Some(bytes.clone());
+        self.solutions = state.solutions().count();
+        input.set_corruption(bytes);
+        Ok(MutationResult::Mutated)
+    }
+
+    fn post_exec(&mut self, state: &mut S, new_corpus_id: Option<CorpusId>) -> Result<(), Error> {
+        let Some(bytes) = self.corrupted.take() else {
+            return self.inner.post_exec(state, new_corpus_id);
+        };
+
+        if let Some(id) = new_corpus_id {
+            settle(state.corpus(), id, &bytes)?;
+        }
+
+        if state.solutions().count() > self.solutions {
+            if let Some(id) = state.solutions().last() {
+                settle(state.solutions(), id, &bytes)?;
+            }
+        }
+
+        self.inner.post_exec(state, new_corpus_id)
+    }
+}
+
+#[cfg(all(test, not(feature = "static-loading")))]
+mod tests {
+    use super::*;
+    use crate::components::{
+        ffi::generator_mutate,
+        tests::load_test_generator,
+        PeacockMutator,
+    };
+    use libafl::prelude::{
+        ConstFeedback,
+        InMemoryCorpus,
+        OnDiskCorpus,
+        StdState,
+        Testcase,
+    };
+    use libafl_bolts::prelude::StdRand;
+
+    fn random_input(min_len: usize) -> PeacockInput {
+        let mut input = PeacockInput::default();
+
+        while input.sequence().len() < min_len {
+            input.sequence_mut().clear();
+            generator_mutate(input.sequence_mut());
+        }
+
+        input
+    }
+
+    #[test]
+    fn test_havoc_mutation() {
+        load_test_generator();
+
+        let dir = std::env::temp_dir().join(format!("peacock-havoc-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let mut feedback = ConstFeedback::new(false);
+        let mut objective = ConstFeedback::new(false);
+        let mut state = StdState::new(
+            StdRand::with_seed(0),
+            InMemoryCorpus::<PeacockInput>::new(),
+            OnDiskCorpus::new(&dir).unwrap(),
+            &mut feedback,
+            &mut objective,
+        )
+        .unwrap();
+
+        let mut mutator = PeacockHavocMutator::new(PeacockMutator::new()).with_probability(1.0);
+        let (mut queued, mut solutions) = (0, 0);
+
+        for i in 0..200 {
+            let original = random_input(8);
+            let mut input = original.clone();
+            assert_eq!(mutator.mutate(&mut state, &mut input).unwrap(), MutationResult::Mutated);
+
+            /* Only the target sees the corruption */
+            assert!(input.is_corrupted());
+            assert_eq!(input.sequence(), original.sequence());
+            let corrupted = mutator.corrupted.clone().unwrap();
+
+            /* Pretend that the execution was interesting */
+            if i % 2 == 0 {
+                let id = state.corpus_mut().add(Testcase::new(input.clone())).unwrap();
+                mutator.post_exec(&mut state, Some(id)).unwrap();
+
+                let testcase = state.corpus().get(id).unwrap().borrow();
+                let stored = testcase.input().as_ref().unwrap();
+
+                match testcase.metadata::<PeacockCorruptionMetadata>() {
+                    Ok(metadata) => {
+                        assert_eq!(metadata.bytes, corrupted);
+                        assert_eq!(stored.sequence(), original.sequence());
+                        assert!(stored.is_corrupted());
+                    },
+                    Err(_) => {
+                        assert_eq!(PeacockInput::serialize(stored), corrupted);
+                        assert!(!stored.is_corrupted());
+                    },
+                }
+
+                queued += 1;
+            } else {
+                let id = state.solutions_mut().add(Testcase::new(input.clone())).unwrap();
+                mutator.post_exec(&mut state, None).unwrap();
+
+                let testcase = state.solutions().get(id).unwrap().borrow();
+                let path = testcase.file_path().clone().unwrap();
+                let stored = PeacockInput::from_file(&path).unwrap();
+
+                if testcase.has_metadata::<PeacockCorruptionMetadata>() {
+                    assert_eq!(stored.sequence(), original.sequence());
+                    assert!(stored.is_corrupted());
+                    assert_eq!(std::fs::read(path.with_extension("corrupted")).unwrap(), corrupted);
+                } else {
+                    assert_eq!(PeacockInput::serialize(&stored), corrupted);
+                    assert!(!stored.is_corrupted());
+                }
+
+                solutions += 1;
+            }
+
+            /* Structural mutations remove the corruption */
+            input.sequence_mut();
+            assert!(!input.is_corrupted());
+        }
+
+        assert_eq!((queued, solutions), (100, 100));
+
+        /* to_file() does not write corruptions */
+        let mut input = random_input(8);
+        input.set_corruption(b"corrupted".to_vec());
+        let path = dir.join("peacock-raw-input");
+        input.to_file(&path).unwrap();
+        let loaded = PeacockInput::from_file(&path).unwrap();
+        assert!(!loaded.is_corrupted());
+        assert_eq!(loaded.sequence(), input.sequence());
+
+        /* Without corruptions, the mutator is the inner mutator */
+        let mut mutator = PeacockHavocMutator::new(PeacockMutator::new()).with_probability(0.0);
+
+        for _ in 0..100 {
+            let mut input = random_input(8);
+            mutator.mutate(&mut state, &mut input).unwrap();
+            assert!(!input.is_corrupted());
+        }
+
+        /* The inner mutator sees every execution, also those of corruptions */
+        let mut mutator = PeacockHavocMutator::new(CountingMutator(0)).with_probability(1.0);
+        let mut input = random_input(8);
+        mutator.mutate(&mut state, &mut input).unwrap();
+        mutator.post_exec(&mut state, None).unwrap();
+        assert_eq!(mutator.inner.0, 1);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// Counts the calls to post_exec()
+    struct CountingMutator(usize);
+
+    impl Named for CountingMutator {
+        fn name(&self) -> &Cow<'static, str> {
+            static NAME: Cow<'static, str> = Cow::Borrowed("CountingMutator");
+            &NAME
+        }
+    }
+
+    impl<S> Mutator<PeacockInput, S> for CountingMutator {
+        fn mutate(&mut self, _state: &mut S, _input: &mut PeacockInput) -> Result<MutationResult, Error> {
+            Ok(MutationResult::Skipped)
+        }
+
+        fn post_exec(&mut self, _state: &mut S, _new_corpus_id: Option<CorpusId>) -> Result<(), Error> {
+            self.0 += 1;
+            Ok(())
+        }
+    }
+}
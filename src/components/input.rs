@@ -14,33 +14,392 @@ use serde::{
     Serialize,
 };
 use std::{
+    cell::UnsafeCell,
     fs::File,
+    hash::{
+        Hash,
+        Hasher,
+    },
     io::Read,
     path::Path,
+    sync::{
+        atomic::{
+            AtomicBool,
+            AtomicUsize,
+            Ordering,
+        },
+        RwLock,
+    },
 };
 
-use crate::components::ffi::{
-    generator_serialize,
-    generator_unparse,
+use crate::components::{
+    ffi::{
+        generator_fingerprint,
+        generator_validate,
+        Generator,
+        GeneratorRef,
+    },
+    postprocess::postprocess,
 };
 
 const BINARY_PREFIX: &str = "peacock-raw-";
-static mut SERIALIZATION_BUFFER: [u8; 128 * 1024 * 1024] = [0; 128 * 1024 * 1024];
+/// The extension of the file next to a raw input that holds the corrupted bytes that it delivers to the target
+pub(crate) const CORRUPTION_EXTENSION: &str = "corrupted";
+const SERIALIZATION_BUFFER_SIZE: usize = 128 * 1024 * 1024;
+static DISCARD_TRUNCATED: AtomicBool = AtomicBool::new(false);
+static SEQUENCE_CAPACITY: AtomicUsize = AtomicUsize::new(DEFAULT_SEQUENCE_CAPACITY);
+static IMPORT_OPTIONS: RwLock<ImportOptions> = RwLock::new(ImportOptions::new());
+
+/// The number of sequence entries that inputs have room for by default,
+/// unless it was changed with [`set_default_sequence_capacity()`].
+pub const DEFAULT_SEQUENCE_CAPACITY: usize = 4096 * 2;
+
+/// Set the number of sequence entries that inputs have room for by default.
+/// This applies to inputs that are created or loaded from disk afterwards and to the
+/// [`PeacockGenerator`](crate::components::PeacockGenerator) and [`PeacockMutator`](crate::components::PeacockMutator)
+/// that are created afterwards. Raise it for grammars whose derivation trees don't fit into
+/// [`DEFAULT_SEQUENCE_CAPACITY`] entries.
+///
+/// Default: [`DEFAULT_SEQUENCE_CAPACITY`]
+pub fn set_default_sequence_capacity(capacity: usize) {
+    assert!(capacity > 0, "Sequence capacity must not be zero");
+    SEQUENCE_CAPACITY.store(capacity, Ordering::Relaxed);
+}
+
+/// The number of sequence entries that inputs have room for by default. See [`set_default_sequence_capacity()`].
+pub fn default_sequence_capacity() -> usize {
+    SEQUENCE_CAPACITY.load(Ordering::Relaxed)
+}
+
+thread_local! {
+    /// The buffers that the inputs of this thread are serialized and postprocessed into.
+    /// A serialization must be consumed before the next one on the same thread starts.
+    static SERIALIZATION_BUFFER: UnsafeCell<Vec<u8>> = UnsafeCell::new(vec![0; SERIALIZATION_BUFFER_SIZE]);
+    static POSTPROCESSING_BUFFER: UnsafeCell<Vec<u8>> = const { UnsafeCell::new(Vec::new()) };
+}
+
+/// The serialization buffer of the current thread
+fn serialization_buffer() -> *mut u8 {
+    SERIALIZATION_BUFFER.with(|buf| unsafe { (*buf.get()).as_mut_ptr() })
+}
+
+/// If an input does not fit into the serialization buffer, the target receives a truncated
+/// input that most likely does not adhere to the grammar. Set this to `true` to deliver an
+/// empty input instead.
+///
+/// Default: `false`
+pub fn discard_truncated(flag: bool) {
+    DISCARD_TRUNCATED.store(flag, Ordering::Relaxed);
+}
+
+/// How [`PeacockInput::from_file`](Input::from_file) loads files.
+///
+/// Files that need more than `max_bytes` bytes or whose walk needs more than `max_steps` steps are not
+/// rejected but cut off: their input only covers a prefix of the file that fits into both bounds and
+/// the mutator grows the rest back.
+/// By default there are no bounds and files whose walk does not fit into the sequence get rejected.
+///
+/// ```
+/// # use peacock_fuzz::components::{set_import_options, ImportOptions};
+/// set_import_options(ImportOptions::new().max_bytes(1 << 20).max_steps(100_000));
+/// ```
+#[derive(Debug, Clone, Copy)]
+pub struct ImportOptions {
+    max_bytes: usize,
+    max_steps: usize,
+    force_import: Option<Generator>,
+}
+
+impl ImportOptions {
+    /// Options without any bounds
+    pub const fn new() -> Self {
+        Self {
+            max_bytes: usize::MAX,
+            max_steps: usize::MAX,
+            force_import: None,
+        }
+    }
+
+    /// Unparse at most `max_bytes` bytes of a file
+    pub fn max_bytes(mut self, max_bytes: usize) -> Self {
+        self.max_bytes = max_bytes;
+        self
+    }
+
+    /// Create walks with at most `max_steps` steps
+    pub fn max_steps(mut self, max_steps: usize) -> Self {
+        self.max_steps = max_steps;
+        self
+    }
+
+    /// If a raw input file was created with a different grammar than the one of the default generator,
+    /// loading it fails by default. Set this to the generator of that grammar to instead serialize the file
+    /// with `generator` and unparse the result with the default generator.
+    /// Files that cannot be expressed in the current grammar as a whole still fail to load.
+    pub fn force_import(mut self, generator: Generator) -> Self {
+        self.force_import = Some(generator);
+        self
+    }
+
+    fn is_bounded(&self) -> bool {
+        self.max_bytes != usize::MAX || self.max_steps != usize::MAX
+    }
+}
+
+impl Default for ImportOptions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Set how files that are loaded from disk afterwards are imported.
+///
+/// Default: [`ImportOptions::new()`]
+pub fn set_import_options(options: ImportOptions) {
+    *IMPORT_OPTIONS.write().unwrap() = options;
+}
+
+/// How files that are loaded from disk are imported. See [`set_import_options()`].
+pub fn import_options() -> ImportOptions {
+    *IMPORT_OPTIONS.read().unwrap()
+}
+
+/// Raw input files of older versions of peacock did not contain a grammar fingerprint.
+#[derive(Deserialize)]
+struct LegacyPeacockInput {
+    sequence: Vec<usize>,
+}
 
 /// This component represents an Input during fuzzing.
-#[derive(Serialize, Deserialize, Debug, Hash)]
+///
+/// An input is serialized with the [`Generator`] of the component that created it or with the
+/// default generator if it was loaded from disk.
+///
+/// Every entry of the sequence is the expansion of one non-terminal, so the capacity of the sequence
+/// bounds the size of the derivation tree and thus also its depth.
+/// When the generator runs out of capacity it stops and the serialization of the input ends
+/// at the first non-terminal that could not be expanded anymore.
+/// Grammars with deeply nested structures may need more than [`DEFAULT_SEQUENCE_CAPACITY`] entries,
+/// see [`set_default_sequence_capacity()`].
+#[derive(Serialize, Deserialize, Debug)]
 pub struct PeacockInput {
     sequence: Vec<usize>,
+    fingerprint: Option<u64>,
+    pinned: usize,
+    seed: Option<u64>,
+    #[serde(skip)]
+    generator: Option<Generator>,
+    #[serde(skip)]
+    corruption: Option<Vec<u8>>,
 }
 
 impl PeacockInput {
-    pub(crate) fn sequence(&self) -> &[usize] {
+    /// Create an empty input that belongs to `generator` or to the default generator if it is `None`
+    /// and has room for `capacity` sequence entries.
+    pub(crate) fn new(generator: Option<Generator>, capacity: usize) -> Self {
+        Self {
+            sequence: Vec::with_capacity(capacity),
+            fingerprint: generator.map_or_else(generator_fingerprint, |generator| generator.fingerprint()),
+            pinned: 0,
+            seed: None,
+            generator,
+            corruption: None,
+        }
+    }
+
+    /// Create an empty input that has room for `capacity` sequence entries.
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self::new(None, capacity)
+    }
+
+    /// The number of sequence entries that this input has room for.
+    pub fn capacity(&self) -> usize {
+        self.sequence.capacity()
+    }
+
+    /// The generator that serializes this input.
+    pub(crate) fn generator(&self) -> GeneratorRef {
+        Generator::or_default(self.generator)
+    }
+
+    /// Whether this input is serialized with the default generator.
+    #[cfg(not(feature = "static-loading"))]
+    pub(crate) fn uses_default_generator(&self) -> bool {
+        self.generator.is_none()
+    }
+
+    pub(crate) fn set_generator(&mut self, generator: Generator) {
+        self.generator = Some(generator);
+    }
+
+    /// The automaton walk of this input, i.e. the rule that was taken for every non-terminal.
+    pub fn sequence(&self) -> &[usize] {
         &self.sequence
     }
 
+    /// The sequence of this input for modification. The input does not have a seed or a corruption anymore afterwards.
     pub(crate) fn sequence_mut(&mut self) -> &mut Vec<usize> {
+        self.seed = None;
+        self.corruption = None;
         &mut self.sequence
     }
+
+    /// Whether the [`PeacockHavocMutator`](crate::components::PeacockHavocMutator) replaced the serialization
+    /// of this input with corrupted bytes. [`to_file()`](Input::to_file) does not write the corruption,
+    /// but [`from_file()`](Input::from_file) reads it from a `.corrupted` file next to a raw input.
+    pub fn is_corrupted(&self) -> bool {
+        self.corruption.is_some()
+    }
+
+    /// Deliver `bytes` to the target instead of the serialization of the sequence until the sequence changes.
+    pub(crate) fn set_corruption(&mut self, bytes: Vec<u8>) {
+        self.corruption = Some(bytes);
+    }
+
+    pub(crate) fn take_corruption(&mut self) -> Option<Vec<u8>> {
+        self.corruption.take()
+    }
+
+    /// The fingerprint of the grammar that this input was created with.
+    pub fn fingerprint(&self) -> Option<u64> {
+        self.fingerprint
+    }
+
+    /// The seed that [`PeacockGenerator::with_explicit_seeds`](crate::components::PeacockGenerator::with_explicit_seeds)
+    /// created this input from. Inputs that were mutated afterwards don't have a seed.
+    pub fn seed(&self) -> Option<u64> {
+        self.seed
+    }
+
+    pub(crate) fn set_seed(&mut self, seed: u64) {
+        self.seed = Some(seed);
+    }
+
+    /// The number of sequence entries at the start of this input that mutators must leave intact.
+    pub fn pinned_len(&self) -> usize {
+        self.pinned
+    }
+
+    /// Pin the first `len` sequence entries of this input such that mutations only happen in the
+    /// remaining suffix. This can be used to keep e.g. the handshake of a protocol intact.
+    pub fn set_pinned_len(&mut self, len: usize) {
+        self.pinned = len;
+    }
+
+    /// Translate this input from the grammar of `from` to the grammar of `to` by unparsing its serialization.
+    /// Returns `false` and empties the input if it cannot be expressed in the new grammar,
+    /// which includes serializations of which only a prefix matches the new grammar.
+    /// The pinned prefix gets released because the steps of the old sequence don't exist anymore.
+    pub(crate) fn migrate(&mut self, from: &Generator, to: &Generator) -> bool {
+        let buf = serialization_buffer();
+        let (len, truncated) = from.serialize(&self.sequence, buf, SERIALIZATION_BUFFER_SIZE);
+        let serialized = unsafe { std::slice::from_raw_parts(buf as *const u8, len) };
+        let len = self.sequence.len();
+
+        self.fingerprint = to.fingerprint();
+        self.pinned = 0;
+        self.seed = None;
+        self.corruption = None;
+        self.sequence.clear();
+        self.reserve_headroom(len);
+
+        if truncated || !to.unparse(&mut self.sequence, serialized) {
+            self.sequence.clear();
+            return false;
+        }
+
+        true
+    }
+
+    /// Make room for `len` entries and leave enough headroom that mutations can grow the sequence.
+    fn reserve_headroom(&mut self, len: usize) {
+        let capacity = len.saturating_mul(2).max(default_sequence_capacity());
+        self.sequence.reserve(capacity.saturating_sub(self.sequence.len()));
+    }
+
+    /// Decode a raw input file. Returns whether the file is in the legacy format without a fingerprint.
+    fn decode_raw(bytes: &[u8]) -> Result<(Self, bool), Error> {
+        if let Ok(mut input) = postcard::from_bytes::<Self>(bytes) {
+            /* The deserialized sequence has no room left */
+            input.reserve_headroom(input.sequence.len());
+            return Ok((input, false));
+        }
+
+        let legacy: LegacyPeacockInput = postcard::from_bytes(bytes)?;
+        let mut input = Self::default();
+        input.reserve_headroom(legacy.sequence.len());
+        input.sequence.extend_from_slice(&legacy.sequence);
+        input.fingerprint = None;
+        Ok((input, true))
+    }
+
+    /// Unparse `bytes` with the default generator within the bounds of `options`.
+    /// On failure, returns where they diverged from the grammar.
+    fn unparse(bytes: &[u8], options: ImportOptions) -> Result<Self, String> {
+        let mut ret = Self::default();
+
+        let generator = Generator::expect_default();
+        let success = if options.is_bounded() {
+            generator.unparse_bounded(&mut ret.sequence, bytes, options.max_bytes, options.max_steps).is_some()
+        } else {
+            generator.unparse(&mut ret.sequence, bytes)
+        };
+
+        if !success {
+            let mut reason = String::new();
+
+            if let Some(failure) = generator.last_unparse_failure() {
+                let (line, column) = failure.line_column(bytes);
+                reason = format!(": diverged from the grammar at {} (line {}, column {})", failure, line, column);
+            }
+
+            return Err(reason);
+        }
+
+        Ok(ret)
+    }
+
+    fn unparse_file(path: &Path, bytes: &[u8], options: ImportOptions) -> Result<Self, Error> {
+        Self::unparse(bytes, options).map_err(|reason| {
+            Error::serialize(format!("Could not unparse sequence from input file {}{}", path.display(), reason))
+        })
+    }
+
+    /// Create an input for the default generator from the bytes it serializes to.
+    /// This is the inverse of [`HasTargetBytes::target_bytes`] as long as no postprocessor is registered.
+    pub fn try_from_bytes(bytes: &[u8]) -> Result<Self, Error> {
+        Self::unparse(bytes, ImportOptions::new())
+            .map_err(|reason| Error::serialize(format!("Could not unparse sequence{}", reason)))
+    }
+
+    /// Replace the sequence of this input with the one that serializes to `bytes` in the grammar of its generator.
+    /// Returns `false` and leaves the input untouched if that is not possible or if the pinned prefix would change.
+    pub(crate) fn reparse(&mut self, bytes: &[u8]) -> bool {
+        let mut sequence = Vec::with_capacity(self.sequence.capacity().max(default_sequence_capacity()));
+        let pinned = &self.sequence[..self.pinned.min(self.sequence.len())];
+
+        if !self.generator().unparse(&mut sequence, bytes) || !sequence.starts_with(pinned) {
+            return false;
+        }
+
+        self.sequence = sequence;
+        self.seed = None;
+        self.corruption = None;
+        true
+    }
+
+    /// Serialize this input without applying the postprocessor.
+    /// The returned slice lives in a buffer that gets overwritten by the next serialization.
+    pub(crate) fn serialize(&self) -> &[u8] {
+        let buf = serialization_buffer();
+        let (mut len, truncated) = self.generator().serialize(&self.sequence, buf, SERIALIZATION_BUFFER_SIZE);
+
+        if truncated && DISCARD_TRUNCATED.load(Ordering::Relaxed) {
+            len = 0;
+        }
+
+        unsafe { std::slice::from_raw_parts(buf as *const u8, len) }
+    }
 }
 
 impl Input for PeacockInput {
@@ -50,7 +409,13 @@ impl Input for PeacockInput {
     }
 
     fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
-        let path = path.as_ref();
+        Self::import(path.as_ref(), import_options())
+    }
+}
+
+impl PeacockInput {
+    /// Load an input from disk like [`from_file`](Input::from_file) but with explicit `options`
+    fn import(path: &Path, options: ImportOptions) -> Result<Self, Error> {
         let mut file = File::open(path)?;
         let mut bytes: Vec<u8> = vec![];
         file.read_to_end(&mut bytes)?;
@@ -61,17 +426,57 @@ impl Input for PeacockInput {
             false
         };
 
-        if is_raw {
-            Ok(postcard::from_bytes(&bytes)?)
-        } else {
-            let mut ret = Self::default();
+        if !is_raw {
+            return Self::unparse_file(path, &bytes, options);
+        }
 
-            if !generator_unparse(&mut ret.sequence, &bytes) {
-                return Err(Error::serialize(format!("Could not unparse sequence from input file {}", path.display())));
-            }
+        let (mut input, legacy) = Self::decode_raw(&bytes)?;
+
+        if legacy {
+            log::warn!(
+                "{} does not contain a grammar fingerprint. Support for such files will be removed in the next release.",
+                path.display()
+            );
+        }
 
-            Ok(ret)
+        match (input.fingerprint, generator_fingerprint()) {
+            (Some(theirs), Some(ours)) if theirs != ours => {
+                /* The indices of the sequence only have a meaning in the grammar of the generator that created them */
+                let Some(foreign) = options.force_import.filter(|generator| generator.fingerprint() == Some(theirs))
+                else {
+                    return Err(Error::serialize(format!(
+                        "Input file {} was created with a different grammar (fingerprint {:016x}, expected {:016x})",
+                        path.display(),
+                        theirs,
+                        ours
+                    )));
+                };
+
+                if !foreign.validate_sequence(&input.sequence) {
+                    return Err(Error::serialize(format!(
+                        "Input file {} contains rules that don't exist in its grammar",
+                        path.display()
+                    )));
+                }
+
+                if !input.migrate(&foreign, &Generator::expect_default()) {
+                    return Err(Error::serialize(format!(
+                        "Input file {} cannot be expressed in the current grammar",
+                        path.display()
+                    )));
+                }
+            },
+            _ if !generator_validate(&input.sequence) => {
+                return Err(Error::serialize(format!(
+                    "Input file {} contains rules that don't exist in the grammar",
+                    path.display()
+                )));
+            },
+            _ => {},
         }
+
+        input.corruption = std::fs::read(path.with_extension(CORRUPTION_EXTENSION)).ok();
+        Ok(input)
     }
 }
 
@@ -82,27 +487,300 @@ impl HasLen for PeacockInput {
 }
 
 impl HasTargetBytes for PeacockInput {
-    fn target_bytes(&self) -> OwnedSlice<u8> {
-        let len = generator_serialize(&self.sequence, unsafe { SERIALIZATION_BUFFER.as_mut_ptr() }, unsafe {
-            SERIALIZATION_BUFFER.len()
+    fn target_bytes(&self) -> OwnedSlice<'_, u8> {
+        let serialized = match &self.corruption {
+            Some(corruption) => corruption.as_slice(),
+            None => self.serialize(),
+        };
+
+        /* Wrap the input only if a postprocessor was registered, the buffer is reused across calls */
+        let processed = POSTPROCESSING_BUFFER.with(|buf| {
+            postprocess(serialized, unsafe { &mut *buf.get() }).map(|processed| (processed.as_ptr(), processed.len()))
         });
 
-        unsafe { OwnedSlice::from_raw_parts(SERIALIZATION_BUFFER.as_ptr(), len) }
+        if let Some((ptr, len)) = processed {
+            return unsafe { OwnedSlice::from_raw_parts(ptr, len) };
+        }
+
+        unsafe { OwnedSlice::from_raw_parts(serialized.as_ptr(), serialized.len()) }
+    }
+}
+
+impl Hash for PeacockInput {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.sequence.hash(state);
+        self.fingerprint.hash(state);
+        self.pinned.hash(state);
     }
 }
 
 impl Default for PeacockInput {
     fn default() -> Self {
-        Self {
-            sequence: Vec::with_capacity(4096 * 2),
-        }
+        Self::new(None, default_sequence_capacity())
     }
 }
 
 impl Clone for PeacockInput {
     fn clone(&self) -> Self {
-        let mut clone = Self::default();
+        let mut clone = Self::new(self.generator, self.sequence.capacity().max(default_sequence_capacity()));
         clone.sequence.extend_from_slice(&self.sequence);
+        clone.fingerprint = self.fingerprint;
+        clone.pinned = self.pinned;
+        clone.seed = self.seed;
+        clone.corruption = self.corruption.clone();
         clone
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_legacy_format() {
+        #[derive(Serialize)]
+        struct Legacy {
+            sequence: Vec<usize>,
+        }
+
+        let bytes = postcard::to_allocvec(&Legacy {
+            sequence: vec![1, 2, 3],
+        })
+        .unwrap();
+        let (input, legacy) = PeacockInput::decode_raw(&bytes).unwrap();
+        assert!(legacy);
+        assert_eq!(input.sequence(), &[1, 2, 3]);
+        assert_eq!(input.fingerprint(), None);
+    }
+
+    #[test]
+    fn test_fingerprint_roundtrip() {
+        let mut input = PeacockInput::default();
+        input.sequence_mut().extend_from_slice(&[4, 5, 6]);
+        input.fingerprint = Some(0x1234);
+        input.set_pinned_len(2);
+
+        let bytes = postcard::to_allocvec(&input).unwrap();
+        let (input, legacy) = PeacockInput::decode_raw(&bytes).unwrap();
+        assert!(!legacy);
+        assert_eq!(input.sequence(), &[4, 5, 6]);
+        assert_eq!(input.fingerprint(), Some(0x1234));
+        assert_eq!(input.pinned_len(), 2);
+        assert_eq!(input.seed(), None);
+
+        let mut input = PeacockInput::default();
+        input.sequence_mut().push(1);
+        input.set_seed(42);
+        let bytes = postcard::to_allocvec(&input).unwrap();
+        let (copy, _) = PeacockInput::decode_raw(&bytes).unwrap();
+        assert_eq!(copy.seed(), Some(42));
+        input.sequence_mut().push(2);
+        assert_eq!(input.seed(), None);
+    }
+
+    #[cfg(not(feature = "static-loading"))]
+    #[test]
+    fn test_truncation() {
+        use crate::components::{
+            ffi::{
+                generator_mutate,
+                generator_serialize,
+            },
+            tests::load_test_generator,
+        };
+
+        load_test_generator();
+
+        let mut out = vec![0u8; 1 << 20];
+        let mut input = PeacockInput::default();
+        let mut len = 0;
+
+        while len < 2 {
+            input.sequence_mut().clear();
+            generator_mutate(input.sequence_mut());
+            (len, _) = generator_serialize(input.sequence(), out.as_mut_ptr(), out.len());
+        }
+
+        let (_, truncated) = generator_serialize(input.sequence(), out.as_mut_ptr(), len);
+        assert!(!truncated);
+
+        let (clipped, truncated) = generator_serialize(input.sequence(), out.as_mut_ptr(), len - 1);
+        assert!(truncated);
+        assert!(clipped < len);
+    }
+
+    #[cfg(not(feature = "static-loading"))]
+    #[test]
+    fn test_unparse_error() {
+        crate::components::tests::load_test_generator();
+
+        let path = std::env::temp_dir().join(format!("peacock-corrupted-{}", std::process::id()));
+        std::fs::write(&path, b"\x00\x01").unwrap();
+        let err = PeacockInput::from_file(&path).unwrap_err().to_string();
+        assert!(err.contains("diverged from the grammar at offset 0 while matching <"), "{}", err);
+        assert!(err.contains("(line 1, column 1)"), "{}", err);
+    }
+
+    #[cfg(not(feature = "static-loading"))]
+    #[test]
+    fn test_import_options() {
+        use crate::components::ffi::{
+            generator_mutate,
+            generator_serialize,
+        };
+
+        crate::components::tests::load_test_generator();
+
+        let mut out = vec![0u8; 1 << 20];
+        let mut input = PeacockInput::default();
+        let mut len = 0;
+
+        while len < 1024 {
+            input.sequence_mut().clear();
+            generator_mutate(input.sequence_mut());
+            (len, _) = generator_serialize(input.sequence(), out.as_mut_ptr(), out.len());
+        }
+
+        let bytes = out[..len].to_vec();
+        let mut imported = PeacockInput::unparse(&bytes, ImportOptions::new().max_bytes(256).max_steps(64)).unwrap();
+        assert!(!imported.sequence().is_empty() && imported.sequence().len() <= 64);
+        assert!(imported.sequence().len() < PeacockInput::try_from_bytes(&bytes).unwrap().sequence().len());
+
+        let (prefix_len, _) = generator_serialize(imported.sequence(), out.as_mut_ptr(), out.len());
+        let common = out[..prefix_len].iter().zip(&bytes).take_while(|(a, b)| a == b).count();
+        assert!(common > 0);
+
+        /* The mutator grows the cut off walk into a complete one */
+        generator_mutate(imported.sequence_mut());
+        let (len, _) = generator_serialize(imported.sequence(), out.as_mut_ptr(), out.len());
+        assert!(PeacockInput::try_from_bytes(&out[..len]).is_ok());
+    }
+
+    #[cfg(not(feature = "static-loading"))]
+    #[test]
+    fn test_invalid_rules() {
+        use crate::components::ffi::generator_mutate;
+
+        crate::components::tests::load_test_generator();
+
+        let path = std::env::temp_dir().join(format!("{}invalid-{}", BINARY_PREFIX, std::process::id()));
+        let mut input = PeacockInput::default();
+        generator_mutate(input.sequence_mut());
+        std::fs::write(&path, postcard::to_allocvec(&input).unwrap()).unwrap();
+        assert_eq!(PeacockInput::from_file(&path).unwrap().sequence(), input.sequence());
+
+        input.sequence_mut()[0] = usize::MAX;
+        std::fs::write(&path, postcard::to_allocvec(&input).unwrap()).unwrap();
+        let err = PeacockInput::from_file(&path).unwrap_err().to_string();
+        assert!(err.contains("contains rules that don't exist in the grammar"), "{}", err);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[cfg(not(feature = "static-loading"))]
+    #[test]
+    fn test_force_import() {
+        use crate::{
+            backends::C::CGenerator,
+            components::tests::compile_generator,
+            grammar::ContextFreeGrammar,
+        };
+
+        crate::components::tests::load_test_generator();
+
+        /* The same language as the default generator but with different rules */
+        let cfg = ContextFreeGrammar::builder()
+            .gramatron_grammar("test-data/grammars/gramatron.json")
+            .unwrap()
+            .optimize(false)
+            .build()
+            .unwrap();
+        let so_file = compile_generator(&cfg, CGenerator::new().symbol_prefix("foreign_"), "foreign_");
+        let foreign = Generator::load(&so_file, "foreign_");
+        assert_ne!(foreign.fingerprint(), generator_fingerprint());
+
+        let path = std::env::temp_dir().join(format!("{}foreign-{}", BINARY_PREFIX, std::process::id()));
+        let mut input = PeacockInput::new(Some(foreign), DEFAULT_SEQUENCE_CAPACITY);
+
+        /* Unparsing a large input takes a while */
+        while input.sequence().is_empty() || input.serialize().len() > 256 {
+            input.sequence_mut().clear();
+            foreign.mutate(input.sequence_mut());
+        }
+
+        let expected = input.serialize().to_vec();
+        std::fs::write(&path, postcard::to_allocvec(&input).unwrap()).unwrap();
+
+        let err = PeacockInput::import(&path, ImportOptions::new()).unwrap_err().to_string();
+        assert!(err.contains("was created with a different grammar"), "{}", err);
+
+        /* The generator of a grammar that the file was not created with does not help */
+        let options = ImportOptions::new().force_import(*Generator::expect_default());
+        let err = PeacockInput::import(&path, options).unwrap_err().to_string();
+        assert!(err.contains("was created with a different grammar"), "{}", err);
+
+        let imported = PeacockInput::import(&path, ImportOptions::new().force_import(foreign));
+        std::fs::remove_file(&path).unwrap();
+
+        let imported = imported.unwrap();
+        assert_eq!(imported.serialize(), &expected[..]);
+        assert_eq!(imported.fingerprint(), generator_fingerprint());
+    }
+
+    #[cfg(not(feature = "static-loading"))]
+    #[test]
+    fn test_migrate() {
+        use crate::{
+            backends::C::CGenerator,
+            components::tests::compile_generator,
+            grammar::ContextFreeGrammar,
+        };
+
+        let mut generators = Vec::new();
+
+        for (name, grammar) in [
+            ("migrate_a_", r#"{"<ENTRYPOINT>": [["'a'", "<ENTRYPOINT>"], ["'a'"]]}"#),
+            ("migrate_ab_", r#"{"<ENTRYPOINT>": [["<C>", "<ENTRYPOINT>"], ["<C>"]], "<C>": [["'a'"], ["'b'"]]}"#),
+        ] {
+            let path = std::env::temp_dir().join(format!("peacock-{}{}.json", name, std::process::id()));
+            std::fs::write(&path, grammar).unwrap();
+            let cfg = ContextFreeGrammar::builder().peacock_grammar(&path).unwrap().optimize(false).build().unwrap();
+            let so_file = compile_generator(&cfg, CGenerator::new().symbol_prefix(name), name);
+            generators.push(Generator::load(&so_file, name));
+        }
+
+        let serialize = |input: &PeacockInput, generator: &Generator| {
+            let mut out = vec![0u8; 1 << 16];
+            let (len, _) = generator.serialize(input.sequence(), out.as_mut_ptr(), out.len());
+            out.truncate(len);
+            out
+        };
+
+        let (a, ab) = (&generators[0], &generators[1]);
+        let mut input = PeacockInput::new(Some(*a), DEFAULT_SEQUENCE_CAPACITY);
+        a.mutate(input.sequence_mut());
+        input.set_pinned_len(1);
+        let expected = serialize(&input, a);
+
+        assert!(input.migrate(a, ab));
+        assert_eq!(serialize(&input, ab), expected);
+        assert_eq!(input.fingerprint(), ab.fingerprint());
+        assert_eq!(input.pinned_len(), 0);
+
+        let mut input = PeacockInput::new(Some(*ab), DEFAULT_SEQUENCE_CAPACITY);
+
+        while !serialize(&input, ab).contains(&b'b') {
+            input.sequence_mut().clear();
+            ab.mutate(input.sequence_mut());
+        }
+
+        assert!(!input.migrate(ab, a));
+        assert!(input.sequence().is_empty());
+        assert_eq!(input.fingerprint(), a.fingerprint());
+
+        /* Only the prefix "aa" of this input can be expressed in the new grammar */
+        let mut input = PeacockInput::new(Some(*ab), DEFAULT_SEQUENCE_CAPACITY);
+        assert!(ab.unparse(input.sequence_mut(), b"aab"));
+        assert!(!input.migrate(ab, a));
+        assert!(input.sequence().is_empty());
+    }
+}
@@ -14,16 +14,423 @@
 //! For an example of dynamic loading see the binary `peacock-fuzz` in `src/bin/fuzz.rs`.    
 //! For an example of static loading see the fuzzer in `test-data/static_loading/src/main.rs`.
 
+mod bytes;
 pub(crate) mod ffi;
 mod generator;
+mod havoc;
 mod input;
 mod mutator;
+mod postprocess;
+#[cfg(not(feature = "static-loading"))]
+mod reload;
+mod stats;
+mod tokens;
 
+pub use bytes::{
+    PeacockBytesMutator,
+    PeacockBytesView,
+};
+#[cfg(not(feature = "static-loading"))]
+pub use ffi::reload_generator;
 pub use ffi::{
     generator_seed as seed_generator,
     load_generator,
+    serialize_to_vec,
+    Capabilities,
+    Generator,
+    LoadedGenerator,
+    UnparseFailure,
+};
+
+pub use generator::{
+    PeacockCoverageGenerator,
+    PeacockCoverageMetadata,
+    PeacockGenerator,
+    PeacockReplayGenerator,
+};
+pub use havoc::{
+    PeacockCorruptionMetadata,
+    PeacockHavocMutator,
+    DEFAULT_CORRUPTION_PROBABILITY,
+};
+pub use input::{
+    default_sequence_capacity,
+    discard_truncated,
+    import_options,
+    set_default_sequence_capacity,
+    set_import_options,
+    ImportOptions,
+    PeacockInput,
+    DEFAULT_SEQUENCE_CAPACITY,
+};
+pub use mutator::{
+    PeacockMutator,
+    PeacockPointMutator,
+};
+pub use postprocess::{
+    set_postprocessor,
+    Base64,
+    Chain,
+    Crc32,
+    Envelope,
+    InputPostprocessor,
+    LengthPrefix,
+};
+#[cfg(not(feature = "static-loading"))]
+pub use reload::GeneratorReloadStage;
+pub use stats::{
+    PeacockStatsMetadata,
+    PeacockStatsStage,
 };
+pub use tokens::{
+    load_dynamic_terminals,
+    TokenCollectorMetadata,
+    TokenCollectorStage,
+};
+
+#[cfg(all(test, not(feature = "static-loading")))]
+pub(crate) mod tests {
+    use libafl::prelude::{
+        Generator as _,
+        HasTargetBytes,
+        Mutator,
+        NopState,
+    };
+    use std::{
+        path::PathBuf,
+        sync::{
+            atomic::{
+                AtomicBool,
+                Ordering,
+            },
+            Arc,
+            Once,
+        },
+        time::Duration,
+    };
+
+    use super::*;
+    use crate::{
+        backends::{
+            interpreter::GrammarInterpreter,
+            C::{
+                compile,
+                CGenerator,
+                CompileOptions,
+            },
+        },
+        grammar::ContextFreeGrammar,
+    };
+
+    /// Compile the code of `generator` for `cfg` into the shared object `<name>.so` in the temp directory.
+    pub(crate) fn compile_generator(cfg: &ContextFreeGrammar, generator: CGenerator, name: &str) -> PathBuf {
+        let dir = std::env::temp_dir();
+        let c_file = dir.join(format!("peacock-{}-{}.c", name, std::process::id()));
+        let so_file = dir.join(format!("peacock-{}-{}.so", name, std::process::id()));
+
+        let sources = generator.generate(&c_file, cfg);
+        compile(&so_file, &sources, &CompileOptions::new().flags(["-O0", "-fPIC", "-shared"])).unwrap();
+
+        so_file
+    }
+
+    /// Compile the gramatron test grammar into a shared object and load it.
+    pub(crate) fn load_test_generator() {
+        static LOAD: Once = Once::new();
+
+        LOAD.call_once(|| {
+            let cfg = ContextFreeGrammar::builder()
+                .gramatron_grammar("test-data/grammars/gramatron.json")
+                .unwrap()
+                .build()
+                .unwrap();
+            let so_file = compile_generator(&cfg, CGenerator::new().emit_statistics(true), "test");
+
+            load_generator(so_file);
+        });
+    }
+
+    #[test]
+    fn test_symbol_prefix() {
+        let mut handles = Vec::new();
+
+        for (letter, prefix) in [('a', "first_"), ('b', "second_")] {
+            let path = std::env::temp_dir().join(format!("peacock-prefix-{}-{}.json", letter, std::process::id()));
+            std::fs::write(&path, format!(r#"{{"<ENTRYPOINT>": [["'{0}'", "<ENTRYPOINT>"], ["'{0}'"]]}}"#, letter))
+                .unwrap();
+            let cfg = ContextFreeGrammar::builder().peacock_grammar(&path).unwrap().build().unwrap();
+            let so_file = compile_generator(&cfg, CGenerator::new().symbol_prefix(prefix), prefix);
+
+            let lib = unsafe { libloading::Library::new(&so_file) }.unwrap();
+            assert!(unsafe { lib.get::<unsafe extern "C" fn()>(b"mutate_sequence") }.is_err());
+
+            handles.push((letter as u8, Generator::load(&so_file, prefix)));
+        }
+
+        assert_ne!(handles[0].1.fingerprint(), handles[1].1.fingerprint());
+
+        let mut state = NopState::<PeacockInput>::new();
+
+        for (letter, handle) in &handles {
+            let mut generator = PeacockGenerator::new().with_generator(handle);
+            let mut mutator = PeacockMutator::new().with_generator(handle);
+
+            for _ in 0..100 {
+                let mut input = generator.generate(&mut state).unwrap();
+                assert_eq!(input.fingerprint(), handle.fingerprint());
+                mutator.mutate(&mut state, &mut input).unwrap();
+
+                let bytes = input.target_bytes();
+                assert!(!bytes.is_empty());
+                assert!(bytes.iter().all(|byte| byte == letter));
+            }
+        }
+    }
+
+    #[test]
+    fn test_serialize_to_vec() {
+        load_test_generator();
+
+        for _ in 0..100 {
+            let input = PeacockGenerator::new().generate(&mut NopState::<PeacockInput>::new()).unwrap();
+            assert_eq!(serialize_to_vec(input.sequence()), input.serialize());
+        }
+
+        /* Outputs that are larger than the first buffer */
+        let path = std::env::temp_dir().join(format!("peacock-to-vec-{}.json", std::process::id()));
+        std::fs::write(&path, r#"{"<ENTRYPOINT>": [["@repeat('A', 10000)", "<ENTRYPOINT>"], ["'B'"]]}"#).unwrap();
+        let cfg = ContextFreeGrammar::builder().peacock_grammar(&path).unwrap().build().unwrap();
+        let so_file = compile_generator(&cfg, CGenerator::new().symbol_prefix("to_vec_"), "to_vec");
+        let generator = Generator::load(&so_file, "to_vec_");
+        let mut sequence = Vec::with_capacity(4096);
+        let mut out = vec![0; 16 * 1024 * 1024];
+
+        for _ in 0..20 {
+            sequence.clear();
+            generator.mutate(&mut sequence);
+
+            let bytes = generator.serialize_to_vec(&sequence);
+            let (len, truncated) = generator.serialize(&sequence, out.as_mut_ptr(), out.len());
+            assert!(!truncated);
+            assert_eq!(bytes, &out[..len]);
+            assert_eq!(bytes.last(), Some(&b'B'));
+            assert_eq!((bytes.len() - 1) % 10000, 0);
+        }
+
+        assert!(generator.serialize_to_vec(&[]).is_empty());
+    }
+
+    #[test]
+    fn test_unparse_failure() {
+        let cfg = ContextFreeGrammar::builder()
+            .peacock_grammar("test-data/grammars/unparse.json")
+            .unwrap()
+            .optimize(false)
+            .build()
+            .unwrap();
+
+        let variants = [(false, false, "why_"), (true, false, "why_thorough_"), (false, true, "why_memo_")];
+
+        for (thorough, memoized, prefix) in variants {
+            let generator =
+                CGenerator::new().symbol_prefix(prefix).thorough_unparsing(thorough).memoized_unparsing(memoized);
+            let so_file = compile_generator(&cfg, generator, prefix);
+            let generator = Generator::load(&so_file, prefix);
+            let mut sequence = Vec::with_capacity(4096);
+
+            assert!(generator.unparse(&mut sequence, b"a=1\nb=2\n"));
+            assert_eq!(generator.last_unparse_failure(), None);
+
+            let input = b"a=1\nb=2\nb=3\na=1\n";
+            assert!(!generator.unparse(&mut sequence, input));
+            let failure = generator.last_unparse_failure().unwrap();
+            assert_eq!(
+                failure,
+                UnparseFailure {
+                    offset: 10,
+                    non_terminal: "VALUE".to_string(),
+                }
+            );
+            assert_eq!(failure.line_column(input), (3, 3));
+
+            /* Trailing input */
+            assert!(!generator.unparse(&mut sequence, b"a=1\n\n"));
+            assert_eq!(generator.last_unparse_failure().unwrap().offset, 4);
+        }
+    }
+
+    #[test]
+    fn test_capabilities() {
+        let grammar = |name: &str| {
+            ContextFreeGrammar::builder()
+                .peacock_grammar(format!("test-data/grammars/{}", name))
+                .unwrap()
+                .build()
+                .unwrap()
+        };
+        let cfg = grammar("regex.json");
+        let other = grammar("unparse.json");
+        let generator = CGenerator::new()
+            .symbol_prefix("serialize_only_")
+            .emit_mutation_procedure(false)
+            .emit_unparsing_procedure(false);
+        let so_file = compile_generator(&cfg, generator, "serialize_only_");
+        let generator = Generator::load(&so_file, "serialize_only_");
+
+        assert_eq!(
+            generator.capabilities(),
+            Capabilities {
+                mutate: false,
+                serialize: true,
+                unparse: false,
+            }
+        );
+        assert_eq!(generator.digest(), Some(cfg.digest()));
+        assert_eq!(generator.digest(), generator.fingerprint().map(|fingerprint| format!("{:016x}", fingerprint)));
+
+        let loaded = LoadedGenerator {
+            generator,
+            digest: generator.digest(),
+            capabilities: generator.capabilities(),
+        };
+        assert!(loaded.matches(&cfg));
+        assert!(!loaded.matches(&other));
+
+        /* Procedures that are missing only fail when they are used */
+        let mut sequence = Vec::with_capacity(16);
+        assert!(std::panic::catch_unwind(move || generator.mutate(&mut sequence)).is_err());
+    }
+
+    #[test]
+    fn test_seed_reproducible() {
+        for (grammar, prefix) in [("regex.json", "seed_regex_"), ("dynamic.json", "seed_dynamic_")] {
+            let cfg = ContextFreeGrammar::builder()
+                .peacock_grammar(format!("test-data/grammars/{}", grammar))
+                .unwrap()
+                .build()
+                .unwrap();
+            let so_file = compile_generator(&cfg, CGenerator::new().symbol_prefix(prefix), prefix);
+            let generator = Generator::load(&so_file, prefix);
+            let mut interpreter = GrammarInterpreter::new(&cfg);
+
+            for token in [&b"0x1337"[..], b"0xCAFE"] {
+                generator.add_dynamic_terminal(token);
+                interpreter.add_dynamic_terminal(token);
+            }
+
+            let mut sequence = Vec::with_capacity(1 << 16);
+            let mut out = vec![0u8; 1 << 20];
+            let mut expected = Vec::new();
+
+            for seed in [1, 2, 1238, u32::MAX as u64 + 1, u64::MAX] {
+                generator.seed(seed);
+                interpreter.seed(seed);
+
+                for _ in 0..20 {
+                    sequence.clear();
+                    generator.mutate(&mut sequence);
+                    let (len, truncated) = generator.serialize(&sequence, out.as_mut_ptr(), out.len());
+                    assert!(!truncated);
+
+                    interpreter.interpret_into(&mut expected, usize::MAX, None);
+                    assert_eq!(&out[..len], &expected[..], "{} diverged for seed {}", grammar, seed);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_malformed_sequence() {
+        let cfg = ContextFreeGrammar::builder()
+            .gramatron_grammar("test-data/grammars/gramatron.json")
+            .unwrap()
+            .build()
+            .unwrap();
+        let so_file = compile_generator(&cfg, CGenerator::new().symbol_prefix("malformed_"), "malformed");
+        let generator = Generator::load(&so_file, "malformed_");
+        let mut out = vec![0u8; 1 << 20];
+        let mut sequence = Vec::with_capacity(1 << 16);
+
+        while sequence.len() < 8 {
+            sequence.clear();
+            generator.mutate(&mut sequence);
+        }
+
+        assert!(generator.validate_sequence(&sequence));
+        assert!(generator.validate_sequence(&sequence[..sequence.len() / 2]));
+        assert!(generator.validate_sequence(&[]));
+        let (len, _) = generator.serialize(&sequence, out.as_mut_ptr(), out.len());
+        let expected = out[..len].to_vec();
+
+        for index in [0, sequence.len() / 2, sequence.len() - 1] {
+            let mut malformed = sequence.clone();
+            malformed[index] = usize::MAX - 1;
+            assert!(!generator.validate_sequence(&malformed));
+
+            /* Everything before the invalid step is serialized, nothing after it */
+            let (len, _) = generator.serialize(&malformed, out.as_mut_ptr(), out.len());
+            assert!(len <= expected.len());
+            assert!(generator.subtree_length(&malformed, index).is_some());
+            generator.mutate_inplace(&mut malformed, index);
+            generator.mutate(&mut malformed);
+        }
+
+        let so_file = compile_generator(
+            &cfg,
+            CGenerator::new().symbol_prefix("malformed_fast_").unsafe_fast_paths(true),
+            "malformed_fast",
+        );
+        let generator = Generator::load(&so_file, "malformed_fast_");
+        assert!(generator.validate_sequence(&sequence));
+        sequence[0] = usize::MAX - 1;
+        assert!(!generator.validate_sequence(&sequence));
+    }
+
+    #[test]
+    fn test_retire_generator() {
+        let cfg = ContextFreeGrammar::builder()
+            .gramatron_grammar("test-data/grammars/gramatron.json")
+            .unwrap()
+            .build()
+            .unwrap();
+        let so_file = compile_generator(&cfg, CGenerator::new().symbol_prefix("retire_"), "retire");
+        let generator = Generator::load(&so_file, "retire_");
+        let fingerprint = generator.fingerprint();
+        let stop = Arc::new(AtomicBool::new(false));
+
+        /* Fuzzing threads are still calling into the generator while it gets retired */
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                let stop = stop.clone();
+
+                std::thread::spawn(move || {
+                    let mut sequence = Vec::with_capacity(1 << 16);
+
+                    while !stop.load(Ordering::Relaxed) {
+                        sequence.clear();
+                        generator.mutate(&mut sequence);
+                        assert!(generator.validate_sequence(&sequence));
+                        assert_eq!(generator.fingerprint(), fingerprint);
+                    }
+                })
+            })
+            .collect();
+
+        std::thread::sleep(Duration::from_millis(50));
+        let guard = generator.acquire();
+        generator.retire();
+        stop.store(true, Ordering::Relaxed);
+
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        /* References that are in use keep the library loaded */
+        assert!(!generator.is_unloaded());
+        assert_eq!(guard.fingerprint(), fingerprint);
+        drop(guard);
 
-pub use generator::PeacockGenerator;
-pub use input::PeacockInput;
-pub use mutator::PeacockMutator;
+        assert!(generator.is_unloaded());
+        assert!(std::panic::catch_unwind(|| generator.fingerprint()).is_err());
+    }
+}
@@ -11,18 +11,69 @@ use libafl_bolts::prelude::{
 use std::borrow::Cow;
 
 use crate::components::{
-    ffi::generator_mutate,
+    ffi::Generator,
+    default_sequence_capacity,
     PeacockInput,
 };
 
 /// This component implements grammar-based mutations.
-pub struct PeacockMutator;
+///
+/// A mutation either cuts off the sequence of an input at a random point and lets the generator
+/// find a new random path from there, or it only replaces the expansion of the non-terminal at
+/// a random point and keeps everything after it. Both strategies are chosen with equal probability. Pinned prefixes of inputs (see [`PeacockInput::set_pinned_len`])
+/// are never touched.
+pub struct PeacockMutator {
+    min_fraction: f64,
+    max_fraction: f64,
+    generator: Option<Generator>,
+    capacity: usize,
+}
 
 impl PeacockMutator {
     /// Create a new mutator.
     #[allow(clippy::new_without_default)]
     pub fn new() -> Self {
-        Self {}
+        Self::with_mutation_window(0.0, 1.0)
+    }
+
+    /// Create a new mutator that only cuts off sequences in the window between `min_fraction`
+    /// and `max_fraction` of the unpinned part of a sequence.
+    /// `with_mutation_window(0.5, 1.0)` for example only mutates the second half of an input.
+    pub fn with_mutation_window(min_fraction: f64, max_fraction: f64) -> Self {
+        assert!(
+            (0.0..=1.0).contains(&min_fraction) && (0.0..=1.0).contains(&max_fraction) && min_fraction <= max_fraction,
+            "Invalid mutation window"
+        );
+
+        Self {
+            min_fraction,
+            max_fraction,
+            generator: None,
+            capacity: default_sequence_capacity(),
+        }
+    }
+
+    /// Mutate inputs with `generator` instead of the default generator.
+    /// The mutated inputs are then also serialized with `generator`.
+    pub fn with_generator(mut self, generator: &Generator) -> Self {
+        self.generator = Some(*generator);
+        self
+    }
+
+    /// Make sure that the sequences of mutated inputs have room for at least `capacity` entries
+    /// before the generator picks a new path, e.g. for inputs that were loaded from disk.
+    /// This should match the capacity of the [`PeacockGenerator`](crate::components::PeacockGenerator).
+    ///
+    /// Default: [`default_sequence_capacity()`] at the time of construction
+    pub fn with_capacity(mut self, capacity: usize) -> Self {
+        assert!(capacity > 0, "Sequence capacity must not be zero");
+        self.capacity = capacity;
+        self
+    }
+
+    /// The number of sequence entries that mutated inputs have room for at least.
+    pub fn capacity(&self) -> usize {
+        self.capacity
     }
 }
 
@@ -38,9 +89,260 @@ where
     S: HasRand,
 {
     fn mutate(&mut self, state: &mut S, input: &mut PeacockInput) -> Result<MutationResult, Error> {
-        let len = state.rand_mut().below(input.sequence().len());
-        input.sequence_mut().truncate(len);
-        generator_mutate(input.sequence_mut());
+        let len = input.sequence().len();
+        let pinned = input.pinned_len();
+
+        if pinned >= len {
+            return Ok(MutationResult::Skipped);
+        }
+
+        let free = len - pinned;
+        let lo = ((free as f64 * self.min_fraction) as usize).min(free - 1);
+        let hi = ((free as f64 * self.max_fraction).ceil() as usize).clamp(lo + 1, free);
+
+        let index = pinned + lo + state.rand_mut().below(hi - lo);
+
+        if let Some(generator) = self.generator {
+            input.set_generator(generator);
+        }
+
+        let generator = input.generator();
+        let sequence = input.sequence_mut();
+        /* A full sequence means that the generator ran out of room the last time, so give it more */
+        let capacity =
+            if sequence.len() == sequence.capacity() { self.capacity.max(sequence.len() * 2) } else { self.capacity };
+        sequence.reserve_exact(capacity.saturating_sub(sequence.len()));
+
+        if state.rand_mut().below(2) == 0 {
+            // Leave room for a subtree that is larger than the one it replaces
+            sequence.reserve(4096);
+
+            if generator.mutate_inplace(sequence, index) {
+                return Ok(MutationResult::Mutated);
+            }
+        }
+
+        sequence.truncate(index);
+        generator.mutate(sequence);
+        Ok(MutationResult::Mutated)
+    }
+}
+
+/// This component implements point mutations.
+///
+/// In contrast to the [`PeacockMutator`], it chooses a different rule for the non-terminal at a random point
+/// of the sequence and keeps as much as possible of the rest of the sequence: the following steps are replayed
+/// for the new derivation and only the ones that don't fit anymore are chosen again.
+/// Inputs where the chosen non-terminal has a single rule are skipped.
+/// Pinned prefixes of inputs (see [`PeacockInput::set_pinned_len`]) are never touched.
+pub struct PeacockPointMutator {
+    generator: Option<Generator>,
+    capacity: usize,
+}
+
+impl PeacockPointMutator {
+    /// Create a new point mutator.
+    #[allow(clippy::new_without_default)]
+    pub fn new() -> Self {
+        Self {
+            generator: None,
+            capacity: default_sequence_capacity(),
+        }
+    }
+
+    /// Mutate inputs with `generator` instead of the default generator.
+    /// The mutated inputs are then also serialized with `generator`.
+    pub fn with_generator(mut self, generator: &Generator) -> Self {
+        self.generator = Some(*generator);
+        self
+    }
+
+    /// Make sure that the sequences of mutated inputs have room for at least `capacity` entries,
+    /// see [`PeacockMutator::with_capacity`].
+    ///
+    /// Default: [`default_sequence_capacity()`] at the time of construction
+    pub fn with_capacity(mut self, capacity: usize) -> Self {
+        assert!(capacity > 0, "Sequence capacity must not be zero");
+        self.capacity = capacity;
+        self
+    }
+}
+
+impl Named for PeacockPointMutator {
+    fn name(&self) -> &Cow<'static, str> {
+        static NAME: Cow<'static, str> = Cow::Borrowed("PeacockPointMutator");
+        &NAME
+    }
+}
+
+impl<S> Mutator<PeacockInput, S> for PeacockPointMutator
+where
+    S: HasRand,
+{
+    fn mutate(&mut self, state: &mut S, input: &mut PeacockInput) -> Result<MutationResult, Error> {
+        let len = input.sequence().len();
+        let pinned = input.pinned_len();
+
+        if pinned >= len {
+            return Ok(MutationResult::Skipped);
+        }
+
+        let index = pinned + state.rand_mut().below(len - pinned);
+
+        if let Some(generator) = self.generator {
+            input.set_generator(generator);
+        }
+
+        let generator = input.generator();
+        let sequence = input.sequence_mut();
+        let capacity =
+            if sequence.len() == sequence.capacity() { self.capacity.max(sequence.len() * 2) } else { self.capacity };
+        sequence.reserve_exact(capacity.saturating_sub(sequence.len()));
+
+        /* Everything stays the same if the rule at the index cannot change */
+        let old = sequence[index];
+
+        if !generator.mutate_from(sequence, index) || sequence.get(index) == Some(&old) {
+            return Ok(MutationResult::Skipped);
+        }
+
         Ok(MutationResult::Mutated)
     }
 }
+
+#[cfg(all(test, not(feature = "static-loading")))]
+mod tests {
+    use super::*;
+    use crate::components::{
+        ffi::{
+            generator_mutate,
+            generator_serialize,
+        },
+        tests::load_test_generator,
+    };
+    use libafl::prelude::NopState;
+
+    #[test]
+    fn test_pinned_prefix() {
+        load_test_generator();
+
+        let mut input = PeacockInput::default();
+
+        while input.sequence().len() < 32 {
+            input.sequence_mut().clear();
+            generator_mutate(input.sequence_mut());
+        }
+
+        let pinned = 16;
+        let prefix = input.sequence()[..pinned].to_vec();
+        input.set_pinned_len(pinned);
+
+        let mut state = NopState::<PeacockInput>::new();
+        let mut mutator = PeacockMutator::with_mutation_window(0.25, 1.0);
+
+        for _ in 0..1000 {
+            mutator.mutate(&mut state, &mut input).unwrap();
+            assert_eq!(&input.sequence()[..pinned], &prefix);
+        }
+    }
+
+    fn serialize(sequence: &[usize]) -> Vec<u8> {
+        let mut out = vec![0; 1024 * 1024];
+        let (len, truncated) = generator_serialize(sequence, out.as_mut_ptr(), out.len());
+        assert!(!truncated);
+        out.truncate(len);
+        out
+    }
+
+    #[test]
+    fn test_inplace_mutation() {
+        load_test_generator();
+
+        let mut sequence = Vec::with_capacity(4096 * 2);
+
+        for _ in 0..1000 {
+            sequence.clear();
+
+            while sequence.len() < 32 {
+                sequence.clear();
+                generator_mutate(&mut sequence);
+            }
+
+            assert_eq!(Generator::expect_default().subtree_length(&sequence, 0), Some(sequence.len()));
+            assert_eq!(Generator::expect_default().subtree_length(&sequence, sequence.len()), Some(0));
+
+            let index = sequence.len() / 2;
+            let end = index + Generator::expect_default().subtree_length(&sequence, index).unwrap();
+            let original = sequence.clone();
+            let output = serialize(&original);
+
+            assert!(Generator::expect_default().mutate_inplace(&mut sequence, index));
+
+            let new_end = index + Generator::expect_default().subtree_length(&sequence, index).unwrap();
+            assert_eq!(&sequence[..index], &original[..index]);
+            assert_eq!(&sequence[new_end..], &original[end..]);
+
+            // The test grammar is in GNF, so serializing a prefix of a sequence yields
+            // exactly the bytes up to the first missing step
+            let tail = &output[serialize(&original[..end]).len()..];
+            let new_output = serialize(&sequence);
+            assert_eq!(&new_output[serialize(&sequence[..new_end]).len()..], tail);
+        }
+    }
+
+    #[test]
+    fn test_point_mutation() {
+        load_test_generator();
+
+        let generator = Generator::expect_default();
+        let mut state = NopState::<PeacockInput>::new();
+        let mut mutator = PeacockPointMutator::new();
+        let mut mutated = 0;
+
+        for _ in 0..1000 {
+            let mut input = PeacockInput::default();
+
+            while input.sequence().len() < 32 {
+                input.sequence_mut().clear();
+                generator_mutate(input.sequence_mut());
+            }
+
+            let pinned = 8;
+            let original = input.sequence().to_vec();
+            input.set_pinned_len(pinned);
+
+            if mutator.mutate(&mut state, &mut input).unwrap() == MutationResult::Skipped {
+                assert_eq!(input.sequence(), &original);
+                continue;
+            }
+
+            mutated += 1;
+            let sequence = input.sequence();
+            let index = sequence.iter().zip(&original).position(|(a, b)| a != b).unwrap();
+            assert!(index >= pinned);
+
+            // The new walk is a complete derivation of the grammar
+            assert!(generator.validate_sequence(sequence));
+            assert_eq!(generator.subtree_length(sequence, 0), Some(sequence.len()));
+        }
+
+        assert!(mutated > 100);
+
+        /* Steps that only exist in the old walk are dropped */
+        let mut sequence = Vec::with_capacity(4096);
+
+        while sequence.len() < 32 {
+            sequence.clear();
+            generator_mutate(&mut sequence);
+        }
+
+        let original = sequence.clone();
+        sequence.extend_from_slice(&[0; 16]);
+        assert!(generator.mutate_from(&mut sequence, original.len()));
+        assert_eq!(sequence, original);
+
+        let len = sequence.len();
+        assert!(generator.mutate_from(&mut sequence, len));
+        assert_eq!(sequence, original);
+    }
+}
@@ -0,0 +1,258 @@
+use std::sync::{
+    atomic::{
+        AtomicBool,
+        Ordering,
+    },
+    Mutex,
+    RwLock,
+};
+
+static POSTPROCESSOR: RwLock<Option<Box<dyn InputPostprocessor>>> = RwLock::new(None);
+static HAS_POSTPROCESSOR: AtomicBool = AtomicBool::new(false);
+
+/// An InputPostprocessor wraps the serialized inputs into whatever the target expects
+/// but the grammar cannot express, e.g. a length prefix or a checksum.
+pub trait InputPostprocessor: Send + Sync {
+    /// Append the transformed `input` to `out`.
+    fn process(&self, input: &[u8], out: &mut Vec<u8>);
+}
+
+/// Register `postprocessor` to transform every input before it is delivered to the target.
+/// [`PeacockInput::target_bytes`](crate::components::PeacockInput) applies it after the serialization.
+/// `None` removes the current postprocessor.
+///
+/// Default: `None`
+pub fn set_postprocessor(postprocessor: Option<Box<dyn InputPostprocessor>>) {
+    let mut current = POSTPROCESSOR.write().unwrap();
+    HAS_POSTPROCESSOR.store(postprocessor.is_some(), Ordering::Release);
+    *current = postprocessor;
+}
+
+/// Apply the registered postprocessor to `input`. Returns `None` without touching `out` if there is none.
+pub(crate) fn postprocess<'a>(input: &[u8], out: &'a mut Vec<u8>) -> Option<&'a [u8]> {
+    if !HAS_POSTPROCESSOR.load(Ordering::Acquire) {
+        return None;
+    }
+
+    let postprocessor = POSTPROCESSOR.read().unwrap();
+    let postprocessor = postprocessor.as_ref()?;
+    out.clear();
+    postprocessor.process(input, out);
+    Some(out)
+}
+
+/// Prepend the length of the input as an unsigned integer of `width` bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LengthPrefix {
+    width: usize,
+    big_endian: bool,
+}
+
+impl LengthPrefix {
+    /// Create a length prefix of `width` bytes, which must be 1, 2, 4 or 8.
+    /// Lengths that don't fit into the prefix are truncated.
+    pub fn new(width: usize, big_endian: bool) -> Self {
+        assert!(matches!(width, 1 | 2 | 4 | 8), "Invalid width of length prefix: {}", width);
+        Self {
+            width,
+            big_endian,
+        }
+    }
+}
+
+impl InputPostprocessor for LengthPrefix {
+    fn process(&self, input: &[u8], out: &mut Vec<u8>) {
+        let len = input.len() as u64;
+
+        if self.big_endian {
+            out.extend_from_slice(&len.to_be_bytes()[8 - self.width..]);
+        } else {
+            out.extend_from_slice(&len.to_le_bytes()[..self.width]);
+        }
+
+        out.extend_from_slice(input);
+    }
+}
+
+const CRC32_TABLE: [u32; 256] = {
+    let mut table = [0; 256];
+    let mut i = 0;
+
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+
+        while bit < 8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB88320 } else { crc >> 1 };
+            bit += 1;
+        }
+
+        table[i] = crc;
+        i += 1;
+    }
+
+    table
+};
+
+/// Append the CRC32 (IEEE, as in zlib) of the input.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Crc32 {
+    big_endian: bool,
+}
+
+impl Crc32 {
+    /// Create a postprocessor that appends the checksum in the given byte order.
+    pub fn new(big_endian: bool) -> Self {
+        Self {
+            big_endian,
+        }
+    }
+
+    /// The CRC32 of `data`.
+    pub fn checksum(data: &[u8]) -> u32 {
+        !data.iter().fold(!0u32, |crc, byte| CRC32_TABLE[((crc ^ *byte as u32) & 0xFF) as usize] ^ (crc >> 8))
+    }
+}
+
+impl InputPostprocessor for Crc32 {
+    fn process(&self, input: &[u8], out: &mut Vec<u8>) {
+        let crc = Self::checksum(input);
+        out.extend_from_slice(input);
+
+        if self.big_endian {
+            out.extend_from_slice(&crc.to_be_bytes());
+        } else {
+            out.extend_from_slice(&crc.to_le_bytes());
+        }
+    }
+}
+
+const BASE64_ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// Encode the input in standard base64 with padding.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Base64;
+
+impl InputPostprocessor for Base64 {
+    fn process(&self, input: &[u8], out: &mut Vec<u8>) {
+        out.reserve(input.len().div_ceil(3) * 4);
+
+        for chunk in input.chunks(3) {
+            let bytes = [chunk[0], chunk.get(1).copied().unwrap_or(0), chunk.get(2).copied().unwrap_or(0)];
+            let n = (bytes[0] as usize) << 16 | (bytes[1] as usize) << 8 | bytes[2] as usize;
+
+            for i in 0..4 {
+                if i <= chunk.len() {
+                    out.push(BASE64_ALPHABET[(n >> (18 - 6 * i)) & 0x3F]);
+                } else {
+                    out.push(b'=');
+                }
+            }
+        }
+    }
+}
+
+/// Surround the input with fixed bytes, e.g. to put it into a JSON field after encoding it with [`Base64`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Envelope {
+    prefix: Vec<u8>,
+    suffix: Vec<u8>,
+}
+
+impl Envelope {
+    /// Create a postprocessor that writes `prefix` before and `suffix` after the input.
+    pub fn new<P: Into<Vec<u8>>, S: Into<Vec<u8>>>(prefix: P, suffix: S) -> Self {
+        Self {
+            prefix: prefix.into(),
+            suffix: suffix.into(),
+        }
+    }
+}
+
+impl InputPostprocessor for Envelope {
+    fn process(&self, input: &[u8], out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.prefix);
+        out.extend_from_slice(input);
+        out.extend_from_slice(&self.suffix);
+    }
+}
+
+/// Apply multiple postprocessors one after another.
+#[derive(Default)]
+pub struct Chain {
+    postprocessors: Vec<Box<dyn InputPostprocessor>>,
+    scratch: Mutex<Vec<u8>>,
+}
+
+impl Chain {
+    /// Create an empty chain that passes inputs through unchanged.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Apply `postprocessor` to the output of the postprocessors that were added before.
+    pub fn then<P: InputPostprocessor + 'static>(mut self, postprocessor: P) -> Self {
+        self.postprocessors.push(Box::new(postprocessor));
+        self
+    }
+
+    /// The number of postprocessors in this chain.
+    pub fn len(&self) -> usize {
+        self.postprocessors.len()
+    }
+
+    /// Whether this chain passes inputs through unchanged.
+    pub fn is_empty(&self) -> bool {
+        self.postprocessors.is_empty()
+    }
+}
+
+impl InputPostprocessor for Chain {
+    fn process(&self, input: &[u8], out: &mut Vec<u8>) {
+        let start = out.len();
+        out.extend_from_slice(input);
+
+        let mut scratch = self.scratch.lock().unwrap();
+
+        for postprocessor in &self.postprocessors {
+            scratch.clear();
+            postprocessor.process(&out[start..], &mut scratch);
+            out.truncate(start);
+            out.extend_from_slice(&scratch);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn apply<P: InputPostprocessor>(postprocessor: &P, input: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        postprocessor.process(input, &mut out);
+        out
+    }
+
+    #[test]
+    fn test_builtins() {
+        assert_eq!(apply(&LengthPrefix::new(2, true), b"abc"), b"\x00\x03abc");
+        assert_eq!(apply(&LengthPrefix::new(4, false), b"abc"), b"\x03\x00\x00\x00abc");
+        assert_eq!(apply(&LengthPrefix::new(1, false), &[0; 256])[0], 0);
+
+        assert_eq!(Crc32::checksum(b"123456789"), 0xCBF43926);
+        assert_eq!(apply(&Crc32::new(true), b"123456789"), b"123456789\xCB\xF4\x39\x26");
+        assert_eq!(apply(&Crc32::new(false), b""), b"\x00\x00\x00\x00");
+
+        for (input, encoded) in
+            [("", ""), ("f", "Zg=="), ("fo", "Zm8="), ("foo", "Zm9v"), ("foob", "Zm9vYg=="), ("foobar", "Zm9vYmFy")]
+        {
+            assert_eq!(apply(&Base64, input.as_bytes()), encoded.as_bytes());
+        }
+
+        assert_eq!(apply(&Envelope::new("{\"data\": \"", "\"}"), b"Zm9v"), b"{\"data\": \"Zm9v\"}");
+
+        let chain = Chain::new().then(Base64).then(LengthPrefix::new(1, false));
+        assert_eq!(apply(&chain, b"foo"), b"\x04Zm9v");
+        assert_eq!(apply(&Chain::new(), b"foo"), b"foo");
+    }
+}
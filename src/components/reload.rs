@@ -0,0 +1,180 @@
+use libafl::prelude::{
+    Corpus,
+    CorpusId,
+    Error,
+    HasCorpus,
+    HasRand,
+    Input,
+    Stage,
+    UsesInput,
+    UsesState,
+};
+use libafl_bolts::prelude::Rand;
+use std::{
+    marker::PhantomData,
+    path::{
+        Path,
+        PathBuf,
+    },
+    time::{
+        Duration,
+        Instant,
+        SystemTime,
+    },
+};
+
+use crate::components::{
+    ffi::{
+        reload_generator,
+        Generator,
+    },
+    PeacockInput,
+};
+
+/// This stage periodically checks whether a grammar file was modified and hot-swaps the default generator
+/// for a new one without restarting the fuzzer.
+///
+/// The new generator is obtained from a user-supplied function that builds and compiles the grammar and
+/// returns the path of the shared object, see [`reload_generator`](crate::components::reload_generator).
+/// Each rebuild should go to a new path: The old shared object is still needed to reproduce the inputs
+/// that were created with it. If the rebuild fails, the fuzzer logs a warning and keeps using the old
+/// generator until the file gets modified again.
+///
+/// Afterwards the inputs in the corpus are translated to the new grammar by unparsing their serialization.
+/// Inputs that cannot be expressed in the new grammar are emptied such that the next mutation generates them
+/// from scratch.
+pub struct GeneratorReloadStage<F, EM> {
+    grammar: PathBuf,
+    interval: Duration,
+    last_check: Instant,
+    modified: Option<SystemTime>,
+    rebuild: F,
+    phantom: PhantomData<EM>,
+}
+
+impl<F, EM> GeneratorReloadStage<F, EM>
+where
+    F: FnMut() -> Result<PathBuf, String>,
+{
+    /// Create a new stage that checks the modification time of `grammar` every `interval` and
+    /// calls `rebuild` if it changed.
+    pub fn new<P: AsRef<Path>>(grammar: P, interval: Duration, rebuild: F) -> Self {
+        let grammar = grammar.as_ref().to_path_buf();
+        let modified = modification_time(&grammar);
+
+        Self {
+            grammar,
+            interval,
+            last_check: Instant::now(),
+            modified,
+            rebuild,
+            phantom: PhantomData,
+        }
+    }
+}
+
+fn modification_time(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|metadata| metadata.modified()).ok()
+}
+
+/// Translate every input of the corpus from the current default generator to the one in `generator_so`.
+/// Returns the number of inputs that could not be translated.
+fn migrate_corpus<S>(state: &mut S, generator_so: PathBuf) -> Result<usize, Error>
+where
+    S: HasCorpus + HasRand + UsesInput<Input = PeacockInput>,
+{
+    /* Inputs that are not cached get loaded from disk, which only works as long as the old grammar is active */
+    let mut inputs: Vec<(CorpusId, Option<PathBuf>, PeacockInput)> = Vec::new();
+
+    for id in state.corpus().ids() {
+        let mut testcase = state.corpus().get(id)?.borrow_mut();
+        state.corpus().load_input_into(&mut testcase)?;
+        inputs.push((id, testcase.file_path().clone(), testcase.input().clone().unwrap()));
+    }
+
+    let old = Generator::expect_default();
+    let new = reload_generator(generator_so);
+    new.seed(state.rand_mut().next());
+
+    let mut failed = 0;
+
+    for (id, path, mut input) in inputs {
+        if input.uses_default_generator() && !input.migrate(&old, &new) {
+            failed += 1;
+        }
+
+        if let Some(path) = path {
+            input.to_file(path)?;
+        }
+
+        let mut testcase = state.corpus().get(id)?.borrow_mut();
+
+        if testcase.input().is_some() {
+            testcase.set_input(input);
+        }
+    }
+
+    Ok(failed)
+}
+
+impl<F, EM> UsesState for GeneratorReloadStage<F, EM>
+where
+    EM: UsesState,
+{
+    type State = EM::State;
+}
+
+impl<E, EM, Z, F> Stage<E, EM, Z> for GeneratorReloadStage<F, EM>
+where
+    EM: UsesState,
+    E: UsesState<State = Self::State>,
+    Z: UsesState<State = Self::State>,
+    Self::State: HasCorpus + HasRand + UsesInput<Input = PeacockInput>,
+    F: FnMut() -> Result<PathBuf, String>,
+{
+    fn perform(
+        &mut self,
+        _fuzzer: &mut Z,
+        _executor: &mut E,
+        state: &mut Self::State,
+        _manager: &mut EM,
+    ) -> Result<(), Error> {
+        if self.last_check.elapsed() < self.interval {
+            return Ok(());
+        }
+
+        self.last_check = Instant::now();
+
+        let modified = modification_time(&self.grammar);
+
+        if modified.is_none() || modified == self.modified {
+            return Ok(());
+        }
+
+        self.modified = modified;
+
+        let generator_so = match (self.rebuild)() {
+            Ok(generator_so) => generator_so,
+            Err(err) => {
+                log::warn!("Keeping the old generator, could not rebuild {}: {}", self.grammar.display(), err);
+                return Ok(());
+            },
+        };
+
+        let failed = migrate_corpus(state, generator_so)?;
+
+        if failed > 0 {
+            log::warn!("{} input(s) of the corpus don't adhere to the new grammar and were emptied", failed);
+        }
+
+        Ok(())
+    }
+
+    fn should_restart(&mut self, _state: &mut Self::State) -> Result<bool, Error> {
+        Ok(true)
+    }
+
+    fn clear_progress(&mut self, _state: &mut Self::State) -> Result<(), Error> {
+        Ok(())
+    }
+}
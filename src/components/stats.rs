@@ -0,0 +1,194 @@
+use libafl::prelude::{
+    Error,
+    HasMetadata,
+    Stage,
+    UsesState,
+};
+use libafl_bolts::impl_serdeany;
+use serde::{
+    Deserialize,
+    Serialize,
+};
+use serde_json as json;
+use std::{
+    fs::File,
+    io::BufWriter,
+    marker::PhantomData,
+    path::{
+        Path,
+        PathBuf,
+    },
+    time::{
+        Duration,
+        Instant,
+    },
+};
+
+use crate::components::ffi::{
+    default_generation,
+    generator_rule_counters,
+    generator_rule_names,
+};
+
+/// The number of times every rule has been serialized, aggregated over all restarts of a fuzzer.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct PeacockStatsMetadata {
+    hits: Vec<u64>,
+}
+
+impl_serdeany!(PeacockStatsMetadata);
+
+impl PeacockStatsMetadata {
+    /// The number of times every rule has been serialized, indexed by rule id.
+    pub fn hits(&self) -> &[u64] {
+        &self.hits
+    }
+}
+
+#[derive(Serialize)]
+struct RuleReport<'a> {
+    rule: usize,
+    nonterminal: &'a str,
+    hits: u64,
+}
+
+/// This stage periodically collects the rule counters of the generator and writes
+/// a JSON report about how often every rule has been used to disk.
+///
+/// The generator must have been created with
+/// [`CGenerator::emit_statistics`](crate::backends::C::CGenerator::emit_statistics),
+/// otherwise this stage does nothing.
+pub struct PeacockStatsStage<EM> {
+    output: PathBuf,
+    interval: Duration,
+    last_report: Instant,
+    last_counters: Vec<usize>,
+    generation: usize,
+    phantom: PhantomData<EM>,
+}
+
+impl<EM> PeacockStatsStage<EM> {
+    /// Create a new stage that writes its report to `output` every `interval`.
+    pub fn new<P: AsRef<Path>>(output: P, interval: Duration) -> Self {
+        Self {
+            output: output.as_ref().to_path_buf(),
+            interval,
+            last_report: Instant::now(),
+            last_counters: Vec::new(),
+            generation: default_generation(),
+            phantom: PhantomData,
+        }
+    }
+
+    fn write_report(&self, hits: &[u64]) -> Result<(), Error> {
+        let names = generator_rule_names().unwrap_or_default();
+        let report: Vec<RuleReport> = hits
+            .iter()
+            .enumerate()
+            .map(|(rule, hits)| RuleReport {
+                rule,
+                nonterminal: names.get(rule).map_or("", String::as_str),
+                hits: *hits,
+            })
+            .collect();
+
+        let file = BufWriter::new(File::create(&self.output)?);
+        json::to_writer_pretty(file, &report)
+            .map_err(|e| Error::serialize(format!("Could not write statistics to {}: {}", self.output.display(), e)))
+    }
+}
+
+impl<EM> UsesState for PeacockStatsStage<EM>
+where
+    EM: UsesState,
+{
+    type State = EM::State;
+}
+
+impl<E, EM, Z> Stage<E, EM, Z> for PeacockStatsStage<EM>
+where
+    EM: UsesState,
+    E: UsesState<State = Self::State>,
+    Z: UsesState<State = Self::State>,
+    Self::State: HasMetadata,
+{
+    fn perform(
+        &mut self,
+        _fuzzer: &mut Z,
+        _executor: &mut E,
+        state: &mut Self::State,
+        _manager: &mut EM,
+    ) -> Result<(), Error> {
+        if self.last_report.elapsed() < self.interval {
+            return Ok(());
+        }
+
+        self.last_report = Instant::now();
+
+        let Some(counters) = generator_rule_counters() else {
+            return Ok(());
+        };
+
+        let metadata = state.metadata_or_insert_with(PeacockStatsMetadata::default);
+
+        /* The rules of a reloaded generator start over */
+        if self.generation != default_generation() {
+            self.generation = default_generation();
+            self.last_counters.clear();
+            metadata.hits.clear();
+        }
+
+        metadata.hits.resize(counters.len(), 0);
+        self.last_counters.resize(counters.len(), 0);
+
+        for (i, counter) in counters.iter().enumerate() {
+            metadata.hits[i] += counter.wrapping_sub(self.last_counters[i]) as u64;
+        }
+
+        self.last_counters = counters;
+        self.write_report(&metadata.hits)
+    }
+
+    fn should_restart(&mut self, _state: &mut Self::State) -> Result<bool, Error> {
+        Ok(true)
+    }
+
+    fn clear_progress(&mut self, _state: &mut Self::State) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+#[cfg(all(test, not(feature = "static-loading")))]
+mod tests {
+    use super::*;
+    use crate::components::{
+        ffi::{
+            generator_mutate,
+            generator_serialize,
+        },
+        tests::load_test_generator,
+    };
+
+    #[test]
+    fn test_rule_counters() {
+        load_test_generator();
+
+        let names = generator_rule_names().unwrap();
+        let before = generator_rule_counters().unwrap();
+        assert_eq!(names.len(), before.len());
+        assert!(names.iter().all(|name| !name.is_empty()));
+
+        let mut sequence = Vec::with_capacity(4096);
+        let mut out = vec![0u8; 1 << 20];
+
+        for _ in 0..16 {
+            sequence.clear();
+            generator_mutate(&mut sequence);
+            generator_serialize(&sequence, out.as_mut_ptr(), out.len());
+        }
+
+        let after = generator_rule_counters().unwrap();
+        let delta: usize = after.iter().zip(&before).map(|(a, b)| a.wrapping_sub(*b)).sum();
+        assert!(delta > 0);
+    }
+}
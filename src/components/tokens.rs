@@ -0,0 +1,308 @@
+use libafl::prelude::{
+    CmpValues,
+    CmpValuesMetadata,
+    Error,
+    HasMetadata,
+    Stage,
+    Tokens,
+    UsesState,
+};
+use libafl_bolts::impl_serdeany;
+use serde::{
+    Deserialize,
+    Serialize,
+};
+use std::{
+    fmt::Write,
+    marker::PhantomData,
+    path::{
+        Path,
+        PathBuf,
+    },
+    time::{
+        Duration,
+        Instant,
+    },
+};
+
+use crate::components::ffi::{
+    default_generation,
+    Generator,
+};
+
+/// All tokens that a [`TokenCollectorStage`] handed to the generator, aggregated over all restarts of a fuzzer.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct TokenCollectorMetadata {
+    tokens: Vec<Vec<u8>>,
+}
+
+impl_serdeany!(TokenCollectorMetadata);
+
+impl TokenCollectorMetadata {
+    /// The tokens that have been added to the dynamic terminals of the generator in the order they were discovered.
+    pub fn tokens(&self) -> &[Vec<u8>] {
+        &self.tokens
+    }
+}
+
+/// Write `tokens` to `path` as a dictionary in AFL format, in the order in which they were added to the generator.
+fn write_token_file(path: &Path, tokens: &[Vec<u8>]) -> Result<(), Error> {
+    let mut content = String::new();
+
+    for (i, token) in tokens.iter().enumerate() {
+        write!(&mut content, "token_{}=\"", i).unwrap();
+
+        for byte in token {
+            match byte {
+                b' '..=b'~' if *byte != b'"' && *byte != b'\\' => content.push(*byte as char),
+                _ => write!(&mut content, "\\x{:02x}", byte).unwrap(),
+            }
+        }
+
+        content.push_str("\"\n");
+    }
+
+    /* Other tools may read the file at any time, so it gets replaced atomically */
+    let tmp = path.with_extension("tmp");
+    std::fs::write(&tmp, content)?;
+    std::fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+/// Add the dynamic terminals that a [`TokenCollectorStage`] wrote into its [pool file](TokenCollectorStage::pool_file)
+/// to the default generator. The dynamic terminals of a sequence are indices into this pool,
+/// so tools that load inputs of a campaign need it to serialize them like the fuzzer did.
+///
+/// Returns the number of terminals that were added.
+pub fn load_dynamic_terminals<P: AsRef<Path>>(path: P) -> Result<usize, Error> {
+    add_token_file(&Generator::expect_default(), path.as_ref())
+}
+
+fn add_token_file(generator: &Generator, path: &Path) -> Result<usize, Error> {
+    let tokens = Tokens::from_file(path)?;
+    Ok(tokens.tokens().iter().filter(|token| generator.add_dynamic_terminal(token)).count())
+}
+
+/// This stage periodically collects tokens that the target compares its input against and
+/// adds them to the dynamic terminals of the generator. This makes it possible to synthesize
+/// magic values that are not part of the grammar.
+///
+/// Tokens are collected from
+/// - the [`Tokens`] metadata of the state, e.g. an autodict that was extracted from the target,
+/// - the byte operands in the [`CmpValuesMetadata`] of a CMPLOG observer,
+/// - dictionary files in AFL format like the ones AFL++ writes with `AFL_LLVM_DICT2FILE`.
+///
+/// The generator only makes use of them if the grammar references the undefined non-terminal
+/// [`DYNAMIC`](crate::grammar::DYNAMIC_NONTERMINAL), otherwise this stage does nothing.
+pub struct TokenCollectorStage<EM> {
+    files: Vec<PathBuf>,
+    pool_file: Option<PathBuf>,
+    interval: Duration,
+    last_collection: Option<Instant>,
+    restored: Option<usize>,
+    generator: Option<Generator>,
+    phantom: PhantomData<EM>,
+}
+
+impl<EM> TokenCollectorStage<EM> {
+    /// Create a new stage that collects tokens every `interval`.
+    pub fn new(interval: Duration) -> Self {
+        Self {
+            files: Vec::new(),
+            pool_file: None,
+            interval,
+            last_collection: None,
+            restored: None,
+            generator: None,
+            phantom: PhantomData,
+        }
+    }
+
+    /// Also collect the tokens from the dictionary file at `path`. The file is read again on every collection
+    /// and may not exist yet.
+    pub fn token_file<P: AsRef<Path>>(mut self, path: P) -> Self {
+        self.files.push(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Write all tokens that were added to the generator to `path` whenever new ones are added.
+    /// Other tools can restore the dynamic terminals of the generator from it with [`load_dynamic_terminals()`].
+    pub fn pool_file<P: AsRef<Path>>(mut self, path: P) -> Self {
+        self.pool_file = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Add the tokens to `generator` instead of the default generator.
+    pub fn with_generator(mut self, generator: &Generator) -> Self {
+        self.generator = Some(*generator);
+        self
+    }
+
+    /// Hand all new tokens to the generator. Returns the number of tokens that were added.
+    fn collect<S: HasMetadata>(&mut self, state: &mut S) -> Result<usize, Error> {
+        let generator = Generator::or_default(self.generator);
+
+        /* The dynamic terminals of the generator don't survive a restart or a reload */
+        let generation = default_generation();
+        let restored = self.restored != Some(generation);
+
+        if restored {
+            self.restored = Some(generation);
+
+            if let Ok(metadata) = state.metadata::<TokenCollectorMetadata>() {
+                for token in &metadata.tokens {
+                    generator.add_dynamic_terminal(token);
+                }
+            }
+        }
+
+        let mut candidates = Vec::new();
+
+        if let Ok(tokens) = state.metadata::<Tokens>() {
+            candidates.extend(tokens.tokens().iter().cloned());
+        }
+
+        if let Ok(cmps) = state.metadata::<CmpValuesMetadata>() {
+            for cmp in &cmps.list {
+                if let CmpValues::Bytes((first, second)) = cmp {
+                    candidates.push(first.clone());
+                    candidates.push(second.clone());
+                }
+            }
+        }
+
+        for file in &self.files {
+            if file.exists() {
+                candidates.extend(Tokens::from_file(file)?.tokens().iter().cloned());
+            }
+        }
+
+        let metadata = state.metadata_or_insert_with(TokenCollectorMetadata::default);
+        let mut added = 0;
+
+        for token in candidates {
+            if !metadata.tokens.contains(&token) && generator.add_dynamic_terminal(&token) {
+                metadata.tokens.push(token);
+                added += 1;
+            }
+        }
+
+        if let Some(path) = &self.pool_file {
+            if restored || added > 0 {
+                write_token_file(path, &metadata.tokens)?;
+            }
+        }
+
+        Ok(added)
+    }
+}
+
+impl<EM> UsesState for TokenCollectorStage<EM>
+where
+    EM: UsesState,
+{
+    type State = EM::State;
+}
+
+impl<E, EM, Z> Stage<E, EM, Z> for TokenCollectorStage<EM>
+where
+    EM: UsesState,
+    E: UsesState<State = Self::State>,
+    Z: UsesState<State = Self::State>,
+    Self::State: HasMetadata,
+{
+    fn perform(
+        &mut self,
+        _fuzzer: &mut Z,
+        _executor: &mut E,
+        state: &mut Self::State,
+        _manager: &mut EM,
+    ) -> Result<(), Error> {
+        if self.last_collection.is_some_and(|last| last.elapsed() < self.interval) {
+            return Ok(());
+        }
+
+        self.last_collection = Some(Instant::now());
+        self.collect(state)?;
+        Ok(())
+    }
+
+    fn should_restart(&mut self, _state: &mut Self::State) -> Result<bool, Error> {
+        Ok(true)
+    }
+
+    fn clear_progress(&mut self, _state: &mut Self::State) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+#[cfg(all(test, not(feature = "static-loading")))]
+mod tests {
+    use super::*;
+    use crate::{
+        backends::C::CGenerator,
+        components::{
+            tests::compile_generator,
+            PeacockInput,
+        },
+        grammar::ContextFreeGrammar,
+    };
+    use libafl::prelude::NopState;
+
+    #[test]
+    fn test_token_collector() {
+        let cfg =
+            ContextFreeGrammar::builder().peacock_grammar("test-data/grammars/dynamic.json").unwrap().build().unwrap();
+        let so_file = compile_generator(&cfg, CGenerator::new().symbol_prefix("dynamic_"), "dynamic");
+        let generator = Generator::load(so_file, "dynamic_");
+        let dict = std::env::temp_dir().join(format!("peacock-tokens-{}.dict", std::process::id()));
+        std::fs::write(&dict, "magic=\"0x1337\"\n").unwrap();
+        let pool = std::env::temp_dir().join(format!("peacock-pool-{}.dict", std::process::id()));
+
+        let mut stage = TokenCollectorStage::<()>::new(Duration::ZERO)
+            .with_generator(&generator)
+            .token_file(&dict)
+            .pool_file(&pool);
+        let mut state = NopState::<PeacockInput>::new();
+        state.add_metadata(Tokens::new().add_tokens([b"0xDEADBEEF".to_vec(), b"\"\\\n".to_vec()]).clone());
+        state.add_metadata(CmpValuesMetadata {
+            list: vec![CmpValues::Bytes((b"0xCAFE".to_vec(), b"0xCAFE".to_vec())), CmpValues::U8((1, 2))],
+        });
+
+        assert_eq!(stage.collect(&mut state).unwrap(), 4);
+        assert_eq!(stage.collect(&mut state).unwrap(), 0);
+        assert_eq!(state.metadata::<TokenCollectorMetadata>().unwrap().tokens().len(), 4);
+
+        /* Another instance of the generator restores the same pool from the pool file */
+        let so_file = compile_generator(&cfg, CGenerator::new().symbol_prefix("dynamic_"), "dynamic-pool");
+        let other = Generator::load(so_file, "dynamic_");
+        assert_eq!(add_token_file(&other, &pool).unwrap(), 4);
+        std::fs::remove_file(&dict).unwrap();
+        std::fs::remove_file(&pool).unwrap();
+
+        let mut outputs = std::collections::HashSet::new();
+        let mut sequence = Vec::with_capacity(4096);
+        let mut out = vec![0u8; 4096];
+
+        for _ in 0..200 {
+            sequence.clear();
+            generator.mutate(&mut sequence);
+            let (len, _) = generator.serialize(&sequence, out.as_mut_ptr(), out.len());
+            let output = out[..len].to_vec();
+
+            /* Inputs with dynamic terminals can be unparsed into the same sequence */
+            let mut unparsed = Vec::with_capacity(4096);
+            assert!(generator.unparse(&mut unparsed, &output));
+            assert_eq!(unparsed, sequence);
+
+            let (other_len, _) = other.serialize(&sequence, out.as_mut_ptr(), out.len());
+            assert_eq!(&out[..other_len], &output[..]);
+
+            outputs.insert(String::from_utf8(output).unwrap());
+        }
+
+        for value in ["0", "", "0xDEADBEEF", "0x1337", "0xCAFE", "\"\\\n"] {
+            assert!(outputs.contains(&format!("if x == {};", value)), "{} was not generated", value);
+        }
+    }
+}
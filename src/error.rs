@@ -1,6 +1,9 @@
 //! This module contains various error types.
 
-use std::path::PathBuf;
+use std::{
+    path::PathBuf,
+    time::Duration,
+};
 use thiserror::Error;
 
 /// A ParsingError means that the syntax or format of the provided grammar is invalid.
@@ -19,6 +22,13 @@ impl ParsingError {
     }
 }
 
+impl ParsingError {
+    /// The exit code that the command line tools of peacock use for this error.
+    pub fn exit_code(&self) -> i32 {
+        2
+    }
+}
+
 impl std::fmt::Display for ParsingError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "ParsingError in {}: {}", self.path.display(), self.msg)
@@ -28,6 +38,10 @@ impl std::fmt::Display for ParsingError {
 /// A GrammarError represents an error with the content of a grammar.
 #[derive(Debug, Error)]
 pub enum GrammarError {
+    /// The grammar does not contain any rules at all
+    #[error("The grammar does not contain any rules")]
+    EmptyGrammar,
+
     /// The grammar does not contain rules to expand the entrypoint
     #[error("The grammar does not contain an explicit entrypoint: {0}")]
     MissingEntrypoint(String),
@@ -35,4 +49,212 @@ pub enum GrammarError {
     /// The grammar is referencing a non-terminal that has no rules to expand.
     #[error("The non-terminal '{0}' is referenced but never defined")]
     MissingNonTerminal(String),
+
+    /// The grammar is referencing multiple non-terminals that have no rules to expand.
+    /// Only returned with [`UndefinedPolicy::Collect`](crate::grammar::UndefinedPolicy::Collect).
+    #[error("The non-terminals {} are referenced but never defined", .0.iter().map(|x| format!("'{}'", x)).collect::<Vec<_>>().join(", "))]
+    MissingNonTerminals(Vec<String>),
+
+    /// Every rule of a non-terminal that is reachable from the entrypoint leads to infinite recursion.
+    #[error("The non-terminal '{0}' can never finish its derivation")]
+    UnterminatedRecursion(String),
+
+    /// Two grammars that are merged define the same non-terminal with different rules.
+    #[error("The non-terminal '{0}' is defined differently in both grammars")]
+    ConflictingDefinition(String),
+
+    /// Excluding strings from the non-terminal leaves nothing to derive.
+    #[error("The non-terminal '{0}' derives nothing but excluded strings")]
+    EmptyExclusion(String),
+
+    /// A rule references a terminal by an index that is not in the terminal table.
+    #[error("The terminal #{index} is referenced but the terminal table only has {len} entries")]
+    InvalidTerminalIndex {
+        /// The index of the terminal that was referenced
+        index: usize,
+
+        /// The number of entries in the terminal table
+        len: usize,
+    },
+
+    /// Optimizing the grammar exceeded the limits that were set in the [`GrammarBuilder`](crate::grammar::GrammarBuilder).
+    #[error("Optimizing the grammar exceeded its complexity limit with {rules} rules after {elapsed:?}. Try building it with optimize(false) instead")]
+    ComplexityLimit {
+        /// The number of rules when the limit was hit
+        rules: usize,
+
+        /// The time spent optimizing the grammar until the limit was hit
+        elapsed: Duration,
+    },
+
+    /// A serialized grammar could not be read, see [`ContextFreeGrammar::from_reader`](crate::grammar::ContextFreeGrammar::from_reader).
+    #[error("The serialized grammar is malformed: {0}")]
+    InvalidSerialization(String),
+
+    /// Converting the grammar into Greibach Normal Form grew it beyond the factor that was set with
+    /// [`GrammarBuilder::max_gnf_growth`](crate::grammar::GrammarBuilder::max_gnf_growth).
+    #[error("Converting the grammar into Greibach Normal Form grew it from {original} to {rules} rules. Try building it with optimize(false) instead")]
+    GnfExplosion {
+        /// The number of rules before the conversion
+        original: usize,
+
+        /// The number of rules when the limit was hit
+        rules: usize,
+    },
+
+    /// A rule of the non-terminal contains binding markers but context-sensitive rules were not enabled with
+    /// [`GrammarBuilder::context_sensitive`](crate::grammar::GrammarBuilder::context_sensitive).
+    #[error("The non-terminal '{0}' binds or uses bytes but context-sensitive rules are disabled")]
+    ContextSensitive(String),
+}
+
+impl GrammarError {
+    /// The exit code that the command line tools of peacock use for this error.
+    /// The codes are stable across releases such that scripts can tell the failures apart.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            GrammarError::InvalidSerialization(_) => 2,
+            GrammarError::MissingEntrypoint(_) => 3,
+            GrammarError::MissingNonTerminal(_) | GrammarError::MissingNonTerminals(_) => 4,
+            GrammarError::UnterminatedRecursion(_) => 5,
+            GrammarError::ConflictingDefinition(_) => 6,
+            GrammarError::EmptyExclusion(_) => 7,
+            GrammarError::InvalidTerminalIndex {
+                ..
+            } => 8,
+            GrammarError::ComplexityLimit {
+                ..
+            } => 9,
+            GrammarError::GnfExplosion {
+                ..
+            } => 10,
+            GrammarError::EmptyGrammar => 11,
+            GrammarError::ContextSensitive(_) => 12,
+        }
+    }
+}
+
+/// A BackendError means that a [`Backend`](crate::backends::Backend) could not generate its output.
+#[derive(Debug, Error)]
+pub enum BackendError {
+    /// The grammar cannot be handled by the backend
+    #[error("Invalid grammar: {0}")]
+    Grammar(#[from] GrammarError),
+
+    /// Writing the output failed
+    #[error("Could not write output: {0}")]
+    Io(#[from] std::io::Error),
+
+    /// Any other reason, for backends outside of this crate
+    #[error(transparent)]
+    Other(Box<dyn std::error::Error + Send + Sync>),
+}
+
+/// A CompileError means that the C compiler could not turn the generated code into a generator.
+#[derive(Debug, Error)]
+pub enum CompileError {
+    /// The compiler or archiver could not be executed at all
+    #[error("Could not launch {program}: {source}")]
+    Launch {
+        /// The program that was executed
+        program: String,
+
+        /// The reason why it could not be executed
+        source: std::io::Error,
+    },
+
+    /// The compiler or archiver exited with an error
+    #[error("Compiling {} failed:\n{stderr}", output.display())]
+    Failed {
+        /// The file that should have been created
+        output: PathBuf,
+
+        /// Everything the program printed to stderr
+        stderr: String,
+    },
+}
+
+/// A ReplayError means that a recorded walk does not fit the grammar that it is replayed on.
+#[derive(Debug, Error)]
+pub enum ReplayError {
+    /// The walk expands a different non-terminal than the grammar at this step
+    #[error("Step {step} of the walk expands non-terminal #{found} but the grammar expects <{expected}> (#{expected_id})")]
+    WrongNonTerminal {
+        /// The index of the step in the walk
+        step: usize,
+
+        /// The name of the non-terminal that the grammar expands at this step
+        expected: String,
+
+        /// The id of the non-terminal that the grammar expands at this step
+        expected_id: usize,
+
+        /// The id of the non-terminal in the walk
+        found: usize,
+    },
+
+    /// The walk chooses a rule that the non-terminal does not have
+    #[error("Step {step} of the walk chooses rule {rule} of <{non_terminal}> but it only has {rules} rules")]
+    RuleOutOfRange {
+        /// The index of the step in the walk
+        step: usize,
+
+        /// The name of the non-terminal
+        non_terminal: String,
+
+        /// The rule in the walk
+        rule: usize,
+
+        /// The number of rules of the non-terminal
+        rules: usize,
+    },
+
+    /// The walk ends before the derivation is finished
+    #[error("The walk ends after {0} steps but the derivation is not finished")]
+    Incomplete(usize),
+
+    /// The derivation is finished before the walk ends
+    #[error("The derivation is finished after {steps} steps but the walk has {len} steps")]
+    TrailingSteps {
+        /// The number of steps that the derivation needed
+        steps: usize,
+
+        /// The length of the walk
+        len: usize,
+    },
+
+    /// Writing the output failed
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
+
+/// A TreeError means that a sequence does not describe a derivation of the grammar,
+/// see [`DerivationTree::from_sequence`](crate::tree::DerivationTree::from_sequence).
+#[derive(Debug, Error)]
+pub enum TreeError {
+    /// The sequence ends before the derivation is finished
+    #[error("The sequence ends at step {step} but <{non_terminal}> still needs a rule")]
+    Truncated {
+        /// The length of the sequence
+        step: usize,
+
+        /// The non-terminal that needed the next entry of the sequence
+        non_terminal: String,
+    },
+
+    /// The sequence chooses a rule that the non-terminal does not have
+    #[error("Step {step} of the sequence chooses rule {rule} of <{non_terminal}> but it only has {rules} rules")]
+    RuleOutOfRange {
+        /// The index of the entry in the sequence
+        step: usize,
+
+        /// The name of the non-terminal
+        non_terminal: String,
+
+        /// The rule in the sequence
+        rule: usize,
+
+        /// The number of rules of the non-terminal
+        rules: usize,
+    },
 }
@@ -0,0 +1,333 @@
+use std::{
+    collections::{
+        BTreeSet,
+        HashMap,
+        HashSet,
+    },
+    ops::Bound,
+    sync::Arc,
+};
+
+use crate::{
+    backends::{
+        interpreter::{
+            GrammarInterpreter,
+            Unparser,
+        },
+        C::LowLevelGrammar,
+    },
+    grammar::{
+        diff::symbol_string,
+        ContextFreeGrammar,
+        ProductionRule,
+        Symbol,
+    },
+};
+
+/// The number of bytes that [`ContextFreeGrammar::unparse_ambiguities`] compares
+pub const DEFAULT_AMBIGUITY_LOOKAHEAD: usize = 2;
+
+/// The length that the samples of [`ContextFreeGrammar::detect_ambiguity`] are kept close to
+const SAMPLE_LEN: usize = 256;
+
+/// Two alternatives of a non-terminal where one can derive a prefix of the other, which the greedy unparser cannot tell apart.
+/// See [`ContextFreeGrammar::unparse_ambiguities`] and [`ContextFreeGrammar::detect_ambiguity`].
+///
+/// Symbols are written in peacock notation: non-terminals as `<A>` and terminals as `'a'`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AmbiguityReport {
+    /// The non-terminal
+    pub non_terminal: String,
+
+    /// The alternative that is defined first or, for [`detect_ambiguity`](ContextFreeGrammar::detect_ambiguity),
+    /// the alternative that generated the sample
+    pub first: Vec<String>,
+
+    /// The alternative that is defined second or the alternative that the unparser picked instead
+    pub second: Vec<String>,
+
+    /// A derivation of one alternative that a derivation of the other alternative starts with
+    /// or the sample that was unparsed differently
+    pub prefix: Vec<u8>,
+}
+
+impl std::fmt::Display for AmbiguityReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "<{}>: {} and {} overlap in {:?}",
+            self.non_terminal,
+            self.first.join(" "),
+            self.second.join(" "),
+            String::from_utf8_lossy(&self.prefix)
+        )
+    }
+}
+
+/// The first bytes of a derivation. `complete` is set if the derivation ends after them.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct Prefix {
+    bytes: Vec<u8>,
+    complete: bool,
+}
+
+type FirstSet = BTreeSet<Prefix>;
+
+/// The prefixes of all derivations of `a` followed by `b`, cut off after `k` bytes.
+fn concat(a: &FirstSet, b: &FirstSet, k: usize) -> FirstSet {
+    let mut result = FirstSet::new();
+
+    for x in a {
+        if !x.complete {
+            result.insert(x.clone());
+            continue;
+        }
+
+        for y in b {
+            let mut bytes = x.bytes.clone();
+            bytes.extend_from_slice(&y.bytes);
+            let complete = y.complete && bytes.len() <= k;
+            bytes.truncate(k);
+            result.insert(Prefix {
+                bytes,
+                complete,
+            });
+        }
+    }
+
+    result
+}
+
+fn first_of_rule(rule: &ProductionRule, first: &HashMap<&str, FirstSet>, k: usize) -> FirstSet {
+    let mut result = FirstSet::from([Prefix {
+        bytes: Vec::new(),
+        complete: true,
+    }]);
+
+    for symbol in rule.rhs() {
+        let next = match symbol {
+            /* Dynamic terminals, uses of bindings and builtins can be anything */
+            Symbol::Terminal(term) if term.is_dynamic() || term.builtin().is_some() => FirstSet::from([Prefix {
+                bytes: Vec::new(),
+                complete: false,
+            }]),
+            Symbol::Terminal(term) if term.binding().is_some_and(|(op, _)| op.is_reference()) => {
+                FirstSet::from([Prefix {
+                    bytes: Vec::new(),
+                    complete: false,
+                }])
+            },
+            Symbol::Terminal(term) => {
+                let mut bytes = term.expanded().as_bytes().to_vec();
+                let complete = bytes.len() <= k;
+                bytes.truncate(k);
+                FirstSet::from([Prefix {
+                    bytes,
+                    complete,
+                }])
+            },
+            Symbol::NonTerminal(nonterm) => first.get(nonterm.id()).cloned().unwrap_or_default(),
+        };
+
+        result = concat(&result, &next, k);
+    }
+
+    result
+}
+
+/// Returns a string in `a` that a string in `b` starts with.
+fn find_overlap(a: &BTreeSet<Vec<u8>>, b: &BTreeSet<Vec<u8>>) -> Option<Vec<u8>> {
+    /* All strings that start with x are sorted directly after x */
+    a.iter()
+        .find(|x| {
+            b.range::<[u8], _>((Bound::Included(x.as_slice()), Bound::Unbounded))
+                .next()
+                .is_some_and(|y| y.starts_with(x))
+        })
+        .cloned()
+}
+
+impl ContextFreeGrammar {
+    /// Find the alternatives of every non-terminal where a derivation of one alternative can be a prefix of
+    /// a derivation of the other, like `'='` and `'=='`. Only derivations of at most [`DEFAULT_AMBIGUITY_LOOKAHEAD`]
+    /// bytes are considered.
+    ///
+    /// The greedy unparser picks the alternative that matches the most input, so it may mis-parse inputs
+    /// of such grammars, after which mutations destroy the structure of the input.
+    pub fn unparse_ambiguities(&self) -> Vec<AmbiguityReport> {
+        self.unparse_ambiguities_with_lookahead(DEFAULT_AMBIGUITY_LOOKAHEAD)
+    }
+
+    /// Like [`unparse_ambiguities`](ContextFreeGrammar::unparse_ambiguities) but considers derivations of at most `k` bytes.
+    pub fn unparse_ambiguities_with_lookahead(&self, k: usize) -> Vec<AmbiguityReport> {
+        let rules = self.rules_by_nonterminal();
+        let mut first: HashMap<&str, FirstSet> = HashMap::new();
+        let mut changed = true;
+
+        /* The sets only grow and are bounded by the lookahead, so this reaches a fixpoint */
+        while changed {
+            changed = false;
+
+            for (nonterm, rules) in &rules {
+                let mut set = FirstSet::new();
+
+                for rule in rules {
+                    set.extend(first_of_rule(rule, &first, k));
+                }
+
+                let entry = first.entry(nonterm.id()).or_default();
+
+                if *entry != set {
+                    *entry = set;
+                    changed = true;
+                }
+            }
+        }
+
+        let mut reports = Vec::new();
+
+        for (nonterm, rules) in &rules {
+            let mut complete = Vec::with_capacity(rules.len());
+            let mut prefixes = Vec::with_capacity(rules.len());
+
+            for rule in rules {
+                let set = first_of_rule(rule, &first, k);
+                complete.push(set.iter().filter(|prefix| prefix.complete).map(|prefix| prefix.bytes.clone()).collect());
+                prefixes.push(set.into_iter().map(|prefix| prefix.bytes).collect());
+            }
+
+            for i in 0..rules.len() {
+                for j in i + 1..rules.len() {
+                    let Some(prefix) =
+                        find_overlap(&complete[i], &prefixes[j]).or_else(|| find_overlap(&complete[j], &prefixes[i]))
+                    else {
+                        continue;
+                    };
+
+                    reports.push(AmbiguityReport {
+                        non_terminal: nonterm.id().to_string(),
+                        first: rules[i].rhs().iter().map(symbol_string).collect(),
+                        second: rules[j].rhs().iter().map(symbol_string).collect(),
+                        prefix,
+                    });
+                }
+            }
+        }
+
+        reports
+    }
+
+    /// Find ambiguities by generating `sample_count` inputs and unparsing them again with the greedy unparser.
+    ///
+    /// If the unparser finds a different derivation for a sample than the one that generated it, the
+    /// first non-terminal where the derivations diverge is reported together with both alternatives and the sample.
+    /// Every pair of alternatives is reported once. This is a heuristic: samples that cannot be unparsed
+    /// at all are ignored and ambiguities that the samples don't hit are not found.
+    ///
+    /// The reports refer to the rules of this grammar, so build it with `optimize(false)` to get reports
+    /// about the rules as they were written.
+    pub fn detect_ambiguity(&self, sample_count: usize) -> Vec<AmbiguityReport> {
+        let grammar = Arc::new(LowLevelGrammar::from_high_level_grammar(self));
+        let rules: HashMap<&str, Vec<&ProductionRule>> =
+            self.rules_by_nonterminal().into_iter().map(|(nonterm, rules)| (nonterm.id(), rules)).collect();
+        let mut interpreter = GrammarInterpreter::with_shared(grammar.clone());
+        let unparser = Unparser::new(&grammar);
+        let mut input = Vec::with_capacity(SAMPLE_LEN);
+        let mut walk = Vec::new();
+        let mut seen = HashSet::new();
+        let mut reports = Vec::new();
+
+        for _ in 0..sample_count {
+            interpreter.interpret_into(&mut input, SAMPLE_LEN, Some(&mut walk));
+
+            let Some(unparsed) = unparser.greedy(&input) else {
+                continue;
+            };
+            let Some(step) = walk.iter().zip(&unparsed).position(|(a, b)| a != b) else {
+                continue;
+            };
+            let (nonterm, generated) = walk[step];
+            let (_, picked) = unparsed[step];
+            let name = &grammar.nonterminals()[nonterm];
+
+            let Some(alternatives) = rules.get(name.as_str()) else {
+                continue;
+            };
+
+            if !seen.insert((nonterm, generated, picked)) {
+                continue;
+            }
+
+            reports.push(AmbiguityReport {
+                non_terminal: name.clone(),
+                first: alternatives[generated].rhs().iter().map(symbol_string).collect(),
+                second: alternatives[picked].rhs().iter().map(symbol_string).collect(),
+                prefix: input.clone(),
+            });
+        }
+
+        reports
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::grammar::tests::grammar;
+
+    #[test]
+    fn test_unparse_ambiguities() {
+        let cfg = grammar(r#"{"<ENTRYPOINT>": [["<OP>", "'x'"]], "<OP>": [["'='"], ["'=='"], ["'!='"]]}"#);
+        assert_eq!(
+            cfg.unparse_ambiguities(),
+            vec![AmbiguityReport {
+                non_terminal: "OP".to_string(),
+                first: vec!["'='".to_string()],
+                second: vec!["'=='".to_string()],
+                prefix: b"=".to_vec(),
+            }]
+        );
+
+        /* Alternatives that differ somewhere after the lookahead are fine */
+        let cfg = grammar(r#"{"<ENTRYPOINT>": [["'abc'"], ["'abd'"], ["'ab'"]]}"#);
+        assert_eq!(cfg.unparse_ambiguities().len(), 2);
+        assert!(cfg.unparse_ambiguities_with_lookahead(1).is_empty());
+
+        /* Derivations of non-terminals */
+        let cfg =
+            grammar(r#"{"<ENTRYPOINT>": [["<A>", "<B>"], ["'ab'"]], "<A>": [["'a'"]], "<B>": [["'b'"], ["'c'"]]}"#);
+        assert_eq!(cfg.unparse_ambiguities()[0].prefix, b"ab");
+        let cfg = grammar(r#"{"<ENTRYPOINT>": [["<A>", "<B>"], ["'ab'"]], "<A>": [["'a'"]], "<B>": [["'c'"]]}"#);
+        assert!(cfg.unparse_ambiguities().is_empty());
+
+        /* Recursion */
+        let cfg =
+            grammar(r#"{"<ENTRYPOINT>": [["<DIGIT>"], ["<DIGIT>", "<ENTRYPOINT>"]], "<DIGIT>": [["'0'"], ["'1'"]]}"#);
+        let reports = cfg.unparse_ambiguities();
+        assert_eq!(reports.len(), 1);
+        assert_eq!(reports[0].prefix, b"0");
+    }
+
+    #[test]
+    fn test_detect_ambiguity() {
+        let cfg = grammar(r#"{"<ENTRYPOINT>": [["<A>", "<A>"]], "<A>": [["'a'"], ["'aa'"], ["'b'"]]}"#);
+        let reports = cfg.detect_ambiguity(200);
+        assert_eq!(reports.len(), 1);
+        assert_eq!(reports[0].non_terminal, "A");
+        assert_eq!(reports[0].first, vec!["'a'".to_string()]);
+        assert_eq!(reports[0].second, vec!["'aa'".to_string()]);
+        assert_eq!(reports[0].prefix, b"aaa");
+
+        /* Alternatives that derive the same bytes */
+        let cfg = grammar(r#"{"<ENTRYPOINT>": [["<A>"], ["<B>"]], "<A>": [["'x'"]], "<B>": [["'x'"]]}"#);
+        let reports = cfg.detect_ambiguity(100);
+        assert_eq!(reports.len(), 1);
+        assert_eq!(reports[0].non_terminal, "ENTRYPOINT");
+        assert_eq!(reports[0].first, vec!["<B>".to_string()]);
+        assert_eq!(reports[0].second, vec!["<A>".to_string()]);
+
+        let cfg =
+            grammar(r#"{"<ENTRYPOINT>": [["<DIGIT>", "<ENTRYPOINT>"], ["<DIGIT>"]], "<DIGIT>": [["'0'"], ["'1'"]]}"#);
+        assert!(cfg.detect_ambiguity(500).is_empty());
+    }
+}
@@ -1,7 +1,16 @@
+use serde::Serialize;
 use std::{
-    collections::HashSet,
+    collections::{
+        HashMap,
+        HashSet,
+    },
     path::Path,
+    time::{
+        Duration,
+        Instant,
+    },
 };
+use unicode_normalization::UnicodeNormalization;
 
 use crate::{
     error::{
@@ -9,20 +18,65 @@ use crate::{
         ParsingError,
     },
     grammar::{
+        cfg::BuildLimits,
         ContextFreeGrammar,
+        Exclusion,
         NonTerminal,
         ProductionRule,
         Symbol,
+        Terminal,
     },
     parser::{
         gramatron,
         peacock,
+        table,
     },
 };
 
 /// The default non-terminal that is used as an entrypoint to the grammar
 pub const DEFAULT_ENTRYPOINT: &str = "ENTRYPOINT";
 
+/// The non-terminal that derives the terminals that were discovered during fuzzing if a grammar
+/// references it without defining it.
+pub const DYNAMIC_NONTERMINAL: &str = "DYNAMIC";
+
+/// Separates the namespace from the name of a non-terminal, e.g. `<http::STRING>`
+pub const NAMESPACE_SEPARATOR: &str = "::";
+
+/// How grammars that were not loaded from a file are named in error messages
+const STRING_ORIGIN: &str = "<string>";
+
+/// The default maximum number of rules a grammar may have during optimization
+pub const DEFAULT_MAX_RULES: usize = 5_000_000;
+
+/// What [`GrammarBuilder::build`] does with non-terminals that are referenced but never defined.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum UndefinedPolicy {
+    /// Fail with [`GrammarError::MissingNonTerminal`] for the first undefined non-terminal
+    #[default]
+    Error,
+
+    /// Define every undefined non-terminal as the empty string and continue.
+    /// The stubbed non-terminals are listed in the [`BuildReport`].
+    StubEmpty,
+
+    /// Fail with [`GrammarError::MissingNonTerminals`] that lists all undefined non-terminals
+    Collect,
+}
+
+/// A Unicode normalization form for [`GrammarBuilder::normalize_terminals`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NormalizationForm {
+    /// Canonical composition, e.g. `é` becomes the single code point U+00E9
+    Nfc,
+
+    /// Canonical decomposition, e.g. `é` becomes `e` followed by the combining accent U+0301
+    Nfd,
+}
+
+/// A hook that was registered with [`GrammarBuilder::transform`]
+type TransformHook = Box<dyn FnMut(&mut Vec<ProductionRule>)>;
+
 /// The GrammarBuilder loads grammars from disk and returns a unified [`ContextFreeGrammar`].
 ///    
 /// Use it like so:
@@ -40,7 +94,18 @@ pub const DEFAULT_ENTRYPOINT: &str = "ENTRYPOINT";
 pub struct GrammarBuilder {
     rules: Vec<ProductionRule>,
     optimize: bool,
+    inline: bool,
     entrypoint: String,
+    max_rules: usize,
+    max_build_time: Option<Duration>,
+    max_gnf_growth: Option<usize>,
+    terminal_table: Option<Vec<String>>,
+    exclusions: Vec<Exclusion>,
+    on_undefined: UndefinedPolicy,
+    stubbed: Vec<String>,
+    normalization: Option<NormalizationForm>,
+    context_sensitive: bool,
+    hooks: Vec<TransformHook>,
 }
 
 impl GrammarBuilder {
@@ -48,7 +113,18 @@ impl GrammarBuilder {
         Self {
             rules: Vec::new(),
             optimize: true,
+            inline: false,
             entrypoint: DEFAULT_ENTRYPOINT.to_string(),
+            max_rules: DEFAULT_MAX_RULES,
+            max_build_time: None,
+            max_gnf_growth: None,
+            terminal_table: None,
+            exclusions: Vec::new(),
+            on_undefined: UndefinedPolicy::Error,
+            stubbed: Vec::new(),
+            normalization: None,
+            context_sensitive: false,
+            hooks: Vec::new(),
         }
     }
 
@@ -62,8 +138,10 @@ impl GrammarBuilder {
         true
     }
 
-    fn check_non_terminals(&self) -> Option<String> {
+    /// All non-terminals that are referenced but never defined, in the order of their first reference.
+    fn check_non_terminals(&self) -> Vec<String> {
         let mut defined_non_terms = HashSet::new();
+        let mut undefined = Vec::new();
 
         for rule in &self.rules {
             defined_non_terms.insert(rule.lhs().id());
@@ -72,8 +150,81 @@ impl GrammarBuilder {
         for rule in &self.rules {
             for symbol in rule.rhs() {
                 if let Symbol::NonTerminal(nonterm) = symbol {
-                    if !defined_non_terms.contains(nonterm.id()) {
-                        return Some(nonterm.id().to_string());
+                    if !defined_non_terms.contains(nonterm.id()) && !undefined.iter().any(|x| x == nonterm.id()) {
+                        undefined.push(nonterm.id().to_string());
+                    }
+                }
+            }
+        }
+
+        undefined
+    }
+
+    /// Handle undefined non-terminals according to the [`UndefinedPolicy`].
+    fn handle_undefined(&mut self) -> Result<(), GrammarError> {
+        let mut undefined = self.check_non_terminals();
+
+        if undefined.is_empty() {
+            return Ok(());
+        }
+
+        match self.on_undefined {
+            UndefinedPolicy::Error => Err(GrammarError::MissingNonTerminal(undefined.swap_remove(0))),
+            UndefinedPolicy::Collect => Err(GrammarError::MissingNonTerminals(undefined)),
+            UndefinedPolicy::StubEmpty => {
+                for nonterm in &undefined {
+                    self.rules.push(ProductionRule::new(
+                        NonTerminal::new(nonterm),
+                        vec![Symbol::Terminal(Terminal::new(String::new()))],
+                    ));
+                }
+
+                self.stubbed = undefined;
+                Ok(())
+            },
+        }
+    }
+
+    /// Find a non-terminal that is reachable from the entrypoint but cannot derive a string of terminals.
+    fn check_termination(&self) -> Option<String> {
+        let mut productive = HashSet::new();
+        let mut changed = true;
+
+        while changed {
+            changed = false;
+
+            for rule in &self.rules {
+                if !productive.contains(rule.lhs().id())
+                    && rule.rhs().iter().all(|symbol| match symbol {
+                        Symbol::Terminal(_) => true,
+                        Symbol::NonTerminal(nonterm) => productive.contains(nonterm.id()),
+                    })
+                {
+                    productive.insert(rule.lhs().id());
+                    changed = true;
+                }
+            }
+        }
+
+        let mut rules_of: HashMap<&str, Vec<&ProductionRule>> = HashMap::new();
+        let mut reachable = HashSet::from([self.entrypoint.as_str()]);
+        let mut queue = vec![self.entrypoint.as_str()];
+
+        for rule in &self.rules {
+            rules_of.entry(rule.lhs().id()).or_default().push(rule);
+        }
+
+        while let Some(nonterm) = queue.pop() {
+            if !productive.contains(nonterm) {
+                return Some(nonterm.to_string());
+            }
+
+            for rule in rules_of.get(nonterm).into_iter().flatten() {
+                for symbol in rule.rhs() {
+                    if let Symbol::NonTerminal(child) = symbol {
+                        if reachable.insert(child.id()) {
+                            queue.push(child.id());
+                        }
                     }
                 }
             }
@@ -81,14 +232,300 @@ impl GrammarBuilder {
 
         None
     }
+
+    /// Find a non-terminal with a rule that contains a binding marker.
+    fn check_bindings(&self) -> Option<String> {
+        self.rules.iter().find(|rule| rule.rhs().iter().any(Symbol::is_binding)).map(|rule| rule.lhs().id().to_string())
+    }
+
+    /// Give [`DYNAMIC_NONTERMINAL`] a rule with the dynamic placeholder if it is used but not defined.
+    fn add_dynamic_rule(&mut self) {
+        let is_defined = self.rules.iter().any(|rule| rule.lhs().id() == DYNAMIC_NONTERMINAL);
+        let is_used = self
+            .rules
+            .iter()
+            .flat_map(|rule| rule.rhs())
+            .any(|symbol| matches!(symbol, Symbol::NonTerminal(nonterm) if nonterm.id() == DYNAMIC_NONTERMINAL));
+
+        if is_used && !is_defined {
+            self.rules.push(ProductionRule::new(
+                NonTerminal::new(DYNAMIC_NONTERMINAL),
+                vec![Symbol::Terminal(Terminal::dynamic())],
+            ));
+        }
+    }
+
+    /// Replace all references of the form `#index` with the corresponding entry of the terminal table.
+    /// Only non-terminals are references, terminals that happen to look like `#index` are kept.
+    fn resolve_terminal_indices(&mut self) -> Result<(), GrammarError> {
+        let Some(table) = &self.terminal_table else {
+            return Ok(());
+        };
+
+        for rule in &mut self.rules {
+            let mut rhs = Vec::with_capacity(rule.rhs().len());
+            let mut changed = false;
+
+            for symbol in rule.rhs() {
+                let Symbol::NonTerminal(nonterm) = symbol else {
+                    rhs.push(symbol.clone());
+                    continue;
+                };
+
+                match nonterm.id().strip_prefix('#').and_then(|index| index.parse::<usize>().ok()) {
+                    Some(index) => {
+                        let Some(term) = table.get(index) else {
+                            return Err(GrammarError::InvalidTerminalIndex {
+                                index,
+                                len: table.len(),
+                            });
+                        };
+
+                        rhs.push(Symbol::Terminal(Terminal::new(term.clone())));
+                        changed = true;
+                    },
+                    None => rhs.push(symbol.clone()),
+                }
+            }
+
+            if changed {
+                *rule = ProductionRule::new(rule.lhs().clone(), rhs);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Resolve everything that spans multiple grammars and check that the rules are complete.
+    fn prepare(&mut self) -> Result<(ContextFreeGrammar, BuildLimits), GrammarError> {
+        let limits = BuildLimits::new(self.max_rules, self.max_build_time, self.max_gnf_growth);
+
+        self.resolve_terminal_indices()?;
+        self.normalize();
+        self.expand_exclusions(&limits)?;
+
+        for hook in &mut self.hooks {
+            hook(&mut self.rules);
+        }
+
+        self.add_dynamic_rule();
+
+        if !self.context_sensitive {
+            if let Some(nonterm) = self.check_bindings() {
+                return Err(GrammarError::ContextSensitive(nonterm));
+            }
+        }
+
+        if self.rules.is_empty() {
+            return Err(GrammarError::EmptyGrammar);
+        }
+
+        if self.check_entrypoint() {
+            return Err(GrammarError::MissingEntrypoint(self.entrypoint.clone()));
+        }
+
+        self.handle_undefined()?;
+
+        if let Some(nonterm) = self.check_termination() {
+            return Err(GrammarError::UnterminatedRecursion(nonterm));
+        }
+
+        let rules = std::mem::take(&mut self.rules);
+        let cfg = ContextFreeGrammar::new(rules, NonTerminal::new(&self.entrypoint));
+        log::debug!(
+            "loaded {} rules with {} non-terminals, entrypoint {}",
+            cfg.rules().len(),
+            cfg.nonterminals().count(),
+            self.entrypoint
+        );
+        Ok((cfg, limits))
+    }
+
+    /// Apply all transformations to `cfg`. `stop` gets called after every transformation and aborts
+    /// the pipeline if it returns true, in which case the name of the last transformation is returned.
+    /// Every transformation is recorded in `trace` if it is given.
+    fn run_passes<F>(
+        &self,
+        cfg: &mut ContextFreeGrammar,
+        limits: &BuildLimits,
+        mut trace: Option<&mut BuildTrace>,
+        mut stop: F,
+    ) -> Result<Option<&'static str>, GrammarError>
+    where
+        F: FnMut(&ContextFreeGrammar) -> bool,
+    {
+        macro_rules! pass {
+            ($name:ident) => {
+                pass!(@run $name, cfg.$name());
+            };
+            ($name:ident, $limits:expr) => {
+                pass!(@run $name, cfg.$name($limits)?);
+            };
+            (@run $name:ident, $call:expr) => {
+                let before = cfg.rules().len();
+                let counts = trace.is_some().then(|| rules_per_nonterminal(cfg));
+                let start = Instant::now();
+                $call;
+                log_pass(stringify!($name), before, cfg, start);
+
+                if let (Some(trace), Some(counts)) = (trace.as_deref_mut(), counts) {
+                    trace.passes.push(PassTrace::new(stringify!($name), before, counts, cfg, start.elapsed()));
+                }
+
+                if stop(cfg) {
+                    return Ok(Some(stringify!($name)));
+                }
+            };
+        }
+
+        if self.optimize {
+            pass!(concatenate_terminals);
+            pass!(remove_epsilon_rules, limits);
+            pass!(remove_duplicate_rules);
+            pass!(remove_unit_rules, limits);
+            pass!(remove_unused_rules);
+
+            if !cfg.is_in_gnf() {
+                pass!(remove_mixed_rules);
+                pass!(break_rules, limits);
+                pass!(remove_left_recursion, limits);
+                pass!(convert_to_gnf, limits);
+                pass!(remove_unused_rules);
+            }
+        }
+
+        if self.inline {
+            pass!(inline_single_use);
+        }
+
+        if cfg.count_entrypoint_rules() > 1 {
+            pass!(set_new_entrypoint);
+        }
+
+        Ok(None)
+    }
+
+    /// Bring all terminals into the configured normalization form.
+    /// Runs, binding markers, builtins and the placeholder of the dynamic non-terminal are left as they are.
+    fn normalize(&mut self) {
+        let Some(form) = self.normalization else {
+            return;
+        };
+
+        for rule in &mut self.rules {
+            let mut rhs = Vec::with_capacity(rule.rhs().len());
+            let mut changed = false;
+
+            for symbol in rule.rhs() {
+                match symbol {
+                    Symbol::Terminal(_) if symbol.is_run() || symbol.is_binding() || symbol.is_builtin() => {
+                        rhs.push(symbol.clone())
+                    },
+                    Symbol::Terminal(term) if !term.is_dynamic() => {
+                        let content = term.content();
+                        let normalized: String = match form {
+                            NormalizationForm::Nfc => content.nfc().collect(),
+                            NormalizationForm::Nfd => content.nfd().collect(),
+                        };
+
+                        changed |= normalized != content;
+                        rhs.push(Symbol::Terminal(Terminal::new(normalized)));
+                    },
+                    _ => rhs.push(symbol.clone()),
+                }
+            }
+
+            if changed {
+                *rule = ProductionRule::new(rule.lhs().clone(), rhs);
+            }
+        }
+    }
+
+    /// Create the rules of all non-terminals that exclude strings from other non-terminals.
+    fn expand_exclusions(&mut self, limits: &BuildLimits) -> Result<(), GrammarError> {
+        for exclusion in std::mem::take(&mut self.exclusions) {
+            if self.rules.iter().any(|rule| rule.lhs() == exclusion.name()) {
+                continue;
+            }
+
+            if !self.rules.iter().any(|rule| rule.lhs() == exclusion.from()) {
+                return Err(GrammarError::MissingNonTerminal(exclusion.from().id().to_string()));
+            }
+
+            let mut new_rules = exclusion.expand(&self.rules, limits)?;
+            self.rules.append(&mut new_rules);
+        }
+
+        Ok(())
+    }
 }
 
 impl GrammarBuilder {
     /// Load a grammar from disk that is in Peacock format. How the peacock format looks like is explained
     /// in the [README](https://github.com/z2-2z/peacock#how-to-write-grammars) of this project.
     pub fn peacock_grammar<P: AsRef<Path>>(mut self, path: P) -> Result<Self, ParsingError> {
-        let mut new_rules = peacock::parse_json(path.as_ref())?;
+        let (mut new_rules, mut exclusions) = peacock::parse_json(path.as_ref())?;
         self.rules.append(&mut new_rules);
+        self.exclusions.append(&mut exclusions);
+        Ok(self)
+    }
+
+    /// Load a grammar from disk that is in Peacock format like [`peacock_grammar()`](GrammarBuilder::peacock_grammar)
+    /// and put its non-terminals into `namespace`, such that grammars which use the same names for different
+    /// things can be merged.
+    ///
+    /// Every non-terminal `<NAME>` of the grammar and of the files it imports becomes `<namespace::NAME>`.
+    /// This also applies to references of undefined non-terminals, so errors about them name the namespace.
+    /// Qualified names like `<common::STRING>` are left as they are and reference other namespaces,
+    /// `<DYNAMIC>` stays global unless the grammar defines it.
+    pub fn peacock_grammar_namespaced<P: AsRef<Path>>(
+        mut self,
+        path: P,
+        namespace: &str,
+    ) -> Result<Self, ParsingError> {
+        let path = path.as_ref();
+
+        if namespace.is_empty() || namespace.contains(NAMESPACE_SEPARATOR) || namespace.contains(['<', '>']) {
+            return Err(ParsingError::new(path, format!("Invalid namespace '{}'", namespace)));
+        }
+
+        let (new_rules, mut exclusions) = peacock::parse_json(path)?;
+        let defines_dynamic = new_rules.iter().any(|rule| rule.lhs().id() == DYNAMIC_NONTERMINAL);
+        let qualify = |nonterm: &NonTerminal| {
+            let id = nonterm.id();
+
+            if id.contains(NAMESPACE_SEPARATOR) || (id == DYNAMIC_NONTERMINAL && !defines_dynamic) {
+                nonterm.clone()
+            } else {
+                NonTerminal::new(format!("{}{}{}", namespace, NAMESPACE_SEPARATOR, id))
+            }
+        };
+
+        for rule in new_rules {
+            let rhs = rule
+                .rhs()
+                .iter()
+                .map(|symbol| match symbol {
+                    Symbol::NonTerminal(nonterm) => Symbol::NonTerminal(qualify(nonterm)),
+                    Symbol::Terminal(_) => symbol.clone(),
+                })
+                .collect();
+            self.rules.push(ProductionRule::new(qualify(rule.lhs()), rhs));
+        }
+
+        for exclusion in &mut exclusions {
+            exclusion.rename(qualify);
+        }
+
+        self.exclusions.append(&mut exclusions);
+        Ok(self)
+    }
+
+    /// Load a grammar in Peacock format from the string `grammar`, e.g. one that was read from stdin.
+    pub fn peacock_grammar_str(mut self, grammar: &str) -> Result<Self, ParsingError> {
+        let (mut new_rules, mut exclusions) = peacock::parse_str(grammar, Path::new(STRING_ORIGIN))?;
+        self.rules.append(&mut new_rules);
+        self.exclusions.append(&mut exclusions);
         Ok(self)
     }
 
@@ -99,6 +536,25 @@ impl GrammarBuilder {
         Ok(self)
     }
 
+    /// Load a grammar in [Gramatron](https://github.com/HexHive/Gramatron)'s format from the string `grammar`.
+    pub fn gramatron_grammar_str(mut self, grammar: &str) -> Result<Self, ParsingError> {
+        let mut new_rules = gramatron::parse_str(grammar, Path::new(STRING_ORIGIN))?;
+        self.rules.append(&mut new_rules);
+        Ok(self)
+    }
+
+    /// Load a table of terminals from disk that rules can reference by their index as `#index`.
+    /// The table is either a JSON array of strings or a text file with one terminal per line.
+    ///
+    /// The references get resolved in [`build()`](GrammarBuilder::build), so this applies to all
+    /// loaded grammars regardless of the order in which they were loaded.
+    /// In the Gramatron format a reference is an unquoted token like `#3`, in the Peacock format it is a non-terminal like `<#3>`.
+    /// Quoted terminals like `'#3'` are never resolved.
+    pub fn terminal_table<P: AsRef<Path>>(mut self, path: P) -> Result<Self, ParsingError> {
+        self.terminal_table = Some(table::parse_table(path.as_ref())?);
+        Ok(self)
+    }
+
     /// Apply Gramatron-style optimizations to this grammar that enable better mutation quality.
     ///
     /// Default: `true`
@@ -107,6 +563,97 @@ impl GrammarBuilder {
         self
     }
 
+    /// Substitute non-terminals that have a single production and are referenced only once
+    /// into the rule that references them. This reduces the number of functions
+    /// the C backend has to emit without changing the generated language.
+    ///
+    /// Default: `false`
+    pub fn inline_single_use(mut self, inline: bool) -> Self {
+        self.inline = inline;
+        self
+    }
+
+    /// Abort optimizing the grammar with a [`GrammarError::ComplexityLimit`] once it has more than `max_rules` rules.
+    /// Some grammars explode during the conversion into Greibach Normal Form.
+    ///
+    /// Default: [`DEFAULT_MAX_RULES`]
+    pub fn max_rules(mut self, max_rules: usize) -> Self {
+        self.max_rules = max_rules;
+        self
+    }
+
+    /// Abort optimizing the grammar with a [`GrammarError::ComplexityLimit`] once it takes longer than `max_build_time`.
+    ///
+    /// Default: no limit
+    pub fn max_build_time(mut self, max_build_time: Duration) -> Self {
+        self.max_build_time = Some(max_build_time);
+        self
+    }
+
+    /// Abort optimizing the grammar with a [`GrammarError::GnfExplosion`] once the conversion into
+    /// Greibach Normal Form grows the grammar to more than `factor` times the rules it started with.
+    /// Every substitution of a leading non-terminal multiplies the alternatives of a rule, so grammars
+    /// with many alternatives can produce thousands of rules and slow down code generation.
+    /// Build such grammars with [`optimize(false)`](GrammarBuilder::optimize) instead.
+    ///
+    /// Default: no limit
+    pub fn max_gnf_growth(mut self, factor: usize) -> Self {
+        self.max_gnf_growth = Some(factor);
+        self
+    }
+
+    /// Choose what happens to non-terminals that are referenced but never defined.
+    /// Stubbing them out is useful while a grammar is still being written.
+    ///
+    /// Default: [`UndefinedPolicy::Error`]
+    pub fn on_undefined(mut self, policy: UndefinedPolicy) -> Self {
+        self.on_undefined = policy;
+        self
+    }
+
+    /// Apply the Unicode normalization `form` to all terminals, such that terminals that look the same
+    /// but were written in different forms, e.g. by different authors of merged grammars, become
+    /// identical and get deduplicated during optimization.
+    /// Every terminal is normalized on its own before it gets concatenated with its neighbors,
+    /// strings that exclusions remove are compared as they were written.
+    ///
+    /// Default: no normalization
+    pub fn normalize_terminals(mut self, form: NormalizationForm) -> Self {
+        self.normalization = Some(form);
+        self
+    }
+
+    /// Allow rules with binding markers, which tie the bytes at one place of an input to the bytes at another place,
+    /// e.g. `{"bind": "tag", "def": "<NAME>"}` and `{"use": "tag", "pop": true}` for matching XML tags, see [`BindingOp`](crate::grammar::BindingOp).
+    /// This makes the grammar context-sensitive: the backends keep a stack of bound bytes per binding name at runtime
+    /// and the unparsers only accept uses that repeat the bytes of their binding.
+    ///
+    /// Without it, [`build()`](GrammarBuilder::build) fails with [`GrammarError::ContextSensitive`] if a rule contains a marker.
+    ///
+    /// Default: `false`
+    pub fn context_sensitive(mut self, enabled: bool) -> Self {
+        self.context_sensitive = enabled;
+        self
+    }
+
+    /// Register a hook that edits the rules of all loaded grammars before they get checked and optimized,
+    /// e.g. with the helpers in [`edit`](crate::grammar::edit). Hooks run in the order of their registration
+    /// after terminal references, normalization and exclusions were resolved.
+    ///
+    /// Hooks can leave the rules in any state, [`build()`](GrammarBuilder::build) validates the result.
+    /// For it to succeed, a hook must keep these invariants:
+    /// - The entrypoint and every referenced non-terminal have at least one rule, except for
+    ///   [`DYNAMIC_NONTERMINAL`], whose rule gets added after the hooks ran.
+    /// - Every reachable non-terminal can finish its derivation.
+    /// - No right-hand-side is empty, Ɛ is an empty [`Terminal`].
+    pub fn transform<F>(mut self, hook: F) -> Self
+    where
+        F: FnMut(&mut Vec<ProductionRule>) + 'static,
+    {
+        self.hooks.push(Box::new(hook));
+        self
+    }
+
     /// Set the entrypoint of all loaded grammars to be the given non-terminal `entrypoint`.
     ///
     /// Default: [`DEFAULT_ENTRYPOINT`]
@@ -116,42 +663,215 @@ impl GrammarBuilder {
     }
 
     /// Create a [`ContextFreeGrammar`].
-    pub fn build(self) -> Result<ContextFreeGrammar, GrammarError> {
-        if self.check_entrypoint() {
-            return Err(GrammarError::MissingEntrypoint(self.entrypoint));
+    ///
+    /// The number of rules before and after every transformation is reported at debug level through the
+    /// [`log`](https://docs.rs/log) crate.
+    pub fn build(mut self) -> Result<ContextFreeGrammar, GrammarError> {
+        let (mut cfg, limits) = self.prepare()?;
+        self.run_passes(&mut cfg, &limits, None, |_| false)?;
+        Ok(cfg)
+    }
+
+    /// Create a [`ContextFreeGrammar`] like [`build()`](GrammarBuilder::build) and check whether the
+    /// result can still produce more than one input.
+    ///
+    /// If the grammar had multiple derivations before the transformations but not afterwards, the
+    /// transformations are applied again one by one to find the one that removed the variability.
+    pub fn build_with_report(mut self) -> Result<(ContextFreeGrammar, BuildReport), GrammarError> {
+        let (mut cfg, limits) = self.prepare()?;
+        let original = ContextFreeGrammar::new(cfg.rules().to_vec(), cfg.entrypoint().clone());
+        let derivations_before = original.count_derivations(MAX_COUNTED_DERIVATIONS);
+
+        self.run_passes(&mut cfg, &limits, None, |_| false)?;
+
+        let derivations = cfg.count_derivations(MAX_COUNTED_DERIVATIONS);
+        let mut collapsed_by = None;
+
+        if derivations < 2 && derivations_before >= 2 {
+            let mut cfg = original;
+            let limits = BuildLimits::new(self.max_rules, self.max_build_time, self.max_gnf_growth);
+            collapsed_by = self.run_passes(&mut cfg, &limits, None, |cfg| cfg.count_derivations(2) < 2)?;
         }
 
-        if let Some(nonterm) = self.check_non_terminals() {
-            return Err(GrammarError::MissingNonTerminal(nonterm));
+        let report = BuildReport {
+            derivations_before,
+            derivations,
+            collapsed_by,
+            stubbed: std::mem::take(&mut self.stubbed),
+        };
+
+        Ok((cfg, report))
+    }
+
+    /// Create a [`ContextFreeGrammar`] like [`build()`](GrammarBuilder::build) and record how every
+    /// transformation changed the number of rules.
+    ///
+    /// [`build()`](GrammarBuilder::build) does not pay for the bookkeeping.
+    pub fn build_with_trace(mut self) -> Result<(ContextFreeGrammar, BuildTrace), GrammarError> {
+        let (mut cfg, limits) = self.prepare()?;
+        let mut trace = BuildTrace::default();
+        self.run_passes(&mut cfg, &limits, Some(&mut trace), |_| false)?;
+        Ok((cfg, trace))
+    }
+}
+
+/// The upper limit of [`BuildReport::derivations`]
+pub const MAX_COUNTED_DERIVATIONS: usize = 1000;
+
+/// The result of the sanity checks in [`GrammarBuilder::build_with_report`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BuildReport {
+    /// The number of derivations of the loaded grammar, capped at [`MAX_COUNTED_DERIVATIONS`]
+    pub derivations_before: usize,
+
+    /// The number of derivations of the final grammar, capped at [`MAX_COUNTED_DERIVATIONS`]
+    pub derivations: usize,
+
+    /// The transformation that reduced the grammar to a single derivation, if the loaded grammar had more
+    pub collapsed_by: Option<&'static str>,
+
+    /// The undefined non-terminals that were defined as the empty string because of [`UndefinedPolicy::StubEmpty`]
+    pub stubbed: Vec<String>,
+}
+
+impl BuildReport {
+    /// Whether the final grammar always produces the same input.
+    pub fn is_degenerate(&self) -> bool {
+        self.derivations < 2
+    }
+}
+
+/// The maximum number of entries in [`PassTrace::growth`]
+pub const MAX_TRACED_GROWTH: usize = 10;
+
+/// How a non-terminal gained rules during a transformation.
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+pub struct RuleGrowth {
+    /// The non-terminal
+    pub non_terminal: String,
+
+    /// The number of its rules before the transformation
+    pub rules_before: usize,
+
+    /// The number of its rules after the transformation
+    pub rules_after: usize,
+}
+
+/// The effect of a single transformation, see [`BuildTrace`].
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+pub struct PassTrace {
+    /// The name of the transformation, e.g. `convert_to_gnf`
+    pub name: &'static str,
+
+    /// The number of rules before the transformation
+    pub rules_before: usize,
+
+    /// The number of rules after the transformation
+    pub rules_after: usize,
+
+    /// The wall time of the transformation in microseconds
+    pub micros: u128,
+
+    /// The non-terminals that gained the most rules, at most [`MAX_TRACED_GROWTH`] of them.
+    /// Sorted by the number of new rules, ties are sorted by name.
+    pub growth: Vec<RuleGrowth>,
+}
+
+impl PassTrace {
+    fn new(
+        name: &'static str,
+        rules_before: usize,
+        counts: HashMap<String, usize>,
+        cfg: &ContextFreeGrammar,
+        elapsed: Duration,
+    ) -> Self {
+        let mut growth: Vec<RuleGrowth> = rules_per_nonterminal(cfg)
+            .into_iter()
+            .filter_map(|(non_terminal, rules_after)| {
+                let rules_before = counts.get(&non_terminal).copied().unwrap_or(0);
+                (rules_after > rules_before).then_some(RuleGrowth {
+                    non_terminal,
+                    rules_before,
+                    rules_after,
+                })
+            })
+            .collect();
+        growth.sort_by(|a, b| {
+            (b.rules_after - b.rules_before)
+                .cmp(&(a.rules_after - a.rules_before))
+                .then_with(|| a.non_terminal.cmp(&b.non_terminal))
+        });
+        growth.truncate(MAX_TRACED_GROWTH);
+
+        Self {
+            name,
+            rules_before,
+            rules_after: cfg.rules().len(),
+            micros: elapsed.as_micros(),
+            growth,
         }
+    }
+}
 
-        let mut cfg = ContextFreeGrammar::new(self.rules, NonTerminal::new(self.entrypoint));
+/// Every transformation of [`GrammarBuilder::build_with_trace`] in the order in which they were applied.
+///
+/// The [`Display`](std::fmt::Display) implementation prints a table, the JSON serialization is meant for tools.
+#[derive(Debug, Default, Clone, Serialize, PartialEq, Eq)]
+pub struct BuildTrace {
+    /// The transformations. A transformation that runs multiple times has multiple entries.
+    pub passes: Vec<PassTrace>,
+}
 
-        if self.optimize {
-            cfg.concatenate_terminals();
-            cfg.remove_duplicate_rules();
-            cfg.remove_unit_rules();
-            cfg.remove_unused_rules();
+impl BuildTrace {
+    /// The first transformation with the given name.
+    pub fn pass(&self, name: &str) -> Option<&PassTrace> {
+        self.passes.iter().find(|pass| pass.name == name)
+    }
+}
 
-            if !cfg.is_in_gnf() {
-                cfg.remove_mixed_rules();
-                cfg.break_rules();
-                cfg.convert_to_gnf();
-                cfg.remove_unused_rules();
+impl std::fmt::Display for BuildTrace {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "{:<24} {:>10} {:>10} {:>12}", "pass", "before", "after", "time (us)")?;
+
+        for pass in &self.passes {
+            writeln!(f, "{:<24} {:>10} {:>10} {:>12}", pass.name, pass.rules_before, pass.rules_after, pass.micros)?;
+
+            for growth in &pass.growth {
+                writeln!(f, "    <{}>: {} -> {}", growth.non_terminal, growth.rules_before, growth.rules_after)?;
             }
         }
 
-        if cfg.count_entrypoint_rules() > 1 {
-            cfg.set_new_entrypoint();
-        }
+        Ok(())
+    }
+}
 
-        Ok(cfg)
+/// The number of rules of every non-terminal
+fn rules_per_nonterminal(cfg: &ContextFreeGrammar) -> HashMap<String, usize> {
+    let mut counts = HashMap::new();
+
+    for rule in cfg.rules() {
+        *counts.entry(rule.lhs().id().to_string()).or_insert(0) += 1;
     }
+
+    counts
+}
+
+/// Report the effect of a transformation at debug level, visible with `RUST_LOG=debug`
+fn log_pass(name: &str, before: usize, cfg: &ContextFreeGrammar, start: Instant) {
+    log::debug!(
+        "{}: {} -> {} rules, {} non-terminals ({:?})",
+        name,
+        before,
+        cfg.rules().len(),
+        cfg.nonterminals().count(),
+        start.elapsed()
+    );
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::grammar::BindingOp;
 
     #[test]
     #[should_panic]
@@ -168,4 +888,377 @@ mod tests {
             .unwrap();
         println!("{:#?}", cfg.rules());
     }
+
+    #[test]
+    fn test_terminal_table() {
+        let cfg = ContextFreeGrammar::builder()
+            .gramatron_grammar("test-data/grammars/terminal_table_rules.json")
+            .unwrap()
+            .terminal_table("test-data/grammars/terminal_table.txt")
+            .unwrap()
+            .optimize(false)
+            .build()
+            .unwrap();
+        let terminals: HashSet<&str> = cfg
+            .rules()
+            .iter()
+            .flat_map(|rule| rule.rhs())
+            .filter_map(|symbol| match symbol {
+                Symbol::Terminal(term) => Some(term.content()),
+                Symbol::NonTerminal(_) => None,
+            })
+            .collect();
+        assert_eq!(terminals, HashSet::from(["GET", "POST", "PUT", " /index.html", "", " HTTP/1.1"]));
+
+        let cfg = ContextFreeGrammar::builder()
+            .peacock_grammar_str(r#"{"<ENTRYPOINT>": [["<#0>", "'#1'"]]}"#)
+            .unwrap()
+            .terminal_table("test-data/grammars/terminal_table.txt")
+            .unwrap()
+            .optimize(false)
+            .build()
+            .unwrap();
+        let terminals: Vec<&str> = cfg
+            .rules()
+            .iter()
+            .flat_map(|rule| rule.rhs())
+            .filter_map(|symbol| match symbol {
+                Symbol::Terminal(term) => Some(term.content()),
+                Symbol::NonTerminal(_) => None,
+            })
+            .collect();
+        assert_eq!(terminals, vec!["GET", "#1"]);
+
+        let err = ContextFreeGrammar::builder()
+            .gramatron_grammar("test-data/grammars/terminal_table_invalid.json")
+            .unwrap()
+            .terminal_table("test-data/grammars/terminal_table.json")
+            .unwrap()
+            .build()
+            .err()
+            .unwrap();
+        assert!(matches!(
+            err,
+            GrammarError::InvalidTerminalIndex {
+                index: 9,
+                len: 5
+            }
+        ));
+    }
+
+    #[test]
+    fn test_normalize_terminals() {
+        /* The same word with a precomposed and with a combining accent */
+        let grammar = r#"{"<ENTRYPOINT>": [["'caf\u00e9'"], ["'cafe\u0301'"], ["'x'", "<DYNAMIC>"]]}"#;
+        let terminals = |cfg: &ContextFreeGrammar| -> Vec<String> {
+            cfg.rules()
+                .iter()
+                .filter_map(|rule| match rule.rhs() {
+                    [Symbol::Terminal(term)] if !term.is_dynamic() => Some(term.content().to_string()),
+                    _ => None,
+                })
+                .collect()
+        };
+
+        let cfg = ContextFreeGrammar::builder().peacock_grammar_str(grammar).unwrap().build().unwrap();
+        assert_eq!(terminals(&cfg), vec!["caf\u{e9}", "cafe\u{301}"]);
+
+        let cfg = ContextFreeGrammar::builder()
+            .peacock_grammar_str(grammar)
+            .unwrap()
+            .normalize_terminals(NormalizationForm::Nfc)
+            .build()
+            .unwrap();
+        assert_eq!(terminals(&cfg), vec!["caf\u{e9}"]);
+
+        let cfg = ContextFreeGrammar::builder()
+            .peacock_grammar_str(grammar)
+            .unwrap()
+            .normalize_terminals(NormalizationForm::Nfd)
+            .build()
+            .unwrap();
+        assert_eq!(terminals(&cfg), vec!["cafe\u{301}"]);
+        assert!(cfg.rules().iter().flat_map(|rule| rule.rhs()).any(|symbol| match symbol {
+            Symbol::Terminal(term) => term.is_dynamic(),
+            Symbol::NonTerminal(_) => false,
+        }));
+    }
+
+    #[test]
+    fn test_namespaces() {
+        let dir = std::env::temp_dir().join(format!("peacock-namespaces-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(
+            dir.join("http.json"),
+            r#"{"<ENTRYPOINT>": [["'GET '", "<STRING>", "' '", "<common::STRING>"]], "<STRING>": [["'/index'"]]}"#,
+        )
+        .unwrap();
+        std::fs::write(dir.join("common.json"), r#"{"<STRING>": [["'abc'"], ["'def'"]]}"#).unwrap();
+        std::fs::write(dir.join("broken.json"), r#"{"<ENTRYPOINT>": [["<STRING>"], ["<DYNAMIC>"]]}"#).unwrap();
+
+        let cfg = ContextFreeGrammar::builder()
+            .peacock_grammar_namespaced(dir.join("http.json"), "http")
+            .unwrap()
+            .peacock_grammar_namespaced(dir.join("common.json"), "common")
+            .unwrap()
+            .entrypoint("http::ENTRYPOINT")
+            .optimize(false)
+            .build()
+            .unwrap();
+        let rules: Vec<String> = cfg
+            .rules()
+            .iter()
+            .map(|rule| {
+                let rhs: Vec<String> = rule.rhs().iter().map(crate::grammar::symbol_string).collect();
+                format!("<{}> -> {}", rule.lhs().id(), rhs.join(" "))
+            })
+            .collect();
+        assert_eq!(
+            rules,
+            vec![
+                "<http::ENTRYPOINT> -> 'GET ' <http::STRING> ' ' <common::STRING>",
+                "<http::STRING> -> '/index'",
+                "<common::STRING> -> 'abc'",
+                "<common::STRING> -> 'def'",
+            ]
+        );
+
+        /* The JSON backend writes the qualified names */
+        let json_file = dir.join("merged.json");
+        crate::backends::json::JsonGenerator::new().generate(&json_file, &cfg);
+        let reloaded = ContextFreeGrammar::builder()
+            .peacock_grammar(&json_file)
+            .unwrap()
+            .entrypoint("http::ENTRYPOINT")
+            .optimize(false)
+            .build()
+            .unwrap();
+        assert_eq!(reloaded.rules(), cfg.rules());
+
+        let err = ContextFreeGrammar::builder()
+            .peacock_grammar_namespaced(dir.join("broken.json"), "broken")
+            .unwrap()
+            .entrypoint("broken::ENTRYPOINT")
+            .build()
+            .err()
+            .unwrap();
+        assert!(matches!(&err, GrammarError::MissingNonTerminal(nonterm) if nonterm == "broken::STRING"));
+
+        assert!(ContextFreeGrammar::builder().peacock_grammar_namespaced(dir.join("http.json"), "a::b").is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_grammar_str() {
+        let from_file =
+            ContextFreeGrammar::builder().peacock_grammar("test-data/grammars/regex.json").unwrap().build().unwrap();
+        let content = std::fs::read_to_string("test-data/grammars/regex.json").unwrap();
+        let from_str = ContextFreeGrammar::builder().peacock_grammar_str(&content).unwrap().build().unwrap();
+        assert_eq!(from_file.rules(), from_str.rules());
+
+        let content = std::fs::read_to_string("test-data/grammars/gramatron.json").unwrap();
+        ContextFreeGrammar::builder().gramatron_grammar_str(&content).unwrap().optimize(false).build().unwrap();
+
+        let err = ContextFreeGrammar::builder().peacock_grammar_str("{").err().unwrap();
+        assert_eq!(err.to_string(), "ParsingError in <string>: Invalid JSON syntax");
+    }
+
+    #[test]
+    fn test_complexity_limit() {
+        let builder = || ContextFreeGrammar::builder().gramatron_grammar("test-data/grammars/gramatron.json").unwrap();
+
+        let err = builder().max_rules(100).build().err().unwrap();
+        assert!(matches!(err, GrammarError::ComplexityLimit { rules, .. } if rules > 100));
+
+        let err = builder().max_build_time(Duration::ZERO).build().err().unwrap();
+        assert!(matches!(err, GrammarError::ComplexityLimit { .. }));
+
+        builder().max_rules(100).optimize(false).build().unwrap();
+    }
+
+    #[test]
+    fn test_gnf_explosion() {
+        let builder = || ContextFreeGrammar::builder().gramatron_grammar("test-data/grammars/gramatron.json").unwrap();
+
+        let err = builder().max_gnf_growth(1).build().err().unwrap();
+        assert!(matches!(err, GrammarError::GnfExplosion { original, rules } if rules > original));
+        assert_eq!(err.exit_code(), 10);
+
+        builder().max_gnf_growth(1000).build().unwrap();
+        builder().max_gnf_growth(1).optimize(false).build().unwrap();
+    }
+
+    #[test]
+    fn test_build_report() {
+        let (_, report) = ContextFreeGrammar::builder()
+            .peacock_grammar_str(r#"{"<ENTRYPOINT>": [["'GET '", "<PATH>"]], "<PATH>": [["'/index.html'"]]}"#)
+            .unwrap()
+            .build_with_report()
+            .unwrap();
+        assert!(report.is_degenerate());
+        assert_eq!(report.derivations_before, 1);
+        assert_eq!(report.collapsed_by, None);
+
+        let (_, report) = ContextFreeGrammar::builder()
+            .peacock_grammar_str(r#"{"<ENTRYPOINT>": [["'a'", "'b'"], ["'ab'"]]}"#)
+            .unwrap()
+            .build_with_report()
+            .unwrap();
+        assert!(report.is_degenerate());
+        assert_eq!(report.derivations_before, 2);
+        assert_eq!(report.collapsed_by, Some("remove_duplicate_rules"));
+
+        let (_, report) = ContextFreeGrammar::builder()
+            .peacock_grammar("test-data/grammars/regex.json")
+            .unwrap()
+            .build_with_report()
+            .unwrap();
+        assert!(!report.is_degenerate());
+        assert_eq!(report.derivations, MAX_COUNTED_DERIVATIONS);
+    }
+
+    #[test]
+    fn test_undefined_policy() {
+        let grammar = r#"{"<ENTRYPOINT>": [["'a'", "<B>", "<C>"], ["<B>", "'x'"]], "<D>": [["<E>"]]}"#;
+        let builder = || ContextFreeGrammar::builder().peacock_grammar_str(grammar).unwrap();
+
+        let err = builder().build().err().unwrap();
+        assert!(matches!(&err, GrammarError::MissingNonTerminal(nonterm) if nonterm == "B"));
+
+        let err = builder().on_undefined(UndefinedPolicy::Collect).build().err().unwrap();
+        assert!(matches!(&err, GrammarError::MissingNonTerminals(nonterms) if nonterms == &["B", "C", "E"]));
+        assert_eq!(err.to_string(), "The non-terminals 'B', 'C', 'E' are referenced but never defined");
+        assert_eq!(err.exit_code(), 4);
+
+        let (cfg, report) = builder().on_undefined(UndefinedPolicy::StubEmpty).build_with_report().unwrap();
+        assert_eq!(report.stubbed, vec!["B", "C", "E"]);
+        assert_eq!(report.derivations, 2);
+
+        let mut interpreter = crate::backends::interpreter::GrammarInterpreter::new(&cfg);
+        let mut outputs = HashSet::new();
+        let mut buf = Vec::new();
+
+        for _ in 0..100 {
+            interpreter.interpret_into(&mut buf, usize::MAX, None);
+            outputs.insert(buf.clone());
+        }
+
+        assert_eq!(outputs, HashSet::from([b"a".to_vec(), b"x".to_vec()]));
+    }
+
+    #[test]
+    fn test_unterminated_recursion() {
+        let err = ContextFreeGrammar::builder()
+            .peacock_grammar_str(r#"{"<ENTRYPOINT>": [["'a'"], ["<A>"]], "<A>": [["'b'", "<A>"]]}"#)
+            .unwrap()
+            .build()
+            .err()
+            .unwrap();
+        assert!(matches!(&err, GrammarError::UnterminatedRecursion(nonterm) if nonterm == "A"));
+        assert_eq!(err.exit_code(), 5);
+
+        /* Non-terminals that are not reachable from the entrypoint don't matter */
+        ContextFreeGrammar::builder()
+            .peacock_grammar_str(
+                r#"{"<ENTRYPOINT>": [["'a'"], ["<A>"]], "<A>": [["'b'", "<A>"], ["'c'"]], "<B>": [["<B>"]]}"#,
+            )
+            .unwrap()
+            .build()
+            .unwrap();
+    }
+
+    #[test]
+    fn test_empty_grammar() {
+        let err = ContextFreeGrammar::builder().peacock_grammar_str("{}").unwrap().build().err().unwrap();
+        assert!(matches!(err, GrammarError::EmptyGrammar));
+        assert_eq!(err.exit_code(), 11);
+
+        /* Transformations can also remove all rules */
+        let err = ContextFreeGrammar::builder()
+            .peacock_grammar_str(r#"{"<ENTRYPOINT>": [["'x'"]]}"#)
+            .unwrap()
+            .transform(|rules| rules.clear())
+            .build()
+            .err()
+            .unwrap();
+        assert!(matches!(err, GrammarError::EmptyGrammar));
+    }
+
+    #[test]
+    fn test_context_sensitive() {
+        let builder = || ContextFreeGrammar::builder().peacock_grammar("test-data/grammars/xml.json").unwrap();
+
+        let err = builder().build().err().unwrap();
+        assert!(matches!(&err, GrammarError::ContextSensitive(nonterm) if nonterm == "ELEMENT"));
+        assert_eq!(err.exit_code(), 12);
+
+        for optimize in [false, true] {
+            let cfg = builder().context_sensitive(true).optimize(optimize).build().unwrap();
+            let markers: HashSet<_> = cfg.terminals().filter_map(Terminal::binding).collect();
+            assert!(markers.contains(&(BindingOp::Begin, "tag")));
+            assert!(markers.contains(&(BindingOp::Pop, "tag")));
+        }
+    }
+
+    #[test]
+    fn test_single_rule_grammar() {
+        for optimize in [false, true] {
+            let cfg = ContextFreeGrammar::builder()
+                .peacock_grammar_str(r#"{"<ENTRYPOINT>": [["'x'"]]}"#)
+                .unwrap()
+                .optimize(optimize)
+                .build()
+                .unwrap();
+            assert_eq!(cfg.count_strings(), Some(1));
+
+            let mut interpreter = crate::backends::interpreter::GrammarInterpreter::new(&cfg);
+            let mut buf = Vec::new();
+            interpreter.interpret(&mut buf).unwrap();
+            assert_eq!(buf, b"x");
+        }
+    }
+
+    #[test]
+    fn test_build_trace() {
+        let grammar = r#"{
+            "<ENTRYPOINT>": [["<A>", "'x'"], ["<A>", "'x'"], ["<B>"]],
+            "<A>": [["'a'"], ["'b'"], ["'c'"], ["'d'"]],
+            "<B>": [["'b'", "'b'"]],
+            "<UNUSED>": [["'u'"]]
+        }"#;
+        let (cfg, trace) =
+            ContextFreeGrammar::builder().peacock_grammar_str(grammar).unwrap().build_with_trace().unwrap();
+
+        assert_eq!(trace.passes.first().unwrap().name, "concatenate_terminals");
+        assert_eq!(trace.passes.last().unwrap().rules_after, cfg.rules().len());
+
+        for pair in trace.passes.windows(2) {
+            assert_eq!(pair[0].rules_after, pair[1].rules_before);
+        }
+
+        for name in ["concatenate_terminals", "remove_duplicate_rules", "remove_unused_rules"] {
+            let pass = trace.pass(name).unwrap();
+            assert!(pass.rules_after <= pass.rules_before, "{}", name);
+            assert!(pass.growth.is_empty(), "{}", name);
+        }
+
+        let duplicates = trace.pass("remove_duplicate_rules").unwrap();
+        assert_eq!(duplicates.rules_before - duplicates.rules_after, 1);
+
+        /* Substituting <A> into <ENTRYPOINT> happens during the conversion to GNF */
+        let gnf = trace.pass("convert_to_gnf").unwrap();
+        assert!(gnf.rules_after > gnf.rules_before);
+        assert_eq!(gnf.growth[0].non_terminal, "ENTRYPOINT");
+
+        for pass in &trace.passes {
+            if pass.name != "convert_to_gnf" {
+                assert!(!pass.growth.iter().any(|growth| growth.non_terminal == "ENTRYPOINT"), "{}", pass.name);
+            }
+        }
+
+        let json = serde_json::to_value(&trace).unwrap();
+        assert_eq!(json["passes"][0]["name"], "concatenate_terminals");
+        assert!(trace.to_string().starts_with("pass "));
+    }
 }
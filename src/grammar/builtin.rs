@@ -0,0 +1,244 @@
+use serde::{
+    Deserialize,
+    Serialize,
+};
+use std::fmt;
+
+/// The maximum length of a `@bytes` builtin. The length of the bytes is stored in the low 16 bits of their step.
+pub const MAX_BUILTIN_BYTES: usize = 0xFFFF;
+
+/// A terminal whose content is chosen from a range when an input is generated, e.g. `@int(0, 1000)`
+/// for a decimal number or `@bytes(1, 64)` for 1 to 64 random bytes.
+///
+/// In automaton walks, a builtin occupies a step like a non-terminal, but the step holds the chosen value
+/// instead of a rule index. Every value of a step is valid: an offset from the minimum that is
+/// larger than the range wraps around. How much of the range the backends use for new values is
+/// configured with a [`GenerationProfile`](crate::backends::GenerationProfile).
+///
+/// The unparsers match the longest decimal number without leading zeros that lies in the range of an `@int`.
+/// The random bytes of a `@bytes` cannot be recovered from an input, so they only match the empty string if
+/// their minimum length is 0.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Builtin {
+    /// A decimal integer between `min` and `max`, both inclusive
+    Int {
+        /// The smallest value
+        min: i64,
+        /// The largest value
+        max: i64,
+    },
+
+    /// Between `min` and `max` random bytes, both inclusive
+    Bytes {
+        /// The smallest number of bytes
+        min: usize,
+        /// The largest number of bytes, at most [`MAX_BUILTIN_BYTES`]
+        max: usize,
+    },
+}
+
+impl Builtin {
+    /// The keyword of the builtin in the peacock format, e.g. `int` for `@int(0, 1000)`.
+    pub fn keyword(&self) -> &'static str {
+        match self {
+            Builtin::Int {
+                ..
+            } => "int",
+            Builtin::Bytes {
+                ..
+            } => "bytes",
+        }
+    }
+
+    /// The number of values above the minimum, i.e. `max - min`.
+    pub fn span(&self) -> u64 {
+        match self {
+            Builtin::Int {
+                min,
+                max,
+            } => max.wrapping_sub(*min) as u64,
+            Builtin::Bytes {
+                min,
+                max,
+            } => (max - min) as u64,
+        }
+    }
+
+    /// The number of bytes of the shortest value.
+    pub fn min_len(&self) -> usize {
+        match self {
+            Builtin::Int {
+                min,
+                max,
+            } => decimal_len(nearest_to_zero(*min, *max)),
+            Builtin::Bytes {
+                min,
+                ..
+            } => *min,
+        }
+    }
+
+    /// The number of bytes of the longest value.
+    pub fn max_len(&self) -> usize {
+        match self {
+            Builtin::Int {
+                min,
+                max,
+            } => decimal_len(*min).max(decimal_len(*max)),
+            Builtin::Bytes {
+                max,
+                ..
+            } => *max,
+        }
+    }
+
+    /// The step of a value that is at most `span` above the minimum, chosen with the random number `draw`.
+    /// Bytes keep the upper bits of `draw` as the seed of their content, so the step needs all 64 bits.
+    pub(crate) fn choose(&self, draw: u64, span: u64) -> u64 {
+        let offset = |x: u64| if span == u64::MAX { x } else { x % (span + 1) };
+
+        match self {
+            Builtin::Int {
+                ..
+            } => offset(draw),
+            Builtin::Bytes {
+                ..
+            } => (draw & !0xFFFF) | offset(draw & 0xFFFF),
+        }
+    }
+
+    /// The step of the shortest value
+    pub(crate) fn shortest(&self) -> u64 {
+        match self {
+            Builtin::Int {
+                min,
+                max,
+            } => nearest_to_zero(*min, *max).wrapping_sub(*min) as u64,
+            Builtin::Bytes {
+                ..
+            } => 0,
+        }
+    }
+
+    /// The bytes that the step `step` stands for, like the serialization functions of the C backend write them.
+    pub(crate) fn render(&self, step: u64) -> Vec<u8> {
+        let span = self.span();
+        let offset = |x: u64| if span == u64::MAX { x } else { x % (span + 1) };
+
+        match self {
+            Builtin::Int {
+                min,
+                ..
+            } => min.wrapping_add(offset(step) as i64).to_string().into_bytes(),
+            Builtin::Bytes {
+                min,
+                ..
+            } => {
+                let len = min + offset(step & 0xFFFF) as usize;
+                random_bytes(step >> 16, len)
+            },
+        }
+    }
+
+    /// Match the value at the start of `input` like the unparsers of the C backend.
+    /// Returns the number of matched bytes and the step of the value.
+    pub(crate) fn unparse(&self, input: &[u8]) -> Option<(usize, usize)> {
+        match self {
+            Builtin::Int {
+                min,
+                max,
+            } => {
+                let (len, value) = match_int(input, *min, *max)?;
+                Some((len, value.wrapping_sub(*min) as usize))
+            },
+            Builtin::Bytes {
+                min,
+                ..
+            } => (*min == 0).then_some((0, 0)),
+        }
+    }
+}
+
+impl fmt::Display for Builtin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Builtin::Int {
+                min,
+                max,
+            } => write!(f, "@int({}, {})", min, max),
+            Builtin::Bytes {
+                min,
+                max,
+            } => write!(f, "@bytes({}, {})", min, max),
+        }
+    }
+}
+
+/// The value in `min..=max` with the smallest magnitude
+fn nearest_to_zero(min: i64, max: i64) -> i64 {
+    if min > 0 {
+        min
+    } else if max < 0 {
+        max
+    } else {
+        0
+    }
+}
+
+fn decimal_len(value: i64) -> usize {
+    value.to_string().len()
+}
+
+/// `len` bytes from a splitmix64 stream that starts at `seed`
+fn random_bytes(mut seed: u64, len: usize) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(len + 8);
+
+    while bytes.len() < len {
+        seed = seed.wrapping_add(0x9E3779B97F4A7C15);
+        let mut z = seed;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+        z ^= z >> 31;
+        bytes.extend_from_slice(&z.to_le_bytes());
+    }
+
+    bytes.truncate(len);
+    bytes
+}
+
+/// The longest decimal number without leading zeros at the start of `input` that lies in `min..=max`.
+/// `-0` is not a number.
+fn match_int(input: &[u8], min: i64, max: i64) -> Option<(usize, i64)> {
+    let negative = input.first() == Some(&b'-');
+    let start = negative as usize;
+    let limit = if negative { 1u64 << 63 } else { i64::MAX as u64 };
+    let mut magnitude = 0u64;
+    let mut best = None;
+
+    for (i, c) in input.iter().enumerate().skip(start) {
+        if !c.is_ascii_digit() || (i > start && input[start] == b'0') {
+            break;
+        }
+
+        let digit = (c - b'0') as u64;
+
+        if magnitude > (limit - digit) / 10 {
+            break;
+        }
+
+        magnitude = magnitude * 10 + digit;
+
+        if negative && magnitude == 0 {
+            break;
+        }
+
+        let value = if negative { 0u64.wrapping_sub(magnitude) as i64 } else { magnitude as i64 };
+
+        if (min..=max).contains(&value) {
+            best = Some((i + 1, value));
+        }
+    }
+
+    best
+}
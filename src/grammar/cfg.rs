@@ -1,21 +1,55 @@
 use ahash::RandomState;
+use indexmap::IndexMap;
 use petgraph::{
+    algo::tarjan_scc,
     visit::Bfs,
     Graph,
 };
-use std::collections::{
-    HashMap,
-    HashSet,
+use serde::{
+    Deserialize,
+    Serialize,
+};
+use std::{
+    borrow::Cow,
+    collections::{
+        HashMap,
+        HashSet,
+    },
+    hash::{
+        Hash,
+        Hasher,
+    },
+    io::{
+        Read,
+        Write,
+    },
+    sync::OnceLock,
+    time::{
+        Duration,
+        Instant,
+    },
 };
 
-use crate::grammar::builder::GrammarBuilder;
+use crate::{
+    backends::interpreter::{
+        Enumeration,
+        GrammarInterpreter,
+    },
+    error::GrammarError,
+    grammar::{
+        builder::GrammarBuilder,
+        edit,
+        Builtin,
+    },
+};
 
 /// This type represents a [non-terminal](https://en.wikipedia.org/wiki/Terminal_and_nonterminal_symbols#Nonterminal_symbols) in a context-free grammar.
-#[derive(Debug, Clone, Eq, Hash, PartialEq)]
+#[derive(Debug, Clone, Eq, Hash, PartialEq, Serialize, Deserialize)]
 pub struct NonTerminal(String);
 
 impl NonTerminal {
-    pub(crate) fn new<S: Into<String>>(s: S) -> Self {
+    /// Create the non-terminal that is written as `<s>` in a grammar.
+    pub fn new<S: Into<String>>(s: S) -> Self {
         Self(s.into())
     }
 
@@ -25,24 +59,241 @@ impl NonTerminal {
     }
 }
 
+/// The largest count of a `@repeat` terminal. Runs are expanded into the output, so larger counts
+/// would produce inputs that don't fit into the buffers of the backends.
+pub const MAX_REPEAT_COUNT: usize = 1 << 24;
+
 /// This type represents a [terminal](https://en.wikipedia.org/wiki/Terminal_and_nonterminal_symbols#Terminal_symbols) in a context-free grammar.
-#[derive(Debug, Clone, Eq, Hash, PartialEq)]
-pub struct Terminal(String);
+///
+/// A terminal is either a string or a run of a single byte that is repeated many times, like `@repeat('A', 4096)`.
+/// Runs are stored as the byte and the number of repetitions, such that long paddings do not bloat the grammar.
+///
+/// Grammars with context-sensitive rules also contain binding markers, see [`BindingOp`].
+/// Builtins like `@int(0, 1000)` get their content when an input is generated, see [`Builtin`].
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct Terminal(TerminalData);
+
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+enum TerminalData {
+    Text(String),
+    Repeat { byte: u8, count: usize },
+    Binding { op: BindingOp, name: String },
+    Builtin { builtin: Builtin },
+    Dynamic,
+}
+
+/// What a binding marker does. Markers make a grammar context-sensitive: they tie the bytes at one place
+/// of an input to the bytes at another place, e.g. the closing tag of an XML element to its opening tag.
+///
+/// Every binding name has a stack of bindings. The bytes that are generated between the [`Begin`](BindingOp::Begin)
+/// and the [`End`](BindingOp::End) marker of a name become a binding, which the [`Use`](BindingOp::Use) and
+/// [`Pop`](BindingOp::Pop) markers of that name repeat. Markers only have an effect on the innermost binding,
+/// so a binding must be complete before it is used and the definition of a binding must leave the
+/// stack of its name as it found it.
+///
+/// In the peacock format, `{"bind": "tag", "def": "<NAME>"}` stands for `@begin(tag) <NAME> @end(tag)`,
+/// `{"use": "tag"}` for `@use(tag)` and `{"use": "tag", "pop": true}` for `@pop(tag)`.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BindingOp {
+    /// Start a new binding, the following bytes become part of it
+    Begin,
+
+    /// Complete the innermost binding
+    End,
+
+    /// Repeat the bytes of the innermost binding
+    Use,
+
+    /// Repeat the bytes of the innermost binding and remove it
+    Pop,
+}
+
+impl BindingOp {
+    /// The keyword of the marker in the peacock format, e.g. `begin` for `@begin(tag)`.
+    pub fn keyword(&self) -> &'static str {
+        match self {
+            BindingOp::Begin => "begin",
+            BindingOp::End => "end",
+            BindingOp::Use => "use",
+            BindingOp::Pop => "pop",
+        }
+    }
+
+    /// Whether the marker produces the bytes of a binding.
+    pub fn is_reference(&self) -> bool {
+        matches!(self, BindingOp::Use | BindingOp::Pop)
+    }
+}
 
 impl Terminal {
-    pub(crate) fn new<S: Into<String>>(s: S) -> Self {
-        Self(s.into())
+    /// Create a terminal that consists of the string `s`. The empty string is Ɛ.
+    pub fn new<S: Into<String>>(s: S) -> Self {
+        Self(TerminalData::Text(s.into()))
+    }
+
+    /// A terminal that consists of `count` times `byte`. `byte` must be ASCII because terminals are strings.
+    pub(crate) fn repeat(byte: u8, count: usize) -> Self {
+        assert!(byte.is_ascii());
+
+        if count == 0 {
+            Self::new("")
+        } else {
+            Self(TerminalData::Repeat {
+                byte,
+                count,
+            })
+        }
+    }
+
+    /// A binding marker that applies `op` to the bindings called `name`.
+    pub(crate) fn marker<S: Into<String>>(op: BindingOp, name: S) -> Self {
+        Self(TerminalData::Binding {
+            op,
+            name: name.into(),
+        })
+    }
+
+    /// A terminal whose content is chosen from the range of `builtin` when an input is generated.
+    pub(crate) fn from_builtin(builtin: Builtin) -> Self {
+        Self(TerminalData::Builtin {
+            builtin,
+        })
     }
 
-    /// The data of the terminal.
+    /// The placeholder that the rule of the undefined non-terminal [`DYNAMIC_NONTERMINAL`](crate::grammar::DYNAMIC_NONTERMINAL) consists of.
+    pub(crate) fn dynamic() -> Self {
+        Self(TerminalData::Dynamic)
+    }
+
+    /// The data of the terminal. Runs are not stored as text and are empty, use [`run()`](Terminal::run)
+    /// or [`expanded()`](Terminal::expanded) for them. Binding markers, builtins and the dynamic placeholder
+    /// have no fixed content and are empty.
     pub fn content(&self) -> &str {
-        &self.0
+        match &self.0 {
+            TerminalData::Text(content) => content,
+            _ => "",
+        }
+    }
+
+    /// Like [`content()`](Terminal::content) but runs get expanded into all of their bytes.
+    pub fn expanded(&self) -> Cow<'_, str> {
+        match &self.0 {
+            TerminalData::Repeat {
+                byte,
+                count,
+            } => Cow::Owned((*byte as char).to_string().repeat(*count)),
+            _ => Cow::Borrowed(self.content()),
+        }
+    }
+
+    /// The byte and the number of its repetitions if this terminal is a run.
+    pub fn run(&self) -> Option<(u8, usize)> {
+        match &self.0 {
+            TerminalData::Repeat {
+                byte,
+                count,
+            } => Some((*byte, *count)),
+            _ => None,
+        }
+    }
+
+    /// The operation and the name of the bindings if this terminal is a binding marker.
+    pub fn binding(&self) -> Option<(BindingOp, &str)> {
+        match &self.0 {
+            TerminalData::Binding {
+                op,
+                name,
+            } => Some((*op, name)),
+            _ => None,
+        }
+    }
+
+    /// The builtin if this terminal is chosen from a range when an input is generated.
+    pub fn builtin(&self) -> Option<&Builtin> {
+        match &self.0 {
+            TerminalData::Builtin {
+                builtin,
+            } => Some(builtin),
+            _ => None,
+        }
+    }
+
+    /// The number of bytes of the terminal. Binding markers, builtins and the dynamic placeholder
+    /// have no fixed length and count as 0 bytes.
+    pub fn len(&self) -> usize {
+        match &self.0 {
+            TerminalData::Text(content) => content.len(),
+            TerminalData::Repeat {
+                count,
+                ..
+            } => *count,
+            TerminalData::Binding {
+                ..
+            }
+            | TerminalData::Builtin {
+                ..
+            }
+            | TerminalData::Dynamic => 0,
+        }
+    }
+
+    /// Whether this terminal is Ɛ. Binding markers, builtins and the dynamic placeholder are not.
+    pub fn is_empty(&self) -> bool {
+        match &self.0 {
+            TerminalData::Text(content) => content.is_empty(),
+            TerminalData::Repeat {
+                count,
+                ..
+            } => *count == 0,
+            _ => false,
+        }
+    }
+
+    /// Whether this terminal is a placeholder for the terminals that are discovered during fuzzing.
+    /// The backends replace it with one of them or with nothing.
+    pub fn is_dynamic(&self) -> bool {
+        matches!(&self.0, TerminalData::Dynamic)
+    }
+
+    /// Append the text of `other` to this terminal. Neither of them may be a run.
+    fn push(&mut self, other: &Terminal) {
+        match (&mut self.0, &other.0) {
+            (TerminalData::Text(content), TerminalData::Text(other)) => content.push_str(other),
+            _ => unreachable!(),
+        }
+    }
+}
+
+/// Prefixes the hash of all terminals that are not strings, followed by a discriminant of their kind
+const HASH_TAG: &str = "\0peacock-terminal\0";
+
+/// Strings hash like before runs existed, such that the [`fixed_hash`](ProductionRule::fixed_hash) of rules does not change.
+/// Every other kind of terminal hashes its own discriminant, such that e.g. a run never collides with a marker.
+impl Hash for Terminal {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        match &self.0 {
+            TerminalData::Text(content) => content.hash(state),
+            TerminalData::Repeat {
+                byte,
+                count,
+            } => (HASH_TAG, 0u8, byte, count).hash(state),
+            TerminalData::Binding {
+                op,
+                name,
+            } => (HASH_TAG, 1u8, op, name).hash(state),
+            TerminalData::Builtin {
+                builtin,
+            } => (HASH_TAG, 2u8, builtin).hash(state),
+            TerminalData::Dynamic => (HASH_TAG, 3u8).hash(state),
+        }
     }
 }
 
 /// A Symbol is either a terminal or a non-terminal and can be found on the right-hand-side
 /// of a production rule.
-#[derive(Debug, Clone, Eq, Hash, PartialEq)]
+#[derive(Debug, Clone, Eq, Hash, PartialEq, Serialize, Deserialize)]
 pub enum Symbol {
     /// A terminal is a chunk of data that will be inserted into the output / parsed from the input.
     Terminal(Terminal),
@@ -63,6 +314,36 @@ impl Symbol {
     pub fn is_non_terminal(&self) -> bool {
         matches!(self, Symbol::NonTerminal(_))
     }
+
+    /// Return whether the Symbol is the placeholder for terminals that are discovered during fuzzing
+    #[inline]
+    pub fn is_dynamic(&self) -> bool {
+        matches!(self, Symbol::Terminal(term) if term.is_dynamic())
+    }
+
+    /// Return whether the Symbol is a run of a repeated byte, see [`Terminal::run`]
+    #[inline]
+    pub fn is_run(&self) -> bool {
+        matches!(self, Symbol::Terminal(term) if term.run().is_some())
+    }
+
+    /// Return whether the Symbol is a binding marker, see [`Terminal::binding`]
+    #[inline]
+    pub fn is_binding(&self) -> bool {
+        matches!(self, Symbol::Terminal(term) if term.binding().is_some())
+    }
+
+    /// Return whether the Symbol is a builtin, see [`Terminal::builtin`]
+    #[inline]
+    pub fn is_builtin(&self) -> bool {
+        matches!(self, Symbol::Terminal(term) if term.builtin().is_some())
+    }
+
+    /// Return whether the Symbol is the empty terminal `Ɛ`
+    #[inline]
+    pub fn is_epsilon(&self) -> bool {
+        matches!(self, Symbol::Terminal(term) if term.is_empty())
+    }
 }
 
 /// A ProductionRule states how to expand a non-terminal.  
@@ -80,14 +361,15 @@ impl Symbol {
 /// }
 /// ```
 /// then multiple `ProductionRules` will be generated, one for each variant.
-#[derive(Debug, Clone, Eq, Hash, PartialEq)]
+#[derive(Debug, Clone, Eq, Hash, PartialEq, Serialize, Deserialize)]
 pub struct ProductionRule {
     lhs: NonTerminal,
     rhs: Vec<Symbol>,
 }
 
 impl ProductionRule {
-    pub(crate) fn new(lhs: NonTerminal, rhs: Vec<Symbol>) -> Self {
+    /// Create the rule `lhs -> rhs`. `rhs` must not be empty, Ɛ is an empty [`Terminal`].
+    pub fn new(lhs: NonTerminal, rhs: Vec<Symbol>) -> Self {
         Self {
             lhs,
             rhs,
@@ -104,7 +386,11 @@ impl ProductionRule {
         &self.rhs
     }
 
-    pub(crate) fn fixed_hash(&self) -> u64 {
+    /// Hash the left-hand-side and the right-hand-side of this rule.
+    ///
+    /// The hash uses a fixed seed, so it is the same across runs and processes of the same build of peacock,
+    /// but it changes if the rule gets edited.
+    pub fn fixed_hash(&self) -> u64 {
         RandomState::with_seeds(0, 0, 0, 0).hash_one(self)
     }
 }
@@ -121,6 +407,38 @@ fn is_mixed(rhs: &[Symbol]) -> bool {
     terms & non_terms
 }
 
+/// Derive the name of a synthetic non-terminal from the non-terminal it originates from,
+/// e.g. `EXPR$split1`, while making sure that it doesn't collide with any name in `used`.
+fn fresh_non_terminal(used: &mut HashSet<String>, origin: &NonTerminal, tag: &str) -> NonTerminal {
+    let mut cursor = 1;
+
+    loop {
+        let name = format!("{}${}{}", origin.id(), tag, cursor);
+
+        if !used.contains(&name) {
+            used.insert(name.clone());
+            return NonTerminal(name);
+        }
+
+        cursor += 1;
+    }
+}
+
+/// The tags of [`fresh_non_terminal`] that the optimizations in this file use.
+const SYNTHETIC_TAGS: [&str; 4] = ["term", "split", "rec", "entry"];
+
+/// If `id` is the name of a synthetic non-terminal, return the name it originates from and its tag.
+fn synthetic_origin(id: &str) -> Option<(&str, &str)> {
+    let (origin, suffix) = id.rsplit_once('$')?;
+    let tag = suffix.trim_end_matches(|c: char| c.is_ascii_digit());
+
+    if tag.len() < suffix.len() && SYNTHETIC_TAGS.contains(&tag) {
+        Some((origin, tag))
+    } else {
+        None
+    }
+}
+
 fn is_only_non_terminals(rhs: &[Symbol]) -> bool {
     for symbol in rhs {
         if symbol.is_terminal() {
@@ -131,14 +449,172 @@ fn is_only_non_terminals(rhs: &[Symbol]) -> bool {
     true
 }
 
+/// Limits on the size of a grammar and the time it takes to optimize it.
+pub(crate) struct BuildLimits {
+    max_rules: usize,
+    max_time: Option<Duration>,
+    max_gnf_growth: Option<usize>,
+    start: Instant,
+}
+
+impl BuildLimits {
+    pub(crate) fn new(max_rules: usize, max_time: Option<Duration>, max_gnf_growth: Option<usize>) -> Self {
+        Self {
+            max_rules,
+            max_time,
+            max_gnf_growth,
+            start: Instant::now(),
+        }
+    }
+
+    pub(crate) fn check(&self, rules: usize) -> Result<(), GrammarError> {
+        let elapsed = self.start.elapsed();
+
+        if rules > self.max_rules || self.max_time.is_some_and(|max_time| elapsed > max_time) {
+            return Err(GrammarError::ComplexityLimit {
+                rules,
+                elapsed,
+            });
+        }
+
+        Ok(())
+    }
+}
+
+impl Default for BuildLimits {
+    fn default() -> Self {
+        Self::new(usize::MAX, None, None)
+    }
+}
+
+/// Decides what [`ContextFreeGrammar::merge_with`] does with non-terminals that are defined in both grammars.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MergeMode {
+    /// Fail with [`GrammarError::ConflictingDefinition`] unless both grammars define the non-terminal with the same rules
+    #[default]
+    Strict,
+
+    /// Join the rules of both definitions
+    Permissive,
+}
+
 /// A ContextFreeGrammar is a set of production rules that describe how to construct an input.
 ///
 /// Use the [`builder()`](ContextFreeGrammar::builder) method to actually create this struct.
+///
+/// A grammar can be serialized with serde and read back in without building it again,
+/// see [`to_writer`](ContextFreeGrammar::to_writer). In JSON it looks like this:
+/// ```json
+/// {
+///     "rules": [
+///         {"lhs": "ENTRYPOINT", "rhs": [{"Terminal": "a"}, {"NonTerminal": "ENTRYPOINT"}]},
+///         {"lhs": "ENTRYPOINT", "rhs": [{"Terminal": "a"}]}
+///     ],
+///     "entrypoint": "ENTRYPOINT"
+/// }
+/// ```
+/// The names of the non-terminals that were introduced by the optimizations are kept as they are.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(try_from = "RawGrammar")]
 pub struct ContextFreeGrammar {
+    rules: Rules,
+    entrypoint: NonTerminal,
+}
+
+/// The production rules of a grammar and the positions of the rules of every non-terminal,
+/// which [`ContextFreeGrammar::rules_for`] builds on first use.
+/// Every mutable access to the rules drops the index, so it never refers to outdated rules.
+#[derive(Default, Serialize)]
+#[serde(transparent)]
+struct Rules {
+    rules: Vec<ProductionRule>,
+    #[serde(skip)]
+    index: OnceLock<HashMap<NonTerminal, Vec<usize>>>,
+}
+
+impl Rules {
+    fn new(rules: Vec<ProductionRule>) -> Self {
+        Self {
+            rules,
+            index: OnceLock::new(),
+        }
+    }
+}
+
+impl std::ops::Deref for Rules {
+    type Target = Vec<ProductionRule>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.rules
+    }
+}
+
+impl std::ops::DerefMut for Rules {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.index.take();
+        &mut self.rules
+    }
+}
+
+impl<'a> IntoIterator for &'a Rules {
+    type Item = &'a ProductionRule;
+    type IntoIter = std::slice::Iter<'a, ProductionRule>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.rules.iter()
+    }
+}
+
+impl<'a> IntoIterator for &'a mut Rules {
+    type Item = &'a mut ProductionRule;
+    type IntoIter = std::slice::IterMut<'a, ProductionRule>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter_mut()
+    }
+}
+
+impl std::fmt::Debug for Rules {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.rules.fmt(f)
+    }
+}
+
+/// A deserialized grammar whose invariants have not been checked yet.
+#[derive(Deserialize)]
+struct RawGrammar {
     rules: Vec<ProductionRule>,
     entrypoint: NonTerminal,
 }
 
+impl TryFrom<RawGrammar> for ContextFreeGrammar {
+    type Error = GrammarError;
+
+    fn try_from(raw: RawGrammar) -> Result<Self, Self::Error> {
+        if raw.rules.is_empty() {
+            return Err(GrammarError::EmptyGrammar);
+        }
+
+        let defined: HashSet<&str> = raw.rules.iter().map(|rule| rule.lhs().id()).collect();
+
+        if !defined.contains(raw.entrypoint.id()) {
+            return Err(GrammarError::MissingEntrypoint(raw.entrypoint.id().to_string()));
+        }
+
+        for rule in &raw.rules {
+            for symbol in rule.rhs() {
+                if let Symbol::NonTerminal(nonterm) = symbol {
+                    if !defined.contains(nonterm.id()) {
+                        return Err(GrammarError::MissingNonTerminal(nonterm.id().to_string()));
+                    }
+                }
+            }
+        }
+
+        Ok(ContextFreeGrammar::new(raw.rules, raw.entrypoint))
+    }
+}
+
 impl ContextFreeGrammar {
     /// Build a ContextFreeGrammar.
     pub fn builder() -> GrammarBuilder {
@@ -155,184 +631,892 @@ impl ContextFreeGrammar {
         &self.entrypoint
     }
 
-    pub(crate) fn new(rules: Vec<ProductionRule>, entrypoint: NonTerminal) -> Self {
-        Self {
-            rules,
-            entrypoint,
-        }
+    /// Iterate over the distinct non-terminals that have production rules in this grammar
+    /// in the order in which they were first defined.
+    pub fn nonterminals(&self) -> impl Iterator<Item = &NonTerminal> {
+        let mut seen = HashSet::new();
+        self.rules.iter().map(ProductionRule::lhs).filter(move |nonterm| seen.insert(*nonterm))
     }
-}
 
-impl ContextFreeGrammar {
-    pub(crate) fn concatenate_terminals(&mut self) {
-        for rule in &mut self.rules {
-            let mut i = 0;
-
-            while i + 1 < rule.rhs.len() {
-                if rule.rhs[i].is_terminal() && rule.rhs[i + 1].is_terminal() {
-                    let Symbol::Terminal(second) = rule.rhs.remove(i + 1) else { unreachable!() };
-                    let Symbol::Terminal(first) = &mut rule.rhs[i] else { unreachable!() };
-                    first.0.push_str(second.content());
-                } else {
-                    i += 1;
-                }
-            }
-        }
+    /// Iterate over the distinct terminals on the right-hand-sides of the rules of this grammar
+    /// in the order in which they first appear.
+    pub fn terminals(&self) -> impl Iterator<Item = &Terminal> {
+        let mut seen = HashSet::new();
+        self.rules
+            .iter()
+            .flat_map(ProductionRule::rhs)
+            .filter_map(|symbol| match symbol {
+                Symbol::Terminal(term) => Some(term),
+                Symbol::NonTerminal(_) => None,
+            })
+            .filter(move |term| seen.insert(*term))
     }
 
-    pub(crate) fn remove_duplicate_rules(&mut self) {
-        let mut hashes = HashSet::with_capacity(self.rules.len());
-        let mut i = 0;
-
-        while i < self.rules.len() {
-            let hash = self.rules[i].fixed_hash();
+    /// Iterate over the production rules of `nonterm` in the order in which they were defined.
+    ///
+    /// The rules are looked up in an index that is built on the first call, so this is cheap
+    /// enough to be called for every non-terminal in a derivation.
+    pub fn rules_for(&self, nonterm: &NonTerminal) -> impl Iterator<Item = &ProductionRule> {
+        let index = self.rules.index.get_or_init(|| {
+            let mut index: HashMap<NonTerminal, Vec<usize>> = HashMap::new();
 
-            if !hashes.insert(hash) {
-                self.rules.remove(i);
-            } else {
-                i += 1;
+            for (i, rule) in self.rules.iter().enumerate() {
+                index.entry(rule.lhs().clone()).or_default().push(i);
             }
-        }
+
+            index
+        });
+
+        index.get(nonterm).into_iter().flatten().map(|i| &self.rules[*i])
     }
 
-    pub(crate) fn remove_unused_rules(&mut self) {
-        let mut graph = Graph::<&str, ()>::new();
-        let mut nodes = HashMap::new();
+    /// Group the production rules of this grammar by their left-hand-side.
+    /// The non-terminals appear in the order in which they were first defined.
+    pub fn rules_by_nonterminal(&self) -> IndexMap<&NonTerminal, Vec<&ProductionRule>> {
+        let mut map: IndexMap<&NonTerminal, Vec<&ProductionRule>> = IndexMap::new();
 
-        /* Construct directed graph of non-terminals */
         for rule in &self.rules {
-            let src = rule.lhs().id();
-            let src = *nodes.entry(src).or_insert_with(|| graph.add_node(src));
+            map.entry(rule.lhs()).or_default().push(rule);
+        }
 
-            for symbol in rule.rhs() {
-                if let Symbol::NonTerminal(nonterm) = symbol {
-                    let dst = nonterm.id();
-                    let dst = *nodes.entry(dst).or_insert_with(|| graph.add_node(dst));
+        map
+    }
 
-                    graph.add_edge(src, dst, ());
-                }
-            }
+    /// Extract the part of this grammar that is reachable from the non-terminal `root`,
+    /// which becomes the entrypoint of the new grammar.
+    ///
+    /// Returns an error if `root` has no production rules in this grammar.
+    pub fn subgrammar(&self, root: &str) -> Result<ContextFreeGrammar, GrammarError> {
+        if !self.rules.iter().any(|rule| rule.lhs().id() == root) {
+            return Err(GrammarError::MissingEntrypoint(root.to_string()));
         }
 
-        /* Do a BFS from entrypoint */
-        let entrypoint = *nodes.get(self.entrypoint.id()).unwrap();
-        let mut bfs = Bfs::new(&graph, entrypoint);
+        let reachable = self.reachable_non_terminals(root);
+        let rules = self.rules.iter().filter(|rule| reachable.contains(rule.lhs().id())).cloned().collect();
+        let mut cfg = ContextFreeGrammar::new(rules, NonTerminal::new(root));
 
-        while let Some(idx) = bfs.next(&graph) {
-            let id = graph.node_weight(idx).unwrap();
-            nodes.remove(id);
+        if cfg.count_entrypoint_rules() > 1 {
+            cfg.set_new_entrypoint();
         }
 
-        /* Now `nodes` contains all the non-terminals that are never used */
-        let nodes: HashSet<String> = nodes.into_keys().map(|x| x.to_string()).collect();
-        let mut i = 0;
+        Ok(cfg)
+    }
 
-        while i < self.rules.len() {
-            let lhs = self.rules[i].lhs().id();
+    /// A stable ID of `rule` that external tools can use to refer to a rule, e.g. to key grammar coverage on.
+    ///
+    /// The ID only depends on the rule itself, so it survives reordering of the rules and
+    /// the addition or removal of other rules. It is the [`fixed_hash`](ProductionRule::fixed_hash) of the rule.
+    pub fn rule_id(&self, rule: &ProductionRule) -> u64 {
+        rule.fixed_hash()
+    }
 
-            if nodes.contains(lhs) {
-                self.rules.remove(i);
-            } else {
-                i += 1;
-            }
-        }
+    /// Write this grammar as JSON into `writer`. It can be read back in with [`from_reader`](ContextFreeGrammar::from_reader).
+    pub fn to_writer<W: Write>(&self, writer: W) -> std::io::Result<()> {
+        serde_json::to_writer(writer, self).map_err(std::io::Error::from)
     }
 
-    pub(crate) fn remove_unit_rules(&mut self) {
-        let mut i = 0;
+    /// Read a grammar that was written by [`to_writer`](ContextFreeGrammar::to_writer).
+    ///
+    /// The grammar is not optimized again, but it is checked that the entrypoint and every referenced
+    /// non-terminal have rules. Malformed JSON results in a [`GrammarError::InvalidSerialization`].
+    pub fn from_reader<R: Read>(reader: R) -> Result<Self, GrammarError> {
+        let raw: RawGrammar =
+            serde_json::from_reader(reader).map_err(|err| GrammarError::InvalidSerialization(err.to_string()))?;
+        Self::try_from(raw)
+    }
 
-        while i < self.rules.len() {
-            let rule = &self.rules[i];
+    /// Compute a fingerprint of this grammar that does not depend on the order of its rules.
+    ///
+    /// Two grammars with the same entrypoint and the same set of rules have the same semantic hash.
+    pub fn semantic_hash(&self) -> u64 {
+        let mut hashes: Vec<u64> = self.rules.iter().map(|rule| rule.fixed_hash()).collect();
+        hashes.sort_unstable();
+        hashes.dedup();
 
-            if rule.rhs().len() == 1 && rule.rhs()[0].is_non_terminal() {
-                let old_rule = self.rules.remove(i);
-                let Symbol::NonTerminal(to_expand) = &old_rule.rhs[0] else { unreachable!() };
-                let mut new_rules = Vec::new();
+        RandomState::with_seeds(0, 0, 0, 0).hash_one((self.entrypoint.id(), hashes))
+    }
 
-                for other_rule in &self.rules {
-                    if to_expand.id() == other_rule.lhs().id() {
-                        new_rules.push(ProductionRule::new(old_rule.lhs().clone(), other_rule.rhs.clone()));
-                    }
-                }
+    /// The [`semantic_hash`](ContextFreeGrammar::semantic_hash) as 16 hex digits.
+    /// Code of the [`C`](crate::backends::C) backend returns the digest of its grammar from `peacock_grammar_digest()`.
+    pub fn digest(&self) -> String {
+        format!("{:016x}", self.semantic_hash())
+    }
 
-                self.rules.append(&mut new_rules);
-            } else {
-                i += 1;
-            }
-        }
+    /// Compute a hash of the entrypoint and the rules of this grammar.
+    ///
+    /// In contrast to [`semantic_hash`](ContextFreeGrammar::semantic_hash), this also depends on the order
+    /// of the rules, which determines the code that the backends generate.
+    pub fn content_hash(&self) -> u64 {
+        let hashes: Vec<u64> = self.rules.iter().map(|rule| rule.fixed_hash()).collect();
+        RandomState::with_seeds(0, 0, 0, 0).hash_one((self.entrypoint.id(), hashes))
     }
 
-    pub(crate) fn remove_mixed_rules(&mut self) {
-        let mut terms = HashMap::new();
+    /// Count the distinct derivation trees of the entrypoint, saturating at `cap`.
+    ///
+    /// Recursive grammars have infinitely many derivations and reach the cap.
+    /// A result below 2 means that the grammar always produces the same input.
+    pub fn count_derivations(&self, cap: usize) -> usize {
+        self.derivation_count(0, 1, |a, b| a.saturating_add(b).min(cap), usize::saturating_mul)
+    }
 
-        for rule in &mut self.rules {
-            if is_mixed(rule.rhs()) {
-                for j in 0..rule.rhs().len() {
-                    if let Symbol::Terminal(term) = &rule.rhs()[j] {
-                        let non_term = terms
-                            .entry(term.clone())
-                            .or_insert_with(|| NonTerminal(format!("(term:{})", term.content())))
-                            .clone();
-                        rule.rhs[j] = Symbol::NonTerminal(non_term);
-                    }
-                }
-            }
+    /// Count the strings that a [finite](ContextFreeGrammar::is_finite) grammar can produce.
+    /// Returns `None` if the grammar is not finite or the number does not fit into a `u128`.
+    ///
+    /// Every derivation tree counts as its own string, so this is exact for unambiguous grammars and
+    /// an upper bound for ambiguous grammars, whose duplicates [`enumerate()`](ContextFreeGrammar::enumerate) filters.
+    pub fn count_strings(&self) -> Option<u128> {
+        if !self.is_finite() {
+            return None;
         }
 
-        for (term, nonterm) in terms {
-            self.rules.push(ProductionRule::new(nonterm, vec![Symbol::Terminal(term)]));
-        }
+        self.derivation_count(
+            Some(0),
+            Some(1),
+            |a, b| a.zip(b).and_then(|(a, b)| a.checked_add(b)),
+            |a, b| a.zip(b).and_then(|(a, b)| a.checked_mul(b)),
+        )
     }
 
-    pub(crate) fn break_rules(&mut self) {
-        let mut nonterm_cursor = 0;
-        let mut i = 0;
-
-        while i < self.rules.len() {
-            let rule = &mut self.rules[i];
-
-            if rule.rhs().len() > 2 && is_only_non_terminals(rule.rhs()) {
-                let len = rule.rhs().len() - 1;
-                let symbols: Vec<Symbol> = rule.rhs.drain(0..len).collect();
+    /// The number of derivation trees of the entrypoint with the arithmetic of `add` and `mul`.
+    /// Undefined non-terminals have no derivations. Only non-terminals that are reachable from the entrypoint
+    /// are counted, so the recursion of unreachable ones cannot keep the fixpoint from being reached.
+    fn derivation_count<T, A, M>(&self, zero: T, one: T, add: A, mul: M) -> T
+    where
+        T: Copy + PartialEq,
+        A: Fn(T, T) -> T,
+        M: Fn(T, T) -> T,
+    {
+        let reachable = self.reachable_non_terminals(self.entrypoint.id());
+        let mut rules = self.rules_by_nonterminal();
+        rules.retain(|nonterm, _| reachable.contains(nonterm.id()));
+        let mut counts: HashMap<&str, T> = rules.keys().map(|nonterm| (nonterm.id(), zero)).collect();
+        let mut changed = true;
+
+        /* The counts only grow until they saturate, so this reaches a fixpoint */
+        while changed {
+            changed = false;
+
+            for (nonterm, rules) in &rules {
+                let mut sum = zero;
+
+                for rule in rules {
+                    let mut product = one;
+
+                    for symbol in rule.rhs() {
+                        if let Symbol::NonTerminal(child) = symbol {
+                            product = mul(product, counts.get(child.id()).copied().unwrap_or(zero));
+                        }
+                    }
 
-                let nonterm = NonTerminal(format!("(break_rules:{})", nonterm_cursor));
-                nonterm_cursor += 1;
+                    sum = add(sum, product);
+                }
 
-                rule.rhs.insert(0, Symbol::NonTerminal(nonterm.clone()));
+                let count = counts.get_mut(nonterm.id()).unwrap();
 
-                self.rules.push(ProductionRule::new(nonterm, symbols));
+                if *count != sum {
+                    *count = sum;
+                    changed = true;
+                }
             }
-
-            i += 1;
         }
+
+        counts.get(self.entrypoint.id()).copied().unwrap_or(zero)
     }
 
-    pub(crate) fn convert_to_gnf(&mut self) {
-        let mut i = 0;
+    /// Whether the language of this grammar is finite, i.e. whether no non-terminal that is reachable from the
+    /// entrypoint is recursive. Grammars that reach a [dynamic terminal](Terminal::is_dynamic) are never finite
+    /// because their strings depend on the terminals that are discovered at runtime.
+    pub fn is_finite(&self) -> bool {
+        let reachable = self.reachable_non_terminals(self.entrypoint.id());
+        let mut graph = Graph::<&str, ()>::new();
+        let mut nodes = HashMap::new();
 
-        while i < self.rules.len() {
-            if self.rules[i].rhs()[0].is_non_terminal() {
-                let mut new_rules = Vec::new();
-                let mut old_rule = self.rules.remove(i);
-                let Symbol::NonTerminal(nonterm) = old_rule.rhs.remove(0) else { unreachable!() };
+        for rule in &self.rules {
+            if !reachable.contains(rule.lhs().id()) {
+                continue;
+            }
 
-                for other_rule in &self.rules {
-                    if other_rule.lhs().id() == nonterm.id() {
-                        let mut new_symbols = other_rule.rhs.clone();
-                        new_symbols.extend_from_slice(old_rule.rhs());
-                        new_rules.push(ProductionRule::new(old_rule.lhs().clone(), new_symbols));
+            let src = rule.lhs().id();
+            let src = *nodes.entry(src).or_insert_with(|| graph.add_node(src));
+
+            for symbol in rule.rhs() {
+                match symbol {
+                    Symbol::Terminal(term) if term.is_dynamic() => return false,
+                    Symbol::Terminal(_) => {},
+                    Symbol::NonTerminal(nonterm) => {
+                        let dst = nonterm.id();
+                        let dst = *nodes.entry(dst).or_insert_with(|| graph.add_node(dst));
+                        graph.add_edge(src, dst, ());
+                    },
+                }
+            }
+        }
+
+        tarjan_scc(&graph)
+            .into_iter()
+            .all(|component| component.len() == 1 && !graph.contains_edge(component[0], component[0]))
+    }
+
+    /// Lazily enumerate every distinct string of a [finite](ContextFreeGrammar::is_finite) grammar,
+    /// shallow derivations first. This is [`GrammarInterpreter::enumerate`] with a depth that covers all derivations,
+    /// use [`dedup_cap(0)`](Enumeration::dedup_cap) to get one string per derivation tree like in
+    /// [`count_strings()`](ContextFreeGrammar::count_strings).
+    ///
+    /// Panics if the grammar is not finite.
+    pub fn enumerate(&self) -> Enumeration {
+        assert!(self.is_finite(), "Only finite grammars can be enumerated");
+
+        /* No path from the entrypoint visits a non-terminal twice */
+        let max_depth = self.reachable_non_terminals(self.entrypoint.id()).len();
+        GrammarInterpreter::new(self).enumerate(max_depth)
+    }
+
+    /// Append the rules of `other` to this grammar. The entrypoint of this grammar stays the entrypoint
+    /// of the merged grammar.
+    ///
+    /// Returns an error if a non-terminal is defined in both grammars with different rules.
+    /// Use [`merge_with`](ContextFreeGrammar::merge_with) to join such definitions instead.
+    pub fn merge(&mut self, other: ContextFreeGrammar) -> Result<(), GrammarError> {
+        self.merge_with(other, MergeMode::Strict)
+    }
+
+    /// Append the rules of `other` to this grammar and handle non-terminals that are defined in
+    /// both grammars according to `mode`. Rules that both grammars have are only kept once.
+    ///
+    /// Synthetic non-terminals that the optimizations of both grammars created independently, like `EXPR$split1`,
+    /// get renamed in `other` if they mean something else in this grammar.
+    pub fn merge_with(&mut self, mut other: ContextFreeGrammar, mode: MergeMode) -> Result<(), GrammarError> {
+        self.rename_colliding_synthetics(&mut other);
+
+        if mode == MergeMode::Strict {
+            let ours = self.rules_by_nonterminal();
+            let theirs = other.rules_by_nonterminal();
+
+            for (nonterm, their_rules) in &theirs {
+                if let Some(our_rules) = ours.get(nonterm) {
+                    let a: HashSet<&ProductionRule> = our_rules.iter().copied().collect();
+                    let b: HashSet<&ProductionRule> = their_rules.iter().copied().collect();
+
+                    if a != b {
+                        return Err(GrammarError::ConflictingDefinition(nonterm.id().to_string()));
                     }
                 }
+            }
+        }
 
-                self.rules.append(&mut new_rules);
-            } else {
-                i += 1;
+        let known: HashSet<ProductionRule> = self.rules.iter().cloned().collect();
+
+        for rule in other.rules.rules {
+            if !known.contains(&rule) {
+                self.rules.push(rule);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Give every synthetic non-terminal of `other` that has different rules in this grammar
+    /// a name that neither grammar uses.
+    fn rename_colliding_synthetics(&self, other: &mut ContextFreeGrammar) {
+        let mut used = self.non_terminal_ids();
+        used.extend(other.non_terminal_ids());
+
+        let ours = self.rules_by_nonterminal();
+        let mut renames = Vec::new();
+
+        for (nonterm, their_rules) in other.rules_by_nonterminal() {
+            let Some((origin, tag)) = synthetic_origin(nonterm.id()) else {
+                continue;
+            };
+            let Some(our_rules) = ours.get(nonterm) else {
+                continue;
+            };
+            let a: HashSet<&ProductionRule> = our_rules.iter().copied().collect();
+            let b: HashSet<&ProductionRule> = their_rules.into_iter().collect();
+
+            if a != b {
+                let fresh = fresh_non_terminal(&mut used, &NonTerminal::new(origin), tag);
+                renames.push((nonterm.id().to_string(), fresh));
+            }
+        }
+
+        for (from, to) in renames {
+            edit::rename_nonterminal(&mut other.rules, &from, to.id());
+        }
+    }
+
+    pub(crate) fn new(rules: Vec<ProductionRule>, entrypoint: NonTerminal) -> Self {
+        Self {
+            rules: Rules::new(rules),
+            entrypoint,
+        }
+    }
+}
+
+impl ContextFreeGrammar {
+    fn non_terminal_ids(&self) -> HashSet<String> {
+        let mut ids = HashSet::new();
+
+        for rule in &self.rules {
+            ids.insert(rule.lhs().id().to_string());
+
+            for symbol in rule.rhs() {
+                if let Symbol::NonTerminal(nonterm) = symbol {
+                    ids.insert(nonterm.id().to_string());
+                }
+            }
+        }
+
+        ids.insert(self.entrypoint.id().to_string());
+        ids
+    }
+
+    pub(crate) fn concatenate_terminals(&mut self) {
+        for rule in &mut self.rules {
+            let mut i = 0;
+
+            while i + 1 < rule.rhs.len() {
+                if rule.rhs[i].is_terminal()
+                    && rule.rhs[i + 1].is_terminal()
+                    && !rule.rhs[i].is_dynamic()
+                    && !rule.rhs[i + 1].is_dynamic()
+                    && !rule.rhs[i].is_run()
+                    && !rule.rhs[i + 1].is_run()
+                    && !rule.rhs[i].is_binding()
+                    && !rule.rhs[i + 1].is_binding()
+                    && !rule.rhs[i].is_builtin()
+                    && !rule.rhs[i + 1].is_builtin()
+                {
+                    let Symbol::Terminal(second) = rule.rhs.remove(i + 1) else { unreachable!() };
+                    let Symbol::Terminal(first) = &mut rule.rhs[i] else { unreachable!() };
+                    first.push(&second);
+                } else {
+                    i += 1;
+                }
+            }
+        }
+    }
+
+    /// Eliminate all epsilon rules except for an epsilon rule of the entrypoint.
+    /// For every nullable non-terminal, rules get additional variants that leave it out.
+    pub(crate) fn remove_epsilon_rules(&mut self, limits: &BuildLimits) -> Result<(), GrammarError> {
+        /* Empty terminals next to other symbols have no effect */
+        for rule in &mut self.rules {
+            if rule.rhs.len() > 1 {
+                rule.rhs.retain(|symbol| !symbol.is_epsilon());
+
+                if rule.rhs.is_empty() {
+                    rule.rhs.push(Symbol::Terminal(Terminal::new("")));
+                }
+            }
+        }
+
+        /* Find all non-terminals that can derive Ɛ */
+        let mut nullable = HashSet::new();
+        let mut changed = true;
+
+        while changed {
+            changed = false;
+
+            for rule in &self.rules {
+                if !nullable.contains(rule.lhs().id())
+                    && rule.rhs().iter().all(|symbol| match symbol {
+                        Symbol::Terminal(term) => term.is_empty(),
+                        Symbol::NonTerminal(nonterm) => nullable.contains(nonterm.id()),
+                    })
+                {
+                    nullable.insert(rule.lhs().id().to_string());
+                    changed = true;
+                }
+            }
+        }
+
+        if nullable.is_empty() {
+            return Ok(());
+        }
+
+        /* Add a variant for every combination of nullable non-terminals that are left out */
+        let mut new_rules = Vec::with_capacity(self.rules.len());
+
+        if nullable.contains(self.entrypoint.id()) {
+            new_rules.push(ProductionRule::new(self.entrypoint.clone(), vec![Symbol::Terminal(Terminal::new(""))]));
+        }
+
+        for rule in std::mem::take(&mut *self.rules) {
+            if rule.rhs().len() == 1 && rule.rhs()[0].is_epsilon() {
+                continue;
+            }
+
+            let mut variants = vec![Vec::with_capacity(rule.rhs().len())];
+
+            for symbol in rule.rhs() {
+                let optional = matches!(symbol, Symbol::NonTerminal(nonterm) if nullable.contains(nonterm.id()));
+                let len = variants.len();
+
+                for i in 0..len {
+                    if optional {
+                        variants.push(variants[i].clone());
+                    }
+
+                    variants[i].push(symbol.clone());
+                }
+
+                limits.check(new_rules.len() + variants.len())?;
+            }
+
+            for rhs in variants {
+                /* Variants that became empty or A -> A are redundant */
+                if rhs.is_empty() || matches!(&rhs[..], [Symbol::NonTerminal(nonterm)] if nonterm == rule.lhs()) {
+                    continue;
+                }
+
+                new_rules.push(ProductionRule::new(rule.lhs().clone(), rhs));
+            }
+        }
+
+        *self.rules = new_rules;
+
+        /* Non-terminals that only derived Ɛ are undefined now */
+        loop {
+            let defined: HashSet<&str> = self.rules.iter().map(|rule| rule.lhs().id()).collect();
+            let undefined = |rule: &ProductionRule| {
+                rule.rhs()
+                    .iter()
+                    .any(|symbol| matches!(symbol, Symbol::NonTerminal(nonterm) if !defined.contains(nonterm.id())))
+            };
+
+            if !self.rules.iter().any(undefined) {
+                break;
+            }
+
+            let rules = self.rules.iter().filter(|rule| !undefined(rule)).cloned().collect();
+            *self.rules = rules;
+        }
+
+        Ok(())
+    }
+
+    pub(crate) fn remove_duplicate_rules(&mut self) {
+        let mut hashes = HashSet::with_capacity(self.rules.len());
+        self.rules.retain(|rule| hashes.insert(rule.fixed_hash()));
+    }
+
+    /// Collect the ids of all non-terminals that are reachable from `root`, including `root` itself.
+    pub(crate) fn reachable_non_terminals(&self, root: &str) -> HashSet<String> {
+        let mut graph = Graph::<&str, ()>::new();
+        let mut nodes = HashMap::new();
+
+        /* Construct directed graph of non-terminals */
+        for rule in &self.rules {
+            let src = rule.lhs().id();
+            let src = *nodes.entry(src).or_insert_with(|| graph.add_node(src));
+
+            for symbol in rule.rhs() {
+                if let Symbol::NonTerminal(nonterm) = symbol {
+                    let dst = nonterm.id();
+                    let dst = *nodes.entry(dst).or_insert_with(|| graph.add_node(dst));
+
+                    graph.add_edge(src, dst, ());
+                }
+            }
+        }
+
+        /* Do a BFS from root */
+        let root = *nodes.get(root).unwrap();
+        let mut bfs = Bfs::new(&graph, root);
+        let mut reachable = HashSet::new();
+
+        while let Some(idx) = bfs.next(&graph) {
+            let id = graph.node_weight(idx).unwrap();
+            reachable.insert(id.to_string());
+        }
+
+        reachable
+    }
+
+    pub(crate) fn remove_unused_rules(&mut self) {
+        let reachable = self.reachable_non_terminals(self.entrypoint.id());
+        self.rules.retain(|rule| reachable.contains(rule.lhs().id()));
+    }
+
+    /// Replace every rule `A -> B γ` for which `expand` returns true with the rules `A -> β γ` for every rule `B -> β`.
+    /// The new rules are checked again. The result is the same as removing such a rule and appending its
+    /// replacements to the list of rules one at a time, but without moving the rules around on every removal.
+    ///
+    /// A replacement that a non-terminal already had, even if it was expanded in the meantime, is not added again.
+    /// Its expansion would not produce anything new, and this breaks cycles like `A -> B`, `B -> A` that would
+    /// otherwise be expanded forever.
+    ///
+    /// Fails with [`GrammarError::GnfExplosion`] if the number of rules exceeds `max_growth` times the original number.
+    fn expand_leading_non_terminals<F>(
+        &mut self,
+        limits: &BuildLimits,
+        max_growth: Option<usize>,
+        expand: F,
+    ) -> Result<(), GrammarError>
+    where
+        F: Fn(&ProductionRule) -> bool,
+    {
+        let mut rules = std::mem::take(&mut *self.rules);
+        let mut removed = vec![false; rules.len()];
+        let mut live = rules.len();
+        let original = rules.len();
+        let max_rules = max_growth.map_or(usize::MAX, |factor| factor.saturating_mul(original));
+        let mut expanded = 0;
+        let mut peak = live;
+        let mut index: HashMap<String, Vec<usize>> = HashMap::new();
+        let mut seen: HashSet<ProductionRule> = rules.iter().cloned().collect();
+
+        for (i, rule) in rules.iter().enumerate() {
+            index.entry(rule.lhs().id().to_string()).or_default().push(i);
+        }
+
+        for i in 0.. {
+            if i >= rules.len() {
+                break;
+            }
+
+            limits.check(live)?;
+
+            if live > max_rules {
+                return Err(GrammarError::GnfExplosion {
+                    original,
+                    rules: live,
+                });
+            }
+
+            if !expand(&rules[i]) {
+                continue;
+            }
+
+            removed[i] = true;
+            live -= 1;
+            expanded += 1;
+
+            let rule = &rules[i];
+            let Symbol::NonTerminal(to_expand) = &rule.rhs[0] else { unreachable!() };
+            let new_rules: Vec<ProductionRule> = index
+                .get(to_expand.id())
+                .into_iter()
+                .flatten()
+                .filter(|j| !removed[**j])
+                .map(|j| {
+                    let mut symbols = rules[*j].rhs.clone();
+                    symbols.extend_from_slice(&rule.rhs[1..]);
+                    ProductionRule::new(rule.lhs.clone(), symbols)
+                })
+                .collect();
+
+            let slots = index.entry(rule.lhs().id().to_string()).or_default();
+
+            for new_rule in new_rules {
+                if !seen.insert(new_rule.clone()) {
+                    continue;
+                }
+
+                slots.push(rules.len());
+                rules.push(new_rule);
+                removed.push(false);
+                live += 1;
+            }
+
+            peak = peak.max(live);
+        }
+
+        log::debug!("expanded {} leading non-terminals, peaked at {} rules", expanded, peak);
+        *self.rules = rules.into_iter().zip(removed).filter_map(|(rule, removed)| (!removed).then_some(rule)).collect();
+        Ok(())
+    }
+
+    pub(crate) fn remove_unit_rules(&mut self, limits: &BuildLimits) -> Result<(), GrammarError> {
+        self.expand_leading_non_terminals(limits, None, |rule| rule.rhs().len() == 1 && rule.rhs()[0].is_non_terminal())
+    }
+
+    pub(crate) fn remove_mixed_rules(&mut self) {
+        let mut used = self.non_terminal_ids();
+        /* Not a HashMap, the new rules must be appended in the same order on every build of a grammar */
+        let mut terms = IndexMap::new();
+
+        for rule in &mut self.rules {
+            if is_mixed(rule.rhs()) {
+                for j in 0..rule.rhs().len() {
+                    if let Symbol::Terminal(term) = &rule.rhs()[j] {
+                        let non_term = terms
+                            .entry(term.clone())
+                            .or_insert_with(|| fresh_non_terminal(&mut used, &rule.lhs, "term"))
+                            .clone();
+                        rule.rhs[j] = Symbol::NonTerminal(non_term);
+                    }
+                }
+            }
+        }
+
+        for (term, nonterm) in terms {
+            self.rules.push(ProductionRule::new(nonterm, vec![Symbol::Terminal(term)]));
+        }
+    }
+
+    pub(crate) fn break_rules(&mut self, limits: &BuildLimits) -> Result<(), GrammarError> {
+        let mut used = self.non_terminal_ids();
+        let mut i = 0;
+
+        while i < self.rules.len() {
+            limits.check(self.rules.len())?;
+            let rule = &mut self.rules[i];
+
+            if rule.rhs().len() > 2 && is_only_non_terminals(rule.rhs()) {
+                let len = rule.rhs().len() - 1;
+                let symbols: Vec<Symbol> = rule.rhs.drain(0..len).collect();
+
+                let nonterm = fresh_non_terminal(&mut used, &rule.lhs, "split");
+
+                rule.rhs.insert(0, Symbol::NonTerminal(nonterm.clone()));
+
+                self.rules.push(ProductionRule::new(nonterm, symbols));
+            }
+
+            i += 1;
+        }
+
+        Ok(())
+    }
+
+    /// Eliminate direct and indirect left recursion with Paull's algorithm.
+    /// Only the non-terminals that are part of a left-recursive cycle get rewritten.
+    pub(crate) fn remove_left_recursion(&mut self, limits: &BuildLimits) -> Result<(), GrammarError> {
+        let mut graph = Graph::<&str, ()>::new();
+        let mut nodes = HashMap::new();
+
+        /* Construct graph of left corners */
+        for rule in &self.rules {
+            let src = rule.lhs().id();
+            let src = *nodes.entry(src).or_insert_with(|| graph.add_node(src));
+
+            if let Symbol::NonTerminal(nonterm) = &rule.rhs()[0] {
+                let dst = nonterm.id();
+                let dst = *nodes.entry(dst).or_insert_with(|| graph.add_node(dst));
+                graph.add_edge(src, dst, ());
+            }
+        }
+
+        /* Every strongly connected component with a cycle needs to be rewritten */
+        let mut components: Vec<HashSet<String>> = Vec::new();
+
+        for component in tarjan_scc(&graph) {
+            if component.len() > 1 || graph.contains_edge(component[0], component[0]) {
+                components.push(component.into_iter().map(|idx| graph[idx].to_string()).collect());
+            }
+        }
+
+        if components.is_empty() {
+            return Ok(());
+        }
+
+        let mut used = self.non_terminal_ids();
+
+        for component in components {
+            /* Process the non-terminals in the order in which they are defined */
+            let mut order: Vec<NonTerminal> = Vec::new();
+            let mut productions: HashMap<String, Vec<Vec<Symbol>>> = HashMap::new();
+            let (members, rules): (Vec<_>, Vec<_>) =
+                std::mem::take(&mut *self.rules).into_iter().partition(|rule| component.contains(rule.lhs().id()));
+            *self.rules = rules;
+
+            for rule in members {
+                if !productions.contains_key(rule.lhs().id()) {
+                    order.push(rule.lhs.clone());
+                }
+
+                productions.entry(rule.lhs.0).or_default().push(rule.rhs);
+            }
+
+            for i in 0..order.len() {
+                /* Substitute A_i -> A_j γ for all j < i */
+                for j in 0..i {
+                    limits.check(self.rules.len() + productions.values().map(Vec::len).sum::<usize>())?;
+                    let prefixes = productions.get(order[j].id()).unwrap().clone();
+                    let old = productions.remove(order[i].id()).unwrap();
+                    let mut new = Vec::with_capacity(old.len());
+
+                    for rhs in old {
+                        if matches!(&rhs[0], Symbol::NonTerminal(nonterm) if nonterm == &order[j]) {
+                            for prefix in &prefixes {
+                                let mut symbols = prefix.clone();
+                                symbols.extend_from_slice(&rhs[1..]);
+                                new.push(symbols);
+                            }
+                        } else {
+                            new.push(rhs);
+                        }
+                    }
+
+                    productions.insert(order[i].id().to_string(), new);
+                }
+
+                /* Remove direct left recursion of A_i */
+                let (recursive, base): (Vec<_>, Vec<_>) = productions
+                    .remove(order[i].id())
+                    .unwrap()
+                    .into_iter()
+                    .partition(|rhs| matches!(&rhs[0], Symbol::NonTerminal(nonterm) if nonterm == &order[i]));
+
+                if recursive.is_empty() {
+                    productions.insert(order[i].id().to_string(), base);
+                    continue;
+                }
+
+                let tail = fresh_non_terminal(&mut used, &order[i], "rec");
+                let mut new = Vec::with_capacity(base.len() * 2);
+                let mut tails = Vec::with_capacity(recursive.len() * 2);
+
+                for rhs in base {
+                    let mut symbols = rhs.clone();
+                    symbols.push(Symbol::NonTerminal(tail.clone()));
+                    new.push(rhs);
+                    new.push(symbols);
+                }
+
+                /* A_i -> A_i is dropped here */
+                for mut rhs in recursive {
+                    rhs.remove(0);
+
+                    if !rhs.is_empty() {
+                        let mut symbols = rhs.clone();
+                        symbols.push(Symbol::NonTerminal(tail.clone()));
+                        tails.push(rhs);
+                        tails.push(symbols);
+                    }
+                }
+
+                productions.insert(order[i].id().to_string(), new);
+                productions.insert(tail.id().to_string(), tails);
+                order.push(tail);
+            }
+
+            for nonterm in order {
+                for rhs in productions.remove(nonterm.id()).unwrap() {
+                    self.rules.push(ProductionRule::new(nonterm.clone(), rhs));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    pub(crate) fn convert_to_gnf(&mut self, limits: &BuildLimits) -> Result<(), GrammarError> {
+        self.expand_leading_non_terminals(limits, limits.max_gnf_growth, |rule| rule.rhs()[0].is_non_terminal())
+    }
+
+    pub(crate) fn inline_single_use(&mut self) {
+        let mut productions = HashMap::<&str, usize>::new();
+        let mut occurrences = HashMap::<&str, usize>::new();
+
+        for rule in &self.rules {
+            *productions.entry(rule.lhs().id()).or_insert(0) += 1;
+
+            for symbol in rule.rhs() {
+                if let Symbol::NonTerminal(nonterm) = symbol {
+                    *occurrences.entry(nonterm.id()).or_insert(0) += 1;
+                }
+            }
+        }
+
+        /* Candidates have a single production, are referenced exactly once and don't reference themselves */
+        let mut candidates: HashSet<String> = self
+            .rules
+            .iter()
+            .filter(|rule| {
+                let id = rule.lhs().id();
+                id != self.entrypoint.id()
+                    && productions.get(id) == Some(&1)
+                    && occurrences.get(id) == Some(&1)
+                    && !rule.rhs().iter().any(|s| matches!(s, Symbol::NonTerminal(n) if n.id() == id))
+            })
+            .map(|rule| rule.lhs().id().to_string())
+            .collect();
+
+        /* Candidates that reference each other in a cycle would be inlined into dangling references */
+        let mut graph = Graph::<&str, ()>::new();
+        let mut nodes = HashMap::new();
+
+        for rule in &self.rules {
+            let src = rule.lhs().id();
+
+            if !candidates.contains(src) {
+                continue;
+            }
+
+            let src = *nodes.entry(src).or_insert_with(|| graph.add_node(src));
+
+            for symbol in rule.rhs() {
+                if let Symbol::NonTerminal(nonterm) = symbol {
+                    if candidates.contains(nonterm.id()) {
+                        let dst = nonterm.id();
+                        let dst = *nodes.entry(dst).or_insert_with(|| graph.add_node(dst));
+                        graph.add_edge(src, dst, ());
+                    }
+                }
+            }
+        }
+
+        let cyclic: Vec<String> = tarjan_scc(&graph)
+            .into_iter()
+            .filter(|component| component.len() > 1)
+            .flatten()
+            .map(|idx| graph[idx].to_string())
+            .collect();
+
+        for id in &cyclic {
+            candidates.remove(id);
+        }
+
+        if candidates.is_empty() {
+            return;
+        }
+
+        let (inlined, rules): (Vec<_>, Vec<_>) =
+            std::mem::take(&mut *self.rules).into_iter().partition(|rule| candidates.contains(rule.lhs().id()));
+        let inlined: HashMap<String, Vec<Symbol>> = inlined.into_iter().map(|rule| (rule.lhs.0, rule.rhs)).collect();
+        *self.rules = rules;
+
+        fn expand(
+            symbols: Vec<Symbol>,
+            inlined: &HashMap<String, Vec<Symbol>>,
+            visiting: &mut HashSet<String>,
+        ) -> Vec<Symbol> {
+            let mut ret = Vec::with_capacity(symbols.len());
+
+            for symbol in symbols {
+                match symbol {
+                    Symbol::NonTerminal(nonterm)
+                        if inlined.contains_key(nonterm.id()) && visiting.insert(nonterm.0.clone()) =>
+                    {
+                        let rhs = inlined.get(nonterm.id()).unwrap().clone();
+                        ret.append(&mut expand(rhs, inlined, visiting));
+                        visiting.remove(nonterm.id());
+                    },
+                    symbol => ret.push(symbol),
+                }
             }
+
+            ret
         }
+
+        let mut visiting = HashSet::new();
+
+        for rule in &mut self.rules {
+            if rule.rhs().iter().any(|s| matches!(s, Symbol::NonTerminal(n) if inlined.contains_key(n.id()))) {
+                let rhs = std::mem::take(&mut rule.rhs);
+                rule.rhs = expand(rhs, &inlined, &mut visiting);
+            }
+        }
+
+        self.concatenate_terminals();
     }
 
     pub(crate) fn set_new_entrypoint(&mut self) {
-        let nonterm = NonTerminal("(real_entrypoint)".to_string());
+        let mut used = self.non_terminal_ids();
+        let nonterm = fresh_non_terminal(&mut used, &self.entrypoint, "entry");
 
         self.rules.push(ProductionRule::new(nonterm.clone(), vec![Symbol::NonTerminal(self.entrypoint.clone())]));
 
@@ -376,6 +1560,54 @@ impl ContextFreeGrammar {
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_merge() {
+        let rule = |lhs: &str, rhs: &str| {
+            ProductionRule::new(NonTerminal::new(lhs), vec![Symbol::Terminal(Terminal::new(rhs))])
+        };
+        let mut a = ContextFreeGrammar::new(vec![rule("A", "a"), rule("B", "b")], NonTerminal::new("A"));
+        let b = || ContextFreeGrammar::new(vec![rule("B", "b"), rule("C", "c")], NonTerminal::new("C"));
+        let c = || ContextFreeGrammar::new(vec![rule("B", "x")], NonTerminal::new("B"));
+
+        a.merge(b()).unwrap();
+        assert_eq!(a.rules(), &[rule("A", "a"), rule("B", "b"), rule("C", "c")]);
+        assert_eq!(a.entrypoint().id(), "A");
+
+        assert!(matches!(a.merge(c()), Err(GrammarError::ConflictingDefinition(nonterm)) if nonterm == "B"));
+        assert_eq!(a.rules().len(), 3);
+        assert_eq!(a.rules_for(&NonTerminal::new("B")).count(), 1);
+
+        a.merge_with(c(), MergeMode::Permissive).unwrap();
+        assert_eq!(a.rules(), &[rule("A", "a"), rule("B", "b"), rule("C", "c"), rule("B", "x")]);
+        assert!(a.rules_for(&NonTerminal::new("B")).eq([&rule("B", "b"), &rule("B", "x")]));
+    }
+
+    #[test]
+    fn test_merge_synthetic_names() {
+        use crate::grammar::tests::rule;
+
+        let mut a = ContextFreeGrammar::new(
+            vec![rule("A", &["<X$split1>", "<X$split2>"]), rule("X$split1", &["a"]), rule("X$split2", &["b"])],
+            NonTerminal::new("A"),
+        );
+        let b = ContextFreeGrammar::new(
+            vec![rule("B", &["<X$split1>", "<X$split2>"]), rule("X$split1", &["c"]), rule("X$split2", &["b"])],
+            NonTerminal::new("B"),
+        );
+
+        a.merge(b).unwrap();
+        assert_eq!(
+            a.rules(),
+            &[
+                rule("A", &["<X$split1>", "<X$split2>"]),
+                rule("X$split1", &["a"]),
+                rule("X$split2", &["b"]),
+                rule("B", &["<X$split3>", "<X$split2>"]),
+                rule("X$split3", &["c"]),
+            ]
+        );
+    }
+
     #[test]
     fn test_unused_rules() {
         let cfg = ContextFreeGrammar::builder()
@@ -409,6 +1641,34 @@ mod tests {
         println!("{:#?}", cfg.rules());
     }
 
+    #[test]
+    fn test_unit_cycles() {
+        let limits = BuildLimits::default();
+        let mut cfg = ContextFreeGrammar::builder()
+            .peacock_grammar("test-data/grammars/unit_cycle.json")
+            .unwrap()
+            .optimize(false)
+            .build()
+            .unwrap();
+        cfg.remove_unit_rules(&limits).unwrap();
+
+        let rules: HashSet<&ProductionRule> = cfg.rules().iter().collect();
+        assert_eq!(rules.len(), cfg.rules().len());
+        assert!(cfg.rules().iter().all(|rule| !(rule.rhs().len() == 1 && rule.rhs()[0].is_non_terminal())));
+
+        /* Every non-terminal gets the rules of all non-terminals in its unit cycles */
+        for (nonterm, rules) in cfg.rules_by_nonterminal() {
+            let expected = match nonterm.id() {
+                "A" | "B" => 1,
+                "ENTRYPOINT" | "C" | "D" | "E" | "F" => 5,
+                _ => continue,
+            };
+            assert_eq!(rules.len(), expected, "{}", nonterm.id());
+        }
+
+        ContextFreeGrammar::builder().peacock_grammar("test-data/grammars/unit_cycle.json").unwrap().build().unwrap();
+    }
+
     #[test]
     #[should_panic]
     fn test_recursion() {
@@ -421,6 +1681,594 @@ mod tests {
         println!("{:#?}", cfg.rules());
     }
 
+    #[test]
+    fn test_enumerate() {
+        let grammar = r#"{
+            "<ENTRYPOINT>": [["<A>", "'-'", "<B>"], ["'x'"]],
+            "<A>": [["'a'"], ["'b'"]],
+            "<B>": [["'1'"], ["'2'"], ["<C>"]],
+            "<C>": [["''"]]
+        }"#;
+
+        for optimize in [false, true] {
+            let cfg =
+                ContextFreeGrammar::builder().peacock_grammar_str(grammar).unwrap().optimize(optimize).build().unwrap();
+            assert!(cfg.is_finite());
+            assert_eq!(cfg.count_strings(), Some(7));
+
+            let mut strings: Vec<String> = cfg.enumerate().map(|string| String::from_utf8(string).unwrap()).collect();
+            strings.sort();
+            assert_eq!(strings, vec!["a-", "a-1", "a-2", "b-", "b-1", "b-2", "x"]);
+        }
+
+        /* Ambiguous grammars count every derivation */
+        let cfg = ContextFreeGrammar::builder()
+            .peacock_grammar_str(r#"{"<ENTRYPOINT>": [["<A>"], ["<B>"]], "<A>": [["'x'"]], "<B>": [["'x'"]]}"#)
+            .unwrap()
+            .optimize(false)
+            .build()
+            .unwrap();
+        assert_eq!(cfg.count_strings(), Some(2));
+        assert_eq!(cfg.enumerate().count(), 1);
+        assert_eq!(cfg.enumerate().dedup_cap(0).count(), 2);
+
+        let cfg = ContextFreeGrammar::builder()
+            .peacock_grammar_str(r#"{"<ENTRYPOINT>": [["'a'", "<ENTRYPOINT>"], ["'a'"]]}"#)
+            .unwrap()
+            .build()
+            .unwrap();
+        assert!(!cfg.is_finite());
+        assert_eq!(cfg.count_strings(), None);
+
+        /* Recursion that is not reachable from the entrypoint does not matter */
+        let cfg = ContextFreeGrammar::builder()
+            .peacock_grammar_str(r#"{"<ENTRYPOINT>": [["'a'"], ["'b'"]], "<B>": [["'b'", "<B>"], ["'b'"]]}"#)
+            .unwrap()
+            .optimize(false)
+            .build()
+            .unwrap();
+        assert_eq!(cfg.count_strings(), Some(2));
+
+        /* 2^127 strings fit into a u128, 2^128 don't */
+        for (bits, count) in [(127, Some(1u128 << 127)), (128, None)] {
+            let entrypoint: Vec<String> = (0..bits).map(|i| format!("\"<BIT{}>\"", i)).collect();
+            let mut grammar = format!(r#"{{"<ENTRYPOINT>": [[{}]]"#, entrypoint.join(", "));
+
+            for i in 0..bits {
+                grammar.push_str(&format!(r#", "<BIT{}>": [["'0'"], ["'1'"]]"#, i));
+            }
+
+            grammar.push('}');
+            let cfg =
+                ContextFreeGrammar::builder().peacock_grammar_str(&grammar).unwrap().optimize(false).build().unwrap();
+            assert!(cfg.is_finite());
+            assert_eq!(cfg.count_strings(), count);
+        }
+    }
+
+    #[test]
+    fn test_semantic_hash() {
+        let cfg = ContextFreeGrammar::builder()
+            .peacock_grammar("test-data/grammars/unit_rules.json")
+            .unwrap()
+            .build()
+            .unwrap();
+        let mut rules = cfg.rules().to_vec();
+        rules.reverse();
+        let reversed = ContextFreeGrammar::new(rules, cfg.entrypoint().clone());
+        assert_eq!(cfg.semantic_hash(), reversed.semantic_hash());
+
+        let other = ContextFreeGrammar::builder()
+            .gramatron_grammar("test-data/grammars/gramatron.json")
+            .unwrap()
+            .build()
+            .unwrap();
+        assert_ne!(cfg.semantic_hash(), other.semantic_hash());
+    }
+
+    #[test]
+    fn test_terminal_hash() {
+        let terminals = [
+            Terminal::new("A"),
+            Terminal::repeat(b'A', 2),
+            Terminal::marker(BindingOp::Begin, "A"),
+            Terminal::marker(BindingOp::Use, "A"),
+            Terminal::from_builtin(Builtin::Int {
+                min: 0,
+                max: 2,
+            }),
+            Terminal::from_builtin(Builtin::Bytes {
+                min: 0,
+                max: 2,
+            }),
+            Terminal::dynamic(),
+        ];
+        let hashes: HashSet<u64> =
+            terminals.iter().map(|term| RandomState::with_seeds(1, 2, 3, 4).hash_one(term)).collect();
+        assert_eq!(hashes.len(), terminals.len());
+    }
+
+    #[test]
+    fn test_rule_id() {
+        let cfg = ContextFreeGrammar::builder()
+            .peacock_grammar("test-data/grammars/unit_rules.json")
+            .unwrap()
+            .build()
+            .unwrap();
+        let ids: HashMap<&ProductionRule, u64> = cfg.rules().iter().map(|rule| (rule, cfg.rule_id(rule))).collect();
+        assert_eq!(ids.values().collect::<HashSet<_>>().len(), cfg.rules().len());
+
+        let mut rules = cfg.rules().to_vec();
+        rules.reverse();
+        rules.pop();
+        let edited = ContextFreeGrammar::new(rules, cfg.entrypoint().clone());
+
+        for rule in edited.rules() {
+            assert_eq!(edited.rule_id(rule), ids[rule]);
+        }
+    }
+
+    /// All bundled grammars that build without further configuration
+    fn bundled_grammars() -> impl Iterator<Item = (&'static str, ContextFreeGrammar)> {
+        [
+            "duplicate_rules.json",
+            "dynamic.json",
+            "epsilon_rules.json",
+            "exclusion.json",
+            "gramatron.json",
+            "greedy_unparsing.json",
+            "indirect_left_recursion.json",
+            "inline_rules.json",
+            "left_recursion.json",
+            "mixed_rules.json",
+            "regex.json",
+            "unit_rules.json",
+            "unparse.json",
+            "unused_rules.json",
+        ]
+        .into_iter()
+        .map(|grammar| {
+            let path = format!("test-data/grammars/{}", grammar);
+            let builder = if grammar == "gramatron.json" {
+                ContextFreeGrammar::builder().gramatron_grammar(&path).unwrap()
+            } else {
+                ContextFreeGrammar::builder().peacock_grammar(&path).unwrap()
+            };
+            (grammar, builder.build().unwrap())
+        })
+    }
+
+    #[test]
+    fn test_serde_roundtrip() {
+        for (grammar, cfg) in bundled_grammars() {
+            let mut json = Vec::new();
+            cfg.to_writer(&mut json).unwrap();
+            let copy = ContextFreeGrammar::from_reader(json.as_slice()).unwrap();
+            assert_eq!(copy.rules(), cfg.rules(), "{}", grammar);
+            assert_eq!(copy.entrypoint(), cfg.entrypoint(), "{}", grammar);
+            assert_eq!(copy.content_hash(), cfg.content_hash(), "{}", grammar);
+        }
+
+        let cfg = ContextFreeGrammar::builder()
+            .peacock_grammar_str(r#"{"<ENTRYPOINT>": [["'x'", "@repeat('A', 4096)"]]}"#)
+            .unwrap()
+            .build()
+            .unwrap();
+        let mut json = Vec::new();
+        cfg.to_writer(&mut json).unwrap();
+        assert!(json.len() < 1024);
+        let copy = ContextFreeGrammar::from_reader(json.as_slice()).unwrap();
+        assert_eq!(copy.rules(), cfg.rules());
+    }
+
+    #[test]
+    fn test_symbol_iterators() {
+        for (grammar, cfg) in bundled_grammars() {
+            let mut nonterminals = Vec::new();
+            let mut terminals = Vec::new();
+
+            for rule in cfg.rules() {
+                if !nonterminals.contains(rule.lhs()) {
+                    nonterminals.push(rule.lhs().clone());
+                }
+
+                for symbol in rule.rhs() {
+                    if let Symbol::Terminal(term) = symbol {
+                        if !terminals.contains(term) {
+                            terminals.push(term.clone());
+                        }
+                    }
+                }
+            }
+
+            assert!(cfg.nonterminals().eq(nonterminals.iter()), "{}", grammar);
+            assert!(cfg.terminals().eq(terminals.iter()), "{}", grammar);
+
+            for nonterm in &nonterminals {
+                let expected = cfg.rules().iter().filter(|rule| rule.lhs() == nonterm);
+                assert!(cfg.rules_for(nonterm).eq(expected), "{}", grammar);
+            }
+
+            /* The index is only built once */
+            let index = cfg.rules.index.get().unwrap() as *const _;
+            assert_eq!(cfg.rules_for(cfg.entrypoint()).count(), cfg.count_entrypoint_rules());
+            assert_eq!(cfg.rules.index.get().unwrap() as *const _, index);
+
+            /* Changing the rules drops the index */
+            let mut cfg = cfg;
+            let rule = cfg.rules()[0].clone();
+            cfg.rules.push(rule);
+            assert!(cfg.rules.index.get().is_none());
+            assert_eq!(cfg.rules_for(&NonTerminal::new("<no such non-terminal>")).count(), 0);
+        }
+    }
+
+    #[test]
+    fn test_serde_validation() {
+        let json = r#"{"rules": [{"lhs": "A", "rhs": [{"NonTerminal": "B"}]}], "entrypoint": "A"}"#;
+        let err = ContextFreeGrammar::from_reader(json.as_bytes()).unwrap_err();
+        assert!(matches!(&err, GrammarError::MissingNonTerminal(nonterm) if nonterm == "B"));
+
+        let json = r#"{"rules": [{"lhs": "A", "rhs": [{"Terminal": "a"}]}], "entrypoint": "B"}"#;
+        let err = ContextFreeGrammar::from_reader(json.as_bytes()).unwrap_err();
+        assert!(matches!(&err, GrammarError::MissingEntrypoint(nonterm) if nonterm == "B"));
+
+        let err = ContextFreeGrammar::from_reader(&b"{"[..]).unwrap_err();
+        assert!(matches!(err, GrammarError::InvalidSerialization(_)));
+        assert_eq!(err.exit_code(), 2);
+
+        let json = r#"{"rules": [], "entrypoint": "A"}"#;
+        let err = ContextFreeGrammar::from_reader(json.as_bytes()).unwrap_err();
+        assert!(matches!(err, GrammarError::EmptyGrammar));
+        assert_eq!(err.exit_code(), 11);
+
+        /* serde itself also validates */
+        assert!(serde_json::from_str::<ContextFreeGrammar>(r#"{"rules": [], "entrypoint": "A"}"#).is_err());
+    }
+
+    #[test]
+    fn test_content_hash() {
+        let cfg = ContextFreeGrammar::builder()
+            .peacock_grammar("test-data/grammars/unit_rules.json")
+            .unwrap()
+            .build()
+            .unwrap();
+        let copy = ContextFreeGrammar::new(cfg.rules().to_vec(), cfg.entrypoint().clone());
+        assert_eq!(cfg.content_hash(), copy.content_hash());
+
+        let mut rules = cfg.rules().to_vec();
+        rules.reverse();
+        let reversed = ContextFreeGrammar::new(rules, cfg.entrypoint().clone());
+        assert_ne!(cfg.content_hash(), reversed.content_hash());
+    }
+
+    #[test]
+    fn test_inline_single_use() {
+        use crate::backends::interpreter::GrammarInterpreter;
+
+        fn language(cfg: &ContextFreeGrammar) -> HashSet<Vec<u8>> {
+            let mut interpreter = GrammarInterpreter::new(cfg);
+            let mut ret = HashSet::new();
+
+            for _ in 0..1000 {
+                let mut buf = Vec::new();
+                interpreter.interpret(&mut buf).unwrap();
+                ret.insert(buf);
+            }
+
+            ret
+        }
+
+        let cfg = ContextFreeGrammar::builder()
+            .peacock_grammar("test-data/grammars/inline_rules.json")
+            .unwrap()
+            .build()
+            .unwrap();
+        let inlined = ContextFreeGrammar::builder()
+            .peacock_grammar("test-data/grammars/inline_rules.json")
+            .unwrap()
+            .inline_single_use(true)
+            .build()
+            .unwrap();
+
+        let count_nonterms =
+            |cfg: &ContextFreeGrammar| cfg.rules().iter().map(|r| r.lhs()).collect::<HashSet<_>>().len();
+        assert!(count_nonterms(&inlined) < count_nonterms(&cfg));
+
+        let expected = language(&cfg);
+        assert_eq!(expected.len(), 6);
+        assert_eq!(expected, language(&inlined));
+    }
+
+    #[test]
+    fn test_inline_single_use_cycle() {
+        let cfg = ContextFreeGrammar::builder()
+            .peacock_grammar_str(
+                r#"{"<ENTRYPOINT>": [["'a'"], ["<X>"]], "<X>": [["'x'"]], "<A>": [["'a'", "<B>"]], "<B>": [["'b'", "<A>"]]}"#,
+            )
+            .unwrap()
+            .optimize(false)
+            .inline_single_use(true)
+            .build()
+            .unwrap();
+
+        let defined: HashSet<&str> = cfg.rules().iter().map(|rule| rule.lhs().id()).collect();
+        assert!(!defined.contains("X"));
+        assert!(defined.contains("A") && defined.contains("B"));
+
+        for rule in cfg.rules() {
+            for symbol in rule.rhs() {
+                if let Symbol::NonTerminal(nonterm) = symbol {
+                    assert!(defined.contains(nonterm.id()), "dangling reference to <{}>", nonterm.id());
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_rules_by_nonterminal() {
+        let cfg = ContextFreeGrammar::builder()
+            .gramatron_grammar("test-data/grammars/gramatron.json")
+            .unwrap()
+            .build()
+            .unwrap();
+        let map = cfg.rules_by_nonterminal();
+
+        assert_eq!(map.values().map(|rules| rules.len()).sum::<usize>(), cfg.rules().len());
+        assert_eq!(*map.get_index(0).unwrap().0, cfg.rules()[0].lhs());
+
+        for (nonterm, rules) in &map {
+            assert!(rules.iter().all(|rule| rule.lhs() == *nonterm));
+        }
+    }
+
+    #[test]
+    fn test_nonterminals() {
+        let cfg = ContextFreeGrammar::builder()
+            .peacock_grammar("test-data/grammars/unparse.json")
+            .unwrap()
+            .optimize(false)
+            .build()
+            .unwrap();
+        let nonterms: Vec<&str> = cfg.nonterminals().map(NonTerminal::id).collect();
+        assert_eq!(nonterms, ["ENTRYPOINT", "LINE", "KEY", "VALUE", "ENTRYPOINT$entry1"]);
+        assert!(cfg.nonterminals().eq(cfg.rules_by_nonterminal().keys().copied()));
+    }
+
+    /// All words of the language of `cfg` that are at most `max_len` bytes long.
+    fn bounded_language(cfg: &ContextFreeGrammar, max_len: usize) -> HashSet<String> {
+        let mut words: HashMap<&str, HashSet<String>> = HashMap::new();
+        let mut changed = true;
+
+        while changed {
+            changed = false;
+
+            for rule in cfg.rules() {
+                let mut prefixes = HashSet::from([String::new()]);
+
+                for symbol in rule.rhs() {
+                    let empty = HashSet::new();
+                    let (single, suffixes) = match symbol {
+                        Symbol::Terminal(term) => (HashSet::from([term.expanded().to_string()]), &empty),
+                        Symbol::NonTerminal(nonterm) => (HashSet::new(), words.get(nonterm.id()).unwrap_or(&empty)),
+                    };
+                    prefixes = prefixes
+                        .iter()
+                        .flat_map(|prefix| {
+                            single.iter().chain(suffixes.iter()).map(move |suffix| format!("{}{}", prefix, suffix))
+                        })
+                        .filter(|word| word.len() <= max_len)
+                        .collect();
+                }
+
+                let entry = words.entry(rule.lhs().id()).or_default();
+
+                for word in prefixes {
+                    changed |= entry.insert(word);
+                }
+            }
+        }
+
+        words.remove(cfg.entrypoint().id()).unwrap_or_default()
+    }
+
+    #[test]
+    fn test_left_recursion() {
+        for path in ["test-data/grammars/left_recursion.json", "test-data/grammars/indirect_left_recursion.json"] {
+            let original =
+                ContextFreeGrammar::builder().peacock_grammar(path).unwrap().optimize(false).build().unwrap();
+            let cfg = ContextFreeGrammar::builder().peacock_grammar(path).unwrap().build().unwrap();
+
+            assert!(cfg
+                .rules()
+                .iter()
+                .filter(|rule| rule.lhs() != cfg.entrypoint())
+                .all(|rule| rule.rhs()[0].is_terminal()));
+
+            let expected = bounded_language(&original, 7);
+            assert!(expected.len() > 10);
+            assert_eq!(expected, bounded_language(&cfg, 7));
+        }
+    }
+
+    #[test]
+    fn test_epsilon_rules() {
+        let path = "test-data/grammars/epsilon_rules.json";
+        let original = ContextFreeGrammar::builder().peacock_grammar(path).unwrap().optimize(false).build().unwrap();
+        let cfg = ContextFreeGrammar::builder().peacock_grammar(path).unwrap().build().unwrap();
+
+        let mut entrypoints = HashSet::from([cfg.entrypoint()]);
+        for rule in cfg.rules() {
+            if rule.lhs() == cfg.entrypoint() {
+                if let Symbol::NonTerminal(nonterm) = &rule.rhs()[0] {
+                    entrypoints.insert(nonterm);
+                }
+            }
+        }
+
+        assert!(cfg
+            .rules()
+            .iter()
+            .filter(|rule| !entrypoints.contains(rule.lhs()))
+            .all(|rule| rule.rhs()[0].is_terminal() && rule.rhs().iter().all(|symbol| !symbol.is_epsilon())));
+
+        let expected = bounded_language(&original, 6);
+        assert!(expected.contains(""));
+        assert_eq!(expected, bounded_language(&cfg, 6));
+    }
+
+    #[test]
+    fn test_optional_trailing_element() {
+        for epsilon in ["[]", r#"[""]"#, "[\"''\"]"] {
+            let grammar = format!(r#"{{"<ENTRYPOINT>": [["'a'", "<OPT>"]], "<OPT>": [["'b'"], {}]}}"#, epsilon);
+
+            for optimize in [false, true] {
+                let cfg = ContextFreeGrammar::builder()
+                    .peacock_grammar_str(&grammar)
+                    .unwrap()
+                    .optimize(optimize)
+                    .build()
+                    .unwrap();
+                assert_eq!(bounded_language(&cfg, 4), HashSet::from(["a".to_string(), "ab".to_string()]));
+
+                if optimize {
+                    assert!(cfg.rules().iter().all(|rule| rule.rhs().iter().all(|symbol| !symbol.is_epsilon())));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_subgrammar() {
+        use crate::backends::interpreter::GrammarInterpreter;
+
+        let cfg = ContextFreeGrammar::builder()
+            .gramatron_grammar("test-data/grammars/gramatron.json")
+            .unwrap()
+            .build()
+            .unwrap();
+        let sub = cfg.subgrammar("ARGS").unwrap();
+
+        assert!(sub.rules().len() < cfg.rules().len());
+        assert!(sub.rules().iter().all(|rule| rule.lhs().id() != "ENTRYPOINT"));
+
+        let mut interpreter = GrammarInterpreter::new(&sub);
+        let mut buf = Vec::new();
+        interpreter.interpret(&mut buf).unwrap();
+        assert!(buf.starts_with(b"("));
+
+        assert!(cfg.subgrammar("DOES_NOT_EXIST").is_err());
+    }
+
+    #[test]
+    fn test_synthetic_names() {
+        let nt = |id: &str| Symbol::NonTerminal(NonTerminal::new(id));
+        let t = |content: &str| Symbol::Terminal(Terminal::new(content));
+        let mut cfg = ContextFreeGrammar::new(
+            vec![
+                ProductionRule::new(NonTerminal::new("A"), vec![nt("B"), nt("B"), nt("A$split1")]),
+                ProductionRule::new(NonTerminal::new("A"), vec![nt("B"), t("x")]),
+                ProductionRule::new(NonTerminal::new("A$split1"), vec![t("y")]),
+                ProductionRule::new(NonTerminal::new("B"), vec![t("x")]),
+            ],
+            NonTerminal::new("A"),
+        );
+
+        cfg.remove_mixed_rules();
+        cfg.break_rules(&BuildLimits::default()).unwrap();
+        cfg.set_new_entrypoint();
+
+        let ids: Vec<&str> = cfg.rules().iter().map(|rule| rule.lhs().id()).collect();
+        assert!(ids.contains(&"A$split2"));
+        assert!(ids.contains(&"A$term1"));
+        assert_eq!(cfg.entrypoint().id(), "A$entry1");
+        assert_eq!(ids.iter().filter(|id| **id == "A$split1").count(), 1);
+    }
+
+    /// The quadratic versions of remove_unit_rules() and convert_to_gnf() that the linear ones must agree with
+    fn naive_expand_leading_non_terminals<F: Fn(&ProductionRule) -> bool>(rules: &mut Vec<ProductionRule>, expand: F) {
+        let mut seen: HashSet<ProductionRule> = rules.iter().cloned().collect();
+        let mut i = 0;
+
+        while i < rules.len() {
+            if expand(&rules[i]) {
+                let mut old_rule = rules.remove(i);
+                let Symbol::NonTerminal(nonterm) = old_rule.rhs.remove(0) else { unreachable!() };
+                let mut new_rules = Vec::new();
+
+                for other_rule in rules.iter() {
+                    if other_rule.lhs().id() == nonterm.id() {
+                        let mut new_symbols = other_rule.rhs.clone();
+                        new_symbols.extend_from_slice(old_rule.rhs());
+                        let new_rule = ProductionRule::new(old_rule.lhs().clone(), new_symbols);
+
+                        if seen.insert(new_rule.clone()) {
+                            new_rules.push(new_rule);
+                        }
+                    }
+                }
+
+                rules.append(&mut new_rules);
+            } else {
+                i += 1;
+            }
+        }
+    }
+
+    #[test]
+    fn test_expand_leading_non_terminals() {
+        let limits = BuildLimits::default();
+        let content = std::fs::read_to_string("test-data/grammars/gramatron.json").unwrap();
+        let mut cfg =
+            ContextFreeGrammar::builder().gramatron_grammar_str(&content).unwrap().optimize(false).build().unwrap();
+        cfg.concatenate_terminals();
+        cfg.remove_epsilon_rules(&limits).unwrap();
+        cfg.remove_duplicate_rules();
+
+        let mut expected = cfg.rules().to_vec();
+        naive_expand_leading_non_terminals(&mut expected, |rule| {
+            rule.rhs().len() == 1 && rule.rhs()[0].is_non_terminal()
+        });
+        cfg.remove_unit_rules(&limits).unwrap();
+        assert_eq!(cfg.rules(), expected);
+
+        cfg.remove_unused_rules();
+        cfg.remove_mixed_rules();
+        cfg.break_rules(&limits).unwrap();
+        cfg.remove_left_recursion(&limits).unwrap();
+
+        let mut expected = cfg.rules().to_vec();
+        naive_expand_leading_non_terminals(&mut expected, |rule| rule.rhs()[0].is_non_terminal());
+        cfg.convert_to_gnf(&limits).unwrap();
+        assert_eq!(cfg.rules(), expected);
+        assert!(cfg.is_in_gnf());
+    }
+
+    #[test]
+    fn test_large_grammar() {
+        const NONTERMINALS: usize = 1000;
+        const RULES: usize = 50;
+
+        /* ENTRYPOINT -> N_i -> M_i are unit rules and every rule of M_i is defined twice */
+        let mut grammar = serde_json::Map::new();
+        let entrypoint: Vec<_> = (0..NONTERMINALS).map(|i| serde_json::json!([format!("<N{}>", i)])).collect();
+        grammar.insert("<ENTRYPOINT>".to_string(), entrypoint.into());
+
+        for i in 0..NONTERMINALS {
+            let terminals: Vec<_> =
+                (0..2 * RULES).map(|j| serde_json::json!([format!("'{}-{}'", i, j % RULES)])).collect();
+            grammar.insert(format!("<N{}>", i), serde_json::json!([[format!("<M{}>", i)]]));
+            grammar.insert(format!("<M{}>", i), terminals.into());
+        }
+
+        let content = serde_json::Value::Object(grammar).to_string();
+        let start = Instant::now();
+        let cfg = ContextFreeGrammar::builder().peacock_grammar_str(&content).unwrap().build().unwrap();
+        assert!(start.elapsed() < Duration::from_secs(60));
+        /* Plus the rule of the new entrypoint */
+        assert_eq!(cfg.rules().len(), NONTERMINALS * RULES + 1);
+    }
+
     #[test]
     #[ignore]
     fn test_mixed_rules() {
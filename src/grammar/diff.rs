@@ -0,0 +1,232 @@
+use serde::Serialize;
+use std::collections::{
+    BTreeMap,
+    BTreeSet,
+};
+
+use crate::grammar::{
+    ContextFreeGrammar,
+    Symbol,
+};
+
+/// The differences of the rules of a single non-terminal that is defined in both grammars.
+#[derive(Debug, Default, Serialize, PartialEq, Eq)]
+pub struct NonTerminalDiff {
+    /// The non-terminal
+    pub non_terminal: String,
+
+    /// Alternatives that only the second grammar has
+    pub added_rules: Vec<Vec<String>>,
+
+    /// Alternatives that only the first grammar has
+    pub removed_rules: Vec<Vec<String>>,
+
+    /// Terminals that only occur in the alternatives of the second grammar
+    pub added_terminals: Vec<String>,
+
+    /// Terminals that only occur in the alternatives of the first grammar
+    pub removed_terminals: Vec<String>,
+}
+
+/// The result of [`diff()`].
+///
+/// Symbols are written in peacock notation: non-terminals as `<A>` and terminals as `'a'`.
+#[derive(Debug, Default, Serialize, PartialEq, Eq)]
+pub struct GrammarDiff {
+    /// The entrypoints of both grammars if they differ
+    pub entrypoint: Option<(String, String)>,
+
+    /// Non-terminals that are only defined in the second grammar
+    pub added_non_terminals: Vec<String>,
+
+    /// Non-terminals that are only defined in the first grammar
+    pub removed_non_terminals: Vec<String>,
+
+    /// Non-terminals that are defined in both grammars but with different rules
+    pub changed_non_terminals: Vec<NonTerminalDiff>,
+}
+
+impl GrammarDiff {
+    /// Whether both grammars have the same rules.
+    pub fn is_empty(&self) -> bool {
+        self.entrypoint.is_none()
+            && self.added_non_terminals.is_empty()
+            && self.removed_non_terminals.is_empty()
+            && self.changed_non_terminals.is_empty()
+    }
+}
+
+impl std::fmt::Display for GrammarDiff {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some((a, b)) = &self.entrypoint {
+            writeln!(f, "entrypoint: <{}> -> <{}>", a, b)?;
+        }
+
+        for nonterm in &self.added_non_terminals {
+            writeln!(f, "+ <{}>", nonterm)?;
+        }
+
+        for nonterm in &self.removed_non_terminals {
+            writeln!(f, "- <{}>", nonterm)?;
+        }
+
+        for changed in &self.changed_non_terminals {
+            writeln!(f, "~ <{}>", changed.non_terminal)?;
+
+            for rule in &changed.added_rules {
+                writeln!(f, "    + {}", rule.join(" "))?;
+            }
+
+            for rule in &changed.removed_rules {
+                writeln!(f, "    - {}", rule.join(" "))?;
+            }
+
+            if !changed.added_terminals.is_empty() {
+                writeln!(f, "    terminals added: {}", changed.added_terminals.join(" "))?;
+            }
+
+            if !changed.removed_terminals.is_empty() {
+                writeln!(f, "    terminals removed: {}", changed.removed_terminals.join(" "))?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+pub(crate) fn symbol_string(symbol: &Symbol) -> String {
+    match symbol {
+        Symbol::Terminal(term) => match (term.run(), term.binding(), term.builtin()) {
+            (Some((byte, count)), _, _) => format!("@repeat('{}', {})", byte as char, count),
+            (_, Some((op, name)), _) => format!("@{}({})", op.keyword(), name),
+            (_, _, Some(builtin)) => builtin.to_string(),
+            _ => format!("'{}'", term.expanded()),
+        },
+        Symbol::NonTerminal(nonterm) => format!("<{}>", nonterm.id()),
+    }
+}
+
+/// Group the rules by non-terminal. Consecutive terminals are merged such that
+/// `'a' 'b'` and `'ab'` are the same alternative. Binding markers and builtins separate terminals like non-terminals.
+fn normalize(grammar: &ContextFreeGrammar) -> BTreeMap<&str, BTreeSet<Vec<String>>> {
+    let mut rules: BTreeMap<&str, BTreeSet<Vec<String>>> = BTreeMap::new();
+
+    for rule in grammar.rules() {
+        let mut rhs: Vec<String> = Vec::with_capacity(rule.rhs().len());
+        let mut terminal = None;
+
+        for symbol in rule.rhs() {
+            match symbol {
+                Symbol::Terminal(term) if term.binding().is_none() && term.builtin().is_none() => {
+                    terminal.get_or_insert_with(String::new).push_str(&term.expanded())
+                },
+                _ => {
+                    if let Some(content) = terminal.take() {
+                        rhs.push(format!("'{}'", content));
+                    }
+
+                    rhs.push(symbol_string(symbol));
+                },
+            }
+        }
+
+        if let Some(content) = terminal {
+            rhs.push(format!("'{}'", content));
+        }
+
+        rules.entry(rule.lhs().id()).or_default().insert(rhs);
+    }
+
+    rules
+}
+
+fn terminals(rules: &BTreeSet<Vec<String>>) -> BTreeSet<&String> {
+    rules.iter().flatten().filter(|symbol| symbol.starts_with('\'')).collect()
+}
+
+/// Compare the rules of two grammars `a` and `b`, ignoring the order of rules and duplicate rules.
+pub fn diff(a: &ContextFreeGrammar, b: &ContextFreeGrammar) -> GrammarDiff {
+    let a_rules = normalize(a);
+    let b_rules = normalize(b);
+    let mut diff = GrammarDiff::default();
+
+    if a.entrypoint() != b.entrypoint() {
+        diff.entrypoint = Some((a.entrypoint().id().to_string(), b.entrypoint().id().to_string()));
+    }
+
+    for (nonterm, a_alternatives) in &a_rules {
+        let Some(b_alternatives) = b_rules.get(nonterm) else {
+            diff.removed_non_terminals.push(nonterm.to_string());
+            continue;
+        };
+
+        if a_alternatives == b_alternatives {
+            continue;
+        }
+
+        let a_terminals = terminals(a_alternatives);
+        let b_terminals = terminals(b_alternatives);
+
+        diff.changed_non_terminals.push(NonTerminalDiff {
+            non_terminal: nonterm.to_string(),
+            added_rules: b_alternatives.difference(a_alternatives).cloned().collect(),
+            removed_rules: a_alternatives.difference(b_alternatives).cloned().collect(),
+            added_terminals: b_terminals.difference(&a_terminals).map(|x| x.to_string()).collect(),
+            removed_terminals: a_terminals.difference(&b_terminals).map(|x| x.to_string()).collect(),
+        });
+    }
+
+    for nonterm in b_rules.keys() {
+        if !a_rules.contains_key(nonterm) {
+            diff.added_non_terminals.push(nonterm.to_string());
+        }
+    }
+
+    diff
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::grammar::{
+        tests::rule,
+        NonTerminal,
+    };
+
+    #[test]
+    fn test_diff() {
+        let a = ContextFreeGrammar::new(
+            vec![rule("S", &["<A>", "x"]), rule("A", &["a", "b"]), rule("A", &["c"]), rule("B", &["b"])],
+            NonTerminal::new("S"),
+        );
+        let b = ContextFreeGrammar::new(
+            vec![rule("A", &["c"]), rule("A", &["ab"]), rule("A", &["c"]), rule("S", &["<A>", "x"])],
+            NonTerminal::new("S"),
+        );
+        assert!(diff(&a, &a).is_empty());
+        assert_eq!(
+            diff(&a, &b),
+            GrammarDiff {
+                removed_non_terminals: vec!["B".to_string()],
+                ..Default::default()
+            }
+        );
+
+        let c = ContextFreeGrammar::new(
+            vec![rule("S", &["<A>", "x"]), rule("A", &["a", "b"]), rule("A", &["d", "<C>"]), rule("C", &["b"])],
+            NonTerminal::new("S"),
+        );
+        let result = diff(&b, &c);
+        assert_eq!(result.added_non_terminals, vec!["C"]);
+        assert_eq!(
+            result.changed_non_terminals,
+            vec![NonTerminalDiff {
+                non_terminal: "A".to_string(),
+                added_rules: vec![vec!["'d'".to_string(), "<C>".to_string()]],
+                removed_rules: vec![vec!["'c'".to_string()]],
+                added_terminals: vec!["'d'".to_string()],
+                removed_terminals: vec!["'c'".to_string()],
+            }]
+        );
+    }
+}
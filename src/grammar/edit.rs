@@ -0,0 +1,161 @@
+//! Helpers that edit the rules of a grammar in a [`GrammarBuilder::transform`](crate::grammar::GrammarBuilder::transform) hook.
+//!
+//! None of them check whether the result is a valid grammar, this happens in
+//! [`GrammarBuilder::build`](crate::grammar::GrammarBuilder::build) after all hooks ran.
+//!
+//! ```
+//! # use peacock_fuzz::grammar::{ContextFreeGrammar, Symbol, Terminal, NonTerminal, edit};
+//! let grammar = ContextFreeGrammar::builder()
+//!     .peacock_grammar_str(r#"{"<ENTRYPOINT>": [["<VALUE>"]], "<VALUE>": [["'1'"], ["'2'"]]}"#).unwrap()
+//!     // Every <VALUE> may now also be preceded by a "-"
+//!     .transform(|rules| {
+//!         edit::wrap_nonterminal(rules, "VALUE", "MAYBE_NEGATIVE");
+//!         edit::add_alternative(rules, "MAYBE_NEGATIVE", vec![
+//!             Symbol::Terminal(Terminal::new("-")),
+//!             Symbol::NonTerminal(NonTerminal::new("VALUE")),
+//!         ]);
+//!     })
+//!     .build().unwrap();
+//! ```
+
+use crate::grammar::{
+    NonTerminal,
+    ProductionRule,
+    Symbol,
+};
+
+/// Copy `rule` and replace every non-terminal `nonterm` in its right-hand-side with `f(nonterm)`.
+fn map_references<F>(rule: &ProductionRule, mut f: F) -> ProductionRule
+where
+    F: FnMut(&NonTerminal) -> NonTerminal,
+{
+    let rhs = rule
+        .rhs()
+        .iter()
+        .map(|symbol| match symbol {
+            Symbol::NonTerminal(nonterm) => Symbol::NonTerminal(f(nonterm)),
+            Symbol::Terminal(_) => symbol.clone(),
+        })
+        .collect();
+    ProductionRule::new(rule.lhs().clone(), rhs)
+}
+
+/// Rename the non-terminal `from` to `to` in all rules, both where it gets defined and where it is referenced.
+/// If `to` already exists, the rules of both non-terminals get merged.
+pub fn rename_nonterminal(rules: &mut [ProductionRule], from: &str, to: &str) {
+    let rename = |nonterm: &NonTerminal| {
+        if nonterm.id() == from {
+            NonTerminal::new(to)
+        } else {
+            nonterm.clone()
+        }
+    };
+
+    for rule in rules {
+        let renamed = map_references(rule, rename);
+        *rule = ProductionRule::new(rename(rule.lhs()), renamed.rhs().to_vec());
+    }
+}
+
+/// Interpose the new non-terminal `wrapper_name` between `target` and all of its references.
+/// Every occurrence of `<target>` in a right-hand-side becomes `<wrapper_name>`, which gets the single rule
+/// `<wrapper_name> -> <target>`. Add alternatives to the wrapper to change what can appear in place of `target`.
+pub fn wrap_nonterminal(rules: &mut Vec<ProductionRule>, target: &str, wrapper_name: &str) {
+    let wrapper = NonTerminal::new(wrapper_name);
+
+    for rule in rules.iter_mut() {
+        *rule = map_references(rule, |nonterm| if nonterm.id() == target { wrapper.clone() } else { nonterm.clone() });
+    }
+
+    rules.push(ProductionRule::new(wrapper, vec![Symbol::NonTerminal(NonTerminal::new(target))]));
+}
+
+/// Add the alternative `rhs` to the non-terminal `lhs` after its last alternative.
+/// An empty `rhs` is not allowed, use an empty terminal for Ɛ instead.
+pub fn add_alternative(rules: &mut Vec<ProductionRule>, lhs: &str, rhs: Vec<Symbol>) {
+    let rule = ProductionRule::new(NonTerminal::new(lhs), rhs);
+
+    match rules.iter().rposition(|rule| rule.lhs().id() == lhs) {
+        Some(position) => rules.insert(position + 1, rule),
+        None => rules.push(rule),
+    }
+}
+
+/// Remove the alternative number `index` of the non-terminal `lhs`, counted in the order of the rules.
+/// Returns the removed rule or `None` if `lhs` has no such alternative.
+pub fn remove_alternative(rules: &mut Vec<ProductionRule>, lhs: &str, index: usize) -> Option<ProductionRule> {
+    let position = rules.iter().enumerate().filter(|(_, rule)| rule.lhs().id() == lhs).nth(index)?.0;
+    Some(rules.remove(position))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        backends::interpreter::GrammarInterpreter,
+        grammar::{
+            ContextFreeGrammar,
+            Terminal,
+        },
+    };
+
+    #[test]
+    fn test_wrap_nonterminal() {
+        let grammar = r#"{
+            "<ENTRYPOINT>": [["<VALUE>"], ["<VALUE>", "','", "<ENTRYPOINT>"]],
+            "<VALUE>": [["'1'"], ["'2'"], ["'('", "<VALUE>", "')'"]]
+        }"#;
+
+        for optimize in [false, true] {
+            let cfg = ContextFreeGrammar::builder()
+                .peacock_grammar_str(grammar)
+                .unwrap()
+                .transform(|rules| {
+                    wrap_nonterminal(rules, "VALUE", "FAULT");
+                    add_alternative(
+                        rules,
+                        "FAULT",
+                        vec![Symbol::Terminal(Terminal::new("!")), Symbol::NonTerminal(NonTerminal::new("VALUE"))],
+                    );
+                    assert!(remove_alternative(rules, "FAULT", 0).is_some());
+                    assert!(remove_alternative(rules, "FAULT", 1).is_none());
+                })
+                .optimize(optimize)
+                .build()
+                .unwrap();
+            let mut interpreter = GrammarInterpreter::new(&cfg);
+
+            for _ in 0..1000 {
+                let mut buf = Vec::new();
+                interpreter.interpret(&mut buf).unwrap();
+
+                for (i, c) in buf.iter().enumerate() {
+                    if matches!(c, b'1' | b'2' | b'(') {
+                        assert_eq!(buf[i - 1], b'!', "{}", String::from_utf8_lossy(&buf));
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_rename_nonterminal() {
+        let cfg = ContextFreeGrammar::builder()
+            .peacock_grammar_str(r#"{"<ENTRYPOINT>": [["<A>", "<A>"]], "<A>": [["'a'"]]}"#)
+            .unwrap()
+            .transform(|rules| rename_nonterminal(rules, "A", "B"))
+            .transform(|rules| add_alternative(rules, "B", vec![Symbol::Terminal(Terminal::new("b"))]))
+            .optimize(false)
+            .build()
+            .unwrap();
+        let rules: Vec<String> = cfg
+            .rules()
+            .iter()
+            .map(|rule| {
+                let rhs: Vec<String> = rule.rhs().iter().map(crate::grammar::symbol_string).collect();
+                format!("<{}> -> {}", rule.lhs().id(), rhs.join(" "))
+            })
+            .collect();
+        assert_eq!(rules, vec!["<ENTRYPOINT> -> <B> <B>", "<B> -> 'a'", "<B> -> 'b'"]);
+    }
+}
@@ -0,0 +1,281 @@
+use std::collections::{
+    HashMap,
+    HashSet,
+};
+
+use crate::{
+    error::GrammarError,
+    grammar::{
+        cfg::BuildLimits,
+        NonTerminal,
+        ProductionRule,
+        Symbol,
+    },
+};
+
+/// The trie state of everything that is not a prefix of an excluded string
+const DEAD: usize = usize::MAX;
+
+/// A trie over the excluded strings. Every state stands for the prefix that leads to it.
+struct Trie {
+    prefixes: Vec<Vec<u8>>,
+    states: HashMap<Vec<u8>, usize>,
+    accepting: HashSet<usize>,
+}
+
+impl Trie {
+    fn new(strings: &[String]) -> Self {
+        let mut trie = Self {
+            prefixes: vec![Vec::new()],
+            states: HashMap::from([(Vec::new(), 0)]),
+            accepting: HashSet::new(),
+        };
+
+        for string in strings {
+            let string = string.as_bytes();
+
+            for i in 1..=string.len() {
+                if !trie.states.contains_key(&string[..i]) {
+                    trie.states.insert(string[..i].to_vec(), trie.prefixes.len());
+                    trie.prefixes.push(string[..i].to_vec());
+                }
+            }
+
+            trie.accepting.insert(trie.states[string]);
+        }
+
+        trie
+    }
+
+    fn step(&self, state: usize, data: &[u8]) -> usize {
+        if state == DEAD || data.is_empty() {
+            return state;
+        }
+
+        let mut prefix = self.prefixes[state].clone();
+        prefix.extend_from_slice(data);
+        self.states.get(&prefix).copied().unwrap_or(DEAD)
+    }
+
+    /// All states that can be reached from `state` by consuming some input
+    fn successors(&self, state: usize) -> Vec<usize> {
+        let mut ret = vec![DEAD];
+
+        if state != DEAD {
+            let prefix = &self.prefixes[state];
+            ret.extend((0..self.prefixes.len()).filter(|other| self.prefixes[*other].starts_with(prefix)));
+        }
+
+        ret
+    }
+}
+
+/// A non-terminal that derives everything that another non-terminal derives except for a fixed set of strings.
+///
+/// The new rules are the product of the rules of `from` with a trie over the excluded strings
+/// such that the result is exact and does not need to be checked at runtime.
+#[derive(Debug, Clone)]
+pub(crate) struct Exclusion {
+    name: NonTerminal,
+    from: NonTerminal,
+    not: Vec<String>,
+}
+
+impl Exclusion {
+    pub(crate) fn new(name: NonTerminal, from: NonTerminal, not: Vec<String>) -> Self {
+        Self {
+            name,
+            from,
+            not,
+        }
+    }
+
+    pub(crate) fn name(&self) -> &NonTerminal {
+        &self.name
+    }
+
+    pub(crate) fn from(&self) -> &NonTerminal {
+        &self.from
+    }
+
+    /// Replace the non-terminal of this exclusion and the one it excludes strings from with `f(name)` and `f(from)`.
+    pub(crate) fn rename<F: Fn(&NonTerminal) -> NonTerminal>(&mut self, f: F) {
+        self.name = f(&self.name);
+        self.from = f(&self.from);
+    }
+
+    /// Create the rules of the non-terminal of this exclusion. `rules` must contain the rules of `from`.
+    pub(crate) fn expand(
+        &self,
+        rules: &[ProductionRule],
+        limits: &BuildLimits,
+    ) -> Result<Vec<ProductionRule>, GrammarError> {
+        let trie = Trie::new(&self.not);
+        let mut rules_of: HashMap<&NonTerminal, Vec<&ProductionRule>> = HashMap::new();
+        let mut names: HashMap<(&NonTerminal, usize, usize), NonTerminal> = HashMap::new();
+        let mut visited = HashSet::from([(&self.from, 0)]);
+        let mut queue = vec![(&self.from, 0)];
+        let mut new_rules = Vec::new();
+
+        for rule in rules {
+            rules_of.entry(rule.lhs()).or_default().push(rule);
+        }
+
+        /* (A, p, q) derives everything that A derives and leads from trie state p to trie state q */
+        let mut product = |nonterm, start, end| {
+            let len = names.len();
+            names
+                .entry((nonterm, start, end))
+                .or_insert_with(|| NonTerminal::new(format!("{}${}", self.name.id(), len)))
+                .clone()
+        };
+
+        while let Some((nonterm, start)) = queue.pop() {
+            for rule in rules_of.get(nonterm).into_iter().flatten() {
+                let mut partial = vec![(Vec::new(), start)];
+
+                for symbol in rule.rhs() {
+                    let mut next = Vec::with_capacity(partial.len());
+
+                    for (mut rhs, state) in partial {
+                        match symbol {
+                            Symbol::Terminal(term) => {
+                                rhs.push(symbol.clone());
+                                next.push((rhs, trie.step(state, term.expanded().as_bytes())));
+                            },
+                            Symbol::NonTerminal(_) if state == DEAD => {
+                                rhs.push(symbol.clone());
+                                next.push((rhs, DEAD));
+                            },
+                            Symbol::NonTerminal(child) => {
+                                if visited.insert((child, state)) {
+                                    queue.push((child, state));
+                                }
+
+                                for end in trie.successors(state) {
+                                    let mut rhs = rhs.clone();
+                                    rhs.push(Symbol::NonTerminal(product(child, state, end)));
+                                    next.push((rhs, end));
+                                }
+                            },
+                        }
+                    }
+
+                    partial = next;
+                    limits.check(new_rules.len() + partial.len())?;
+                }
+
+                for (rhs, end) in partial {
+                    new_rules.push(ProductionRule::new(product(nonterm, start, end), rhs));
+                }
+            }
+        }
+
+        for end in trie.successors(0) {
+            if !trie.accepting.contains(&end) {
+                let rhs = vec![Symbol::NonTerminal(product(&self.from, 0, end))];
+                new_rules.push(ProductionRule::new(self.name.clone(), rhs));
+            }
+        }
+
+        /* Many of the products derive nothing */
+        let mut created: HashSet<NonTerminal> = names.into_values().collect();
+        created.insert(self.name.clone());
+        let mut productive = HashSet::new();
+        let mut changed = true;
+
+        let is_productive = |symbol: &Symbol, productive: &HashSet<NonTerminal>| match symbol {
+            Symbol::Terminal(_) => true,
+            Symbol::NonTerminal(nonterm) => !created.contains(nonterm) || productive.contains(nonterm),
+        };
+
+        while changed {
+            changed = false;
+
+            for rule in &new_rules {
+                if !productive.contains(rule.lhs())
+                    && rule.rhs().iter().all(|symbol| is_productive(symbol, &productive))
+                {
+                    productive.insert(rule.lhs().clone());
+                    changed = true;
+                }
+            }
+        }
+
+        if !productive.contains(&self.name) {
+            return Err(GrammarError::EmptyExclusion(self.from.id().to_string()));
+        }
+
+        new_rules.retain(|rule| {
+            productive.contains(rule.lhs()) && rule.rhs().iter().all(|symbol| is_productive(symbol, &productive))
+        });
+
+        Ok(new_rules)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        backends::interpreter::GrammarInterpreter,
+        error::GrammarError,
+        grammar::ContextFreeGrammar,
+    };
+    use std::collections::HashSet;
+
+    fn language(cfg: &ContextFreeGrammar, n: usize) -> HashSet<String> {
+        let mut interpreter = GrammarInterpreter::new(cfg);
+        let mut ret = HashSet::new();
+
+        for _ in 0..n {
+            let mut buf = Vec::new();
+            interpreter.interpret(&mut buf).unwrap();
+            ret.insert(String::from_utf8(buf).unwrap());
+        }
+
+        ret
+    }
+
+    #[test]
+    fn test_exclusion() {
+        let keywords = ["if", "in", "fin", "f"];
+
+        for optimize in [false, true] {
+            let cfg = ContextFreeGrammar::builder()
+                .peacock_grammar("test-data/grammars/exclusion.json")
+                .unwrap()
+                .optimize(optimize)
+                .build()
+                .unwrap();
+            let identifiers = language(&cfg, 10_000);
+
+            for keyword in keywords {
+                assert!(!identifiers.contains(keyword));
+            }
+
+            for identifier in ["i", "n", "ff", "iff", "fi", "nif", "infi"] {
+                assert!(identifiers.contains(identifier), "{} was not generated", identifier);
+            }
+        }
+
+        let cfg = ContextFreeGrammar::builder()
+            .peacock_grammar("test-data/grammars/exclusion.json")
+            .unwrap()
+            .entrypoint("IDENTIFIER")
+            .build()
+            .unwrap();
+        let identifiers = language(&cfg, 10_000);
+
+        for keyword in keywords {
+            assert!(identifiers.contains(keyword));
+        }
+
+        let err = ContextFreeGrammar::builder()
+            .peacock_grammar("test-data/grammars/exclusion_empty.json")
+            .unwrap()
+            .build()
+            .err()
+            .unwrap();
+        assert!(matches!(err, GrammarError::EmptyExclusion(nonterm) if nonterm == "KEYWORD"));
+    }
+}
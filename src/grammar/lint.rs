@@ -0,0 +1,333 @@
+use std::collections::{
+    BTreeMap,
+    BTreeSet,
+    HashMap,
+};
+
+use crate::grammar::{
+    BindingOp,
+    Builtin,
+    ContextFreeGrammar,
+    NonTerminal,
+    Symbol,
+};
+
+/// How bad a [`LintFinding`] is
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum LintSeverity {
+    /// The grammar could be simplified
+    Info,
+
+    /// The grammar wastes mutation entropy
+    Warning,
+
+    /// The grammar contains a mistake
+    Error,
+}
+
+impl std::fmt::Display for LintSeverity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LintSeverity::Info => write!(f, "info"),
+            LintSeverity::Warning => write!(f, "warning"),
+            LintSeverity::Error => write!(f, "error"),
+        }
+    }
+}
+
+/// The kinds of problems that [`lint()`] detects
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum LintKind {
+    /// Alternatives that are identical once consecutive terminals are concatenated, like `'a' 'b'` and `'ab'`
+    DuplicateAlternatives,
+
+    /// Alternatives that are identical except for non-terminals that derive exactly the same rules under different names
+    EquivalentAlternatives,
+
+    /// Alternatives that only differ in whitespace at the start or the end of their terminals, like `'x' ' ' <A>` and `'x' <A>`
+    WhitespaceVariants,
+
+    /// A non-terminal with a single, non-recursive alternative that could be substituted into the rules that reference it
+    InlinableNonTerminal,
+}
+
+impl LintKind {
+    /// The severity of all findings of this kind
+    pub fn severity(&self) -> LintSeverity {
+        match self {
+            LintKind::DuplicateAlternatives => LintSeverity::Error,
+            LintKind::EquivalentAlternatives | LintKind::WhitespaceVariants => LintSeverity::Warning,
+            LintKind::InlinableNonTerminal => LintSeverity::Info,
+        }
+    }
+}
+
+/// A problem that [`lint()`] found in the alternatives of a non-terminal.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LintFinding {
+    /// How bad the problem is
+    pub severity: LintSeverity,
+
+    /// What the problem is
+    pub kind: LintKind,
+
+    /// The left-hand-side of the affected alternatives
+    pub non_terminal: String,
+
+    /// The indices of the affected alternatives in the order in which the rules of the non-terminal are defined,
+    /// i.e. indices into [`ContextFreeGrammar::rules_for`]
+    pub alternatives: Vec<usize>,
+}
+
+impl std::fmt::Display for LintFinding {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let alternatives = self.alternatives.iter().map(|i| format!("#{}", i)).collect::<Vec<_>>().join(", ");
+
+        write!(f, "{}: <{}>: ", self.severity, self.non_terminal)?;
+
+        match self.kind {
+            LintKind::DuplicateAlternatives => write!(f, "alternatives {} are identical", alternatives),
+            LintKind::EquivalentAlternatives => {
+                write!(f, "alternatives {} only differ in the names of equivalent non-terminals", alternatives)
+            },
+            LintKind::WhitespaceVariants => {
+                write!(f, "alternatives {} only differ in surrounding whitespace", alternatives)
+            },
+            LintKind::InlinableNonTerminal => write!(f, "has a single alternative that could be inlined"),
+        }
+    }
+}
+
+/// A symbol of a normalized alternative
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+enum Token<'a> {
+    Text(String),
+    Dynamic,
+    Binding(BindingOp, &'a str),
+    Builtin(Builtin),
+    Name(&'a str),
+    Class(usize),
+}
+
+/// Concatenate consecutive terminals of `rhs` and replace the non-terminals with `nonterm`.
+/// If `trim` is set, whitespace at the start and the end of the concatenated terminals is removed.
+fn normalize<'a, F>(rhs: &'a [Symbol], trim: bool, nonterm: F) -> Vec<Token<'a>>
+where
+    F: Fn(&'a NonTerminal) -> Token<'a>,
+{
+    let mut tokens = Vec::with_capacity(rhs.len());
+    let mut text = String::new();
+
+    let flush = |text: &mut String, tokens: &mut Vec<Token<'a>>| {
+        let content = if trim { text.trim() } else { text.as_str() };
+
+        if !content.is_empty() {
+            tokens.push(Token::Text(content.to_string()));
+        }
+
+        text.clear();
+    };
+
+    for symbol in rhs {
+        match symbol {
+            Symbol::Terminal(term) if term.is_dynamic() => {
+                flush(&mut text, &mut tokens);
+                tokens.push(Token::Dynamic);
+            },
+            Symbol::Terminal(term) if term.builtin().is_some() => {
+                flush(&mut text, &mut tokens);
+                tokens.push(Token::Builtin(*term.builtin().unwrap()));
+            },
+            Symbol::Terminal(term) => match term.binding() {
+                Some((op, name)) => {
+                    flush(&mut text, &mut tokens);
+                    tokens.push(Token::Binding(op, name));
+                },
+                None => text.push_str(&term.expanded()),
+            },
+            Symbol::NonTerminal(nt) => {
+                flush(&mut text, &mut tokens);
+                tokens.push(nonterm(nt));
+            },
+        }
+    }
+
+    flush(&mut text, &mut tokens);
+    tokens
+}
+
+/// The equivalence class of `nonterm`. Undefined non-terminals are only equivalent to themselves.
+fn class_token<'a>(classes: &HashMap<&str, usize>, nonterm: &'a NonTerminal) -> Token<'a> {
+    classes.get(nonterm.id()).map_or(Token::Name(nonterm.id()), |class| Token::Class(*class))
+}
+
+/// Partition the non-terminals into classes of non-terminals that have the same alternatives
+/// if equivalent non-terminals are considered equal. Works like the minimization of a DFA.
+fn equivalence_classes<'a>(rules: &[(&'a str, Vec<&'a [Symbol]>)]) -> HashMap<&'a str, usize> {
+    let mut classes: HashMap<&str, usize> = rules.iter().map(|(nonterm, _)| (*nonterm, 0)).collect();
+    let mut count = 1;
+
+    loop {
+        let mut signatures: BTreeMap<(usize, BTreeSet<Vec<Token>>), usize> = BTreeMap::new();
+        let mut new_classes = HashMap::with_capacity(classes.len());
+
+        for (nonterm, alternatives) in rules {
+            let signature =
+                alternatives.iter().map(|rhs| normalize(rhs, false, |nt| class_token(&classes, nt))).collect();
+            let next = signatures.len();
+            let class = *signatures.entry((classes[nonterm], signature)).or_insert(next);
+            new_classes.insert(*nonterm, class);
+        }
+
+        classes = new_classes;
+
+        /* Classes only get split, so the partition is stable once their number stays the same */
+        if signatures.len() == count {
+            return classes;
+        }
+
+        count = signatures.len();
+    }
+}
+
+/// Groups of at least two indices whose keys are equal and that contain at least two distinct `originals`
+fn groups<K: Ord, O: Ord>(keys: &[K], originals: &[O]) -> Vec<Vec<usize>> {
+    let mut groups: BTreeMap<&K, Vec<usize>> = BTreeMap::new();
+
+    for (i, key) in keys.iter().enumerate() {
+        groups.entry(key).or_default().push(i);
+    }
+
+    let mut groups: Vec<Vec<usize>> = groups
+        .into_values()
+        .filter(|group| group.iter().map(|i| &originals[*i]).collect::<BTreeSet<_>>().len() > 1)
+        .collect();
+    groups.sort();
+    groups
+}
+
+/// Find alternatives that waste mutation entropy because the unparser and the mutator cannot tell them apart,
+/// and non-terminals that only add an unnecessary level of indirection.
+///
+/// This is most useful on grammars that were built without optimizations, since [`GrammarBuilder::optimize`](crate::grammar::GrammarBuilder::optimize)
+/// removes duplicate alternatives and introduces many new non-terminals.
+pub fn lint(grammar: &ContextFreeGrammar) -> Vec<LintFinding> {
+    let rules: Vec<(&str, Vec<&[Symbol]>)> = grammar
+        .rules_by_nonterminal()
+        .into_iter()
+        .map(|(nonterm, rules)| (nonterm.id(), rules.into_iter().map(|rule| rule.rhs()).collect()))
+        .collect();
+    let classes = equivalence_classes(&rules);
+    let mut findings = Vec::new();
+
+    let mut report = |kind: LintKind, nonterm: &str, alternatives: Vec<usize>| {
+        findings.push(LintFinding {
+            severity: kind.severity(),
+            kind,
+            non_terminal: nonterm.to_string(),
+            alternatives,
+        });
+    };
+
+    for (nonterm, alternatives) in &rules {
+        let literal: Vec<Vec<Token>> =
+            alternatives.iter().map(|rhs| normalize(rhs, false, |nt| Token::Name(nt.id()))).collect();
+        let indices: Vec<usize> = (0..alternatives.len()).collect();
+
+        for group in groups(&literal, &indices) {
+            report(LintKind::DuplicateAlternatives, nonterm, group);
+        }
+
+        let equivalent: Vec<Vec<Token>> =
+            alternatives.iter().map(|rhs| normalize(rhs, false, |nt| class_token(&classes, nt))).collect();
+
+        for group in groups(&equivalent, &literal) {
+            report(LintKind::EquivalentAlternatives, nonterm, group);
+        }
+
+        let trimmed: Vec<Vec<Token>> =
+            alternatives.iter().map(|rhs| normalize(rhs, true, |nt| Token::Name(nt.id()))).collect();
+
+        for group in groups(&trimmed, &literal) {
+            report(LintKind::WhitespaceVariants, nonterm, group);
+        }
+
+        if let [rhs] = alternatives.as_slice() {
+            let is_recursive =
+                rhs.iter().any(|symbol| matches!(symbol, Symbol::NonTerminal(nt) if nt.id() == *nonterm));
+
+            if *nonterm != grammar.entrypoint().id() && !is_recursive && !rhs.iter().any(Symbol::is_dynamic) {
+                report(LintKind::InlinableNonTerminal, nonterm, vec![0]);
+            }
+        }
+    }
+
+    findings
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::grammar::tests::grammar;
+
+    fn kinds(findings: &[LintFinding]) -> Vec<(LintKind, &str, &[usize])> {
+        findings
+            .iter()
+            .map(|finding| (finding.kind, finding.non_terminal.as_str(), finding.alternatives.as_slice()))
+            .collect()
+    }
+
+    #[test]
+    fn test_duplicate_alternatives() {
+        let cfg = grammar(r#"{"<ENTRYPOINT>": [["'a'", "'b'"], ["'c'"], ["'ab'"], ["'a'", "''", "'b'"]]}"#);
+        let findings = lint(&cfg);
+        assert_eq!(kinds(&findings), vec![(LintKind::DuplicateAlternatives, "ENTRYPOINT", [0, 2, 3].as_slice())]);
+        assert_eq!(findings[0].severity, LintSeverity::Error);
+        assert_eq!(findings[0].to_string(), "error: <ENTRYPOINT>: alternatives #0, #2, #3 are identical");
+    }
+
+    #[test]
+    fn test_equivalent_alternatives() {
+        /* <A> and <B> derive the same strings, even though they are recursive */
+        let cfg = grammar(
+            r#"{
+                "<ENTRYPOINT>": [["'x'", "<A>"], ["'x'", "<B>"], ["'x'", "<C>"]],
+                "<A>": [["'a'", "<A>"], ["'b'"]],
+                "<B>": [["'a'", "<B>"], ["'b'"]],
+                "<C>": [["'a'", "<C>"], ["'c'"]]
+            }"#,
+        );
+        assert_eq!(kinds(&lint(&cfg)), vec![(LintKind::EquivalentAlternatives, "ENTRYPOINT", [0, 1].as_slice())]);
+    }
+
+    #[test]
+    fn test_whitespace_variants() {
+        let cfg = grammar(
+            r#"{
+                "<ENTRYPOINT>": [["'x'", "' '", "<A>"], ["'x'", "<A>"], ["'y '", "<A>"], ["'x'", "<A>", "' '"]],
+                "<A>": [["'a'"], ["'b'"]]
+            }"#,
+        );
+        let findings = lint(&cfg);
+        assert_eq!(kinds(&findings), vec![(LintKind::WhitespaceVariants, "ENTRYPOINT", [0, 1, 3].as_slice())]);
+        assert_eq!(findings[0].severity, LintSeverity::Warning);
+    }
+
+    #[test]
+    fn test_inlinable_non_terminals() {
+        let cfg = grammar(
+            r#"{
+                "<ENTRYPOINT>": [["<A>", "<B>"]],
+                "<A>": [["'a'", "<C>"]],
+                "<B>": [["'b'", "<B>"], ["'b'"]],
+                "<C>": [["'c'"], ["'d'"]]
+            }"#,
+        );
+        let findings = lint(&cfg);
+        assert_eq!(kinds(&findings), vec![(LintKind::InlinableNonTerminal, "A", [0].as_slice())]);
+        assert_eq!(findings[0].to_string(), "info: <A>: has a single alternative that could be inlined");
+
+        let cfg = grammar(r#"{"<ENTRYPOINT>": [["'a'"], ["'b'"]]}"#);
+        assert!(lint(&cfg).is_empty());
+    }
+}
@@ -30,8 +30,42 @@
 //! }
 //! ```
 
+mod ambiguity;
 mod builder;
+mod builtin;
 mod cfg;
+mod diff;
+pub mod edit;
+mod exclusion;
+mod lint;
+mod regular;
 
+pub use ambiguity::*;
 pub use builder::*;
+pub use builtin::*;
 pub use cfg::*;
+pub use diff::*;
+pub(crate) use exclusion::*;
+pub use lint::*;
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// Build the grammar in the peacock format `json` without optimizations.
+    pub(crate) fn grammar(json: &str) -> ContextFreeGrammar {
+        ContextFreeGrammar::builder().peacock_grammar_str(json).unwrap().optimize(false).build().unwrap()
+    }
+
+    /// A rule of `lhs`. Symbols in `rhs` that are enclosed in `<>` are non-terminals, all others are terminals.
+    pub(crate) fn rule(lhs: &str, rhs: &[&str]) -> ProductionRule {
+        let rhs = rhs
+            .iter()
+            .map(|symbol| match symbol.strip_prefix('<').and_then(|x| x.strip_suffix('>')) {
+                Some(nonterm) => Symbol::NonTerminal(NonTerminal::new(nonterm)),
+                None => Symbol::Terminal(Terminal::new(*symbol)),
+            })
+            .collect();
+        ProductionRule::new(NonTerminal::new(lhs), rhs)
+    }
+}
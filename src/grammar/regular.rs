@@ -0,0 +1,409 @@
+use indexmap::IndexMap;
+use petgraph::{
+    algo::tarjan_scc,
+    graphmap::DiGraphMap,
+};
+use std::collections::HashMap;
+
+use crate::grammar::{
+    ContextFreeGrammar,
+    ProductionRule,
+    Symbol,
+};
+
+type Rules<'a> = IndexMap<&'a str, Vec<&'a ProductionRule>>;
+
+/// A regular expression that keeps itself simplified while it is being built.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Regex {
+    /// Matches nothing
+    Never,
+    /// Matches the empty string
+    Empty,
+    Literal(String),
+    Concatenation(Vec<Regex>),
+    Alternation(Vec<Regex>),
+    Optional(Box<Regex>),
+    Star(Box<Regex>),
+}
+
+impl Regex {
+    fn concatenation<I: IntoIterator<Item = Regex>>(items: I) -> Self {
+        let mut result: Vec<Regex> = Vec::new();
+
+        for item in items {
+            match item {
+                Regex::Never => return Regex::Never,
+                Regex::Empty => {},
+                Regex::Concatenation(inner) => result.extend(inner),
+                Regex::Literal(s) => match result.last_mut() {
+                    Some(Regex::Literal(prev)) => prev.push_str(&s),
+                    _ => result.push(Regex::Literal(s)),
+                },
+                item => result.push(item),
+            }
+        }
+
+        match result.len() {
+            0 => Regex::Empty,
+            1 => result.pop().unwrap(),
+            _ => Regex::Concatenation(result),
+        }
+    }
+
+    fn alternation<I: IntoIterator<Item = Regex>>(items: I) -> Self {
+        let mut result: Vec<Regex> = Vec::new();
+        let mut optional = false;
+        let mut stack: Vec<Regex> = items.into_iter().collect();
+        stack.reverse();
+
+        while let Some(item) = stack.pop() {
+            match item {
+                Regex::Never => {},
+                Regex::Empty => optional = true,
+                Regex::Optional(inner) => {
+                    optional = true;
+                    stack.push(*inner);
+                },
+                Regex::Alternation(inner) => stack.extend(inner.into_iter().rev()),
+                item => {
+                    if !result.contains(&item) {
+                        result.push(item);
+                    }
+                },
+            }
+        }
+
+        let regex = match result.len() {
+            0 if optional => return Regex::Empty,
+            0 => return Regex::Never,
+            1 => result.pop().unwrap(),
+            _ => Regex::Alternation(result),
+        };
+
+        if optional && !matches!(regex, Regex::Star(_)) {
+            Regex::Optional(Box::new(regex))
+        } else {
+            regex
+        }
+    }
+
+    fn star(self) -> Self {
+        match self {
+            Regex::Never | Regex::Empty => Regex::Empty,
+            Regex::Star(_) => self,
+            Regex::Optional(inner) => inner.star(),
+            _ => Regex::Star(Box::new(self)),
+        }
+    }
+
+    /// Precedence levels: 0 = alternation, 1 = concatenation, 2 = operand of a quantifier
+    fn render(&self, precedence: usize, out: &mut String) {
+        match self {
+            Regex::Never => unreachable!("Never is eliminated before rendering"),
+            Regex::Empty => {},
+            Regex::Literal(s) => {
+                let group = precedence >= 2 && s.chars().count() > 1;
+                Self::group(group, out, |out| s.chars().for_each(|c| escape(c, out)));
+            },
+            Regex::Concatenation(items) => {
+                Self::group(precedence >= 2, out, |out| items.iter().for_each(|item| item.render(1, out)));
+            },
+            Regex::Alternation(items)
+                if items.iter().all(|item| matches!(item, Regex::Literal(s) if s.chars().count() == 1)) =>
+            {
+                let mut chars: Vec<char> = items
+                    .iter()
+                    .filter_map(|item| match item {
+                        Regex::Literal(s) => s.chars().next(),
+                        _ => None,
+                    })
+                    .collect();
+                chars.sort_unstable();
+                render_class(&chars, out);
+            },
+            Regex::Alternation(items) => {
+                Self::group(precedence >= 1, out, |out| {
+                    for (i, item) in items.iter().enumerate() {
+                        if i > 0 {
+                            out.push('|');
+                        }
+
+                        item.render(0, out);
+                    }
+                });
+            },
+            Regex::Optional(inner) => {
+                inner.render(2, out);
+                out.push('?');
+            },
+            Regex::Star(inner) => {
+                inner.render(2, out);
+                out.push('*');
+            },
+        }
+    }
+
+    fn group<F: FnOnce(&mut String)>(group: bool, out: &mut String, f: F) {
+        if group {
+            out.push('(');
+        }
+
+        f(out);
+
+        if group {
+            out.push(')');
+        }
+    }
+}
+
+/// Render sorted, distinct characters as a character class with ranges for runs of at least three characters
+fn render_class(chars: &[char], out: &mut String) {
+    out.push('[');
+
+    for run in chars.chunk_by(|a, b| *a as u32 + 1 == *b as u32) {
+        if run.len() >= 3 {
+            escape_in_class(run[0], out);
+            out.push('-');
+            escape_in_class(run[run.len() - 1], out);
+        } else {
+            run.iter().for_each(|c| escape_in_class(*c, out));
+        }
+    }
+
+    out.push(']');
+}
+
+fn escape_in_class(c: char, out: &mut String) {
+    match c {
+        '\\' | ']' | '[' | '^' | '-' => {
+            out.push('\\');
+            out.push(c);
+        },
+        c => escape(c, out),
+    }
+}
+
+fn escape(c: char, out: &mut String) {
+    match c {
+        '\n' => out.push_str("\\n"),
+        '\r' => out.push_str("\\r"),
+        '\t' => out.push_str("\\t"),
+        '\\' | '.' | '+' | '*' | '?' | '(' | ')' | '|' | '[' | ']' | '{' | '}' | '^' | '$' => {
+            out.push('\\');
+            out.push(c);
+        },
+        c if c.is_ascii_control() => out.push_str(&format!("\\x{:02x}", c as u32)),
+        c => out.push(c),
+    }
+}
+
+impl ContextFreeGrammar {
+    /// Convert this grammar into an equivalent regular expression, such that the same specification
+    /// can be used with regex-based tools. The expression must match the whole input.
+    ///
+    /// This succeeds if the grammar describes a regular language in a way that is recognizable:
+    /// non-terminals may only be recursive if all rules of their cycle are either right-linear
+    /// (`<A> -> 'a' <A>`) or left-linear (`<A> -> <A> 'a'`). Such cycles are turned into repetitions
+    /// by state elimination. Returns `None` for any other kind of recursion, e.g. nesting like
+    /// `<A> -> '(' <A> ')'`, if the grammar references dynamic terminals, bindings or builtins or if it cannot derive any input.
+    pub fn try_to_regex(&self) -> Option<String> {
+        let reachable = self.reachable_non_terminals(self.entrypoint().id());
+        let rules: Rules =
+            self.rules_by_nonterminal().into_iter().map(|(nonterm, rules)| (nonterm.id(), rules)).collect();
+        let mut graph = DiGraphMap::<&str, ()>::new();
+
+        for (nonterm, rules) in &rules {
+            if !reachable.contains(*nonterm) {
+                continue;
+            }
+
+            graph.add_node(nonterm);
+
+            for rule in rules {
+                for symbol in rule.rhs() {
+                    match symbol {
+                        Symbol::NonTerminal(child) => {
+                            graph.add_edge(nonterm, child.id(), ());
+                        },
+                        Symbol::Terminal(term) if term.is_dynamic() || term.builtin().is_some() => return None,
+                        Symbol::Terminal(term) if term.binding().is_some_and(|(op, _)| op.is_reference()) => {
+                            return None
+                        },
+                        Symbol::Terminal(_) => {},
+                    }
+                }
+            }
+        }
+
+        let mut solved: HashMap<&str, Regex> = HashMap::new();
+
+        /* Components come in reverse topological order, so all children outside of a component are solved already */
+        for mut component in tarjan_scc(&graph) {
+            component.sort_by_key(|nonterm| rules.get_index_of(nonterm));
+
+            let solution = solve_component(&component, &rules, &solved)?;
+            solved.extend(component.into_iter().zip(solution));
+        }
+
+        match solved.remove(self.entrypoint().id())? {
+            Regex::Never => None,
+            regex => {
+                let mut out = String::new();
+                regex.render(0, &mut out);
+                Some(out)
+            },
+        }
+    }
+}
+
+/// The equation `X = coefficients[0] X_0 | ... | coefficients[n] X_n | base` of a non-terminal of a
+/// right-linear component. In a left-linear component the coefficients come after the non-terminals.
+struct Equation {
+    coefficients: Vec<Regex>,
+    base: Regex,
+}
+
+/// Solve the equations of all non-terminals in a strongly connected component of the rule graph.
+/// Returns `None` if the component is neither right-linear nor left-linear.
+fn solve_component(component: &[&str], rules: &Rules, solved: &HashMap<&str, Regex>) -> Option<Vec<Regex>> {
+    let member = |id: &str| component.iter().position(|x| *x == id);
+    let symbol_regex = |symbol: &Symbol| match symbol {
+        Symbol::Terminal(term) if term.expanded().is_empty() => Regex::Empty,
+        Symbol::Terminal(term) => Regex::Literal(term.expanded().to_string()),
+        Symbol::NonTerminal(nonterm) => solved.get(nonterm.id()).cloned().unwrap_or(Regex::Never),
+    };
+
+    'linearity: for right in [true, false] {
+        let mut equations = Vec::with_capacity(component.len());
+
+        for nonterm in component {
+            let mut coefficients = vec![Vec::new(); component.len()];
+            let mut base = Vec::new();
+            for rule in rules.get(nonterm).into_iter().flatten() {
+                let rhs = rule.rhs();
+                let (recursion, rest) = match (right, rhs) {
+                    (true, [rest @ .., Symbol::NonTerminal(last)]) => (member(last.id()), rest),
+                    (false, [Symbol::NonTerminal(first), rest @ ..]) => (member(first.id()), rest),
+                    _ => (None, rhs),
+                };
+
+                /* A member anywhere else means that the component is not linear in this direction */
+                for symbol in if recursion.is_some() { rest } else { rhs } {
+                    if matches!(symbol, Symbol::NonTerminal(nonterm) if member(nonterm.id()).is_some()) {
+                        continue 'linearity;
+                    }
+                }
+
+                match recursion {
+                    Some(j) => coefficients[j].push(Regex::concatenation(rest.iter().map(symbol_regex))),
+                    None => base.push(Regex::concatenation(rhs.iter().map(symbol_regex))),
+                }
+            }
+
+            equations.push(Equation {
+                coefficients: coefficients.into_iter().map(Regex::alternation).collect(),
+                base: Regex::alternation(base),
+            });
+        }
+
+        /* Right-linear: X = c Y, left-linear: X = Y c */
+        let join = |outer: Regex, inner: Regex| {
+            if right {
+                Regex::concatenation([outer, inner])
+            } else {
+                Regex::concatenation([inner, outer])
+            }
+        };
+
+        /* State elimination with Arden's rule: X = c X | r  =>  X = c* r */
+        for k in 0..equations.len() {
+            let repetition = std::mem::replace(&mut equations[k].coefficients[k], Regex::Never).star();
+            let eq = &mut equations[k];
+
+            for coefficient in &mut eq.coefficients {
+                *coefficient = join(repetition.clone(), std::mem::replace(coefficient, Regex::Never));
+            }
+
+            eq.base = join(repetition, std::mem::replace(&mut eq.base, Regex::Never));
+
+            let coefficients = equations[k].coefficients.clone();
+            let base = equations[k].base.clone();
+
+            for (i, eq) in equations.iter_mut().enumerate() {
+                if i == k || eq.coefficients[k] == Regex::Never {
+                    continue;
+                }
+
+                let factor = std::mem::replace(&mut eq.coefficients[k], Regex::Never);
+
+                for (j, coefficient) in eq.coefficients.iter_mut().enumerate() {
+                    let substituted = join(factor.clone(), coefficients[j].clone());
+                    *coefficient = Regex::alternation([std::mem::replace(coefficient, Regex::Never), substituted]);
+                }
+
+                let substituted = join(factor, base.clone());
+                eq.base = Regex::alternation([std::mem::replace(&mut eq.base, Regex::Never), substituted]);
+            }
+        }
+
+        return Some(equations.into_iter().map(|eq| eq.base).collect());
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn regex_of(grammar: &str) -> Option<String> {
+        ContextFreeGrammar::builder()
+            .peacock_grammar_str(grammar)
+            .unwrap()
+            .optimize(false)
+            .build()
+            .unwrap()
+            .try_to_regex()
+    }
+
+    #[test]
+    fn test_finite_grammars() {
+        assert_eq!(regex_of(r#"{"<ENTRYPOINT>": [["'a'", "<B>"]], "<B>": [["'b'"], ["'cd'"]]}"#).unwrap(), "a(b|cd)");
+        assert_eq!(
+            regex_of(r#"{"<ENTRYPOINT>": [["'a'"], ["'-'"], ["'c'"], ["'b'"], ["'x'"]]}"#).unwrap(),
+            "[\\-a-cx]"
+        );
+        assert_eq!(regex_of(r#"{"<ENTRYPOINT>": [["'ab'", "<B>"]], "<B>": [["'c'"], ["''"]]}"#).unwrap(), "abc?");
+        assert_eq!(regex_of(r#"{"<ENTRYPOINT>": [["<B>", "'.'"]], "<B>": [["'xy'"], ["''"]]}"#).unwrap(), "(xy)?\\.");
+        assert_eq!(regex_of(r#"{"<ENTRYPOINT>": [["'x'"], ["'y'", "'z'"]]}"#).unwrap(), "x|yz");
+    }
+
+    #[test]
+    fn test_linear_recursion() {
+        let right = r#"{"<ENTRYPOINT>": [["'a'", "<ENTRYPOINT>"], ["'b'"]]}"#;
+        assert_eq!(regex_of(right).unwrap(), "a*b");
+
+        let left = r#"{"<ENTRYPOINT>": [["<ENTRYPOINT>", "'a'"], ["'b'"]]}"#;
+        assert_eq!(regex_of(left).unwrap(), "ba*");
+
+        let mutual = r#"{"<ENTRYPOINT>": [["'x'", "<A>"]], "<A>": [["'a'", "<B>"], ["''"]], "<B>": [["'b'", "<A>"]]}"#;
+        assert_eq!(regex_of(mutual).unwrap(), "x(a(ba)*b)?");
+
+        /* The result must be understood by the own regex parser */
+        for grammar in [right, left, mutual] {
+            let regex = regex_of(grammar).unwrap();
+            assert!(crate::parser::regex::compile("X", &regex).is_ok(), "{}", regex);
+        }
+    }
+
+    #[test]
+    fn test_context_free_grammars() {
+        assert_eq!(regex_of(r#"{"<ENTRYPOINT>": [["'('", "<ENTRYPOINT>", "')'"], ["'x'"]]}"#), None);
+        assert_eq!(regex_of(r#"{"<ENTRYPOINT>": [["<ENTRYPOINT>", "'+'", "<ENTRYPOINT>"], ["'x'"]]}"#), None);
+        assert_eq!(regex_of(r#"{"<ENTRYPOINT>": [["'a'", "<DYNAMIC>"]]}"#), None);
+
+        /* Recursion that is not reachable does not matter */
+        assert_eq!(regex_of(r#"{"<ENTRYPOINT>": [["'x'"]], "<A>": [["'('", "<A>", "')'"], ["'y'"]]}"#).unwrap(), "x");
+    }
+}
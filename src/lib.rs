@@ -8,7 +8,7 @@
 //!   - `json`: Convert loaded grammar(s) into peacock format
 //!   - `interpreter`: Generate inputs by interpreting a grammar
 //!
-//!   but you can easily write your own.
+//!   but you can easily write your own by implementing [`Backend`](backends::Backend).
 //! - __runtime__: LibAFL components that you can use in your fuzzer to realize grammar-based mutations.
 //!
 //! ## Grammars
@@ -45,15 +45,17 @@
 //! - `static-loading`: Activate this if you want to compile the generated C code into the fuzzer. For more details see the
 //!   documentation of the `components`.
 //! - `debug-codegen`: This affects the C backend and inserts calls to printf() at the beginning of every generated function to
-//!    help troubleshooting.
+//!   help troubleshooting.
 
 #![deny(missing_docs)]
 
 pub(crate) mod parser;
 
+pub mod analysis;
 pub mod backends;
 pub mod error;
 pub mod grammar;
+pub mod tree;
 
 #[cfg(feature = "components")]
 pub mod components;
@@ -1,7 +1,11 @@
+use indexmap::IndexMap;
 use serde_json as json;
 use std::{
     fs::File,
-    io::BufReader,
+    io::{
+        BufReader,
+        Read,
+    },
     path::Path,
 };
 
@@ -34,29 +38,47 @@ fn parse_until<F: FnMut(u8) -> bool>(buf: &[u8], mut delim: F) -> &[u8] {
     &buf[..cursor]
 }
 
-fn parse_grammar(value: json::Value) -> Result<Vec<ProductionRule>, String> {
-    let mut rules = Vec::new();
+/// The typed representation of a gramatron grammar: Non-terminals mapped to their variants.
+type Grammar = IndexMap<String, Vec<String>>;
 
+/// Convert a generic JSON value into a [`Grammar`] while reporting precise type errors.
+/// This is only used when deserializing directly into a [`Grammar`] fails.
+fn typed_grammar(value: json::Value) -> Result<Grammar, String> {
     let object = match value {
         json::Value::Object(object) => object,
         _ => return Err("Gramatron grammar must be specified as an object".to_string()),
     };
+    let mut grammar = Grammar::with_capacity(object.len());
 
-    for (key, value) in &object {
+    for (key, value) in object {
         let rhs = match value {
             json::Value::Array(rhs) => rhs,
             _ => return Err(format!("Right-hand-side of '{}' must be an array", key)),
         };
+        let mut variants = Vec::with_capacity(rhs.len());
+
+        for rule in rhs {
+            match rule {
+                json::Value::String(rule) => variants.push(rule),
+                _ => return Err(format!("Right-hand-side of '{}' must be an array of strings", key)),
+            }
+        }
+
+        grammar.insert(key, variants);
+    }
+
+    Ok(grammar)
+}
 
+fn parse_grammar(grammar: Grammar) -> Result<Vec<ProductionRule>, String> {
+    let mut rules = Vec::new();
+
+    for (key, rhs) in &grammar {
         if rhs.is_empty() {
             return Err(format!("Invalid production rule '{}': Must not be empty", key));
         }
 
         for rule in rhs {
-            let rule = match rule.as_str() {
-                Some(rule) => rule,
-                _ => return Err(format!("Right-hand-side of '{}' must be an array of strings", key)),
-            };
             let mut symbols = Vec::new();
             let rule = rule.as_bytes();
             let mut cursor = 0;
@@ -90,8 +112,9 @@ fn parse_grammar(value: json::Value) -> Result<Vec<ProductionRule>, String> {
                 }
             }
 
+            // A string with no tokens is an explicit Ɛ
             if symbols.is_empty() {
-                return Err(format!("Right-hand-side of '{}' must not contain a string with no tokens", key));
+                symbols.push(Symbol::Terminal(Terminal::new("")));
             }
 
             rules.push(ProductionRule::new(NonTerminal::new(key.clone()), symbols));
@@ -103,14 +126,31 @@ fn parse_grammar(value: json::Value) -> Result<Vec<ProductionRule>, String> {
 
 pub fn parse_json(path: &Path) -> Result<Vec<ProductionRule>, ParsingError> {
     let file = File::open(path).unwrap();
-    let reader = BufReader::new(file);
+    let mut reader = BufReader::new(file);
+    let mut content = String::new();
+
+    if reader.read_to_string(&mut content).is_err() {
+        return Err(ParsingError::new(path, "Invalid JSON syntax"));
+    }
+
+    parse_str(&content, path)
+}
 
-    let value: json::Value = match json::from_reader(reader) {
-        Ok(value) => value,
+/// Parse a grammar that is already in memory. `origin` only appears in error messages.
+pub fn parse_str(content: &str, origin: &Path) -> Result<Vec<ProductionRule>, ParsingError> {
+    let grammar = match json::from_str::<Grammar>(content) {
+        Ok(grammar) => grammar,
         Err(_) => {
-            return Err(ParsingError::new(path, "Invalid JSON syntax"));
+            let value: json::Value = match json::from_str(content) {
+                Ok(value) => value,
+                Err(_) => {
+                    return Err(ParsingError::new(origin, "Invalid JSON syntax"));
+                },
+            };
+
+            typed_grammar(value).map_err(|e| ParsingError::new(origin, e))?
         },
     };
 
-    parse_grammar(value).map_err(|e| ParsingError::new(path, e))
+    parse_grammar(grammar).map_err(|e| ParsingError::new(origin, e))
 }
@@ -1,2 +1,4 @@
 pub mod gramatron;
 pub mod peacock;
+pub(crate) mod regex;
+pub mod table;
@@ -1,22 +1,37 @@
+use indexmap::IndexMap;
 use json_comments::{
     CommentSettings,
     StripComments,
 };
+use serde::Deserialize;
 use serde_json as json;
 use std::{
+    collections::HashSet,
     fs::File,
-    io::BufReader,
-    path::Path,
+    io::{
+        BufReader,
+        Read,
+    },
+    path::{
+        Path,
+        PathBuf,
+    },
 };
 
 use crate::{
     error::ParsingError,
     grammar::{
+        BindingOp,
+        Builtin,
+        Exclusion,
         NonTerminal,
         ProductionRule,
         Symbol,
         Terminal,
+        MAX_BUILTIN_BYTES,
+        MAX_REPEAT_COUNT,
     },
+    parser::regex,
 };
 
 fn parse_non_terminal(keyword: &str) -> Option<&str> {
@@ -27,62 +42,355 @@ fn parse_non_terminal(keyword: &str) -> Option<&str> {
     }
 }
 
-fn parse_terminal(keyword: &str) -> &str {
+/// Terminals are either enclosed in `''` and taken verbatim or enclosed in `""`,
+/// where `\"` and `\\` stand for a double quote and a backslash.
+fn parse_terminal(keyword: &str) -> Result<String, String> {
     if keyword.len() >= 2 && keyword.starts_with('\'') && keyword.ends_with('\'') {
-        &keyword[1..keyword.len() - 1]
-    } else {
-        keyword
+        return Ok(keyword[1..keyword.len() - 1].to_string());
     }
+
+    if keyword.len() < 2 || !keyword.starts_with('"') || !keyword.ends_with('"') {
+        return Ok(keyword.to_string());
+    }
+
+    let mut content = String::with_capacity(keyword.len() - 2);
+    let mut chars = keyword[1..keyword.len() - 1].chars();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some(c @ ('"' | '\\')) => content.push(c),
+                Some(c) => return Err(format!("Invalid escape sequence '\\{}' in terminal {}", c, keyword)),
+                None => return Err(format!("Terminal {} ends with an unfinished escape sequence", keyword)),
+            },
+            '"' => return Err(format!("Unescaped '\"' in terminal {}", keyword)),
+            c => content.push(c),
+        }
+    }
+
+    Ok(content)
 }
 
-fn parse_grammar(value: json::Value) -> Result<Vec<ProductionRule>, String> {
-    let mut rules = Vec::new();
+/// `@regex(...)` terminals are desugared into rules of a non-terminal with the same name.
+fn parse_regex(keyword: &str) -> Option<&str> {
+    keyword.strip_prefix("@regex(")?.strip_suffix(')')
+}
+
+/// `@repeat('A', 4096)` terminals stand for a run of a single ASCII character.
+/// Returns `None` if `keyword` is not of that form.
+fn parse_repeat(keyword: &str) -> Option<Result<Terminal, String>> {
+    let args = keyword.strip_prefix("@repeat(")?.strip_suffix(')')?;
+
+    let Some((byte, count)) = args.rsplit_once(',') else {
+        return Some(Err(format!("Invalid terminal {}: Expected a character and a count", keyword)));
+    };
+    let byte = match parse_terminal(byte.trim()) {
+        Ok(byte) if byte.len() == 1 && byte.is_ascii() => byte.as_bytes()[0],
+        Ok(_) => {
+            return Some(Err(format!("Invalid terminal {}: Only single ASCII characters can be repeated", keyword)))
+        },
+        Err(err) => return Some(Err(err)),
+    };
+    let Ok(count) = count.trim().parse::<usize>() else {
+        return Some(Err(format!("Invalid terminal {}: '{}' is not a valid count", keyword, count.trim())));
+    };
+
+    if count > MAX_REPEAT_COUNT {
+        return Some(Err(format!(
+            "Invalid terminal {}: At most {} repetitions are supported",
+            keyword, MAX_REPEAT_COUNT
+        )));
+    }
+
+    Some(Ok(Terminal::repeat(byte, count)))
+}
+
+/// Binding names may not be empty or contain whitespace or parentheses, such that markers like `@use(name)`
+/// can be written back unambiguously.
+fn check_binding_name(name: &str) -> Result<&str, String> {
+    if name.is_empty() || name.contains(|c: char| c.is_whitespace() || c == '(' || c == ')') {
+        return Err(format!("Invalid binding name '{}'", name));
+    }
+
+    Ok(name)
+}
 
+/// `@begin(name)`, `@end(name)`, `@use(name)` and `@pop(name)` are binding markers.
+/// Returns `None` if `keyword` is not of that form.
+fn parse_binding(keyword: &str) -> Option<Result<Terminal, String>> {
+    let (keyword, args) = keyword.strip_prefix('@')?.strip_suffix(')')?.split_once('(')?;
+    let op = [BindingOp::Begin, BindingOp::End, BindingOp::Use, BindingOp::Pop]
+        .into_iter()
+        .find(|op| op.keyword() == keyword)?;
+
+    Some(check_binding_name(args).map(|name| Terminal::marker(op, name)))
+}
+
+/// `@int(min, max)` and `@bytes(min, max)` are builtins whose content is chosen from a range.
+/// Returns `None` if `keyword` is not of that form.
+fn parse_builtin(keyword: &str) -> Option<Result<Terminal, String>> {
+    let (name, args) = keyword.strip_prefix('@')?.strip_suffix(')')?.split_once('(')?;
+
+    if name != "int" && name != "bytes" {
+        return None;
+    }
+
+    let error = |reason: String| Some(Err(format!("Invalid terminal {}: {}", keyword, reason)));
+
+    let Some((min, max)) = args.split_once(',') else {
+        return error("Expected a minimum and a maximum".to_string());
+    };
+    let (min, max) = (min.trim(), max.trim());
+
+    let builtin = if name == "int" {
+        match (min.parse::<i64>(), max.parse::<i64>()) {
+            (Ok(min), Ok(max)) if min <= max => Builtin::Int {
+                min,
+                max,
+            },
+            (Ok(_), Ok(_)) => return error("The minimum is larger than the maximum".to_string()),
+            (Err(_), _) => return error(format!("'{}' is not a valid bound", min)),
+            (_, Err(_)) => return error(format!("'{}' is not a valid bound", max)),
+        }
+    } else {
+        match (min.parse::<usize>(), max.parse::<usize>()) {
+            (Ok(_), Ok(max)) if max > MAX_BUILTIN_BYTES => {
+                return error(format!("At most {} bytes are supported", MAX_BUILTIN_BYTES))
+            },
+            (Ok(min), Ok(max)) if min <= max => Builtin::Bytes {
+                min,
+                max,
+            },
+            (Ok(_), Ok(_)) => return error("The minimum is larger than the maximum".to_string()),
+            (Err(_), _) => return error(format!("'{}' is not a valid bound", min)),
+            (_, Err(_)) => return error(format!("'{}' is not a valid bound", max)),
+        }
+    };
+
+    Some(Ok(Terminal::from_builtin(builtin)))
+}
+
+/// A single element of a variant.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum Token {
+    /// A terminal, non-terminal or regex
+    Symbol(String),
+
+    /// Everything that `from` derives except for the terminals in `not`
+    Exclusion { not: Vec<String>, from: String },
+
+    /// The symbol `def`, whose bytes get bound to the name `bind`
+    Binding { bind: String, def: String },
+
+    /// The bytes of the innermost binding of a name, `pop` also removes the binding
+    Use {
+        #[serde(rename = "use")]
+        name: String,
+        #[serde(default)]
+        pop: bool,
+    },
+}
+
+/// The typed representation of a peacock grammar: Non-terminals mapped to their variants.
+type Grammar = IndexMap<String, Vec<Vec<Token>>>;
+
+/// The key that lists the files whose rules are merged into a grammar.
+const IMPORT_KEY: &str = "$import";
+
+/// The key of the metadata of a grammar, e.g. its name, version and description.
+pub const META_KEY: &str = "$meta";
+
+/// Keys that start with a `$` are directives or metadata and not non-terminals.
+fn is_directive(key: &str) -> bool {
+    key.starts_with('$')
+}
+
+/// Remove the list of imported files from a grammar object.
+/// The other keys keep their order because it determines the order of the rules.
+fn take_imports(value: &mut json::Value) -> Result<Vec<String>, String> {
+    let Some(object) = value.as_object_mut() else {
+        return Ok(Vec::new());
+    };
+    let Some(imports) = object.get(IMPORT_KEY).cloned() else {
+        return Ok(Vec::new());
+    };
+    object.retain(|key, _| key != IMPORT_KEY);
+
+    match json::from_value(imports) {
+        Ok(imports) => Ok(imports),
+        Err(_) => Err(format!("'{}' must be an array of file names", IMPORT_KEY)),
+    }
+}
+
+/// Convert a generic JSON value into a [`Grammar`] while reporting precise type errors.
+/// This is only used when deserializing directly into a [`Grammar`] fails.
+fn typed_grammar(value: json::Value) -> Result<Grammar, String> {
     let object = match value {
         json::Value::Object(object) => object,
         _ => return Err("Peacock grammar must be specified as an object".to_string()),
     };
+    let mut grammar = Grammar::with_capacity(object.len());
 
-    for (key, value) in &object {
-        // LHS must be a non-terminal
-        let lhs = match parse_non_terminal(key) {
-            Some(lhs) => lhs,
-            None => return Err(format!("'{}' is not a valid non-terminal", key)),
-        };
+    for (key, value) in object {
+        if is_directive(&key) {
+            continue;
+        }
 
-        // RHS must be an array of an array of strings that are either terminals or non-terminals
         let rhs = match value {
             json::Value::Array(rhs) => rhs,
             _ => return Err(format!("Right-hand-side of '{}' must be an array", key)),
         };
-
-        if rhs.is_empty() {
-            return Err(format!("Invalid production rule '{}': Must not be empty", key));
-        }
+        let mut variants = Vec::with_capacity(rhs.len());
 
         for rule in rhs {
             let tokens = match rule {
                 json::Value::Array(tokens) => tokens,
                 _ => return Err(format!("Right-hand-side of '{}' must be an array of arrays", key)),
             };
+            let mut variant = Vec::with_capacity(tokens.len());
 
-            if tokens.is_empty() {
-                return Err(format!("Invalid production rule '{}': One of its variants is empty", key));
+            for token in tokens {
+                match token {
+                    json::Value::String(token) => variant.push(Token::Symbol(token)),
+                    json::Value::Object(object) => {
+                        let (kind, form) = if object.contains_key("bind") {
+                            ("Binding", "{\"bind\": name, \"def\": symbol}")
+                        } else if object.contains_key("use") {
+                            ("Use", "{\"use\": name, \"pop\": bool}")
+                        } else {
+                            ("Exclusion", "{\"not\": [terminals...], \"from\": non-terminal}")
+                        };
+
+                        match json::from_value(json::Value::Object(object)) {
+                            Ok(token) => variant.push(token),
+                            Err(_) => return Err(format!("{} in '{}' must be of the form {}", kind, key, form)),
+                        }
+                    },
+                    _ => {
+                        return Err(format!(
+                            "Right-hand-side of '{}' must be an array of arrays of strings or exclusions",
+                            key
+                        ))
+                    },
+                }
             }
 
-            let mut symbols = Vec::new();
+            variants.push(variant);
+        }
+
+        grammar.insert(key, variants);
+    }
+
+    Ok(grammar)
+}
+
+/// `{"not": [...], "from": "<A>"}` becomes a non-terminal whose rules are created by the builder.
+fn parse_exclusion(not: &[String], from: &str) -> Result<Exclusion, String> {
+    let Some(from_id) = parse_non_terminal(from) else {
+        return Err(format!("Exclusions must be from a non-terminal but got '{}'", from));
+    };
+    let mut excluded = Vec::with_capacity(not.len());
+
+    for token in not {
+        if parse_non_terminal(token).is_some()
+            || parse_regex(token).is_some()
+            || parse_repeat(token).is_some()
+            || parse_binding(token).is_some()
+            || parse_builtin(token).is_some()
+        {
+            return Err(format!("Exclusions can only exclude terminals but got '{}'", token));
+        }
+
+        excluded.push(parse_terminal(token)?);
+    }
+
+    let name = format!("{}\\{{{}}}", from_id, not.join(","));
+    Ok(Exclusion::new(NonTerminal::new(name), NonTerminal::new(from_id), excluded))
+}
+
+/// Parse a terminal, non-terminal or regex. The rules of regexes that were not seen before are added to `rules`.
+fn parse_symbol<'a>(
+    token: &'a str,
+    rules: &mut Vec<ProductionRule>,
+    regexes: &mut HashSet<&'a str>,
+) -> Result<Symbol, String> {
+    if let Some(nonterm) = parse_non_terminal(token) {
+        Ok(Symbol::NonTerminal(NonTerminal::new(nonterm)))
+    } else if let Some(pattern) = parse_regex(token) {
+        if regexes.insert(token) {
+            rules.extend(regex::compile(token, pattern)?);
+        }
+
+        Ok(Symbol::NonTerminal(NonTerminal::new(token)))
+    } else if let Some(term) = parse_repeat(token) {
+        Ok(Symbol::Terminal(term?))
+    } else if let Some(term) = parse_binding(token) {
+        Ok(Symbol::Terminal(term?))
+    } else if let Some(term) = parse_builtin(token) {
+        Ok(Symbol::Terminal(term?))
+    } else {
+        Ok(Symbol::Terminal(Terminal::new(parse_terminal(token)?)))
+    }
+}
+
+fn parse_grammar(grammar: Grammar) -> Result<(Vec<ProductionRule>, Vec<Exclusion>), String> {
+    let mut rules = Vec::new();
+    let mut regexes = HashSet::new();
+    let mut exclusions = Vec::new();
+
+    for (key, rhs) in &grammar {
+        if is_directive(key) {
+            continue;
+        }
+
+        // LHS must be a non-terminal
+        let lhs = match parse_non_terminal(key) {
+            Some(lhs) => lhs,
+            None => return Err(format!("'{}' is not a valid non-terminal", key)),
+        };
+
+        // RHS must be an array of an array of strings that are either terminals or non-terminals
+        if rhs.is_empty() {
+            return Err(format!("Invalid production rule '{}': Must not be empty", key));
+        }
+
+        for tokens in rhs {
+            let mut symbols = Vec::with_capacity(tokens.len());
+
+            // An empty variant is an explicit Ɛ
+            if tokens.is_empty() {
+                symbols.push(Symbol::Terminal(Terminal::new("")));
+            }
 
             for token in tokens {
-                let token = match token.as_str() {
-                    Some(token) => token,
-                    _ => return Err(format!("Right-hand-side of '{}' must be an array of arrays of strings", key)),
-                };
-
-                if let Some(nonterm) = parse_non_terminal(token) {
-                    symbols.push(Symbol::NonTerminal(NonTerminal::new(nonterm)));
-                } else {
-                    let term = parse_terminal(token);
-                    symbols.push(Symbol::Terminal(Terminal::new(term)));
+                match token {
+                    Token::Symbol(token) => symbols.push(parse_symbol(token, &mut rules, &mut regexes)?),
+                    Token::Exclusion {
+                        not,
+                        from,
+                    } => {
+                        let exclusion = parse_exclusion(not, from)?;
+                        symbols.push(Symbol::NonTerminal(exclusion.name().clone()));
+                        exclusions.push(exclusion);
+                    },
+                    /* Bindings are desugared into their markers around the definition */
+                    Token::Binding {
+                        bind,
+                        def,
+                    } => {
+                        let name = check_binding_name(bind)?;
+                        symbols.push(Symbol::Terminal(Terminal::marker(BindingOp::Begin, name)));
+                        symbols.push(parse_symbol(def, &mut rules, &mut regexes)?);
+                        symbols.push(Symbol::Terminal(Terminal::marker(BindingOp::End, name)));
+                    },
+                    Token::Use {
+                        name,
+                        pop,
+                    } => {
+                        let op = if *pop { BindingOp::Pop } else { BindingOp::Use };
+                        symbols.push(Symbol::Terminal(Terminal::marker(op, check_binding_name(name)?)));
+                    },
                 }
             }
 
@@ -90,22 +398,121 @@ fn parse_grammar(value: json::Value) -> Result<Vec<ProductionRule>, String> {
         }
     }
 
-    Ok(rules)
+    Ok((rules, exclusions))
+}
+
+/// Keeps track of the imported files while a grammar is being loaded.
+#[derive(Default)]
+struct Imports {
+    /// The chain of files that led to the file that is being parsed
+    stack: Vec<PathBuf>,
+
+    /// Files that were already imported and are not imported again
+    loaded: HashSet<PathBuf>,
+}
+
+type Rules = (Vec<ProductionRule>, Vec<Exclusion>);
+
+pub fn parse_json(path: &Path) -> Result<Rules, ParsingError> {
+    parse_file(path, &mut Imports::default())
 }
 
-pub fn parse_json(path: &Path) -> Result<Vec<ProductionRule>, ParsingError> {
-    let file = File::open(path).unwrap();
-    let reader = BufReader::new(file);
-    let reader = StripComments::with_settings(CommentSettings::c_style(), reader);
+/// The value of the [`META_KEY`] of the grammar in `path` or `None` if it has no metadata.
+/// The metadata of imported files is not included.
+pub fn parse_metadata(path: &Path) -> Result<Option<json::Value>, ParsingError> {
+    let Ok(content) = std::fs::read_to_string(path) else {
+        return Err(ParsingError::new(path, "Cannot open file"));
+    };
+    let reader = StripComments::with_settings(CommentSettings::c_style(), content.as_bytes());
 
-    let value: json::Value = match json::from_reader(reader) {
-        Ok(value) => value,
+    match json::from_reader::<_, json::Value>(reader) {
+        Ok(json::Value::Object(mut object)) => Ok(object.remove(META_KEY)),
+        Ok(_) => Err(ParsingError::new(path, "Peacock grammar must be specified as an object")),
+        Err(_) => Err(ParsingError::new(path, "Invalid JSON syntax")),
+    }
+}
+
+fn parse_file(path: &Path, imports: &mut Imports) -> Result<Rules, ParsingError> {
+    let Ok(file) = File::open(path) else {
+        return Err(ParsingError::new(path, "Cannot open file"));
+    };
+    let mut reader = BufReader::new(file);
+    let mut content = String::new();
+
+    if reader.read_to_string(&mut content).is_err() {
+        return Err(ParsingError::new(path, "Invalid JSON syntax"));
+    }
+
+    parse_content(&content, path, imports)
+}
+
+/// Parse a grammar that is already in memory. `origin` only appears in error messages and
+/// imports are resolved relative to its directory.
+pub fn parse_str(content: &str, origin: &Path) -> Result<Rules, ParsingError> {
+    parse_content(content, origin, &mut Imports::default())
+}
+
+fn parse_content(content: &str, origin: &Path, imports: &mut Imports) -> Result<Rules, ParsingError> {
+    let mut reader = StripComments::with_settings(CommentSettings::c_style(), content.as_bytes());
+    let mut content = String::new();
+
+    if reader.read_to_string(&mut content).is_err() {
+        return Err(ParsingError::new(origin, "Invalid JSON syntax"));
+    }
+
+    let (grammar, imported) = match json::from_str::<Grammar>(&content) {
+        Ok(grammar) => (grammar, Vec::new()),
         Err(_) => {
-            return Err(ParsingError::new(path, "Invalid JSON syntax"));
+            let mut value: json::Value = match json::from_str(&content) {
+                Ok(value) => value,
+                Err(_) => {
+                    return Err(ParsingError::new(origin, "Invalid JSON syntax"));
+                },
+            };
+            let imported = take_imports(&mut value).map_err(|e| ParsingError::new(origin, e))?;
+
+            (typed_grammar(value).map_err(|e| ParsingError::new(origin, e))?, imported)
         },
     };
 
-    parse_grammar(value).map_err(|e| ParsingError::new(path, e))
+    let (mut rules, mut exclusions) = parse_grammar(grammar).map_err(|e| ParsingError::new(origin, e))?;
+
+    if imported.is_empty() {
+        return Ok((rules, exclusions));
+    }
+
+    /* Grammars from strings have no file to detect cycles with */
+    let canonical = std::fs::canonicalize(origin).ok();
+    imports.stack.extend(canonical.clone());
+    let directory = origin.parent().unwrap_or(Path::new(""));
+
+    for file in imported {
+        let path = directory.join(&file);
+        let Ok(canonical) = std::fs::canonicalize(&path) else {
+            return Err(ParsingError::new(origin, format!("Cannot import '{}'", file)));
+        };
+
+        if let Some(position) = imports.stack.iter().position(|other| other == &canonical) {
+            let cycle: Vec<String> =
+                imports.stack[position..].iter().chain([&canonical]).map(|path| path.display().to_string()).collect();
+            return Err(ParsingError::new(origin, format!("Import cycle: {}", cycle.join(" -> "))));
+        }
+
+        /* Files that are imported by multiple files only contribute their rules once */
+        if !imports.loaded.insert(canonical) {
+            continue;
+        }
+
+        let (mut new_rules, mut new_exclusions) = parse_file(&path, imports)?;
+        rules.append(&mut new_rules);
+        exclusions.append(&mut new_exclusions);
+    }
+
+    if canonical.is_some() {
+        imports.stack.pop();
+    }
+
+    Ok((rules, exclusions))
 }
 
 #[cfg(test)]
@@ -116,4 +523,233 @@ mod tests {
     fn test_peacock() {
         println!("{:#?}", parse_json(Path::new("test-data/grammars/test-peacock.json")).unwrap());
     }
+
+    #[test]
+    fn test_regex() {
+        let (rules, _) = parse_json(Path::new("test-data/grammars/regex.json")).unwrap();
+        let identifier = NonTerminal::new("@regex([a-zA-Z_][a-zA-Z0-9_]*)");
+        assert_eq!(rules.iter().filter(|rule| rule.lhs() == &identifier).count(), 1);
+        assert!(rules.iter().any(|rule| rule.lhs().id() == "@regex(-?(0|[1-9]\\d{0,3}))$1"));
+
+        let grammar = indexmap::indexmap! {
+            "<A>".to_string() => vec![vec![Token::Symbol("@regex(a{1,2}?)".to_string())]],
+        };
+        assert_eq!(parse_grammar(grammar).unwrap_err(), "Invalid regex 'a{1,2}?': Unsupported lazy quantifier");
+    }
+
+    #[test]
+    fn test_quotes() {
+        let grammar = indexmap::indexmap! {
+            "<A>".to_string() => vec![vec![
+                Token::Symbol("'it'".to_string()),
+                Token::Symbol("\"'s\"".to_string()),
+                Token::Symbol("\"<B>\"".to_string()),
+                Token::Symbol("\" say \\\"hi\\\" \\\\ \"".to_string()),
+                Token::Symbol("'\"'".to_string()),
+                Token::Symbol("\"\"".to_string()),
+            ]],
+        };
+        let (rules, _) = parse_grammar(grammar).unwrap();
+        let terminals: Vec<String> = rules[0]
+            .rhs()
+            .iter()
+            .map(|symbol| match symbol {
+                Symbol::Terminal(term) => term.expanded().into_owned(),
+                Symbol::NonTerminal(_) => unreachable!(),
+            })
+            .collect();
+        assert_eq!(terminals, ["it", "'s", "<B>", " say \"hi\" \\ ", "\"", ""]);
+
+        assert_eq!(parse_terminal("\"a\\n\"").unwrap_err(), "Invalid escape sequence '\\n' in terminal \"a\\n\"");
+        assert_eq!(parse_terminal("\"a\"b\"").unwrap_err(), "Unescaped '\"' in terminal \"a\"b\"");
+        assert_eq!(parse_terminal("\"a\\\"").unwrap_err(), "Terminal \"a\\\" ends with an unfinished escape sequence");
+
+        let exclusion = parse_exclusion(&["\"it's\"".to_string(), "'if'".to_string()], "<A>").unwrap();
+        assert_eq!(exclusion.from().id(), "A");
+    }
+
+    #[test]
+    fn test_repeat() {
+        let grammar = indexmap::indexmap! {
+            "<A>".to_string() => vec![vec![
+                Token::Symbol("@repeat('A', 4096)".to_string()),
+                Token::Symbol("@repeat(\",\",3)".to_string()),
+                Token::Symbol("@repeat('x', 0)".to_string()),
+            ]],
+        };
+        let (rules, _) = parse_grammar(grammar).unwrap();
+        let rhs = rules[0].rhs();
+        assert!(matches!(&rhs[0], Symbol::Terminal(term) if term.run() == Some((b'A', 4096)) && term.len() == 4096));
+        assert!(matches!(&rhs[1], Symbol::Terminal(term) if term.content().is_empty() && term.expanded() == ",,,"));
+        assert!(rhs[2].is_epsilon());
+
+        for (token, err) in [
+            ("@repeat('AB', 2)", "Only single ASCII characters can be repeated"),
+            ("@repeat('A')", "Expected a character and a count"),
+            ("@repeat('A', -1)", "'-1' is not a valid count"),
+            ("@repeat('A', 16777217)", "At most 16777216 repetitions are supported"),
+        ] {
+            let grammar = indexmap::indexmap! {
+                "<A>".to_string() => vec![vec![Token::Symbol(token.to_string())]],
+            };
+            assert!(parse_grammar(grammar).unwrap_err().ends_with(err), "{}", token);
+        }
+    }
+
+    #[test]
+    fn test_builtins() {
+        let grammar = indexmap::indexmap! {
+            "<A>".to_string() => vec![vec![
+                Token::Symbol("@int(-10, 1000)".to_string()),
+                Token::Symbol("@bytes(0,64)".to_string()),
+                Token::Symbol("'x'".to_string()),
+            ]],
+        };
+        let (rules, _) = parse_grammar(grammar).unwrap();
+        let rhs = rules[0].rhs();
+        let int = Builtin::Int {
+            min: -10,
+            max: 1000,
+        };
+        let bytes = Builtin::Bytes {
+            min: 0,
+            max: 64,
+        };
+        assert!(matches!(&rhs[0], Symbol::Terminal(term) if term.builtin() == Some(&int)));
+        assert!(matches!(&rhs[1], Symbol::Terminal(term) if term.builtin() == Some(&bytes)));
+        assert!(!rhs[0].is_epsilon() && rhs[0].is_builtin() && !rhs[2].is_builtin());
+
+        for (token, err) in [
+            ("@int(5, 4)", "The minimum is larger than the maximum"),
+            ("@int(0)", "Expected a minimum and a maximum"),
+            ("@int(0, 1e3)", "'1e3' is not a valid bound"),
+            ("@bytes(-1, 4)", "'-1' is not a valid bound"),
+            ("@bytes(0, 65536)", "At most 65535 bytes are supported"),
+        ] {
+            let grammar = indexmap::indexmap! {
+                "<A>".to_string() => vec![vec![Token::Symbol(token.to_string())]],
+            };
+            assert!(parse_grammar(grammar).unwrap_err().ends_with(err), "{}", token);
+        }
+
+        assert!(parse_exclusion(&["@int(0, 1)".to_string()], "<A>").is_err());
+    }
+
+    #[test]
+    fn test_exclusion() {
+        let (rules, exclusions) = parse_json(Path::new("test-data/grammars/exclusion.json")).unwrap();
+        assert_eq!(exclusions.len(), 1);
+        assert_eq!(exclusions[0].name().id(), "IDENTIFIER\\{'if','in','fin','f'}");
+        assert_eq!(exclusions[0].from().id(), "IDENTIFIER");
+        assert_eq!(rules[0].rhs(), &[Symbol::NonTerminal(exclusions[0].name().clone())]);
+
+        assert_eq!(
+            parse_exclusion(&["<B>".to_string()], "<A>").unwrap_err(),
+            "Exclusions can only exclude terminals but got '<B>'"
+        );
+        assert_eq!(parse_exclusion(&[], "'a'").unwrap_err(), "Exclusions must be from a non-terminal but got ''a''");
+    }
+
+    #[test]
+    fn test_bindings() {
+        let (rules, _) = parse_json(Path::new("test-data/grammars/xml.json")).unwrap();
+        let markers: Vec<String> = rules[1]
+            .rhs()
+            .iter()
+            .filter_map(|symbol| match symbol {
+                Symbol::Terminal(term) => term.binding().map(|(op, name)| format!("@{}({})", op.keyword(), name)),
+                Symbol::NonTerminal(_) => None,
+            })
+            .collect();
+        assert_eq!(markers, ["@begin(tag)", "@end(tag)", "@pop(tag)"]);
+        assert_eq!(rules[1].rhs()[2], Symbol::NonTerminal(NonTerminal::new("NAME")));
+
+        let grammar = indexmap::indexmap! {
+            "<A>".to_string() => vec![vec![
+                Token::Symbol("@begin(id)".to_string()),
+                Token::Symbol("@end(id)".to_string()),
+                Token::Use {
+                    name: "id".to_string(),
+                    pop: false,
+                },
+            ]],
+        };
+        let (rules, _) = parse_grammar(grammar).unwrap();
+        assert!(matches!(&rules[0].rhs()[2], Symbol::Terminal(term) if term.binding() == Some((BindingOp::Use, "id"))));
+
+        for (token, err) in [
+            (Token::Symbol("@use(a b)".to_string()), "Invalid binding name 'a b'"),
+            (
+                Token::Binding {
+                    bind: String::new(),
+                    def: "<B>".to_string(),
+                },
+                "Invalid binding name ''",
+            ),
+        ] {
+            let grammar = indexmap::indexmap! {
+                "<A>".to_string() => vec![vec![token]],
+            };
+            assert_eq!(parse_grammar(grammar).unwrap_err(), err);
+        }
+
+        assert_eq!(
+            typed_grammar(json::json!({"<A>": [[{"bind": "tag"}]]})).unwrap_err(),
+            "Binding in '<A>' must be of the form {\"bind\": name, \"def\": symbol}"
+        );
+    }
+
+    #[test]
+    fn test_imports() {
+        let (rules, _) = parse_json(Path::new("test-data/grammars/imports/main.json")).unwrap();
+        let lhs: Vec<&str> = rules.iter().map(|rule| rule.lhs().id()).collect();
+        assert_eq!(lhs, ["ENTRYPOINT", "ENTRYPOINT", "STATEMENT", "NUMBER", "NUMBER", "IDENTIFIER"]);
+
+        let err = parse_json(Path::new("test-data/grammars/imports/cycle-a.json")).unwrap_err().to_string();
+        assert!(err.contains("Import cycle: "), "{}", err);
+        assert!(err.ends_with("cycle-a.json"), "{}", err);
+
+        let err = parse_str(r#"{"$import": ["missing.json"], "<A>": [["'a'"]]}"#, Path::new("<string>")).unwrap_err();
+        assert_eq!(err.to_string(), "ParsingError in <string>: Cannot import 'missing.json'");
+
+        let err = parse_str(r#"{"$import": "common.json", "<A>": [["'a'"]]}"#, Path::new("<string>")).unwrap_err();
+        assert_eq!(err.to_string(), "ParsingError in <string>: '$import' must be an array of file names");
+    }
+
+    #[test]
+    fn test_directives() {
+        let path = Path::new("<string>");
+        let (rules, _) = parse_str(r#"{"$meta": {"name": "a"}, "<A>": [["'a'"]], "$other": [["'b'"]]}"#, path).unwrap();
+        assert_eq!(rules.len(), 1);
+        assert_eq!(rules[0].lhs().id(), "A");
+
+        /* Both ways of deserializing a grammar skip directives */
+        let (rules, _) = parse_str(r#"{"$other": [["'b'"]], "<A>": [["'a'"]]}"#, path).unwrap();
+        assert_eq!(rules.len(), 1);
+
+        let file = std::env::temp_dir().join(format!("peacock-meta-{}.json", std::process::id()));
+        std::fs::write(&file, "// comment\n{\"$meta\": {\"version\": \"1.0\"}, \"<A>\": [[\"'a'\"]]}").unwrap();
+        assert_eq!(parse_metadata(&file).unwrap(), Some(json::json!({"version": "1.0"})));
+
+        std::fs::write(&file, r#"{"<A>": [["'a'"]]}"#).unwrap();
+        assert_eq!(parse_metadata(&file).unwrap(), None);
+        std::fs::remove_file(&file).unwrap();
+    }
+
+    #[test]
+    fn test_type_errors() {
+        assert_eq!(
+            typed_grammar(json::json!({"<A>": ["'a'"]})).unwrap_err(),
+            "Right-hand-side of '<A>' must be an array of arrays"
+        );
+        assert_eq!(
+            typed_grammar(json::json!({"<A>": [[1]]})).unwrap_err(),
+            "Right-hand-side of '<A>' must be an array of arrays of strings or exclusions"
+        );
+        assert_eq!(
+            typed_grammar(json::json!({"<A>": [[{"not": ["'a'"]}]]})).unwrap_err(),
+            "Exclusion in '<A>' must be of the form {\"not\": [terminals...], \"from\": non-terminal}"
+        );
+        assert_eq!(typed_grammar(json::json!([])).unwrap_err(), "Peacock grammar must be specified as an object");
+    }
 }
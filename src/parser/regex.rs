@@ -0,0 +1,404 @@
+//! A minimal compiler from regular expressions to production rules.
+//!
+//! Supported are literals, escaped metacharacters, `.`, character classes like `[a-z_]` and `[^0-9]`,
+//! the shorthands `\d`, `\w` and `\s`, groups, alternation and the quantifiers `*`, `+`, `?`,
+//! `{n}`, `{n,}` and `{n,m}`.
+//!
+//! Unsupported are anchors, lazy quantifiers, backreferences, lookarounds and any other escape sequence.
+//! `.` and negated classes only produce printable ASCII characters.
+
+use crate::grammar::{
+    NonTerminal,
+    ProductionRule,
+    Symbol,
+    Terminal,
+};
+
+/// Counted repetitions are unrolled, so they must stay small
+const MAX_REPETITION: usize = 256;
+
+#[derive(Debug)]
+enum Node {
+    Literal(char),
+    Class(Vec<char>),
+    Group(Vec<Vec<Node>>),
+    Repeat(Box<Node>, usize, Option<usize>),
+}
+
+fn printable() -> impl Iterator<Item = char> {
+    (0x20u8..0x7f).map(char::from)
+}
+
+fn shorthand(c: char) -> Option<Vec<char>> {
+    match c {
+        'd' => Some(('0'..='9').collect()),
+        'w' => Some(('a'..='z').chain('A'..='Z').chain('0'..='9').chain(['_']).collect()),
+        's' => Some(vec![' ', '\t', '\n', '\r', '\x0b', '\x0c']),
+        _ => None,
+    }
+}
+
+fn escaped(c: char) -> Result<char, String> {
+    match c {
+        'n' => Ok('\n'),
+        'r' => Ok('\r'),
+        't' => Ok('\t'),
+        c if c.is_ascii_punctuation() => Ok(c),
+        c => Err(format!("Unsupported escape sequence \\{}", c)),
+    }
+}
+
+struct RegexParser<'a> {
+    chars: std::iter::Peekable<std::str::Chars<'a>>,
+}
+
+impl<'a> RegexParser<'a> {
+    fn new(pattern: &'a str) -> Self {
+        Self {
+            chars: pattern.chars().peekable(),
+        }
+    }
+
+    fn parse(mut self) -> Result<Vec<Vec<Node>>, String> {
+        let alternatives = self.parse_alternation()?;
+
+        match self.chars.next() {
+            None => Ok(alternatives),
+            Some(c) => Err(format!("Unexpected '{}'", c)),
+        }
+    }
+
+    fn parse_alternation(&mut self) -> Result<Vec<Vec<Node>>, String> {
+        let mut alternatives = vec![self.parse_concatenation()?];
+
+        while self.chars.next_if_eq(&'|').is_some() {
+            alternatives.push(self.parse_concatenation()?);
+        }
+
+        Ok(alternatives)
+    }
+
+    fn parse_concatenation(&mut self) -> Result<Vec<Node>, String> {
+        let mut nodes = Vec::new();
+
+        while let Some(c) = self.chars.peek() {
+            if *c == '|' || *c == ')' {
+                break;
+            }
+
+            let atom = self.parse_atom()?;
+            nodes.push(self.parse_quantifiers(atom)?);
+        }
+
+        Ok(nodes)
+    }
+
+    fn parse_atom(&mut self) -> Result<Node, String> {
+        match self.chars.next().unwrap() {
+            '(' => {
+                if self.chars.peek() == Some(&'?') {
+                    return Err("Unsupported group modifier '(?'".to_string());
+                }
+
+                let alternatives = self.parse_alternation()?;
+
+                if self.chars.next() != Some(')') {
+                    return Err("Missing ')'".to_string());
+                }
+
+                Ok(Node::Group(alternatives))
+            },
+            '[' => self.parse_class(),
+            '.' => Ok(Node::Class(printable().collect())),
+            '\\' => {
+                let c = self.chars.next().ok_or("Pattern must not end with '\\'")?;
+
+                match shorthand(c) {
+                    Some(class) => Ok(Node::Class(class)),
+                    None => Ok(Node::Literal(escaped(c)?)),
+                }
+            },
+            c @ ('*' | '+' | '?' | '{') => Err(format!("Nothing to repeat before '{}'", c)),
+            c @ ('^' | '$') => Err(format!("Unsupported anchor '{}'", c)),
+            c => Ok(Node::Literal(c)),
+        }
+    }
+
+    fn parse_class(&mut self) -> Result<Node, String> {
+        let negated = self.chars.next_if_eq(&'^').is_some();
+        let mut class = Vec::new();
+        let mut first = true;
+
+        loop {
+            let c = match self.chars.next() {
+                None => return Err("Missing ']'".to_string()),
+                Some(']') if !first => break,
+                Some('\\') => {
+                    let c = self.chars.next().ok_or("Pattern must not end with '\\'")?;
+
+                    if let Some(chars) = shorthand(c) {
+                        class.extend(chars);
+                        first = false;
+                        continue;
+                    }
+
+                    escaped(c)?
+                },
+                Some(c) => c,
+            };
+            first = false;
+
+            if self.chars.peek() == Some(&'-') {
+                self.chars.next();
+
+                match self.chars.next() {
+                    None => return Err("Missing ']'".to_string()),
+                    Some(']') => {
+                        class.extend([c, '-']);
+                        break;
+                    },
+                    Some(end) => {
+                        let end = if end == '\\' { escaped(self.chars.next().ok_or("Missing ']'")?)? } else { end };
+
+                        if end < c {
+                            return Err(format!("Invalid range {}-{}", c, end));
+                        }
+
+                        class.extend(c..=end);
+                    },
+                }
+            } else {
+                class.push(c);
+            }
+        }
+
+        if negated {
+            class = printable().filter(|c| !class.contains(c)).collect();
+        }
+
+        class.sort_unstable();
+        class.dedup();
+
+        if class.is_empty() {
+            return Err("Character class must not be empty".to_string());
+        }
+
+        Ok(Node::Class(class))
+    }
+
+    fn parse_number(&mut self) -> Option<usize> {
+        let mut number = String::new();
+
+        while let Some(c) = self.chars.next_if(|c| c.is_ascii_digit()) {
+            number.push(c);
+        }
+
+        number.parse().ok()
+    }
+
+    fn parse_quantifiers(&mut self, mut node: Node) -> Result<Node, String> {
+        loop {
+            let (min, max) = match self.chars.peek() {
+                Some('*') => (0, None),
+                Some('+') => (1, None),
+                Some('?') => (0, Some(1)),
+                Some('{') => {
+                    self.chars.next();
+                    let min = self.parse_number().ok_or("Invalid repetition")?;
+                    let max = if self.chars.next_if_eq(&',').is_some() { self.parse_number() } else { Some(min) };
+
+                    if self.chars.peek() != Some(&'}') {
+                        return Err("Invalid repetition".to_string());
+                    }
+
+                    if max.is_some_and(|max| max < min) || min.max(max.unwrap_or(0)) > MAX_REPETITION {
+                        return Err(format!("Repetitions must be ordered and at most {}", MAX_REPETITION));
+                    }
+
+                    (min, max)
+                },
+                _ => return Ok(node),
+            };
+
+            self.chars.next();
+
+            if self.chars.peek() == Some(&'?') {
+                return Err("Unsupported lazy quantifier".to_string());
+            }
+
+            node = Node::Repeat(Box::new(node), min, max);
+        }
+    }
+}
+
+struct Compiler {
+    name: String,
+    counter: usize,
+    rules: Vec<ProductionRule>,
+}
+
+impl Compiler {
+    fn fresh(&mut self) -> NonTerminal {
+        self.counter += 1;
+        NonTerminal::new(format!("{}${}", self.name, self.counter))
+    }
+
+    fn epsilon() -> Vec<Symbol> {
+        vec![Symbol::Terminal(Terminal::new(""))]
+    }
+
+    fn alternation(&mut self, lhs: NonTerminal, alternatives: &[Vec<Node>]) {
+        for nodes in alternatives {
+            let rhs = self.concatenation(nodes);
+            self.rules.push(ProductionRule::new(lhs.clone(), rhs));
+        }
+    }
+
+    fn concatenation(&mut self, nodes: &[Node]) -> Vec<Symbol> {
+        let mut rhs = Vec::with_capacity(nodes.len());
+
+        for node in nodes {
+            match (node, rhs.last_mut()) {
+                (Node::Literal(c), Some(Symbol::Terminal(_))) => {
+                    let Some(Symbol::Terminal(term)) = rhs.pop() else { unreachable!() };
+                    rhs.push(Symbol::Terminal(Terminal::new(format!("{}{}", term.content(), c))));
+                },
+                _ => rhs.push(self.node(node)),
+            }
+        }
+
+        if rhs.is_empty() {
+            rhs = Self::epsilon();
+        }
+
+        rhs
+    }
+
+    fn node(&mut self, node: &Node) -> Symbol {
+        match node {
+            Node::Literal(c) => Symbol::Terminal(Terminal::new(c.to_string())),
+            Node::Class(class) => {
+                let lhs = self.fresh();
+
+                for c in class {
+                    self.rules
+                        .push(ProductionRule::new(lhs.clone(), vec![Symbol::Terminal(Terminal::new(c.to_string()))]));
+                }
+
+                Symbol::NonTerminal(lhs)
+            },
+            Node::Group(alternatives) => {
+                let lhs = self.fresh();
+                self.alternation(lhs.clone(), alternatives);
+                Symbol::NonTerminal(lhs)
+            },
+            Node::Repeat(node, min, max) => {
+                let item = self.node(node);
+                let lhs = self.fresh();
+                let mut rhs = vec![item.clone(); *min];
+
+                match max {
+                    /* X* -> '' | X X* */
+                    None => {
+                        let star = self.fresh();
+                        self.rules.push(ProductionRule::new(star.clone(), Self::epsilon()));
+                        self.rules
+                            .push(ProductionRule::new(star.clone(), vec![item, Symbol::NonTerminal(star.clone())]));
+                        rhs.push(Symbol::NonTerminal(star));
+                    },
+                    /* X{0,k} -> '' | X X{0,k-1} */
+                    Some(max) if max > min => {
+                        let mut optional = self.fresh();
+                        self.rules.push(ProductionRule::new(optional.clone(), Self::epsilon()));
+                        self.rules.push(ProductionRule::new(optional.clone(), vec![item.clone()]));
+
+                        for _ in min + 1..*max {
+                            let next = self.fresh();
+                            self.rules.push(ProductionRule::new(next.clone(), Self::epsilon()));
+                            self.rules.push(ProductionRule::new(
+                                next.clone(),
+                                vec![item.clone(), Symbol::NonTerminal(optional)],
+                            ));
+                            optional = next;
+                        }
+
+                        rhs.push(Symbol::NonTerminal(optional));
+                    },
+                    Some(_) => {},
+                }
+
+                if rhs.is_empty() {
+                    rhs = Self::epsilon();
+                }
+
+                self.rules.push(ProductionRule::new(lhs.clone(), rhs));
+                Symbol::NonTerminal(lhs)
+            },
+        }
+    }
+}
+
+/// Compile `pattern` into production rules. The language of the non-terminal `name`
+/// is the set of strings that match the pattern. Helper non-terminals are named `name$N`.
+pub fn compile(name: &str, pattern: &str) -> Result<Vec<ProductionRule>, String> {
+    let alternatives = RegexParser::new(pattern).parse().map_err(|e| format!("Invalid regex '{}': {}", pattern, e))?;
+    let mut compiler = Compiler {
+        name: name.to_string(),
+        counter: 0,
+        rules: Vec::new(),
+    };
+
+    compiler.alternation(NonTerminal::new(name), &alternatives);
+    Ok(compiler.rules)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        backends::interpreter::GrammarInterpreter,
+        grammar::ContextFreeGrammar,
+    };
+    use std::collections::HashSet;
+
+    fn sample(pattern: &str, count: usize) -> HashSet<String> {
+        let rules = compile("ENTRYPOINT", pattern).unwrap();
+        let cfg = ContextFreeGrammar::new(rules, NonTerminal::new("ENTRYPOINT"));
+        let mut interpreter = GrammarInterpreter::new(&cfg);
+        let mut buf = Vec::new();
+        let mut words = HashSet::new();
+
+        for _ in 0..count {
+            interpreter.interpret_into(&mut buf, 64, None);
+            words.insert(String::from_utf8(buf.clone()).unwrap());
+        }
+
+        words
+    }
+
+    #[test]
+    fn test_regex_language() {
+        let words = sample("(ab|c)d?e{2}", 500);
+        let expected: HashSet<String> = ["abee", "abdee", "cee", "cdee"].iter().map(|x| x.to_string()).collect();
+        assert_eq!(words, expected);
+
+        for word in sample("[a-zA-Z_][a-zA-Z0-9_]*", 500) {
+            let mut chars = word.chars();
+            let first = chars.next().unwrap();
+            assert!(first.is_ascii_alphabetic() || first == '_');
+            assert!(chars.all(|c| c.is_ascii_alphanumeric() || c == '_'));
+        }
+
+        for word in sample("x{1,3}[^x]", 200) {
+            assert!((2..=4).contains(&word.len()));
+            assert!(!word.ends_with('x'));
+        }
+    }
+
+    #[test]
+    fn test_regex_errors() {
+        for pattern in ["^a", "a$", "*a", "(a", "[a", "a{3,1}", "a*?", "(?:a)", "\\b", "a{1000}"] {
+            assert!(compile("A", pattern).is_err(), "{}", pattern);
+        }
+    }
+}
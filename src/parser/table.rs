@@ -0,0 +1,45 @@
+use serde_json as json;
+use std::{
+    fs::File,
+    io::{
+        BufReader,
+        Read,
+    },
+    path::Path,
+};
+
+use crate::error::ParsingError;
+
+/// Load a terminal table that is either a JSON array of strings or
+/// a plain text file with one terminal per line.
+pub fn parse_table(path: &Path) -> Result<Vec<String>, ParsingError> {
+    let file =
+        File::open(path).map_err(|e| ParsingError::new(path, format!("Could not open terminal table: {}", e)))?;
+    let mut reader = BufReader::new(file);
+    let mut content = String::new();
+
+    if reader.read_to_string(&mut content).is_err() {
+        return Err(ParsingError::new(path, "Terminal table must be valid UTF-8"));
+    }
+
+    if content.trim_start().starts_with('[') {
+        return json::from_str::<Vec<String>>(&content)
+            .map_err(|_| ParsingError::new(path, "Terminal table must be specified as an array of strings"));
+    }
+
+    Ok(content.lines().map(|line| line.to_string()).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_table_formats() {
+        let lines = parse_table(Path::new("test-data/grammars/terminal_table.txt")).unwrap();
+        let array = parse_table(Path::new("test-data/grammars/terminal_table.json")).unwrap();
+        assert_eq!(lines, array);
+        assert_eq!(lines[0], "GET");
+        assert_eq!(lines[3], "");
+    }
+}
@@ -0,0 +1,433 @@
+//! Turn automaton walks into derivation trees for inspection.
+//!
+//! Use it like so:
+//! ```
+//! # use peacock_fuzz::{backends::C::LowLevelGrammar, grammar::ContextFreeGrammar, tree::DerivationTree};
+//! let grammar = ContextFreeGrammar::builder()
+//!     .peacock_grammar_str(r#"{"<ENTRYPOINT>": [["<DIGIT>", "'+'", "<DIGIT>"]], "<DIGIT>": [["'1'"], ["'2'"]]}"#).unwrap()
+//!     .optimize(false)
+//!     .build().unwrap();
+//! let grammar = LowLevelGrammar::from_high_level_grammar(&grammar);
+//!
+//! let tree = DerivationTree::from_sequence(&grammar, &[0, 1, 0]).unwrap();
+//! assert_eq!(tree.leaves().flat_map(|leaf| leaf.content.clone()).collect::<Vec<u8>>(), b"2+1");
+//! assert_eq!(tree.to_sequence(), [0, 1, 0]);
+//! print!("{}", tree.pretty_print());
+//! ```
+
+use std::{
+    fmt::Write,
+    ops::Range,
+};
+
+use crate::{
+    backends::{
+        interpreter::Bindings,
+        C::{
+            LLSymbol,
+            LowLevelGrammar,
+        },
+    },
+    error::TreeError,
+};
+
+/// A terminal in a [`DerivationTree`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Leaf {
+    /// The bytes of the terminal
+    pub content: Vec<u8>,
+
+    /// Where the terminal is in the serialized input
+    pub range: Range<usize>,
+}
+
+/// A child of a [`Node`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Child {
+    /// An expanded non-terminal
+    Node(Node),
+
+    /// A terminal
+    Leaf(Leaf),
+}
+
+/// The expansion of a non-terminal in a [`DerivationTree`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Node {
+    /// The id of the non-terminal in the [`LowLevelGrammar`]
+    pub non_terminal: usize,
+
+    /// The name of the non-terminal
+    pub name: String,
+
+    /// The index of the rule of the non-terminal that was chosen
+    pub rule: usize,
+
+    /// The index of the entry of the sequence that chose the rule
+    pub step: usize,
+
+    /// The bytes of the serialized input that this expansion produced
+    pub range: Range<usize>,
+
+    /// The symbols of the rule in the order of the rule.
+    /// Dynamic terminals have no children because their content is only known to the generator.
+    /// Builtins have their value as the only child.
+    /// Binding markers only become leaves if they repeat the bytes of a binding.
+    pub children: Vec<Child>,
+}
+
+/// The derivation tree that an automaton walk describes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DerivationTree {
+    root: Node,
+}
+
+/// A partially built node while walking a sequence
+struct Frame<'a> {
+    node: Node,
+    rule: &'a [LLSymbol],
+    position: usize,
+}
+
+impl DerivationTree {
+    /// Walk `sequence` like the serializer of the generated code does and build the tree of the derivation.
+    /// Entries after the end of the derivation are ignored, like the serializer does.
+    ///
+    /// Fails if the sequence ends before the derivation is finished or chooses a rule that does not exist.
+    pub fn from_sequence(grammar: &LowLevelGrammar, sequence: &[usize]) -> Result<Self, TreeError> {
+        let mut offset = 0;
+        let mut step = 0;
+        let mut stack = vec![Self::expand(grammar, sequence, &mut step, grammar.entrypoint().id(), 0)?];
+        let mut bindings = Bindings::new(grammar.bindings().len());
+
+        loop {
+            let frame = stack.last_mut().unwrap();
+
+            let Some(symbol) = frame.rule.get(frame.position) else {
+                let mut frame = stack.pop().unwrap();
+                frame.node.range.end = offset;
+
+                match stack.last_mut() {
+                    Some(parent) => parent.node.children.push(Child::Node(frame.node)),
+                    None => {
+                        return Ok(Self {
+                            root: frame.node,
+                        })
+                    },
+                }
+
+                continue;
+            };
+
+            frame.position += 1;
+
+            let content = match symbol {
+                LLSymbol::Terminal(term) => grammar.terminals()[term.id()].as_bytes().to_vec(),
+                LLSymbol::Repeat {
+                    byte,
+                    count,
+                } => vec![*byte; *count],
+                LLSymbol::Binding {
+                    op,
+                    slot,
+                } => {
+                    let content = bindings.apply(*op, *slot);
+
+                    if content.is_empty() {
+                        continue;
+                    }
+
+                    content
+                },
+                LLSymbol::NonTerminal(nonterm) => {
+                    let mut child = Self::expand(grammar, sequence, &mut step, nonterm.id(), offset)?;
+
+                    /* The step of a builtin holds its value */
+                    if let Some(builtin) = grammar.builtin(nonterm.id()) {
+                        let content = builtin.render(child.node.rule as u64);
+                        bindings.record(&content);
+                        offset += content.len();
+                        child.node.range.end = offset;
+                        child.node.children.push(Child::Leaf(Leaf {
+                            content,
+                            range: child.node.range.clone(),
+                        }));
+                        frame.node.children.push(Child::Node(child.node));
+                        continue;
+                    }
+
+                    stack.push(child);
+                    continue;
+                },
+            };
+
+            bindings.record(&content);
+            let range = offset..offset + content.len();
+            offset = range.end;
+            frame.node.children.push(Child::Leaf(Leaf {
+                content,
+                range,
+            }));
+        }
+    }
+
+    /// Choose the rule of `nonterm` at entry `step` of `sequence`
+    fn expand<'a>(
+        grammar: &'a LowLevelGrammar,
+        sequence: &[usize],
+        step: &mut usize,
+        nonterm: usize,
+        offset: usize,
+    ) -> Result<Frame<'a>, TreeError> {
+        let name = grammar.nonterminals()[nonterm].clone();
+
+        let Some(&rule) = sequence.get(*step) else {
+            return Err(TreeError::Truncated {
+                step: *step,
+                non_terminal: name,
+            });
+        };
+
+        /* Rule i > 0 of the dynamic non-terminal stands for the dynamic terminal i - 1 */
+        let is_dynamic = grammar.dynamic().is_some_and(|dynamic| dynamic.id() == nonterm);
+        let symbols: &[LLSymbol] = if is_dynamic || grammar.builtin(nonterm).is_some() {
+            &[]
+        } else {
+            let rules = &grammar.rules()[&nonterm];
+
+            match rules.get(rule) {
+                Some(symbols) => symbols,
+                None => {
+                    return Err(TreeError::RuleOutOfRange {
+                        step: *step,
+                        non_terminal: name,
+                        rule,
+                        rules: rules.len(),
+                    })
+                },
+            }
+        };
+
+        let node = Node {
+            non_terminal: nonterm,
+            name,
+            rule,
+            step: *step,
+            range: offset..offset,
+            children: Vec::with_capacity(symbols.len()),
+        };
+        *step += 1;
+
+        Ok(Frame {
+            node,
+            rule: symbols,
+            position: 0,
+        })
+    }
+
+    /// The expansion of the entrypoint.
+    pub fn root(&self) -> &Node {
+        &self.root
+    }
+
+    /// Iterate over all nodes in the order in which the sequence chose their rules.
+    pub fn nodes(&self) -> impl Iterator<Item = &Node> {
+        let mut stack = vec![&self.root];
+
+        std::iter::from_fn(move || {
+            let node = stack.pop()?;
+
+            for child in node.children.iter().rev() {
+                if let Child::Node(child) = child {
+                    stack.push(child);
+                }
+            }
+
+            Some(node)
+        })
+    }
+
+    /// Iterate over all terminals in the order in which they appear in the serialized input.
+    pub fn leaves(&self) -> impl Iterator<Item = &Leaf> {
+        let mut stack = vec![self.root.children.iter()];
+
+        std::iter::from_fn(move || loop {
+            let children = stack.last_mut()?;
+
+            match children.next() {
+                Some(Child::Leaf(leaf)) => return Some(leaf),
+                Some(Child::Node(node)) => stack.push(node.children.iter()),
+                None => {
+                    stack.pop();
+                },
+            }
+        })
+    }
+
+    /// The automaton walk that this tree describes, the inverse of [`from_sequence()`](DerivationTree::from_sequence).
+    pub fn to_sequence(&self) -> Vec<usize> {
+        self.nodes().map(|node| node.rule).collect()
+    }
+
+    /// Render the tree with one line per symbol, indented by two spaces per level.
+    /// Non-terminals show the chosen rule and the range of bytes they produced, terminals their content.
+    pub fn pretty_print(&self) -> String {
+        let mut output = String::new();
+        let mut stack: Vec<(&Child, usize)> = self.root.children.iter().rev().map(|child| (child, 1)).collect();
+        write_node(&mut output, &self.root, 0);
+
+        while let Some((child, depth)) = stack.pop() {
+            match child {
+                Child::Leaf(leaf) => writeln!(
+                    output,
+                    "{}'{}'",
+                    "  ".repeat(depth),
+                    String::from_utf8_lossy(&leaf.content).escape_debug()
+                )
+                .unwrap(),
+                Child::Node(node) => {
+                    write_node(&mut output, node, depth);
+                    stack.extend(node.children.iter().rev().map(|child| (child, depth + 1)));
+                },
+            }
+        }
+
+        output
+    }
+
+    /// Render the tree in the DOT language of graphviz.
+    pub fn to_dot(&self) -> String {
+        let mut output = String::from("digraph derivation {\n");
+        let mut ids = 0;
+        let mut stack = vec![(&self.root, ids)];
+
+        while let Some((node, id)) = stack.pop() {
+            writeln!(output, "    n{} [label=\"<{}> #{}\"];", id, escape_dot(node.name.as_bytes()), node.rule).unwrap();
+
+            for child in &node.children {
+                ids += 1;
+                writeln!(output, "    n{} -> n{};", id, ids).unwrap();
+
+                match child {
+                    Child::Node(child) => stack.push((child, ids)),
+                    Child::Leaf(leaf) => {
+                        writeln!(output, "    n{} [shape=box, label=\"{}\"];", ids, escape_dot(&leaf.content)).unwrap()
+                    },
+                }
+            }
+        }
+
+        output.push_str("}\n");
+        output
+    }
+}
+
+/// Write the line of `node` in [`DerivationTree::pretty_print()`]
+fn write_node(output: &mut String, node: &Node, depth: usize) {
+    writeln!(output, "{}<{}> #{} [{}..{}]", "  ".repeat(depth), node.name, node.rule, node.range.start, node.range.end)
+        .unwrap();
+}
+
+/// Turn `content` into the content of a quoted DOT string
+fn escape_dot(content: &[u8]) -> String {
+    String::from_utf8_lossy(content).escape_debug().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        backends::interpreter::GrammarInterpreter,
+        grammar::{
+            tests::rule,
+            ContextFreeGrammar,
+            NonTerminal,
+        },
+    };
+
+    fn grammar() -> LowLevelGrammar {
+        let cfg = ContextFreeGrammar::new(
+            vec![rule("S", &["(", "<S>", "<A>", ")"]), rule("S", &["x"]), rule("A", &["\n"]), rule("A", &[""])],
+            NonTerminal::new("S"),
+        );
+        LowLevelGrammar::from_high_level_grammar(&cfg)
+    }
+
+    #[test]
+    fn test_from_sequence() {
+        let ll = grammar();
+        let tree = DerivationTree::from_sequence(&ll, &[0, 1, 0, 7]).unwrap();
+
+        assert_eq!(tree.to_sequence(), [0, 1, 0]);
+        assert_eq!(tree.root().range, 0..4);
+        assert_eq!(
+            tree.nodes().map(|node| (node.name.as_str(), node.step, node.range.clone())).collect::<Vec<_>>(),
+            [("S", 0, 0..4), ("S", 1, 1..2), ("A", 2, 2..3)]
+        );
+        assert_eq!(
+            tree.leaves().map(|leaf| (leaf.content.as_slice(), leaf.range.clone())).collect::<Vec<_>>(),
+            [(&b"("[..], 0..1), (b"x", 1..2), (b"\n", 2..3), (b")", 3..4)]
+        );
+        assert_eq!(
+            tree.pretty_print(),
+            "<S> #0 [0..4]
+  '('
+  <S> #1 [1..2]
+    'x'
+  <A> #0 [2..3]
+    '\\n'
+  ')'
+"
+        );
+
+        let dot = tree.to_dot();
+        assert!(dot.starts_with("digraph derivation {\n    n0 [label=\"<S> #0\"];\n"));
+        assert!(dot.contains("[shape=box, label=\"\\n\"];"));
+        assert_eq!(dot.matches(" -> ").count(), 6);
+
+        assert!(matches!(
+            DerivationTree::from_sequence(&ll, &[0, 0, 1]),
+            Err(TreeError::Truncated { step: 3, ref non_terminal }) if non_terminal == "A"
+        ));
+        assert!(matches!(
+            DerivationTree::from_sequence(&ll, &[0, 1, 2]),
+            Err(TreeError::RuleOutOfRange {
+                step: 2,
+                rule: 2,
+                rules: 2,
+                ..
+            })
+        ));
+        assert!(matches!(
+            DerivationTree::from_sequence(&ll, &[]),
+            Err(TreeError::Truncated {
+                step: 0,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let cfg = ContextFreeGrammar::builder()
+            .gramatron_grammar("test-data/grammars/gramatron.json")
+            .unwrap()
+            .build()
+            .unwrap();
+        let ll = LowLevelGrammar::from_high_level_grammar(&cfg);
+        let mut interpreter = GrammarInterpreter::new(&cfg);
+
+        for _ in 0..100 {
+            let mut output = Vec::new();
+            let mut walk = Vec::new();
+            interpreter.interpret_recorded(&mut output, &mut walk).unwrap();
+            let sequence: Vec<usize> = walk.iter().map(|(_, rule)| *rule).collect();
+
+            let tree = DerivationTree::from_sequence(&ll, &sequence).unwrap();
+            assert_eq!(tree.to_sequence(), sequence);
+            assert_eq!(tree.root().range, 0..output.len());
+            assert_eq!(tree.leaves().flat_map(|leaf| leaf.content.clone()).collect::<Vec<_>>(), output);
+            assert!(tree.nodes().zip(&walk).all(|(node, (nonterm, _))| node.non_terminal == *nonterm));
+        }
+    }
+}
@@ -28,8 +28,67 @@ use peacock_fuzz::components::{
     seed_generator,
 };
 
+/// The knobs of the fuzzer, with the same defaults as peacock-fuzz
+struct Config {
+    timeout: Duration,
+    kill_signal: Signal,
+    power_schedule: PowerSchedule,
+    map_size: usize,
+    cache_size: usize,
+}
+
+impl Config {
+    fn new() -> Self {
+        Self {
+            timeout: Duration::from_secs(10),
+            kill_signal: Signal::SIGKILL,
+            power_schedule: PowerSchedule::EXPLORE,
+            map_size: 2_621_440,
+            cache_size: 128,
+        }
+    }
+    
+    /// How long the target may run before it is killed
+    fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+    
+    /// The signal that kills the target on a timeout
+    fn kill_signal(mut self, signal: Signal) -> Self {
+        self.kill_signal = signal;
+        self
+    }
+    
+    /// The power schedule of the weighted scheduler that decides how often a queue entry gets mutated
+    fn power_schedule(mut self, schedule: PowerSchedule) -> Self {
+        self.power_schedule = schedule;
+        self
+    }
+    
+    /// The size of the coverage map, rounded up to a multiple of 64 like peacock-fuzz does
+    fn map_size(mut self, map_size: usize) -> Self {
+        assert!(map_size > 0);
+        self.map_size = map_size.next_multiple_of(64);
+        self
+    }
+    
+    /// How many inputs of the queue are cached in memory
+    fn cache_size(mut self, cache_size: usize) -> Self {
+        assert!(cache_size > 0);
+        self.cache_size = cache_size;
+        self
+    }
+}
+
 fn main() -> Result<(), Error> {
     let args: Vec<String> = std::env::args().skip(1).collect();
+    let config = Config::new()
+        .timeout(Duration::from_secs(10))
+        .kill_signal(Signal::SIGKILL)
+        .power_schedule(PowerSchedule::EXPLORE)
+        .map_size(2_621_440)
+        .cache_size(128);
     
     load_generator();
     
@@ -37,18 +96,14 @@ fn main() -> Result<(), Error> {
         let output_dir = Path::new("output");
         let queue_dir = output_dir.join("queue");
         let crashes_dir = output_dir.join("crashes");
-        const MAP_SIZE: usize = 2_621_440;
         let seed = current_nanos();
-        let powerschedule = PowerSchedule::EXPLORE;
-        let timeout = Duration::from_secs(10);
-        let signal = str::parse::<Signal>("SIGKILL").unwrap();
         let debug_child = cfg!(debug_assertions);
         
         let mut shmem_provider = UnixShMemProvider::new()?;
-        let mut shmem = shmem_provider.new_shmem(MAP_SIZE)?;
+        let mut shmem = shmem_provider.new_shmem(config.map_size)?;
         shmem.write_to_env("__AFL_SHM_ID")?;
         let shmem_buf = shmem.as_slice_mut();
-        std::env::set_var("AFL_MAP_SIZE", format!("{}", MAP_SIZE));
+        std::env::set_var("AFL_MAP_SIZE", format!("{}", config.map_size));
         
         let edges_observer = unsafe { HitcountsMapObserver::new(StdMapObserver::new("shared_mem", shmem_buf)).track_indices() };
         
@@ -68,14 +123,14 @@ fn main() -> Result<(), Error> {
             TimeoutFeedback::new()
         );
         
-        seed_generator(seed as usize);
+        seed_generator(seed);
         
         let mut state = if let Some(state) = state {
             state
         } else {
             StdState::new(
                 StdRand::with_seed(seed),
-                CachedOnDiskCorpus::<PeacockInput>::new(&queue_dir, 128)?,
+                CachedOnDiskCorpus::<PeacockInput>::new(&queue_dir, config.cache_size)?,
                 OnDiskCorpus::new(crashes_dir)?,
                 &mut feedback,
                 &mut objective,
@@ -91,7 +146,7 @@ fn main() -> Result<(), Error> {
             StdWeightedScheduler::with_schedule(
                 &mut state,
                 &edges_observer,
-                Some(powerschedule),
+                Some(config.power_schedule),
             )
         );
         
@@ -101,10 +156,10 @@ fn main() -> Result<(), Error> {
             .program(&args[0])
             .debug_child(debug_child)
             .parse_afl_cmdline(args.get(1..).unwrap_or(&[]))
-            .coverage_map_size(MAP_SIZE)
+            .coverage_map_size(config.map_size)
             .is_persistent(false)
-            .timeout(timeout)
-            .kill_signal(signal)
+            .timeout(config.timeout)
+            .kill_signal(config.kill_signal)
             .build_dynamic_map(edges_observer, tuple_list!(time_observer))?;
         
         state.load_initial_inputs(